@@ -32,6 +32,22 @@ if bashio::config.has_value mqtt_password ; then
   export GOVEE_MQTT_PASSWORD="$(bashio::config mqtt_password)"
 fi
 
+if bashio::config.has_value mqtt_mirror_host ; then
+  export GOVEE_MQTT_MIRROR_HOST="$(bashio::config mqtt_mirror_host)"
+fi
+
+if bashio::config.has_value mqtt_mirror_port ; then
+  export GOVEE_MQTT_MIRROR_PORT="$(bashio::config mqtt_mirror_port)"
+fi
+
+if bashio::config.has_value mqtt_mirror_username ; then
+  export GOVEE_MQTT_MIRROR_USER="$(bashio::config mqtt_mirror_username)"
+fi
+
+if bashio::config.has_value mqtt_mirror_password ; then
+  export GOVEE_MQTT_MIRROR_PASSWORD="$(bashio::config mqtt_mirror_password)"
+fi
+
 if bashio::config.has_value debug_level ; then
   export RUST_LOG="$(bashio::config debug_level)"
 fi
@@ -48,6 +64,18 @@ if bashio::config.has_value govee_api_key ; then
   export GOVEE_API_KEY="$(bashio::config govee_api_key)"
 fi
 
+if bashio::config.has_value govee_accounts ; then
+  export GOVEE_ACCOUNTS="$(bashio::config govee_accounts)"
+fi
+
+if bashio::config.has_value iot_publish_rate ; then
+  export GOVEE_IOT_PUBLISH_RATE="$(bashio::config iot_publish_rate)"
+fi
+
+if bashio::config.has_value iot_publish_concurrency ; then
+  export GOVEE_IOT_PUBLISH_CONCURRENCY="$(bashio::config iot_publish_concurrency)"
+fi
+
 if bashio::config.has_value no_multicast ; then
   export GOVEE_LAN_NO_MULTICAST="$(bashio::config no_multicast)"
 fi
@@ -64,6 +92,106 @@ if bashio::config.has_value scan ; then
   export GOVEE_LAN_SCAN="$(bashio::config scan)"
 fi
 
+if bashio::config.has_value multicast_interfaces ; then
+  export GOVEE_LAN_MULTICAST_INTERFACES="$(bashio::config multicast_interfaces)"
+fi
+
+if bashio::config.has_value scan_interval ; then
+  export GOVEE_LAN_SCAN_INTERVAL="$(bashio::config scan_interval)"
+fi
+
+if bashio::config.has_value scene_icon_base_url ; then
+  export GOVEE_SCENE_ICON_BASE_URL="$(bashio::config scene_icon_base_url)"
+fi
+
+if bashio::config.has_value restore_after_power_loss ; then
+  export GOVEE_RESTORE_AFTER_POWER_LOSS="$(bashio::config restore_after_power_loss)"
+fi
+
+if bashio::config.has_value segment_layouts ; then
+  export GOVEE_SEGMENT_LAYOUTS="$(bashio::config segment_layouts)"
+fi
+
+if bashio::config.has_value color_presets ; then
+  export GOVEE_COLOR_PRESETS="$(bashio::config color_presets)"
+fi
+
+if bashio::config.has_value scene_filters ; then
+  export GOVEE_SCENE_FILTERS="$(bashio::config scene_filters)"
+fi
+
+if bashio::config.has_value scene_select_per_category ; then
+  export GOVEE_SCENE_SELECT_PER_CATEGORY="$(bashio::config scene_select_per_category)"
+fi
+
+if bashio::config.has_value hass_birth_topic ; then
+  export GOVEE_HASS_BIRTH_TOPIC="$(bashio::config hass_birth_topic)"
+fi
+
+if bashio::config.has_value hass_birth_payload ; then
+  export GOVEE_HASS_BIRTH_PAYLOAD="$(bashio::config hass_birth_payload)"
+fi
+
+if bashio::config.has_value derived_sensors ; then
+  export GOVEE_DERIVED_SENSORS="$(bashio::config derived_sensors)"
+fi
+
+if bashio::config.has_value rate_of_change_window ; then
+  export GOVEE_RATE_OF_CHANGE_WINDOW="$(bashio::config rate_of_change_window)"
+fi
+
+if bashio::config.has_value maintenance_window ; then
+  export GOVEE_MAINTENANCE_WINDOW="$(bashio::config maintenance_window)"
+fi
+
+if bashio::config.has_value state_merge_policy ; then
+  export GOVEE_STATE_MERGE_POLICY="$(bashio::config state_merge_policy)"
+fi
+
+if bashio::config.has_value cloud_pacing ; then
+  export GOVEE_CLOUD_PACING="$(bashio::config cloud_pacing)"
+fi
+
+if bashio::config.has_value command_queue ; then
+  export GOVEE_COMMAND_QUEUE="$(bashio::config command_queue)"
+fi
+
+if bashio::config.has_value poll_intervals ; then
+  export GOVEE_POLL_INTERVALS="$(bashio::config poll_intervals)"
+fi
+
+if bashio::config.has_value cloud_failure_policy ; then
+  export GOVEE_CLOUD_FAILURE_POLICY="$(bashio::config cloud_failure_policy)"
+fi
+
+if bashio::config.has_value probe_capabilities ; then
+  export GOVEE_PROBE_CAPABILITIES="$(bashio::config probe_capabilities)"
+fi
+
+if bashio::config.has_value power_save ; then
+  export GOVEE_POWER_SAVE="$(bashio::config power_save)"
+fi
+
+if bashio::config.has_value watchdog_rules ; then
+  export GOVEE_WATCHDOG_RULES="$(bashio::config watchdog_rules)"
+fi
+
+if bashio::config.has_value auto_brightness ; then
+  export GOVEE_AUTO_BRIGHTNESS="$(bashio::config auto_brightness)"
+fi
+
+if bashio::config.has_value preset_schedule ; then
+  export GOVEE_PRESET_SCHEDULE="$(bashio::config preset_schedule)"
+fi
+
+if bashio::config.has_value bridge_instance ; then
+  export GOVEE_BRIDGE_INSTANCE="$(bashio::config bridge_instance)"
+fi
+
+if bashio::config.has_value shard_claims ; then
+  export GOVEE_SHARD_CLAIMS="$(bashio::config shard_claims)"
+fi
+
 if bashio::config.has_value temperature_scale ; then
   export GOVEE_TEMPERATURE_SCALE="$(bashio::config temperature_scale)"
 fi
@@ -0,0 +1,87 @@
+import { LitElement, html} from "lit";
+import { Task } from '@lit/task';
+
+const RELATIONS = {
+  sub_device: "Outlet of",
+  feast_leader: "Led by",
+  feast_member: "Follows",
+};
+
+export class DeviceGraph extends LitElement {
+  _graphTask = new Task(this, {
+    task: async ([], {signal}) => {
+      const response = await fetch('/api/device-graph', {signal});
+      if (!response.ok) {
+        throw new Error(response.status);
+      }
+      return response.json();
+    },
+    args: () => []
+  });
+
+  render() {
+    return this._graphTask.render({
+      pending: () => html`<p>Loading device relationships...</p>`,
+      complete: (graph) => this._render_graph(graph),
+      error: (e) => html`<p>Failed to load device relationships: ${e}</p>`,
+    });
+  }
+
+  // Render into the normal DOM so that bootstrap CSS applies
+  createRenderRoot() {
+    return this;
+  }
+
+  _render_graph(graph) {
+    if (graph.edges.length == 0) {
+      return html``;
+    }
+
+    const nodes = new Map(graph.nodes.map((node) => [node.id, node]));
+    const label = (id) => {
+      const node = nodes.get(id);
+      if (node === undefined) {
+        return id;
+      }
+      return node.sku ? `${node.name} (${node.sku})` : node.name;
+    };
+
+    // Group the dependents under the device or leader that they depend upon
+    const by_target = new Map();
+    for (const edge of graph.edges) {
+      if (!by_target.has(edge.to)) {
+        by_target.set(edge.to, []);
+      }
+      by_target.get(edge.to).push(edge);
+    }
+
+    return html`
+      <h3 class="mt-4">Device Relationships</h3>
+      <p>
+        <a href="/api/device-graph/dot">Download as graphviz DOT</a>
+      </p>
+      <table class="table">
+        <thead>
+          <tr><th>Device or Leader</th><th>Dependents</th></tr>
+        </thead>
+        <tbody>
+          ${[...by_target.entries()].map(([target, edges]) => html`
+            <tr>
+              <td>${label(target)}</td>
+              <td>
+                <ul class="list-unstyled mb-0">
+                  ${edges.map((edge) => html`
+                    <li>${label(edge.from)}
+                      <span class="text-body-secondary">
+                        (${RELATIONS[edge.relation] ?? edge.relation})
+                      </span>
+                    </li>`)}
+                </ul>
+              </td>
+            </tr>`)}
+        </tbody>
+      </table>
+    `;
+  }
+}
+customElements.define("gv-device-graph", DeviceGraph);
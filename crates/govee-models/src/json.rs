@@ -0,0 +1,56 @@
+//! Helpers for the unusual encodings found in Govee payloads
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// Govee uses both booleans and integers to represent truthiness
+pub fn boolean_int<'de, D: serde::de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<bool, D::Error> {
+    Ok(match serde::de::Deserialize::deserialize(deserializer)? {
+        JsonValue::Bool(b) => b,
+        JsonValue::Number(num) => {
+            num.as_i64()
+                .ok_or(serde::de::Error::custom("Invalid number"))?
+                != 0
+        }
+        JsonValue::Null => false,
+        _ => return Err(serde::de::Error::custom("Wrong type, expected boolean")),
+    })
+}
+
+pub fn opt_boolean_int<'de, D: serde::de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "boolean_int")] bool);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(b)| b))
+}
+
+/// Serialize a value as a string holding its json representation
+pub fn as_json<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: serde::Serializer,
+{
+    use serde::ser::Error as _;
+
+    let s = serde_json::to_string(value).map_err(|e| S::Error::custom(format!("{e:#}")))?;
+
+    s.serialize(serializer)
+}
+
+/// Deserialize a value from a string holding its json representation
+pub fn embedded_json<'de, T: DeserializeOwned, D: serde::de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    use serde::de::Error as _;
+    let s = String::deserialize(deserializer)?;
+    serde_json_path_to_error::from_str(if s.is_empty() { "null" } else { &s }).map_err(|e| {
+        D::Error::custom(format!(
+            "{} {e:#} while processing embedded json text {s}",
+            std::any::type_name::<T>()
+        ))
+    })
+}
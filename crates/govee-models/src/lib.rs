@@ -0,0 +1,8 @@
+//! The serde models for the payloads produced by the various Govee
+//! APIs, as used by govee2mqtt. This crate has no networking or
+//! platform specific dependencies and builds for wasm32, so that
+//! browser based tools can parse the same data that the bridge does.
+pub mod json;
+pub mod platform;
+pub mod redacted;
+pub mod undoc;
@@ -0,0 +1,342 @@
+//! Models for the Govee Platform API V1, as described at
+//! <https://developer.govee.com/reference/get-you-devices>
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HttpDeviceState {
+    pub sku: String,
+    pub device: String,
+    pub capabilities: Vec<DeviceCapabilityState>,
+}
+
+impl HttpDeviceState {
+    pub fn capability_by_instance(&self, instance: &str) -> Option<&DeviceCapabilityState> {
+        self.capabilities
+            .iter()
+            .find(|c| c.instance.eq_ignore_ascii_case(instance))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub struct DeviceCapabilityState {
+    #[serde(rename = "type")]
+    pub kind: DeviceCapabilityKind,
+    pub instance: String,
+    pub state: JsonValue,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HttpDeviceInfo {
+    pub sku: String,
+    pub device: String,
+    #[serde(default, rename = "deviceName")]
+    pub device_name: String,
+    #[serde(default, rename = "type")]
+    pub device_type: DeviceType,
+    pub capabilities: Vec<DeviceCapability>,
+}
+
+impl HttpDeviceInfo {
+    pub fn capability_by_instance(&self, instance: &str) -> Option<&DeviceCapability> {
+        self.capabilities
+            .iter()
+            .find(|c| c.instance.eq_ignore_ascii_case(instance))
+    }
+
+    pub fn supports_rgb(&self) -> bool {
+        self.capability_by_instance("colorRgb").is_some()
+    }
+
+    pub fn supports_brightness(&self) -> bool {
+        self.capability_by_instance("brightness").is_some()
+    }
+
+    pub fn supports_dynamic_scenes(&self) -> bool {
+        self.capabilities
+            .iter()
+            .any(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
+    }
+
+    /// If supported, returns the number of segments
+    pub fn supports_segmented_rgb(&self) -> Option<std::ops::Range<u32>> {
+        let cap = self.capability_by_instance("segmentedColorRgb")?;
+        let field = cap.struct_field_by_name("segment")?;
+        match field.field_type {
+            DeviceParameters::Array {
+                size:
+                    Some(ArraySize {
+                        // These are the display indices. eg: 1-based
+                        min: label_min,
+                        max: label_max,
+                    }),
+                element_range:
+                    Some(ElementRange {
+                        // These are the actual indices. eg: 0-based
+                        min: range_min,
+                        // We ignore the max here, because the data
+                        // reported by Govee can be bogus:
+                        // <https://developer.govee.com/discuss/6599afb91cb48d002dbed2b8>
+                        max: _,
+                    }),
+                ..
+            } => {
+                // This range is an inclusive range, so add 1
+                let num_segments = (1 + label_max).saturating_sub(label_min);
+                // Return our exclusive range
+                Some(range_min..range_min + num_segments)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn supports_segmented_brightness(&self) -> Option<(u32, u32)> {
+        let cap = self.capability_by_instance("segmentedBrightness")?;
+        let field = cap.struct_field_by_name("brightness")?;
+        match &field.field_type {
+            DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            } => Some((*min, *max)),
+            _ => None,
+        }
+    }
+
+    pub fn get_brightness_range(&self) -> Option<(u32, u32)> {
+        let cap = self.capability_by_instance("brightness")?;
+
+        match cap.parameters {
+            Some(DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            }) => Some((min, max)),
+            _ => None,
+        }
+    }
+
+    pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
+        let cap = self.capability_by_instance("colorTemperatureK")?;
+
+        match cap.parameters {
+            Some(DeviceParameters::Integer {
+                range: IntegerRange { min, max, .. },
+                ..
+            }) => Some((min, max)),
+            _ => None,
+        }
+    }
+}
+
+/// Helper to generate boilerplate around govee enum string types.
+/// The expansion refers to `Deserialize`, `Deserializer`, `Serialize`,
+/// `Serializer` and `strum_macros`, which must be in scope where it is used.
+#[macro_export]
+macro_rules! enum_string {
+    {pub enum $name:ident {
+     $($var:ident = $label:literal),* $(,)?
+     }
+    } => {
+
+#[derive(Debug, Clone, PartialEq, Eq, strum_macros::Display, strum_macros::EnumString)]
+pub enum $name {
+    $(
+        #[strum(serialize = $label)]
+        $var,
+    )*
+        Other(String),
+}
+
+impl Default for $name {
+    fn default() -> Self {
+        Self::Other("NONE".to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for $name {
+    fn deserialize<D>(d: D) -> Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(d)?;
+
+        if let Ok(t) = s.parse::<Self>() {
+            Ok(t)
+        } else {
+            Ok(Self::Other(s))
+        }
+    }
+}
+
+impl Serialize for $name {
+    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::Other(s) => s.serialize(serializer),
+            _ => self.to_string().serialize(serializer),
+        }
+    }
+}
+
+    }
+}
+
+enum_string! {
+pub enum DeviceType {
+    Light = "devices.types.light",
+    AirPurifier = "devices.types.air_purifier",
+    Thermometer = "devices.types.thermometer",
+    Socket = "devices.types.socket",
+    Sensor = "devices.types.sensor",
+    Heater = "devices.types.heater",
+    Humidifier = "devices.types.humidifier",
+    Dehumidifier = "devices.types.dehumidifier",
+    IceMaker = "devices.types.ice_maker",
+    AromaDiffuser = "devices.types.aroma_diffuser",
+    Fan = "devices.types.fan",
+    Kettle = "devices.types.kettle",
+}
+}
+
+enum_string! {
+pub enum DeviceCapabilityKind {
+    OnOff = "devices.capabilities.on_off",
+    Toggle = "devices.capabilities.toggle",
+    Range = "devices.capabilities.range",
+    Mode = "devices.capabilities.mode",
+    ColorSetting = "devices.capabilities.color_setting",
+    SegmentColorSetting = "devices.capabilities.segment_color_setting",
+    MusicSetting = "devices.capabilities.music_setting",
+    DynamicScene = "devices.capabilities.dynamic_scene",
+    WorkMode = "devices.capabilities.work_mode",
+    DynamicSetting = "devices.capabilities.dynamic_setting",
+    TemperatureSetting = "devices.capabilities.temperature_setting",
+    Online = "devices.capabilities.online",
+    Property = "devices.capabilities.property",
+    Event = "devices.capabilities.event",
+}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceCapability {
+    #[serde(rename = "type")]
+    pub kind: DeviceCapabilityKind,
+    pub instance: String,
+    pub parameters: Option<DeviceParameters>,
+    #[serde(rename = "alarmType")]
+    pub alarm_type: Option<u32>,
+    #[serde(rename = "eventState")]
+    pub event_state: Option<JsonValue>,
+}
+
+impl DeviceCapability {
+    pub fn enum_parameter_by_name(&self, name: &str) -> Option<u32> {
+        self.parameters
+            .as_ref()
+            .and_then(|p| p.enum_parameter_by_name(name))
+    }
+
+    pub fn struct_field_by_name(&self, name: &str) -> Option<&StructField> {
+        match &self.parameters {
+            Some(DeviceParameters::Struct { fields }) => {
+                fields.iter().find(|f| f.field_name == name)
+            }
+            _ => None,
+        }
+    }
+
+    /// The range of an integer capability
+    pub fn integer_range(&self) -> Option<&IntegerRange> {
+        match &self.parameters {
+            Some(DeviceParameters::Integer { range, .. }) => Some(range),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "dataType")]
+pub enum DeviceParameters {
+    #[serde(rename = "ENUM")]
+    Enum { options: Vec<EnumOption> },
+    #[serde(rename = "INTEGER")]
+    Integer {
+        unit: Option<String>,
+        range: IntegerRange,
+    },
+    #[serde(rename = "STRUCT")]
+    Struct { fields: Vec<StructField> },
+    #[serde(rename = "Array")]
+    Array {
+        size: Option<ArraySize>,
+        #[serde(rename = "elementRange")]
+        element_range: Option<ElementRange>,
+        #[serde(rename = "elementType")]
+        element_type: Option<String>,
+        #[serde(default)]
+        options: Vec<ArrayOption>,
+    },
+}
+
+impl DeviceParameters {
+    pub fn enum_parameter_by_name(&self, name: &str) -> Option<u32> {
+        match self {
+            DeviceParameters::Enum { options } => options
+                .iter()
+                .find(|e| e.name == name && e.value.is_i64())
+                .map(|e| e.value.as_i64().expect("i64") as u32),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StructField {
+    #[serde(rename = "fieldName")]
+    pub field_name: String,
+
+    #[serde(flatten)]
+    pub field_type: DeviceParameters,
+
+    #[serde(rename = "defaultValue")]
+    pub default_value: Option<JsonValue>,
+
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ElementRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ArraySize {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IntegerRange {
+    pub min: u32,
+    pub max: u32,
+    pub precision: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EnumOption {
+    pub name: String,
+    #[serde(default)]
+    pub value: JsonValue,
+    #[serde(flatten)]
+    pub extras: HashMap<String, JsonValue>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ArrayOption {
+    pub value: u32,
+}
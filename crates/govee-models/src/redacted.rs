@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static LOG_SENSITIVE_DATA: AtomicBool = AtomicBool::new(false);
+
+/// Controls whether the Debug representation of Redacted values
+/// shows their content
+pub fn set_log_sensitive_data(enable: bool) {
+    LOG_SENSITIVE_DATA.store(enable, Ordering::Relaxed);
+}
+
+/// Some data is not meant for human eyes except in very unusual circumstances.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(transparent)]
+pub struct Redacted<T: std::fmt::Debug>(T);
+
+impl<T: std::fmt::Debug> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Redacted<T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if LOG_SENSITIVE_DATA.load(Ordering::Relaxed) {
+            self.0.fmt(fmt)
+        } else {
+            fmt.write_str("REDACTED")
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::ops::Deref for Redacted<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
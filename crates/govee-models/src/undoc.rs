@@ -0,0 +1,432 @@
+//! Models for the undocumented API used by the Govee Home app
+use crate::json::{as_json, boolean_int, embedded_json, opt_boolean_int};
+use crate::redacted::Redacted;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LightEffectLibraryResponse {
+    pub data: LightEffectLibraryCategoryList,
+    pub message: String,
+    pub status: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LightEffectLibraryCategoryList {
+    pub categories: Vec<LightEffectCategory>,
+    pub support_speed: u8,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LightEffectCategory {
+    pub category_id: u32,
+    pub category_name: String,
+    pub scenes: Vec<LightEffectScene>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LightEffectScene {
+    pub scene_id: u32,
+    pub icon_urls: Vec<String>,
+    pub scene_name: String,
+    pub analytic_name: String,
+    pub scene_type: u32,
+    pub scene_code: u32,
+    pub scence_category_id: u32,
+    pub pop_up_prompt: u32,
+    pub scenes_hint: String,
+    /// Eg: min/max applicable device version constraints
+    pub rule: JsonValue,
+    pub light_effects: Vec<LightEffectEntry>,
+    pub voice_url: String,
+    pub create_time: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LightEffectEntry {
+    pub scence_param_id: u32,
+    pub scence_name: String,
+    /// base64 encoded
+    pub scence_param: String,
+    pub scene_code: u16,
+    pub special_effect: Vec<JsonValue>,
+    pub cmd_version: u32,
+    pub scene_type: u32,
+    pub diy_effect_code: Vec<JsonValue>,
+    pub diy_effect_str: String,
+    pub rules: Vec<JsonValue>,
+    pub speed_info: JsonValue,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickResponse {
+    pub data: OneClickComponentList,
+    pub message: String,
+    pub status: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickComponentList {
+    pub components: Vec<OneClickComponent>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickComponent {
+    pub can_disable: Option<u8>,
+    #[serde(deserialize_with = "boolean_int")]
+    pub can_manage: bool,
+
+    pub feast_type: Option<u64>,
+    #[serde(default)]
+    pub feasts: Vec<JsonValue>,
+
+    #[serde(default)]
+    pub groups: Vec<JsonValue>,
+
+    pub main_device: Option<JsonValue>,
+
+    pub component_id: u64,
+    #[serde(default)]
+    pub environments: Vec<JsonValue>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub component_type: u64,
+
+    pub guide_url: Option<String>,
+    pub h5_url: Option<String>,
+    pub video_url: Option<String>,
+
+    #[serde(default)]
+    pub one_clicks: Vec<OneClick>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClick {
+    pub name: String,
+    pub plan_type: i64,
+    pub preset_id: i64,
+    pub preset_state: i64,
+    pub siri_engine_id: i64,
+    #[serde(rename = "type")]
+    pub rule_type: i64,
+    pub desc: String,
+    #[serde(default)]
+    pub exec_rules: Vec<JsonValue>,
+    pub group_id: i64,
+    pub group_name: String,
+    #[serde(default)]
+    pub iot_rules: Vec<OneClickIotRule>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickIotRule {
+    pub device_obj: OneClickIotRuleDevice,
+    pub rule: Vec<OneClickIotRuleEntry>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickIotRuleEntry {
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub blue_msg: JsonValue,
+    pub cmd_type: u64,
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub cmd_val: OneClickIotRuleEntryCmd,
+    pub device_type: u32,
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub iot_msg: JsonValue,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickIotRuleEntryCmd {
+    pub open: Option<u32>,
+    pub scenes_code: Option<u16>,
+    pub scence_id: Option<u16>,
+    pub scenes_str: Option<String>,
+    pub scence_param_id: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OneClickIotRuleDevice {
+    pub name: String,
+    pub device: String,
+    pub sku: String,
+
+    pub topic: Redacted<String>,
+
+    pub ble_address: String,
+    pub ble_name: String,
+    pub device_splicing_status: u32,
+    pub feast_id: u64,
+    pub feast_name: String,
+    pub feast_type: u64,
+    pub goods_type: u64,
+    pub ic: Option<u32>,
+    #[serde(rename = "ic_sub_1")]
+    pub ic_sub_1: Option<u32>,
+    #[serde(rename = "ic_sub_2")]
+    pub ic_sub_2: Option<u32>,
+    #[serde(deserialize_with = "boolean_int")]
+    pub is_feast: bool,
+    pub pact_type: u32,
+    pub pact_code: u32,
+
+    pub settings: Option<JsonValue>,
+    pub spec: String,
+    pub sub_device: String,
+    pub sub_device_num: u64,
+    pub sub_devices: Option<JsonValue>,
+
+    pub version_hard: String,
+    pub version_soft: String,
+    pub wifi_soft_version: String,
+    pub wifi_hard_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DevicesResponse {
+    pub devices: Vec<DeviceEntry>,
+    pub groups: Vec<GroupEntry>,
+    pub message: String,
+    pub status: u16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupEntry {
+    pub group_id: u64,
+    pub group_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceEntry {
+    pub attributes_id: u32,
+    pub device_id: Option<u32>,
+    pub device: String,
+    pub device_ext: DeviceEntryExt,
+    pub device_name: String,
+    pub goods_type: u32,
+    pub group_id: u64,
+    pub pact_code: Option<u32>,
+    pub pact_type: Option<u32>,
+    pub share: Option<u32>,
+    pub sku: String,
+    pub spec: String,
+    #[serde(deserialize_with = "boolean_int")]
+    pub support_scene: bool,
+    pub version_hard: String,
+    pub version_soft: String,
+    pub gid_confirmed: Option<bool>,
+}
+
+impl DeviceEntry {
+    pub fn device_topic(&self) -> anyhow::Result<&str> {
+        self.device_ext
+            .device_settings
+            .topic
+            .as_ref()
+            .map(|t| t.as_str())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "device {id} has no topic, is it a BLE-only device?",
+                    id = self.device
+                )
+            })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceEntryExt {
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub device_settings: DeviceSettings,
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub ext_resources: ExtResources,
+    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
+    pub last_device_data: LastDeviceData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSettings {
+    /// Maybe be absent for BLE devices
+    pub wifi_name: Option<String>,
+    pub address: Option<String>,
+    pub ble_name: Option<String>,
+    pub topic: Option<Redacted<String>>,
+    pub wifi_mac: Option<String>,
+    pub pact_type: Option<u32>,
+    pub pact_code: Option<u32>,
+    pub dsp_version_soft: Option<JsonValue>,
+    pub wifi_soft_version: Option<String>,
+    pub wifi_hard_version: Option<String>,
+    pub ic: Option<u32>,
+    #[serde(rename = "ic_sub_1")]
+    pub ic_sub_1: Option<u32>,
+    #[serde(rename = "ic_sub_2")]
+    pub ic_sub_2: Option<u32>,
+    pub secret_code: Option<Redacted<String>>,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub boil_water_completed_noti_on_off: bool,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub boil_water_exception_noti_on_off: bool,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub completion_noti_on_off: bool,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub auto_shut_down_on_off: bool,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub water_shortage_on_off: bool,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub air_quality_on_off: bool,
+    pub mcu_soft_version: Option<String>,
+    pub mcu_hard_version: Option<String>,
+    pub sku: Option<String>,
+    pub device: Option<String>,
+    pub device_name: Option<String>,
+    pub version_hard: Option<String>,
+    pub version_soft: Option<String>,
+    pub play_state: Option<bool>,
+    pub tem_min: Option<i64>,
+    pub tem_max: Option<i64>,
+    pub tem_warning: Option<bool>,
+    pub fah_open: Option<bool>,
+    pub tem_cali: Option<i64>,
+    pub hum_min: Option<i64>,
+    pub hum_max: Option<i64>,
+    pub hum_warning: Option<bool>,
+    pub hum_cali: Option<i64>,
+    pub net_waring: Option<bool>,
+    pub upload_rate: Option<i64>,
+    pub battery: Option<i64>,
+    /// millisecond timestamp
+    pub time: Option<u64>,
+    pub wifi_level: Option<i64>,
+
+    pub pm25_min: Option<i64>,
+    pub pm25_max: Option<i64>,
+    pub pm25_warning: Option<bool>,
+
+    // The next 2 fields are the settings of presence sensors
+    /// The range within which presence is detected, in centimeters
+    pub detection_distance: Option<i64>,
+    pub sensitivity: Option<i64>,
+
+    /// `{"sub_0": {"name": "Device Name"}}`
+    pub sub_devices: Option<JsonValue>,
+    /// The schedules configured on the device via the Govee app, eg:
+    /// `{"timing": [{"enable": 1, "openHour": 7, "openMin": 0,
+    /// "closeHour": 23, "closeMin": 30, "repeat": 127}]}`.
+    /// The layout varies between models, so it is left as json.
+    pub timer: Option<JsonValue>,
+    pub bd_type: Option<i64>,
+    #[serde(deserialize_with = "boolean_int", default)]
+    pub filter_expire_on_off: bool,
+
+    /// eg: Glide Hexa. Value is base64 encoded data
+    pub shapes: Option<String>,
+    pub support_ble_broad_v3: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtResources {
+    pub sku_url: Option<String>,
+    pub head_on_img_new: Option<String>,
+    pub head_on_img: Option<String>,
+    pub head_off_img: Option<String>,
+    pub head_off_img_new: Option<String>,
+    pub ext: Option<String>,
+    pub ic: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LastDeviceData {
+    pub online: Option<bool>,
+    /// Whether the gateway of a sub-device, such as a leak
+    /// detector paired with an H5043, is online
+    #[serde(rename = "gwonline")]
+    pub gateway_online: Option<bool>,
+    pub bind: Option<bool>,
+
+    pub tem: Option<i64>,
+    pub hum: Option<i64>,
+    /// timestamp in milliseconds
+    pub last_time: Option<u64>,
+    pub avg_day_tem: Option<i64>,
+    pub avg_day_hum: Option<i64>,
+
+    // The next 4 fields are reported by energy monitoring plugs
+    /// Watts
+    pub power: Option<f64>,
+    /// kWh consumed since the plug was set up
+    pub energy: Option<f64>,
+    /// Volts
+    pub voltage: Option<f64>,
+    /// Amps
+    pub current: Option<f64>,
+
+    /// Whether a presence sensor currently detects someone
+    #[serde(default, alias = "presence", deserialize_with = "opt_boolean_int")]
+    pub occupied: Option<bool>,
+}
+
+/// A firmware update that is available for a device, but
+/// which has not yet been installed via the Govee app
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdate {
+    /// The version of the firmware that would be installed
+    pub version_soft: String,
+    /// The release notes of the update, if any
+    #[serde(default, alias = "updateDesc")]
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiyEffectsResponse {
+    pub data: DiyEffectsData,
+    pub message: String,
+    pub status: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiyEffectsData {
+    #[serde(default)]
+    pub groups: Vec<DiyEffectGroup>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiyEffectGroup {
+    #[serde(default)]
+    pub group_name: String,
+    #[serde(default)]
+    pub diys: Vec<DiyEffect>,
+}
+
+/// A DIY effect that the user created in the Govee Home app
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiyEffect {
+    pub sku: String,
+    pub diy_name: String,
+    /// The id by which the effect is activated
+    pub diy_code: u32,
+}
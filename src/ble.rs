@@ -6,6 +6,13 @@ use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::sync::Arc;
 
+// The advertisement decoders are only used by the scanner,
+// but are always built so that their tests run
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+pub mod advertisement;
+#[cfg(feature = "ble")]
+pub mod scanner;
+
 static MGR: Lazy<PacketManager> = Lazy::new(PacketManager::new);
 
 #[derive(Clone, PartialEq, Eq)]
@@ -17,9 +24,12 @@ impl std::fmt::Debug for HexBytes {
     }
 }
 
+type EncoderFn = Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>;
+type DecoderFn = Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>;
+
 pub struct PacketCodec {
-    encode: Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>,
-    decode: Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>,
+    encode: EncoderFn,
+    decode: DecoderFn,
     supported_skus: &'static [&'static str],
     type_id: TypeId,
 }
@@ -51,16 +61,16 @@ pub struct PacketManager {
 }
 
 impl PacketManager {
-    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<HashMap<TypeId, Arc<PacketCodec>>> {
+    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<'_, HashMap<TypeId, Arc<PacketCodec>>> {
         MutexGuard::map(self.codec_by_sku.lock(), |codecs| {
             codecs.entry(sku.to_string()).or_insert_with(|| {
                 let mut map = HashMap::new();
 
                 for codec in &self.all_codecs {
-                    if codec.supported_skus.iter().any(|s| *s == sku) {
-                        if map.insert(codec.type_id.clone(), codec.clone()).is_some() {
-                            eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
-                        }
+                    if codec.supported_skus.contains(&sku)
+                        && map.insert(codec.type_id, codec.clone()).is_some()
+                    {
+                        eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
                     }
                 }
 
@@ -240,6 +250,67 @@ impl PacketManager {
             0x01,
             on,
         ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetBrightness,
+            SetBrightness,
+            0x33,
+            0x04,
+            percent,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetColorRgb,
+            SetColorRgb,
+            0x33,
+            0x05,
+            0x02,
+            r,
+            g,
+            b,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetColorTemperature,
+            SetColorTemperature,
+            0x33,
+            0x05,
+            0x02,
+            0xff,
+            0xff,
+            0xff,
+            0x01,
+            kelvin,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetSegmentColorRgb,
+            SetSegmentColorRgb,
+            0x33,
+            0x05,
+            0x15,
+            0x01,
+            r,
+            g,
+            b,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            segments,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetSegmentBrightness,
+            SetSegmentBrightness,
+            0x33,
+            0x05,
+            0x15,
+            0x02,
+            percent,
+            segments,
+        ));
 
         Self {
             codec_by_sku: Mutex::new(HashMap::new()),
@@ -255,7 +326,7 @@ pub trait DecodePacketParam {
 
 impl DecodePacketParam for u8 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        *self = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        *self = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         Ok(&data[1..])
     }
 
@@ -266,7 +337,7 @@ impl DecodePacketParam for u8 {
 
 impl DecodePacketParam for u16 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        let lo = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        let lo = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         let hi = *data.get(1).ok_or_else(|| anyhow!("EOF"))?;
         *self = ((hi as u16) << 8) | lo as u16;
         Ok(&data[2..])
@@ -280,6 +351,24 @@ impl DecodePacketParam for u16 {
     }
 }
 
+/// A u16 that is transmitted in big endian byte order
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BigEndianU16(pub u16);
+
+impl DecodePacketParam for BigEndianU16 {
+    fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        let hi = *data.first().ok_or_else(|| anyhow!("EOF"))?;
+        let lo = *data.get(1).ok_or_else(|| anyhow!("EOF"))?;
+        self.0 = ((hi as u16) << 8) | lo as u16;
+        Ok(&data[2..])
+    }
+
+    fn encode_param(&self, target: &mut Vec<u8>) {
+        target.push((self.0 >> 8) as u8);
+        target.push((self.0 & 0xff) as u8);
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
 pub struct SetHumidifierNightlightParams {
     pub on: bool,
@@ -289,14 +378,14 @@ pub struct SetHumidifierNightlightParams {
     pub brightness: u8,
 }
 
-impl Into<SetHumidifierNightlightParams> for NotifyHumidifierNightlightParams {
-    fn into(self) -> SetHumidifierNightlightParams {
+impl From<NotifyHumidifierNightlightParams> for SetHumidifierNightlightParams {
+    fn from(val: NotifyHumidifierNightlightParams) -> Self {
         SetHumidifierNightlightParams {
-            on: self.on,
-            r: self.r,
-            g: self.g,
-            b: self.b,
-            brightness: self.brightness,
+            on: val.on,
+            r: val.r,
+            g: val.g,
+            b: val.b,
+            brightness: val.brightness,
         }
     }
 }
@@ -315,9 +404,9 @@ pub struct NotifyHumidifierNightlightParams {
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TargetHumidity(u8);
 
-impl Into<u8> for TargetHumidity {
-    fn into(self) -> u8 {
-        self.0
+impl From<TargetHumidity> for u8 {
+    fn from(val: TargetHumidity) -> Self {
+        val.0
     }
 }
 
@@ -372,11 +461,83 @@ pub struct SetDevicePower {
     pub on: bool,
 }
 
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetBrightness {
+    pub percent: u8,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetColorRgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetColorTemperature {
+    pub kelvin: BigEndianU16,
+}
+
+/// A set of segments, as a bitmask with bit 0 for the first segment,
+/// transmitted in little endian byte order
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentMask(pub u32);
+
+impl SegmentMask {
+    /// The largest number of segments that a mask can address
+    pub const MAX_SEGMENTS: u32 = 32;
+
+    pub fn from_segments(segments: impl IntoIterator<Item = u32>) -> anyhow::Result<Self> {
+        let mut mask = 0u32;
+        for segment in segments {
+            anyhow::ensure!(
+                segment < Self::MAX_SEGMENTS,
+                "segment {segment} is out of range"
+            );
+            mask |= 1 << segment;
+        }
+        Ok(Self(mask))
+    }
+}
+
+impl DecodePacketParam for SegmentMask {
+    fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(data.len() >= 4, "EOF");
+        self.0 = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        Ok(&data[4..])
+    }
+
+    fn encode_param(&self, target: &mut Vec<u8>) {
+        target.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+/// Sets the color of some of the segments of a segmented light
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentColorRgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub segments: SegmentMask,
+}
+
+/// Sets the brightness of some of the segments of a segmented light
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentBrightness {
+    pub percent: u8,
+    pub segments: SegmentMask,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GoveeBlePacket {
     Generic(HexBytes),
     SetSceneCode(SetSceneCode),
     SetDevicePower(SetDevicePower),
+    SetBrightness(SetBrightness),
+    SetColorRgb(SetColorRgb),
+    SetColorTemperature(SetColorTemperature),
+    SetSegmentColorRgb(SetSegmentColorRgb),
+    SetSegmentBrightness(SetSegmentBrightness),
     SetHumidifierNightlight(SetHumidifierNightlightParams),
     NotifyHumidifierMode(NotifyHumidifierMode),
     SetHumidifierMode(SetHumidifierMode),
@@ -423,7 +584,7 @@ impl<'de> Deserialize<'de> for Base64HexBytes {
 fn calculate_checksum(data: &[u8]) -> u8 {
     let mut checksum: u8 = 0;
     for &b in data {
-        checksum = checksum ^ b;
+        checksum ^= b;
     }
     checksum
 }
@@ -510,6 +671,33 @@ mod test {
             &SetDevicePower { on: true },
             GoveeBlePacket::SetDevicePower(SetDevicePower { on: true }),
         );
+        round_trip(
+            "Generic:Light",
+            &SetBrightness { percent: 42 },
+            GoveeBlePacket::SetBrightness(SetBrightness { percent: 42 }),
+        );
+        round_trip(
+            "Generic:Light",
+            &SetColorRgb {
+                r: 255,
+                g: 255,
+                b: 255,
+            },
+            GoveeBlePacket::SetColorRgb(SetColorRgb {
+                r: 255,
+                g: 255,
+                b: 255,
+            }),
+        );
+        round_trip(
+            "Generic:Light",
+            &SetColorTemperature {
+                kelvin: BigEndianU16(6500),
+            },
+            GoveeBlePacket::SetColorTemperature(SetColorTemperature {
+                kelvin: BigEndianU16(6500),
+            }),
+        );
         round_trip(
             "H7160",
             &SetHumidifierNightlightParams {
@@ -528,4 +716,39 @@ mod test {
             }),
         );
     }
+
+    #[test]
+    fn segment_packets() {
+        let segments = SegmentMask::from_segments([0, 2, 9]).unwrap();
+        assert_eq!(segments, SegmentMask(0b10_0000_0101));
+        assert!(SegmentMask::from_segments([32]).is_err());
+
+        assert_eq!(
+            MGR.encode_for_sku(
+                "Generic:Light",
+                &SetSegmentColorRgb {
+                    r: 0xff,
+                    g: 0x80,
+                    b: 0x00,
+                    segments,
+                }
+            )
+            .unwrap(),
+            vec![
+                0x33, 0x05, 0x15, 0x01, 0xff, 0x80, 0x00, 0, 0, 0, 0, 0, 0x05, 0x02, 0, 0, 0, 0, 0,
+                0x5a
+            ]
+        );
+        round_trip(
+            "Generic:Light",
+            &SetSegmentBrightness {
+                percent: 40,
+                segments,
+            },
+            GoveeBlePacket::SetSegmentBrightness(SetSegmentBrightness {
+                percent: 40,
+                segments,
+            }),
+        );
+    }
 }
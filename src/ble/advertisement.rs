@@ -0,0 +1,208 @@
+//! Decodes the readings that BLE-only thermometers and hygrometers,
+//! such as the H5074, H5075 and H5101, broadcast in the manufacturer
+//! data of their advertisements. These devices never appear on the
+//! LAN or via AWS IoT, so passively listening is the only way to
+//! obtain their readings without a Govee hub.
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The company id used by the H5072, H5074 and H5075
+const MFR_GOVEE: u16 = 0xec88;
+/// The company id used by the H5101 family
+const MFR_H5101: u16 = 0x0001;
+
+/// SKUs that report using the `MFR_H5101` layout
+const H5101_FAMILY: &[&str] = &[
+    "H5100", "H5101", "H5102", "H5104", "H5108", "H5174", "H5177",
+];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct BleSensorReading {
+    pub temperature_celsius: f64,
+    pub humidity_percent: f64,
+    pub battery_percent: u8,
+}
+
+/// A decoded advertisement
+#[derive(Clone, Debug, PartialEq)]
+pub struct BleAdvertisement {
+    pub sku: String,
+    pub reading: BleSensorReading,
+}
+
+/// Extract the SKU from the advertised local name,
+/// eg: `GVH5075_1A2B` or `Govee_H5074_1A2B`
+pub fn sku_from_local_name(name: &str) -> Option<String> {
+    let start = name.find('H')?;
+    let sku: String = name[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    (sku.len() == 5 && sku[1..].chars().all(|c| c.is_ascii_digit())).then_some(sku)
+}
+
+/// A 24-bit big endian value that packs both the temperature and
+/// humidity: `temperature * 10000 + humidity * 10`, with the top
+/// bit indicating a negative temperature.
+/// The top bit of the battery byte indicates an error.
+fn decode_packed(data: &[u8]) -> Option<BleSensorReading> {
+    let &[a, b, c, battery] = data else {
+        return None;
+    };
+    let packed = u32::from_be_bytes([0, a, b, c]);
+    let negative = packed & 0x80_0000 != 0;
+    let value = packed & 0x7f_ffff;
+    if battery & 0x80 != 0 {
+        return None;
+    }
+
+    let temperature = (value / 1000) as f64 / 10.;
+    Some(BleSensorReading {
+        temperature_celsius: if negative { -temperature } else { temperature },
+        humidity_percent: (value % 1000) as f64 / 10.,
+        battery_percent: battery,
+    })
+}
+
+/// Little endian i16 temperature and u16 humidity in hundredths,
+/// followed by the battery percentage
+fn decode_h5074(data: &[u8]) -> Option<BleSensorReading> {
+    let &[t0, t1, h0, h1, battery] = data else {
+        return None;
+    };
+    Some(BleSensorReading {
+        temperature_celsius: i16::from_le_bytes([t0, t1]) as f64 / 100.,
+        humidity_percent: u16::from_le_bytes([h0, h1]) as f64 / 100.,
+        battery_percent: battery,
+    })
+}
+
+/// Decode the manufacturer data of an advertisement.
+/// Returns None if the advertisement is not from a supported sensor.
+pub fn decode_advertisement(
+    local_name: Option<&str>,
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+) -> Option<BleAdvertisement> {
+    let sku = local_name.and_then(sku_from_local_name);
+
+    if let Some(data) = manufacturer_data.get(&MFR_GOVEE) {
+        let (default_sku, reading) = match data.len() {
+            6 => ("H5075", decode_packed(&data[1..5])?),
+            9 => ("H5074", decode_h5074(&data[1..6])?),
+            _ => return None,
+        };
+        return Some(BleAdvertisement {
+            sku: sku.unwrap_or_else(|| default_sku.to_string()),
+            reading,
+        });
+    }
+
+    // Company id 1 is not exclusive to Govee, so require
+    // that the name identifies the device too
+    let sku = sku?;
+    if H5101_FAMILY.contains(&sku.as_str()) {
+        let data = manufacturer_data.get(&MFR_H5101)?;
+        if data.len() == 6 {
+            return Some(BleAdvertisement {
+                reading: decode_packed(&data[2..6])?,
+                sku,
+            });
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mfr(id: u16, data: &[u8]) -> HashMap<u16, Vec<u8>> {
+        [(id, data.to_vec())].into_iter().collect()
+    }
+
+    #[test]
+    fn local_names() {
+        assert_eq!(
+            sku_from_local_name("GVH5075_1A2B").as_deref(),
+            Some("H5075")
+        );
+        assert_eq!(
+            sku_from_local_name("Govee_H5074_1A2B").as_deref(),
+            Some("H5074")
+        );
+        assert_eq!(
+            sku_from_local_name("GVH5101_A1B2").as_deref(),
+            Some("H5101")
+        );
+        assert_eq!(sku_from_local_name("Headphones"), None);
+    }
+
+    #[test]
+    fn h5075() {
+        // 0x03_6d_7d = 224637: 22.4C, 63.7%
+        let advert = decode_advertisement(
+            Some("GVH5075_1A2B"),
+            &mfr(MFR_GOVEE, &[0x00, 0x03, 0x6d, 0x7d, 0x55, 0x00]),
+        )
+        .unwrap();
+        assert_eq!(advert.sku, "H5075");
+        assert_eq!(
+            advert.reading,
+            BleSensorReading {
+                temperature_celsius: 22.4,
+                humidity_percent: 63.7,
+                battery_percent: 85,
+            }
+        );
+
+        // Negative: -5.2C, 41.0%
+        let advert =
+            decode_advertisement(None, &mfr(MFR_GOVEE, &[0x00, 0x80, 0xcc, 0xba, 0x64, 0x00]))
+                .unwrap();
+        assert_eq!(advert.reading.temperature_celsius, -5.2);
+        assert_eq!(advert.reading.humidity_percent, 41.0);
+
+        // The error bit is set in the battery byte
+        assert_eq!(
+            decode_advertisement(None, &mfr(MFR_GOVEE, &[0x00, 0x03, 0x6d, 0x7d, 0xd5, 0x00])),
+            None
+        );
+    }
+
+    #[test]
+    fn h5074() {
+        let advert = decode_advertisement(
+            Some("Govee_H5074_1A2B"),
+            &mfr(
+                MFR_GOVEE,
+                &[0x00, 0x2a, 0x08, 0x7c, 0x15, 0x64, 0x02, 0x00, 0x00],
+            ),
+        )
+        .unwrap();
+        assert_eq!(advert.sku, "H5074");
+        assert_eq!(
+            advert.reading,
+            BleSensorReading {
+                temperature_celsius: 20.9,
+                humidity_percent: 55.0,
+                battery_percent: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn h5101() {
+        let data = [0x01, 0x01, 0x03, 0x6d, 0x7d, 0x55];
+        let advert = decode_advertisement(Some("GVH5101_A1B2"), &mfr(MFR_H5101, &data)).unwrap();
+        assert_eq!(advert.sku, "H5101");
+        assert_eq!(advert.reading.temperature_celsius, 22.4);
+
+        // Company id 1 alone is not enough to identify the device
+        assert_eq!(decode_advertisement(None, &mfr(MFR_H5101, &data)), None);
+        assert_eq!(
+            decode_advertisement(Some("GVH5075_1A2B"), &mfr(MFR_H5101, &data)),
+            None
+        );
+    }
+}
@@ -0,0 +1,58 @@
+//! Passively listens for the advertisements of BLE-only sensors
+//! using the local bluetooth adapter, and feeds their readings
+//! into the device state.
+use crate::ble::advertisement::decode_advertisement;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager, PeripheralId};
+use futures_util::StreamExt;
+
+async fn handle_event(
+    adapter: &Adapter,
+    state: &StateHandle,
+    id: &PeripheralId,
+) -> anyhow::Result<()> {
+    let peripheral = adapter.peripheral(id).await?;
+    let Some(props) = peripheral.properties().await? else {
+        return Ok(());
+    };
+    let Some(advert) = decode_advertisement(props.local_name.as_deref(), &props.manufacturer_data)
+    else {
+        return Ok(());
+    };
+    log::trace!("BLE: {} {advert:?}", props.address);
+    state
+        .record_ble_advertisement(&props.address.to_string(), advert)
+        .await
+}
+
+pub async fn run_ble_scanner(state: StateHandle) -> anyhow::Result<()> {
+    let manager = Manager::new()
+        .await
+        .context("initializing bluetooth. Is bluez running?")?;
+    let adapter = manager
+        .adapters()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no bluetooth adapter found"))?;
+    log::info!("BLE: scanning using {}", adapter.adapter_info().await?);
+
+    let mut events = adapter.events().await?;
+    adapter.start_scan(ScanFilter::default()).await?;
+
+    while let Some(event) = events.next().await {
+        let id = match &event {
+            CentralEvent::DeviceDiscovered(id)
+            | CentralEvent::DeviceUpdated(id)
+            | CentralEvent::ManufacturerDataAdvertisement { id, .. } => id,
+            _ => continue,
+        };
+        if let Err(err) = handle_event(&adapter, &state, id).await {
+            log::warn!("BLE: {err:#}");
+        }
+    }
+
+    anyhow::bail!("BLE event stream ended");
+}
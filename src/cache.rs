@@ -1,33 +1,117 @@
+use crate::service::daily_window::DailyWindow;
 use anyhow::Context;
 use arc_swap::ArcSwap;
-use chrono::{DateTime, Utc};
-use once_cell::sync::Lazy;
+use chrono::{DateTime, Local, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sqlite_cache::{Cache, CacheConfig};
+use std::collections::BTreeMap;
 use std::future::Future;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
+use uuid::Uuid;
 
 pub static CACHE: Lazy<ArcSwap<Cache>> =
     Lazy::new(|| open_cache().expect("failed to initialize cache").into());
 
-fn cache_file_name() -> PathBuf {
-    let cache_dir = std::env::var("GOVEE_CACHE_DIR")
+/// Overrides the directory returned by `cache_dir`
+static CACHE_DIR: OnceCell<PathBuf> = OnceCell::new();
+/// Overrides the file in which the cache is stored
+static CACHE_FILE: OnceCell<PathBuf> = OnceCell::new();
+/// The window during which the deferred refreshes happen
+static MAINTENANCE_WINDOW: OnceCell<DailyWindow> = OnceCell::new();
+
+/// Defer the refresh of the expensive entries to the window
+pub fn set_maintenance_window(window: DailyWindow) {
+    let _ = MAINTENANCE_WINDOW.set(window);
+}
+
+/// Whether the refresh of a deferrable entry should wait
+/// for the maintenance window
+fn defer_refresh() -> bool {
+    MAINTENANCE_WINDOW
+        .get()
+        .is_some_and(|window| !window.contains(Local::now().time()))
+}
+
+/// The directory in which the cache and other persistent
+/// state are stored
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = CACHE_DIR.get() {
+        return dir.clone();
+    }
+    std::env::var("GOVEE_CACHE_DIR")
         .ok()
         .map(PathBuf::from)
-        .or_else(|| dirs_next::cache_dir())
-        .expect("failed to resolve cache dir");
+        .or_else(dirs_next::cache_dir)
+        .expect("failed to resolve cache dir")
+}
 
-    cache_dir.join("govee2mqtt-cache.sqlite")
+/// Isolate the tests from the persistent state of whoever runs them:
+/// the cache is held in memory, and other state is looked for in a
+/// directory that doesn't exist
+#[cfg(test)]
+pub fn isolate_cache() {
+    static ISOLATE: std::sync::Once = std::sync::Once::new();
+    ISOLATE.call_once(|| {
+        let _ = CACHE_DIR.set(std::env::temp_dir().join("govee2mqtt-test-nonexistent"));
+        // SQLite holds a database named `:memory:` in memory
+        let _ = CACHE_FILE.set(PathBuf::from(":memory:"));
+        // Another test may already have opened the default cache
+        if let Some(cache) = Lazy::get(&CACHE) {
+            cache.store(open_cache().expect("failed to initialize cache"));
+        }
+    });
+}
+
+/// Write `data` to `path`, replacing any prior content, such that
+/// only the current user can read it
+pub fn write_private_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+/// Allow only the current user to read an existing file. The cache
+/// holds account tokens, including the long-lived refresh token.
+fn make_private(path: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if path.exists() {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("restricting the permissions of {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn cache_file_name() -> PathBuf {
+    match CACHE_FILE.get() {
+        Some(file) => file.clone(),
+        None => cache_dir().join("govee2mqtt-cache.sqlite"),
+    }
 }
 
 fn open_cache() -> anyhow::Result<Arc<Cache>> {
     let cache_file = cache_file_name();
     let conn = sqlite_cache::rusqlite::Connection::open(&cache_file)
-        .expect(&format!("failed to open {cache_file:?}"));
-    Ok(Arc::new(Cache::new(
+        .unwrap_or_else(|_| panic!("failed to open {cache_file:?}"));
+    let cache = Cache::new(
         // We have low cardinality and can be pretty relaxed
         CacheConfig {
             flush_gc_ratio: 1024,
@@ -35,17 +119,119 @@ fn open_cache() -> anyhow::Result<Arc<Cache>> {
             max_ttl: None,
         },
         conn,
-    )?))
+    )?;
+    make_private(&cache_file)?;
+    migrate_cache(&cache).with_context(|| format!("migrating cache {cache_file:?}"))?;
+    Ok(Arc::new(cache))
+}
+
+/// Bump this when the layout of the cache changes in a way that
+/// requires existing entries to be migrated
+const CACHE_VERSION: u32 = 1;
+
+/// Prior to version 1, these account specific entries were not
+/// namespaced, which meant that multiple accounts would clobber
+/// each other. We can't tell which account they belong to, so
+/// they are removed and will be fetched again on demand.
+/// The per-device entries that were not namespaced will simply
+/// expire, as the sqlite cache doesn't allow enumerating them.
+const UNNAMESPACED_KEYS: &[(&str, &str)] = &[
+    ("http-api", "device-list"),
+    ("rest-api", "device-list"),
+    ("undoc-api", "account-info"),
+    ("undoc-api", "community-login"),
+    ("undoc-api", "iot-key"),
+    ("undoc-api", "one-click-shortcuts"),
+];
+
+fn migrate_cache(cache: &Cache) -> anyhow::Result<()> {
+    let meta = cache.topic("cache-meta")?;
+    let version: u32 = meta
+        .get("version")?
+        .and_then(|value| String::from_utf8(value.data).ok()?.parse().ok())
+        .unwrap_or(0);
+
+    if version >= CACHE_VERSION {
+        return Ok(());
+    }
+
+    if version < 1 {
+        log::info!("Removing cache entries that are not namespaced by account");
+        for (topic, key) in UNNAMESPACED_KEYS {
+            cache.topic(topic)?.delete(key)?;
+        }
+    }
+
+    meta.set(
+        "version",
+        CACHE_VERSION.to_string().as_bytes(),
+        Duration::from_secs(86400 * 365 * 10),
+    )?;
+    Ok(())
+}
+
+/// Produce a cache key that is specific to an account or API key,
+/// so that multiple accounts can share the same cache without
+/// clobbering each other. `account` is hashed so that the secret
+/// key isn't stored in the cache.
+pub fn account_key(account: &str, key: &str) -> String {
+    let namespace = Uuid::new_v5(&Uuid::NAMESPACE_OID, account.as_bytes());
+    format!("{}-{key}", namespace.simple())
 }
 
 pub fn purge_cache() -> anyhow::Result<()> {
     let cache_file = cache_file_name();
     std::fs::remove_file(&cache_file)
         .with_context(|| format!("removing cache file {cache_file:?}"))?;
-    CACHE.store(open_cache()?.into());
+    CACHE.store(open_cache()?);
+    USAGE.lock().clear();
     Ok(())
 }
 
+/// How an entry has been used by cache_get since startup.
+/// The sqlite cache can't enumerate its entries, so this is
+/// the only view we have of what it contains.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CacheKeyUsage {
+    pub hits: u64,
+    pub misses: u64,
+    /// When the current value should be refreshed
+    pub expires: Option<DateTime<Utc>>,
+    /// Set if the most recent refresh failed
+    pub last_error: Option<String>,
+}
+
+static USAGE: Lazy<Mutex<BTreeMap<String, BTreeMap<String, CacheKeyUsage>>>> =
+    Lazy::new(Default::default);
+
+fn record_usage(options: &CacheGetOptions, apply: impl FnOnce(&mut CacheKeyUsage)) {
+    let mut usage = USAGE.lock();
+    apply(
+        usage
+            .entry(options.topic.to_string())
+            .or_default()
+            .entry(options.key.to_string())
+            .or_default(),
+    );
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheReport {
+    pub path: PathBuf,
+    pub size_bytes: Option<u64>,
+    /// topic -> key -> usage
+    pub usage: BTreeMap<String, BTreeMap<String, CacheKeyUsage>>,
+}
+
+pub fn cache_report() -> CacheReport {
+    let path = cache_file_name();
+    CacheReport {
+        size_bytes: std::fs::metadata(&path).ok().map(|m| m.len()),
+        path,
+        usage: USAGE.lock().clone(),
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct CacheEntry<T> {
     expires: DateTime<Utc>,
@@ -75,6 +261,10 @@ pub struct CacheGetOptions<'a> {
     pub hard_ttl: Duration,
     pub negative_ttl: Duration,
     pub allow_stale: bool,
+    /// When a maintenance window is configured, an expired entry is
+    /// used as-is outside of the window, rather than being refreshed,
+    /// so that the refresh happens during the window instead
+    pub defer_to_maintenance: bool,
 }
 
 pub enum CacheComputeResult<T> {
@@ -96,6 +286,40 @@ pub fn invalidate_key(topic: &str, key: &str) -> anyhow::Result<()> {
     Ok(topic.delete(key)?)
 }
 
+/// Store a value directly, rather than computing it via cache_get
+pub fn cache_put<T: Serialize>(
+    topic: &str,
+    key: &str,
+    value: &T,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    let topic = CACHE.load().topic(topic)?;
+    let data = serde_json::to_vec(value)?;
+    Ok(topic.set(key, &data, ttl)?)
+}
+
+/// Store a value such that a subsequent cache_get for the same
+/// key will return it, rather than computing it
+#[cfg(test)]
+pub fn cache_seed<T: Serialize>(topic: &str, key: &str, value: T) -> anyhow::Result<()> {
+    let entry = CacheEntry {
+        expires: Utc::now() + chrono::Duration::days(365),
+        result: CacheResult::Ok(value),
+    };
+    let topic = CACHE.load().topic(topic)?;
+    let data = serde_json::to_vec(&entry)?;
+    Ok(topic.set(key, &data, Duration::from_secs(86400 * 365))?)
+}
+
+/// Retrieve a value that was stored via cache_put
+pub fn cache_peek<T: DeserializeOwned>(topic: &str, key: &str) -> anyhow::Result<Option<T>> {
+    let topic = CACHE.load().topic(topic)?;
+    match topic.get(key)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value.data)?)),
+        None => Ok(None),
+    }
+}
+
 /// Cache an item with a soft TTL; we'll retry the operation
 /// if the TTL has expired, but allow stale reads
 pub async fn cache_get<T, Fut>(options: CacheGetOptions<'_>, future: Fut) -> anyhow::Result<T>
@@ -112,8 +336,15 @@ where
     if let Some(current) = &current_value {
         match serde_json::from_slice::<CacheEntry<T>>(&current.data) {
             Ok(entry) => {
-                if now < entry.expires {
+                let deferred = options.defer_to_maintenance
+                    && matches!(entry.result, CacheResult::Ok(_))
+                    && defer_refresh();
+                if now < entry.expires || deferred {
                     log::trace!("cache hit for {}", options.key);
+                    record_usage(&options, |usage| {
+                        usage.hits += 1;
+                        usage.expires.replace(entry.expires);
+                    });
                     return entry.result.into_result();
                 }
 
@@ -130,6 +361,15 @@ where
 
     log::trace!("cache miss for {}", options.key);
     let value: anyhow::Result<CacheComputeResult<T>> = future.await;
+    record_usage(&options, |usage| {
+        usage.misses += 1;
+        usage.expires.replace(match &value {
+            Ok(CacheComputeResult::WithTtl(_, ttl)) => Utc::now() + *ttl,
+            Ok(CacheComputeResult::Value(_)) => Utc::now() + options.soft_ttl,
+            Err(_) => Utc::now() + options.negative_ttl,
+        });
+        usage.last_error = value.as_ref().err().map(|err| format!("{err:#}"));
+    });
     match value {
         Ok(CacheComputeResult::WithTtl(value, ttl)) => {
             let entry = CacheEntry {
@@ -0,0 +1,43 @@
+use crate::commands::serve::ServeCommand;
+use crate::service::config_file::load_config_file;
+use clap::Parser;
+
+/// Work with the configuration file passed via --config
+#[derive(clap::Parser, Debug)]
+pub struct ConfigCommand {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(clap::Parser, Debug)]
+enum SubCommand {
+    /// Check that the configuration file can be parsed, and that
+    /// the options it sets, together with those set on the command
+    /// line and in the environment, are valid
+    Validate,
+}
+
+impl ConfigCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        match &self.cmd {
+            SubCommand::Validate => {
+                let path = args
+                    .config_file()?
+                    .ok_or_else(|| anyhow::anyhow!("No configuration file; pass --config"))?;
+                let options = load_config_file(&path)?;
+
+                // The file has already been applied to the environment,
+                // so the options can be checked the same way that the
+                // service checks them when it starts
+                ServeCommand::try_parse_from(["serve"])?.check_options(args)?;
+
+                println!(
+                    "{} is valid, and sets {} options",
+                    path.display(),
+                    options.len()
+                );
+                Ok(())
+            }
+        }
+    }
+}
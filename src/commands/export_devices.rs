@@ -0,0 +1,281 @@
+use crate::commands::list::load_devices;
+use crate::service::device::Device;
+use crate::service::device_graph::DeviceGraph;
+use crate::service::inventory::{inventory, inventory_csv};
+use serde::Serialize;
+
+/// Produce a report of the known devices and their capabilities,
+/// suitable for attaching to a GitHub issue.
+#[derive(clap::Parser, Debug)]
+pub struct ExportDevicesCommand {
+    #[arg(long)]
+    skip_lan: bool,
+
+    /// Mask device ids, MAC and IP addresses in the output
+    #[arg(long)]
+    redact: bool,
+
+    /// The output format
+    #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+    format: ExportFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    Json,
+    Markdown,
+    /// The outlet and feast relationships between devices
+    GraphJson,
+    /// As graph-json, but in the graphviz DOT language
+    GraphDot,
+    /// An asset inventory of names, MAC addresses and firmware versions
+    InventoryJson,
+    /// As inventory-json, but as CSV
+    InventoryCsv,
+}
+
+#[derive(Serialize, Debug)]
+struct ExportedDevice {
+    sku: String,
+    id: String,
+    name: String,
+    room: Option<String>,
+    ip: Option<String>,
+    device_type: String,
+    wifi_mac: Option<String>,
+    firmware: Firmware,
+    transports: Vec<&'static str>,
+    capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct Firmware {
+    wifi_soft: Option<String>,
+    wifi_hard: Option<String>,
+    ble_soft: Option<String>,
+    ble_hard: Option<String>,
+    mcu_soft: Option<String>,
+    mcu_hard: Option<String>,
+}
+
+/// Mask all but the last two octets of a MAC-like identifier,
+/// so that devices can still be told apart in the report.
+fn redact_id(id: &str) -> String {
+    let octets: Vec<&str> = id.split(':').collect();
+    if octets.len() <= 2 {
+        return "REDACTED".to_string();
+    }
+    let keep = octets.len() - 2;
+    octets
+        .iter()
+        .enumerate()
+        .map(|(idx, o)| if idx < keep { "XX" } else { o })
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Mask the device ids in the graph; feast ids are left
+/// alone as they are not MAC-like
+fn redact_graph(graph: &mut DeviceGraph) {
+    let redact = |id: &mut String| {
+        if id.contains(':') {
+            *id = redact_id(id);
+        }
+    };
+    for node in &mut graph.nodes {
+        redact(&mut node.id);
+    }
+    for edge in &mut graph.edges {
+        redact(&mut edge.from);
+        redact(&mut edge.to);
+    }
+}
+
+impl ExportedDevice {
+    fn new(device: &Device, redact: bool) -> Self {
+        let mut firmware = Firmware::default();
+        let mut wifi_mac = None;
+
+        if let Some(info) = &device.undoc_device_info {
+            let settings = &info.entry.device_ext.device_settings;
+            firmware.wifi_soft = settings.wifi_soft_version.clone();
+            firmware.wifi_hard = settings.wifi_hard_version.clone();
+            firmware.mcu_soft = settings.mcu_soft_version.clone();
+            firmware.mcu_hard = settings.mcu_hard_version.clone();
+            firmware.ble_soft = Some(info.entry.version_soft.clone());
+            firmware.ble_hard = Some(info.entry.version_hard.clone());
+            wifi_mac = settings.wifi_mac.clone();
+        }
+        if let Some(lan) = &device.lan_device {
+            firmware.wifi_soft = Some(lan.wifi_version_soft.clone());
+            firmware.wifi_hard = Some(lan.wifi_version_hard.clone());
+            firmware.ble_soft = Some(lan.ble_version_soft.clone());
+            firmware.ble_hard = Some(lan.ble_version_hard.clone());
+        }
+
+        let mut transports = vec![];
+        if device.lan_device.is_some() {
+            transports.push("lan");
+        }
+        if device.iot_api_supported() {
+            transports.push("iot");
+        }
+        if device.http_device_info.is_some() {
+            transports.push("platform");
+        }
+        if device.is_ble_only_device() == Some(true) {
+            transports.push("ble");
+        }
+
+        let capabilities = device
+            .http_device_info
+            .as_ref()
+            .map(|info| {
+                info.capabilities
+                    .iter()
+                    .map(|cap| format!("{}:{}", cap.kind, cap.instance))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let ip = device.ip_addr().map(|ip| {
+            if redact {
+                "REDACTED".to_string()
+            } else {
+                ip.to_string()
+            }
+        });
+
+        Self {
+            sku: device.sku.clone(),
+            id: if redact {
+                redact_id(&device.id)
+            } else {
+                device.id.clone()
+            },
+            name: device.name(),
+            room: device.room_name().map(|r| r.to_string()),
+            ip,
+            device_type: device.device_type().to_string(),
+            wifi_mac: wifi_mac.map(|mac| if redact { redact_id(&mac) } else { mac }),
+            firmware,
+            transports,
+            capabilities,
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        fn opt(s: &Option<String>) -> &str {
+            s.as_deref().unwrap_or("-")
+        }
+
+        let fw = &self.firmware;
+        let mut md = format!("### {} ({})\n\n", self.name, self.sku);
+        md.push_str("| Field | Value |\n|---|---|\n");
+        md.push_str(&format!("| id | `{}` |\n", self.id));
+        md.push_str(&format!("| room | {} |\n", opt(&self.room)));
+        md.push_str(&format!("| ip | {} |\n", opt(&self.ip)));
+        md.push_str(&format!("| type | {} |\n", self.device_type));
+        md.push_str(&format!("| wifi mac | {} |\n", opt(&self.wifi_mac)));
+        md.push_str(&format!(
+            "| wifi firmware | {} / {} |\n",
+            opt(&fw.wifi_soft),
+            opt(&fw.wifi_hard)
+        ));
+        md.push_str(&format!(
+            "| ble firmware | {} / {} |\n",
+            opt(&fw.ble_soft),
+            opt(&fw.ble_hard)
+        ));
+        md.push_str(&format!(
+            "| mcu firmware | {} / {} |\n",
+            opt(&fw.mcu_soft),
+            opt(&fw.mcu_hard)
+        ));
+        md.push_str(&format!(
+            "| transports | {} |\n",
+            self.transports.join(", ")
+        ));
+        md.push_str(&format!(
+            "| capabilities | {} |\n",
+            if self.capabilities.is_empty() {
+                "-".to_string()
+            } else {
+                self.capabilities.join(", ")
+            }
+        ));
+        md
+    }
+}
+
+impl ExportDevicesCommand {
+    async fn device_graph(
+        &self,
+        args: &crate::Args,
+        devices: &[Device],
+    ) -> anyhow::Result<DeviceGraph> {
+        let mut feasts = vec![];
+        if let Ok(client) = args.undoc_args.api_client() {
+            feasts = client.parse_feasts().await?;
+        }
+        let mut graph = DeviceGraph::build(devices, &feasts);
+        if self.redact {
+            redact_graph(&mut graph);
+        }
+        Ok(graph)
+    }
+
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = load_devices(args, self.skip_lan).await?;
+
+        let mut devices = state.devices().await;
+        devices.sort_by_key(|d| (d.sku.clone(), d.id.clone()));
+
+        let exported: Vec<ExportedDevice> = devices
+            .iter()
+            .map(|d| ExportedDevice::new(d, self.redact))
+            .collect();
+
+        match self.format {
+            ExportFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&exported)?);
+            }
+            ExportFormat::Markdown => {
+                println!("## govee2mqtt {}\n", crate::version_info::govee_version());
+                for d in &exported {
+                    println!("{}", d.to_markdown());
+                }
+            }
+            ExportFormat::GraphJson => {
+                let graph = self.device_graph(args, &devices).await?;
+                println!("{}", serde_json::to_string_pretty(&graph)?);
+            }
+            ExportFormat::GraphDot => {
+                let graph = self.device_graph(args, &devices).await?;
+                print!("{}", graph.to_dot());
+            }
+            ExportFormat::InventoryJson => {
+                println!("{}", serde_json::to_string_pretty(&inventory(&devices))?);
+            }
+            ExportFormat::InventoryCsv => {
+                print!("{}", inventory_csv(&inventory(&devices)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redaction() {
+        assert_eq!(
+            redact_id("AA:BB:CC:DD:EE:FF:12:34"),
+            "XX:XX:XX:XX:XX:XX:12:34"
+        );
+        assert_eq!(redact_id("a1b2c3d4e5f6"), "REDACTED");
+    }
+}
@@ -1,9 +1,12 @@
 use crate::ble::{Base64HexBytes, SetSceneCode};
-use crate::lan_api::{Client, DiscoOptions};
+use crate::lan_api::{save_lan_token, Client, DiscoOptions, LanCommand};
+use crate::scene_param::{decoder_for_sku, scene_param_packets};
+use crate::service::supervisor::Supervisor;
 use crate::undoc_api::GoveeUndocumentedApi;
 use clap_num::maybe_hex;
 use std::collections::BTreeMap;
 use std::net::IpAddr;
+use std::time::Duration;
 use uncased::Uncased;
 
 #[derive(clap::Parser, Debug)]
@@ -36,6 +39,20 @@ enum SubCommand {
         #[arg(value_parser=maybe_hex::<u8>)]
         data: Vec<u8>,
     },
+    /// Send an arbitrary LAN API command, eg: `devStatus '{}'`
+    Raw {
+        cmd: String,
+        /// JSON payload for the command
+        data: String,
+    },
+    /// Pair with a device whose firmware requires a token before
+    /// it will accept LAN commands. The token is saved and used
+    /// for subsequent LAN control of the device.
+    Pair {
+        /// How many seconds to wait for pairing to be confirmed
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+    },
     Scene {
         /// List available scenes
         #[arg(long)]
@@ -44,12 +61,17 @@ enum SubCommand {
         /// Name of a scene to activate
         #[arg(required_unless_present = "list")]
         scene: Option<String>,
+
+        /// Decode the scene param and send it ahead of the scene
+        /// code, for devices whose scene params we understand
+        #[arg(long)]
+        with_param: bool,
     },
 }
 
 impl LanControlCommand {
     pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
-        let (client, _scan) = Client::new(DiscoOptions::default()).await?;
+        let (client, _scan) = Client::new(DiscoOptions::default(), &Supervisor::default()).await?;
 
         let device = client.scan_ip(self.ip).await?;
 
@@ -72,38 +94,70 @@ impl LanControlCommand {
                     .send_color_rgb(crate::lan_api::DeviceColor { r, g, b })
                     .await?;
             }
-            SubCommand::Scene { list, scene } => {
-                let mut scene_code_by_name = BTreeMap::new();
+            SubCommand::Scene {
+                list,
+                scene,
+                with_param,
+            } => {
+                let mut effect_by_name = BTreeMap::new();
                 for category in GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await? {
                     for scene in category.scenes {
                         for effect in scene.light_effects {
                             if effect.scene_code != 0 {
-                                scene_code_by_name
-                                    .insert(Uncased::new(scene.scene_name), effect.scene_code);
+                                effect_by_name.insert(Uncased::new(scene.scene_name), effect);
                                 break;
                             }
                         }
                     }
                 }
                 if *list {
-                    for name in scene_code_by_name.keys() {
+                    for name in effect_by_name.keys() {
                         println!("{name}");
                     }
                 } else {
                     let scene = Uncased::new(scene.clone().expect("scene if not list"));
-                    if let Some(code) = scene_code_by_name.get(&scene) {
+                    let Some(effect) = effect_by_name.get(&scene) else {
+                        anyhow::bail!("scene {scene} not found");
+                    };
+                    if *with_param {
+                        let decoder = decoder_for_sku(&device.sku).ok_or_else(|| {
+                            anyhow::anyhow!("no scene param decoder for {}", device.sku)
+                        })?;
+                        let param = decoder.decode(effect)?;
+                        println!("Decoded {} param: {param:?}", decoder.family());
+                        let packets = scene_param_packets(decoder, &param, effect.scene_code)?;
+                        device.send_real(packets).await?;
+                    } else {
                         let encoded = Base64HexBytes::encode_for_sku(
                             "Generic:Light",
-                            &SetSceneCode { code: *code },
+                            &SetSceneCode {
+                                code: effect.scene_code,
+                            },
                         )?
                         .base64();
                         println!("Computed {encoded}");
                         device.send_real(vec![encoded]).await?;
-                    } else {
-                        anyhow::bail!("scene {scene} not found");
                     }
                 }
             }
+            SubCommand::Pair { timeout } => {
+                println!(
+                    "Requesting pairing with {} {}. Confirm the request on the \
+                    device, or in the Govee Home app, within {timeout} seconds.",
+                    device.sku, device.device
+                );
+                let token = client.pair(&device, Duration::from_secs(*timeout)).await?;
+                let path = save_lan_token(&device.device, &token)?;
+                println!("Paired successfully. The token was saved to {path:?}");
+            }
+            SubCommand::Raw { cmd, data } => {
+                device
+                    .send_command(LanCommand::Custom {
+                        cmd: cmd.to_string(),
+                        data: serde_json::from_str(data)?,
+                    })
+                    .await?;
+            }
             SubCommand::Command { data } => {
                 let encoded = Base64HexBytes::with_bytes(data.to_vec()).base64();
                 println!("encoded: {encoded}");
@@ -11,14 +11,14 @@ impl LanDiscoCommand {
             anyhow::bail!("Discovery options are empty");
         }
 
-        let (client, mut scan) = Client::new(options).await?;
+        let state = crate::service::state::State::new();
 
-        let deadline = Instant::now() + Duration::from_secs(args.lan_disco_args.disco_timeout()?);
+        let (client, mut scan) = Client::new(options, state.supervisor()).await?;
 
-        let state = crate::service::state::State::new();
+        let deadline = Instant::now() + Duration::from_secs(args.lan_disco_args.disco_timeout()?);
 
         while let Ok(Some(lan_device)) = tokio::time::timeout_at(deadline, scan.recv()).await {
-            if !state.device_by_id(&lan_device.device).await.is_some() {
+            if state.device_by_id(&lan_device.device).await.is_none() {
                 let mut device = state.device_mut(&lan_device.sku, &lan_device.device).await;
 
                 device.set_lan_device(lan_device.clone());
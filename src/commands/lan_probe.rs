@@ -0,0 +1,62 @@
+use crate::lan_api::{Client, DiscoOptions};
+use crate::service::supervisor::Supervisor;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Send a discovery packet directly to each of the given addresses,
+/// and report which of them respond as Govee devices, and via which
+/// local interface. This is useful for checking whether devices on
+/// another VLAN or subnet can be reached before adding them to the
+/// scan list.
+#[derive(clap::Parser, Debug)]
+pub struct LanProbeCommand {
+    #[arg(required = true)]
+    ip: Vec<IpAddr>,
+}
+
+impl LanProbeCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        // Only the probed addresses are scanned, so that
+        // any response comes from one of them
+        let (client, _scan) = Client::new(
+            DiscoOptions {
+                enable_multicast: false,
+                ..DiscoOptions::default()
+            },
+            &Supervisor::default(),
+        )
+        .await?;
+
+        let mut failed = false;
+        for ip in &self.ip {
+            let started = Instant::now();
+            match client.scan_ip(*ip).await {
+                Ok(device) => {
+                    let via = client
+                        .discovery_for_device(&device.device)
+                        .and_then(|d| d.interface)
+                        .unwrap_or_else(|| "a router".to_string());
+                    println!(
+                        "{ip:<15} {sku:<6} {id} responded in {rtt:?} via {via}",
+                        sku = device.sku,
+                        id = device.device,
+                        rtt = started.elapsed(),
+                    );
+                }
+                Err(err) => {
+                    failed = true;
+                    println!("{ip:<15} no response: {err:#}");
+                }
+            }
+        }
+
+        if failed {
+            anyhow::bail!(
+                "Some addresses did not respond. Check that the LAN API is enabled \
+                for those devices in the Govee Home app, and that UDP ports 4001 \
+                and 4002 are not blocked between them and this machine"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::undoc_api::{GoveeUndocumentedApi, SavedLogin};
+use std::path::PathBuf;
+
+/// Log in to your Govee account and save the resulting token
+/// and AWS IoT certificate, so that `govee serve` can be run
+/// with `--govee-token-file` rather than your account password.
+#[derive(clap::Parser, Debug)]
+pub struct LoginCommand {
+    /// Where to save the token
+    #[arg(long, default_value = "govee-token.json")]
+    output: PathBuf,
+}
+
+impl LoginCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let email = args.undoc_args.email()?;
+        let client = GoveeUndocumentedApi::new(&email, args.undoc_args.password()?);
+
+        let account = client.login_account().await?;
+        let iot_key = client.get_iot_key(&account.token).await?;
+        let community_token = match client.login_community().await {
+            Ok(token) => Some(token),
+            Err(err) => {
+                log::warn!("Unable to obtain community token: {err:#}");
+                log::warn!("One-click shortcuts will not be available");
+                None
+            }
+        };
+
+        SavedLogin::new(email, account, iot_key, community_token).save(&self.output)?;
+
+        println!(
+            "Saved login to {}. You can now pass --govee-token-file {} \
+            (or set GOVEE_TOKEN_FILE) instead of your account password.",
+            self.output.display(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
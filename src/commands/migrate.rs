@@ -0,0 +1,182 @@
+use crate::service::device::normalize_device_id;
+use crate::service::entity_overrides::EntityIdOverrides;
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Produce the entity id overrides that let govee2mqtt take over the
+/// light entities of the Govee cloud integration, so that their
+/// history carries on after switching to govee2mqtt.
+#[derive(clap::Parser, Debug)]
+pub struct MigrateFromCloudIntegrationCommand {
+    /// The entity registry exported from Home Assistant; the
+    /// `.storage/core.entity_registry` file in its config directory
+    registry: PathBuf,
+
+    /// The platform of the entities to migrate
+    #[arg(long, default_value = "govee")]
+    platform: String,
+
+    /// Where to write the overrides. Prints them if not specified.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RegistryEntity {
+    entity_id: String,
+    platform: String,
+    unique_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RegistryData {
+    entities: Vec<RegistryEntity>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Registry {
+    /// The `.storage/core.entity_registry` file
+    Storage { data: RegistryData },
+    /// Just the list of entities
+    Entities(Vec<RegistryEntity>),
+}
+
+impl Registry {
+    fn entities(self) -> Vec<RegistryEntity> {
+        match self {
+            Self::Storage { data } => data.entities,
+            Self::Entities(entities) => entities,
+        }
+    }
+}
+
+/// Find the device id within the unique id of an entity. The cloud
+/// integration has used both the bare device id and forms with a
+/// prefix or suffix around it, so look for a run of at least six
+/// hex bytes, with or without colons.
+fn device_id_from_unique_id(unique_id: &str) -> Option<String> {
+    unique_id
+        .split(|c: char| !(c.is_ascii_hexdigit() || c == ':'))
+        .map(|token| token.trim_matches(':'))
+        .filter(|token| token.chars().filter(|c| c.is_ascii_hexdigit()).count() >= 12)
+        .map(normalize_device_id)
+        .find(|id| id.contains(':'))
+}
+
+/// Map the light entities of the cloud integration to the unique ids
+/// of our light entities. Returns the overrides, along with the
+/// entities that could not be matched.
+fn build_overrides(
+    entities: Vec<RegistryEntity>,
+    platform: &str,
+) -> (EntityIdOverrides, Vec<RegistryEntity>) {
+    let mut overrides = EntityIdOverrides::new();
+    let mut unmatched = vec![];
+    for entity in entities {
+        if entity.platform != platform || !entity.entity_id.starts_with("light.") {
+            continue;
+        }
+        match device_id_from_unique_id(&entity.unique_id) {
+            Some(id) => {
+                // Matches the unique id of the light in hass_mqtt::light
+                let unique_id = format!("gv2mqtt-{}", id.replace(':', ""));
+                overrides.insert(unique_id, entity.entity_id);
+            }
+            None => unmatched.push(entity),
+        }
+    }
+    (overrides, unmatched)
+}
+
+impl MigrateFromCloudIntegrationCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let data = std::fs::read_to_string(&self.registry)
+            .with_context(|| format!("reading {}", self.registry.display()))?;
+        let registry: Registry = serde_json::from_str(&data)
+            .with_context(|| format!("parsing {}", self.registry.display()))?;
+
+        let (overrides, unmatched) = build_overrides(registry.entities(), &self.platform);
+        anyhow::ensure!(
+            !overrides.is_empty(),
+            "No {} light entities were found in {}",
+            self.platform,
+            self.registry.display()
+        );
+
+        for entity in &unmatched {
+            eprintln!(
+                "Unable to find a device id in the unique id {} of {}; skipping it",
+                entity.unique_id, entity.entity_id
+            );
+        }
+
+        let json = serde_json::to_string_pretty(&overrides)?;
+        match &self.output {
+            Some(path) => {
+                std::fs::write(path, json)
+                    .with_context(|| format!("writing {}", path.display()))?;
+                eprintln!(
+                    "Wrote {} entity id overrides to {}",
+                    overrides.len(),
+                    path.display()
+                );
+            }
+            None => println!("{json}"),
+        }
+        eprintln!(
+            "Remove the {} integration from Home Assistant before starting \
+            govee2mqtt with GOVEE_ENTITY_ID_OVERRIDES set to the path of the \
+            overrides, so that the entity ids are free to be taken over.",
+            self.platform
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_registry_entities() {
+        let registry: Registry = serde_json::from_str(
+            r#"{
+              "version": 1,
+              "data": {
+                "entities": [
+                  {"entity_id": "light.floor_lamp", "platform": "govee",
+                   "unique_id": "AA:BB:CC:DD:EE:FF:00:11"},
+                  {"entity_id": "light.strip", "platform": "govee",
+                   "unique_id": "govee_aabbccddeeff0022_light"},
+                  {"entity_id": "light.mystery", "platform": "govee",
+                   "unique_id": "mystery"},
+                  {"entity_id": "sensor.strip_rssi", "platform": "govee",
+                   "unique_id": "AA:BB:CC:DD:EE:FF:00:22_rssi"},
+                  {"entity_id": "light.hue", "platform": "hue",
+                   "unique_id": "00:17:88:01:00:bd:c7:b9-0b"}
+                ]
+              }
+            }"#,
+        )
+        .unwrap();
+
+        let (overrides, unmatched) = build_overrides(registry.entities(), "govee");
+        assert_eq!(
+            overrides,
+            EntityIdOverrides::from([
+                (
+                    "gv2mqtt-AABBCCDDEEFF0011".to_string(),
+                    "light.floor_lamp".to_string()
+                ),
+                (
+                    "gv2mqtt-AABBCCDDEEFF0022".to_string(),
+                    "light.strip".to_string()
+                ),
+            ])
+        );
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(unmatched[0].entity_id, "light.mystery");
+    }
+}
@@ -1,7 +1,24 @@
+pub mod config;
+pub mod export_devices;
+#[cfg(feature = "cloud")]
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
+pub mod lan_probe;
 pub mod list;
+#[cfg(feature = "cloud")]
 pub mod list_http;
+#[cfg(feature = "cloud")]
+pub mod login;
+pub mod migrate;
+#[cfg(feature = "cloud")]
+pub mod quirks;
+pub mod scene_search;
+#[cfg(feature = "cloud")]
+pub mod schema_drift;
 pub mod serve;
+pub mod support_matrix;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "cloud")]
 pub mod undoc;
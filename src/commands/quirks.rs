@@ -0,0 +1,132 @@
+use crate::service::quirk_bundle::{
+    bundle_dir, is_trusted, load_or_create_signing_key, QuirkBundle, QuirkSpec,
+};
+use crate::service::quirks::resolve_quirk;
+use anyhow::Context;
+use std::path::PathBuf;
+
+const DESCRIBE_IT: &str = "Describe it in a file and pass it via --from";
+
+/// Share support for devices as signed quirk bundles
+#[derive(clap::Parser, Debug)]
+pub struct QuirksCommand {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(clap::Parser, Debug)]
+enum SubCommand {
+    /// Produce a signed bundle holding the quirks for the given SKUs
+    Export {
+        /// The SKUs to include
+        #[arg(required = true)]
+        sku: Vec<String>,
+
+        /// A JSON file holding a list of quirks to sign, in place
+        /// of the quirks that govee2mqtt already knows about.
+        /// Use the output of `export` as a starting point.
+        #[arg(long)]
+        from: Option<PathBuf>,
+
+        /// The ed25519 key used to sign the bundle.
+        /// A new key is created if it doesn't already exist.
+        #[arg(long, default_value = "govee-quirks-signing.key")]
+        signing_key: PathBuf,
+
+        /// Where to write the bundle. Prints it if not specified.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Verify a bundle and install it into the quirks directory,
+    /// so that it is loaded the next time govee2mqtt starts
+    Import { bundle: PathBuf },
+}
+
+impl QuirksCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        match &self.cmd {
+            SubCommand::Export {
+                sku,
+                from,
+                signing_key,
+                output,
+            } => {
+                let quirks: Vec<QuirkSpec> = match from {
+                    Some(path) => {
+                        let data = std::fs::read(path)
+                            .with_context(|| format!("reading {}", path.display()))?;
+                        let specs: Vec<QuirkSpec> = serde_json::from_slice(&data)
+                            .with_context(|| format!("parsing {}", path.display()))?;
+                        specs
+                            .into_iter()
+                            .filter(|spec| sku.contains(&spec.sku))
+                            .collect()
+                    }
+                    None => sku
+                        .iter()
+                        .map(|sku| {
+                            resolve_quirk(sku)
+                                .map(QuirkSpec::from_quirk)
+                                .ok_or_else(|| {
+                                    anyhow::anyhow!("There are no quirks for {sku}. {DESCRIBE_IT}")
+                                })
+                        })
+                        .collect::<anyhow::Result<_>>()?,
+                };
+                anyhow::ensure!(!quirks.is_empty(), "no quirks matched {sku:?}");
+
+                let key = load_or_create_signing_key(signing_key)?;
+                let bundle = QuirkBundle::sign(quirks, &key)?;
+                let json = serde_json::to_string_pretty(&bundle)?;
+                match output {
+                    Some(path) => {
+                        std::fs::write(path, json)
+                            .with_context(|| format!("writing {}", path.display()))?;
+                        eprintln!(
+                            "Wrote bundle to {}, signed by {}",
+                            path.display(),
+                            bundle.signer
+                        );
+                    }
+                    None => println!("{json}"),
+                }
+            }
+            SubCommand::Import { bundle: path } => {
+                let bundle = QuirkBundle::load(path)?;
+                if !is_trusted(&bundle) {
+                    anyhow::bail!(
+                        "The bundle is signed by {}, which is not \
+                        listed in $GOVEE_TRUSTED_QUIRK_SIGNERS. Add it there \
+                        if you trust the person that shared it with you.",
+                        bundle.signer
+                    );
+                }
+
+                let dir = bundle_dir();
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("creating {}", dir.display()))?;
+                let file_name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?;
+                let dest = dir.join(file_name).with_extension("json");
+                std::fs::write(&dest, serde_json::to_string_pretty(&bundle)?)
+                    .with_context(|| format!("writing {}", dest.display()))?;
+
+                let skus: Vec<&str> = bundle
+                    .payload
+                    .quirks
+                    .iter()
+                    .map(|q| q.sku.as_str())
+                    .collect();
+                println!(
+                    "Installed quirks for {} from {}, signed by {}. \
+                    Restart govee2mqtt to use them.",
+                    skus.join(", "),
+                    dest.display(),
+                    bundle.signer
+                );
+            }
+        }
+        Ok(())
+    }
+}
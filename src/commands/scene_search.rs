@@ -0,0 +1,53 @@
+use crate::commands::list::load_devices;
+use crate::service::scene_search::{search_scenes, SceneQuery};
+
+/// Search the scene catalogs of all of your devices, and show
+/// which devices support each of the matching scenes
+#[derive(clap::Parser, Debug)]
+pub struct SceneSearchCommand {
+    #[arg(long)]
+    skip_lan: bool,
+
+    /// Only show scenes in categories whose name contains this text
+    #[arg(long)]
+    category: Option<String>,
+
+    /// Output the results as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Only show scenes whose name contains this text
+    keyword: Option<String>,
+}
+
+impl SceneSearchCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = load_devices(args, self.skip_lan).await?;
+        let devices = state.devices().await;
+
+        let query = SceneQuery {
+            keyword: self.keyword.clone(),
+            category: self.category.clone(),
+        };
+        let matches = search_scenes(&devices, &query).await?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&matches)?);
+            return Ok(());
+        }
+
+        for m in matches {
+            println!("{} ({})", m.name, m.categories.join(", "));
+            for d in m.devices {
+                println!(
+                    "    {sku:<7} {id} {name}",
+                    sku = d.sku,
+                    id = d.id,
+                    name = d.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use crate::commands::list::load_devices;
+use crate::undoc_api::GoveeUndocumentedApi;
+use std::collections::BTreeSet;
+
+/// Query the Govee APIs and report any differences between the
+/// data that they returned and what govee2mqtt expects.
+/// The output is suitable for including in an issue report.
+#[derive(clap::Parser, Debug)]
+pub struct SchemaDriftCommand {
+    #[arg(long)]
+    skip_lan: bool,
+}
+
+impl SchemaDriftCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = load_devices(args, self.skip_lan).await?;
+
+        // Scene catalogs are a frequent source of drift, so
+        // check those too
+        let skus: BTreeSet<String> = state.devices().await.into_iter().map(|d| d.sku).collect();
+        for sku in skus {
+            if let Err(err) = GoveeUndocumentedApi::get_scenes_for_device(&sku).await {
+                log::warn!("Failed to fetch scenes for {sku}: {err:#}");
+            }
+        }
+
+        let report = crate::schema_drift::report();
+        if report.is_empty() {
+            println!("No schema drift was observed");
+        } else {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Ok(())
+    }
+}
@@ -1,35 +1,229 @@
-use crate::lan_api::Client as LanClient;
+use crate::hass_mqtt::binary_sensor::IotLinkDiagnostic;
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::sensor::{CloudRateLimitDiagnostic, TaskHealthDiagnostic};
+use crate::lan_api::{truthy, Client as LanClient};
+use crate::opt_env_var;
+use crate::platform_api::GoveeApiClient;
+use crate::service::auto_brightness::{
+    parse_auto_brightness_rules, periodic_auto_brightness, AutoBrightnessRule,
+};
+use crate::service::capability_probe::probe_capabilities;
+use crate::service::cloud_failure::{
+    parse_cloud_failure_rules, set_cloud_failure_rules, CloudFailureRule,
+};
+use crate::service::cloud_health::{self, CloudUnavailable, CLOUD_STATUS_TOPIC};
+use crate::service::cloud_pacing::{cloud_pacing, paced_sleep, set_cloud_pacing, CloudPacing};
+use crate::service::command_audit::{self, COMMAND_AUDIT_TOPIC};
+use crate::service::command_queue::{set_command_queue_config, CommandQueueConfig};
+use crate::service::daily_window::DailyWindow;
 use crate::service::device::Device;
-use crate::service::hass::spawn_hass_integration;
+use crate::service::firmware_watch::periodic_firmware_watch;
+use crate::service::hass::{schedule_reregistration, spawn_hass_integration};
 use crate::service::http::run_http_server;
-use crate::service::iot::start_iot_client;
+#[cfg(feature = "cloud")]
+use crate::service::iot::{connect_iot_client, start_iot_client};
+use crate::service::iot_health;
+use crate::service::playlist::run_playlists;
+use crate::service::poll_intervals::{
+    load_poll_interval_rules, parse_poll_interval_rules, poll_interval_for, poll_tick,
+    set_poll_interval_rules, PollApi, PollIntervalRule, DEFAULT_LAN_INTERVAL,
+};
+use crate::service::power_save::{power_save, set_power_save, wait_while_power_save};
+use crate::service::preset_schedule::{
+    parse_preset_schedule_rules, periodic_preset_schedule, PresetScheduleRule,
+};
+use crate::service::shard::{owns_device, set_shard_config};
+use crate::service::stall_detector::spawn_stall_detector;
+#[cfg(feature = "cloud")]
+use crate::service::state::AccountClients;
 use crate::service::state::StateHandle;
+use crate::service::state_merge::{set_state_merge_policy, StateMergePolicy};
+use crate::service::state_snapshot::{periodic_state_snapshot, restore_device_states};
+use crate::service::supervisor::RestartPolicy;
+use crate::service::systemd;
+use crate::service::watchdog::{
+    parse_watchdog_rules, periodic_watchdog, set_watchdog_rules, WatchdogRule,
+};
+#[cfg(feature = "cloud")]
+use crate::undoc_api::GoveeAccount;
+use crate::undoc_api::GoveeUndocumentedApi;
+use crate::undoc_api::{DeviceEntry, FirmwareUpdate, LoginAccountResponse};
 use crate::version_info::govee_version;
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{DateTime, Local, Utc};
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use tokio::time::{sleep, Duration};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+pub static POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
     /// The port on which the HTTP API will listen
     #[arg(long, default_value_t = 8056)]
     http_port: u16,
+
+    /// A daily window of local time, in the form HH:MM-HH:MM, during
+    /// which to refresh the device list, scene catalogs, device
+    /// capabilities and the AWS IoT certificate.
+    /// You may also set this via the GOVEE_MAINTENANCE_WINDOW
+    /// environment variable.
+    #[arg(long)]
+    maintenance_window: Option<DailyWindow>,
+
+    /// How to choose between conflicting state reported by the
+    /// LAN API, AWS IoT and the Platform API, in the form
+    /// `lan=3,iot=2,platform=1,window=0`. Of the reports received
+    /// within `window` seconds of the most recent one, the value from
+    /// the transport with the highest trust is used.
+    /// You may also set this via the GOVEE_STATE_MERGE_POLICY
+    /// environment variable.
+    #[arg(long)]
+    state_merge_policy: Option<StateMergePolicy>,
+
+    /// Vary the intervals between recurring requests to the Govee
+    /// cloud, to make them look less automated. Either the preset
+    /// `conservative`, or a list in the form
+    /// `jitter=20,quiet=01:00-06:00,quiet_factor=3`, which adds up
+    /// to 20% of random jitter, and polls 3 times less often during
+    /// the quiet hours. Pacing is off by default.
+    /// You may also set this via the GOVEE_CLOUD_PACING
+    /// environment variable.
+    #[arg(long)]
+    cloud_pacing: Option<CloudPacing>,
+
+    /// How to queue commands for devices, in the form
+    /// `window=0.1s,lan=0s,iot=0.5s,platform=1s`. Successive commands
+    /// that change the same thing on a device within `window` are
+    /// coalesced so that only the latest is sent, and commands sent to
+    /// a device via the LAN API, AWS IoT or the Platform API are spaced
+    /// out by at least the given delay.
+    /// You may also set this via the GOVEE_COMMAND_QUEUE
+    /// environment variable.
+    #[arg(long)]
+    command_queue: Option<CommandQueueConfig>,
+
+    /// Set the interval at which devices are polled, in the form
+    /// `[TARGET][/API]=INTERVAL`. TARGET is a SKU, device id or
+    /// device name, or `*` for all devices, and API is one of
+    /// `platform`, `iot` or `lan`. eg: `H5179=30m`,
+    /// `Porch Light/platform=1h` or `lan=30s`. May be repeated;
+    /// the most specific rule for a device wins.
+    /// You may also set this via the GOVEE_POLL_INTERVALS
+    /// environment variable, separating the rules with semicolons.
+    #[arg(long)]
+    poll_interval: Vec<PollIntervalRule>,
+
+    /// A file holding poll interval rules, one per line. Lines
+    /// starting with `#` are ignored.
+    /// You may also set this via the GOVEE_POLL_INTERVALS_FILE
+    /// environment variable.
+    #[arg(long)]
+    poll_intervals_file: Option<PathBuf>,
+
+    /// What to do with a command that fails via the Govee cloud, in
+    /// the form `[TARGET=]POLICY`. POLICY is `drop`, `queue` to retry
+    /// it for up to 10 minutes, or `lan` to hold it until the device
+    /// is reachable via the LAN API. TARGET is a device class such as
+    /// `light` or `heater`, a SKU, a device id or a device name.
+    /// eg: `queue` or `heater=drop`. May be repeated; the most
+    /// specific rule for a device wins. The default is `drop`.
+    /// You may also set this via the GOVEE_CLOUD_FAILURE_POLICY
+    /// environment variable, separating the rules with semicolons.
+    #[arg(long)]
+    cloud_failure_policy: Vec<CloudFailureRule>,
+
+    /// Power-cycle a smart plug when a condition has held for a
+    /// while, in the form
+    /// `plug=Printer Plug,topic=octoprint/status,payload=offline,after=10m`
+    /// or `plug=Camera Plug,device=Camera,after=15m`. The optional
+    /// `off`, `cooldown` and `max_per_day` keys set how long the plug
+    /// is switched off, the shortest time between power cycles and
+    /// the most power cycles in a day. May be repeated.
+    /// You may also set this via the GOVEE_WATCHDOG_RULES
+    /// environment variable, separating the rules with semicolons.
+    #[arg(long)]
+    watchdog: Vec<WatchdogRule>,
+
+    /// Scale the brightness of a lamp that has an ambient light
+    /// sensor against the measured light level, in the form
+    /// `LAMP=LUX:PERCENT,LUX:PERCENT,...`, eg: `Desk Lamp=0:10,50:40,300:100`.
+    /// The brightness is interpolated between the points of the curve.
+    /// May be repeated.
+    /// You may also set this via the GOVEE_AUTO_BRIGHTNESS
+    /// environment variable, separating the rules with semicolons.
+    #[arg(long)]
+    auto_brightness: Vec<AutoBrightnessRule>,
+
+    /// Switch lamps between their native presets, such as Focus and
+    /// Relax, at set times of day, in the form
+    /// `LAMP=HH:MM PRESET,HH:MM PRESET,...`,
+    /// eg: `Desk Lamp=08:00 Focus,18:30 Relax`.
+    /// May be repeated.
+    /// You may also set this via the GOVEE_PRESET_SCHEDULE
+    /// environment variable, separating the rules with semicolons.
+    #[arg(long)]
+    preset_schedule: Vec<PresetScheduleRule>,
+
+    /// Test the capabilities that devices advertise but don't report
+    /// a state for, by setting them and reading them back. This will
+    /// change the settings of those devices. Each capability
+    /// is only tested once; capabilities that fail are registered
+    /// with Home Assistant as disabled by default.
+    /// You may also set GOVEE_PROBE_CAPABILITIES=true via the environment.
+    #[arg(long)]
+    probe_capabilities: bool,
+
+    /// Start in power save mode, which suspends the recurring polling
+    /// of devices and the Govee cloud until it is switched off via
+    /// the `gv2mqtt/power-save/set` MQTT topic.
+    /// You may also set GOVEE_POWER_SAVE=true via the environment.
+    #[arg(long)]
+    power_save: bool,
+
+    /// Listen for the advertisements of BLE-only sensors, such as
+    /// the H5075 thermometer, using the local bluetooth adapter.
+    /// You may also set GOVEE_BLE_SCAN=true via the environment.
+    #[cfg(feature = "ble")]
+    #[arg(long)]
+    ble_scan: bool,
+
+    /// The bluetooth address of a BLE sensor that isn't in your Govee
+    /// account, but whose advertisements should be read anyway.
+    /// Sensors that are in your account are always read.
+    /// May be repeated.
+    /// You may also set GOVEE_BLE_SENSORS=AA:BB:CC:DD:EE:FF,... via
+    /// the environment.
+    #[cfg(feature = "ble")]
+    #[arg(long)]
+    ble_sensor: Vec<String>,
 }
 
 async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
     let now = Utc::now();
 
     if device.is_ble_only_device() == Some(true) {
-        // We can't poll this device, we have no ble support
+        // We can't poll this device; BLE-only sensors are
+        // read passively by the BLE scanner, if enabled
         return Ok(());
     }
 
-    let poll_interval = device.preferred_poll_interval();
+    let needs_platform = device.needs_platform_poll();
+    let api = if !needs_platform && device.pollable_via_iot() {
+        PollApi::Iot
+    } else {
+        PollApi::Platform
+    };
+    let poll_interval = chrono::Duration::from_std(cloud_pacing().shape(poll_interval_for(
+        device,
+        api,
+        device.preferred_poll_interval().to_std()?,
+    )))?;
 
     let can_update = match &device.last_polled {
         None => true,
@@ -41,17 +235,17 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
     }
 
     let device_state = device.device_state();
+    let is_stale = |updated: DateTime<Utc>| now - updated > poll_interval;
     let needs_update = match &device_state {
         None => true,
-        Some(state) => now - state.updated > poll_interval,
-    };
+        Some(state) => is_stale(state.updated),
+    } || (device.has_light_sensor()
+        && device.last_http_device_state_update.is_none_or(is_stale));
 
     if !needs_update {
         return Ok(());
     }
 
-    let needs_platform = device.needs_platform_poll();
-
     // Don't interrogate via HTTP if we can use the LAN.
     // If we have LAN and the device is stale, it is likely
     // offline and there is little sense in burning up request
@@ -61,13 +255,11 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
         return Ok(());
     }
 
-    if !needs_platform {
-        if state.poll_iot_api(&device).await? {
-            return Ok(());
-        }
+    if !needs_platform && state.poll_iot_api(device).await? {
+        return Ok(());
     }
 
-    state.poll_platform_api(&device).await?;
+    state.poll_platform_api(device).await?;
 
     Ok(())
 }
@@ -75,51 +267,641 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
 async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
     sleep(Duration::from_secs(20)).await;
     loop {
-        for d in state.devices().await {
+        wait_while_power_save().await;
+        for d in state.devices().await.into_iter().filter(owns_device) {
             if let Err(err) = poll_single_device(&state, &d).await {
-                log::error!("while polling {d}: {err:#}");
+                if CloudUnavailable::is_cloud_unavailable(&err) {
+                    log::debug!("while polling {d}: {err:#}");
+                } else {
+                    log::error!("while polling {d}: {err:#}");
+                }
+            }
+        }
+
+        paced_sleep(poll_tick(Duration::from_secs(60))).await;
+    }
+}
+
+/// Periodically query the status of LAN devices so that we
+/// can maintain a picture of the quality of their connection
+async fn periodic_lan_rtt_measurement(state: StateHandle) -> anyhow::Result<()> {
+    sleep(Duration::from_secs(30)).await;
+    let mut last_measured: HashMap<String, Instant> = HashMap::new();
+    loop {
+        wait_while_power_save().await;
+        for d in state.devices().await.into_iter().filter(owns_device) {
+            if let Some(lan_device) = &d.lan_device {
+                // Allow a little slack, so that a device whose interval
+                // matches the tick isn't skipped every other time
+                let interval = poll_interval_for(&d, PollApi::Lan, DEFAULT_LAN_INTERVAL);
+                if let Some(last) = last_measured.get(&d.id) {
+                    if last.elapsed() + Duration::from_secs(1) < interval {
+                        continue;
+                    }
+                }
+                last_measured.insert(d.id.to_string(), Instant::now());
+
+                if let Err(err) = state.measure_lan_rtt(lan_device).await {
+                    log::debug!("while measuring LAN RTT for {d}: {err:#}");
+                }
             }
         }
 
+        sleep(poll_tick(DEFAULT_LAN_INTERVAL)).await;
+    }
+}
+
+/// Held while a maintenance refresh is running, so that a refresh
+/// requested via `/api/discovery` waits for the scheduled one
+/// to finish, rather than running alongside it
+static MAINTENANCE: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+/// Perform the expensive refreshes that we'd rather not do
+/// during the day while people are interacting with their devices
+pub async fn run_maintenance(state: &StateHandle) -> anyhow::Result<()> {
+    let _running = match MAINTENANCE.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            log::info!("Waiting for the running maintenance refresh to complete");
+            MAINTENANCE.lock().await
+        }
+    };
+    log::info!("Starting maintenance refresh");
+
+    let mut accounts = vec![(
+        None,
+        state.get_platform_client().await,
+        state.get_undoc_client().await,
+    )];
+    for (email, clients) in state.get_account_clients().await {
+        accounts.push((Some(email), clients.platform, clients.undoc));
+    }
+
+    for (email, platform, undoc) in accounts {
+        let email = email.as_deref();
+        if let Some(client) = platform {
+            client.invalidate_device_list()?;
+            refresh_platform_device_list(state, &client, email).await?;
+        }
+
+        if let Some(client) = undoc {
+            let acct = refresh_undoc_device_list(state, &client, email).await?;
+
+            client.invalidate_iot_key()?;
+            if let Err(err) = client.get_iot_key(&acct.token).await {
+                log::warn!(
+                    "Failed to refresh the AWS IoT certificate: {err:#}. \
+                    IoT may not be able to reconnect if it is disconnected"
+                );
+            }
+        }
+    }
+
+    let skus: BTreeSet<String> = state.devices().await.into_iter().map(|d| d.sku).collect();
+    for sku in skus {
+        crate::cache::invalidate_key("undoc-api", &format!("scenes-{sku}"))?;
+        if let Err(err) = GoveeUndocumentedApi::get_scenes_for_device(&sku).await {
+            log::warn!("Failed to refresh scenes for {sku}: {err:#}");
+        }
+    }
+
+    // Pick up any changes to capabilities, names and scenes
+    if let Some(client) = state.get_hass_client().await {
+        client.register_with_hass(state).await?;
+    }
+
+    log::info!("Maintenance refresh complete");
+    Ok(())
+}
+
+async fn periodic_maintenance(state: StateHandle, window: DailyWindow) -> anyhow::Result<()> {
+    // Startup has just performed a full refresh, so don't
+    // repeat it if we happen to be started inside the window
+    let mut was_in_window = window.contains(Local::now().time());
+    loop {
         sleep(Duration::from_secs(60)).await;
+
+        let in_window = window.contains(Local::now().time());
+        if in_window && !was_in_window {
+            if power_save() {
+                log::info!("Skipping the maintenance refresh while in power save mode");
+            } else if let Err(err) = run_maintenance(&state).await {
+                log::error!("Maintenance refresh failed: {err:#}");
+            }
+        }
+        was_in_window = in_window;
+    }
+}
+
+/// Publish the schema drift report to mqtt whenever we observe
+/// something new
+async fn periodic_schema_drift_report(state: StateHandle) -> anyhow::Result<()> {
+    let mut last_generation = 0;
+    loop {
+        sleep(Duration::from_secs(60)).await;
+
+        let generation = crate::schema_drift::generation();
+        if generation == last_generation {
+            continue;
+        }
+        if let Some(client) = state.get_hass_client().await {
+            client
+                .publish_obj("gv2mqtt/schema-drift", crate::schema_drift::report())
+                .await?;
+            last_generation = generation;
+        }
+    }
+}
+
+/// Publish the command audit log whenever a command is recorded
+async fn periodic_command_audit_report(state: StateHandle) -> anyhow::Result<()> {
+    let mut last_generation = None;
+    loop {
+        sleep(Duration::from_secs(5)).await;
+
+        let generation = command_audit::generation();
+        if last_generation == Some(generation) {
+            continue;
+        }
+        if let Some(client) = state.get_hass_client().await {
+            client
+                .publish_retained(COMMAND_AUDIT_TOPIC, command_audit::recent_commands(None))
+                .await?;
+            last_generation.replace(generation);
+        }
+    }
+}
+
+/// Publish the state of the Govee cloud endpoints whenever it changes
+async fn periodic_cloud_status_report(state: StateHandle) -> anyhow::Result<()> {
+    let mut last_generation = None;
+    let mut last_iot_generation = None;
+    let iot_link = IotLinkDiagnostic::new();
+    let rate_limit = CloudRateLimitDiagnostic::new();
+    loop {
+        cloud_health::expire_cooldowns();
+        let generation = cloud_health::generation();
+        if last_generation != Some(generation) {
+            if let Some(client) = state.get_hass_client().await {
+                client
+                    .publish_retained(CLOUD_STATUS_TOPIC, cloud_health::cloud_status())
+                    .await?;
+                rate_limit.notify_state(&client).await?;
+                last_generation.replace(generation);
+            }
+        }
+
+        let generation = iot_health::generation();
+        if last_iot_generation != Some(generation) {
+            if let Some(client) = state.get_hass_client().await {
+                iot_link.notify_state(&client).await?;
+                last_iot_generation.replace(generation);
+            }
+        }
+
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Periodically publish the status of the supervised tasks and
+/// the discovery stats to the stats topic
+async fn periodic_stats_report(state: StateHandle) -> anyhow::Result<()> {
+    let health = TaskHealthDiagnostic::new(&state);
+    loop {
+        sleep(Duration::from_secs(60)).await;
+
+        if let Some(client) = state.get_hass_client().await {
+            client
+                .publish_obj(
+                    "gv2mqtt/stats",
+                    serde_json::json!({
+                        "tasks": state.supervisor().task_statuses(),
+                        "discovery": crate::hass_mqtt::discovery::stats(),
+                    }),
+                )
+                .await?;
+            health.notify_state(&client).await?;
+        }
+    }
+}
+
+async fn start_undoc_client(
+    args: &crate::Args,
+    state: &StateHandle,
+    client: &GoveeUndocumentedApi,
+) -> anyhow::Result<()> {
+    let acct = refresh_undoc_device_list(state, client, None).await?;
+    #[cfg(feature = "cloud")]
+    start_iot_client(args, state.clone(), Some(acct)).await?;
+    #[cfg(not(feature = "cloud"))]
+    let _ = (args, acct);
+    Ok(())
+}
+
+/// Start the clients for each of the additional accounts, and
+/// merge their devices with those of the primary account
+async fn start_additional_accounts(args: &crate::Args, state: &StateHandle) -> anyhow::Result<()> {
+    let accounts = args.undoc_args.additional_accounts()?;
+    #[cfg(not(feature = "cloud"))]
+    if !accounts.is_empty() {
+        log::warn!(
+            "Ignoring the additional Govee accounts: {}",
+            crate::local_only::LOCAL_ONLY
+        );
+    }
+    #[cfg(not(feature = "cloud"))]
+    let _ = state;
+    #[cfg(feature = "cloud")]
+    for (idx, account) in accounts.iter().enumerate() {
+        if let Err(err) = start_additional_account(args, state, idx + 1, account).await {
+            log::error!("Unable to use the Govee account {}: {err:#}", account.email);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cloud")]
+async fn start_additional_account(
+    args: &crate::Args,
+    state: &StateHandle,
+    idx: usize,
+    account: &GoveeAccount,
+) -> anyhow::Result<()> {
+    let email = account.email.as_str();
+    log::info!("Starting clients for the additional account {email}");
+    let mut clients = AccountClients::default();
+
+    if let Some(key) = &account.api_key {
+        let client = GoveeApiClient::new(key.clone());
+        refresh_platform_device_list(state, &client, Some(email)).await?;
+        clients.platform.replace(client);
+    }
+
+    let undoc = GoveeUndocumentedApi::new(email, account.password.as_str());
+    let acct = refresh_undoc_device_list(state, &undoc, Some(email)).await?;
+
+    // Each account has its own IoT certificate
+    let numbered = |path: &std::path::Path| format!("{}.{idx}", path.display());
+    let iot = connect_iot_client(
+        args,
+        state.clone(),
+        &undoc,
+        acct,
+        numbered(&args.undoc_args.govee_iot_key).as_ref(),
+        numbered(&args.undoc_args.govee_iot_cert).as_ref(),
+        Some(email),
+    )
+    .await?;
+
+    clients.undoc.replace(undoc);
+    clients.iot.replace(iot);
+    state.set_account_clients(email, clients).await;
+    Ok(())
+}
+
+/// `account` is the email of the additional account that the
+/// client belongs to, or None for the primary account
+async fn refresh_platform_device_list(
+    state: &StateHandle,
+    client: &GoveeApiClient,
+    account: Option<&str>,
+) -> anyhow::Result<()> {
+    for info in client.get_devices().await? {
+        let mut device = state.device_mut(&info.sku, &info.device).await;
+        device.set_http_device_info(info);
+        if account.is_some() {
+            device.set_account(account);
+        }
+    }
+    Ok(())
+}
+
+/// How many firmware checks may be in flight at once
+const FIRMWARE_CHECK_CONCURRENCY: usize = 4;
+
+/// Check each device for a firmware update, a few at a time.
+/// Returns the outcome of each check that succeeded, keyed by device id.
+#[cfg_attr(not(feature = "cloud"), allow(unused_variables))]
+async fn check_firmware_updates(
+    client: &GoveeUndocumentedApi,
+    acct: &LoginAccountResponse,
+    entries: &[DeviceEntry],
+) -> HashMap<String, Option<FirmwareUpdate>> {
+    let permits = Arc::new(Semaphore::new(FIRMWARE_CHECK_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for entry in entries {
+        let client = client.clone();
+        let token = acct.token.clone();
+        let entry = entry.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let result = client.check_firmware_update(&token, &entry).await;
+            (entry, result)
+        });
+    }
+
+    let mut updates = HashMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((entry, Ok(update))) => {
+                updates.insert(entry.device, update);
+            }
+            Ok((entry, Err(err))) => {
+                log::debug!(
+                    "Unable to check firmware of {} {}: {err:#}",
+                    entry.sku,
+                    entry.device
+                );
+            }
+            Err(err) => log::error!("firmware check task failed: {err:#}"),
+        }
     }
+    updates
+}
+
+/// `account` is the email of the additional account that the
+/// client belongs to, or None for the primary account
+async fn refresh_undoc_device_list(
+    state: &StateHandle,
+    client: &GoveeUndocumentedApi,
+    account: Option<&str>,
+) -> anyhow::Result<LoginAccountResponse> {
+    log::info!("Querying undocumented API for device + room list");
+    let acct = client.login_account_cached().await?;
+    let info = client.get_device_list(&acct.token).await?;
+    if let Err(err) = client.get_diy_effects_for_account().await {
+        log::warn!("Unable to fetch DIY effects: {err:#}");
+    }
+    let mut group_by_id = HashMap::new();
+    for group in info.groups {
+        group_by_id.insert(group.group_id, group.group_name);
+    }
+    let mut firmware_updates = check_firmware_updates(client, &acct, &info.devices).await;
+    for entry in info.devices {
+        let mut device = state.device_mut(&entry.sku, &entry.device).await;
+        if let Some(update) = firmware_updates.remove(&entry.device) {
+            device.set_firmware_update(update);
+        }
+        let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
+        let had_power_reading = device.supports_power_metering();
+        device.set_undoc_device_info(entry, room_name);
+        if !had_power_reading && device.supports_power_metering() {
+            schedule_reregistration(state.clone(), "Power measurements were reported");
+        }
+        if account.is_some() {
+            device.set_account(account);
+        }
+    }
+
+    Ok(acct)
 }
 
 impl ServeCommand {
+    fn maintenance_window(&self) -> anyhow::Result<Option<DailyWindow>> {
+        match self.maintenance_window {
+            Some(window) => Ok(Some(window)),
+            None => opt_env_var("GOVEE_MAINTENANCE_WINDOW"),
+        }
+    }
+
+    fn state_merge_policy(&self) -> anyhow::Result<Option<StateMergePolicy>> {
+        match self.state_merge_policy {
+            Some(policy) => Ok(Some(policy)),
+            None => opt_env_var("GOVEE_STATE_MERGE_POLICY"),
+        }
+    }
+
+    fn cloud_pacing(&self) -> anyhow::Result<Option<CloudPacing>> {
+        match self.cloud_pacing {
+            Some(pacing) => Ok(Some(pacing)),
+            None => opt_env_var("GOVEE_CLOUD_PACING"),
+        }
+    }
+
+    fn command_queue(&self) -> anyhow::Result<Option<CommandQueueConfig>> {
+        match self.command_queue {
+            Some(config) => Ok(Some(config)),
+            None => opt_env_var("GOVEE_COMMAND_QUEUE"),
+        }
+    }
+
+    /// The rules from the file come first, then the environment,
+    /// then the command line, so that later sources take
+    /// precedence over equally specific earlier rules
+    fn poll_interval_rules(&self) -> anyhow::Result<Vec<PollIntervalRule>> {
+        let mut rules = vec![];
+        let file = match &self.poll_intervals_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_POLL_INTERVALS_FILE")?,
+        };
+        if let Some(path) = file {
+            rules.extend(load_poll_interval_rules(&path)?);
+        }
+        if let Some(v) = opt_env_var::<String>("GOVEE_POLL_INTERVALS")? {
+            rules.extend(parse_poll_interval_rules(&v).context("GOVEE_POLL_INTERVALS")?);
+        }
+        rules.extend(self.poll_interval.iter().cloned());
+        Ok(rules)
+    }
+
+    /// The rules from the environment come first, so that equally
+    /// specific rules from the command line take precedence
+    fn cloud_failure_rules(&self) -> anyhow::Result<Vec<CloudFailureRule>> {
+        let mut rules = vec![];
+        if let Some(v) = opt_env_var::<String>("GOVEE_CLOUD_FAILURE_POLICY")? {
+            rules.extend(parse_cloud_failure_rules(&v).context("GOVEE_CLOUD_FAILURE_POLICY")?);
+        }
+        rules.extend(self.cloud_failure_policy.iter().cloned());
+        Ok(rules)
+    }
+
+    fn watchdog_rules(&self) -> anyhow::Result<Vec<WatchdogRule>> {
+        let mut rules = vec![];
+        if let Some(v) = opt_env_var::<String>("GOVEE_WATCHDOG_RULES")? {
+            rules.extend(parse_watchdog_rules(&v).context("GOVEE_WATCHDOG_RULES")?);
+        }
+        rules.extend(self.watchdog.iter().cloned());
+        Ok(rules)
+    }
+
+    fn auto_brightness_rules(&self) -> anyhow::Result<Vec<AutoBrightnessRule>> {
+        let mut rules = vec![];
+        if let Some(v) = opt_env_var::<String>("GOVEE_AUTO_BRIGHTNESS")? {
+            rules.extend(parse_auto_brightness_rules(&v).context("GOVEE_AUTO_BRIGHTNESS")?);
+        }
+        rules.extend(self.auto_brightness.iter().cloned());
+        Ok(rules)
+    }
+
+    fn preset_schedule_rules(&self) -> anyhow::Result<Vec<PresetScheduleRule>> {
+        let mut rules = vec![];
+        if let Some(v) = opt_env_var::<String>("GOVEE_PRESET_SCHEDULE")? {
+            rules.extend(parse_preset_schedule_rules(&v).context("GOVEE_PRESET_SCHEDULE")?);
+        }
+        rules.extend(self.preset_schedule.iter().cloned());
+        Ok(rules)
+    }
+
+    fn probe_capabilities(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_PROBE_CAPABILITIES")? {
+            return truthy(&v);
+        }
+        Ok(self.probe_capabilities)
+    }
+
+    fn power_save(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_POWER_SAVE")? {
+            return truthy(&v);
+        }
+        Ok(self.power_save)
+    }
+
+    #[cfg(feature = "ble")]
+    fn ble_scan(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_BLE_SCAN")? {
+            return truthy(&v);
+        }
+        Ok(self.ble_scan)
+    }
+
+    #[cfg(feature = "ble")]
+    fn ble_sensors(&self) -> anyhow::Result<Vec<String>> {
+        let mut addresses = vec![];
+        if let Some(v) = opt_env_var::<String>("GOVEE_BLE_SENSORS")? {
+            addresses.extend(v.split(',').map(|addr| addr.trim().to_string()));
+        }
+        addresses.extend(self.ble_sensor.iter().cloned());
+        Ok(addresses)
+    }
+
+    /// Resolve each of the options that are checked when the
+    /// service starts, returning the first problem found
+    pub fn check_options(&self, args: &crate::Args) -> anyhow::Result<()> {
+        args.api_args.opt_api_key()?;
+        args.undoc_args.opt_email()?;
+        args.undoc_args.opt_password()?;
+        args.undoc_args.iot_publish_rate()?;
+        args.undoc_args.iot_publish_concurrency()?;
+        args.undoc_args.opt_token_file()?;
+        args.undoc_args.additional_accounts()?;
+        args.lan_disco_args.to_disco_options()?;
+        args.lan_disco_args.disco_timeout()?;
+
+        let hass = &args.hass_args;
+        hass.opt_mqtt_host()?;
+        #[cfg(feature = "embedded-broker")]
+        hass.mqtt_broker_listen()?;
+        hass.mqtt_port()?;
+        hass.mqtt_username()?;
+        hass.mqtt_password()?;
+        hass.birth_message()?;
+        hass.mirror_config()?;
+        hass.scene_icon_base_url()?;
+        hass.restore_after_power_loss()?;
+        hass.segment_layouts()?;
+        hass.color_presets()?;
+        hass.scene_filters()?;
+        hass.scene_select_per_category()?;
+        hass.derived_sensors()?;
+        hass.rate_of_change_window()?;
+        hass.shard_config()?;
+        hass.playlists()?;
+        hass.entity_id_overrides()?;
+        hass.device_customizations()?;
+        hass.temperature_scale()?;
+
+        self.maintenance_window()?;
+        self.state_merge_policy()?;
+        self.cloud_pacing()?;
+        self.command_queue()?;
+        self.poll_interval_rules()?;
+        self.cloud_failure_rules()?;
+        self.watchdog_rules()?;
+        self.auto_brightness_rules()?;
+        self.preset_schedule_rules()?;
+        self.probe_capabilities()?;
+        self.power_save()?;
+        #[cfg(feature = "ble")]
+        self.ble_scan()?;
+        #[cfg(feature = "ble")]
+        self.ble_sensors()?;
+        Ok(())
+    }
+
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
         log::info!("Starting service. version {}", govee_version());
+        spawn_stall_detector();
+        if let Some(policy) = self.state_merge_policy()? {
+            log::info!("State merge policy is {policy:?}");
+            set_state_merge_policy(policy);
+        }
+        if let Some(config) = args.hass_args.shard_config()? {
+            log::info!(
+                "This is bridge instance {}, claiming {:?}",
+                config.instance,
+                config.claims
+            );
+            set_shard_config(Some(config));
+        }
+        if let Some(pacing) = self.cloud_pacing()? {
+            log::info!("Cloud pacing is {pacing:?}");
+            set_cloud_pacing(pacing);
+        }
+        if let Some(config) = self.command_queue()? {
+            log::info!("Command queue is {config:?}");
+            set_command_queue_config(config);
+        }
+        let poll_rules = self.poll_interval_rules()?;
+        if !poll_rules.is_empty() {
+            log::info!("Poll interval rules are {poll_rules:?}");
+            set_poll_interval_rules(poll_rules);
+        }
+        let cloud_failure_rules = self.cloud_failure_rules()?;
+        if !cloud_failure_rules.is_empty() {
+            log::info!("Cloud failure rules are {cloud_failure_rules:?}");
+            set_cloud_failure_rules(cloud_failure_rules);
+        }
+        let watchdog_rules = self.watchdog_rules()?;
+        let have_watchdog = !watchdog_rules.is_empty();
+        if have_watchdog {
+            log::info!("Watchdog rules are {watchdog_rules:?}");
+            set_watchdog_rules(watchdog_rules);
+        }
+        if self.power_save()? {
+            set_power_save(true);
+        }
         let state = Arc::new(crate::service::state::State::new());
 
+        #[cfg(not(feature = "cloud"))]
+        log::info!("This is a local only build; only the LAN API will be used");
+
         // First, use the HTTP APIs to determine the list of devices and
         // their names.
 
         if let Ok(client) = args.api_args.api_client() {
             log::info!("Querying platform API for device list");
-            for info in client.get_devices().await? {
-                let mut device = state.device_mut(&info.sku, &info.device).await;
-                device.set_http_device_info(info);
-            }
-
+            refresh_platform_device_list(&state, &client, None).await?;
             state.set_platform_client(client).await;
         }
         if let Ok(client) = args.undoc_args.api_client() {
-            log::info!("Querying undocumented API for device + room list");
-            let acct = client.login_account_cached().await?;
-            let info = client.get_device_list(&acct.token).await?;
-            let mut group_by_id = HashMap::new();
-            for group in info.groups {
-                group_by_id.insert(group.group_id, group.group_name);
-            }
-            for entry in info.devices {
-                let mut device = state.device_mut(&entry.sku, &entry.device).await;
-                let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
-                device.set_undoc_device_info(entry, room_name);
+            match start_undoc_client(args, &state, &client).await {
+                Ok(()) => {
+                    state.set_undoc_client(client).await;
+                }
+                Err(err) if client.has_saved_login() => {
+                    // Rather than failing to start, run without the
+                    // cloud features until the user logs in again
+                    log::error!(
+                        "Unable to use the saved Govee account token: {err:#}. \
+                        Continuing without cloud based features. \
+                        Run `govee login` to obtain a new token."
+                    );
+                }
+                Err(err) => return Err(err),
             }
-
-            start_iot_client(args, state.clone(), Some(acct)).await?;
-
-            state.set_undoc_client(client).await;
         }
+        start_additional_accounts(args, &state).await?;
 
         // Now start discovery
 
@@ -127,29 +909,31 @@ impl ServeCommand {
         if !options.is_empty() {
             log::info!("Starting LAN discovery");
             let state = state.clone();
-            let (client, mut scan) = LanClient::new(options).await?;
+            let (client, mut scan) = LanClient::new(options, state.supervisor()).await?;
 
             state.set_lan_client(client.clone()).await;
 
             tokio::spawn(async move {
                 while let Some(lan_device) = scan.recv().await {
                     log::trace!("LAN disco: {lan_device:?}");
-                    state
-                        .device_mut(&lan_device.sku, &lan_device.device)
-                        .await
-                        .set_lan_device(lan_device.clone());
+                    let power_restored = {
+                        let mut device =
+                            state.device_mut(&lan_device.sku, &lan_device.device).await;
+                        let power_restored = device.lan_rediscovered_after_power_loss();
+                        device.set_lan_device(lan_device.clone());
+                        power_restored
+                    };
 
                     let state = state.clone();
-                    let client = client.clone();
                     tokio::spawn(async move {
-                        if let Ok(status) = client.query_status(&lan_device).await {
-                            state
-                                .device_mut(&lan_device.sku, &lan_device.device)
-                                .await
-                                .set_lan_device_status(status);
-
-                            log::trace!("LAN disco: update and notify {}", lan_device.device);
-                            state.notify_of_state_change(&lan_device.device).await.ok();
+                        log::trace!("LAN disco: update and notify {}", lan_device.device);
+                        state.measure_lan_rtt(&lan_device).await.ok();
+                        if power_restored {
+                            if let Some(device) = state.device_by_id(&lan_device.device).await {
+                                if let Err(err) = state.handle_power_restored(&device).await {
+                                    log::error!("Restoring {device} after power loss: {err:#}");
+                                }
+                            }
                         }
                     });
                 }
@@ -184,8 +968,9 @@ impl ServeCommand {
             }
             if let Some(undoc) = &device.undoc_device_info {
                 let room = &undoc.room_name;
-                let supports_iot = undoc.entry.device_ext.device_settings.topic.is_some();
-                let ble_only = undoc.entry.device_ext.device_settings.wifi_name.is_none();
+                let settings = &undoc.entry.device_ext.device_settings;
+                let supports_iot = settings.topic.is_some() || settings.wifi_name.is_some();
+                let ble_only = settings.wifi_name.is_none();
                 log::info!(
                     "  Undoc: room={room:?} supports_iot={supports_iot} ble_only={ble_only}"
                 );
@@ -224,19 +1009,163 @@ impl ServeCommand {
             log::info!("");
         }
 
+        restore_device_states(&state).await;
+        let supervisor = state.supervisor().clone();
+        {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_state_snapshot",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_state_snapshot(state).await }
+                },
+            );
+        }
+
         // Start periodic status polling
         {
             let state = state.clone();
-            tokio::spawn(async move {
-                if let Err(err) = periodic_state_poll(state).await {
-                    log::error!("periodic_state_poll: {err:#}");
-                }
+            supervisor.supervise("periodic_state_poll", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { periodic_state_poll(state).await }
+            });
+        }
+        {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_firmware_watch",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_firmware_watch(state).await }
+                },
+            );
+        }
+        if let Some(window) = self.maintenance_window()? {
+            log::info!("Maintenance window is {window:?}");
+            crate::cache::set_maintenance_window(window);
+            let state = state.clone();
+            supervisor.supervise("periodic_maintenance", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { periodic_maintenance(state, window).await }
+            });
+        }
+        {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_schema_drift_report",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_schema_drift_report(state).await }
+                },
+            );
+        }
+        {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_command_audit_report",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_command_audit_report(state).await }
+                },
+            );
+        }
+        {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_cloud_status_report",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_cloud_status_report(state).await }
+                },
+            );
+        }
+        {
+            let state = state.clone();
+            supervisor.supervise("periodic_stats_report", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { periodic_stats_report(state).await }
             });
         }
+        if state.get_lan_client().await.is_some() {
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_lan_rtt_measurement",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    async move { periodic_lan_rtt_measurement(state).await }
+                },
+            );
+        }
+
+        if self.probe_capabilities()? {
+            if let Err(err) = probe_capabilities(&state).await {
+                log::error!("probe_capabilities: {err:#}");
+            }
+        }
 
         // start advertising on local mqtt
         spawn_hass_integration(state.clone(), &args.hass_args).await?;
 
+        {
+            let state = state.clone();
+            supervisor.supervise("run_playlists", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { run_playlists(state).await }
+            });
+        }
+        let auto_brightness_rules = self.auto_brightness_rules()?;
+        if !auto_brightness_rules.is_empty() {
+            log::info!("Auto brightness rules are {auto_brightness_rules:?}");
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_auto_brightness",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    let rules = auto_brightness_rules.clone();
+                    async move { periodic_auto_brightness(state, rules).await }
+                },
+            );
+        }
+        let preset_schedule_rules = self.preset_schedule_rules()?;
+        if !preset_schedule_rules.is_empty() {
+            log::info!("Preset schedule rules are {preset_schedule_rules:?}");
+            let state = state.clone();
+            supervisor.supervise(
+                "periodic_preset_schedule",
+                RestartPolicy::Always,
+                move || {
+                    let state = state.clone();
+                    let rules = preset_schedule_rules.clone();
+                    async move { periodic_preset_schedule(state, rules).await }
+                },
+            );
+        }
+        if have_watchdog {
+            let state = state.clone();
+            supervisor.supervise("periodic_watchdog", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { periodic_watchdog(state).await }
+            });
+        }
+        #[cfg(feature = "ble")]
+        if self.ble_scan()? {
+            state.set_ble_sensors(self.ble_sensors()?).await;
+            let state = state.clone();
+            supervisor.supervise("ble_scanner", RestartPolicy::Always, move || {
+                let state = state.clone();
+                async move { crate::ble::scanner::run_ble_scanner(state).await }
+            });
+        }
+
+        systemd::spawn_watchdog(state.clone());
+
         run_http_server(state.clone(), self.http_port)
             .await
             .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
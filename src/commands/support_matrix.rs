@@ -0,0 +1,183 @@
+use crate::commands::list::load_devices;
+use crate::platform_api::DeviceCapabilityKind;
+use crate::service::device::Device;
+use std::collections::BTreeMap;
+
+/// Show which operations are supported over which transports,
+/// for each discovered SKU
+#[derive(clap::Parser, Debug)]
+pub struct SupportMatrixCommand {
+    #[arg(long)]
+    skip_lan: bool,
+
+    /// Emit JSON rather than a human readable table
+    #[arg(long)]
+    json: bool,
+}
+
+const OPERATIONS: &[&str] = &[
+    "power",
+    "brightness",
+    "color",
+    "color_temperature",
+    "scenes",
+    "segments",
+    "music",
+    "timers",
+];
+
+/// Returns a map of operation name to the list of transports
+/// that can carry out that operation for the device
+fn support_for_device(device: &Device) -> BTreeMap<&'static str, Vec<&'static str>> {
+    let quirk = device.resolve_quirk();
+    let lan =
+        device.lan_device.is_some() || quirk.as_ref().map(|q| q.lan_api_capable).unwrap_or(false);
+    let iot = device.iot_api_supported();
+    let info = device.http_device_info.as_ref();
+
+    let platform_instance = |instance: &str| {
+        info.map(|info| info.capability_by_instance(instance).is_some())
+            .unwrap_or(false)
+    };
+    let platform_kind = |kind: DeviceCapabilityKind| {
+        info.map(|info| info.capabilities.iter().any(|cap| cap.kind == kind))
+            .unwrap_or(false)
+    };
+
+    let mut result = BTreeMap::new();
+    for &op in OPERATIONS {
+        let (via_lan, via_iot, via_platform) = match op {
+            "power" => (lan, iot, platform_instance("powerSwitch")),
+            "brightness" if device.supports_brightness() => {
+                (lan, iot, platform_instance("brightness"))
+            }
+            "color" if device.supports_rgb() => (lan, iot, platform_instance("colorRgb")),
+            "color_temperature" if device.get_color_temperature_range().is_some() => {
+                (lan, iot, platform_instance("colorTemperatureK"))
+            }
+            "scenes" => (
+                lan,
+                false,
+                platform_kind(DeviceCapabilityKind::DynamicScene),
+            ),
+            "segments" => (
+                lan && device.lan_segment_count().is_some(),
+                false,
+                platform_instance("segmentedColorRgb"),
+            ),
+            "music" => (
+                false,
+                false,
+                platform_kind(DeviceCapabilityKind::MusicSetting),
+            ),
+            "timers" => (
+                false,
+                false,
+                info.map(|info| {
+                    info.capabilities
+                        .iter()
+                        .any(|cap| cap.instance.to_ascii_lowercase().contains("timer"))
+                })
+                .unwrap_or(false),
+            ),
+            _ => (false, false, false),
+        };
+
+        let mut transports = vec![];
+        if via_lan {
+            transports.push("lan");
+        }
+        if via_iot {
+            transports.push("iot");
+        }
+        if via_platform {
+            transports.push("platform");
+        }
+        result.insert(op, transports);
+    }
+    result
+}
+
+impl SupportMatrixCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = load_devices(args, self.skip_lan).await?;
+
+        // Several devices may share the same SKU; report only the
+        // first that we encounter, as the support is determined
+        // by the SKU.
+        let mut by_sku = BTreeMap::new();
+        for device in state.devices().await {
+            by_sku
+                .entry(device.sku.clone())
+                .or_insert_with(|| support_for_device(&device));
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&by_sku)?);
+            return Ok(());
+        }
+
+        for (sku, support) in by_sku {
+            println!("{sku}");
+            for (op, transports) in support {
+                let transports = if transports.is_empty() {
+                    "-".to_string()
+                } else {
+                    transports.join(" ")
+                };
+                println!("  {op:<18} {transports}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::HttpDeviceInfo;
+    use serde_json::Value as JsonValue;
+
+    fn device(index: usize) -> Device {
+        let list: JsonValue =
+            serde_json::from_str(include_str!("../../test-data/list_devices.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(list["data"][index].clone()).unwrap();
+        let mut device = Device::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        device
+    }
+
+    #[test]
+    fn support() {
+        // H6601 has no quirks, so only the platform API is known to work
+        let h6601 = device(0);
+        let support = support_for_device(&h6601);
+        for op in [
+            "power",
+            "brightness",
+            "color",
+            "color_temperature",
+            "scenes",
+            "segments",
+            "music",
+        ] {
+            assert_eq!(support[op], vec!["platform"], "{op}");
+        }
+        assert!(support["timers"].is_empty());
+
+        // H7055 is known to support the LAN and IoT APIs
+        let h7055 = device(2);
+        assert_eq!(h7055.sku, "H7055");
+        let support = support_for_device(&h7055);
+        assert_eq!(support["power"], vec!["lan", "iot", "platform"]);
+        assert_eq!(support["scenes"], vec!["lan", "platform"]);
+        assert_eq!(support["music"], vec!["platform"]);
+
+        // Nothing is known about a device without capabilities or quirks
+        let unknown = Device::new("H0000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let support = support_for_device(&unknown);
+        assert_eq!(support.len(), OPERATIONS.len());
+        assert!(support.values().all(|transports| transports.is_empty()));
+    }
+}
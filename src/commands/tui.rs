@@ -0,0 +1,353 @@
+use crate::lan_api::DeviceColor;
+use chrono::{DateTime, Local, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// How often the devices and events are fetched from the service
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How much the brightness changes with each key press
+const BRIGHTNESS_STEP: u8 = 10;
+
+/// A terminal dashboard showing the devices of a running `govee serve`,
+/// along with its recent events, which can also turn devices on and off
+/// and adjust their brightness. It talks to the service via its HTTP
+/// server, so it works over ssh on a headless server.
+#[derive(clap::Parser, Debug)]
+pub struct TuiCommand {
+    /// The address of the HTTP server of the service
+    #[arg(long, default_value = "http://127.0.0.1:8056")]
+    url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct DeviceItem {
+    sku: String,
+    id: String,
+    name: String,
+    room: Option<String>,
+    #[serde(default)]
+    transports: Vec<String>,
+    state: Option<DeviceStateItem>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct DeviceStateItem {
+    on: bool,
+    light_on: Option<bool>,
+    online: Option<bool>,
+    kelvin: u32,
+    color: DeviceColor,
+    brightness: u8,
+    scene: Option<String>,
+    source: String,
+}
+
+impl DeviceStateItem {
+    fn is_on(&self) -> bool {
+        self.light_on.unwrap_or(self.on)
+    }
+
+    fn power(&self) -> &'static str {
+        match (self.online, self.is_on()) {
+            (Some(false), _) => "offline",
+            (_, true) => "on",
+            (_, false) => "off",
+        }
+    }
+
+    fn color(&self) -> String {
+        match &self.scene {
+            Some(scene) => scene.to_string(),
+            None if self.kelvin != 0 => format!("{}K", self.kelvin),
+            None => {
+                let c = &self.color;
+                format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct EventItem {
+    time: DateTime<Utc>,
+    device_name: Option<String>,
+    message: String,
+}
+
+/// The brightness one step up or down from `current`. Never goes
+/// below 1%, as 0% turns some devices off.
+fn step_brightness(current: u8, up: bool) -> u8 {
+    if up {
+        current.saturating_add(BRIGHTNESS_STEP).min(100)
+    } else {
+        current.saturating_sub(BRIGHTNESS_STEP).max(1)
+    }
+}
+
+struct App {
+    client: reqwest::Client,
+    url: String,
+    devices: Vec<DeviceItem>,
+    events: Vec<EventItem>,
+    table: TableState,
+    /// Why the service couldn't be reached, if it couldn't
+    error: Option<String>,
+    /// The outcome of the most recent command
+    status: Option<String>,
+}
+
+impl App {
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+    ) -> anyhow::Result<T> {
+        let response = self
+            .client
+            .request(method, format!("{}{path}", self.url))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    async fn refresh(&mut self) {
+        let devices = self.request(reqwest::Method::GET, "/api/devices").await;
+        let events = self.request(reqwest::Method::GET, "/api/events").await;
+        match (devices, events) {
+            (Ok(devices), Ok(events)) => {
+                self.devices = devices;
+                self.events = events;
+                self.error = None;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                self.error = Some(format!("Unable to reach {}: {err:#}", self.url));
+            }
+        }
+
+        let selected = match self.table.selected() {
+            _ if self.devices.is_empty() => None,
+            Some(n) => Some(n.min(self.devices.len() - 1)),
+            None => Some(0),
+        };
+        self.table.select(selected);
+    }
+
+    fn selected_device(&self) -> Option<&DeviceItem> {
+        self.devices.get(self.table.selected()?)
+    }
+
+    /// Send a command to the selected device, where `command`
+    /// is the remainder of the path, eg: `power/on`
+    async fn control(&mut self, method: reqwest::Method, command: String) {
+        let Some(device) = self.selected_device() else {
+            return;
+        };
+        let name = device.name.clone();
+        let path = format!("/api/device/{}/{command}", device.id);
+        self.status = Some(
+            match self.request::<serde_json::Value>(method, &path).await {
+                Ok(_) => format!("{name}: {command}"),
+                Err(err) => format!("{name}: {command} failed: {err:#}"),
+            },
+        );
+        self.refresh().await;
+    }
+
+    async fn toggle_power(&mut self) {
+        let Some(device) = self.selected_device() else {
+            return;
+        };
+        let on = device.state.as_ref().map(|s| s.is_on()).unwrap_or(false);
+        let command = if on { "power/off" } else { "power/on" };
+        self.control(reqwest::Method::GET, command.to_string())
+            .await;
+    }
+
+    async fn adjust_brightness(&mut self, up: bool) {
+        let Some(state) = self.selected_device().and_then(|d| d.state.as_ref()) else {
+            return;
+        };
+        let level = step_brightness(state.brightness, up);
+        self.control(reqwest::Method::GET, format!("brightness/{level}"))
+            .await;
+    }
+
+    fn select_relative(&mut self, delta: isize) {
+        if self.devices.is_empty() {
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let last = self.devices.len() as isize - 1;
+        self.table
+            .select(Some((current + delta).clamp(0, last) as usize));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [devices_area, events_area, status_area] = Layout::vertical([
+            Constraint::Min(5),
+            Constraint::Length(10),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let header = Row::new([
+            "Name",
+            "Room",
+            "SKU",
+            "Power",
+            "Brightness",
+            "Color",
+            "Source",
+            "Transports",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.devices.iter().map(|device| {
+            let (power, brightness, color, source) = match &device.state {
+                Some(state) => (
+                    state.power().to_string(),
+                    format!("{}%", state.brightness),
+                    state.color(),
+                    state.source.to_string(),
+                ),
+                None => Default::default(),
+            };
+            Row::new([
+                device.name.to_string(),
+                device.room.clone().unwrap_or_default(),
+                device.sku.to_string(),
+                power,
+                brightness,
+                color,
+                source,
+                device.transports.join(", "),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(3),
+                Constraint::Fill(2),
+                Constraint::Length(8),
+                Constraint::Length(7),
+                Constraint::Length(10),
+                Constraint::Fill(2),
+                Constraint::Length(12),
+                Constraint::Fill(2),
+            ],
+        )
+        .header(header)
+        .block(Block::bordered().title(format!(" Devices: {} ", self.url)))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, devices_area, &mut self.table);
+
+        let visible = events_area.height.saturating_sub(2) as usize;
+        let events: Vec<ListItem> = self
+            .events
+            .iter()
+            .skip(self.events.len().saturating_sub(visible))
+            .map(|event| {
+                let time = event.time.with_timezone(&Local).format("%H:%M:%S");
+                let line = match &event.device_name {
+                    Some(name) => format!("{time} {name}: {}", event.message),
+                    None => format!("{time} {}", event.message),
+                };
+                ListItem::new(line)
+            })
+            .collect();
+        frame.render_widget(
+            List::new(events).block(Block::bordered().title(" Recent Events ")),
+            events_area,
+        );
+
+        let status = match (&self.error, &self.status) {
+            (Some(error), _) => Line::styled(error.as_str(), Style::default().fg(Color::Red)),
+            (None, Some(status)) => {
+                Line::styled(status.as_str(), Style::default().fg(Color::Yellow))
+            }
+            (None, None) => Line::from(
+                "↑/↓ select  enter toggle power  +/- brightness  r refresh device  q quit",
+            ),
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+}
+
+async fn run_app(terminal: &mut DefaultTerminal, app: &mut App) -> anyhow::Result<()> {
+    let mut last_refresh: Option<Instant> = None;
+    loop {
+        if last_refresh
+            .map(|t| t.elapsed() >= REFRESH_INTERVAL)
+            .unwrap_or(true)
+        {
+            app.refresh().await;
+            last_refresh = Some(Instant::now());
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+
+        if !tokio::task::block_in_place(|| event::poll(Duration::from_millis(250)))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => app.select_relative(-1),
+            KeyCode::Down | KeyCode::Char('j') => app.select_relative(1),
+            KeyCode::Enter | KeyCode::Char(' ') => app.toggle_power().await,
+            KeyCode::Char('+') | KeyCode::Char('=') => app.adjust_brightness(true).await,
+            KeyCode::Char('-') => app.adjust_brightness(false).await,
+            KeyCode::Char('r') => {
+                app.control(reqwest::Method::POST, "refresh".to_string())
+                    .await
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TuiCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let mut app = App {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            url: self.url.trim_end_matches('/').to_string(),
+            devices: vec![],
+            events: vec![],
+            table: TableState::default(),
+            error: None,
+            status: None,
+        };
+
+        let mut terminal = ratatui::init();
+        let result = run_app(&mut terminal, &mut app).await;
+        ratatui::restore();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn brightness_steps() {
+        assert_eq!(step_brightness(50, true), 60);
+        assert_eq!(step_brightness(95, true), 100);
+        assert_eq!(step_brightness(50, false), 40);
+        assert_eq!(step_brightness(5, false), 1);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::service::iot::start_iot_client;
+use anyhow::Context;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(clap::Parser, Debug)]
 pub struct UndocCommand {
@@ -8,6 +10,7 @@ pub struct UndocCommand {
 }
 
 #[derive(clap::Parser, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum SubCommand {
     DumpOneClick {},
     ShowOneClick {},
@@ -41,7 +44,11 @@ impl UndocCommand {
                 start_iot_client(args, state.clone(), None).await?;
                 let iot = state.get_iot_client().await.expect("just started iot");
 
-                iot.activate_one_click(&item).await?;
+                // Each message is awaited until it has been published
+                tokio::time::timeout(Duration::from_secs(30), iot.activate_one_click(item))
+                    .await
+                    .context("timed out running the One-Click")??;
+                println!("Ran {}", item.name);
             }
         }
         Ok(())
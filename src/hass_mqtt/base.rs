@@ -1,4 +1,5 @@
 use crate::service::device::Device as ServiceDevice;
+use crate::service::device_customization::{device_customization, DeviceCustomization};
 use crate::service::hass::topic_safe_id;
 use crate::version_info::govee_version;
 use serde::Serialize;
@@ -53,16 +54,25 @@ pub struct Device {
     pub identifiers: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub connections: Vec<(String, String)>,
+    /// Applied to the entities of the device by publish_entity_config
+    #[serde(skip)]
+    pub customization: Option<DeviceCustomization>,
 }
 
 impl Device {
     pub fn for_device(device: &ServiceDevice) -> Self {
+        let customization = device_customization(device);
+        let custom = customization.as_ref();
         Self {
-            name: device.name(),
+            name: custom
+                .and_then(|c| c.name.clone())
+                .unwrap_or_else(|| device.name()),
             manufacturer: "Govee".to_string(),
             model: device.sku.to_string(),
             sw_version: None,
-            suggested_area: device.room_name().map(|s| s.to_string()),
+            suggested_area: custom
+                .and_then(|c| c.area.clone())
+                .or_else(|| device.room_name().map(|s| s.to_string())),
             via_device: Some("gv2mqtt".to_string()),
             identifiers: vec![
                 format!("gv2mqtt-{}", topic_safe_id(device)),
@@ -72,6 +82,7 @@ impl Device {
                 */
             ],
             connections: vec![],
+            customization,
         }
     }
 
@@ -85,6 +96,7 @@ impl Device {
             via_device: None,
             identifiers: vec!["gv2mqtt".to_string()],
             connections: vec![],
+            customization: None,
         }
     }
 }
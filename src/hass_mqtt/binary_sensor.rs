@@ -0,0 +1,381 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::derived::DerivedSensorDefinition;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::iot_health::{iot_link_up, link_statuses};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BinarySensorConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+}
+
+impl BinarySensorConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("binary_sensor", state, client, &self.base, self).await
+    }
+
+    pub async fn notify_state(&self, client: &HassClient, on: bool) -> anyhow::Result<()> {
+        client
+            .publish(&self.state_topic, if on { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+/// Reports whether the device has raised any out of range
+/// alarms, with the details of those alarms as attributes
+#[derive(Clone)]
+pub struct SensorAlarmProblem {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl SensorAlarmProblem {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("binary-sensor-{id}-alarm", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Alarm".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("problem"),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                json_attributes_topic: Some(format!(
+                    "gv2mqtt/binary_sensor/{unique_id}/attributes"
+                )),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for SensorAlarmProblem {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(topic, json!({"alarms": device.sensor_alarms}))
+                .await?;
+        }
+        self.sensor
+            .notify_state(client, !device.sensor_alarms.is_empty())
+            .await
+    }
+}
+
+/// Reports whether the AWS IoT sessions are connected,
+/// with the health of each of them as attributes
+#[derive(Clone)]
+pub struct IotLinkDiagnostic {
+    sensor: BinarySensorConfig,
+}
+
+impl Default for IotLinkDiagnostic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IotLinkDiagnostic {
+    pub fn new() -> Self {
+        let unique_id = "global-iot-link".to_string();
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("AWS IoT Connection".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("connectivity"),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                json_attributes_topic: Some(format!(
+                    "gv2mqtt/binary_sensor/{unique_id}/attributes"
+                )),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IotLinkDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(up) = iot_link_up() else {
+            return Ok(());
+        };
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, link_statuses()).await?;
+        }
+        self.sensor.notify_state(client, up).await
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum LeakSensorKind {
+    /// Whether water has been detected
+    Leak,
+    /// Whether the detector, and the gateway that it is
+    /// paired with, are connected to the Govee cloud
+    Online,
+}
+
+/// The state of a leak detector, such as the H5054
+#[derive(Clone)]
+pub struct LeakBinarySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    kind: LeakSensorKind,
+}
+
+impl LeakBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: LeakSensorKind) -> Self {
+        let (suffix, name, device_class, entity_category) = match kind {
+            LeakSensorKind::Leak => ("leak", "Leak", "moisture", None),
+            LeakSensorKind::Online => (
+                "online",
+                "Online",
+                "connectivity",
+                Some("diagnostic".to_string()),
+            ),
+        };
+        let unique_id = format!("binary-sensor-{id}-{suffix}", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(device_class),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                json_attributes_topic: match kind {
+                    LeakSensorKind::Leak => {
+                        Some(format!("gv2mqtt/binary_sensor/{unique_id}/attributes"))
+                    }
+                    LeakSensorKind::Online => None,
+                },
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            kind,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LeakBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = device.leak_reading else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            LeakSensorKind::Leak => reading.leak,
+            LeakSensorKind::Online => reading.is_reachable(),
+        };
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            // The leak state is retained while the detector is
+            // unreachable, as it may still be relevant; these
+            // attributes indicate whether it may be stale
+            client
+                .publish_obj(
+                    topic,
+                    json!({
+                        "reachable": reading.is_reachable(),
+                        "last_update": device.last_leak_reading_update,
+                    }),
+                )
+                .await?;
+        }
+        match value {
+            Some(on) => self.sensor.notify_state(client, on).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether a presence sensor, such as the H5127, detects someone,
+/// with its detection settings as attributes
+#[derive(Clone)]
+pub struct PresenceBinarySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PresenceBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("binary-sensor-{id}-occupancy", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Occupancy".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("occupancy"),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                json_attributes_topic: Some(format!(
+                    "gv2mqtt/binary_sensor/{unique_id}/attributes"
+                )),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PresenceBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = device.presence_reading else {
+            return Ok(());
+        };
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(
+                    topic,
+                    json!({
+                        "detection_distance_cm": reading.detection_distance,
+                        "sensitivity": reading.sensitivity,
+                    }),
+                )
+                .await?;
+        }
+        match reading.occupied {
+            Some(on) => self.sensor.notify_state(client, on).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A user-defined or preset binary sensor, such as frost risk,
+/// that is on whenever its expression evaluates to non-zero
+#[derive(Clone)]
+pub struct DerivedBinarySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    definition: DerivedSensorDefinition,
+}
+
+impl DerivedBinarySensor {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        definition: &DerivedSensorDefinition,
+    ) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-gv2mqtt-derived-{name}",
+            id = topic_safe_id(device),
+            name = topic_safe_string(&definition.name)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(definition.name.to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: definition.device_class,
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                json_attributes_topic: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            definition: definition.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DerivedBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        match self.definition.evaluate(&device) {
+            Some(value) => self.sensor.notify_state(client, value != 0.).await,
+            None => Ok(()),
+        }
+    }
+}
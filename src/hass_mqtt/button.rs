@@ -105,6 +105,59 @@ impl ButtonConfig {
         }
     }
 
+    pub fn playlist_command(device: &ServiceDevice, playlist: &str, command: &str) -> Self {
+        let safe_name = topic_safe_string(playlist);
+        let unique_id = format!(
+            "gv2mqtt-{id}-playlist-{safe_name}-{command}",
+            id = topic_safe_id(device),
+        );
+        let icon = match command {
+            "play" => "mdi:play",
+            "pause" => "mdi:pause",
+            "next" => "mdi:skip-next",
+            _ => "mdi:stop",
+        };
+        Self {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: Some(format!(
+                    "Playlist {playlist} {command}",
+                    command = camel_case_to_space_separated(command)
+                )),
+                entity_category: None,
+                origin: Origin::default(),
+                device: Device::for_device(device),
+                unique_id,
+                device_class: None,
+                icon: Some(icon.to_string()),
+            },
+            command_topic: format!(
+                "gv2mqtt/{id}/playlist/{safe_name}/command",
+                id = topic_safe_id(device)
+            ),
+            payload_press: Some(command.to_string()),
+        }
+    }
+
+    /// Offered for devices that have more scenes than we
+    /// initially offer in Home Assistant
+    pub fn load_all_scenes_for_device(device: &ServiceDevice) -> Self {
+        Self {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: Some("Load All Scenes".to_string()),
+                entity_category: Some("config".to_string()),
+                origin: Origin::default(),
+                device: Device::for_device(device),
+                unique_id: format!("gv2mqtt-{id}-load-all-scenes", id = topic_safe_id(device)),
+                device_class: None,
+                icon: Some("mdi:playlist-plus".to_string()),
+            },
+            command_topic: format!("gv2mqtt/{id}/load-all-scenes", id = topic_safe_id(device)),
+            payload_press: None,
+        }
+    }
+
     pub fn request_platform_data_for_device(device: &ServiceDevice) -> Self {
         let unique_id = format!(
             "gv2mqtt-{id}-request-platform-data",
@@ -0,0 +1,399 @@
+//! Discovery payloads are sent for every entity of every device,
+//! which adds up to a lot of data for larger installations and
+//! can stress small brokers. Home Assistant accepts abbreviated
+//! keys in discovery payloads, and only needs the full device
+//! information to be described once; subsequent entities can
+//! refer to the device by its identifiers alone.
+//! This module rewrites the payloads accordingly, and tracks
+//! how much data was published so that the effect can be seen.
+//!
+//! The config topics that were published are remembered across
+//! restarts, so that entities that were registered under a differently
+//! formatted id for the same device by an earlier version can be
+//! removed from Home Assistant. Entities that simply weren't produced
+//! this time are left alone, as that may be the result of a transient
+//! failure to fetch the device information from the cloud, and removing
+//! them would lose any customizations made to them in Home Assistant.
+use crate::cache::cache_dir;
+use crate::service::device::normalize_device_id;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+/// Abbreviations for the entity level keys that we use.
+/// <https://www.home-assistant.io/integrations/mqtt/#supported-abbreviations-in-mqtt-discovery-messages>
+const ENTITY_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("availability_topic", "avty_t"),
+    ("brightness_scale", "bri_scl"),
+    ("command_topic", "cmd_t"),
+    ("device", "dev"),
+    ("device_class", "dev_cla"),
+    ("effect_list", "fx_list"),
+    ("enabled_by_default", "en"),
+    ("entity_category", "ent_cat"),
+    ("event_types", "evt_typ"),
+    ("icon", "ic"),
+    ("json_attributes_topic", "json_attr_t"),
+    ("max_humidity", "max_hum"),
+    ("max_mireds", "max_mirs"),
+    ("min_humidity", "min_hum"),
+    ("min_mireds", "min_mirs"),
+    ("mode_command_topic", "mode_cmd_t"),
+    ("mode_state_topic", "mode_stat_t"),
+    ("optimistic", "opt"),
+    ("options", "ops"),
+    ("origin", "o"),
+    ("payload_available", "pl_avail"),
+    ("payload_lock", "pl_lock"),
+    ("payload_on", "pl_on"),
+    ("payload_press", "pl_prs"),
+    ("payload_unlock", "pl_unlk"),
+    ("position_topic", "pos_t"),
+    ("set_position_topic", "set_pos_t"),
+    ("state_class", "stat_cla"),
+    ("state_locked", "stat_locked"),
+    ("state_topic", "stat_t"),
+    ("state_unlocked", "stat_unlocked"),
+    ("supported_color_modes", "sup_clrm"),
+    ("target_humidity_command_topic", "hum_cmd_t"),
+    ("target_humidity_state_topic", "hum_stat_t"),
+    ("unique_id", "uniq_id"),
+    ("unit_of_measurement", "unit_of_meas"),
+];
+
+const DEVICE_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("connections", "cns"),
+    ("identifiers", "ids"),
+    ("manufacturer", "mf"),
+    ("model", "mdl"),
+    ("suggested_area", "sa"),
+    ("sw_version", "sw"),
+];
+
+const ORIGIN_ABBREVIATIONS: &[(&str, &str)] = &[("sw_version", "sw"), ("support_url", "url")];
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiscoveryStats {
+    /// The number of discovery payloads published
+    pub payloads: usize,
+    /// The size the payloads would have been without minimization
+    pub full_bytes: usize,
+    /// The size of the payloads that were actually published
+    pub published_bytes: usize,
+}
+
+#[derive(Default)]
+struct Tracker {
+    /// Whether a registration of all entities is in progress
+    registering: bool,
+    /// Identifiers of the devices that have been fully described
+    /// during the current registration
+    described_devices: HashSet<String>,
+    stats: DiscoveryStats,
+    /// The config topics published during the current registration,
+    /// and the id of the device that each belongs to
+    published: ConfigTopics,
+}
+
+/// Config topic -> device id
+pub type ConfigTopics = BTreeMap<String, String>;
+
+impl Tracker {
+    fn begin(&mut self) {
+        self.registering = true;
+        self.described_devices.clear();
+        self.stats = DiscoveryStats::default();
+        self.published.clear();
+    }
+
+    /// Ends the registration, returning the config topics that were
+    /// published during it. Entities that are published afterwards,
+    /// such as those of a newly discovered device, describe their
+    /// device in full, as they aren't part of a registration.
+    fn finish(&mut self) -> ConfigTopics {
+        self.registering = false;
+        self.described_devices.clear();
+        std::mem::take(&mut self.published)
+    }
+
+    fn minimize_device(&mut self, device: Map<String, Value>) -> Value {
+        if self.registering {
+            minimize_device(device, &mut self.described_devices)
+        } else {
+            Value::Object(abbreviate(device, DEVICE_ABBREVIATIONS))
+        }
+    }
+}
+
+static TRACKER: Lazy<Mutex<Tracker>> = Lazy::new(Default::default);
+
+/// Called at the start of registering all entities with hass,
+/// so that each device is fully described once per registration
+/// and so that the stats reflect the most recent registration
+pub fn begin_registration() {
+    TRACKER.lock().begin();
+}
+
+/// Record that a config was published for an entity of a device,
+/// given the identifier of the device in its discovery payload,
+/// eg: `gv2mqtt-AABBCCDDEEFF422A`. The entities of the bridge
+/// itself and of groups are not tracked.
+pub fn record_config_topic(topic: &str, device_identifier: &str) {
+    let Some(id) = device_identifier.strip_prefix("gv2mqtt-") else {
+        return;
+    };
+    if id.starts_with("group-") {
+        return;
+    }
+    TRACKER
+        .lock()
+        .published
+        .insert(topic.to_string(), normalize_device_id(id));
+}
+
+fn config_topics_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-discovery-topics.json")
+}
+
+fn load_config_topics() -> ConfigTopics {
+    let Ok(data) = std::fs::read_to_string(config_topics_file_name()) else {
+        return ConfigTopics::new();
+    };
+    serde_json::from_str(&data)
+        .map_err(|err| log::warn!("Ignoring unreadable discovery topics: {err:#}"))
+        .unwrap_or_default()
+}
+
+fn save_config_topics(topics: &ConfigTopics) -> anyhow::Result<()> {
+    let path = config_topics_file_name();
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_string(topics)?)?;
+    std::fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+/// Returns a key that is the same for the config topics of an entity
+/// regardless of how the id of its device was formatted
+fn entity_key(topic: &str) -> String {
+    let mut key = topic.to_ascii_lowercase();
+    key.retain(|c| c != ':');
+    key
+}
+
+/// Returns the previously published topics that should be removed:
+/// those of devices that are still present, which were not published
+/// this time around, but for which the same entity was published
+/// under a differently formatted id. The topics of devices that are
+/// absent are left alone, as they may simply not have been
+/// discovered yet.
+fn stale_config_topics(
+    previous: &ConfigTopics,
+    current: &ConfigTopics,
+    live_devices: &HashSet<String>,
+) -> Vec<String> {
+    let current_keys: HashSet<String> = current.keys().map(|topic| entity_key(topic)).collect();
+    previous
+        .iter()
+        .filter(|(topic, id)| {
+            !current.contains_key(*topic)
+                && live_devices.contains(*id)
+                && current_keys.contains(&entity_key(topic))
+        })
+        .map(|(topic, _)| topic.to_string())
+        .collect()
+}
+
+/// Called once all entities have been registered. Returns the
+/// config topics that are stale and should be cleared, and
+/// remembers the topics that remain for next time.
+pub fn finish_registration(live_devices: &[String]) -> Vec<String> {
+    let live_devices: HashSet<String> = live_devices
+        .iter()
+        .map(|id| normalize_device_id(id))
+        .collect();
+    let previous = load_config_topics();
+    let mut current = TRACKER.lock().finish();
+    let stale = stale_config_topics(&previous, &current, &live_devices);
+
+    for (topic, id) in previous {
+        if !live_devices.contains(&id) {
+            current.entry(topic).or_insert(id);
+        }
+    }
+    if let Err(err) = save_config_topics(&current) {
+        log::warn!("Failed to save the discovery topics: {err:#}");
+    }
+
+    stale
+}
+
+/// Returns the stats for the most recent registration
+pub fn stats() -> DiscoveryStats {
+    TRACKER.lock().stats.clone()
+}
+
+fn abbreviate(map: Map<String, Value>, table: &[(&str, &str)]) -> Map<String, Value> {
+    map.into_iter()
+        .map(|(key, value)| {
+            let key = table
+                .iter()
+                .find(|(full, _)| *full == key)
+                .map(|(_, abbrev)| abbrev.to_string())
+                .unwrap_or(key);
+            (key, value)
+        })
+        .collect()
+}
+
+fn minimize_device(device: Map<String, Value>, described: &mut HashSet<String>) -> Value {
+    let device = abbreviate(device, DEVICE_ABBREVIATIONS);
+    let Some(ids) = device.get("ids").cloned() else {
+        return Value::Object(device);
+    };
+
+    if described.insert(ids.to_string()) {
+        return Value::Object(device);
+    }
+
+    let mut reference = Map::new();
+    reference.insert("ids".to_string(), ids);
+    Value::Object(reference)
+}
+
+/// Serialize a discovery payload, abbreviating it and replacing
+/// the device information with a reference to a device that
+/// has already been described
+pub fn serialize_config<T: Serialize>(config: &T) -> anyhow::Result<String> {
+    let full = serde_json::to_value(config)?;
+    let full_bytes = full.to_string().len();
+
+    let Value::Object(map) = full else {
+        anyhow::bail!("discovery payload must be an object, got {full}");
+    };
+
+    let mut tracker = TRACKER.lock();
+    let mut minimized = Map::new();
+    for (key, value) in abbreviate(map, ENTITY_ABBREVIATIONS) {
+        let value = match (key.as_str(), value) {
+            ("dev", Value::Object(device)) => tracker.minimize_device(device),
+            ("o", Value::Object(origin)) => Value::Object(abbreviate(origin, ORIGIN_ABBREVIATIONS)),
+            (_, value) => value,
+        };
+        minimized.insert(key, value);
+    }
+
+    let payload = Value::Object(minimized).to_string();
+    tracker.stats.payloads += 1;
+    tracker.stats.full_bytes += full_bytes;
+    tracker.stats.published_bytes += payload.len();
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stale_topics() {
+        let topics = |entries: &[(&str, &str)]| -> ConfigTopics {
+            entries
+                .iter()
+                .map(|(topic, id)| (topic.to_string(), id.to_string()))
+                .collect()
+        };
+        let previous = topics(&[
+            (
+                "homeassistant/light/gv2mqtt-AABBCCDDEEFF0011/config",
+                "AA:BB:CC:DD:EE:FF:00:11",
+            ),
+            (
+                "homeassistant/light/gv2mqtt-aabbccddeeff0011/config",
+                "AA:BB:CC:DD:EE:FF:00:11",
+            ),
+            (
+                "homeassistant/sensor/sensor-AABBCCDDEEFF0011-gv2mqtt-wifi/config",
+                "AA:BB:CC:DD:EE:FF:00:11",
+            ),
+            (
+                "homeassistant/light/gv2mqtt-1122334455667788/config",
+                "11:22:33:44:55:66:77:88",
+            ),
+        ]);
+        let current = topics(&[(
+            "homeassistant/light/gv2mqtt-AABBCCDDEEFF0011/config",
+            "AA:BB:CC:DD:EE:FF:00:11",
+        )]);
+        let live = ["AA:BB:CC:DD:EE:FF:00:11".to_string()]
+            .into_iter()
+            .collect();
+
+        // The duplicate of the live device is removed, but the entity
+        // that wasn't produced this time, perhaps because its info
+        // couldn't be fetched, and the device that hasn't been
+        // discovered yet are left alone
+        k9::assert_equal!(
+            stale_config_topics(&previous, &current, &live),
+            vec!["homeassistant/light/gv2mqtt-aabbccddeeff0011/config".to_string()]
+        );
+    }
+
+    #[test]
+    fn minimize_payloads() {
+        let entity = |unique_id: &str| {
+            json!({
+                "availability_topic": "gv2mqtt/availability",
+                "name": "Light",
+                "unique_id": unique_id,
+                "origin": {"name": "gv2mqtt", "sw_version": "1.0", "url": "x"},
+                "device": {
+                    "name": "Lamp",
+                    "manufacturer": "Govee",
+                    "model": "H6078",
+                    "identifiers": ["gv2mqtt-AABB"],
+                },
+                "schema": "json",
+            })
+        };
+
+        let mut described = HashSet::new();
+        let first = entity("a");
+        let device = first["device"].as_object().unwrap().clone();
+        k9::assert_equal!(
+            minimize_device(device.clone(), &mut described),
+            json!({
+                "ids": ["gv2mqtt-AABB"],
+                "mdl": "H6078",
+                "mf": "Govee",
+                "name": "Lamp",
+            })
+        );
+        k9::assert_equal!(
+            minimize_device(device.clone(), &mut described),
+            json!({"ids": ["gv2mqtt-AABB"]})
+        );
+
+        // Devices are only referenced during a registration
+        let mut tracker = Tracker::default();
+        tracker.begin();
+        assert!(tracker.minimize_device(device.clone()).get("mdl").is_some());
+        assert!(tracker.minimize_device(device.clone()).get("mdl").is_none());
+        tracker.finish();
+        assert!(tracker.minimize_device(device.clone()).get("mdl").is_some());
+        assert!(tracker.minimize_device(device.clone()).get("mdl").is_some());
+        tracker.begin();
+        assert!(tracker.minimize_device(device.clone()).get("mdl").is_some());
+
+        let payload = serialize_config(&entity("b")).unwrap();
+        let parsed: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(parsed["avty_t"], "gv2mqtt/availability");
+        assert_eq!(parsed["uniq_id"], "b");
+        assert_eq!(parsed["schema"], "json");
+        assert_eq!(parsed["o"]["sw"], "1.0");
+        assert!(payload.len() < entity("b").to_string().len());
+    }
+}
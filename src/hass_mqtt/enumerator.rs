@@ -1,33 +1,59 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::{
+    DerivedBinarySensor, IotLinkDiagnostic, LeakBinarySensor, LeakSensorKind, PresenceBinarySensor,
+    SensorAlarmProblem,
+};
 use crate::hass_mqtt::button::ButtonConfig;
 use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::event::{CommandFailedEvent, SensorAlarmEvent};
 use crate::hass_mqtt::humidifier::Humidifier;
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
+use crate::hass_mqtt::light::{DeviceLight, GroupLight};
+use crate::hass_mqtt::lock::ExtendedControlLock;
+use crate::hass_mqtt::number::{ActiveWorkModeNumber, CapabilityNumber, WorkModeNumber};
 use crate::hass_mqtt::scene::SceneConfig;
-use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
-use crate::hass_mqtt::switch::CapabilitySwitch;
+use crate::hass_mqtt::select::{
+    ColorPresetSelect, DisplayUnitSelect, ExtendedControlSelect, SceneCategorySelect,
+    SceneModeSelect, WorkModePresetSelect, WorkModeSelect,
+};
+use crate::hass_mqtt::sensor::{
+    BleSensor, BleSensorKind, CapabilitySensor, CloudRateLimitDiagnostic, DerivedSensor,
+    DeviceStatusDiagnostic, FirmwareDiagnostic, FirmwareDiagnosticKind, GlobalFixedDiagnostic,
+    LanRttDiagnostic, LeakBatterySensor, PlaylistSensor, PowerSensor, PowerSensorKind,
+    RateOfChangeSensor, ScheduleSensor, TaskHealthDiagnostic, WifiDiagnostic, WifiDiagnosticKind,
+};
+use crate::hass_mqtt::switch::{CapabilitySwitch, ExtendedControlSwitch, PowerSaveSwitch};
+use crate::hass_mqtt::update::FirmwareUpdateEntity;
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
 use crate::service::device::Device as ServiceDevice;
+use crate::service::device_group::device_groups;
 use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
+use crate::service::quirks::ExtendedControl;
+use crate::service::rate_of_change::RateMeasurement;
+use crate::service::shard::{is_leader, owns_device};
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
 use anyhow::Context;
+use serde_json::json;
 
 use uuid::Uuid;
 
 pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<EntityList> {
     let mut entities = EntityList::new();
 
-    enumerate_global_entities(state, &mut entities).await?;
-    enumerate_scenes(state, &mut entities).await?;
+    if is_leader() {
+        enumerate_global_entities(state, &mut entities).await?;
+        enumerate_scenes(state, &mut entities).await?;
+        enumerate_feasts(state, &mut entities).await?;
+        for group in device_groups(&state.devices().await) {
+            entities.add(GroupLight::new(&group, state));
+        }
+    }
 
     let devices = state.devices().await;
 
-    for d in &devices {
+    for d in devices.iter().filter(|d| owns_device(d)) {
         enumerate_entities_for_device(d, state, &mut entities)
             .await
             .with_context(|| format!("Config::for_device({d})"))?;
@@ -37,23 +63,43 @@ pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<Entity
 }
 
 async fn enumerate_global_entities(
-    _state: &StateHandle,
+    state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
     entities.add(GlobalFixedDiagnostic::new("Version", govee_version()));
     entities.add(ButtonConfig::new("Purge Caches", purge_cache_topic()));
+    entities.add(PowerSaveSwitch::new());
+    entities.add(TaskHealthDiagnostic::new(state));
+    entities.add(CommandFailedEvent::new());
+    if state.get_undoc_client().await.is_some() {
+        entities.add(IotLinkDiagnostic::new());
+    }
+    if state.get_undoc_client().await.is_some() || state.get_platform_client().await.is_some() {
+        entities.add(CloudRateLimitDiagnostic::new());
+    }
     Ok(())
 }
 
 async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> anyhow::Result<()> {
-    if let Some(undoc) = state.get_undoc_client().await {
-        match undoc.parse_one_clicks().await {
+    if state.get_undoc_client().await.is_some() {
+        match state.resolve_one_clicks().await {
             Ok(items) => {
                 for oc in items {
                     let unique_id = format!(
                         "gv2mqtt-one-click-{}",
                         Uuid::new_v5(&Uuid::NAMESPACE_DNS, oc.name.as_bytes()).simple()
                     );
+                    let devices: Vec<_> = oc
+                        .entries
+                        .iter()
+                        .map(|entry| {
+                            json!({
+                                "name": entry.name,
+                                "sku": entry.sku,
+                                "id": entry.device,
+                            })
+                        })
+                        .collect();
                     entities.add(SceneConfig {
                         base: EntityConfig {
                             availability_topic: availability_topic(),
@@ -67,6 +113,10 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
                         },
                         command_topic: oneclick_topic(),
                         payload_on: oc.name,
+                        json_attributes_topic: Some(format!(
+                            "gv2mqtt/scene/{unique_id}/attributes"
+                        )),
+                        attributes: Some(json!({ "devices": devices })),
                     });
                 }
             }
@@ -79,7 +129,32 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
     Ok(())
 }
 
-async fn entities_for_work_mode<'a>(
+async fn enumerate_feasts(state: &StateHandle, entities: &mut EntityList) -> anyhow::Result<()> {
+    if let Some(undoc) = state.get_undoc_client().await {
+        match undoc.parse_feasts().await {
+            Ok(feasts) => {
+                for feast in feasts {
+                    let Some(leader) = state.device_by_id(&feast.leader.device).await else {
+                        log::debug!(
+                            "Skipping feast {}: leader {} is not known",
+                            feast.name,
+                            feast.leader.device
+                        );
+                        continue;
+                    };
+                    entities.add(DeviceLight::for_feast(&feast, &leader, state).await?);
+                }
+            }
+            Err(err) => {
+                log::warn!("Failed to parse feasts: {err:#}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn entities_for_work_mode(
     d: &ServiceDevice,
     state: &StateHandle,
     cap: &DeviceCapability,
@@ -141,33 +216,227 @@ async fn entities_for_work_mode<'a>(
 
     entities.add(WorkModeSelect::new(d, &work_modes, state));
 
+    // Lamps such as the H6047 have presets that are better
+    // chosen by name than by a mode number and value
+    if d.device_type() == DeviceType::Light {
+        if let Some(presets) = WorkModePresetSelect::new(d, &work_modes, state) {
+            entities.add(presets);
+        }
+    }
+
+    // When more than one mode has an adjustable level, offer a single
+    // number that follows whichever of them is active
+    let adjustable_modes = work_modes
+        .modes
+        .values()
+        .filter(|mode| mode.contiguous_value_range().is_some())
+        .count();
+    if adjustable_modes > 1 {
+        entities.add(ActiveWorkModeNumber::new(d, state, &work_modes));
+    }
+
     Ok(())
 }
 
-pub async fn enumerate_entities_for_device<'a>(
-    d: &'a ServiceDevice,
+pub async fn enumerate_entities_for_device(
+    d: &ServiceDevice,
     state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
+    // BLE-only sensors are read-only, so expose them even though
+    // they cannot be controlled. Readings that are also available
+    // via the Platform API (eg: via a hub) are left to that.
+    if d.ble_sensor_reading.is_some() {
+        if d.get_capability_by_instance("sensorTemperature").is_none() {
+            entities.add(BleSensor::new(d, state, BleSensorKind::Temperature).await);
+        }
+        if d.get_capability_by_instance("sensorHumidity").is_none() {
+            entities.add(BleSensor::new(d, state, BleSensorKind::Humidity).await);
+        }
+        entities.add(BleSensor::new(d, state, BleSensorKind::Battery).await);
+    }
+
+    if d.supports_power_metering() {
+        for kind in [
+            PowerSensorKind::Power,
+            PowerSensorKind::Energy,
+            PowerSensorKind::Voltage,
+            PowerSensorKind::Current,
+        ] {
+            entities.add(PowerSensor::new(d, state, kind));
+        }
+    }
+
+    // Leak detectors are read-only, and have nothing else to offer
+    if d.is_leak_sensor() {
+        entities.add(LeakBinarySensor::new(d, state, LeakSensorKind::Leak));
+        entities.add(LeakBinarySensor::new(d, state, LeakSensorKind::Online));
+        entities.add(LeakBatterySensor::new(d, state));
+        return Ok(());
+    }
+
+    // Presence sensors are read-only, aside from the settings
+    // that the Platform API allows to be adjusted
+    if d.is_presence_sensor() {
+        entities.add(PresenceBinarySensor::new(d, state));
+        if let Some(info) = &d.http_device_info {
+            for cap in &info.capabilities {
+                if cap.kind == DeviceCapabilityKind::Range && cap.integer_range().is_some() {
+                    entities.add(CapabilityNumber::new(d, state, cap));
+                }
+            }
+        }
+        return Ok(());
+    }
+
     if !d.is_controllable() {
         return Ok(());
     }
 
     entities.add(DeviceStatusDiagnostic::new(d, state));
     entities.add(ButtonConfig::request_platform_data_for_device(d));
+    if state.device_has_many_scenes(d).await {
+        entities.add(ButtonConfig::load_all_scenes_for_device(d));
+    }
+    if d.lan_device.is_some() {
+        entities.add(LanRttDiagnostic::new(d, state));
+    }
+    if !d.schedules().is_empty() {
+        entities.add(ScheduleSensor::new(d, state));
+    }
+
+    let versions = d.firmware_versions();
+    for (kind, version) in [
+        (FirmwareDiagnosticKind::Version, &versions.version_soft),
+        (
+            FirmwareDiagnosticKind::HardwareVersion,
+            &versions.version_hard,
+        ),
+        (
+            FirmwareDiagnosticKind::WifiVersion,
+            &versions.wifi_soft_version,
+        ),
+        (
+            FirmwareDiagnosticKind::WifiHardwareVersion,
+            &versions.wifi_hard_version,
+        ),
+    ] {
+        if version.is_some() {
+            entities.add(FirmwareDiagnostic::new(d, state, kind));
+        }
+    }
+    // Pending updates are only known via the undocumented API
+    if d.undoc_device_info.is_some() {
+        entities.add(FirmwareUpdateEntity::new(d, state));
+    }
+
+    // Only some devices report these, so the sensors are created
+    // once a value has been seen; see Device::update_wifi_info
+    if d.has_wifi() {
+        for (kind, value) in [
+            (WifiDiagnosticKind::Rssi, d.wifi_rssi),
+            (WifiDiagnosticKind::Channel, d.wifi_channel),
+            (WifiDiagnosticKind::Level, d.wifi_level()),
+        ] {
+            if value.is_some() {
+                entities.add(WifiDiagnostic::new(d, state, kind));
+            }
+        }
+    }
+
+    let mut sensor_inputs = vec![];
+    if d.get_capability_by_instance("sensorTemperature").is_some() || d.ble_sensor_reading.is_some()
+    {
+        sensor_inputs.push("temperature");
+    }
+    if d.get_capability_by_instance("sensorHumidity").is_some() || d.ble_sensor_reading.is_some() {
+        sensor_inputs.push("humidity");
+    }
+    if !sensor_inputs.is_empty() {
+        for definition in state.get_derived_sensors().await {
+            if !definition.applies_to(d) || !definition.is_computable_with(&sensor_inputs) {
+                continue;
+            }
+            if definition.binary {
+                entities.add(DerivedBinarySensor::new(d, state, &definition));
+            } else {
+                entities.add(DerivedSensor::new(d, state, &definition));
+            }
+        }
+    }
+    if let Some(window) = state.get_rate_of_change_window().await {
+        if sensor_inputs.contains(&"temperature") {
+            entities.add(RateOfChangeSensor::new(
+                d,
+                state,
+                RateMeasurement::Temperature,
+                window,
+            ));
+        }
+        if sensor_inputs.contains(&"humidity") {
+            entities.add(RateOfChangeSensor::new(
+                d,
+                state,
+                RateMeasurement::Humidity,
+                window,
+            ));
+        }
+    }
+
+    if d.supports_sensor_alarms() {
+        entities.add(SensorAlarmProblem::new(d, state));
+        entities.add(SensorAlarmEvent::new(d));
+    }
 
     if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
-        entities.add(DeviceLight::for_device(&d, state, None).await?);
+        entities.add(DeviceLight::for_device(d, state, None).await?);
+    }
+
+    if let Some(presets) = ColorPresetSelect::new(d, state).await {
+        entities.add(presets);
     }
 
     if matches!(
         d.device_type(),
         DeviceType::Humidifier | DeviceType::Dehumidifier
     ) {
-        entities.add(Humidifier::new(&d, state).await?);
+        entities.add(Humidifier::new(d, state).await?);
+    }
+
+    for playlist in state.get_playlists().await {
+        if playlist.is_for_device(d) {
+            entities.add(PlaylistSensor::new(d, state, playlist.name()));
+            for command in ["play", "pause", "next", "stop"] {
+                entities.add(ButtonConfig::playlist_command(d, playlist.name(), command));
+            }
+        }
     }
 
-    if d.device_type() != DeviceType::Light {
+    if let Some(quirk) = d.resolve_quirk() {
+        for control in quirk.extended_controls {
+            match control {
+                ExtendedControl::Switch { .. } => {
+                    entities.add(ExtendedControlSwitch::new(d, state, control));
+                }
+                ExtendedControl::Select { .. } => {
+                    entities.add(ExtendedControlSelect::new(d, state, control));
+                }
+                ExtendedControl::Lock { .. } => {
+                    entities.add(ExtendedControlLock::new(d, state, control));
+                }
+            }
+        }
+    }
+
+    if d.display_temperature_scale().is_some() {
+        entities.add(DisplayUnitSelect::new(d, state));
+    }
+
+    if state.scene_select_per_category().await {
+        for (category, scenes) in state.device_scenes_by_category(d).await? {
+            entities.add(SceneCategorySelect::new(d, state, &category, scenes));
+        }
+    } else if d.device_type() != DeviceType::Light {
         if let Some(scenes) = SceneModeSelect::new(d, state).await? {
             entities.add(scenes);
         }
@@ -177,7 +446,7 @@ pub async fn enumerate_entities_for_device<'a>(
         for cap in &info.capabilities {
             match &cap.kind {
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
-                    entities.add(CapabilitySwitch::new(&d, state, cap).await?);
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
                 }
                 DeviceCapabilityKind::ColorSetting
                 | DeviceCapabilityKind::SegmentColorSetting
@@ -193,11 +462,11 @@ pub async fn enumerate_entities_for_device<'a>(
                 }
 
                 DeviceCapabilityKind::Property => {
-                    entities.add(CapabilitySensor::new(&d, state, cap).await?);
+                    entities.add(CapabilitySensor::new(d, state, cap).await?);
                 }
 
                 DeviceCapabilityKind::TemperatureSetting => {
-                    entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
+                    entities.add(TargetTemperatureEntity::new(d, state, cap).await?);
                 }
 
                 kind => {
@@ -209,9 +478,9 @@ pub async fn enumerate_entities_for_device<'a>(
             }
         }
 
-        if let Some(segments) = info.supports_segmented_rgb() {
+        if let Some(segments) = state.segment_range(d).await {
             for n in segments {
-                entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
+                entities.add(DeviceLight::for_device(d, state, Some(n)).await?);
             }
         }
     }
@@ -0,0 +1,178 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::{Device as ServiceDevice, SensorAlarm};
+use crate::service::event_log::record_event;
+use crate::service::hass::{availability_topic, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EventConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+    pub event_types: Vec<&'static str>,
+}
+
+impl EventConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("event", state, client, &self.base, self).await
+    }
+}
+
+/// Fires each time that the device raises an out of range alarm.
+/// Unlike most entities, this has no persistent state: events are
+/// published by `publish_sensor_alarm` as they are received.
+#[derive(Clone)]
+pub struct SensorAlarmEvent {
+    event: EventConfig,
+}
+
+impl SensorAlarmEvent {
+    pub fn new(device: &ServiceDevice) -> Self {
+        Self {
+            event: EventConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Alarm Triggered".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("event-{id}-alarm", id = topic_safe_id(device)),
+                    device_class: None,
+                    icon: Some("mdi:alarm-light".to_string()),
+                },
+                state_topic: Self::state_topic(device),
+                event_types: vec![
+                    "temperature_low",
+                    "temperature_high",
+                    "temperature_out_of_range",
+                    "humidity_low",
+                    "humidity_high",
+                    "humidity_out_of_range",
+                ],
+            },
+        }
+    }
+
+    fn state_topic(device: &ServiceDevice) -> String {
+        format!(
+            "gv2mqtt/event/event-{id}-alarm/state",
+            id = topic_safe_id(device)
+        )
+    }
+}
+
+#[async_trait]
+impl EntityInstance for SensorAlarmEvent {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.event.publish(state, client).await
+    }
+
+    async fn notify_state(&self, _client: &HassClient) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fire the SensorAlarmEvent for a newly raised alarm
+pub async fn publish_sensor_alarm(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    alarm: &SensorAlarm,
+) -> anyhow::Result<()> {
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish_obj(
+                SensorAlarmEvent::state_topic(device),
+                json!({
+                    "event_type": alarm.event_type(),
+                    "measurement": alarm.measurement,
+                    "value": alarm.value,
+                    "threshold": alarm.threshold,
+                }),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Fires when a command to a device is given up on after failing
+/// to go through the Govee cloud; see `cloud_failure`
+#[derive(Clone)]
+pub struct CommandFailedEvent {
+    event: EventConfig,
+}
+
+impl Default for CommandFailedEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandFailedEvent {
+    const STATE_TOPIC: &'static str = "gv2mqtt/event/global-command-failed/state";
+
+    pub fn new() -> Self {
+        Self {
+            event: EventConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Command Failed".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: "global-command-failed".to_string(),
+                    device_class: None,
+                    icon: Some("mdi:cloud-alert".to_string()),
+                },
+                state_topic: Self::STATE_TOPIC.to_string(),
+                event_types: vec!["dropped", "expired"],
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CommandFailedEvent {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.event.publish(state, client).await
+    }
+
+    async fn notify_state(&self, _client: &HassClient) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fire the CommandFailedEvent. `event_type` is `dropped` for a
+/// command that was given up on straight away, or `expired` for one
+/// that was held for a retry that never succeeded.
+pub async fn publish_command_failed(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    topic: &str,
+    event_type: &str,
+    error: &anyhow::Error,
+) -> anyhow::Result<()> {
+    record_event(
+        Some(device),
+        format!("command {topic} failed ({event_type}): {error:#}"),
+    );
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish_obj(
+                CommandFailedEvent::STATE_TOPIC,
+                json!({
+                    "event_type": event_type,
+                    "device": device.id,
+                    "device_name": device.name(),
+                    "topic": topic,
+                    "error": format!("{error:#}"),
+                }),
+            )
+            .await?;
+    }
+    Ok(())
+}
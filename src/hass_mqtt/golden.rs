@@ -0,0 +1,147 @@
+//! Golden file tests of the discovery payloads and state messages that
+//! are published for each of the devices in the fixture device lists.
+//! Each SKU has a file in `test-data/discovery` that holds exactly what
+//! is published for it, so that any change to the entities that are
+//! generated shows up as a difference to that file.
+//!
+//! After an intended change, regenerate the files by running
+//! `GOVEE_UPDATE_GOLDEN=1 cargo test golden` and review the differences.
+use crate::cache::{cache_seed, isolate_cache};
+use crate::hass_mqtt::discovery::begin_registration;
+use crate::hass_mqtt::enumerator::enumerate_entities_for_device;
+use crate::hass_mqtt::instance::EntityList;
+use crate::platform_api::{from_json, HttpDeviceInfo};
+use crate::service::hass::HassClient;
+use crate::service::state::{State, StateHandle};
+use crate::undoc_api::LightEffectCategory;
+use crate::version_info::govee_version;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Platform API device lists, whose devices are the fixtures
+const FIXTURES: &[&str] = &[
+    include_str!("../../test-data/list_devices.json"),
+    include_str!("../../test-data/list_devices_2.json"),
+    include_str!("../../test-data/list_devices_issue4.json"),
+];
+
+#[derive(Deserialize)]
+struct DeviceList {
+    data: Vec<HttpDeviceInfo>,
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/discovery")
+}
+
+/// The first device of each SKU in the fixtures
+fn fixture_devices() -> BTreeMap<String, HttpDeviceInfo> {
+    let mut devices = BTreeMap::new();
+    for fixture in FIXTURES {
+        let list: DeviceList = from_json(fixture).expect("fixture to parse");
+        for info in list.data {
+            devices.entry(info.sku.to_string()).or_insert(info);
+        }
+    }
+    devices
+}
+
+/// Render the messages published for a device: the topic of
+/// each, followed by its payload, pretty printed if it is JSON
+fn render(messages: &[(String, String)]) -> String {
+    let mut rendered = String::new();
+    for (topic, payload) in messages {
+        let payload = match serde_json::from_str::<serde_json::Value>(payload) {
+            Ok(value @ (serde_json::Value::Object(_) | serde_json::Value::Array(_))) => {
+                serde_json::to_string_pretty(&value).expect("json to serialize")
+            }
+            _ => payload.to_string(),
+        };
+        rendered.push_str(&format!("{topic}\n{payload}\n\n"));
+    }
+    // The version changes with every release
+    rendered.replace(govee_version(), "VERSION")
+}
+
+async fn published_for_device(info: &HttpDeviceInfo) -> String {
+    let state: StateHandle = Arc::new(State::new());
+    state
+        .set_hass_disco_prefix("homeassistant".to_string())
+        .await;
+    state
+        .device_mut(&info.sku, &info.device)
+        .await
+        .set_http_device_info(info.clone());
+    let device = state
+        .device_by_id(&info.device)
+        .await
+        .expect("device to exist");
+
+    let (client, messages) = HassClient::capture();
+    let mut entities = EntityList::new();
+    enumerate_entities_for_device(&device, &state, &mut entities)
+        .await
+        .expect("entities to enumerate");
+    begin_registration();
+    entities
+        .publish_config(&state, &client)
+        .await
+        .expect("configs to publish");
+    entities
+        .notify_state(&client)
+        .await
+        .expect("states to publish");
+
+    let messages = messages.lock().clone();
+    render(&messages)
+}
+
+/// Use an empty cache of our own, seeded with an empty scene library
+/// for each of the fixture SKUs, so that the output doesn't depend on
+/// the state of the user running the tests, nor on the network
+fn seed_scenes(devices: &BTreeMap<String, HttpDeviceInfo>) {
+    isolate_cache();
+    for sku in devices.keys() {
+        let scenes: Vec<LightEffectCategory> = vec![];
+        cache_seed("undoc-api", &format!("scenes-{sku}"), scenes).unwrap();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn golden() {
+    let update = std::env::var_os("GOVEE_UPDATE_GOLDEN").is_some();
+    let dir = golden_dir();
+    let mut mismatched = vec![];
+
+    let devices = fixture_devices();
+    seed_scenes(&devices);
+
+    for (sku, info) in devices {
+        let actual = published_for_device(&info).await;
+        let path = dir.join(format!("{sku}.txt"));
+        if update {
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(&path, &actual).unwrap();
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&path).unwrap_or_default();
+        if expected != actual {
+            let line = expected
+                .lines()
+                .zip(actual.lines())
+                .position(|(e, a)| e != a)
+                .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+            mismatched.push(format!("{} differs from line {}", path.display(), line + 1));
+        }
+    }
+
+    assert!(
+        mismatched.is_empty(),
+        "The published messages differ from the golden files; if that is \
+        intended, run `GOVEE_UPDATE_GOLDEN=1 cargo test golden` to update them:\n{}",
+        mismatched.join("\n")
+    );
+}
@@ -43,6 +43,15 @@ pub struct HumidifierConfig {
     pub modes: Vec<String>,
 
     pub state_topic: String,
+
+    /// we will publish the measured humidity here, if the
+    /// device has a humidity sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_humidity_topic: Option<String>,
+
+    /// we will publish what the device is currently doing here;
+    /// one of `off`, `humidifying` or `drying`
+    pub action_topic: String,
 }
 
 #[derive(Clone)]
@@ -55,7 +64,8 @@ pub struct Humidifier {
 impl Humidifier {
     pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
         let _quirk = device.resolve_quirk();
-        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
+        let use_iot =
+            device.iot_api_supported() && state.get_iot_client_for(device).await.is_some();
         let optimistic = !use_iot;
 
         let device_class = if device.device_type() == DeviceType::Humidifier {
@@ -80,6 +90,16 @@ impl Humidifier {
             id = topic_safe_id(device)
         );
         let state_topic = format!("gv2mqtt/humidifier/{id}/state", id = topic_safe_id(device));
+        let action_topic = format!("gv2mqtt/humidifier/{id}/action", id = topic_safe_id(device));
+        let current_humidity_topic =
+            device
+                .get_capability_by_instance("sensorHumidity")
+                .map(|_| {
+                    format!(
+                        "gv2mqtt/humidifier/{id}/current-humidity",
+                        id = topic_safe_id(device)
+                    )
+                });
 
         let mode_command_topic = format!(
             "gv2mqtt/humidifier/{id}/set-mode",
@@ -103,17 +123,16 @@ impl Humidifier {
 
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
-                match &cap.parameters {
-                    Some(DeviceParameters::Integer {
-                        range: IntegerRange { min, max, .. },
-                        unit,
-                    }) => {
-                        if unit.as_deref() == Some("unit.percent") {
-                            min_humidity.replace(*min as u8);
-                            max_humidity.replace(*max as u8);
-                        }
+                if let Some(DeviceParameters::Integer {
+                    range: IntegerRange { min, max, .. },
+                    unit,
+                }) = &cap.parameters
+                {
+                    // Some humidifiers don't specify the unit
+                    if matches!(unit.as_deref(), Some("unit.percent") | None) {
+                        min_humidity.replace(*min as u8);
+                        max_humidity.replace(*max as u8);
                     }
-                    _ => {}
                 }
             }
         }
@@ -148,6 +167,8 @@ impl Humidifier {
                 mode_state_topic,
                 modes,
                 state_topic,
+                current_humidity_topic,
+                action_topic,
                 optimistic,
             },
             device_id: device.id.to_string(),
@@ -176,19 +197,25 @@ impl EntityInstance for Humidifier {
             .await
             .expect("device to exist");
 
-        match device.device_state() {
-            Some(device_state) => {
-                let is_on = device_state.on;
-                client
-                    .publish(
-                        &self.humidifier.state_topic,
-                        if is_on { "ON" } else { "OFF" },
-                    )
-                    .await?;
-            }
-            None => {
-                client.publish(&self.humidifier.state_topic, "OFF").await?;
-            }
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        client
+            .publish(
+                &self.humidifier.state_topic,
+                if is_on { "ON" } else { "OFF" },
+            )
+            .await?;
+        client
+            .publish(
+                &self.humidifier.action_topic,
+                humidifier_action(&device.device_type(), is_on),
+            )
+            .await?;
+
+        if let (Some(topic), Some(humidity)) = (
+            &self.humidifier.current_humidity_topic,
+            device.sensor_humidity_percent(),
+        ) {
+            client.publish(topic, humidity.to_string()).await?;
         }
 
         if let Some(humidity) = device.target_humidity_percent {
@@ -242,6 +269,15 @@ impl EntityInstance for Humidifier {
     }
 }
 
+/// The value for the hass humidifier action topic
+fn humidifier_action(device_type: &DeviceType, is_on: bool) -> &'static str {
+    match (device_type, is_on) {
+        (_, false) => "off",
+        (DeviceType::Dehumidifier, true) => "drying",
+        (_, true) => "humidifying",
+    }
+}
+
 pub async fn mqtt_device_set_work_mode(
     Payload(mode): Payload<String>,
     Params(IdParameter { id }): Params<IdParameter>,
@@ -277,7 +313,7 @@ pub async fn mqtt_humidifier_set_target(
 
     let device = state.resolve_device_for_control(&id).await?;
 
-    let use_iot = device.pollable_via_iot() && state.get_iot_client().await.is_some();
+    let use_iot = device.pollable_via_iot() && state.get_iot_client_for(&device).await.is_some();
 
     if !use_iot {
         if let Some(info) = &device.http_device_info {
@@ -1,4 +1,7 @@
 use crate::hass_mqtt::base::EntityConfig;
+use crate::hass_mqtt::discovery::{record_config_topic, serialize_config};
+use crate::service::capability_probe::is_entity_unsupported;
+use crate::service::entity_overrides::entity_id_override;
 use crate::service::hass::HassClient;
 use crate::service::state::StateHandle;
 use anyhow::Context;
@@ -19,15 +22,41 @@ pub async fn publish_entity_config<T: Serialize>(
     base: &EntityConfig,
     config: &T,
 ) -> anyhow::Result<()> {
-    // TODO: remember all published topics for future GC
-
     let disco = state.get_hass_disco_prefix().await;
     let topic = format!(
         "{disco}/{integration}/{unique_id}/config",
         unique_id = base.unique_id
     );
 
-    client.publish_obj(topic, config).await
+    let unsupported = is_entity_unsupported(&base.unique_id);
+    let entity_id = entity_id_override(integration, &base.unique_id);
+    let customization = base.device.customization.as_ref();
+    let disabled = customization
+        .map(|c| c.disables(&base.unique_id, base.name.as_deref()))
+        .unwrap_or(false);
+    // The entity without a name of its own is the main entity of the device
+    let icon = customization
+        .and_then(|c| c.icon.as_ref())
+        .filter(|_| base.name.is_none());
+    let payload = if unsupported || disabled || entity_id.is_some() || icon.is_some() {
+        let mut config = serde_json::to_value(config)?;
+        if unsupported || disabled {
+            config["enabled_by_default"] = false.into();
+        }
+        if let Some(entity_id) = entity_id {
+            config["default_entity_id"] = entity_id.into();
+        }
+        if let Some(icon) = icon {
+            config["icon"] = icon.to_string().into();
+        }
+        serialize_config(&config)?
+    } else {
+        serialize_config(config)?
+    };
+    if let Some(identifier) = base.device.identifiers.first() {
+        record_config_topic(&topic, identifier);
+    }
+    client.publish(topic, payload).await
 }
 
 #[derive(Default, Clone)]
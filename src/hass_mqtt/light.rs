@@ -1,15 +1,22 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceType;
+use crate::scene_param::{scene_param_summaries, SceneParamSummary};
 use crate::service::device::Device as ServiceDevice;
+use crate::service::device_group::{
+    aggregate_group_state, device_groups, group_command_topic, group_state_topic, DeviceGroup,
+};
 use crate::service::hass::{
     availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
     topic_safe_id, HassClient,
 };
 use crate::service::state::StateHandle;
+use crate::service::state_merge::describe_sources;
+use crate::undoc_api::{GoveeUndocumentedApi, ParsedFeast};
 use async_trait::async_trait;
 use serde::Serialize;
 use serde_json::json;
+use std::collections::BTreeMap;
 
 /// <https://www.home-assistant.io/integrations/light.mqtt/#json-schema>
 #[derive(Serialize, Clone, Debug)]
@@ -46,6 +53,9 @@ pub struct LightConfig {
     pub max_mireds: Option<u32>,
 
     pub payload_available: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
 }
 
 impl LightConfig {
@@ -57,6 +67,10 @@ impl LightConfig {
 #[derive(Clone)]
 pub struct DeviceLight {
     light: LightConfig,
+    /// Maps scene names to the URL of their locally proxied icon
+    scene_icons: BTreeMap<String, String>,
+    /// The decoded scene params, keyed by scene name
+    scene_params: BTreeMap<String, SceneParamSummary>,
     device_id: String,
     state: StateHandle,
 }
@@ -64,7 +78,7 @@ pub struct DeviceLight {
 #[async_trait]
 impl EntityInstance for DeviceLight {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.light.publish(&state, &client).await
+        self.light.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -112,7 +126,26 @@ impl EntityInstance for DeviceLight {
 
                 client
                     .publish_obj(&self.light.state_topic, &light_state)
-                    .await
+                    .await?;
+
+                if let Some(topic) = &self.light.json_attributes_topic {
+                    client
+                        .publish_obj(
+                            topic,
+                            json!({
+                                "recent_scenes": device.scene_history,
+                                "scene_icons": self.scene_icons,
+                                "scene_param": device_state
+                                    .scene
+                                    .as_ref()
+                                    .and_then(|scene| self.scene_params.get(scene)),
+                                "state_sources": describe_sources(&device_state),
+                                "last_updated": device_state.updated,
+                            }),
+                        )
+                        .await?;
+                }
+                Ok(())
             }
             None => {
                 // TODO: mark as unavailable or something? Don't
@@ -127,6 +160,23 @@ impl EntityInstance for DeviceLight {
 }
 
 impl DeviceLight {
+    /// Represent a feast (eg: a DreamView group) as a logical light.
+    /// Its state mirrors that of the leader device, and commands
+    /// are routed via the leader.
+    pub async fn for_feast(
+        feast: &ParsedFeast,
+        leader: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<Self> {
+        let mut light = Self::for_device(leader, state, None).await?;
+        light.light.base.name = Some(format!("Feast: {}", feast.name));
+        light.light.base.device = Device::this_service();
+        light.light.base.unique_id = format!("gv2mqtt-feast-{}", feast.id);
+        light.light.command_topic = format!("gv2mqtt/feast/{}/command", feast.id);
+        light.light.json_attributes_topic = None;
+        Ok(light)
+    }
+
     pub async fn for_device(
         device: &ServiceDevice,
         state: &StateHandle,
@@ -157,13 +207,13 @@ impl DeviceLight {
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
             id = topic_safe_id(device),
-            seg = segment.map(|n| format!("-{n}")).unwrap_or(String::new())
+            seg = segment.map(|n| format!("-{n}")).unwrap_or_default()
         );
 
         let effect_list = if segment.is_some() {
             vec![]
         } else {
-            match state.device_list_scenes(device).await {
+            match state.device_published_scenes(device).await {
                 Ok(scenes) => scenes,
                 Err(err) => {
                     log::error!("Unable to list scenes for {device}: {err:#}");
@@ -172,6 +222,41 @@ impl DeviceLight {
             }
         };
 
+        let mut scene_icons = BTreeMap::new();
+        if let (None, Some(base_url)) = (segment, state.get_scene_icon_base_url().await) {
+            match GoveeUndocumentedApi::get_scene_ids_with_icons(&device.sku).await {
+                Ok(scenes) => {
+                    let base_url = base_url.trim_end_matches('/');
+                    for (name, scene_id) in scenes {
+                        if effect_list.contains(&name) {
+                            scene_icons.insert(
+                                name,
+                                format!(
+                                    "{base_url}/api/scene-icon/{sku}/{scene_id}",
+                                    sku = device.sku
+                                ),
+                            );
+                        }
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Unable to list scene icons for {device}: {err:#}");
+                }
+            }
+        }
+
+        let scene_params = if segment.is_some() {
+            BTreeMap::new()
+        } else {
+            match scene_param_summaries(&device.sku).await {
+                Ok(params) => params,
+                Err(err) => {
+                    log::warn!("Unable to decode scene params for {device}: {err:#}");
+                    BTreeMap::new()
+                }
+            }
+        };
+
         let mut supported_color_modes = vec![];
         let mut color_mode = false;
 
@@ -180,17 +265,16 @@ impl DeviceLight {
             color_mode = true;
         }
 
-        let (min_mireds, max_mireds) = if segment.is_some() {
-            (None, None)
-        } else if let Some((min, max)) = device.get_color_temperature_range() {
-            supported_color_modes.push("color_temp".to_string());
-            color_mode = true;
-            // Note that min and max are swapped by the translation
-            // from kelvin to mired
-            (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
-        } else {
-            (None, None)
-        };
+        let (min_mireds, max_mireds) =
+            if let Some((min, max)) = device.get_color_temperature_range() {
+                supported_color_modes.push("color_temp".to_string());
+                color_mode = true;
+                // Note that min and max are swapped by the translation
+                // from kelvin to mired
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            } else {
+                (None, None)
+            };
 
         let brightness = segment.is_some()
             || quirk
@@ -203,6 +287,14 @@ impl DeviceLight {
                 .map(|info| info.supports_brightness())
                 .unwrap_or(false);
 
+        let json_attributes_topic = match segment {
+            Some(_) => None,
+            None => Some(format!(
+                "gv2mqtt/light/{id}/attributes",
+                id = topic_safe_id(device)
+            )),
+        };
+
         let name = match segment {
             Some(n) => Some(format!("Segment {:03}", n + 1)),
             None if device_type == DeviceType::Humidifier => Some("Night Light".to_string()),
@@ -235,9 +327,134 @@ impl DeviceLight {
                 min_mireds,
                 optimistic: segment.is_some(),
                 icon,
+                json_attributes_topic,
             },
+            scene_icons,
+            scene_params,
             device_id: device.id.to_string(),
             state: state.clone(),
         })
     }
 }
+
+/// Represents a Govee device group (eg: a room) as a single light.
+/// Commands fan out to each of the member lights, and the state
+/// is aggregated from that of the members.
+#[derive(Clone)]
+pub struct GroupLight {
+    light: LightConfig,
+    group_id: u64,
+    state: StateHandle,
+}
+
+impl GroupLight {
+    pub fn new(group: &DeviceGroup, state: &StateHandle) -> Self {
+        let mut supported_color_modes = vec![];
+        if group.members.iter().any(|m| m.supports_rgb()) {
+            supported_color_modes.push("rgb".to_string());
+        }
+
+        let ranges: Vec<(u32, u32)> = group
+            .members
+            .iter()
+            .filter_map(|m| m.get_color_temperature_range())
+            .collect();
+        let (min_mireds, max_mireds) = match (
+            ranges.iter().map(|(min, _)| *min).min(),
+            ranges.iter().map(|(_, max)| *max).max(),
+        ) {
+            (Some(min), Some(max)) => {
+                supported_color_modes.push("color_temp".to_string());
+                // Note that min and max are swapped by the translation
+                // from kelvin to mired
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            }
+            _ => (None, None),
+        };
+
+        Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(format!("Group: {}", group.name)),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: format!("gv2mqtt-group-{}", group.id),
+                    entity_category: None,
+                    icon: None,
+                },
+                schema: "json".to_string(),
+                command_topic: group_command_topic(group.id),
+                state_topic: group_state_topic(group.id),
+                color_mode: !supported_color_modes.is_empty(),
+                supported_color_modes,
+                brightness: group.members.iter().any(|m| m.supports_brightness()),
+                brightness_scale: 100,
+                // The members don't share a scene list
+                effect: false,
+                effect_list: vec![],
+                payload_available: "online".to_string(),
+                max_mireds,
+                min_mireds,
+                optimistic: false,
+                icon: Some("mdi:lightbulb-group".to_string()),
+                json_attributes_topic: None,
+            },
+            group_id: group.id,
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for GroupLight {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.light.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let devices = self.state.devices().await;
+        let Some(group) = device_groups(&devices)
+            .into_iter()
+            .find(|group| group.id == self.group_id)
+        else {
+            return Ok(());
+        };
+
+        let states: Vec<_> = group
+            .members
+            .iter()
+            .filter_map(|m| m.device_state())
+            .collect();
+        let Some(group_state) = aggregate_group_state(&states) else {
+            return Ok(());
+        };
+
+        let light_state = if !group_state.on {
+            json!({"state":"OFF"})
+        } else if group_state.kelvin == 0 {
+            json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {
+                    "r": group_state.color.r,
+                    "g": group_state.color.g,
+                    "b": group_state.color.b,
+                },
+                "brightness": group_state.brightness,
+            })
+        } else {
+            json!({
+                "state": "ON",
+                "color_mode": "color_temp",
+                "brightness": group_state.brightness,
+                "color_temp": kelvin_to_mired(group_state.kelvin),
+            })
+        };
+
+        client
+            .publish_obj(&self.light.state_topic, &light_state)
+            .await
+    }
+}
@@ -0,0 +1,94 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, extended_control_command_topic, extended_control_state_topic,
+    topic_safe_id, HassClient,
+};
+use crate::service::quirks::ExtendedControl;
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LockConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+    pub command_topic: String,
+    pub state_topic: String,
+    pub payload_lock: String,
+    pub payload_unlock: String,
+    pub state_locked: String,
+    pub state_unlocked: String,
+    pub optimistic: bool,
+}
+
+impl LockConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("lock", state, client, &self.base, self).await
+    }
+}
+
+/// A lock for one of the quirk-defined extended controls
+pub struct ExtendedControlLock {
+    lock: LockConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: &'static str,
+}
+
+impl ExtendedControlLock {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, control: &ExtendedControl) -> Self {
+        let instance = control.instance();
+        let unique_id = format!("gv2mqtt-{id}-{instance}", id = topic_safe_id(device));
+
+        Self {
+            lock: LockConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(control.name().to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some(control.icon().to_string()),
+                },
+                command_topic: extended_control_command_topic(device, instance),
+                state_topic: extended_control_state_topic(device, instance),
+                // We report the most recently applied command as the
+                // state, so the state values match the payloads
+                payload_lock: "LOCK".to_string(),
+                payload_unlock: "UNLOCK".to_string(),
+                state_locked: "LOCK".to_string(),
+                state_unlocked: "UNLOCK".to_string(),
+                // The device doesn't report whether it is locked, so
+                // the state is only ever the last command that we sent
+                optimistic: true,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ExtendedControlLock {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.lock.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(value) = device.extended_control_values.get(self.instance_name) {
+            client.publish(&self.lock.state_topic, value).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,21 @@
 pub mod base;
+pub mod binary_sensor;
 pub mod button;
 pub mod climate;
 pub mod cover;
+pub mod discovery;
 pub mod enumerator;
+pub mod event;
+#[cfg(test)]
+mod golden;
 pub mod humidifier;
 pub mod instance;
 pub mod light;
+pub mod lock;
 pub mod number;
 pub mod scene;
 pub mod select;
 pub mod sensor;
 pub mod switch;
+pub mod update;
 pub mod work_mode;
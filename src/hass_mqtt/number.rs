@@ -1,11 +1,18 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::hass_mqtt::work_mode::{ParsedWorkMode, WorkMode};
+use crate::platform_api::DeviceCapability;
+use crate::service::device::{Device as ServiceDevice, PresenceReading};
+use crate::service::hass::{
+    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string,
+    HassClient, IdAndInst, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
+use chrono::Utc;
 use mosquitto_rs::router::{Params, Payload, State};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::ops::Range;
@@ -116,7 +123,7 @@ impl WorkModeNumber {
 #[async_trait]
 impl EntityInstance for WorkModeNumber {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.number.publish(&state, &client).await
+        self.number.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -132,28 +139,14 @@ impl EntityInstance for WorkModeNumber {
             .await
             .expect("device to exist");
 
-        if let Some(cap) = device.get_state_capability_by_instance("workMode") {
-            if let Some(work_mode) = cap.state.pointer("/value/workMode") {
-                if *work_mode == self.work_mode {
-                    // The current mode matches us, so it is valid to
-                    // read the current parameter for that mode
-
-                    if let Some(value) = cap.state.pointer("/value/modeValue") {
-                        if let Some(n) = value.as_i64() {
-                            client.publish(state_topic, n.to_string()).await?;
-                            return Ok(());
-                        }
-                    }
-                }
-            }
-        }
-
-        if let Some(work_mode) = self.work_mode.as_i64() {
-            // FIXME: assuming humidifier, rename that field?
-            if let Some(n) = device.humidifier_param_by_mode.get(&(work_mode as u8)) {
-                client.publish(state_topic, n.to_string()).await?;
-                return Ok(());
-            }
+        let mode = WorkMode {
+            name: self.mode_name.to_string(),
+            value: self.work_mode.clone(),
+            ..WorkMode::default()
+        };
+        if let Some(n) = mode.current_value(&device) {
+            client.publish(state_topic, n.to_string()).await?;
+            return Ok(());
         }
 
         // We might get some data to report later, so this is just debug for now
@@ -167,6 +160,280 @@ impl EntityInstance for WorkModeNumber {
     }
 }
 
+/// A number that sets an integer capability of the Platform API,
+/// such as the sensitivity of a presence sensor
+pub struct CapabilityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl CapabilityNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, cap: &DeviceCapability) -> Self {
+        let command_topic = format!(
+            "gv2mqtt/number/{id}/capability/{inst}",
+            id = topic_safe_id(device),
+            inst = cap.instance
+        );
+        let state_topic = format!(
+            "gv2mqtt/number/{id}/capability-state/{inst}",
+            id = topic_safe_id(device),
+            inst = cap.instance
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = cap.instance
+        );
+        let range = cap.integer_range();
+
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(camel_case_to_space_separated(&cap.instance)),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: None,
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: range.map(|r| r.min as f32),
+                max: range.map(|r| r.max as f32),
+                step: range.map(|r| r.precision.max(1) as f32).unwrap_or(1f32),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: cap.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CapabilityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let reported = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value").and_then(|v| v.as_i64()));
+        // The device list of the undocumented API also
+        // reports the sensitivity of presence sensors
+        let value = reported.or_else(|| match self.instance_name.as_str() {
+            "sensitivity" => device.presence_reading.and_then(|r| r.sensitivity),
+            _ => None,
+        });
+
+        match value {
+            Some(value) => self.number.notify_state(client, &value.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn mqtt_capability_number_command(
+    Payload(value): Payload<i64>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {value}");
+    let device = state.resolve_device_for_control(&id).await?;
+    let client = state
+        .get_platform_client_for(&device)
+        .await
+        .ok_or_else(|| anyhow!("setting {instance} of {id} requires the Platform API"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow!("No platform state available to set {id} {instance}"))?;
+    let applied = client.set_range(info, &instance, value).await?;
+
+    {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        if !device.set_state_capability_value(&instance, applied.value.clone())
+            && instance == "sensitivity"
+        {
+            // Mirror the fallback used by CapabilityNumber::notify_state
+            device.update_presence_reading(
+                PresenceReading {
+                    sensitivity: applied.value.as_i64(),
+                    ..Default::default()
+                },
+                Utc::now(),
+            );
+        }
+    }
+    state.notify_of_state_change(&device.id).await
+}
+
+/// A number that adjusts the parameter of whichever work mode is
+/// currently active. The range of the parameter depends on the mode,
+/// so the config is re-published with the appropriate range whenever
+/// the mode changes.
+pub struct ActiveWorkModeNumber {
+    number: Mutex<NumberConfig>,
+    device_id: String,
+    state: StateHandle,
+}
+
+/// Updates the config to reflect the range of a work mode,
+/// returning true if it changed
+fn apply_work_mode_range(number: &mut NumberConfig, range: &Range<i64>) -> bool {
+    let min = Some(range.start as f32);
+    let max = Some(range.end.saturating_sub(1) as f32);
+    if number.min == min && number.max == max {
+        return false;
+    }
+    number.min = min;
+    number.max = max;
+    true
+}
+
+impl ActiveWorkModeNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, work_modes: &ParsedWorkMode) -> Self {
+        let command_topic = active_work_mode_number_command_topic(device);
+        let state_topic = format!(
+            "gv2mqtt/number/{id}/state/active-work-mode",
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-active-work-mode-number",
+            id = topic_safe_id(device)
+        );
+
+        let mut number = NumberConfig {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: Some("Mode Level".to_string()),
+                device_class: None,
+                origin: Origin::default(),
+                device: Device::for_device(device),
+                unique_id,
+                entity_category: None,
+                icon: Some("mdi:tune-variant".to_string()),
+            },
+            command_topic,
+            state_topic: Some(state_topic),
+            min: Some(0.),
+            max: Some(255.),
+            step: 1f32,
+            unit_of_measurement: None,
+        };
+        if let Some(range) = work_modes
+            .current_mode(device)
+            .and_then(|mode| mode.contiguous_value_range())
+        {
+            apply_work_mode_range(&mut number, &range);
+        }
+
+        Self {
+            number: Mutex::new(number),
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ActiveWorkModeNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        let number = self.number.lock().clone();
+        number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let work_modes = ParsedWorkMode::with_device(&device)?;
+        let Some(mode) = work_modes.current_mode(&device) else {
+            return Ok(());
+        };
+        let Some(range) = mode.contiguous_value_range() else {
+            // This mode has no adjustable level
+            return Ok(());
+        };
+
+        let (number, range_changed) = {
+            let mut number = self.number.lock();
+            let changed = apply_work_mode_range(&mut number, &range);
+            (number.clone(), changed)
+        };
+        if range_changed {
+            log::info!(
+                "Work mode for {device} is now {}, updating level range to {range:?}",
+                mode.name
+            );
+            number.publish(&self.state, client).await?;
+        }
+
+        if let Some(n) = mode.current_value(&device) {
+            number.notify_state(client, &n.to_string()).await?;
+        }
+        Ok(())
+    }
+}
+
+pub fn active_work_mode_number_command_topic(device: &ServiceDevice) -> String {
+    format!(
+        "gv2mqtt/number/{id}/command/active-work-mode",
+        id = topic_safe_id(device)
+    )
+}
+
+pub async fn mqtt_active_work_mode_number_command(
+    Payload(value): Payload<i64>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("Mode Level for {id}: {value}");
+    let Some(device) = state
+        .resolve_device_for_coalesced_control(&id, "active-work-mode")
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let mode = work_modes
+        .current_mode(&device)
+        .ok_or_else(|| anyhow!("the current work mode of {device} is not known"))?;
+    let work_mode = mode
+        .value
+        .as_i64()
+        .ok_or_else(|| anyhow!("work mode {} has a non-integer value", mode.name))?;
+    if let Some(range) = mode.contiguous_value_range() {
+        if !range.contains(&value) {
+            anyhow::bail!(
+                "{value} is outside of the range {range:?} for work mode {}",
+                mode.name
+            );
+        }
+    }
+
+    state
+        .humidifier_set_parameter(&device, work_mode, value)
+        .await?;
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct IdAndModeName {
     id: String,
@@ -185,7 +452,12 @@ pub async fn mqtt_number_command(
 ) -> anyhow::Result<()> {
     log::info!("{mode_name} for {id}: {value}");
     let work_mode: i64 = work_mode.parse()?;
-    let device = state.resolve_device_for_control(&id).await?;
+    let Some(device) = state
+        .resolve_device_for_coalesced_control(&id, &format!("work-mode-{work_mode}"))
+        .await?
+    else {
+        return Ok(());
+    };
 
     state
         .humidifier_set_parameter(&device, work_mode, value)
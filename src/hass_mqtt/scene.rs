@@ -4,6 +4,7 @@ use crate::service::hass::HassClient;
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
+use serde_json::Value as JsonValue;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SceneConfig {
@@ -12,6 +13,12 @@ pub struct SceneConfig {
 
     pub command_topic: String,
     pub payload_on: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+
+    /// Published to json_attributes_topic, if set
+    #[serde(skip)]
+    pub attributes: Option<JsonValue>,
 }
 
 impl SceneConfig {
@@ -23,11 +30,14 @@ impl SceneConfig {
 #[async_trait]
 impl EntityInstance for SceneConfig {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.publish(&state, &client).await
+        self.publish(state, client).await
     }
 
-    async fn notify_state(&self, _client: &HassClient) -> anyhow::Result<()> {
-        // Scenes have no state
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        // Scenes have no state, but may have attributes
+        if let (Some(topic), Some(attributes)) = (&self.json_attributes_topic, &self.attributes) {
+            client.publish_obj(topic, attributes).await?;
+        }
         Ok(())
     }
 }
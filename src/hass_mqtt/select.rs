@@ -1,14 +1,19 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::service::color_preset::PresetColor;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, extended_control_command_topic, extended_control_state_topic,
+    topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
+use crate::service::quirks::ExtendedControl;
 use crate::service::state::StateHandle;
+use crate::temperature::TemperatureScale;
 use anyhow::Context;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
-use serde_json::json;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SelectConfig {
@@ -64,7 +69,7 @@ impl WorkModeSelect {
 #[async_trait]
 impl EntityInstance for WorkModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -74,32 +79,113 @@ impl EntityInstance for WorkModeSelect {
             .await
             .expect("device to exist");
 
-        if let Some(mode_value) = device.humidifier_work_mode {
-            if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
-                let mode_value_json = json!(mode_value);
-                if let Some(mode) = work_mode.mode_for_value(&mode_value_json) {
-                    client
-                        .publish(&self.select.state_topic, mode.name.to_string())
-                        .await?;
-                }
-            }
-        } else {
-            let work_modes = ParsedWorkMode::with_device(&device)?;
-
-            if let Some(cap) = device.get_state_capability_by_instance("workMode") {
-                if let Some(mode_num) = cap.state.pointer("/value/workMode") {
-                    if let Some(mode) = work_modes.mode_for_value(mode_num) {
-                        return client
-                            .publish(&self.select.state_topic, mode.name.to_string())
-                            .await;
-                    }
-                }
-            }
+        let work_modes = match ParsedWorkMode::with_device(&device) {
+            Ok(work_modes) => work_modes,
+            Err(_) if device.humidifier_work_mode.is_some() => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if let Some(mode) = work_modes.current_mode(&device) {
+            client
+                .publish(&self.select.state_topic, mode.name.to_string())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Offers the native presets of a lamp, such as Focus or Relax
+pub struct WorkModePresetSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl WorkModePresetSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        work_modes: &ParsedWorkMode,
+        state: &StateHandle,
+    ) -> Option<Self> {
+        let presets = work_modes.presets();
+        if presets.is_empty() {
+            return None;
         }
+
+        let command_topic = format!(
+            "gv2mqtt/{id}/set-work-mode-preset",
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "gv2mqtt/{id}/notify-work-mode-preset",
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-workModePreset", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Preset".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:desk-lamp".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: presets.into_iter().map(|preset| preset.label).collect(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WorkModePresetSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let work_modes = ParsedWorkMode::with_device(&device)?;
+        client
+            .publish(
+                &self.select.state_topic,
+                work_modes
+                    .current_preset(&device)
+                    .map(|preset| preset.label)
+                    .unwrap_or_default(),
+            )
+            .await?;
         Ok(())
     }
 }
 
+pub async fn mqtt_set_work_mode_preset(
+    Payload(label): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_work_mode_preset(&device, &label)
+        .await
+        .context("mqtt_set_work_mode_preset: state.device_set_work_mode_preset")?;
+
+    Ok(())
+}
+
 pub struct SceneModeSelect {
     select: SelectConfig,
     device_id: String,
@@ -108,7 +194,7 @@ pub struct SceneModeSelect {
 
 impl SceneModeSelect {
     pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Option<Self>> {
-        let scenes = state.device_list_scenes(device).await?;
+        let scenes = state.device_published_scenes(device).await?;
         if scenes.is_empty() {
             return Ok(None);
         }
@@ -143,7 +229,7 @@ impl SceneModeSelect {
 #[async_trait]
 impl EntityInstance for SceneModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -166,6 +252,73 @@ impl EntityInstance for SceneModeSelect {
     }
 }
 
+/// Offers the scenes of one category, eg: Nature, for devices with so
+/// many scenes that a single list of them is unwieldy. Choosing a scene
+/// sets it just as choosing it from the effect list of the light does.
+pub struct SceneCategorySelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl SceneCategorySelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        category: &str,
+        scenes: Vec<String>,
+    ) -> Self {
+        let id = topic_safe_id(device);
+        let category_id = topic_safe_string(category);
+        let unique_id = format!("gv2mqtt-{id}-scenes-{category_id}");
+
+        Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(format!("Scenes: {category}")),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:palette".to_string()),
+                },
+                command_topic: format!("gv2mqtt/{id}/set-mode-scene"),
+                state_topic: format!("gv2mqtt/{id}/notify-scene-category-{category_id}"),
+                options: scenes,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for SceneCategorySelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        // Only the select of the category of the active scene shows it
+        let scene = device
+            .device_state()
+            .and_then(|s| s.scene)
+            .filter(|scene| self.select.options.contains(scene))
+            .unwrap_or_default();
+        client.publish(&self.select.state_topic, scene).await?;
+
+        Ok(())
+    }
+}
+
 pub async fn mqtt_set_mode_scene(
     Payload(scene): Payload<String>,
     Params(IdParameter { id }): Params<IdParameter>,
@@ -180,3 +333,308 @@ pub async fn mqtt_set_mode_scene(
 
     Ok(())
 }
+
+/// Controls the temperature unit shown on the device's own display.
+/// This is independent of the unit used when reporting temperatures
+/// to Home Assistant.
+pub struct DisplayUnitSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DisplayUnitSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let command_topic = format!("gv2mqtt/{id}/set-display-unit", id = topic_safe_id(device));
+        let state_topic = format!(
+            "gv2mqtt/{id}/notify-display-unit",
+            id = topic_safe_id(device)
+        );
+        let availability_topic = availability_topic();
+        let unique_id = format!("gv2mqtt-{id}-display-unit", id = topic_safe_id(device));
+
+        Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic,
+                    name: Some("Display Unit".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:thermometer".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: vec![
+                    TemperatureScale::Celsius.to_string(),
+                    TemperatureScale::Farenheit.to_string(),
+                ],
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DisplayUnitSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(scale) = device.display_temperature_scale() {
+            client
+                .publish(&self.select.state_topic, scale.to_string())
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_display_unit(
+    Payload(unit): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let scale: TemperatureScale = unit.parse()?;
+
+    state
+        .device_set_display_temperature_scale(&device, scale)
+        .await
+        .context("mqtt_set_display_unit: state.device_set_display_temperature_scale")?;
+
+    Ok(())
+}
+
+/// A select for one of the quirk-defined extended controls
+pub struct ExtendedControlSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: &'static str,
+}
+
+impl ExtendedControlSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, control: &ExtendedControl) -> Self {
+        let instance = control.instance();
+        let options = match control {
+            ExtendedControl::Select { options, .. } => {
+                options.iter().map(|(name, _)| name.to_string()).collect()
+            }
+            ExtendedControl::Switch { .. } | ExtendedControl::Lock { .. } => vec![],
+        };
+        let unique_id = format!("gv2mqtt-{id}-{instance}", id = topic_safe_id(device));
+
+        Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(control.name().to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some(control.icon().to_string()),
+                },
+                command_topic: extended_control_command_topic(device, instance),
+                state_topic: extended_control_state_topic(device, instance),
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ExtendedControlSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(value) = device.extended_control_values.get(self.instance_name) {
+            client.publish(&self.select.state_topic, value).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies one of the user-defined color presets
+pub struct ColorPresetSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ColorPresetSelect {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let presets = state.color_presets(device).await;
+        if presets.is_empty() {
+            return None;
+        }
+
+        let command_topic = format!("gv2mqtt/{id}/set-color-preset", id = topic_safe_id(device));
+        let state_topic = format!(
+            "gv2mqtt/{id}/notify-color-preset",
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-color-preset", id = topic_safe_id(device));
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Color Preset".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:palette".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: presets.into_iter().map(|preset| preset.name).collect(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ColorPresetSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(device_state) = device.device_state() {
+            // Report the preset that matches the current color, if any
+            let active = self
+                .state
+                .color_presets(&device)
+                .await
+                .into_iter()
+                .find(|preset| {
+                    let color_matches = match preset.color {
+                        PresetColor::Rgb(color) => {
+                            device_state.kelvin == 0 && device_state.color == color
+                        }
+                        PresetColor::Kelvin(kelvin) => device_state.kelvin == kelvin,
+                    };
+                    color_matches
+                        && preset
+                            .brightness
+                            .map(|b| b == device_state.brightness)
+                            .unwrap_or(true)
+                });
+            client
+                .publish(
+                    &self.select.state_topic,
+                    active.map(|preset| preset.name).unwrap_or_default(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_set_color_preset(
+    Payload(name): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_apply_color_preset(&device, &name)
+        .await
+        .context("mqtt_set_color_preset: state.device_apply_color_preset")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+    use crate::service::state::State as ServiceState;
+    use crate::undoc_api::DevicesResponse;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn display_unit() {
+        let resp: DevicesResponse = from_json(include_str!(
+            "../../test-data/undoc-device-list-issue-21.json"
+        ))
+        .unwrap();
+        let entry = resp
+            .devices
+            .into_iter()
+            .find(|entry| entry.sku == "H5100")
+            .unwrap();
+
+        let state: StateHandle = Arc::new(ServiceState::new());
+        let (sku, id) = (entry.sku.clone(), entry.device.clone());
+        state
+            .device_mut(&sku, &id)
+            .await
+            .set_undoc_device_info(entry, None);
+        let device = state.device_by_id(&id).await.unwrap();
+        assert_eq!(
+            device.display_temperature_scale(),
+            Some(TemperatureScale::Celsius)
+        );
+
+        let select = DisplayUnitSelect::new(&device, &state);
+        // Each option is a value that the command handler accepts
+        for option in &select.select.options {
+            option.parse::<TemperatureScale>().unwrap();
+        }
+
+        let (client, messages) = HassClient::capture();
+        select.notify_state(&client).await.unwrap();
+        state
+            .device_mut(&sku, &id)
+            .await
+            .set_display_temperature_scale(TemperatureScale::Farenheit);
+        select.notify_state(&client).await.unwrap();
+
+        let topic = select.select.state_topic.clone();
+        assert_eq!(
+            *messages.lock(),
+            vec![
+                (topic.clone(), TemperatureScale::Celsius.to_string()),
+                (topic, TemperatureScale::Farenheit.to_string()),
+            ]
+        );
+    }
+}
@@ -3,16 +3,23 @@ use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
+use crate::service::capability_probe::unsupported_capabilities;
+use crate::service::cloud_health::rate_limit_cooldowns;
+use crate::service::derived::DerivedSensorDefinition;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
-use crate::service::quirks::HumidityUnits;
+use crate::service::playlist::{PlaybackState, PlaylistStatus};
+use crate::service::rate_of_change::{record_reading, RateMeasurement};
 use crate::service::state::StateHandle;
+use crate::service::transport_health::transport_report;
 use crate::temperature::{TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE};
 use async_trait::async_trait;
 use chrono::Utc;
 use serde::Serialize;
 use serde_json::json;
 
+const DEVICE_CLASS_ILLUMINANCE: &str = "illuminance";
+
 #[derive(Serialize, Clone, Debug)]
 pub struct SensorConfig {
     #[serde(flatten)]
@@ -57,11 +64,11 @@ pub struct GlobalFixedDiagnostic {
 #[async_trait]
 impl EntityInstance for GlobalFixedDiagnostic {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.notify_state(&client, &self.value).await
+        self.sensor.notify_state(client, &self.value).await
     }
 }
 
@@ -92,6 +99,119 @@ impl GlobalFixedDiagnostic {
     }
 }
 
+/// Reports whether any of the supervised background tasks
+/// are failing repeatedly
+#[derive(Clone)]
+pub struct TaskHealthDiagnostic {
+    sensor: SensorConfig,
+    state: StateHandle,
+}
+
+#[async_trait]
+impl EntityInstance for TaskHealthDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let supervisor = self.state.supervisor();
+        let summary = if supervisor.failed_tasks().is_empty() {
+            "OK"
+        } else {
+            "Degraded"
+        };
+        self.sensor.notify_state(client, summary).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(topic, supervisor.task_statuses())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl TaskHealthDiagnostic {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-task-health".to_string();
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Task Health".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:heart-pulse".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+/// Reports when the Govee cloud will accept requests again, after it
+/// answered some of them with 429 Too Many Requests
+#[derive(Clone)]
+pub struct CloudRateLimitDiagnostic {
+    sensor: SensorConfig,
+}
+
+#[async_trait]
+impl EntityInstance for CloudRateLimitDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let cooldowns = rate_limit_cooldowns();
+        let value = match cooldowns.values().max() {
+            Some(until) => until.to_rfc3339(),
+            None => "None".to_string(),
+        };
+        self.sensor.notify_state(client, &value).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, cooldowns).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for CloudRateLimitDiagnostic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CloudRateLimitDiagnostic {
+    pub fn new() -> Self {
+        let unique_id = "global-cloud-rate-limit".to_string();
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Cloud Rate Limited Until".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("timestamp"),
+                    icon: Some("mdi:speedometer-slow".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+            },
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct CapabilitySensor {
     sensor: SensorConfig,
@@ -115,24 +235,28 @@ impl CapabilitySensor {
         let unit_of_measurement = match instance.instance.as_str() {
             "sensorTemperature" => Some(state.get_temperature_scale().await.unit_of_measurement()),
             "sensorHumidity" => Some("%"),
+            "sensorLux" => Some("lx"),
             _ => None,
         };
 
         let device_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(DEVICE_CLASS_TEMPERATURE),
             "sensorHumidity" => Some(DEVICE_CLASS_HUMIDITY),
+            "sensorLux" => Some(DEVICE_CLASS_ILLUMINANCE),
             _ => None,
         };
 
         let state_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(StateClass::Measurement),
             "sensorHumidity" => Some(StateClass::Measurement),
+            "sensorLux" => Some(StateClass::Measurement),
             _ => None,
         };
 
         let name = match instance.instance.as_str() {
             "sensorTemperature" => "Temperature".to_string(),
             "sensorHumidity" => "Humidity".to_string(),
+            "sensorLux" => "Illuminance".to_string(),
             "online" => "Connected to Govee Cloud".to_string(),
             _ => instance.instance.to_string(),
         };
@@ -150,7 +274,7 @@ impl CapabilitySensor {
                     icon: None,
                 },
                 state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
-                state_class: state_class,
+                state_class,
                 unit_of_measurement,
                 json_attributes_topic: None,
             },
@@ -164,7 +288,7 @@ impl CapabilitySensor {
 #[async_trait]
 impl EntityInstance for CapabilitySensor {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -174,48 +298,29 @@ impl EntityInstance for CapabilitySensor {
             .await
             .expect("device to exist");
 
-        let quirk = device.resolve_quirk();
-
         if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
             let value = match self.instance_name.as_str() {
-                "sensorTemperature" => {
-                    let units = quirk
-                        .and_then(|q| q.platform_temperature_sensor_units)
-                        .unwrap_or(TemperatureUnits::Celsius);
-
-                    match cap
-                        .state
-                        .pointer("/value")
-                        .and_then(|v| v.as_f64())
-                        .map(|v| TemperatureValue::new(v, units))
-                    {
-                        Some(v) => {
-                            let value = v
-                                .as_unit(self.state.get_temperature_scale().await.into())
-                                .value();
-                            format!("{value:.2}")
-                        }
-                        None => "".to_string(),
+                "sensorTemperature" => match device.sensor_temperature() {
+                    Some(v) => {
+                        let value = v
+                            .as_unit(self.state.get_temperature_scale().await.into())
+                            .value();
+                        format!("{value:.2}")
                     }
-                }
-                "sensorHumidity" => {
-                    let units = quirk
-                        .and_then(|q| q.platform_humidity_sensor_units)
-                        .unwrap_or(HumidityUnits::RelativePercent);
-                    match cap
-                        .state
-                        .pointer("/value/currentHumidity")
-                        .and_then(|v| v.as_f64())
-                        .map(|v| units.from_reading_to_relative_percent(v))
-                    {
-                        Some(v) => format!("{v:.2}"),
-                        None => "".to_string(),
-                    }
-                }
+                    None => "".to_string(),
+                },
+                "sensorHumidity" => match device.sensor_humidity_percent() {
+                    Some(v) => format!("{v:.2}"),
+                    None => "".to_string(),
+                },
+                "sensorLux" => match device.sensor_lux() {
+                    Some(v) => format!("{v:.0}"),
+                    None => "".to_string(),
+                },
                 _ => cap.state.to_string(),
             };
 
-            return self.sensor.notify_state(&client, &value).await;
+            return self.sensor.notify_state(client, &value).await;
         }
         log::trace!(
             "CapabilitySensor::notify_state: didn't find state for {device} {instance}",
@@ -225,43 +330,56 @@ impl EntityInstance for CapabilitySensor {
     }
 }
 
-pub struct DeviceStatusDiagnostic {
+/// A sensor whose value is computed from the other sensor
+/// readings of the device
+#[derive(Clone)]
+pub struct DerivedSensor {
     sensor: SensorConfig,
     device_id: String,
     state: StateHandle,
+    definition: DerivedSensorDefinition,
 }
 
-impl DeviceStatusDiagnostic {
-    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
-        let unique_id = format!("sensor-{id}-gv2mqtt-status", id = topic_safe_id(device),);
+impl DerivedSensor {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        definition: &DerivedSensorDefinition,
+    ) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-derived-{name}",
+            id = topic_safe_id(device),
+            name = topic_safe_string(&definition.name)
+        );
 
         Self {
             sensor: SensorConfig {
                 base: EntityConfig {
                     availability_topic: availability_topic(),
-                    name: Some("Status".to_string()),
-                    entity_category: Some("diagnostic".to_string()),
+                    name: Some(definition.name.to_string()),
+                    entity_category: None,
                     origin: Origin::default(),
                     device: Device::for_device(device),
                     unique_id: unique_id.clone(),
-                    device_class: None,
+                    device_class: definition.device_class,
                     icon: None,
                 },
                 state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
-                state_class: None,
-                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
-                unit_of_measurement: None,
+                state_class: Some(StateClass::Measurement),
+                json_attributes_topic: None,
+                unit_of_measurement: definition.unit,
             },
             device_id: device.id.to_string(),
             state: state.clone(),
+            definition: definition.clone(),
         }
     }
 }
 
 #[async_trait]
-impl EntityInstance for DeviceStatusDiagnostic {
+impl EntityInstance for DerivedSensor {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -271,41 +389,800 @@ impl EntityInstance for DeviceStatusDiagnostic {
             .await
             .expect("device to exist");
 
-        let iot_state = device.compute_iot_device_state();
-        let lan_state = device.compute_lan_device_state();
-        let http_state = device.compute_http_device_state();
-        let platform_metadata = &device.http_device_info;
-        let platform_state = &device.http_device_state;
-        let device_state = device.device_state();
+        if let Some(value) = self.definition.evaluate(&device) {
+            self.sensor
+                .notify_state(client, &format!("{value:.2}"))
+                .await?;
+        }
+        Ok(())
+    }
+}
 
-        let now = Utc::now();
+/// Reports how quickly the temperature or humidity of
+/// a sensor device is changing
+#[derive(Clone)]
+pub struct RateOfChangeSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    measurement: RateMeasurement,
+    window: std::time::Duration,
+}
 
-        let threshold = *POLL_INTERVAL + chrono::Duration::seconds(30);
+impl RateOfChangeSensor {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        measurement: RateMeasurement,
+        window: std::time::Duration,
+    ) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-{suffix}",
+            id = topic_safe_id(device),
+            suffix = measurement.suffix()
+        );
 
-        let summary = match &device_state {
-            Some(state) => {
-                if now - state.updated > threshold {
-                    "Missing".to_string()
-                } else {
-                    "Available".to_string()
-                }
-            }
-            None => "Unknown".to_string(),
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(measurement.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:chart-line-variant".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: Some(StateClass::Measurement),
+                json_attributes_topic: None,
+                unit_of_measurement: Some(measurement.unit()),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            measurement,
+            window,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for RateOfChangeSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let value = match self.measurement {
+            RateMeasurement::Temperature => device
+                .sensor_temperature()
+                .map(|t| t.as_unit(TemperatureUnits::Celsius).value()),
+            RateMeasurement::Humidity => device.sensor_humidity_percent(),
+        };
+        let (Some(value), Some(when)) = (value, device.sensor_reading_updated()) else {
+            return Ok(());
         };
 
-        let attributes = json!({
-            "iot": iot_state,
-            "lan": lan_state,
-            "http": http_state,
-            "platform_metadata": platform_metadata,
-            "platform_state": platform_state,
-            "overall": device_state,
-        });
+        if let Some(rate) =
+            record_reading(&self.device_id, self.measurement, when, value, self.window)
+        {
+            self.sensor
+                .notify_state(client, &format!("{rate:.2}"))
+                .await?;
+        }
+        Ok(())
+    }
+}
 
-        self.sensor.notify_state(&client, &summary).await?;
-        if let Some(topic) = &self.sensor.json_attributes_topic {
-            client.publish_obj(topic, attributes).await?;
+#[derive(Clone, Copy, Debug)]
+pub enum WifiDiagnosticKind {
+    Rssi,
+    Level,
+    Channel,
+}
+
+/// Reports information about the Wi-Fi connection of a device,
+/// to help identify devices with poor connectivity
+#[derive(Clone)]
+pub struct WifiDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    kind: WifiDiagnosticKind,
+}
+
+impl WifiDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: WifiDiagnosticKind) -> Self {
+        let (suffix, name, device_class, unit_of_measurement, state_class) = match kind {
+            WifiDiagnosticKind::Rssi => (
+                "rssi",
+                "WiFi Signal Strength",
+                Some("signal_strength"),
+                Some("dBm"),
+                Some(StateClass::Measurement),
+            ),
+            WifiDiagnosticKind::Level => (
+                "wifi-level",
+                "WiFi Signal Level",
+                None,
+                None,
+                Some(StateClass::Measurement),
+            ),
+            WifiDiagnosticKind::Channel => ("wifi-channel", "WiFi Channel", None, None, None),
+        };
+        let unique_id = format!("sensor-{id}-gv2mqtt-{suffix}", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class,
+                    icon: Some("mdi:wifi".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class,
+                json_attributes_topic: None,
+                unit_of_measurement,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            kind,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WifiDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let value = match self.kind {
+            WifiDiagnosticKind::Rssi => device.wifi_rssi,
+            WifiDiagnosticKind::Level => device.wifi_level(),
+            WifiDiagnosticKind::Channel => device.wifi_channel,
+        };
+
+        if let Some(value) = value {
+            self.sensor.notify_state(client, &value.to_string()).await?;
         }
         Ok(())
     }
 }
+
+#[derive(Clone, Copy, Debug)]
+pub enum FirmwareDiagnosticKind {
+    Version,
+    HardwareVersion,
+    WifiVersion,
+    WifiHardwareVersion,
+}
+
+/// Reports a hardware or firmware version of a device
+#[derive(Clone)]
+pub struct FirmwareDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    kind: FirmwareDiagnosticKind,
+}
+
+impl FirmwareDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: FirmwareDiagnosticKind) -> Self {
+        let (suffix, name, icon) = match kind {
+            FirmwareDiagnosticKind::Version => ("version-soft", "Firmware Version", "mdi:chip"),
+            FirmwareDiagnosticKind::HardwareVersion => {
+                ("version-hard", "Hardware Version", "mdi:chip")
+            }
+            FirmwareDiagnosticKind::WifiVersion => {
+                ("wifi-version-soft", "WiFi Firmware Version", "mdi:wifi-cog")
+            }
+            FirmwareDiagnosticKind::WifiHardwareVersion => {
+                ("wifi-version-hard", "WiFi Hardware Version", "mdi:wifi-cog")
+            }
+        };
+        let unique_id = format!("sensor-{id}-gv2mqtt-{suffix}", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some(icon.to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: None,
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            kind,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FirmwareDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let versions = device.firmware_versions();
+        let value = match self.kind {
+            FirmwareDiagnosticKind::Version => versions.version_soft,
+            FirmwareDiagnosticKind::HardwareVersion => versions.version_hard,
+            FirmwareDiagnosticKind::WifiVersion => versions.wifi_soft_version,
+            FirmwareDiagnosticKind::WifiHardwareVersion => versions.wifi_hard_version,
+        };
+
+        if let Some(value) = value {
+            self.sensor.notify_state(client, &value).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum BleSensorKind {
+    Temperature,
+    Humidity,
+    Battery,
+}
+
+/// A reading decoded from the BLE advertisements of a sensor
+#[derive(Clone)]
+pub struct BleSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    kind: BleSensorKind,
+}
+
+impl BleSensor {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle, kind: BleSensorKind) -> Self {
+        let (suffix, name, device_class, unit_of_measurement, entity_category) = match kind {
+            BleSensorKind::Temperature => (
+                "temperature",
+                "Temperature",
+                DEVICE_CLASS_TEMPERATURE,
+                state.get_temperature_scale().await.unit_of_measurement(),
+                None,
+            ),
+            BleSensorKind::Humidity => ("humidity", "Humidity", DEVICE_CLASS_HUMIDITY, "%", None),
+            BleSensorKind::Battery => (
+                "battery",
+                "Battery",
+                "battery",
+                "%",
+                Some("diagnostic".to_string()),
+            ),
+        };
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-ble-{suffix}",
+            id = topic_safe_id(device)
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(device_class),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: Some(StateClass::Measurement),
+                json_attributes_topic: None,
+                unit_of_measurement: Some(unit_of_measurement),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            kind,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for BleSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = device.ble_sensor_reading else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            BleSensorKind::Temperature => {
+                let value =
+                    TemperatureValue::new(reading.temperature_celsius, TemperatureUnits::Celsius)
+                        .as_unit(self.state.get_temperature_scale().await.into())
+                        .value();
+                format!("{value:.2}")
+            }
+            BleSensorKind::Humidity => format!("{:.2}", reading.humidity_percent),
+            BleSensorKind::Battery => reading.battery_percent.to_string(),
+        };
+        self.sensor.notify_state(client, &value).await
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum PowerSensorKind {
+    Power,
+    Energy,
+    Voltage,
+    Current,
+}
+
+/// A measurement reported by an energy monitoring plug.
+/// The device and state classes match those that hass
+/// requires for the sensor to be used in the Energy dashboard.
+#[derive(Clone)]
+pub struct PowerSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+    kind: PowerSensorKind,
+}
+
+impl PowerSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: PowerSensorKind) -> Self {
+        let (suffix, name, device_class, unit_of_measurement, state_class) = match kind {
+            PowerSensorKind::Power => ("power", "Power", "power", "W", StateClass::Measurement),
+            PowerSensorKind::Energy => (
+                "energy",
+                "Energy",
+                "energy",
+                "kWh",
+                StateClass::TotalIncreasing,
+            ),
+            PowerSensorKind::Voltage => (
+                "voltage",
+                "Voltage",
+                "voltage",
+                "V",
+                StateClass::Measurement,
+            ),
+            PowerSensorKind::Current => (
+                "current",
+                "Current",
+                "current",
+                "A",
+                StateClass::Measurement,
+            ),
+        };
+        let unique_id = format!("sensor-{id}-gv2mqtt-{suffix}", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(device_class),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: Some(state_class),
+                json_attributes_topic: None,
+                unit_of_measurement: Some(unit_of_measurement),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            kind,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PowerSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(reading) = device.power_reading else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            PowerSensorKind::Power => reading.power_watts,
+            PowerSensorKind::Energy => reading.energy_kwh,
+            PowerSensorKind::Voltage => reading.voltage,
+            PowerSensorKind::Current => reading.current_amps,
+        };
+        match value {
+            Some(value) => {
+                self.sensor
+                    .notify_state(client, &format!("{value:.3}"))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The battery level of a leak detector, as reported via the cloud
+#[derive(Clone)]
+pub struct LeakBatterySensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl LeakBatterySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-battery", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Battery".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("battery"),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: Some(StateClass::Measurement),
+                json_attributes_topic: None,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LeakBatterySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        match device.leak_reading.and_then(|r| r.battery_percent) {
+            Some(battery) => self.sensor.notify_state(client, &battery.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct DeviceStatusDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceStatusDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-status", id = topic_safe_id(device),);
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Status".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceStatusDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let iot_state = device.compute_iot_device_state();
+        let lan_state = device.compute_lan_device_state();
+        let http_state = device.compute_http_device_state();
+        let platform_metadata = &device.http_device_info;
+        let platform_state = &device.http_device_state;
+        let device_state = device.device_state();
+
+        let now = Utc::now();
+
+        let threshold = *POLL_INTERVAL + chrono::Duration::seconds(30);
+
+        let summary = match &device_state {
+            Some(state) => {
+                if now - state.updated > threshold {
+                    "Missing".to_string()
+                } else {
+                    "Available".to_string()
+                }
+            }
+            None => "Unknown".to_string(),
+        };
+
+        let attributes = json!({
+            "iot": iot_state,
+            "lan": lan_state,
+            "http": http_state,
+            "platform_metadata": platform_metadata,
+            "platform_state": platform_state,
+            "overall": device_state,
+            "last_updated": device_state.as_ref().map(|state| state.updated),
+            "unsupported_capabilities": unsupported_capabilities(&device.id),
+            "transport": transport_report(&device.id),
+        });
+
+        self.sensor.notify_state(client, &summary).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, attributes).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the rolling average LAN API round trip time for a device,
+/// with where and when it was found by discovery as attributes
+pub struct LanRttDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl LanRttDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-lan-rtt", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("LAN Round Trip Time".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("duration"),
+                    icon: Some("mdi:lan-pending".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: Some(StateClass::Measurement),
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: Some("ms"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for LanRttDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(rtt) = device.lan_rtt {
+            self.sensor
+                .notify_state(client, &rtt.as_millis().to_string())
+                .await?;
+        }
+        let discovery = match self.state.get_lan_client().await {
+            Some(lan) => lan.discovery_for_device(&self.device_id),
+            None => None,
+        };
+        if let (Some(topic), Some(discovery)) = (&self.sensor.json_attributes_topic, discovery) {
+            client.publish_obj(topic, discovery).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the scene currently shown by a playlist, with the
+/// playback state and remaining time as attributes
+pub struct PlaylistSensor {
+    sensor: SensorConfig,
+    playlist: String,
+    state: StateHandle,
+}
+
+impl PlaylistSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, playlist: &str) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-playlist-{name}",
+            id = topic_safe_id(device),
+            name = topic_safe_string(playlist)
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(format!("Playlist {playlist}")),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:playlist-play".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: None,
+            },
+            playlist: playlist.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlaylistSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let playlists = self.state.get_playlists().await;
+        let Some(playlist) = playlists.iter().find(|p| p.name() == self.playlist) else {
+            return Ok(());
+        };
+
+        let status = PlaylistStatus::from(playlist);
+        let value = match (status.state, &status.scene) {
+            (PlaybackState::Stopped, _) | (_, None) => "Stopped",
+            (_, Some(scene)) => scene.as_str(),
+        };
+        self.sensor.notify_state(client, value).await?;
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, &status).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The schedules that were configured on the device via the Govee
+/// app. The state is the number of enabled schedules, and the
+/// schedules themselves are published as attributes.
+pub struct ScheduleSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ScheduleSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-schedules", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Schedules".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:calendar-clock".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ScheduleSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let schedules = device.schedules();
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(topic, json!({ "schedules": schedules }))
+                .await?;
+        }
+        let enabled = schedules.iter().filter(|s| s.enabled).count();
+        self.sensor.notify_state(client, &enabled.to_string()).await
+    }
+}
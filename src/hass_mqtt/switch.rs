@@ -3,9 +3,12 @@ use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
-    HassClient,
+    availability_topic, camel_case_to_space_separated, extended_control_command_topic,
+    extended_control_state_topic, power_save_command_topic, power_save_state_topic,
+    switch_instance_state_topic, topic_safe_id, HassClient,
 };
+use crate::service::power_save::power_save;
+use crate::service::quirks::ExtendedControl;
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
@@ -84,7 +87,7 @@ impl CapabilitySwitch {
 #[async_trait]
 impl EntityInstance for CapabilitySwitch {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.switch.publish(&state, &client).await
+        self.switch.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -142,3 +145,100 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+/// A switch for one of the quirk-defined extended controls
+pub struct ExtendedControlSwitch {
+    switch: SwitchConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: &'static str,
+}
+
+impl ExtendedControlSwitch {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, control: &ExtendedControl) -> Self {
+        let instance = control.instance();
+        let unique_id = format!("gv2mqtt-{id}-{instance}", id = topic_safe_id(device));
+
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(control.name().to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some(control.icon().to_string()),
+                },
+                command_topic: extended_control_command_topic(device, instance),
+                state_topic: extended_control_state_topic(device, instance),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ExtendedControlSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(value) = device.extended_control_values.get(self.instance_name) {
+            client.publish(&self.switch.state_topic, value).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Switches the bridge in and out of power save mode
+pub struct PowerSaveSwitch {
+    switch: SwitchConfig,
+}
+
+impl PowerSaveSwitch {
+    pub fn new() -> Self {
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Power Save".to_string()),
+                    entity_category: Some("config".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: "global-power-save".to_string(),
+                    device_class: None,
+                    icon: Some("mdi:battery-heart-variant".to_string()),
+                },
+                command_topic: power_save_command_topic(),
+                state_topic: power_save_state_topic(),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PowerSaveSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        client
+            .publish(
+                &self.switch.state_topic,
+                if power_save() { "ON" } else { "OFF" },
+            )
+            .await
+    }
+}
@@ -0,0 +1,97 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{availability_topic, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UpdateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+}
+
+impl UpdateConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("update", state, client, &self.base, self).await
+    }
+}
+
+/// Indicates whether a firmware update is pending for a device.
+/// Updates can only be installed via the Govee app, so this
+/// has no install command.
+#[derive(Clone)]
+pub struct FirmwareUpdateEntity {
+    update: UpdateConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FirmwareUpdateEntity {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("update-{id}-firmware", id = topic_safe_id(device));
+        Self {
+            update: UpdateConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Firmware".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("firmware"),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/update/{unique_id}/state"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FirmwareUpdateEntity {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.update.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(installed) = device.firmware_versions().version_soft else {
+            return Ok(());
+        };
+        // With no pending update, the latest version is the installed one
+        let (latest, summary) = match &device.firmware_update {
+            Some(update) => (
+                update.version_soft.clone(),
+                // hass limits the summary to 255 characters
+                update
+                    .description
+                    .as_ref()
+                    .map(|s| s.chars().take(255).collect::<String>()),
+            ),
+            None => (installed.clone(), None),
+        };
+
+        client
+            .publish_obj(
+                &self.update.state_topic,
+                &json!({
+                    "installed_version": installed,
+                    "latest_version": latest,
+                    "release_summary": summary,
+                }),
+            )
+            .await
+    }
+}
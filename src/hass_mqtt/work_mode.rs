@@ -2,7 +2,7 @@ use crate::platform_api::{DeviceCapability, DeviceParameters, EnumOption};
 use crate::service::device::Device as ServiceDevice;
 use anyhow::anyhow;
 use serde::Deserialize;
-use serde_json::Value as JsonValue;
+use serde_json::{json, Value as JsonValue};
 use std::collections::BTreeMap;
 use std::ops::Range;
 
@@ -32,26 +32,20 @@ impl ParsedWorkMode {
             .struct_field_by_name("workMode")
             .ok_or_else(|| anyhow!("workMode not found in {cap:?}"))?;
 
-        match &wm.field_type {
-            DeviceParameters::Enum { options } => {
-                for opt in options {
-                    work_modes.add(opt.name.to_string(), opt.value.clone());
-                }
+        if let DeviceParameters::Enum { options } = &wm.field_type {
+            for opt in options {
+                work_modes.add(opt.name.to_string(), opt.value.clone());
             }
-            _ => {}
         }
 
         if let Some(mv) = cap.struct_field_by_name("modeValue") {
-            match &mv.field_type {
-                DeviceParameters::Enum { options } => {
-                    for opt in options {
-                        let mode_name = &opt.name;
-                        if let Some(work_mode) = work_modes.get_mut(mode_name) {
-                            work_mode.add_values(opt);
-                        }
+            if let DeviceParameters::Enum { options } = &mv.field_type {
+                for opt in options {
+                    let mode_name = &opt.name;
+                    if let Some(work_mode) = work_modes.get_mut(mode_name) {
+                        work_mode.add_values(opt);
                     }
                 }
-                _ => {}
             }
         }
         Ok(work_modes)
@@ -75,19 +69,19 @@ impl ParsedWorkMode {
     pub fn adjust_for_device(&mut self, sku: &str) {
         match sku {
             "H7160" | "H7143" => {
-                self.modes
-                    .get_mut("Manual")
-                    .map(|m| m.label = "Manual: Mist Level".to_string());
+                if let Some(m) = self.modes.get_mut("Manual") {
+                    m.label = "Manual: Mist Level".to_string();
+                }
             }
             "H7131" => {
-                self.modes.get_mut("gearMode").map(|m| {
+                if let Some(m) = self.modes.get_mut("gearMode") {
                     m.label = "Heat".to_string();
-                });
+                }
             }
             "H7173" => {
-                self.modes.get_mut("gearMode").map(|m| {
+                if let Some(m) = self.modes.get_mut("gearMode") {
                     m.label = "Heat".to_string();
-                });
+                }
             }
             _ => {
                 for mode in self.modes.values_mut() {
@@ -98,26 +92,33 @@ impl ParsedWorkMode {
     }
 
     pub fn mode_for_value(&self, value: &JsonValue) -> Option<&WorkMode> {
-        for mode in self.modes.values() {
-            if mode.value == *value {
-                return Some(mode);
-            }
-        }
-        None
+        self.modes
+            .values()
+            .find(|&mode| mode.value == *value)
+            .map(|v| v as _)
     }
 
     pub fn mode_by_name(&self, name: &str) -> Option<&WorkMode> {
         self.modes.get(name)
     }
 
+    /// Returns the mode in which the device is currently operating,
+    /// preferring the state reported via IoT over the Platform API
+    pub fn current_mode(&self, device: &ServiceDevice) -> Option<&WorkMode> {
+        if let Some(mode_value) = device.humidifier_work_mode {
+            return self.mode_for_value(&json!(mode_value));
+        }
+
+        let cap = device.get_state_capability_by_instance("workMode")?;
+        self.mode_for_value(cap.state.pointer("/value/workMode")?)
+    }
+
     #[allow(unused)]
     pub fn mode_by_label(&self, name: &str) -> Option<&WorkMode> {
-        for mode in self.modes.values() {
-            if mode.label() == name {
-                return Some(mode);
-            }
-        }
-        None
+        self.modes
+            .values()
+            .find(|&mode| mode.label() == name)
+            .map(|v| v as _)
     }
 
     pub fn get_mode_names(&self) -> Vec<String> {
@@ -143,16 +144,71 @@ impl ParsedWorkMode {
 
     #[allow(unused)]
     pub fn modes_with_values(&self) -> impl Iterator<Item = &WorkMode> {
-        self.modes.values().filter_map(|mode| {
-            if mode.values.is_empty() {
-                None
-            } else {
-                Some(mode)
+        self.modes.values().filter(|mode| !mode.values.is_empty())
+    }
+
+    /// Returns the native presets of the device: each named value
+    /// of a mode, and each mode that has no adjustable value
+    pub fn presets(&self) -> Vec<WorkModePreset> {
+        let mut presets = vec![];
+        for mode in self.modes.values() {
+            let Some(mode_num) = mode.value.as_i64() else {
+                continue;
+            };
+            if mode.should_show_as_preset() {
+                presets.push(WorkModePreset {
+                    label: mode.label().to_string(),
+                    mode_name: mode.name.to_string(),
+                    mode_num,
+                    value: mode.default_value(),
+                });
+                continue;
+            }
+            for value in &mode.values {
+                if let (Some(name), Some(value)) = (&value.name, value.value.as_i64()) {
+                    presets.push(WorkModePreset {
+                        label: name.to_string(),
+                        mode_name: mode.name.to_string(),
+                        mode_num,
+                        value,
+                    });
+                }
             }
+        }
+        presets
+    }
+
+    pub fn preset_by_label(&self, label: &str) -> Option<WorkModePreset> {
+        self.presets()
+            .into_iter()
+            .find(|preset| preset.label.eq_ignore_ascii_case(label))
+    }
+
+    /// Returns the preset that the device is currently using, if any
+    pub fn current_preset(&self, device: &ServiceDevice) -> Option<WorkModePreset> {
+        let mode = self.current_mode(device)?;
+        let value = if mode.should_show_as_preset() {
+            None
+        } else {
+            Some(mode.current_value(device)?)
+        };
+        self.presets().into_iter().find(|preset| {
+            preset.mode_name == mode.name && value.map(|v| v == preset.value).unwrap_or(true)
         })
     }
 }
 
+/// A native preset of a device, such as the Focus or Relax mode of a
+/// desk lamp, which is activated via its work mode rather than by
+/// setting the color or brightness directly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkModePreset {
+    pub label: String,
+    pub mode_name: String,
+    pub mode_num: i64,
+    pub value: i64,
+}
+
 #[derive(Default, Debug)]
 pub struct WorkMode {
     pub name: String,
@@ -237,7 +293,7 @@ impl WorkMode {
         self.default_value
             .as_ref()
             .and_then(|v| v.as_i64())
-            .or_else(|| self.values.get(0).and_then(|wmv| wmv.value.as_i64()))
+            .or_else(|| self.values.first().and_then(|wmv| wmv.value.as_i64()))
             .or_else(|| self.value_range.as_ref().map(|r| r.start))
             .unwrap_or(0)
     }
@@ -262,12 +318,10 @@ impl WorkMode {
         let min = *values.iter().min()?;
         let max = *values.iter().max()?;
 
-        let mut expect = min;
-        for item in values {
+        for (expect, item) in (min..).zip(values) {
             if item != expect {
                 return None;
             }
-            expect = expect + 1;
         }
 
         Some(min..max + 1)
@@ -276,12 +330,37 @@ impl WorkMode {
     pub fn should_show_as_preset(&self) -> bool {
         self.contiguous_value_range().is_none() && self.values.is_empty()
     }
+
+    /// Returns the most recently known parameter value for this mode
+    pub fn current_value(&self, device: &ServiceDevice) -> Option<i64> {
+        if let Some(cap) = device.get_state_capability_by_instance("workMode") {
+            if cap.state.pointer("/value/workMode") == Some(&self.value) {
+                // The current mode matches us, so it is valid to
+                // read the current parameter for that mode
+                if let Some(n) = cap
+                    .state
+                    .pointer("/value/modeValue")
+                    .and_then(|v| v.as_i64())
+                {
+                    return Some(n);
+                }
+            }
+        }
+
+        // FIXME: assuming humidifier, rename that field?
+        let mode_num = self.value.as_i64()?;
+        device
+            .humidifier_param_by_mode
+            .get(&(mode_num as u8))
+            .map(|&n| n as i64)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::platform_api::{from_json, DeviceCapabilityKind, StructField};
+    use crate::platform_api::{from_json, DeviceCapabilityKind};
+    use govee_models::platform::StructField;
     use serde_json::json;
     use std::collections::HashMap;
 
@@ -371,7 +450,7 @@ ParsedWorkMode {
     #[test]
     fn test_work_mode_parser2() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-81.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-81.json")).unwrap();
 
         let wm = ParsedWorkMode::with_capability(&cap).unwrap();
 
@@ -513,7 +592,7 @@ ParsedWorkMode {
     #[test]
     fn test_work_mode_parser4() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-93.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-93.json")).unwrap();
 
         let wm = ParsedWorkMode::with_capability(&cap).unwrap();
 
@@ -593,7 +672,7 @@ ParsedWorkMode {
     #[test]
     fn test_issue100() {
         let cap: DeviceCapability =
-            from_json(&include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
+            from_json(include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
 
         let mut wm = ParsedWorkMode::with_capability(&cap).unwrap();
         wm.adjust_for_device("H7173");
@@ -651,4 +730,51 @@ ParsedWorkMode {
         assert_eq!(wm.mode_by_name("Boiling").unwrap().default_value(), 0);
         assert_eq!(wm.mode_by_name("DIY").unwrap().default_value(), 1);
     }
+
+    #[test]
+    fn presets() {
+        let mut wm = ParsedWorkMode::default();
+        wm.add("Reading".to_string(), 1.into());
+        wm.add("Lighting".to_string(), 2.into());
+        for (name, value) in [("Focus", 1), ("Relax", 2)] {
+            wm.get_mut("Lighting").unwrap().values.push(WorkModeValue {
+                value: value.into(),
+                name: Some(name.to_string()),
+                computed_label: String::new(),
+            });
+        }
+        wm.adjust_for_device("H6047");
+
+        let labels: Vec<_> = wm.presets().into_iter().map(|p| p.label).collect();
+        assert_eq!(labels, vec!["Focus", "Relax", "Reading"]);
+        assert_eq!(
+            wm.preset_by_label("relax"),
+            Some(WorkModePreset {
+                label: "Relax".to_string(),
+                mode_name: "Lighting".to_string(),
+                mode_num: 2,
+                value: 2,
+            })
+        );
+        assert_eq!(wm.preset_by_label("Party"), None);
+    }
+
+    #[test]
+    fn current_mode_and_value() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-issue-81.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+
+        let mut device = ServiceDevice::new("H7160", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(wm.current_mode(&device).is_none());
+
+        device.set_humidifier_work_mode_and_param(3, 55);
+        let mode = wm.current_mode(&device).unwrap();
+        assert_eq!(mode.name, "Auto");
+        assert_eq!(mode.current_value(&device), Some(55));
+        assert_eq!(
+            wm.mode_by_name("Custom").unwrap().current_value(&device),
+            None
+        );
+    }
 }
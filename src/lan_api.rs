@@ -1,14 +1,26 @@
 use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::cache::{cache_dir, write_private_file};
 use crate::opt_env_var;
 use crate::platform_api::from_json;
+use crate::service::command_audit::PendingCommand;
+use crate::service::command_queue::pace_command;
+use crate::service::device::normalize_device_id;
+use crate::service::state_merge::Transport;
+use crate::service::supervisor::{RestartPolicy, Supervisor};
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
+use chrono::{DateTime, Utc};
+pub use govee_models::json::{boolean_int, opt_boolean_int};
 use if_addrs::IfAddr;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex as SyncMutex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::Mutex;
@@ -24,7 +36,68 @@ const LISTEN_PORT: u16 = 4002;
 /// The port on which govee devices listen for control requests
 const CMD_PORT: u16 = 4003;
 /// The multicast group of which govee LAN-API enabled devices are members
-const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
+const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const MULTICAST: IpAddr = IpAddr::V4(MULTICAST_V4);
+/// If we have previously heard from devices, but then hear nothing
+/// at all for this long, assume that the network has flapped (eg: the
+/// router rebooted) and rebind the listener. Since we re-scan at least
+/// every minute, responsive devices will reply well within this time.
+/// A longer scan interval extends this to 5 scan intervals.
+const SILENCE_THRESHOLD: Duration = Duration::from_secs(300);
+/// The default of the longest interval between discovery scans
+const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// The smallest CIDR prefix accepted as a scan target, to keep
+/// a typo from unicasting to a whole /8
+const MIN_SCAN_PREFIX: u8 = 22;
+
+/// An address to scan: either a single address, which may be a
+/// broadcast or multicast address, or an IPv4 range in CIDR notation,
+/// each of whose host addresses is scanned individually
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanTarget(Vec<IpAddr>);
+
+impl ScanTarget {
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for ScanTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let Some((addr, prefix)) = s.split_once('/') else {
+            let ip = s
+                .parse()
+                .with_context(|| format!("parsing {s} as IpAddr"))?;
+            return Ok(Self(vec![ip]));
+        };
+
+        let addr: Ipv4Addr = addr
+            .parse()
+            .with_context(|| format!("parsing {addr} as an IPv4 address"))?;
+        let prefix: u8 = prefix
+            .parse()
+            .with_context(|| format!("parsing {prefix} as a prefix length"))?;
+        anyhow::ensure!(
+            (MIN_SCAN_PREFIX..=32).contains(&prefix),
+            "the prefix length of {s} must be between {MIN_SCAN_PREFIX} and 32"
+        );
+
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        let network = u32::from(addr) & mask;
+        let last = network | !mask;
+        // The network and broadcast addresses aren't hosts,
+        // except in the /31 and /32 cases
+        let hosts = if prefix >= 31 {
+            network..=last
+        } else {
+            network + 1..=last - 1
+        };
+        Ok(Self(hosts.map(|a| IpAddr::V4(Ipv4Addr::from(a))).collect()))
+    }
+}
 
 #[derive(clap::Parser, Debug)]
 pub struct LanDiscoArguments {
@@ -44,11 +117,26 @@ pub struct LanDiscoArguments {
     #[arg(long, global = true)]
     pub global_broadcast: bool,
 
-    /// Addresses to scan. May be broadcast addresses or individual
-    /// IP addresses. Can be specified multiple times.
+    /// Addresses to scan. May be broadcast addresses, individual
+    /// IP addresses, or IPv4 ranges such as 10.0.20.0/24, each of
+    /// whose addresses is scanned individually.
+    /// Can be specified multiple times.
     /// You may also set GOVEE_LAN_SCAN=10.0.0.1,10.0.0.2 via the environment.
     #[arg(long, global = true)]
-    pub scan: Vec<IpAddr>,
+    pub scan: Vec<ScanTarget>,
+
+    /// The name of a network interface, such as eth1, from which to
+    /// multicast discovery packets, rather than only the interface of
+    /// the default route. Can be specified multiple times.
+    /// You may also set GOVEE_LAN_MULTICAST_INTERFACES=eth0,eth1 via the environment.
+    #[arg(long, global = true)]
+    pub multicast_interface: Vec<String>,
+
+    /// The longest interval between discovery scans, in seconds.
+    /// Scans are sent more often while discovery is starting up.
+    /// You may also set GOVEE_LAN_SCAN_INTERVAL via the environment.
+    #[arg(long, global = true)]
+    pub scan_interval: Option<u64>,
 
     /// How long to wait for discovery to complete, in seconds
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
@@ -78,9 +166,15 @@ impl LanDiscoArguments {
     pub fn to_disco_options(&self) -> anyhow::Result<DiscoOptions> {
         let mut options = DiscoOptions {
             enable_multicast: !self.no_multicast,
-            additional_addresses: self.scan.clone(),
+            additional_addresses: self
+                .scan
+                .iter()
+                .flat_map(|target| target.addresses().iter().copied())
+                .collect(),
             broadcast_all_interfaces: self.broadcast_all,
             global_broadcast: self.global_broadcast,
+            multicast_interfaces: self.multicast_interface.clone(),
+            scan_interval: DEFAULT_SCAN_INTERVAL,
         };
 
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_NO_MULTICAST")? {
@@ -97,14 +191,35 @@ impl LanDiscoArguments {
 
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_SCAN")? {
             for addr in v.split(',') {
-                let ip = addr
-                    .trim()
+                let target: ScanTarget = addr
                     .parse()
-                    .with_context(|| format!("parsing {v} as IpAddr"))?;
-                options.additional_addresses.push(ip);
+                    .with_context(|| format!("parsing GOVEE_LAN_SCAN={v}"))?;
+                options
+                    .additional_addresses
+                    .extend_from_slice(target.addresses());
             }
         }
 
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_MULTICAST_INTERFACES")? {
+            options.multicast_interfaces.extend(
+                v.split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty()),
+            );
+        }
+
+        let scan_interval = match opt_env_var("GOVEE_LAN_SCAN_INTERVAL")? {
+            Some(v) => Some(v),
+            None => self.scan_interval,
+        };
+        if let Some(secs) = scan_interval {
+            anyhow::ensure!(
+                secs >= 2,
+                "the LAN scan interval must be at least 2 seconds"
+            );
+            options.scan_interval = Duration::from_secs(secs);
+        }
+
         Ok(options)
     }
 
@@ -117,6 +232,7 @@ impl LanDiscoArguments {
     }
 }
 
+#[derive(Clone)]
 pub struct DiscoOptions {
     /// Use the MULTICAST address defined in the LAN protocol
     pub enable_multicast: bool,
@@ -128,9 +244,25 @@ pub struct DiscoOptions {
     pub broadcast_all_interfaces: bool,
     /// Broadcast to the global broadcast address
     pub global_broadcast: bool,
+    /// Multicast from each of these interfaces, rather
+    /// than via the default route
+    pub multicast_interfaces: Vec<String>,
+    /// The longest interval between discovery scans
+    pub scan_interval: Duration,
 }
 
 impl DiscoOptions {
+    /// Returns a copy of these options that also scans `addresses`
+    fn with_additional_addresses<I: IntoIterator<Item = IpAddr>>(&self, addresses: I) -> Self {
+        let mut options = self.clone();
+        for addr in addresses {
+            if !options.additional_addresses.contains(&addr) {
+                options.additional_addresses.push(addr);
+            }
+        }
+        options
+    }
+
     pub fn is_empty(&self) -> bool {
         !self.enable_multicast
             && self.additional_addresses.is_empty()
@@ -146,6 +278,8 @@ impl Default for DiscoOptions {
             additional_addresses: vec![],
             broadcast_all_interfaces: false,
             global_broadcast: false,
+            multicast_interfaces: vec![],
+            scan_interval: DEFAULT_SCAN_INTERVAL,
         }
     }
 }
@@ -169,11 +303,158 @@ pub enum Request {
     },
     #[serde(rename = "ptReal")]
     PtReal { command: Vec<String> },
+    #[serde(rename = "pair")]
+    Pair { account_topic: AccountTopic },
+}
+
+/// A typed LAN API command, for use with `LanDevice::send_command`.
+/// This saves callers from having to construct the raw JSON that is
+/// sent over the wire.
+///
+/// Commands that don't have a typed representation here can be
+/// sent using `LanCommand::Custom`; if you find yourself using that
+/// for a command that is generally useful, consider adding a typed
+/// variant for it here instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanCommand {
+    /// Turn the device on or off
+    Turn(bool),
+    /// Set the brightness, as a percentage
+    Brightness(u8),
+    /// Set the color and/or color temperature.
+    /// A color_temperature_kelvin of 0 selects the RGB color.
+    ColorWc {
+        color: DeviceColor,
+        color_temperature_kelvin: u32,
+    },
+    /// Send a sequence of base64 encoded BLE packets
+    PtReal(Vec<String>),
+    /// Send an arbitrary command. `cmd` is the LAN API command
+    /// name and `data` is sent verbatim as its payload.
+    Custom { cmd: String, data: JsonValue },
+}
+
+impl LanCommand {
+    pub fn color_rgb(color: DeviceColor) -> Self {
+        Self::ColorWc {
+            color,
+            color_temperature_kelvin: 0,
+        }
+    }
+
+    pub fn color_temperature_kelvin(color_temperature_kelvin: u32) -> Self {
+        Self::ColorWc {
+            color: DeviceColor::default(),
+            color_temperature_kelvin,
+        }
+    }
+
+    fn to_request(&self) -> Option<Request> {
+        Some(match self {
+            Self::Turn(on) => Request::Turn {
+                value: if *on { 1 } else { 0 },
+            },
+            Self::Brightness(value) => Request::Brightness { value: *value },
+            Self::ColorWc {
+                color,
+                color_temperature_kelvin,
+            } => Request::Color {
+                color: *color,
+                color_temperature_kelvin: *color_temperature_kelvin,
+            },
+            Self::PtReal(command) => Request::PtReal {
+                command: command.clone(),
+            },
+            Self::Custom { .. } => return None,
+        })
+    }
+}
+
+impl Serialize for LanCommand {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Custom { cmd, data } => {
+                serde_json::json!({"cmd": cmd, "data": data}).serialize(serializer)
+            }
+            _ => self.to_request().serialize(serializer),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct RequestMessage {
-    msg: Request,
+struct RequestMessage<T = Request> {
+    msg: T,
+    /// The token obtained by pairing with the device, for
+    /// firmware that requires it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl<T> RequestMessage<T> {
+    fn for_device(device: &LanDevice, msg: T) -> Self {
+        Self {
+            msg,
+            token: lan_token_for_device(&device.device),
+        }
+    }
+}
+
+fn lan_tokens_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-lan-tokens.json")
+}
+
+#[derive(Default)]
+struct LanTokens {
+    /// The modification time of the file when it was loaded, so that
+    /// a token saved by `govee lan-control pair` is picked up by a
+    /// running service
+    modified: Option<SystemTime>,
+    /// Maps normalized device ids to the token obtained by pairing
+    tokens: HashMap<String, String>,
+}
+
+static LAN_TOKENS: Lazy<SyncMutex<LanTokens>> = Lazy::new(Default::default);
+
+/// Returns the tokens, reloading them if the file has changed
+fn lan_tokens() -> parking_lot::MutexGuard<'static, LanTokens> {
+    let path = lan_tokens_file_name();
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    let mut tokens = LAN_TOKENS.lock();
+    if tokens.modified != modified {
+        let loaded: HashMap<String, String> = match std::fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|err| {
+                log::error!("Ignoring LAN tokens in {path:?}: {err:#}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        tokens.tokens = loaded
+            .into_iter()
+            .map(|(id, token)| (normalize_device_id(&id), token))
+            .collect();
+        tokens.modified = modified;
+    }
+    tokens
+}
+
+pub fn lan_token_for_device(id: &str) -> Option<String> {
+    lan_tokens().tokens.get(&normalize_device_id(id)).cloned()
+}
+
+/// Record the token for a device, persisting it so that it will
+/// be used by the service and by subsequent runs
+pub fn save_lan_token(id: &str, token: &str) -> anyhow::Result<PathBuf> {
+    let mut tokens = lan_tokens();
+    tokens
+        .tokens
+        .insert(normalize_device_id(id), token.to_string());
+    let path = lan_tokens_file_name();
+    write_private_file(
+        &path,
+        serde_json::to_string_pretty(&tokens.tokens)?.as_bytes(),
+    )?;
+    tokens.modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    Ok(path)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
@@ -195,87 +476,88 @@ impl LanDevice {
     pub async fn send_request(&self, msg: Request) -> anyhow::Result<()> {
         log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
         let client = udp_socket_for_target(self.ip).await?;
-        let data = serde_json::to_string(&RequestMessage { msg })?;
+        let data = serde_json::to_string(&RequestMessage::for_device(self, msg))?;
         client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
 
         Ok(())
     }
 
+    pub async fn send_command(&self, msg: LanCommand) -> anyhow::Result<()> {
+        log::trace!("LanDevice::send_command to {:?} {msg:?}", self.ip);
+        let audit = PendingCommand::start(Transport::Lan, &self.device, &self.sku, &msg);
+        let result = async {
+            pace_command(Transport::Lan, &self.device).await;
+            let client = udp_socket_for_target(self.ip).await?;
+            let data = serde_json::to_string(&RequestMessage::for_device(self, msg))?;
+            client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
+            Ok(())
+        }
+        .await;
+        audit.finish(&result);
+        result
+    }
+
     pub async fn send_turn(&self, on: bool) -> anyhow::Result<()> {
-        self.send_request(Request::Turn {
-            value: if on { 1 } else { 0 },
-        })
-        .await
+        self.send_command(LanCommand::Turn(on)).await
     }
 
     pub async fn send_brightness(&self, percent: u8) -> anyhow::Result<()> {
-        self.send_request(Request::Brightness { value: percent })
-            .await
+        self.send_command(LanCommand::Brightness(percent)).await
     }
 
     pub async fn send_color_rgb(&self, color: DeviceColor) -> anyhow::Result<()> {
-        self.send_request(Request::Color {
-            color,
-            color_temperature_kelvin: 0,
-        })
-        .await
+        self.send_command(LanCommand::color_rgb(color)).await
     }
 
     pub async fn send_real(&self, commands: Vec<String>) -> anyhow::Result<()> {
-        self.send_request(Request::PtReal { command: commands })
-            .await
+        self.send_command(LanCommand::PtReal(commands)).await
     }
 
     pub async fn send_color_temperature_kelvin(
         &self,
         color_temperature_kelvin: u32,
     ) -> anyhow::Result<()> {
-        self.send_request(Request::Color {
-            color: DeviceColor { r: 0, g: 0, b: 0 },
+        self.send_command(LanCommand::color_temperature_kelvin(
             color_temperature_kelvin,
-        })
+        ))
         .await
     }
 
     pub async fn set_scene_by_name(&self, scene_name: &str) -> anyhow::Result<()> {
-        for category in GoveeUndocumentedApi::get_scenes_for_device(&self.sku).await? {
-            for scene in category.scenes {
-                for effect in scene.light_effects {
-                    if scene.scene_name == scene_name && effect.scene_code != 0 {
-                        let encoded = Base64HexBytes::encode_for_sku(
-                            "Generic:Light",
-                            &SetSceneCode {
-                                code: effect.scene_code,
-                            },
-                        )?
-                        .base64();
-                        log::info!(
-                            "sending scene packet {encoded:x?} for {scene_name}, code {}",
-                            effect.scene_code
-                        );
-                        return self.send_real(vec![encoded]).await;
-                    }
-                }
-            }
-        }
-
-        anyhow::bail!("unable to set scene {scene_name} for {}", self.device);
+        let encoded = scene_packet_by_name(&self.sku, scene_name)
+            .await?
+            .ok_or_else(|| {
+                anyhow::anyhow!("unable to set scene {scene_name} for {}", self.device)
+            })?;
+        self.send_real(vec![encoded]).await
     }
 }
 
-pub fn boolean_int<'de, D: serde::de::Deserializer<'de>>(
-    deserializer: D,
-) -> Result<bool, D::Error> {
-    Ok(match serde::de::Deserialize::deserialize(deserializer)? {
-        JsonValue::Bool(b) => b,
-        JsonValue::Number(num) => {
-            num.as_i64()
-                .ok_or(serde::de::Error::custom("Invalid number"))?
-                != 0
+/// Returns the base64 encoded ptReal packet that activates the named
+/// scene from the scene catalog for `sku`, suitable for sending via
+/// either the LAN API or AWS IoT
+pub async fn scene_packet_by_name(sku: &str, scene_name: &str) -> anyhow::Result<Option<String>> {
+    for category in GoveeUndocumentedApi::get_scenes_for_device(sku).await? {
+        for scene in category.scenes {
+            for effect in scene.light_effects {
+                if scene.scene_name == scene_name && effect.scene_code != 0 {
+                    let encoded = Base64HexBytes::encode_for_sku(
+                        "Generic:Light",
+                        &SetSceneCode {
+                            code: effect.scene_code,
+                        },
+                    )?
+                    .base64();
+                    log::info!(
+                        "scene packet {encoded:x?} for {scene_name}, code {}",
+                        effect.scene_code
+                    );
+                    return Ok(Some(encoded));
+                }
+            }
         }
-        JsonValue::Null => false,
-        _ => return Err(serde::de::Error::custom("Wrong type, expected boolean")),
-    })
+    }
+    Ok(None)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
@@ -302,6 +584,13 @@ pub enum Response {
     Scan(LanDevice),
     #[serde(rename = "devStatus")]
     DevStatus(DeviceStatus),
+    #[serde(rename = "pair")]
+    Pair(PairResponse),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairResponse {
+    pub token: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -320,9 +609,93 @@ struct ClientListener {
     tx: Sender<Response>,
 }
 
+/// Where and when a device was found by discovery
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LanDiscovery {
+    pub device: String,
+    pub sku: String,
+    pub ip: IpAddr,
+    /// The local interface whose network includes the device,
+    /// or None if the device is reached via a router
+    pub interface: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Whether `ip` is on the same network as the
+/// interface address `local` with `netmask`
+fn same_network(ip: IpAddr, local: IpAddr, netmask: IpAddr) -> bool {
+    match (ip, local, netmask) {
+        (IpAddr::V4(ip), IpAddr::V4(local), IpAddr::V4(mask)) => {
+            u32::from(ip) & u32::from(mask) == u32::from(local) & u32::from(mask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(local), IpAddr::V6(mask)) => {
+            u128::from(ip) & u128::from(mask) == u128::from(local) & u128::from(mask)
+        }
+        _ => false,
+    }
+}
+
+/// The name of the local interface whose network includes `ip`
+fn interface_for_address(ip: IpAddr) -> Option<String> {
+    let ifaces = if_addrs::get_if_addrs()
+        .map_err(|err| log::error!("get_if_addrs: {err:#}"))
+        .ok()?;
+    ifaces
+        .into_iter()
+        .find(|iface| {
+            let (local, netmask) = match &iface.addr {
+                IfAddr::V4(v4) => (IpAddr::V4(v4.ip), IpAddr::V4(v4.netmask)),
+                IfAddr::V6(v6) => (IpAddr::V6(v6.ip), IpAddr::V6(v6.netmask)),
+            };
+            same_network(ip, local, netmask)
+        })
+        .map(|iface| iface.name)
+}
+
 #[derive(Default)]
 struct ClientInner {
     mux: Mutex<Vec<ClientListener>>,
+    /// The addresses of devices that have responded to discovery,
+    /// which are scanned directly when re-discovering
+    known_addresses: SyncMutex<HashSet<IpAddr>>,
+    /// Keyed by device id
+    discoveries: SyncMutex<HashMap<String, LanDiscovery>>,
+}
+
+impl ClientInner {
+    fn record_discovery(&self, info: &LanDevice) {
+        let now = Utc::now();
+        let mut discoveries = self.discoveries.lock();
+        if let Some(existing) = discoveries.get_mut(&info.device) {
+            if existing.ip == info.ip {
+                existing.last_seen = now;
+                return;
+            }
+        }
+        let first_seen = discoveries
+            .get(&info.device)
+            .map(|d| d.first_seen)
+            .unwrap_or(now);
+        let interface = interface_for_address(info.ip);
+        log::debug!(
+            "LAN disco: {} at {} via {}",
+            info.device,
+            info.ip,
+            interface.as_deref().unwrap_or("a router")
+        );
+        discoveries.insert(
+            info.device.to_string(),
+            LanDiscovery {
+                device: info.device.to_string(),
+                sku: info.sku.to_string(),
+                ip: info.ip,
+                interface,
+                first_seen,
+                last_seen: now,
+            },
+        );
+    }
 }
 
 #[derive(Clone)]
@@ -365,6 +738,20 @@ impl Broadcaster {
         Ok(Self { addr, socket })
     }
 
+    /// Multicast to `group` from the interface that has the address
+    /// `local`, rather than from the interface of the default route
+    pub async fn on_interface(group: Ipv4Addr, local: Ipv4Addr) -> std::io::Result<Self> {
+        // Linux sends multicast from the interface that owns
+        // the address to which the socket is bound
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.join_multicast_v4(group, local)?;
+        socket.set_multicast_loop_v4(false)?;
+        Ok(Self {
+            addr: group.into(),
+            socket,
+        })
+    }
+
     pub async fn broadcast<B: AsRef<[u8]>>(&self, bytes: B) -> std::io::Result<()> {
         self.socket
             .send_to(bytes.as_ref(), (self.addr, SCAN_PORT))
@@ -373,51 +760,114 @@ impl Broadcaster {
     }
 }
 
+/// The interfaces named in `multicast_interfaces` that don't exist,
+/// so that each is only complained about once
+static MISSING_INTERFACES: Lazy<SyncMutex<HashSet<String>>> = Lazy::new(Default::default);
+
 async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
     let mut addresses = options.additional_addresses.clone();
-    if options.enable_multicast {
-        addresses.push(MULTICAST);
-    }
     if options.global_broadcast {
         addresses.push(Ipv4Addr::BROADCAST.into());
     }
-    if options.broadcast_all_interfaces {
+
+    let ifaces = if options.broadcast_all_interfaces || !options.multicast_interfaces.is_empty() {
         match if_addrs::get_if_addrs() {
-            Ok(ifaces) => {
-                for iface in ifaces {
-                    if iface.is_loopback() {
-                        continue;
-                    }
-                    let bcast = match iface.addr {
-                        IfAddr::V4(v4) => v4.broadcast.map(IpAddr::V4),
-                        IfAddr::V6(v6) => v6.broadcast.map(IpAddr::V6),
-                    };
-                    if let Some(addr) = bcast {
-                        log::debug!("Adding bcast {addr} from if {}", iface.name);
-                        addresses.push(addr);
-                    }
-                }
-            }
+            Ok(ifaces) => ifaces,
             Err(err) => {
                 log::error!("get_if_addrs: {err:#}");
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    if options.broadcast_all_interfaces {
+        for iface in &ifaces {
+            if iface.is_loopback() {
+                continue;
+            }
+            let bcast = match &iface.addr {
+                IfAddr::V4(v4) => v4.broadcast.map(IpAddr::V4),
+                IfAddr::V6(v6) => v6.broadcast.map(IpAddr::V6),
+            };
+            if let Some(addr) = bcast {
+                log::debug!("Adding bcast {addr} from if {}", iface.name);
+                addresses.push(addr);
             }
         }
     }
 
     let mut broadcasters = vec![];
+    if options.enable_multicast {
+        if options.multicast_interfaces.is_empty() {
+            addresses.push(MULTICAST);
+        }
+        for name in &options.multicast_interfaces {
+            let locals: Vec<Ipv4Addr> = ifaces
+                .iter()
+                .filter(|iface| iface.name == *name)
+                .filter_map(|iface| match &iface.addr {
+                    IfAddr::V4(v4) => Some(v4.ip),
+                    IfAddr::V6(_) => None,
+                })
+                .collect();
+            if locals.is_empty() {
+                if MISSING_INTERFACES.lock().insert(name.to_string()) {
+                    log::warn!(
+                        "There is no interface named {name} with an IPv4 address to multicast from"
+                    );
+                }
+                continue;
+            }
+            MISSING_INTERFACES.lock().remove(name);
+            for local in locals {
+                match Broadcaster::on_interface(MULTICAST_V4, local).await {
+                    Ok(b) => broadcasters.push(b),
+                    Err(err) => {
+                        log::error!("{MULTICAST} via {name} ({local}): {err:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    // Unicast and broadcast addresses share a socket per address
+    // family, as a range may expand to hundreds of them
+    let mut unicast: HashMap<bool, UdpSocket> = HashMap::new();
+    let mut unicast_addresses = vec![];
     for addr in addresses {
-        match Broadcaster::new(addr).await {
-            Ok(b) => broadcasters.push(b),
-            Err(err) => {
-                log::error!("{addr}: {err:#}");
+        if addr.is_multicast() {
+            match Broadcaster::new(addr).await {
+                Ok(b) => broadcasters.push(b),
+                Err(err) => {
+                    log::error!("{addr}: {err:#}");
+                }
+            }
+            continue;
+        }
+        if let std::collections::hash_map::Entry::Vacant(entry) = unicast.entry(addr.is_ipv4()) {
+            match udp_socket_for_target(addr)
+                .await
+                .and_then(|socket| socket.set_broadcast(true).map(|_| socket))
+            {
+                Ok(socket) => {
+                    entry.insert(socket);
+                }
+                Err(err) => {
+                    log::error!("{addr}: {err:#}");
+                    continue;
+                }
             }
         }
+        unicast_addresses.push(addr);
     }
 
     let scan = serde_json::to_string(&RequestMessage {
         msg: Request::Scan {
             account_topic: AccountTopic::Reserve,
         },
+        token: None,
     })
     .expect("to serialize scan message");
     for b in broadcasters {
@@ -426,14 +876,20 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
             log::error!("Error broadcasting to {b:?}: {err:#}");
         }
     }
+    for addr in unicast_addresses {
+        let Some(socket) = unicast.get(&addr.is_ipv4()) else {
+            continue;
+        };
+        log::trace!("Send disco packet to {addr}");
+        if let Err(err) = socket.send_to(scan.as_bytes(), (addr, SCAN_PORT)).await {
+            log::error!("Error sending disco packet to {addr}: {err:#}");
+        }
+    }
     Ok(())
 }
 
-async fn lan_disco(
-    options: DiscoOptions,
-    inner: Arc<ClientInner>,
-) -> anyhow::Result<Receiver<LanDevice>> {
-    let listen = UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
+async fn bind_listener() -> anyhow::Result<UdpSocket> {
+    UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
         "Cannot bind to UDP Port 4002, which is required \
         for the Govee LAN API to function. Most likely cause is that you \
         are running another integration (perhaps `Govee LAN Control`, or \
@@ -441,7 +897,15 @@ async fn lan_disco(
         Both cannot run on the same machine at the same time. \
         Consider disabling `Govee LAN Control` or setting `lanDisable` in \
         `homebridge-govee`.",
-    )?;
+    )
+}
+
+async fn lan_disco(
+    options: DiscoOptions,
+    inner: Arc<ClientInner>,
+    supervisor: &Supervisor,
+) -> anyhow::Result<Receiver<LanDevice>> {
+    let listen = bind_listener().await?;
     let (tx, rx) = channel(8);
 
     async fn process_packet(
@@ -467,6 +931,8 @@ async fn lan_disco(
         }
 
         if let Response::Scan(info) = response.msg {
+            inner.known_addresses.lock().insert(info.ip);
+            inner.record_discovery(&info);
             tx.send(info).await?;
         }
 
@@ -475,21 +941,30 @@ async fn lan_disco(
 
     async fn run_disco(
         options: &DiscoOptions,
-        listen: UdpSocket,
+        listen: &UdpSocket,
         tx: Sender<LanDevice>,
         inner: Arc<ClientInner>,
     ) -> anyhow::Result<()> {
-        send_scan(options).await?;
+        // Scan the devices that we already know about directly, as
+        // well as via the configured discovery options, so that they
+        // are quickly re-discovered after the listener is rebound
+        let scan_options =
+            || options.with_additional_addresses(inner.known_addresses.lock().iter().copied());
+
+        send_scan(&scan_options()).await?;
 
-        let mut retry_interval = Duration::from_secs(2);
-        let max_retry = Duration::from_secs(60);
+        let max_retry = options.scan_interval;
+        let mut retry_interval = Duration::from_secs(2).min(max_retry);
+        let silence_threshold = SILENCE_THRESHOLD.max(max_retry * 5);
         let mut last_send = Instant::now();
+        let mut last_received = Instant::now();
         loop {
             let mut buf = [0u8; 4096];
 
             let deadline = last_send + retry_interval;
             match tokio::time::timeout_at(deadline, listen.recv_from(&mut buf)).await {
                 Ok(Ok((len, addr))) => {
+                    last_received = Instant::now();
                     if let Err(err) = process_packet(addr, &buf[0..len], &inner, &tx).await {
                         log::error!("process_packet: {err:#}");
                     }
@@ -498,7 +973,14 @@ async fn lan_disco(
                     log::error!("recv_from: {err:#}");
                 }
                 Err(_) => {
-                    send_scan(options).await?;
+                    let silence = last_received.elapsed();
+                    if silence > silence_threshold && !inner.known_addresses.lock().is_empty() {
+                        anyhow::bail!(
+                            "Heard nothing from any LAN API device for {silence:?}, \
+                            the network may have changed"
+                        );
+                    }
+                    send_scan(&scan_options()).await?;
                     last_send = Instant::now();
                     retry_interval = (retry_interval * 2).min(max_retry);
                 }
@@ -506,9 +988,25 @@ async fn lan_disco(
         }
     }
 
-    tokio::spawn(async move {
-        if let Err(err) = run_disco(&options, listen, tx, inner).await {
-            log::error!("Error at the disco: {err:#}");
+    // When run_disco fails, the supervisor restarts it after a backoff,
+    // which limits how often we rebind. The first run uses the socket
+    // that we bound above, so that errors binding it are reported to
+    // our caller; subsequent runs bind a fresh socket.
+    let listen = SyncMutex::new(Some(listen));
+    supervisor.supervise("lan_disco", RestartPolicy::OnFailure, move || {
+        let listen = listen.lock().take();
+        let options = options.clone();
+        let tx = tx.clone();
+        let inner = inner.clone();
+        async move {
+            let listen = match listen {
+                Some(listen) => listen,
+                None => {
+                    log::info!("Rebinding the LAN API listener and re-discovering devices");
+                    bind_listener().await?
+                }
+            };
+            run_disco(&options, &listen, tx, inner).await
         }
     });
 
@@ -516,13 +1014,28 @@ async fn lan_disco(
 }
 
 impl Client {
-    pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
+    /// Start discovery, running the listener as a task of `supervisor`
+    pub async fn new(
+        options: DiscoOptions,
+        supervisor: &Supervisor,
+    ) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
         let inner = Arc::new(ClientInner::default());
-        let rx = lan_disco(options, Arc::clone(&inner)).await?;
+        let rx = lan_disco(options, Arc::clone(&inner), supervisor).await?;
 
         Ok((Self { inner }, rx))
     }
 
+    /// Where and when each device was found by discovery
+    pub fn discoveries(&self) -> Vec<LanDiscovery> {
+        let mut discoveries: Vec<_> = self.inner.discoveries.lock().values().cloned().collect();
+        discoveries.sort_by(|a, b| a.device.cmp(&b.device));
+        discoveries
+    }
+
+    pub fn discovery_for_device(&self, id: &str) -> Option<LanDiscovery> {
+        self.inner.discoveries.lock().get(id).cloned()
+    }
+
     async fn add_listener(&self, addr: IpAddr) -> anyhow::Result<Receiver<Response>> {
         let (tx, rx) = channel(1);
         let mut mux = self.inner.mux.lock().await;
@@ -543,6 +1056,7 @@ impl Client {
             msg: Request::Scan {
                 account_topic: AccountTopic::Reserve,
             },
+            token: None,
         })
         .expect("to serialize scan message");
         bcast.broadcast(scan).await?;
@@ -577,4 +1091,150 @@ impl Client {
 
         anyhow::bail!("timed out waiting for status");
     }
+
+    /// Request a LAN control token from a device whose firmware
+    /// requires pairing. The pairing request must be confirmed
+    /// on the device, or in the Govee Home app, within `timeout`.
+    pub async fn pair(&self, device: &LanDevice, timeout: Duration) -> anyhow::Result<String> {
+        let mut rx = self.add_listener(device.ip).await?;
+        let deadline = Instant::now() + timeout;
+        while Instant::now() <= deadline {
+            log::trace!("request pairing with {}", device.ip);
+            device
+                .send_request(Request::Pair {
+                    account_topic: AccountTopic::Reserve,
+                })
+                .await?;
+            match tokio::time::timeout(Duration::from_secs(2), rx.recv()).await {
+                Ok(Some(Response::Pair(pair))) => {
+                    return Ok(pair.token);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => anyhow::bail!("listener thread terminated"),
+                Err(_) => {}
+            }
+        }
+
+        anyhow::bail!("timed out waiting for pairing to be confirmed");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lan_command_serialization() {
+        k9::assert_equal!(
+            serde_json::to_string(&RequestMessage {
+                msg: LanCommand::Brightness(50),
+                token: None,
+            })
+            .unwrap(),
+            r#"{"msg":{"cmd":"brightness","data":{"value":50}}}"#
+        );
+        k9::assert_equal!(
+            serde_json::to_string(&RequestMessage {
+                msg: LanCommand::Turn(true),
+                token: Some("secret".to_string()),
+            })
+            .unwrap(),
+            r#"{"msg":{"cmd":"turn","data":{"value":1}},"token":"secret"}"#
+        );
+        k9::assert_equal!(
+            serde_json::to_string(&LanCommand::color_rgb(DeviceColor { r: 1, g: 2, b: 3 }))
+                .unwrap(),
+            r#"{"cmd":"colorwc","data":{"color":{"r":1,"g":2,"b":3},"colorTemInKelvin":0}}"#
+        );
+        k9::assert_equal!(
+            serde_json::to_string(&LanCommand::Custom {
+                cmd: "razer".to_string(),
+                data: serde_json::json!({"pt": "uwABsQFt"}),
+            })
+            .unwrap(),
+            r#"{"cmd":"razer","data":{"pt":"uwABsQFt"}}"#
+        );
+    }
+
+    #[test]
+    fn rediscovery_scans_known_addresses() {
+        let options = DiscoOptions {
+            additional_addresses: vec!["10.0.0.1".parse().unwrap()],
+            ..DiscoOptions::default()
+        };
+        let known: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let burst = options.with_additional_addresses(known);
+        k9::assert_equal!(
+            burst.additional_addresses,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert!(burst.enable_multicast);
+    }
+
+    #[test]
+    fn scan_targets() {
+        let target: ScanTarget = "10.0.20.1".parse().unwrap();
+        k9::assert_equal!(
+            target.addresses(),
+            &["10.0.20.1".parse::<IpAddr>().unwrap()]
+        );
+
+        let target: ScanTarget = "10.0.20.9/29".parse().unwrap();
+        let hosts: Vec<String> = target.addresses().iter().map(|a| a.to_string()).collect();
+        k9::assert_equal!(
+            hosts,
+            vec![
+                "10.0.20.9",
+                "10.0.20.10",
+                "10.0.20.11",
+                "10.0.20.12",
+                "10.0.20.13",
+                "10.0.20.14"
+            ]
+        );
+        k9::assert_equal!(
+            "10.0.20.0/24"
+                .parse::<ScanTarget>()
+                .unwrap()
+                .addresses()
+                .len(),
+            254
+        );
+        k9::assert_equal!(
+            "10.0.20.5/32"
+                .parse::<ScanTarget>()
+                .unwrap()
+                .addresses()
+                .len(),
+            1
+        );
+
+        assert!("10.0.0.0/8".parse::<ScanTarget>().is_err());
+        assert!("10.0.20.0/33".parse::<ScanTarget>().is_err());
+        assert!("fd00::/120".parse::<ScanTarget>().is_err());
+        assert!("lamp".parse::<ScanTarget>().is_err());
+    }
+
+    #[test]
+    fn interface_networks() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(same_network(
+            ip("10.0.20.7"),
+            ip("10.0.20.1"),
+            ip("255.255.255.0")
+        ));
+        assert!(!same_network(
+            ip("10.0.30.7"),
+            ip("10.0.20.1"),
+            ip("255.255.255.0")
+        ));
+        assert!(!same_network(
+            ip("fd00::7"),
+            ip("10.0.20.1"),
+            ip("255.255.255.0")
+        ));
+    }
 }
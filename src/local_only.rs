@@ -0,0 +1,279 @@
+//! Stand-ins for the Govee cloud clients, used by local only builds
+//! made with `--no-default-features --features lan,mqtt`.
+//!
+//! The clients are uninhabited types, so a client can never be
+//! constructed and the state never holds one. That makes every code
+//! path that would talk to the Platform API, the undocumented API or
+//! AWS IoT unreachable, while letting the device pipeline keep its
+//! usual `if let Some(client) = state.get_platform_client()` shape
+//! rather than being littered with cfg attributes.
+//! The scene catalog lookups aren't tied to a client, so they
+//! report that they are unavailable instead.
+use crate::platform_api::{
+    ControlDeviceResponseCapability, DeviceCapability, HttpDeviceInfo, HttpDeviceState,
+};
+use crate::temperature::TemperatureValue;
+use crate::undoc_api::{
+    DeviceEntry, DevicesResponse, DiyEffect, FirmwareUpdate, IotKey, LightEffectCategory,
+    LoginAccountResponse, ParsedFeast, ParsedOneClick, SceneIcon,
+};
+use serde_json::Value as JsonValue;
+
+pub const LOCAL_ONLY: &str = "govee2mqtt was built without the cloud feature";
+
+#[derive(Clone)]
+pub enum GoveeApiClient {}
+
+impl GoveeApiClient {
+    pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+        match *self {}
+    }
+
+    pub fn invalidate_device_list(&self) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn control_device<V: Into<JsonValue>>(
+        &self,
+        _device: &HttpDeviceInfo,
+        _capability: &DeviceCapability,
+        _value: V,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn get_device_state(
+        &self,
+        _device: &HttpDeviceInfo,
+    ) -> anyhow::Result<HttpDeviceState> {
+        match *self {}
+    }
+
+    pub async fn list_scene_names(&self, _device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>> {
+        match *self {}
+    }
+
+    pub async fn scene_name_for_value(
+        &self,
+        _device: &HttpDeviceInfo,
+        _value: &JsonValue,
+    ) -> anyhow::Result<Option<String>> {
+        match *self {}
+    }
+
+    pub async fn set_scene_by_name(
+        &self,
+        _device: &HttpDeviceInfo,
+        _scene: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_target_temperature(
+        &self,
+        _device: &HttpDeviceInfo,
+        _instance_name: &str,
+        _target: TemperatureValue,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_work_mode(
+        &self,
+        _device: &HttpDeviceInfo,
+        _work_mode: i64,
+        _value: i64,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_toggle_state(
+        &self,
+        _device: &HttpDeviceInfo,
+        _instance: &str,
+        _on: bool,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_range(
+        &self,
+        _device: &HttpDeviceInfo,
+        _instance: &str,
+        _value: i64,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_power_state(
+        &self,
+        _device: &HttpDeviceInfo,
+        _on: bool,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_brightness(
+        &self,
+        _device: &HttpDeviceInfo,
+        _percent: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_color_temperature(
+        &self,
+        _device: &HttpDeviceInfo,
+        _kelvin: u32,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_color_rgb(
+        &self,
+        _device: &HttpDeviceInfo,
+        _r: u8,
+        _g: u8,
+        _b: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_segment_rgb(
+        &self,
+        _device: &HttpDeviceInfo,
+        _segment: u32,
+        _r: u8,
+        _g: u8,
+        _b: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+
+    pub async fn set_segment_brightness(
+        &self,
+        _device: &HttpDeviceInfo,
+        _segment: u32,
+        _percent: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        match *self {}
+    }
+}
+
+#[derive(Clone)]
+pub enum GoveeUndocumentedApi {}
+
+impl GoveeUndocumentedApi {
+    pub async fn get_scenes_for_device(_sku: &str) -> anyhow::Result<Vec<LightEffectCategory>> {
+        anyhow::bail!("Scene catalogs are unavailable: {LOCAL_ONLY}")
+    }
+
+    pub async fn get_scene_ids_with_icons(_sku: &str) -> anyhow::Result<Vec<(String, u32)>> {
+        anyhow::bail!("Scene icons are unavailable: {LOCAL_ONLY}")
+    }
+
+    pub async fn get_scene_icon(_sku: &str, _scene_id: u32) -> anyhow::Result<SceneIcon> {
+        anyhow::bail!("Scene icons are unavailable: {LOCAL_ONLY}")
+    }
+
+    pub fn has_saved_login(&self) -> bool {
+        match *self {}
+    }
+
+    pub async fn login_account_cached(&self) -> anyhow::Result<LoginAccountResponse> {
+        match *self {}
+    }
+
+    pub async fn get_iot_key(&self, _token: &str) -> anyhow::Result<IotKey> {
+        match *self {}
+    }
+
+    pub fn invalidate_iot_key(&self) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn get_device_list(&self, _token: &str) -> anyhow::Result<DevicesResponse> {
+        match *self {}
+    }
+
+    pub async fn get_diy_effects_for_account(&self) -> anyhow::Result<Vec<DiyEffect>> {
+        match *self {}
+    }
+
+    pub async fn set_device_settings(
+        &self,
+        _token: &str,
+        _sku: &str,
+        _device: &str,
+        _settings: JsonValue,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn check_firmware_update(
+        &self,
+        _token: &str,
+        _entry: &DeviceEntry,
+    ) -> anyhow::Result<Option<FirmwareUpdate>> {
+        match *self {}
+    }
+
+    pub async fn parse_feasts(&self) -> anyhow::Result<Vec<ParsedFeast>> {
+        match *self {}
+    }
+
+    pub async fn parse_one_clicks(&self) -> anyhow::Result<Vec<ParsedOneClick>> {
+        match *self {}
+    }
+}
+
+#[derive(Clone)]
+pub enum IotClient {}
+
+impl IotClient {
+    pub fn is_device_compatible(&self, _device: &DeviceEntry) -> bool {
+        match *self {}
+    }
+
+    pub async fn request_status_update(&self, _device: &DeviceEntry) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn set_power_state(&self, _device: &DeviceEntry, _on: bool) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn set_brightness(&self, _device: &DeviceEntry, _percent: u8) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn set_color_temperature(
+        &self,
+        _device: &DeviceEntry,
+        _kelvin: u32,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn set_color_rgb(
+        &self,
+        _device: &DeviceEntry,
+        _r: u8,
+        _g: u8,
+        _b: u8,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn send_real(
+        &self,
+        _device: &DeviceEntry,
+        _commands: Vec<String>,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub async fn activate_one_click(&self, _item: &ParsedOneClick) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
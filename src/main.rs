@@ -1,8 +1,12 @@
+// A local only build compiles out the cloud clients, leaving behind the
+// parts of the cache and state that exist only to serve them
+#![cfg_attr(not(feature = "cloud"), allow(dead_code, unused_imports))]
 use crate::lan_api::LanDiscoArguments;
 use crate::platform_api::GoveeApiArguments;
 use crate::service::hass::HassArguments;
 use crate::undoc_api::UndocApiArguments;
 use clap::Parser;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 mod ble;
@@ -10,9 +14,14 @@ mod cache;
 mod commands;
 mod hass_mqtt;
 mod lan_api;
+#[cfg(not(feature = "cloud"))]
+mod local_only;
 #[macro_use]
 mod platform_api;
+#[cfg(feature = "cloud")]
 mod rest_api;
+mod scene_param;
+mod schema_drift;
 mod service;
 mod temperature;
 mod undoc_api;
@@ -30,30 +39,79 @@ pub struct Args {
     #[command(flatten)]
     hass_args: HassArguments,
 
+    /// A YAML file holding options, using the same names as the
+    /// options of the Home Assistant addon, eg: `mqtt_host`.
+    /// Options passed on the command line or via the environment
+    /// take precedence over those in the file.
+    /// You may also set this via the GOVEE_CONFIG environment variable.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
 
 #[derive(clap::Parser, Debug)]
 pub enum SubCommand {
+    Config(commands::config::ConfigCommand),
+    ExportDevices(commands::export_devices::ExportDevicesCommand),
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
+    LanProbe(commands::lan_probe::LanProbeCommand),
+    #[cfg(feature = "cloud")]
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
+    #[cfg(feature = "cloud")]
+    Login(commands::login::LoginCommand),
+    MigrateFromCloudIntegration(commands::migrate::MigrateFromCloudIntegrationCommand),
+    #[cfg(feature = "cloud")]
+    Quirks(commands::quirks::QuirksCommand),
+    SceneSearch(commands::scene_search::SceneSearchCommand),
+    #[cfg(feature = "cloud")]
     HttpControl(commands::http_control::HttpControlCommand),
-    Serve(commands::serve::ServeCommand),
+    #[cfg(feature = "cloud")]
+    SchemaDrift(commands::schema_drift::SchemaDriftCommand),
+    Serve(Box<commands::serve::ServeCommand>),
+    SupportMatrix(commands::support_matrix::SupportMatrixCommand),
+    #[cfg(feature = "tui")]
+    Tui(commands::tui::TuiCommand),
+    #[cfg(feature = "cloud")]
     Undoc(commands::undoc::UndocCommand),
 }
 
 impl Args {
+    pub fn config_file(&self) -> anyhow::Result<Option<PathBuf>> {
+        match &self.config {
+            Some(path) => Ok(Some(path.clone())),
+            None => opt_env_var("GOVEE_CONFIG"),
+        }
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         match &self.cmd {
+            SubCommand::Config(cmd) => cmd.run(self).await,
+            SubCommand::ExportDevices(cmd) => cmd.run(self).await,
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
+            SubCommand::LanProbe(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
+            SubCommand::Login(cmd) => cmd.run(self).await,
+            SubCommand::MigrateFromCloudIntegration(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
+            SubCommand::Quirks(cmd) => cmd.run(self).await,
+            SubCommand::SceneSearch(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
+            SubCommand::SchemaDrift(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
+            SubCommand::SupportMatrix(cmd) => cmd.run(self).await,
+            #[cfg(feature = "tui")]
+            SubCommand::Tui(cmd) => cmd.run(self).await,
+            #[cfg(feature = "cloud")]
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
     }
@@ -110,15 +168,28 @@ fn setup_logger() {
         .init();
 }
 
-#[tokio::main(worker_threads = 2)]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     color_backtrace::install();
     if let Ok(path) = dotenvy::dotenv() {
         eprintln!("Loading environment overrides from {path:?}");
     }
 
     setup_logger();
+    govee_models::redacted::set_log_sensitive_data(undoc_api::should_log_sensitive_data());
 
     let args = Args::parse();
-    args.run().await
+    if let Some(path) = args.config_file()? {
+        log::info!("Loading options from {}", path.display());
+        service::config_file::apply_config_file(&path)?;
+        govee_models::redacted::set_log_sensitive_data(undoc_api::should_log_sensitive_data());
+    }
+
+    // The config file is applied to the environment, which is only
+    // safe while this is the only thread, so the runtime is started
+    // after that rather than via #[tokio::main]
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?
+        .block_on(args.run())
 }
@@ -1,14 +1,31 @@
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+#[cfg(feature = "cloud")]
+use crate::cache::{account_key, cache_get, CacheComputeResult, CacheGetOptions};
 use crate::hass_mqtt::climate::parse_temperature_constraints;
+#[cfg(not(feature = "cloud"))]
+pub use crate::local_only::GoveeApiClient;
 use crate::opt_env_var;
+use crate::service::brightness::platform_brightness_scale;
+#[cfg(feature = "cloud")]
+use crate::service::cloud_health;
+#[cfg(feature = "cloud")]
+use crate::service::command_audit::PendingCommand;
+use crate::service::command_queue::pace_command;
+use crate::service::device::normalize_device_id;
+#[cfg(feature = "cloud")]
+use crate::service::stall_detector::track_operation;
 use crate::service::state::sort_and_dedup_scenes;
+use crate::service::state_merge::Transport;
 use crate::temperature::{TemperatureUnits, TemperatureValue};
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
+pub use govee_models::platform::{
+    DeviceCapability, DeviceCapabilityKind, DeviceCapabilityState, DeviceParameters, DeviceType,
+    EnumOption, HttpDeviceInfo, HttpDeviceState, IntegerRange,
+};
+#[cfg(feature = "cloud")]
 use reqwest::Method;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::collections::HashMap;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -19,10 +36,12 @@ use thiserror::Error;
 // with a higher number, Govee HTTP API v2 that is described at
 // <https://govee.readme.io/reference/getlightdeviceinfo>
 
+#[cfg(feature = "cloud")]
 const SERVER: &str = "https://openapi.api.govee.com";
 pub const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
 pub const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
 
+#[cfg(feature = "cloud")]
 fn endpoint(url: &str) -> String {
     format!("{SERVER}{url}")
 }
@@ -52,31 +71,41 @@ impl GoveeApiArguments {
         })
     }
 
+    #[cfg(feature = "cloud")]
     pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
         let key = self.api_key()?;
         Ok(GoveeApiClient::new(key))
     }
+
+    #[cfg(not(feature = "cloud"))]
+    pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
+        anyhow::bail!(crate::local_only::LOCAL_ONLY)
+    }
 }
 
+#[cfg(feature = "cloud")]
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
 }
 
+#[cfg(feature = "cloud")]
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
         Self { key: key.into() }
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+        let key = account_key(&self.key, "device-list");
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
-                key: "device-list",
+                key: &key,
                 soft_ttl: Duration::from_secs(900),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
+                defer_to_maintenance: true,
             },
             async {
                 let url = endpoint("/router/api/v1/user/devices");
@@ -87,11 +116,18 @@ impl GoveeApiClient {
         .await
     }
 
+    /// Forget the cached device list, so that the next call
+    /// to get_devices will fetch a fresh one
+    pub fn invalidate_device_list(&self) -> anyhow::Result<()> {
+        crate::cache::invalidate_key("http-api", &account_key(&self.key, "device-list"))
+    }
+
     pub async fn get_device_by_id<I: AsRef<str>>(&self, id: I) -> anyhow::Result<HttpDeviceInfo> {
         let id = id.as_ref();
+        let normalized = normalize_device_id(id);
         let devices = self.get_devices().await?;
         for d in devices {
-            if d.device == id {
+            if normalize_device_id(&d.device) == normalized {
                 return Ok(d);
             }
         }
@@ -104,6 +140,29 @@ impl GoveeApiClient {
         capability: &DeviceCapability,
         value: V,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let value = value.into();
+        let audit = PendingCommand::start(
+            Transport::Platform,
+            &device.device,
+            &device.sku,
+            serde_json::json!({
+                "type": capability.kind,
+                "instance": capability.instance,
+                "value": value,
+            }),
+        );
+        let result = self.send_control(device, capability, value).await;
+        audit.finish(&result);
+        result
+    }
+
+    async fn send_control(
+        &self,
+        device: &HttpDeviceInfo,
+        capability: &DeviceCapability,
+        value: JsonValue,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        pace_command(Transport::Platform, &device.device).await;
         let url = endpoint("/router/api/v1/device/control");
         let request = ControlDeviceRequest {
             request_id: "uuid".to_string(),
@@ -113,7 +172,7 @@ impl GoveeApiClient {
                 capability: ControlDeviceCapability {
                     kind: capability.kind.clone(),
                     instance: capability.instance.to_string(),
-                    value: value.into(),
+                    value,
                 },
             },
         };
@@ -155,7 +214,10 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-diy-{}-{}", device.sku, device.device);
+        let key = account_key(
+            &self.key,
+            &format!("scene-list-diy-{}-{}", device.sku, device.device),
+        );
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
@@ -164,6 +226,7 @@ impl GoveeApiClient {
                 hard_ttl: ONE_WEEK,
                 negative_ttl: FIVE_MINUTES,
                 allow_stale: true,
+                defer_to_maintenance: true,
             },
             async {
                 let url = endpoint("/router/api/v1/device/diy-scenes");
@@ -193,7 +256,10 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-{}-{}", device.sku, device.device);
+        let key = account_key(
+            &self.key,
+            &format!("scene-list-{}-{}", device.sku, device.device),
+        );
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
@@ -202,6 +268,7 @@ impl GoveeApiClient {
                 hard_ttl: ONE_WEEK,
                 negative_ttl: FIVE_MINUTES,
                 allow_stale: true,
+                defer_to_maintenance: true,
             },
             async {
                 let url = endpoint("/router/api/v1/device/scenes");
@@ -229,8 +296,8 @@ impl GoveeApiClient {
     ) -> anyhow::Result<Vec<DeviceCapability>> {
         let mut result = vec![];
 
-        let scene_caps = self.get_device_scenes(&device).await?;
-        let diy_caps = self.get_device_diy_scenes(&device).await?;
+        let scene_caps = self.get_device_scenes(device).await?;
+        let diy_caps = self.get_device_diy_scenes(device).await?;
         let undoc_caps =
             match GoveeUndocumentedApi::synthesize_platform_api_scene_list(&device.sku).await {
                 Ok(caps) => caps,
@@ -303,13 +370,10 @@ impl GoveeApiClient {
             if let Some(DeviceParameters::Struct { fields }) = &cap.parameters {
                 for f in fields {
                     if f.field_name == "musicMode" {
-                        match &f.field_type {
-                            DeviceParameters::Enum { options } => {
-                                for opt in options {
-                                    result.push(format!("Music: {}", opt.name));
-                                }
+                        if let DeviceParameters::Enum { options } = &f.field_type {
+                            for opt in options {
+                                result.push(format!("Music: {}", opt.name));
                             }
-                            _ => {}
                         }
                     }
                 }
@@ -323,12 +387,23 @@ impl GoveeApiClient {
         Ok(sort_and_dedup_scenes(result))
     }
 
+    /// Returns the name of the scene whose value matches the value
+    /// of the lightScene state reported for the device
+    pub async fn scene_name_for_value(
+        &self,
+        device: &HttpDeviceInfo,
+        value: &JsonValue,
+    ) -> anyhow::Result<Option<String>> {
+        let caps = self.get_scene_caps(device).await?;
+        Ok(scene_name_for_value(&caps, value))
+    }
+
     pub async fn set_scene_by_name(
         &self,
         device: &HttpDeviceInfo,
         scene: &str,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
-        if scene == "" {
+        if scene.is_empty() {
             // Can't set no scene
             anyhow::bail!("Cannot set scene to no-scene");
         }
@@ -342,7 +417,7 @@ impl GoveeApiClient {
                             "sensitivity": 100,
                             "autoColor": 1,
                         });
-                        return self.control_device(&device, &cap, value).await;
+                        return self.control_device(device, cap, value).await;
                     }
                 }
             }
@@ -354,7 +429,7 @@ impl GoveeApiClient {
                 Some(DeviceParameters::Enum { options }) => {
                     for opt in options {
                         if scene.eq_ignore_ascii_case(&opt.name) {
-                            return self.control_device(&device, &cap, opt.value.clone()).await;
+                            return self.control_device(device, &cap, opt.value.clone()).await;
                         }
                     }
                 }
@@ -392,7 +467,7 @@ impl GoveeApiClient {
             "unit": "Celsius",
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_work_mode(
@@ -410,7 +485,7 @@ impl GoveeApiClient {
             "modeValue": value
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_toggle_state(
@@ -427,7 +502,27 @@ impl GoveeApiClient {
             .enum_parameter_by_name(if on { "on" } else { "off" })
             .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?;
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
+    }
+
+    /// Set an integer capability, such as the sensitivity
+    /// of a presence sensor, clamped to its range
+    pub async fn set_range(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+        value: i64,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(instance)
+            .ok_or_else(|| anyhow::anyhow!("device has no {instance}"))?;
+
+        let value = match cap.integer_range() {
+            Some(range) => value.clamp(range.min as i64, range.max as i64),
+            None => value,
+        };
+
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_power_state(
@@ -446,14 +541,10 @@ impl GoveeApiClient {
         let cap = device
             .capability_by_instance("brightness")
             .ok_or_else(|| anyhow::anyhow!("device has no brightness"))?;
-        let value = match &cap.parameters {
-            Some(DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            }) => (percent as u32).max(*min).min(*max),
-            _ => anyhow::bail!("unexpected parameter type for brightness"),
-        };
-        self.control_device(&device, &cap, value).await
+        let scale = platform_brightness_scale(device)
+            .ok_or_else(|| anyhow::anyhow!("unexpected parameter type for brightness"))?;
+        let value = scale.to_device(percent).max(scale.min);
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_temperature(
@@ -471,7 +562,7 @@ impl GoveeApiClient {
             }) => (kelvin).max(*min).min(*max),
             _ => anyhow::bail!("unexpected parameter type for colorTemperatureK"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_rgb(
@@ -485,7 +576,7 @@ impl GoveeApiClient {
             .capability_by_instance("colorRgb")
             .ok_or_else(|| anyhow::anyhow!("device has no colorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_segment_rgb(
@@ -501,8 +592,8 @@ impl GoveeApiClient {
             .ok_or_else(|| anyhow::anyhow!("device has no segmentedColorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
                 "segment": vec![segment],
                 "rgb": value,
@@ -528,8 +619,8 @@ impl GoveeApiClient {
         let value = (percent as u32).max(min).min(max);
 
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
                 "segment": vec![segment],
                 "brightness": value,
@@ -540,7 +631,6 @@ impl GoveeApiClient {
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDeviceScenesResponse {
     #[serde(rename = "requestId")]
     pub request_id: String,
@@ -551,7 +641,6 @@ struct GetDeviceScenesResponse {
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDeviceScenesResponsePayload {
     pub sku: String,
     pub device: String,
@@ -629,7 +718,6 @@ struct GetDeviceStateRequestPayload {
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDeviceStateResponse {
     #[serde(rename = "requestId")]
     pub request_id: String,
@@ -639,349 +727,161 @@ struct GetDeviceStateResponse {
     pub payload: HttpDeviceState,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct HttpDeviceState {
-    pub sku: String,
-    pub device: String,
-    pub capabilities: Vec<DeviceCapabilityState>,
-}
-
-impl HttpDeviceState {
-    pub fn capability_by_instance(&self, instance: &str) -> Option<&DeviceCapabilityState> {
-        self.capabilities
-            .iter()
-            .find(|c| c.instance.eq_ignore_ascii_case(instance))
-    }
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceCapabilityState {
-    #[serde(rename = "type")]
-    pub kind: DeviceCapabilityKind,
-    pub instance: String,
-    pub state: JsonValue,
-}
-
 #[derive(Deserialize, Serialize, Debug)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDevicesResponse {
     pub code: u32,
     pub message: String,
     pub data: Vec<HttpDeviceInfo>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct HttpDeviceInfo {
-    pub sku: String,
-    pub device: String,
-    #[serde(default, rename = "deviceName")]
-    pub device_name: String,
-    #[serde(default, rename = "type")]
-    pub device_type: DeviceType,
-    pub capabilities: Vec<DeviceCapability>,
-}
-
-impl HttpDeviceInfo {
-    pub fn capability_by_instance(&self, instance: &str) -> Option<&DeviceCapability> {
-        self.capabilities
-            .iter()
-            .find(|c| c.instance.eq_ignore_ascii_case(instance))
+/// Parse json into T, recording any schema drift that was observed
+/// along the way. Unknown fields are tolerated.
+/// Find the scene option whose value matches `value`. Scene values
+/// are structured, such as `{"id": 1606, "paramId": 1681}`, or are
+/// a number, so they are compared as a whole.
+pub fn scene_name_for_value(caps: &[DeviceCapability], value: &JsonValue) -> Option<String> {
+    if value.is_null() {
+        return None;
     }
-
-    pub fn supports_rgb(&self) -> bool {
-        self.capability_by_instance("colorRgb").is_some()
-    }
-
-    pub fn supports_brightness(&self) -> bool {
-        self.capability_by_instance("brightness").is_some()
-    }
-
-    pub fn supports_dynamic_scenes(&self) -> bool {
-        self.capabilities
-            .iter()
-            .any(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
-    }
-
-    /// If supported, returns the number of segments
-    pub fn supports_segmented_rgb(&self) -> Option<std::ops::Range<u32>> {
-        let cap = self.capability_by_instance("segmentedColorRgb")?;
-        let field = cap.struct_field_by_name("segment")?;
-        match field.field_type {
-            DeviceParameters::Array {
-                size:
-                    Some(ArraySize {
-                        // These are the display indices. eg: 1-based
-                        min: label_min,
-                        max: label_max,
-                    }),
-                element_range:
-                    Some(ElementRange {
-                        // These are the actual indices. eg: 0-based
-                        min: range_min,
-                        // We ignore the max here, because the data
-                        // reported by Govee can be bogus:
-                        // <https://developer.govee.com/discuss/6599afb91cb48d002dbed2b8>
-                        max: _,
-                    }),
-                ..
-            } => {
-                // This range is an inclusive range, so add 1
-                let num_segments = (1 + label_max).saturating_sub(label_min);
-                // Return our exclusive range
-                Some(range_min..range_min + num_segments)
-            }
+    caps.iter()
+        .filter_map(|cap| match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => Some(options),
             _ => None,
-        }
-    }
+        })
+        .flatten()
+        .find(|opt| opt.value == *value)
+        .map(|opt| opt.name.to_string())
+}
 
-    pub fn supports_segmented_brightness(&self) -> Option<(u32, u32)> {
-        let cap = self.capability_by_instance("segmentedBrightness")?;
-        let field = cap.struct_field_by_name("brightness")?;
-        match &field.field_type {
-            DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            } => Some((*min, *max)),
-            _ => None,
+pub fn from_json<T: serde::de::DeserializeOwned, S: AsRef<[u8]>>(text: S) -> anyhow::Result<T> {
+    let text = text.as_ref();
+    let type_name = std::any::type_name::<T>();
+
+    let mut unknown = vec![];
+    let mut deserializer = serde_json::Deserializer::from_slice(text);
+    let result =
+        serde_ignored::deserialize(&mut deserializer, |path| unknown.push(path.to_string()))
+            .and_then(|value| deserializer.end().map(|()| value));
+
+    match result {
+        Ok(value) => {
+            for path in unknown {
+                crate::schema_drift::record_unknown_field(type_name, &path);
+            }
+            Ok(value)
         }
-    }
-
-    pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
-        let cap = self.capability_by_instance("colorTemperatureK")?;
-
-        match cap.parameters {
-            Some(DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            }) => Some((min, max)),
-            _ => None,
+        Err(_) => {
+            // Parse again to produce an error that includes the
+            // path to the problematic field
+            let err = match serde_json_path_to_error::from_slice::<T>(text) {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            crate::schema_drift::record_parse_error(type_name, &err.to_string());
+            Err(anyhow::anyhow!(
+                "{type_name} {err}. Input: {}",
+                String::from_utf8_lossy(text)
+            ))
         }
     }
 }
 
-/// Helper to generate boilerplate around govee enum string types
-macro_rules! enum_string {
-    {pub enum $name:ident {
-     $($var:ident = $label:literal),* $(,)?
-     }
-    } => {
-
-#[derive(Debug, Clone, PartialEq, Eq, strum_macros::Display, strum_macros::EnumString)]
-pub enum $name {
-    $(
-        #[strum(serialize = $label)]
-        $var,
-    )*
-        Other(String),
-}
-
-impl Default for $name {
-    fn default() -> Self {
-        Self::Other("NONE".to_string())
-    }
-}
-
-impl<'de> Deserialize<'de> for $name {
-    fn deserialize<D>(d: D) -> Result<Self, <D as Deserializer<'de>>::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(d)?;
-
-        if let Ok(t) = s.parse::<Self>() {
-            Ok(t)
-        } else {
-            Ok(Self::Other(s))
+/// As from_json, but parses the json as it is read from `reader`,
+/// so that the whole of the text never needs to be held in memory.
+/// The text isn't available to include in an error, which is only
+/// able to describe where parsing failed.
+pub fn from_json_reader<T: serde::de::DeserializeOwned, R: std::io::Read>(
+    reader: R,
+) -> anyhow::Result<T> {
+    let type_name = std::any::type_name::<T>();
+
+    let mut unknown = vec![];
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let result =
+        serde_ignored::deserialize(&mut deserializer, |path| unknown.push(path.to_string()))
+            .and_then(|value| deserializer.end().map(|()| value));
+
+    match result {
+        Ok(value) => {
+            for path in unknown {
+                crate::schema_drift::record_unknown_field(type_name, &path);
+            }
+            Ok(value)
         }
-    }
-}
-
-impl Serialize for $name {
-    fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
-    where
-        S: Serializer,
-    {
-        match self {
-            Self::Other(s) => s.serialize(serializer),
-            _ => self.to_string().serialize(serializer),
+        Err(err) => {
+            crate::schema_drift::record_parse_error(type_name, &err.to_string());
+            Err(anyhow::anyhow!("{type_name} {err}"))
         }
     }
 }
 
-    }
-}
-
-enum_string! {
-pub enum DeviceType {
-    Light = "devices.types.light",
-    AirPurifier = "devices.types.air_purifier",
-    Thermometer = "devices.types.thermometer",
-    Socket = "devices.types.socket",
-    Sensor = "devices.types.sensor",
-    Heater = "devices.types.heater",
-    Humidifier = "devices.types.humidifier",
-    Dehumidifier = "devices.types.dehumidifier",
-    IceMaker = "devices.types.ice_maker",
-    AromaDiffuser = "devices.types.aroma_diffuser",
-    Fan = "devices.types.fan",
-    Kettle = "devices.types.kettle",
+/// Presents the chunks of a response body, as they are received,
+/// as a blocking reader
+#[cfg(feature = "cloud")]
+struct ChunkReader {
+    rx: tokio::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
 }
-}
-
-enum_string! {
-pub enum DeviceCapabilityKind {
-    OnOff = "devices.capabilities.on_off",
-    Toggle = "devices.capabilities.toggle",
-    Range = "devices.capabilities.range",
-    Mode = "devices.capabilities.mode",
-    ColorSetting = "devices.capabilities.color_setting",
-    SegmentColorSetting = "devices.capabilities.segment_color_setting",
-    MusicSetting = "devices.capabilities.music_setting",
-    DynamicScene = "devices.capabilities.dynamic_scene",
-    WorkMode = "devices.capabilities.work_mode",
-    DynamicSetting = "devices.capabilities.dynamic_setting",
-    TemperatureSetting = "devices.capabilities.temperature_setting",
-    Online = "devices.capabilities.online",
-    Property = "devices.capabilities.property",
-    Event = "devices.capabilities.event",
-}
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceCapability {
-    #[serde(rename = "type")]
-    pub kind: DeviceCapabilityKind,
-    pub instance: String,
-    pub parameters: Option<DeviceParameters>,
-    #[serde(rename = "alarmType")]
-    pub alarm_type: Option<u32>,
-    #[serde(rename = "eventState")]
-    pub event_state: Option<JsonValue>,
-}
-
-impl DeviceCapability {
-    pub fn enum_parameter_by_name(&self, name: &str) -> Option<u32> {
-        self.parameters
-            .as_ref()
-            .and_then(|p| p.enum_parameter_by_name(name))
-    }
 
-    pub fn struct_field_by_name(&self, name: &str) -> Option<&StructField> {
-        match &self.parameters {
-            Some(DeviceParameters::Struct { fields }) => {
-                fields.iter().find(|f| f.field_name == name)
+#[cfg(feature = "cloud")]
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
             }
-            _ => None,
         }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(tag = "dataType")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub enum DeviceParameters {
-    #[serde(rename = "ENUM")]
-    Enum { options: Vec<EnumOption> },
-    #[serde(rename = "INTEGER")]
-    Integer {
-        unit: Option<String>,
-        range: IntegerRange,
-    },
-    #[serde(rename = "STRUCT")]
-    Struct { fields: Vec<StructField> },
-    #[serde(rename = "Array")]
-    Array {
-        size: Option<ArraySize>,
-        #[serde(rename = "elementRange")]
-        element_range: Option<ElementRange>,
-        #[serde(rename = "elementType")]
-        element_type: Option<String>,
-        #[serde(default)]
-        options: Vec<ArrayOption>,
-    },
-}
+/// Parse the json body of a potentially very large response while it
+/// is being received, rather than buffering all of it first. The chunks
+/// are handed to a blocking task, which parses them as they arrive.
+#[cfg(feature = "cloud")]
+pub async fn stream_json_body<T: serde::de::DeserializeOwned + Send + 'static>(
+    mut response: reqwest::Response,
+) -> anyhow::Result<T> {
+    let url = response.url().clone();
+    if !response.status().is_success() {
+        return http_response_body(response).await;
+    }
 
-impl DeviceParameters {
-    pub fn enum_parameter_by_name(&self, name: &str) -> Option<u32> {
-        match self {
-            DeviceParameters::Enum { options } => options
-                .iter()
-                .find(|e| e.name == name && e.value.is_i64())
-                .map(|e| e.value.as_i64().expect("i64") as u32),
-            _ => None,
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let parser = tokio::task::spawn_blocking(move || {
+        from_json_reader::<T, _>(std::io::BufReader::new(ChunkReader {
+            rx,
+            chunk: vec![],
+            pos: 0,
+        }))
+    });
+
+    while let Some(chunk) = response.chunk().await.transpose() {
+        let chunk = chunk
+            .map(|bytes| bytes.to_vec())
+            .map_err(std::io::Error::other);
+        let failed = chunk.is_err();
+        // An error sending means that the parser has already given up
+        if tx.send(chunk).await.is_err() || failed {
+            break;
         }
     }
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-// No deny_unknown_fields here, because we embed via flatten
-pub struct StructField {
-    #[serde(rename = "fieldName")]
-    pub field_name: String,
-
-    #[serde(flatten)]
-    pub field_type: DeviceParameters,
-
-    #[serde(rename = "defaultValue")]
-    pub default_value: Option<JsonValue>,
+    drop(tx);
 
-    #[serde(default)]
-    pub required: bool,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct ElementRange {
-    pub min: u32,
-    pub max: u32,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct ArraySize {
-    pub min: u32,
-    pub max: u32,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct IntegerRange {
-    pub min: u32,
-    pub max: u32,
-    pub precision: u32,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct EnumOption {
-    pub name: String,
-    #[serde(default)]
-    pub value: JsonValue,
-    #[serde(flatten)]
-    pub extras: HashMap<String, JsonValue>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct ArrayOption {
-    pub value: u32,
-}
-
-pub fn from_json<T: serde::de::DeserializeOwned, S: AsRef<[u8]>>(text: S) -> anyhow::Result<T> {
-    let text = text.as_ref();
-    serde_json_path_to_error::from_slice(text).map_err(|err| {
-        anyhow::anyhow!(
-            "{} {err}. Input: {}",
-            std::any::type_name::<T>(),
-            String::from_utf8_lossy(text)
-        )
-    })
+    parser
+        .await?
+        .with_context(|| format!("parsing {url} response"))
 }
 
+#[cfg(feature = "cloud")]
 #[derive(Deserialize, Debug)]
 struct EmbeddedRequestStatus {
     #[serde(alias = "msg")]
@@ -990,6 +890,7 @@ struct EmbeddedRequestStatus {
     status: u16,
 }
 
+#[cfg(feature = "cloud")]
 #[derive(Error, Debug)]
 #[error("Failed with status {status} {}: {content}", .status.canonical_reason().unwrap_or(""))]
 pub struct HttpRequestFailed {
@@ -997,6 +898,7 @@ pub struct HttpRequestFailed {
     content: String,
 }
 
+#[cfg(feature = "cloud")]
 impl HttpRequestFailed {
     #[allow(unused)]
     pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
@@ -1004,16 +906,20 @@ impl HttpRequestFailed {
     }
 }
 
+#[cfg(feature = "cloud")]
 pub async fn json_body<T: serde::de::DeserializeOwned>(
     response: reqwest::Response,
 ) -> anyhow::Result<T> {
     let url = response.url().clone();
+    let status = response.status();
     let data = response
         .bytes()
         .await
         .with_context(|| format!("read {url} response body"))?;
 
-    if let Ok(status) = from_json::<EmbeddedRequestStatus, _>(&data) {
+    // Not using from_json here, as this is only a probe for an error
+    // status and we don't want to record every other field as drift
+    if let Ok(status) = serde_json::from_slice::<EmbeddedRequestStatus>(&data) {
         if status.status != reqwest::StatusCode::OK.as_u16() {
             if let Ok(code) = reqwest::StatusCode::from_u16(status.status) {
                 return Err(HttpRequestFailed {
@@ -1036,9 +942,285 @@ pub async fn json_body<T: serde::de::DeserializeOwned>(
         }
     }
 
-    from_json(&data).with_context(|| format!("parsing {url} response"))
+    from_json(&data).with_context(|| {
+        format!(
+            "parsing {url} response{}",
+            describe_capture(&url, status, &data)
+        )
+    })
+}
+
+/// Responses that are larger than this are truncated when captured
+#[cfg(feature = "cloud")]
+const MAX_CAPTURE_SIZE: usize = 64 * 1024;
+/// How many captured responses to keep
+#[cfg(feature = "cloud")]
+const MAX_CAPTURES: usize = 20;
+/// The responses of these endpoints hold credentials, such as tokens
+/// and the IoT certificate, so they are never captured
+#[cfg(feature = "cloud")]
+const UNCAPTURED_ENDPOINTS: &[&str] = &["/login", "/iot/key", "/refresh-tokens"];
+/// Values of object fields whose names contain any of these
+/// are redacted from captured responses
+#[cfg(feature = "cloud")]
+const SENSITIVE_FIELDS: &[&str] = &["token", "pass", "p12", "key", "secret", "email", "topic"];
+
+/// Where the raw bodies of responses that we couldn't make sense of
+/// are saved, so that they can be attached to bug reports
+#[cfg(feature = "cloud")]
+pub fn capture_dir() -> std::path::PathBuf {
+    crate::cache::cache_dir().join("govee2mqtt-http-captures")
+}
+
+/// Save the raw body of a response into `dir`, removing the oldest
+/// captures so that no more than MAX_CAPTURES are kept
+#[cfg(feature = "cloud")]
+fn save_capture(
+    dir: &std::path::Path,
+    url: &reqwest::Url,
+    status: reqwest::StatusCode,
+    data: &[u8],
+) -> anyhow::Result<std::path::PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let endpoint: String = format!("{}{}", url.host_str().unwrap_or(""), url.path())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = dir.join(format!(
+        "{}-{endpoint}.txt",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f")
+    ));
+
+    let mut content = format!(
+        "{url}\nstatus {status}\n{len} bytes{truncated}\n\n",
+        len = data.len(),
+        truncated = if data.len() > MAX_CAPTURE_SIZE {
+            ", truncated"
+        } else {
+            ""
+        }
+    )
+    .into_bytes();
+    content.extend_from_slice(&data[..data.len().min(MAX_CAPTURE_SIZE)]);
+    crate::cache::write_private_file(&path, &content)?;
+
+    let mut captures: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|p| p.extension().map(|ext| ext == "txt").unwrap_or(false))
+        .collect();
+    // The names begin with the time of the capture
+    captures.sort();
+    let excess = captures.len().saturating_sub(MAX_CAPTURES);
+    for old in &captures[..excess] {
+        std::fs::remove_file(old).ok();
+    }
+
+    Ok(path)
 }
 
+/// Replace the values of sensitive fields in a JSON response body,
+/// unless the user has opted in to logging sensitive data
+#[cfg(feature = "cloud")]
+fn redact_capture(data: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    fn redact(value: &mut JsonValue) {
+        match value {
+            JsonValue::Object(map) => {
+                for (name, value) in map.iter_mut() {
+                    let name = name.to_ascii_lowercase();
+                    if !value.is_object()
+                        && !value.is_array()
+                        && SENSITIVE_FIELDS.iter().any(|s| name.contains(s))
+                    {
+                        *value = JsonValue::String("REDACTED".to_string());
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            JsonValue::Array(items) => items.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+
+    if crate::undoc_api::should_log_sensitive_data() {
+        return data.into();
+    }
+    match serde_json::from_slice::<JsonValue>(data) {
+        Ok(mut value) => {
+            redact(&mut value);
+            serde_json::to_vec(&value).unwrap_or_default().into()
+        }
+        // Not JSON, most likely an HTML error page
+        Err(_) => data.into(),
+    }
+}
+
+/// Capture the raw body of a response, returning a note to include
+/// in the resulting error that refers to the capture
+#[cfg(feature = "cloud")]
+fn describe_capture(url: &reqwest::Url, status: reqwest::StatusCode, data: &[u8]) -> String {
+    if UNCAPTURED_ENDPOINTS
+        .iter()
+        .any(|endpoint| url.path().ends_with(endpoint))
+    {
+        return String::new();
+    }
+    match save_capture(&capture_dir(), url, status, &redact_capture(data)) {
+        Ok(path) => format!(
+            ". The response body was saved to {}; please include it in bug reports",
+            path.display()
+        ),
+        Err(err) => {
+            log::warn!("Failed to capture the {url} response body: {err:#}");
+            String::new()
+        }
+    }
+}
+
+/// How long to wait for a connection to be established, separately
+/// from the overall timeout, so that unreachable hosts fail fast
+#[cfg(feature = "cloud")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build a client whose requests time out after `timeout`.
+/// The timeout covers the whole request, including resolving
+/// the name of the host.
+#[cfg(feature = "cloud")]
+pub fn http_client(timeout: Duration) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+        .build()
+}
+
+/// How many times to retry a request that failed with a server error,
+/// a timeout or a connection error
+#[cfg(feature = "cloud")]
+const SERVER_ERROR_RETRIES: u32 = 2;
+
+#[cfg(feature = "cloud")]
+#[async_trait::async_trait]
+pub trait SendWithRetry {
+    /// Send the request, retrying with jittered backoff if it fails
+    /// in a way that is likely to be transient. Requests to an endpoint
+    /// whose circuit breaker is open fail fast with
+    /// `cloud_health::CloudUnavailable`.
+    async fn send_with_retry(self) -> anyhow::Result<reqwest::Response>;
+}
+
+#[cfg(feature = "cloud")]
+#[async_trait::async_trait]
+impl SendWithRetry for reqwest::RequestBuilder {
+    async fn send_with_retry(self) -> anyhow::Result<reqwest::Response> {
+        let built = self.try_clone().and_then(|r| r.build().ok());
+        let endpoint = built.as_ref().and_then(|r| {
+            let host = r.url().host_str()?;
+            Some(cloud_health::endpoint_for(host, r.url().path()))
+        });
+        let operation = match &built {
+            Some(r) => format!(
+                "{} {}{}",
+                r.method(),
+                r.url().host_str().unwrap_or(""),
+                r.url().path()
+            ),
+            None => "http request".to_string(),
+        };
+        let _operation = track_operation(operation);
+        if let Some(endpoint) = &endpoint {
+            cloud_health::check_endpoint(endpoint)?;
+        }
+        // 429 cooldowns apply to the class of request: the host and path
+        let class = built.as_ref().and_then(|r| {
+            let host = r.url().host_str()?;
+            Some(format!("{host}{}", r.url().path()))
+        });
+        if let Some(class) = &class {
+            if let Some(retry_in) = cloud_health::rate_limit_cooldown(class) {
+                if retry_in > cloud_health::MAX_RATE_LIMIT_WAIT {
+                    return Err(cloud_health::rate_limited_error(class, retry_in));
+                }
+                log::info!("waiting {retry_in:?} for the rate limit of {class} to end");
+                tokio::time::sleep(retry_in).await;
+            }
+        }
+        let record_failure = |error: &str| {
+            if let Some(endpoint) = &endpoint {
+                cloud_health::record_failure(endpoint, error);
+            }
+        };
+
+        let mut request = self;
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 0;
+        let mut rate_limited = false;
+        loop {
+            // Requests with a streaming body can't be cloned, and so
+            // can't be retried
+            let next = request.try_clone();
+            let retry_in = backoff.mul_f64(rand::Rng::gen_range(&mut rand::thread_rng(), 0.8..1.2));
+            let can_retry = next.is_some() && attempt < SERVER_ERROR_RETRIES;
+            match request.send().await {
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| cloud_health::parse_retry_after(v, chrono::Utc::now()));
+                    let retry_after = match &class {
+                        Some(class) => cloud_health::record_rate_limited(class, retry_after),
+                        None => return Ok(response),
+                    };
+                    // Wait out a short cooldown once; otherwise
+                    // leave it to the caller to report the 429
+                    if rate_limited || retry_after > cloud_health::MAX_RATE_LIMIT_WAIT {
+                        return Ok(response);
+                    }
+                    let Some(next) = next else {
+                        return Ok(response);
+                    };
+                    rate_limited = true;
+                    tokio::time::sleep(retry_after).await;
+                    request = next;
+                    continue;
+                }
+                Ok(response) if response.status().is_server_error() => {
+                    let status = response.status();
+                    if !can_retry {
+                        record_failure(&format!("status {status}"));
+                        return Ok(response);
+                    }
+                    log::warn!(
+                        "request {} status {status}; retrying in {retry_in:?}",
+                        response.url()
+                    );
+                }
+                Ok(response) => {
+                    if let Some(endpoint) = &endpoint {
+                        cloud_health::record_success(endpoint);
+                    }
+                    return Ok(response);
+                }
+                Err(err) if err.is_timeout() || err.is_connect() => {
+                    if !can_retry {
+                        record_failure(&err.to_string());
+                        return Err(err.into());
+                    }
+                    log::warn!("request failed: {err}; retrying in {retry_in:?}");
+                }
+                Err(err) => return Err(err.into()),
+            }
+            tokio::time::sleep(retry_in).await;
+            backoff *= 2;
+            attempt += 1;
+            request = next.expect("checked by can_retry");
+        }
+    }
+}
+
+#[cfg(feature = "cloud")]
 pub async fn http_response_body<R: serde::de::DeserializeOwned>(
     response: reqwest::Response,
 ) -> anyhow::Result<R> {
@@ -1054,6 +1236,18 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
             )
         })?;
 
+        if serde_json::from_slice::<JsonValue>(&body_bytes).is_err() {
+            // Most likely an HTML error page, which is too noisy to
+            // include in full in the error
+            anyhow::bail!(
+                "request {url} status {}: {}. Response body: {:.200}{}",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or(""),
+                String::from_utf8_lossy(&body_bytes),
+                describe_capture(&url, status, &body_bytes)
+            );
+        }
+
         anyhow::bail!(
             "request {url} status {}: {}. Response body: {}",
             status.as_u16(),
@@ -1070,17 +1264,16 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
     })
 }
 
+#[cfg(feature = "cloud")]
 impl GoveeApiClient {
     async fn get_request_with_json_response<T: reqwest::IntoUrl, R: serde::de::DeserializeOwned>(
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let response = http_client(Duration::from_secs(60))?
             .request(Method::GET, url)
             .header("Govee-API-Key", &self.key)
-            .send()
+            .send_with_retry()
             .await?;
 
         http_response_body(response).await
@@ -1096,13 +1289,11 @@ impl GoveeApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let response = http_client(Duration::from_secs(60))?
             .request(method, url)
             .header("Govee-API-Key", &self.key)
             .json(body)
-            .send()
+            .send_with_retry()
             .await?;
 
         http_response_body(response).await
@@ -1117,15 +1308,62 @@ mod test {
 
     #[test]
     fn get_device_scenes() {
-        let resp: GetDeviceScenesResponse = from_json(&SCENE_LIST).unwrap();
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn scene_for_state_value() {
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
+        let caps = resp.payload.capabilities;
+        assert_eq!(
+            scene_name_for_value(&caps, &json!({"id": 1606, "paramId": 1681})).as_deref(),
+            Some("Sunrise")
+        );
+        // The order of the fields doesn't matter
+        assert_eq!(
+            scene_name_for_value(&caps, &json!({"paramId": 1682, "id": 1607})).as_deref(),
+            Some("Sunset")
+        );
+        assert_eq!(scene_name_for_value(&caps, &json!({"id": 1606})), None);
+        assert_eq!(scene_name_for_value(&caps, &json!("Sunrise")), None);
+        assert_eq!(scene_name_for_value(&caps, &JsonValue::Null), None);
+    }
+
+    #[cfg(feature = "cloud")]
+    #[test]
+    fn chunked_json() {
+        let text = include_str!("../test-data/undoc-one-click.json");
+        let (tx, rx) = tokio::sync::mpsc::channel(text.len());
+        // Small chunks split the text mid-token
+        for chunk in text.as_bytes().chunks(7) {
+            tx.blocking_send(Ok(chunk.to_vec())).unwrap();
+        }
+        drop(tx);
+
+        let streamed: govee_models::undoc::OneClickResponse = from_json_reader(ChunkReader {
+            rx,
+            chunk: vec![],
+            pos: 0,
+        })
+        .unwrap();
+        let parsed: govee_models::undoc::OneClickResponse = from_json(text).unwrap();
+        assert_eq!(
+            format!("{streamed:#?}"),
+            format!("{parsed:#?}"),
+            "streamed and buffered parsing agree"
+        );
+
+        let truncated: anyhow::Result<govee_models::undoc::OneClickResponse> =
+            from_json_reader(&text.as_bytes()[..text.len() / 2]);
+        assert!(truncated.is_err());
+    }
+
     const GET_DEVICE_STATE_EXAMPLE: &str = include_str!("../test-data/get_device_state.json");
 
     #[test]
     fn get_device_state() {
-        let resp: GetDeviceStateResponse = from_json(&GET_DEVICE_STATE_EXAMPLE).unwrap();
+        let resp: GetDeviceStateResponse = from_json(GET_DEVICE_STATE_EXAMPLE).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -1135,19 +1373,19 @@ mod test {
     #[test]
     fn list_devices_issue4() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/list_devices_issue4.json")).unwrap();
+            from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_devices_2() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE2).unwrap();
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_devices() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE).unwrap();
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -1162,4 +1400,55 @@ mod test {
             "\"something\""
         );
     }
+
+    #[cfg(feature = "cloud")]
+    #[test]
+    fn capture_response_body() {
+        let dir =
+            std::env::temp_dir().join(format!("govee2mqtt-capture-test-{}", std::process::id()));
+        let status = reqwest::StatusCode::BAD_GATEWAY;
+
+        let big = vec![b'x'; MAX_CAPTURE_SIZE * 2];
+        let url: reqwest::Url = "https://app2.govee.com/big".parse().unwrap();
+        let path = save_capture(&dir, &url, status, &big).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("https://app2.govee.com/big\nstatus 502 Bad Gateway\n"));
+        assert!(content.contains("truncated"));
+        assert!(content.len() < MAX_CAPTURE_SIZE + 200);
+
+        let mut path = path;
+        for n in 0..MAX_CAPTURES + 5 {
+            let url: reqwest::Url = format!("https://app2.govee.com/{n}").parse().unwrap();
+            path = save_capture(&dir, &url, status, b"<html>").unwrap();
+        }
+        k9::assert_equal!(std::fs::read_dir(&dir).unwrap().count(), MAX_CAPTURES);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "cloud")]
+    #[test]
+    fn capture_redaction() {
+        let body = br#"{"status":200,"data":{"token":"abc","p12Pass":"xyz","nested":[{"refreshToken":"r","sku":"H6000"}],"keys":{"a":1}}}"#;
+        let redacted: JsonValue = serde_json::from_slice(&redact_capture(body)).unwrap();
+        k9::assert_equal!(
+            redacted,
+            json!({"status":200,"data":{"token":"REDACTED","p12Pass":"REDACTED","nested":[{"refreshToken":"REDACTED","sku":"H6000"}],"keys":{"a":1}}})
+        );
+        k9::assert_equal!(&*redact_capture(b"<html>"), b"<html>");
+
+        let url: reqwest::Url = "https://app2.govee.com/app/v1/account/iot/key"
+            .parse()
+            .unwrap();
+        k9::assert_equal!(
+            describe_capture(&url, reqwest::StatusCode::OK, b"{\"p12\":"),
+            ""
+        );
+    }
 }
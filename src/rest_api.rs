@@ -1,5 +1,6 @@
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
-use crate::platform_api::{http_response_body, ONE_WEEK};
+use crate::cache::{account_key, cache_get, CacheComputeResult, CacheGetOptions};
+use crate::platform_api::{http_client, http_response_body, SendWithRetry, ONE_WEEK};
+use govee_models::enum_string;
 use reqwest::Method;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
@@ -26,14 +27,16 @@ impl RestApiClient {
     }
 
     pub async fn list_devices(&self) -> anyhow::Result<Vec<RestDeviceInfo>> {
+        let key = account_key(&self.key, "device-list");
         cache_get(
             CacheGetOptions {
                 topic: "rest-api",
-                key: "device-list",
+                key: &key,
                 soft_ttl: Duration::from_secs(900),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
+                defer_to_maintenance: true,
             },
             async {
                 let url = endpoint("/v1/devices");
@@ -142,12 +145,10 @@ impl RestApiClient {
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let response = http_client(Duration::from_secs(60))?
             .request(Method::GET, url)
             .header("Govee-API-Key", &self.key)
-            .send()
+            .send_with_retry()
             .await?;
 
         http_response_body(response).await
@@ -163,13 +164,11 @@ impl RestApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let response = http_client(Duration::from_secs(60))?
             .request(method, url)
             .header("Govee-API-Key", &self.key)
             .json(body)
-            .send()
+            .send_with_retry()
             .await?;
 
         http_response_body(response).await
@@ -177,7 +176,6 @@ impl RestApiClient {
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDevicesResponse {
     code: u32,
     message: String,
@@ -185,13 +183,11 @@ struct GetDevicesResponse {
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDevicesDeviceList {
     devices: Vec<RestDeviceInfo>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct RestDeviceInfo {
     #[serde(rename = "model")]
     pub sku: String,
@@ -209,7 +205,6 @@ pub struct RestDeviceInfo {
 }
 
 #[derive(Default, Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct RestDeviceProperties {
     #[serde(rename = "colorTem", default)]
     pub color_temperature: Option<ColorTemperatureProperties>,
@@ -219,13 +214,11 @@ pub struct RestDeviceProperties {
 }
 
 #[derive(Default, Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct ColorTemperatureProperties {
     pub range: RestRange,
 }
 
 #[derive(Default, Deserialize, Serialize, Debug, Clone, Copy)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct RestRange {
     pub min: i64,
     pub max: i64,
@@ -250,14 +243,14 @@ mod test {
     #[test]
     fn list_devices() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/rest-list-devices.json")).unwrap();
+            from_json(include_str!("../test-data/rest-list-devices.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_appliances() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/rest-appliances.json")).unwrap();
+            from_json(include_str!("../test-data/rest-appliances.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 }
@@ -0,0 +1,381 @@
+//! Decoding of the `scence_param` blobs found in the scene catalog.
+//! The blob describes the effect in detail and is sent to the device,
+//! split across a sequence of multi-part BLE packets, ahead of the
+//! scene code that activates it. The layout of the blob differs
+//! between product families, so each family has its own decoder.
+use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::lan_api::DeviceColor;
+use crate::undoc_api::{GoveeUndocumentedApi, LightEffectEntry};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The command byte of the multi-part packets that carry a scene param
+const MULTI_PART: u8 = 0xa3;
+/// How many bytes of the scene param fit in each multi-part packet
+const MULTI_PART_CHUNK: usize = 17;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "family")]
+pub enum DecodedSceneParam {
+    RibbonLamp(RibbonLampSceneParam),
+}
+
+/// A family-independent description of a decoded scene param,
+/// for presenting to automations via MQTT
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SceneParamSummary {
+    pub family: &'static str,
+    pub layers: Vec<SceneLayerSummary>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SceneLayerSummary {
+    pub effect: u8,
+    pub mode: u8,
+    pub brightness: u8,
+    pub speed: u8,
+    pub colors: Vec<DeviceColor>,
+}
+
+impl DecodedSceneParam {
+    pub fn summary(&self) -> SceneParamSummary {
+        match self {
+            Self::RibbonLamp(param) => SceneParamSummary {
+                family: "RibbonLamp",
+                layers: param
+                    .layers
+                    .iter()
+                    .map(|layer| SceneLayerSummary {
+                        effect: layer.effect(),
+                        mode: layer.mode(),
+                        brightness: layer.brightness(),
+                        speed: layer.speed(),
+                        colors: layer.colors.clone(),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Decode the scene params of all of the scenes in the catalog of
+/// the specified SKU that we know how to decode, keyed by scene name
+pub async fn scene_param_summaries(
+    sku: &str,
+) -> anyhow::Result<BTreeMap<String, SceneParamSummary>> {
+    let mut result = BTreeMap::new();
+    let Some(decoder) = decoder_for_sku(sku) else {
+        return Ok(result);
+    };
+    for category in GoveeUndocumentedApi::get_scenes_for_device(sku).await? {
+        for scene in category.scenes {
+            // Multi-effect scenes are described by their first effect
+            let Some(effect) = scene.light_effects.first() else {
+                continue;
+            };
+            match decoder.decode(effect) {
+                Ok(param) => {
+                    result.insert(scene.scene_name, param.summary());
+                }
+                Err(err) => {
+                    log::trace!("Unable to decode {sku} scene {}: {err:#}", scene.scene_name);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Implemented for each product family whose scene params we understand
+pub trait SceneParamDecoder: Send + Sync {
+    /// A short name for the product family
+    fn family(&self) -> &'static str;
+
+    /// The byte that identifies the param layout in the first
+    /// of the multi-part packets
+    fn multi_part_type(&self) -> u8;
+
+    /// Decode the scene param of the specified effect
+    fn decode(&self, effect: &LightEffectEntry) -> anyhow::Result<DecodedSceneParam>;
+
+    /// Produce the raw bytes of a decoded scene param
+    fn encode(&self, param: &DecodedSceneParam) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Returns the decoder for the scene params of the specified SKU
+pub fn decoder_for_sku(sku: &str) -> Option<&'static dyn SceneParamDecoder> {
+    match sku {
+        "H6072" | "H6076" | "H6078" | "H607C" => Some(&RibbonLampDecoder),
+        _ => None,
+    }
+}
+
+fn decode_base64(effect: &LightEffectEntry) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        !effect.scence_param.is_empty(),
+        "scene {} has no param",
+        effect.scence_param_id
+    );
+    Ok(data_encoding::BASE64.decode(effect.scence_param.as_bytes())?)
+}
+
+/// Build the base64 encoded ptReal packets that apply the scene
+/// param and then activate the scene, suitable for sending via
+/// `LanDevice::send_real` or AWS IoT
+pub fn scene_param_packets(
+    decoder: &dyn SceneParamDecoder,
+    param: &DecodedSceneParam,
+    scene_code: u16,
+) -> anyhow::Result<Vec<String>> {
+    let mut data = vec![0x01, 0, decoder.multi_part_type()];
+    data.extend(decoder.encode(param)?);
+    let num_chunks = data.len().div_ceil(MULTI_PART_CHUNK);
+    anyhow::ensure!(num_chunks < 0xff, "scene param is too large");
+    data[1] = num_chunks as u8;
+
+    let mut packets = vec![];
+    for (idx, chunk) in data.chunks(MULTI_PART_CHUNK).enumerate() {
+        let idx = if idx + 1 == num_chunks {
+            0xff
+        } else {
+            idx as u8
+        };
+        let mut packet = vec![MULTI_PART, idx];
+        packet.extend_from_slice(chunk);
+        packets.push(Base64HexBytes::with_bytes(packet).base64());
+    }
+
+    packets.push(
+        Base64HexBytes::encode_for_sku("Generic:Light", &SetSceneCode { code: scene_code })?
+            .base64(),
+    );
+
+    Ok(packets)
+}
+
+/// One of the effects that are layered to produce the scene
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RibbonLampLayer {
+    /// Leading bytes whose meaning is not yet known
+    pub header: [u8; 6],
+    /// Timing information for each phase of the layer
+    pub phases: Vec<[u8; 6]>,
+    /// Appears to hold the effect mode, brightness and speed
+    pub params: [u8; 3],
+    pub colors: Vec<DeviceColor>,
+    /// Trailing bytes whose meaning is not yet known
+    pub trailer: [u8; 7],
+}
+
+impl RibbonLampLayer {
+    /// The kind of effect; the first byte of the header
+    pub fn effect(&self) -> u8 {
+        self.header[0]
+    }
+
+    /// Appears to select the direction or style of the effect
+    pub fn mode(&self) -> u8 {
+        self.params[0]
+    }
+
+    /// The brightness of the layer in percent
+    pub fn brightness(&self) -> u8 {
+        ((self.params[1] as u32 * 100 + 127) / 255) as u8
+    }
+
+    /// The speed of the effect; larger values are faster
+    pub fn speed(&self) -> u8 {
+        self.params[2]
+    }
+}
+
+/// The scene param used by the ribbon and tower lamps, such as the
+/// H6072, which is composed of a number of layers
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RibbonLampSceneParam {
+    pub layers: Vec<RibbonLampLayer>,
+    /// The number of zero bytes that pad the end of the param
+    pub padding: usize,
+}
+
+/// The scene type of the layered effects; other types use a
+/// different layout that we don't yet understand
+const RIBBON_LAMP_LAYERED: u32 = 2;
+
+pub struct RibbonLampDecoder;
+
+impl RibbonLampDecoder {
+    fn parse(data: &[u8]) -> anyhow::Result<RibbonLampSceneParam> {
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+            anyhow::ensure!(data.len() >= len, "scene param is truncated");
+            let (taken, rest) = data.split_at(len);
+            *data = rest;
+            Ok(taken)
+        }
+        fn take_array<const N: usize>(data: &mut &[u8]) -> anyhow::Result<[u8; N]> {
+            Ok(take(data, N)?.try_into()?)
+        }
+
+        let mut data = data;
+        let [num_layers] = take_array(&mut data)?;
+        let mut layers = vec![];
+        for _ in 0..num_layers {
+            let header: [u8; 6] = take_array(&mut data)?;
+            let [num_phases] = take_array(&mut data)?;
+            let mut phases = vec![];
+            for _ in 0..num_phases {
+                phases.push(take_array(&mut data)?);
+            }
+            let params = take_array(&mut data)?;
+            let [num_colors] = take_array(&mut data)?;
+            let mut colors = vec![];
+            for _ in 0..num_colors {
+                let [r, g, b] = take_array(&mut data)?;
+                colors.push(DeviceColor { r, g, b });
+            }
+            let trailer = take_array(&mut data)?;
+            layers.push(RibbonLampLayer {
+                header,
+                phases,
+                params,
+                colors,
+                trailer,
+            });
+        }
+
+        anyhow::ensure!(
+            data.iter().all(|&b| b == 0),
+            "{} unexpected bytes after the last layer",
+            data.len()
+        );
+
+        Ok(RibbonLampSceneParam {
+            layers,
+            padding: data.len(),
+        })
+    }
+}
+
+impl SceneParamDecoder for RibbonLampDecoder {
+    fn family(&self) -> &'static str {
+        "RibbonLamp"
+    }
+
+    fn multi_part_type(&self) -> u8 {
+        0x02
+    }
+
+    fn decode(&self, effect: &LightEffectEntry) -> anyhow::Result<DecodedSceneParam> {
+        anyhow::ensure!(
+            effect.scene_type == RIBBON_LAMP_LAYERED,
+            "scene type {} is not supported",
+            effect.scene_type
+        );
+        let data = decode_base64(effect)?;
+        Ok(DecodedSceneParam::RibbonLamp(Self::parse(&data)?))
+    }
+
+    fn encode(&self, param: &DecodedSceneParam) -> anyhow::Result<Vec<u8>> {
+        let DecodedSceneParam::RibbonLamp(param) = param;
+
+        fn count(len: usize) -> anyhow::Result<u8> {
+            u8::try_from(len).map_err(|_| anyhow::anyhow!("too many entries: {len}"))
+        }
+
+        let mut data = vec![count(param.layers.len())?];
+        for layer in &param.layers {
+            data.extend_from_slice(&layer.header);
+            data.push(count(layer.phases.len())?);
+            for phase in &layer.phases {
+                data.extend_from_slice(phase);
+            }
+            data.extend_from_slice(&layer.params);
+            data.push(count(layer.colors.len())?);
+            for color in &layer.colors {
+                data.extend_from_slice(&[color.r, color.g, color.b]);
+            }
+            data.extend_from_slice(&layer.trailer);
+        }
+        data.resize(data.len() + param.padding, 0);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+    use crate::undoc_api::LightEffectLibraryResponse;
+
+    #[test]
+    fn ribbon_lamp_round_trip() {
+        let resp: LightEffectLibraryResponse =
+            from_json(include_str!("../test-data/light-effect-library-h6072.json")).unwrap();
+        let decoder = decoder_for_sku("H6072").expect("H6072 to have a decoder");
+
+        let mut decoded = 0;
+        for category in &resp.data.categories {
+            for scene in &category.scenes {
+                for effect in &scene.light_effects {
+                    if effect.scene_type != RIBBON_LAMP_LAYERED {
+                        assert!(decoder.decode(effect).is_err());
+                        continue;
+                    }
+
+                    let param = decoder.decode(effect).unwrap_or_else(|err| {
+                        panic!("decoding {}: {err:#}", scene.scene_name);
+                    });
+                    k9::assert_equal!(
+                        data_encoding::BASE64.encode(&decoder.encode(&param).unwrap()),
+                        effect.scence_param,
+                        "{}",
+                        scene.scene_name
+                    );
+                    decoded += 1;
+                }
+            }
+        }
+        k9::assert_equal!(decoded, 45);
+    }
+
+    #[test]
+    fn ribbon_lamp_sunrise() {
+        let resp: LightEffectLibraryResponse =
+            from_json(include_str!("../test-data/light-effect-library-h6072.json")).unwrap();
+        let effect = &resp.data.categories[0].scenes[0].light_effects[0];
+        let decoder = decoder_for_sku("H6072").unwrap();
+
+        let DecodedSceneParam::RibbonLamp(param) = decoder.decode(effect).unwrap();
+        k9::assert_equal!(param.layers.len(), 3);
+        k9::assert_equal!(
+            param.layers[0].colors,
+            vec![DeviceColor {
+                r: 255,
+                g: 255,
+                b: 0
+            }]
+        );
+        k9::assert_equal!(param.layers[1].colors.len(), 16);
+
+        let summary = DecodedSceneParam::RibbonLamp(param.clone()).summary();
+        k9::assert_equal!(summary.family, "RibbonLamp");
+        k9::assert_equal!(summary.layers[0].brightness, 100);
+        k9::assert_equal!(summary.layers[0].speed, 50);
+        k9::assert_equal!(summary.layers[1].brightness, 98);
+        k9::assert_equal!(summary.layers[1].speed, 20);
+
+        let packets =
+            scene_param_packets(decoder, &DecodedSceneParam::RibbonLamp(param), 2099).unwrap();
+        let bytes: Vec<Vec<u8>> = packets
+            .iter()
+            .map(|p| data_encoding::BASE64.decode(p.as_bytes()).unwrap())
+            .collect();
+        // 3 bytes of preamble plus the 133 bytes of the param
+        // need 8 multi-part packets, followed by the scene code
+        k9::assert_equal!(bytes.len(), 9);
+        k9::assert_equal!(&bytes[0][0..5], &[0xa3, 0x00, 0x01, 0x08, 0x02]);
+        k9::assert_equal!(&bytes[7][0..2], &[0xa3, 0xff]);
+        k9::assert_equal!(&bytes[8][0..3], &[0x33, 0x05, 0x04]);
+        assert!(bytes.iter().all(|p| p.len() == 20));
+    }
+}
@@ -0,0 +1,106 @@
+//! Govee's APIs change from time to time, adding new fields or
+//! changing the shape of existing ones. Rather than failing when
+//! that happens, we record what we observed here so that it can
+//! be reported via mqtt, the HTTP API or the `schema-drift`
+//! subcommand, which makes it easier to produce an issue report.
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How many distinct parse errors to retain per type
+const MAX_ERRORS: usize = 5;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TypeDrift {
+    /// Paths of fields that were present in the data but
+    /// which are not known to us
+    pub unknown_fields: BTreeSet<String>,
+    /// The most recent errors produced when parsing the data,
+    /// typically because of missing or changed fields
+    pub errors: Vec<String>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct DriftReport {
+    types: BTreeMap<String, TypeDrift>,
+    generation: usize,
+}
+
+static REPORT: Lazy<Mutex<DriftReport>> = Lazy::new(Default::default);
+
+fn update<F: FnOnce(&mut TypeDrift) -> bool>(type_name: &str, func: F) {
+    let mut report = REPORT.lock();
+    let entry = report.types.entry(type_name.to_string()).or_default();
+    let now = Utc::now();
+    entry.first_seen.get_or_insert(now);
+    entry.last_seen.replace(now);
+    if func(entry) {
+        report.generation += 1;
+    }
+}
+
+pub fn record_unknown_field(type_name: &str, path: &str) {
+    update(type_name, |entry| {
+        let added = entry.unknown_fields.insert(path.to_string());
+        if added {
+            log::debug!("Schema drift: {type_name} has unknown field {path}");
+        }
+        added
+    });
+}
+
+pub fn record_parse_error(type_name: &str, error: &str) {
+    update(type_name, |entry| {
+        if entry.errors.iter().any(|e| e == error) {
+            return false;
+        }
+        log::warn!("Schema drift: failed to parse {type_name}: {error}");
+        entry.errors.push(error.to_string());
+        if entry.errors.len() > MAX_ERRORS {
+            entry.errors.remove(0);
+        }
+        true
+    });
+}
+
+/// Returns a counter that changes each time new drift is observed
+pub fn generation() -> usize {
+    REPORT.lock().generation
+}
+
+/// Returns the drift observed so far, keyed by type name
+pub fn report() -> BTreeMap<String, TypeDrift> {
+    REPORT.lock().types.clone()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::platform_api::from_json;
+    use serde::Deserialize;
+
+    #[test]
+    fn unknown_fields_are_recorded() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Example {
+            known: u32,
+        }
+
+        let value: Example = from_json(r#"{"known": 1, "novel": {"inner": true}}"#).unwrap();
+        assert_eq!(value.known, 1);
+        assert!(from_json::<Example, _>(r#"{"novel": 2}"#).is_err());
+
+        let report = super::report();
+        let drift = report
+            .iter()
+            .find(|(name, _)| name.ends_with("::Example"))
+            .map(|(_, drift)| drift)
+            .unwrap();
+        assert!(drift.unknown_fields.contains("novel"));
+        assert_eq!(drift.errors.len(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+//! Some Govee lamps have an ambient light sensor. Auto brightness
+//! scales the brightness of such a lamp against the measured light
+//! level, following a curve of `lux:percent` points between which
+//! the brightness is interpolated linearly.
+//!
+//! The sensor also sees the light of the lamp itself, so a rule may
+//! give the light level that the lamp adds at full brightness, which
+//! is subtracted, in proportion to the brightness, while it is on.
+//!
+//! The lamp is never turned on or off by the controller. When the
+//! brightness is changed by something else while the lamp is on,
+//! the controller leaves the lamp alone until it has been turned
+//! off and on again.
+use crate::service::poll_intervals::parse_rule_list;
+use crate::service::shard::owns_device;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use std::time::Duration;
+
+/// How often the lamps are adjusted
+const ADJUST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The smallest change in brightness that is worth sending to the lamp
+const HYSTERESIS_PERCENT: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoBrightnessRule {
+    /// The lamp, by name or id
+    pub lamp: String,
+    /// The lux that the lamp adds to its own sensor at 100% brightness
+    pub own_lux: f64,
+    /// (lux, brightness percent), sorted by lux
+    pub curve: Vec<(f64, u8)>,
+}
+
+impl std::str::FromStr for AutoBrightnessRule {
+    type Err = anyhow::Error;
+
+    /// Parses `LAMP[@OWN_LUX]=LUX:PERCENT,LUX:PERCENT,...`,
+    /// eg: `Desk Lamp@40=0:10,50:40,300:100`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (lamp, points) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected LAMP=LUX:PERCENT,..., got '{s}'"))?;
+        let (lamp, own_lux) = match lamp.rsplit_once('@') {
+            Some((lamp, own_lux)) => {
+                let own_lux: f64 = own_lux
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("lux of the lamp itself in '{s}'"))?;
+                anyhow::ensure!(
+                    own_lux.is_finite() && own_lux >= 0.,
+                    "lux of the lamp itself in '{s}' must not be negative"
+                );
+                (lamp, own_lux)
+            }
+            None => (lamp, 0.),
+        };
+        let lamp = lamp.trim();
+        anyhow::ensure!(!lamp.is_empty(), "missing lamp in '{s}'");
+
+        let mut curve = vec![];
+        for point in points
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+        {
+            let (lux, percent) = point
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("expected LUX:PERCENT, got '{point}'"))?;
+            let lux: f64 = lux
+                .trim()
+                .parse()
+                .with_context(|| format!("lux in '{point}'"))?;
+            let percent: u8 = percent
+                .trim()
+                .parse()
+                .with_context(|| format!("percent in '{point}'"))?;
+            anyhow::ensure!(
+                lux.is_finite() && lux >= 0.,
+                "lux in '{point}' must not be negative"
+            );
+            anyhow::ensure!(
+                (1..=100).contains(&percent),
+                "percent in '{point}' must be between 1 and 100"
+            );
+            curve.push((lux, percent));
+        }
+        anyhow::ensure!(!curve.is_empty(), "missing curve in '{s}'");
+        curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        anyhow::ensure!(
+            curve.windows(2).all(|w| w[0].0 != w[1].0),
+            "duplicate lux value in '{s}'"
+        );
+
+        Ok(Self {
+            lamp: lamp.to_string(),
+            own_lux,
+            curve,
+        })
+    }
+}
+
+impl AutoBrightnessRule {
+    /// The ambient light level, discounting the light that the
+    /// lamp itself contributes to the measured level
+    pub fn ambient_lux(&self, measured: f64, on: bool, brightness: u8) -> f64 {
+        if !on {
+            return measured;
+        }
+        (measured - self.own_lux * brightness as f64 / 100.).max(0.)
+    }
+
+    /// The brightness for a light level. Levels outside of the
+    /// curve use the brightness of its nearest end.
+    pub fn brightness_for(&self, lux: f64) -> u8 {
+        let first = self.curve[0];
+        if lux <= first.0 {
+            return first.1;
+        }
+        for w in self.curve.windows(2) {
+            let ((lux_a, pct_a), (lux_b, pct_b)) = (w[0], w[1]);
+            if lux <= lux_b {
+                let t = (lux - lux_a) / (lux_b - lux_a);
+                return (pct_a as f64 + t * (pct_b as f64 - pct_a as f64)).round() as u8;
+            }
+        }
+        self.curve[self.curve.len() - 1].1
+    }
+}
+
+/// Parse a list of rules separated by semicolons or newlines
+pub fn parse_auto_brightness_rules(s: &str) -> anyhow::Result<Vec<AutoBrightnessRule>> {
+    parse_rule_list(s, "auto brightness rule")
+}
+
+#[derive(Default, Debug)]
+struct LampState {
+    /// The brightness that was last set by the controller
+    last_set: Option<u8>,
+    /// Set when the brightness was changed by something else,
+    /// until the lamp is turned off
+    overridden: bool,
+}
+
+impl LampState {
+    /// Decide what brightness to set, given whether the lamp
+    /// is on, its current brightness and the wanted brightness
+    fn adjust(&mut self, on: bool, current: u8, wanted: u8) -> Option<u8> {
+        if !on {
+            *self = Self::default();
+            return None;
+        }
+        if self.overridden {
+            return None;
+        }
+        if let Some(last) = self.last_set {
+            if current.abs_diff(last) > 1 {
+                self.overridden = true;
+                return None;
+            }
+        }
+        if current.abs_diff(wanted) < HYSTERESIS_PERCENT {
+            return None;
+        }
+        self.last_set = Some(wanted);
+        Some(wanted)
+    }
+}
+
+pub async fn periodic_auto_brightness(
+    state: StateHandle,
+    rules: Vec<AutoBrightnessRule>,
+) -> anyhow::Result<()> {
+    let mut lamp_states: Vec<LampState> = rules.iter().map(|_| LampState::default()).collect();
+
+    loop {
+        for (rule, lamp_state) in rules.iter().zip(lamp_states.iter_mut()) {
+            let Some(lamp) = state.resolve_device(&rule.lamp).await else {
+                continue;
+            };
+            if !owns_device(&lamp) {
+                continue;
+            }
+            let (Some(device_state), Some(lux)) = (lamp.device_state(), lamp.sensor_lux()) else {
+                continue;
+            };
+
+            let on = device_state.light_on.unwrap_or(device_state.on);
+            let lux = rule.ambient_lux(lux, on, device_state.brightness);
+            let wanted = rule.brightness_for(lux);
+            let was_overridden = lamp_state.overridden;
+            let adjustment = lamp_state.adjust(on, device_state.brightness, wanted);
+            if lamp_state.overridden && !was_overridden {
+                log::info!(
+                    "auto brightness: the brightness of {lamp} was changed \
+                    elsewhere; leaving it alone until it is turned off"
+                );
+            }
+            if let Some(brightness) = adjustment {
+                log::info!("auto brightness: setting {lamp} to {brightness}% for {lux} lux");
+                if let Err(err) = state.device_set_brightness(&lamp, brightness).await {
+                    log::error!("auto brightness: setting {lamp} brightness: {err:#}");
+                }
+            }
+        }
+
+        tokio::time::sleep(ADJUST_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn curve() {
+        let rule: AutoBrightnessRule = "Desk Lamp=300:100, 0:10, 50:40".parse().unwrap();
+        assert_eq!(rule.lamp, "Desk Lamp");
+        assert_eq!(rule.curve, vec![(0., 10), (50., 40), (300., 100)]);
+        assert_eq!(rule.own_lux, 0.);
+        assert_eq!(rule.brightness_for(0.), 10);
+        assert_eq!(rule.brightness_for(25.), 25);
+        assert_eq!(rule.brightness_for(175.), 70);
+        assert_eq!(rule.brightness_for(1000.), 100);
+
+        assert!("Desk Lamp=".parse::<AutoBrightnessRule>().is_err());
+        assert!("Desk Lamp=10:0".parse::<AutoBrightnessRule>().is_err());
+        assert!("Desk Lamp=10:5,10:50"
+            .parse::<AutoBrightnessRule>()
+            .is_err());
+        assert!("=10:50".parse::<AutoBrightnessRule>().is_err());
+        assert!("Desk Lamp@-5=10:50".parse::<AutoBrightnessRule>().is_err());
+    }
+
+    #[test]
+    fn own_light() {
+        let rule: AutoBrightnessRule = "Desk Lamp @ 40=0:10,300:100".parse().unwrap();
+        assert_eq!(rule.lamp, "Desk Lamp");
+        assert_eq!(rule.own_lux, 40.);
+        assert_eq!(rule.ambient_lux(100., true, 50), 80.);
+        assert_eq!(rule.ambient_lux(100., false, 50), 100.);
+        assert_eq!(rule.ambient_lux(10., true, 100), 0.);
+    }
+
+    #[test]
+    fn manual_override() {
+        let mut lamp = LampState::default();
+        assert_eq!(lamp.adjust(true, 50, 80), Some(80));
+        // Within the hysteresis
+        assert_eq!(lamp.adjust(true, 80, 83), None);
+        assert_eq!(lamp.adjust(true, 80, 60), Some(60));
+        // Someone else set the brightness
+        assert_eq!(lamp.adjust(true, 20, 90), None);
+        assert_eq!(lamp.adjust(true, 20, 90), None);
+        // Turning the lamp off and on again hands back control
+        assert_eq!(lamp.adjust(false, 20, 90), None);
+        assert_eq!(lamp.adjust(true, 20, 90), Some(90));
+    }
+}
@@ -0,0 +1,123 @@
+//! Brightness is a percentage everywhere in govee2mqtt, but the
+//! devices don't all agree: some SKUs and transports use 0-100 and
+//! others use 0-254 (or some other range advertised by the platform
+//! API). Converting back and forth loses precision, so that setting
+//! 50% can read back as 49%. This module centralizes the conversion,
+//! and reconciles the reported value with the value that we most
+//! recently requested, so that hass shows what the user set.
+use crate::platform_api::HttpDeviceInfo;
+use crate::service::quirks::resolve_quirk;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrightnessScale {
+    /// The smallest value that the device will accept
+    pub min: u32,
+    /// The value that corresponds to 100%
+    pub max: u32,
+}
+
+impl Default for BrightnessScale {
+    fn default() -> Self {
+        Self::PERCENT
+    }
+}
+
+impl BrightnessScale {
+    pub const PERCENT: Self = Self { min: 0, max: 100 };
+
+    pub fn new(min: u32, max: u32) -> Self {
+        Self {
+            min,
+            max: max.max(1),
+        }
+    }
+
+    /// Convert a percentage into the value to send to the device.
+    /// 0% is passed through as 0 so that it can still mean off.
+    pub fn to_device(self, percent: u8) -> u32 {
+        let percent = percent.min(100) as u32;
+        if percent == 0 {
+            return 0;
+        }
+        let value = (percent * self.max + 50) / 100;
+        value.clamp(self.min.max(1), self.max)
+    }
+
+    /// Convert a value reported by the device into a percentage
+    pub fn to_percent(self, value: u32) -> u8 {
+        let value = value.min(self.max);
+        ((value * 100 + self.max / 2) / self.max) as u8
+    }
+
+    /// Convert a reported value into a percentage, preferring the
+    /// percentage that we most recently requested if the reported
+    /// value is within one step of what that request would have sent.
+    /// This hysteresis absorbs the rounding that happens on the way
+    /// to the device and back.
+    pub fn reconcile(self, value: u32, requested: Option<u8>) -> u8 {
+        if let Some(requested) = requested {
+            if self.to_device(requested).abs_diff(value) <= 1 && (requested == 0) == (value == 0) {
+                return requested;
+            }
+        }
+        self.to_percent(value)
+    }
+}
+
+/// The scale used by the platform API for a device, as advertised
+/// by its brightness capability
+pub fn platform_brightness_scale(info: &HttpDeviceInfo) -> Option<BrightnessScale> {
+    let (min, max) = info.get_brightness_range()?;
+    Some(BrightnessScale::new(min, max))
+}
+
+/// The scale used by the AWS IoT API for a given SKU
+pub fn iot_brightness_scale(sku: &str) -> BrightnessScale {
+    resolve_quirk(sku)
+        .map(|quirk| quirk.iot_brightness_scale)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percent_is_identity() {
+        let scale = BrightnessScale::PERCENT;
+        for p in 0..=100 {
+            assert_eq!(scale.to_device(p), p as u32);
+            assert_eq!(scale.to_percent(p as u32), p);
+        }
+    }
+
+    #[test]
+    fn byte_scale() {
+        let scale = BrightnessScale::new(0, 254);
+        assert_eq!(scale.to_device(100), 254);
+        assert_eq!(scale.to_device(50), 127);
+        assert_eq!(scale.to_device(1), 3);
+        assert_eq!(scale.to_device(0), 0);
+        assert_eq!(scale.to_percent(254), 100);
+        assert_eq!(scale.to_percent(127), 50);
+
+        // A device that reports one step lower than it was sent
+        // would drift from 49% to 48%,
+        assert_eq!(scale.to_percent(scale.to_device(49) - 1), 48);
+        // but the reconciliation preserves what was set
+        assert_eq!(scale.reconcile(scale.to_device(49), Some(49)), 49);
+        assert_eq!(scale.reconcile(scale.to_device(49) - 1, Some(49)), 49);
+        // A genuine change made elsewhere is reported as-is
+        assert_eq!(scale.reconcile(200, Some(49)), 79);
+        assert_eq!(scale.reconcile(0, Some(1)), 0);
+    }
+
+    #[test]
+    fn minimum() {
+        let scale = BrightnessScale::new(1, 100);
+        assert_eq!(scale.to_device(0), 0);
+        assert_eq!(scale.to_device(1), 1);
+        assert_eq!(scale.reconcile(51, Some(50)), 50);
+    }
+}
@@ -0,0 +1,460 @@
+//! A minimal MQTT 3.1.1 broker, so that small installations can
+//! run govee2mqtt standalone and point Home Assistant directly at
+//! it, rather than also having to run mosquitto.
+//!
+//! It supports just what govee2mqtt and Home Assistant need:
+//! subscriptions with wildcards, retained messages, last will
+//! messages and optional username/password authentication.
+//! Messages are always delivered at QoS 0, and no state is kept
+//! for clients once they disconnect.
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const CONNACK_UNACCEPTABLE_PROTOCOL: u8 = 1;
+const CONNACK_BAD_CREDENTIALS: u8 = 4;
+
+/// Packets larger than this are rejected
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct BrokerCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+struct Session {
+    tx: UnboundedSender<Vec<u8>>,
+    subscriptions: Vec<String>,
+}
+
+#[derive(Default)]
+struct Broker {
+    sessions: HashMap<u64, Session>,
+    retained: HashMap<String, Vec<u8>>,
+}
+
+static BROKER: Lazy<Mutex<Broker>> = Lazy::new(Default::default);
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+/// Returns true if the topic filter, which may contain
+/// `+` and `#` wildcards, matches the topic name
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards don't match topics that begin with a '$'
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn encode_remaining_length(packet: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    encode_remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    encode_packet((PUBLISH << 4) | u8::from(retain), &body)
+}
+
+/// A cursor over the variable header and payload of a packet
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        let (&b, rest) = self
+            .data
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("packet is truncated"))?;
+        self.data = rest;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        anyhow::ensure!(self.data.len() >= len, "packet is truncated");
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        Ok(std::str::from_utf8(self.bytes()?)?.to_string())
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Reads a packet, returning its fixed header byte and its body
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<(u8, Vec<u8>)> {
+    let header = reader.read_u8().await?;
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        anyhow::ensure!(shift < 28, "malformed remaining length");
+    }
+    anyhow::ensure!(len <= MAX_PACKET_SIZE, "packet of {len} bytes is too large");
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+fn publish(msg: &Message) {
+    let mut broker = BROKER.lock();
+    if msg.retain {
+        if msg.payload.is_empty() {
+            broker.retained.remove(&msg.topic);
+        } else {
+            broker
+                .retained
+                .insert(msg.topic.to_string(), msg.payload.clone());
+        }
+    }
+
+    let packet = encode_publish(&msg.topic, &msg.payload, false);
+    for session in broker.sessions.values() {
+        if session
+            .subscriptions
+            .iter()
+            .any(|filter| topic_matches(filter, &msg.topic))
+        {
+            session.tx.send(packet.clone()).ok();
+        }
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    credentials: Option<BrokerCredentials>,
+) -> anyhow::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+
+    let (header, body) = read_packet(&mut reader).await?;
+    anyhow::ensure!(header >> 4 == CONNECT, "expected CONNECT packet");
+    let mut r = Reader { data: &body };
+    let protocol = r.string()?;
+    let level = r.u8()?;
+    if !matches!((protocol.as_str(), level), ("MQTT", 4) | ("MQIsdp", 3)) {
+        writer
+            .write_all(&[CONNACK << 4, 2, 0, CONNACK_UNACCEPTABLE_PROTOCOL])
+            .await?;
+        anyhow::bail!("unsupported protocol {protocol} level {level}");
+    }
+    let flags = r.u8()?;
+    let keep_alive = r.u16()?;
+    let client_id = r.string()?;
+    let will = if flags & 0x04 != 0 {
+        let topic = r.string()?;
+        let payload = r.bytes()?.to_vec();
+        Some(Message {
+            topic,
+            payload,
+            retain: flags & 0x20 != 0,
+        })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 {
+        Some(r.string()?)
+    } else {
+        None
+    };
+    let password = if flags & 0x40 != 0 {
+        Some(r.string()?)
+    } else {
+        None
+    };
+
+    if let Some(creds) = &credentials {
+        if username.as_deref() != Some(creds.username.as_str())
+            || password.as_deref() != Some(creds.password.as_str())
+        {
+            writer
+                .write_all(&[CONNACK << 4, 2, 0, CONNACK_BAD_CREDENTIALS])
+                .await?;
+            anyhow::bail!("client {client_id} supplied incorrect credentials");
+        }
+    }
+
+    writer.write_all(&[CONNACK << 4, 2, 0, 0]).await?;
+    log::debug!("broker: {client_id} connected");
+
+    let (tx, mut rx) = unbounded_channel::<Vec<u8>>();
+    let session_id = NEXT_SESSION.fetch_add(1, Ordering::SeqCst);
+    BROKER.lock().sessions.insert(
+        session_id,
+        Session {
+            tx: tx.clone(),
+            subscriptions: vec![],
+        },
+    );
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            if writer.write_all(&packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // The client is considered to have gone away if we hear
+    // nothing from it for one and a half keep alive periods
+    let timeout = (keep_alive > 0).then_some(tokio::time::Duration::from_millis(
+        u64::from(keep_alive) * 1500,
+    ));
+
+    let result: anyhow::Result<bool> = async {
+        loop {
+            let (header, body) = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read_packet(&mut reader))
+                    .await
+                    .context("keep alive expired")??,
+                None => read_packet(&mut reader).await?,
+            };
+            let mut r = Reader { data: &body };
+
+            match header >> 4 {
+                PUBLISH => {
+                    let qos = (header >> 1) & 3;
+                    let topic = r.string()?;
+                    if qos > 0 {
+                        let packet_id = r.u16()?;
+                        let ack = if qos == 1 { PUBACK } else { PUBREC };
+                        let [hi, lo] = packet_id.to_be_bytes();
+                        tx.send(vec![ack << 4, 2, hi, lo]).ok();
+                    }
+                    publish(&Message {
+                        topic,
+                        payload: r.rest().to_vec(),
+                        retain: header & 1 != 0,
+                    });
+                }
+                PUBREL => {
+                    let [hi, lo] = r.u16()?.to_be_bytes();
+                    tx.send(vec![PUBCOMP << 4, 2, hi, lo]).ok();
+                }
+                PUBACK | PUBREC | PUBCOMP => {}
+                SUBSCRIBE => {
+                    let packet_id = r.u16()?;
+                    let mut filters = vec![];
+                    while !r.is_empty() {
+                        let filter = r.string()?;
+                        let _requested_qos = r.u8()?;
+                        filters.push(filter);
+                    }
+
+                    let mut ack = packet_id.to_be_bytes().to_vec();
+                    // We only deliver at QoS 0
+                    ack.resize(2 + filters.len(), 0);
+                    tx.send(encode_packet(SUBACK << 4, &ack)).ok();
+
+                    let mut broker = BROKER.lock();
+                    for (topic, payload) in &broker.retained {
+                        if filters.iter().any(|filter| topic_matches(filter, topic)) {
+                            tx.send(encode_publish(topic, payload, true)).ok();
+                        }
+                    }
+                    if let Some(session) = broker.sessions.get_mut(&session_id) {
+                        for filter in filters {
+                            if !session.subscriptions.contains(&filter) {
+                                session.subscriptions.push(filter);
+                            }
+                        }
+                    }
+                }
+                UNSUBSCRIBE => {
+                    let packet_id = r.u16()?;
+                    let mut filters = vec![];
+                    while !r.is_empty() {
+                        filters.push(r.string()?);
+                    }
+                    if let Some(session) = BROKER.lock().sessions.get_mut(&session_id) {
+                        session.subscriptions.retain(|f| !filters.contains(f));
+                    }
+                    tx.send(encode_packet(UNSUBACK << 4, &packet_id.to_be_bytes()))
+                        .ok();
+                }
+                PINGREQ => {
+                    tx.send(vec![PINGRESP << 4, 0]).ok();
+                }
+                DISCONNECT => {
+                    return Ok(true);
+                }
+                other => {
+                    anyhow::bail!("unexpected packet type {other}");
+                }
+            }
+        }
+    }
+    .await;
+
+    BROKER.lock().sessions.remove(&session_id);
+    drop(tx);
+    writer_task.await.ok();
+
+    let clean = match result {
+        Ok(clean) => clean,
+        Err(err) => {
+            log::debug!("broker: {client_id}: {err:#}");
+            false
+        }
+    };
+    log::debug!("broker: {client_id} disconnected");
+
+    if !clean {
+        if let Some(will) = will {
+            publish(&will);
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the broker listening on the specified address,
+/// eg: `0.0.0.0:1883`, and serve clients in the background
+pub async fn start_broker(
+    listen: &str,
+    credentials: Option<BrokerCredentials>,
+) -> anyhow::Result<std::net::SocketAddr> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("binding embedded mqtt broker to {listen}"))?;
+    let addr = listener.local_addr()?;
+    log::info!("Embedded mqtt broker is listening on {addr}");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let credentials = credentials.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_client(stream, credentials).await {
+                            log::warn!("broker: client {peer}: {err:#}");
+                        }
+                    });
+                }
+                Err(err) => {
+                    log::error!("broker: accept failed: {err:#}");
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("gv2mqtt/#", "gv2mqtt/light/abc/command"));
+        assert!(topic_matches("gv2mqtt/#", "gv2mqtt"));
+        assert!(topic_matches(
+            "gv2mqtt/+/abc/command",
+            "gv2mqtt/light/abc/command"
+        ));
+        assert!(!topic_matches("gv2mqtt/+/abc", "gv2mqtt/light/abc/command"));
+        assert!(topic_matches(
+            "homeassistant/status",
+            "homeassistant/status"
+        ));
+        assert!(!topic_matches(
+            "homeassistant/status",
+            "homeassistant/statuses"
+        ));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn remaining_length() {
+        for (len, expect) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (2_097_152, vec![0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut encoded = vec![];
+            encode_remaining_length(&mut encoded, len);
+            assert_eq!(encoded, expect, "encoding {len}");
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Govee sometimes advertises capabilities that a device ignores,
+//! such as a color temperature control on an RGB-only strip.
+//! When probing is enabled, each suspicious capability is tested
+//! once by setting it and reading it back via the Platform API.
+//! The results are remembered as learned quirks in the quirks
+//! directory, and the entities for capabilities that failed are
+//! registered with Home Assistant as disabled by default.
+//!
+//! Probing changes the setting on the real device, so only the color
+//! and color temperature of lights are probed, and only when the prior
+//! state of the light is known, so that it can be restored afterwards.
+use crate::cache::cache_dir;
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceParameters, DeviceType};
+use crate::service::device::{normalize_device_id, Device};
+use crate::service::hass::topic_safe_id;
+use crate::service::quirk_bundle::bundle_dir;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tokio::time::{sleep, Duration};
+
+/// How long to wait for a device to apply a probe before
+/// reading back its state
+const PROBE_SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// The capabilities that may be probed
+const PROBED_INSTANCES: [&str; 2] = ["colorRgb", "colorTemperatureK"];
+
+/// The name of the file, in the quirks directory,
+/// that holds the results of probing
+pub const LEARNED_QUIRKS_FILE_NAME: &str = "learned-capabilities.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProbeResult {
+    pub supported: bool,
+    /// Explains how the result was determined
+    pub note: String,
+    /// The unique id of the entity that represents the capability
+    pub unique_id: String,
+    pub probed: DateTime<Utc>,
+}
+
+/// Maps normalized device id to the probe results
+/// for each capability instance
+type LearnedQuirks = BTreeMap<String, BTreeMap<String, ProbeResult>>;
+
+/// Re-key the results by normalized device id. Earlier versions kept
+/// the id in whichever form the APIs reported it, so the results for
+/// the same device may be split over several entries; for a capability
+/// that was probed more than once, the most recent result is kept.
+fn merge_duplicates(learned: LearnedQuirks) -> LearnedQuirks {
+    let mut merged = LearnedQuirks::new();
+    for (id, results) in learned {
+        let entry = merged.entry(normalize_device_id(&id)).or_default();
+        for (instance, result) in results {
+            match entry.get(&instance) {
+                Some(existing) if existing.probed >= result.probed => {}
+                _ => {
+                    entry.insert(instance, result);
+                }
+            }
+        }
+    }
+    merged
+}
+
+fn learned_quirks_file_name() -> PathBuf {
+    bundle_dir().join(LEARNED_QUIRKS_FILE_NAME)
+}
+
+/// Where the results were kept by earlier versions
+fn legacy_learned_quirks_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-learned-quirks.json")
+}
+
+static LEARNED: Lazy<Mutex<LearnedQuirks>> = Lazy::new(|| {
+    let mut path = learned_quirks_file_name();
+    if !path.exists() {
+        path = legacy_learned_quirks_file_name();
+    }
+    let quirks = match std::fs::read_to_string(&path) {
+        Ok(data) => serde_json::from_str(&data).unwrap_or_else(|err| {
+            log::error!("Ignoring learned quirks in {path:?}: {err:#}");
+            LearnedQuirks::new()
+        }),
+        Err(_) => LearnedQuirks::new(),
+    };
+    Mutex::new(merge_duplicates(quirks))
+});
+
+fn record_result(device_id: &str, instance: &str, result: ProbeResult) -> anyhow::Result<()> {
+    let mut learned = LEARNED.lock();
+    learned
+        .entry(normalize_device_id(device_id))
+        .or_default()
+        .insert(instance.to_string(), result);
+    let path = learned_quirks_file_name();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&*learned)?)
+        .with_context(|| format!("writing {path:?}"))
+}
+
+/// Returns the capabilities of the device which failed probing,
+/// along with the reason why
+pub fn unsupported_capabilities(device_id: &str) -> BTreeMap<String, String> {
+    LEARNED
+        .lock()
+        .get(&normalize_device_id(device_id))
+        .map(|results| {
+            results
+                .iter()
+                .filter(|(_, result)| !result.supported)
+                .map(|(instance, result)| (instance.to_string(), result.note.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns true if the entity with the specified unique id
+/// represents a capability that failed probing
+pub fn is_entity_unsupported(unique_id: &str) -> bool {
+    LEARNED
+        .lock()
+        .values()
+        .flat_map(|results| results.values())
+        .any(|result| !result.supported && result.unique_id == unique_id)
+}
+
+fn has_been_probed(device_id: &str, instance: &str) -> bool {
+    LEARNED
+        .lock()
+        .get(&normalize_device_id(device_id))
+        .map(|results| results.contains_key(instance))
+        .unwrap_or(false)
+}
+
+fn current_value(device: &Device, instance: &str) -> Option<JsonValue> {
+    let state = device.get_state_capability_by_instance(instance)?;
+    let value = state.state.get("value")?;
+    match value {
+        JsonValue::Null => None,
+        JsonValue::String(s) if s.is_empty() => None,
+        value => Some(value.clone()),
+    }
+}
+
+/// A color capability of a light is suspicious if the
+/// device doesn't report a value for it in its state
+fn is_suspicious(device: &Device, cap: &DeviceCapability) -> bool {
+    device.device_type() == DeviceType::Light
+        && cap.kind == DeviceCapabilityKind::ColorSetting
+        && PROBED_INSTANCES.contains(&cap.instance.as_str())
+        && current_value(device, &cap.instance).is_none()
+}
+
+/// Pick a value to send when probing the capability
+fn probe_value(cap: &DeviceCapability) -> Option<JsonValue> {
+    match cap.parameters.as_ref()? {
+        DeviceParameters::Integer { range, .. } => Some(((range.min + range.max) / 2).into()),
+        DeviceParameters::Enum { options } => options.last().map(|opt| opt.value.clone()),
+        DeviceParameters::Struct { .. } | DeviceParameters::Array { .. } => None,
+    }
+}
+
+async fn probe_capability(
+    state: &StateHandle,
+    device: &Device,
+    cap: &DeviceCapability,
+) -> anyhow::Result<Option<(bool, String)>> {
+    let Some(value) = probe_value(cap) else {
+        return Ok(None);
+    };
+    let client = state
+        .get_platform_client_for(device)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("probing requires the Platform API"))?;
+    let info = device
+        .http_device_info
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no Platform API metadata for {device}"))?;
+
+    if let Err(err) = client.control_device(info, cap, value.clone()).await {
+        return Ok(Some((
+            false,
+            format!("the device rejected {value}: {err:#}"),
+        )));
+    }
+
+    sleep(PROBE_SETTLE_TIME).await;
+    let http_state = client.get_device_state(info).await?;
+    let reported = http_state
+        .capability_by_instance(&cap.instance)
+        .and_then(|s| s.state.get("value").cloned());
+
+    Ok(Some(if reported.as_ref() == Some(&value) {
+        (true, format!("the device applied {value}"))
+    } else {
+        (
+            false,
+            format!(
+                "the device accepted {value} but reported {}",
+                reported.unwrap_or(JsonValue::Null)
+            ),
+        )
+    }))
+}
+
+/// Probe the suspicious capabilities of all devices that have
+/// not previously been probed
+pub async fn probe_capabilities(state: &StateHandle) -> anyhow::Result<()> {
+    for device in state.devices().await {
+        let Some(info) = &device.http_device_info else {
+            continue;
+        };
+
+        for cap in &info.capabilities {
+            if has_been_probed(&device.id, &cap.instance) || !is_suspicious(&device, cap) {
+                continue;
+            }
+            let Some(prior) = device.snapshot_light_state() else {
+                log::debug!(
+                    "Not probing {} of {device}, as its state isn't \
+                    known and so couldn't be restored",
+                    cap.instance
+                );
+                continue;
+            };
+
+            log::info!("Probing {} capability of {device}", cap.instance);
+            let outcome = probe_capability(state, &device, cap).await;
+            if let Err(err) = state.restore_light_state(&device, &prior).await {
+                log::error!("Restoring {device} after probing {}: {err:#}", cap.instance);
+            }
+            match outcome {
+                Ok(Some((supported, note))) => {
+                    if supported {
+                        log::info!("{device} supports {}: {note}", cap.instance);
+                    } else {
+                        log::warn!(
+                            "{device} doesn't appear to support {}: {note}. \
+                            Its entity will be disabled by default.",
+                            cap.instance
+                        );
+                    }
+                    record_result(
+                        &device.id,
+                        &cap.instance,
+                        ProbeResult {
+                            supported,
+                            note,
+                            unique_id: format!(
+                                "gv2mqtt-{id}-{inst}",
+                                id = topic_safe_id(&device),
+                                inst = cap.instance
+                            ),
+                            probed: Utc::now(),
+                        },
+                    )?;
+                }
+                Ok(None) => {
+                    log::debug!("Don't know how to probe {} of {device}", cap.instance);
+                }
+                Err(err) => {
+                    log::error!("Probing {} of {device}: {err:#}", cap.instance);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::HttpDeviceInfo;
+
+    fn light() -> Device {
+        let list: JsonValue =
+            serde_json::from_str(include_str!("../../test-data/list_devices.json")).unwrap();
+        let mut info: HttpDeviceInfo = serde_json::from_value(list["data"][0].clone()).unwrap();
+        info.device_type = DeviceType::Light;
+        let mut device = Device::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        device
+    }
+
+    #[test]
+    fn suspicious_capabilities() {
+        let device = light();
+        let cap = |instance: &str| device.get_capability_by_instance(instance).unwrap().clone();
+
+        // Without any reported state, the color capabilities are suspect,
+        // but other capabilities are never probed
+        assert!(is_suspicious(&device, &cap("colorTemperatureK")));
+        assert!(is_suspicious(&device, &cap("colorRgb")));
+        assert!(!is_suspicious(&device, &cap("gradientToggle")));
+        assert!(!is_suspicious(&device, &cap("brightness")));
+
+        // Only lights are probed
+        let mut heater = Device::new("H7130", "AA:BB:CC:DD:EE:FF:42:2B");
+        let mut info = device.http_device_info.clone().unwrap();
+        info.device_type = DeviceType::Heater;
+        heater.set_http_device_info(info);
+        assert!(!is_suspicious(&heater, &cap("colorTemperatureK")));
+    }
+
+    #[test]
+    fn probe_values() {
+        let device = light();
+        let kelvin = device
+            .get_capability_by_instance("colorTemperatureK")
+            .unwrap();
+        let range = kelvin.integer_range().unwrap();
+        assert_eq!(
+            probe_value(kelvin),
+            Some(JsonValue::from((range.min + range.max) / 2))
+        );
+        let music = device.get_capability_by_instance("musicMode").unwrap();
+        assert_eq!(probe_value(music), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! What to do with a command that failed because it had to be sent via
+//! the Govee cloud, which is the case for any device that isn't currently
+//! reachable via the LAN API. Users differ on this: some would rather the
+//! command was carried out late than not at all, while others would rather
+//! it was dropped than have a light change long after they asked. Cloud
+//! failure rules choose the policy for all devices, for a class of device,
+//! for the devices of a SKU or for an individual device.
+//!
+//! The device that a command was for is captured while the command is
+//! dispatched, so that a failed command can be attributed to its device
+//! without having to change each of the command handlers.
+use crate::service::cloud_health::{CloudUnavailable, RateLimited};
+use crate::service::device::Device;
+use crate::service::poll_intervals::parse_rule_list;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long a command may wait to be retried before it is given up on
+pub const MAX_COMMAND_AGE: Duration = Duration::from_secs(600);
+
+/// The delay between attempts at a queued command
+pub const QUEUE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CloudFailurePolicy {
+    /// Give up on the command, and fire the Command Failed event
+    #[default]
+    Drop,
+    /// Try the command again periodically, until it succeeds,
+    /// a newer command is sent to the device, or it expires
+    Queue,
+    /// Hold on to the command until the device can be reached
+    /// via the LAN API, then send it that way
+    LanOnly,
+}
+
+impl std::str::FromStr for CloudFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "drop" => Ok(Self::Drop),
+            "queue" => Ok(Self::Queue),
+            "lan" | "lan-only" | "lan_only" => Ok(Self::LanOnly),
+            s => anyhow::bail!("unknown cloud failure policy '{s}'. Expected drop, queue or lan"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloudFailureRule {
+    /// A device class, SKU, device id or device name,
+    /// or None for all devices
+    pub target: Option<String>,
+    pub policy: CloudFailurePolicy,
+}
+
+impl std::str::FromStr for CloudFailureRule {
+    type Err = anyhow::Error;
+
+    /// Parses `[TARGET=]POLICY`, where TARGET is a device class such
+    /// as `light` or `heater`, a SKU, a device id or a device name, or
+    /// `*` for all devices. eg: `queue`, `heater=drop`, `Porch Light=lan`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (target, policy) = match s.split_once('=') {
+            Some((target, policy)) => (target.trim(), policy),
+            None => ("*", s),
+        };
+        anyhow::ensure!(!target.is_empty(), "missing target in '{s}'");
+        Ok(Self {
+            target: (target != "*").then(|| target.to_string()),
+            policy: policy.parse()?,
+        })
+    }
+}
+
+/// The class of a device as used by the rules, eg: `light`
+fn device_class(device: &Device) -> String {
+    let device_type = serde_json::to_value(device.device_type()).ok();
+    let device_type = device_type
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    device_type
+        .strip_prefix("devices.types.")
+        .unwrap_or(device_type)
+        .to_string()
+}
+
+impl CloudFailureRule {
+    /// How specific the rule is for the device, or None if it
+    /// doesn't apply. Higher values are more specific.
+    fn specificity(&self, device: &Device) -> Option<u8> {
+        match &self.target {
+            None => Some(0),
+            Some(t) if t.eq_ignore_ascii_case(&device_class(device)) => Some(1),
+            Some(t) if t.eq_ignore_ascii_case(&device.sku) => Some(2),
+            Some(t)
+                if t.eq_ignore_ascii_case(&device.id) || t.eq_ignore_ascii_case(&device.name()) =>
+            {
+                Some(3)
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Parse a list of rules separated by semicolons or newlines
+pub fn parse_cloud_failure_rules(s: &str) -> anyhow::Result<Vec<CloudFailureRule>> {
+    parse_rule_list(s, "cloud failure rule")
+}
+
+static RULES: Lazy<Mutex<Vec<CloudFailureRule>>> = Lazy::new(Default::default);
+
+pub fn set_cloud_failure_rules(rules: Vec<CloudFailureRule>) {
+    *RULES.lock() = rules;
+}
+
+fn policy_from_rules(rules: &[CloudFailureRule], device: &Device) -> CloudFailurePolicy {
+    rules
+        .iter()
+        .filter_map(|rule| Some((rule.specificity(device)?, rule.policy)))
+        // The last of equally specific rules wins
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, policy)| policy)
+        .unwrap_or_default()
+}
+
+/// The policy for commands to `device` that fail via the cloud
+pub fn cloud_failure_policy(device: &Device) -> CloudFailurePolicy {
+    policy_from_rules(&RULES.lock(), device)
+}
+
+/// Returns true if `err` was caused by a failure to reach the Govee
+/// cloud, which may succeed if tried again later. Other errors, such
+/// as an invalid payload or an unknown scene, fail the same way each
+/// time, so retrying the command is pointless.
+pub fn is_cloud_failure(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<CloudUnavailable>()
+            || cause.is::<RateLimited>()
+            // A failure to publish via AWS IoT
+            || cause.is::<mosquitto_rs::Error>()
+            || cause.is::<tokio::time::error::Elapsed>()
+            || is_transport_error(cause)
+    })
+}
+
+#[cfg(feature = "cloud")]
+fn is_transport_error(cause: &(dyn std::error::Error + 'static)) -> bool {
+    cause.is::<reqwest::Error>()
+}
+
+#[cfg(not(feature = "cloud"))]
+fn is_transport_error(_cause: &(dyn std::error::Error + 'static)) -> bool {
+    false
+}
+
+/// The number of commands that have been dispatched to each device,
+/// so that a waiting command can tell that it has been superseded
+static COMMAND_SEQUENCE: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(Default::default);
+
+pub fn command_sequence(device_id: &str) -> u64 {
+    COMMAND_SEQUENCE
+        .lock()
+        .get(device_id)
+        .copied()
+        .unwrap_or_default()
+}
+
+tokio::task_local! {
+    static COMMAND_DEVICE: RefCell<Option<String>>;
+}
+
+/// Record that the command being dispatched is for `device_id`.
+/// Called when a device is resolved for control.
+pub fn note_command_device(device_id: &str) {
+    *COMMAND_SEQUENCE
+        .lock()
+        .entry(device_id.to_string())
+        .or_default() += 1;
+    let _ = COMMAND_DEVICE.try_with(|device| device.borrow_mut().replace(device_id.to_string()));
+}
+
+/// Run a command, returning its result along with the id of
+/// the device that it was for, if it got as far as resolving one
+pub async fn track_command_device<F: Future>(command: F) -> (F::Output, Option<String>) {
+    COMMAND_DEVICE
+        .scope(RefCell::new(None), async move {
+            let result = command.await;
+            let device_id = COMMAND_DEVICE.with(|device| device.borrow_mut().take());
+            (result, device_id)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rules =
+            parse_cloud_failure_rules("queue; light=lan;H7130=drop\nH6008_422A=queue").unwrap();
+        let porch = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:2A");
+        let heater = Device::new("H7130", "AA:BB:CC:DD:EE:FF:42:2B");
+        let lamp = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:2C");
+        let thermometer = Device::new("H5179", "AA:BB:CC:DD:EE:FF:42:2D");
+
+        assert_eq!(policy_from_rules(&rules, &porch), CloudFailurePolicy::Queue);
+        assert_eq!(policy_from_rules(&rules, &heater), CloudFailurePolicy::Drop);
+        assert_eq!(
+            policy_from_rules(&rules, &lamp),
+            CloudFailurePolicy::LanOnly
+        );
+        assert_eq!(
+            policy_from_rules(&rules, &thermometer),
+            CloudFailurePolicy::Queue
+        );
+        assert_eq!(policy_from_rules(&[], &lamp), CloudFailurePolicy::Drop);
+
+        assert!("light=sometimes".parse::<CloudFailureRule>().is_err());
+        assert!("=queue".parse::<CloudFailureRule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn cloud_failures() {
+        let endpoint = "cloud-failure-test";
+        for _ in 0..3 {
+            crate::service::cloud_health::record_failure(endpoint, "connection refused");
+        }
+        let unavailable = crate::service::cloud_health::check_endpoint(endpoint)
+            .unwrap_err()
+            .context("set_device_state");
+        assert!(is_cloud_failure(&unavailable));
+
+        let timed_out = tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(is_cloud_failure(
+            &anyhow::Error::new(timed_out).context("waiting for the device")
+        ));
+
+        let bad_payload = anyhow::anyhow!("invalid OFFF for AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!is_cloud_failure(&bad_payload));
+        let unknown_scene = anyhow::anyhow!("scene 'Sunrize' is not available for this device")
+            .context("device_set_scene");
+        assert!(!is_cloud_failure(&unknown_scene));
+    }
+}
@@ -0,0 +1,436 @@
+//! Tracks the health of the Govee cloud endpoints that we talk to.
+//! An endpoint is a host together with the first segment of the path,
+//! eg: `app2.govee.com/device`, as a single host serves several APIs
+//! that can fail independently of each other.
+//! Each endpoint has a circuit breaker: after several consecutive
+//! failed requests the circuit opens, and requests to that endpoint
+//! fail fast, without touching the network, until a backoff period
+//! has elapsed. A single request is then let through to probe the
+//! endpoint; if it succeeds the circuit closes again, otherwise the
+//! backoff is doubled. This keeps a Govee outage from producing a
+//! flood of slow requests and errors in the log.
+//!
+//! A request that is answered with 429 Too Many Requests is different:
+//! the endpoint is working, but we've been asked to back off. Rather than
+//! counting against the circuit breaker, the Retry-After of the response
+//! puts just that class of request, ie: the host and path, into a cooldown.
+//!
+//! The state of the endpoints is published to `gv2mqtt/bridge/cloud_status`.
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The name of the AWS IoT endpoint
+pub const IOT_ENDPOINT: &str = "aws-iot";
+
+pub const CLOUD_STATUS_TOPIC: &str = "gv2mqtt/bridge/cloud_status";
+
+/// How many consecutive failures open the circuit
+const FAILURE_THRESHOLD: u32 = 3;
+const INITIAL_OPEN_DURATION: Duration = Duration::from_secs(30);
+const MAX_OPEN_DURATION: Duration = Duration::from_secs(600);
+
+/// The cooldown after a 429 response that has no usable Retry-After
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// The longest cooldown that we'll accept from a Retry-After
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+/// Requests that would have to wait no longer than this for a cooldown
+/// to end wait it out; otherwise they fail fast with RateLimited
+pub const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// The endpoint that a request to `path` on `host` is made to
+pub fn endpoint_for(host: &str, path: &str) -> String {
+    match path.split('/').find(|segment| !segment.is_empty()) {
+        Some(segment) => format!("{host}/{segment}"),
+        None => host.to_string(),
+    }
+}
+
+/// Returned instead of making a request to an endpoint whose circuit is open
+#[derive(Error, Debug)]
+#[error("the Govee cloud endpoint {endpoint} is unavailable; retrying in {retry_in:?}")]
+pub struct CloudUnavailable {
+    endpoint: String,
+    retry_in: Duration,
+}
+
+impl CloudUnavailable {
+    pub fn is_cloud_unavailable(err: &anyhow::Error) -> bool {
+        err.root_cause().downcast_ref::<Self>().is_some()
+    }
+}
+
+/// Returned instead of making a request of a class that is cooling
+/// down after the endpoint responded with 429 Too Many Requests
+#[derive(Error, Debug)]
+#[error("the Govee cloud endpoint {endpoint} is rate limited; retrying in {retry_in:?}")]
+pub struct RateLimited {
+    endpoint: String,
+    retry_in: Duration,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests are made as usual
+    Closed,
+    /// Requests fail fast
+    Open,
+    /// A probe request is in flight
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    open_until: Instant,
+    open_for: Duration,
+    last_error: Option<String>,
+    degraded_since: Option<DateTime<Utc>>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            open_until: Instant::now(),
+            open_for: INITIAL_OPEN_DURATION,
+            last_error: None,
+            degraded_since: None,
+        }
+    }
+}
+
+impl Breaker {
+    /// Whether a request may be made now. Otherwise,
+    /// returns how long until the next probe.
+    fn allow(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::HalfOpen => Err(self.open_for),
+            CircuitState::Open if now >= self.open_until => {
+                self.state = CircuitState::HalfOpen;
+                Ok(())
+            }
+            CircuitState::Open => Err(self.open_until - now),
+        }
+    }
+
+    /// Returns true if the circuit closed as a result
+    fn success(&mut self) -> bool {
+        let was_degraded = self.state != CircuitState::Closed;
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.open_for = INITIAL_OPEN_DURATION;
+        self.last_error = None;
+        self.degraded_since = None;
+        was_degraded
+    }
+
+    /// Returns the time for which the circuit opened, if it did
+    fn failure(&mut self, now: Instant, error: String, jitter: f64) -> Option<Duration> {
+        self.consecutive_failures += 1;
+        self.last_error.replace(error);
+        let open = match self.state {
+            CircuitState::Closed => self.consecutive_failures >= FAILURE_THRESHOLD,
+            CircuitState::HalfOpen => {
+                self.open_for = (self.open_for * 2).min(MAX_OPEN_DURATION);
+                true
+            }
+            CircuitState::Open => false,
+        };
+        if !open {
+            return None;
+        }
+        let open_for = self.open_for.mul_f64(1. + jitter.clamp(-0.2, 0.2));
+        self.state = CircuitState::Open;
+        self.open_until = now + open_for;
+        self.degraded_since.get_or_insert_with(Utc::now);
+        Some(open_for)
+    }
+}
+
+static BREAKERS: Lazy<Mutex<BTreeMap<String, Breaker>>> = Lazy::new(Default::default);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn changed() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Incremented whenever the status of an endpoint changes
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Fail with CloudUnavailable if requests to the endpoint
+/// should not be made right now
+pub fn check_endpoint(endpoint: &str) -> anyhow::Result<()> {
+    let mut breakers = BREAKERS.lock();
+    let breaker = breakers.entry(endpoint.to_string()).or_default();
+    let was = breaker.state;
+    let result = breaker.allow(Instant::now());
+    if breaker.state != was {
+        changed();
+    }
+    result.map_err(|retry_in| {
+        CloudUnavailable {
+            endpoint: endpoint.to_string(),
+            retry_in,
+        }
+        .into()
+    })
+}
+
+pub fn record_success(endpoint: &str) {
+    let mut breakers = BREAKERS.lock();
+    let breaker = breakers.entry(endpoint.to_string()).or_default();
+    if breaker.success() {
+        log::info!("Govee cloud endpoint {endpoint} has recovered");
+        changed();
+    }
+}
+
+pub fn record_failure(endpoint: &str, error: &str) {
+    let jitter = rand::thread_rng().gen_range(-1.0..=1.0) * 0.2;
+    let mut breakers = BREAKERS.lock();
+    let breaker = breakers.entry(endpoint.to_string()).or_default();
+    if let Some(open_for) = breaker.failure(Instant::now(), error.to_string(), jitter) {
+        log::warn!(
+            "Govee cloud endpoint {endpoint} is failing ({error}); \
+            pausing requests to it for {open_for:?}"
+        );
+        changed();
+    }
+}
+
+/// Parse the value of a Retry-After header, which is either a number of
+/// seconds or an HTTP date, into how long to wait from `now`
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let when = DateTime::parse_from_rfc2822(value).ok()?;
+            (when.with_timezone(&Utc) - now)
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(MAX_RETRY_AFTER))
+}
+
+#[derive(Clone, Copy)]
+struct Cooldown {
+    until: Instant,
+    /// `until`, as the wall clock time to report
+    reported_until: DateTime<Utc>,
+}
+
+/// The cooldown of each class of request that was rate limited
+static COOLDOWNS: Lazy<Mutex<BTreeMap<String, Cooldown>>> = Lazy::new(Default::default);
+
+/// Record that a request of `class` was answered with 429, and the
+/// Retry-After of the response, if it had a usable one
+pub fn record_rate_limited(class: &str, retry_after: Option<Duration>) -> Duration {
+    let retry_after = retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+    let until = Instant::now() + retry_after;
+    let mut cooldowns = COOLDOWNS.lock();
+    // A later response may not shorten a cooldown
+    let extends = cooldowns
+        .get(class)
+        .map(|existing| until > existing.until)
+        .unwrap_or(true);
+    if extends {
+        let reported_until =
+            Utc::now() + chrono::Duration::from_std(retry_after).unwrap_or_default();
+        cooldowns.insert(
+            class.to_string(),
+            Cooldown {
+                until,
+                reported_until,
+            },
+        );
+    }
+    log::warn!("Govee cloud endpoint {class} is rate limiting us; pausing it for {retry_after:?}");
+    changed();
+    retry_after
+}
+
+/// How long until requests of `class` may be made again, if they
+/// are cooling down
+pub fn rate_limit_cooldown(class: &str) -> Option<Duration> {
+    let cooldown = COOLDOWNS.lock().get(class).copied()?;
+    cooldown
+        .until
+        .checked_duration_since(Instant::now())
+        .filter(|d| !d.is_zero())
+}
+
+pub fn rate_limited_error(class: &str, retry_in: Duration) -> anyhow::Error {
+    RateLimited {
+        endpoint: class.to_string(),
+        retry_in,
+    }
+    .into()
+}
+
+/// Forget the cooldowns that have ended
+pub fn expire_cooldowns() {
+    let now = Instant::now();
+    let mut cooldowns = COOLDOWNS.lock();
+    let before = cooldowns.len();
+    cooldowns.retain(|_, cooldown| cooldown.until > now);
+    if cooldowns.len() != before {
+        changed();
+    }
+}
+
+/// The classes of request that are cooling down, and when they end
+pub fn rate_limit_cooldowns() -> BTreeMap<String, DateTime<Utc>> {
+    let now = Instant::now();
+    COOLDOWNS
+        .lock()
+        .iter()
+        .filter(|(_, cooldown)| cooldown.until > now)
+        .map(|(class, cooldown)| (class.to_string(), cooldown.reported_until))
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub degraded_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CloudStatus {
+    /// `ok` or `degraded`
+    pub status: &'static str,
+    pub endpoints: BTreeMap<String, EndpointStatus>,
+    /// The classes of request that are cooling down after a
+    /// 429 response, and when they may be made again
+    pub rate_limited: BTreeMap<String, DateTime<Utc>>,
+}
+
+pub fn cloud_status() -> CloudStatus {
+    let breakers = BREAKERS.lock();
+    let endpoints: BTreeMap<String, EndpointStatus> = breakers
+        .iter()
+        .map(|(endpoint, breaker)| {
+            (
+                endpoint.to_string(),
+                EndpointStatus {
+                    state: breaker.state,
+                    consecutive_failures: breaker.consecutive_failures,
+                    last_error: breaker.last_error.clone(),
+                    degraded_since: breaker.degraded_since,
+                },
+            )
+        })
+        .collect();
+    drop(breakers);
+    let rate_limited = rate_limit_cooldowns();
+    let degraded =
+        endpoints.values().any(|e| e.state != CircuitState::Closed) || !rate_limited.is_empty();
+    CloudStatus {
+        status: if degraded { "degraded" } else { "ok" },
+        endpoints,
+        rate_limited,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker() {
+        let start = Instant::now();
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.allow(start), Ok(()));
+
+        assert_eq!(breaker.failure(start, "500".to_string(), 0.), None);
+        assert_eq!(breaker.failure(start, "500".to_string(), 0.), None);
+        assert_eq!(
+            breaker.failure(start, "500".to_string(), 0.),
+            Some(INITIAL_OPEN_DURATION)
+        );
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert!(breaker.allow(start + Duration::from_secs(10)).is_err());
+
+        // A single probe is let through once the circuit has been open long enough
+        let probe = start + INITIAL_OPEN_DURATION;
+        assert_eq!(breaker.allow(probe), Ok(()));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        assert!(breaker.allow(probe).is_err());
+
+        // A failed probe doubles the backoff
+        assert_eq!(
+            breaker.failure(probe, "timeout".to_string(), 0.),
+            Some(INITIAL_OPEN_DURATION * 2)
+        );
+        let probe = probe + INITIAL_OPEN_DURATION * 2;
+        assert_eq!(breaker.allow(probe), Ok(()));
+        assert!(breaker.success());
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.open_for, INITIAL_OPEN_DURATION);
+        assert!(!breaker.success());
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("86400", now), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            endpoint_for("app2.govee.com", "/device/rest/devices/v1/list"),
+            "app2.govee.com/device"
+        );
+        assert_eq!(
+            endpoint_for("app2.govee.com", "/account/rest/account/v1/login"),
+            "app2.govee.com/account"
+        );
+        assert_eq!(endpoint_for("app2.govee.com", "/"), "app2.govee.com");
+    }
+
+    #[test]
+    fn rate_limit_cooldown_is_per_class() {
+        let class = "openapi.api.govee.com/router/api/v1/device/state";
+        record_rate_limited(class, Some(Duration::from_secs(45)));
+        let remaining = rate_limit_cooldown(class).unwrap();
+        assert!(remaining > Duration::from_secs(40) && remaining <= Duration::from_secs(45));
+        assert!(rate_limit_cooldowns().contains_key(class));
+        assert_eq!(
+            rate_limit_cooldown("openapi.api.govee.com/router/api/v1/device/control"),
+            None
+        );
+    }
+}
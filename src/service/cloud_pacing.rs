@@ -0,0 +1,172 @@
+//! Recurring requests to the Govee cloud that are made with robotic
+//! regularity have been reported to get accounts flagged and
+//! throttled. Cloud pacing adds random jitter to the intervals
+//! between those requests, and can stretch them out during a daily
+//! window of quiet hours, such as overnight, when a person would
+//! rarely be using the Govee app.
+use crate::service::daily_window::DailyWindow;
+use chrono::{Local, NaiveTime};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct CloudPacing {
+    /// Vary each interval randomly by up to this percentage
+    pub jitter_percent: u8,
+    /// A daily window of local time during which to poll less often
+    pub quiet_hours: Option<DailyWindow>,
+    /// How much longer to make intervals during the quiet hours
+    pub quiet_factor: u32,
+}
+
+impl CloudPacing {
+    /// A preset that is unlikely to resemble automation
+    pub fn conservative() -> Self {
+        Self {
+            jitter_percent: 30,
+            quiet_hours: "01:00-06:00".parse().ok(),
+            quiet_factor: 4,
+        }
+    }
+}
+
+impl std::str::FromStr for CloudPacing {
+    type Err = anyhow::Error;
+
+    /// Parses either a preset name, `off` or `conservative`, or a list
+    /// of `jitter=20,quiet=01:00-06:00,quiet_factor=3`. The list may
+    /// follow a preset to adjust it, eg: `conservative,jitter=10`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut pacing = Self::default();
+        for item in s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|s| !s.is_empty())
+        {
+            match item {
+                "off" => pacing = Self::default(),
+                "conservative" => pacing = Self::conservative(),
+                _ => {
+                    let (key, value) = item
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("expected key=value, got '{item}'"))?;
+                    let value = value.trim();
+                    match key.trim() {
+                        "jitter" => {
+                            pacing.jitter_percent = value.parse()?;
+                            anyhow::ensure!(
+                                pacing.jitter_percent <= 90,
+                                "jitter must be no more than 90 percent"
+                            );
+                        }
+                        "quiet" => pacing.quiet_hours = Some(value.parse()?),
+                        "quiet_factor" => pacing.quiet_factor = value.parse()?,
+                        key => anyhow::bail!(
+                            "unknown cloud pacing key '{key}'. \
+                            Expected one of jitter, quiet or quiet_factor"
+                        ),
+                    }
+                }
+            }
+        }
+        if pacing.quiet_hours.is_some() && pacing.quiet_factor == 0 {
+            pacing.quiet_factor = 2;
+        }
+        Ok(pacing)
+    }
+}
+
+impl CloudPacing {
+    /// Shape an interval according to the pacing, given the local
+    /// time of day and a random number in the range -1.0..=1.0
+    pub fn shape_at(&self, interval: Duration, now: NaiveTime, random: f64) -> Duration {
+        let mut interval = interval;
+        if let Some(quiet) = &self.quiet_hours {
+            if quiet.contains(now) {
+                interval *= self.quiet_factor.max(1);
+            }
+        }
+        let jitter = random.clamp(-1., 1.) * self.jitter_percent as f64 / 100.;
+        interval.mul_f64(1. + jitter)
+    }
+
+    /// Shape an interval for the current local time of day
+    pub fn shape(&self, interval: Duration) -> Duration {
+        if *self == Self::default() {
+            return interval;
+        }
+        let random = rand::thread_rng().gen_range(-1.0..=1.0);
+        self.shape_at(interval, Local::now().time(), random)
+    }
+}
+
+static PACING: Lazy<Mutex<CloudPacing>> = Lazy::new(Default::default);
+
+pub fn set_cloud_pacing(pacing: CloudPacing) {
+    *PACING.lock() = pacing;
+}
+
+pub fn cloud_pacing() -> CloudPacing {
+    *PACING.lock()
+}
+
+/// Sleep for an interval between recurring cloud requests,
+/// shaped according to the configured pacing
+pub async fn paced_sleep(interval: Duration) {
+    tokio::time::sleep(cloud_pacing().shape(interval)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_pacing() {
+        assert_eq!(
+            "off".parse::<CloudPacing>().unwrap(),
+            CloudPacing::default()
+        );
+        assert_eq!(
+            "conservative".parse::<CloudPacing>().unwrap(),
+            CloudPacing::conservative()
+        );
+
+        let pacing: CloudPacing = "conservative, jitter=10".parse().unwrap();
+        assert_eq!(pacing.jitter_percent, 10);
+        assert_eq!(pacing.quiet_factor, 4);
+
+        let pacing: CloudPacing = "quiet=23:00-07:00".parse().unwrap();
+        assert_eq!(pacing.jitter_percent, 0);
+        assert_eq!(pacing.quiet_factor, 2);
+
+        assert!("jitter=95".parse::<CloudPacing>().is_err());
+        assert!("wobble=1".parse::<CloudPacing>().is_err());
+        assert!("sometimes".parse::<CloudPacing>().is_err());
+    }
+
+    #[test]
+    fn shape_interval() {
+        let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let minute = Duration::from_secs(60);
+        let pacing = CloudPacing::conservative();
+
+        assert_eq!(pacing.shape_at(minute, t("12:00"), 0.), minute);
+        assert_eq!(
+            pacing.shape_at(minute, t("12:00"), 1.),
+            Duration::from_secs(78)
+        );
+        assert_eq!(
+            pacing.shape_at(minute, t("12:00"), -1.),
+            Duration::from_secs(42)
+        );
+        assert_eq!(
+            pacing.shape_at(minute, t("03:00"), 0.),
+            Duration::from_secs(240)
+        );
+
+        // The default pacing leaves intervals alone
+        assert_eq!(CloudPacing::default().shape(minute), minute);
+    }
+}
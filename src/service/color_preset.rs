@@ -0,0 +1,156 @@
+//! Named color presets, such as "Movie amber" or "Reading white",
+//! are offered as a select entity on each light, so that common
+//! colors can be applied from dashboards and voice assistants
+//! without having to remember their RGB values.
+use crate::lan_api::DeviceColor;
+use crate::service::device::Device;
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresetColor {
+    Rgb(DeviceColor),
+    Kelvin(u32),
+}
+
+impl std::str::FromStr for PresetColor {
+    type Err = anyhow::Error;
+
+    /// Parses a color temperature such as `4000K`, or any CSS color,
+    /// such as `#ffbf00`, `rgb(255,191,0)` or `orange`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if let Some(kelvin) = s.strip_suffix(['K', 'k']) {
+            if let Ok(kelvin) = kelvin.trim().parse::<u32>() {
+                return Ok(Self::Kelvin(kelvin));
+            }
+        }
+        let color = csscolorparser::parse(s).with_context(|| format!("parsing color '{s}'"))?;
+        let [r, g, b, _a] = color.to_rgba8();
+        Ok(Self::Rgb(DeviceColor { r, g, b }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColorPreset {
+    /// The id or name of the device that the preset applies to,
+    /// or None if it applies to all devices
+    pub device: Option<String>,
+    pub name: String,
+    pub color: PresetColor,
+    /// The brightness to set along with the color, if any
+    pub brightness: Option<u8>,
+}
+
+impl std::str::FromStr for ColorPreset {
+    type Err = anyhow::Error;
+
+    /// Parses `[Device:]Name=Color[@Brightness%]`, eg:
+    /// `Movie amber=#ffbf00@40%` or `Desk Lamp:Reading white=4000K`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (label, spec) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected Name=Color, got '{s}'"))?;
+
+        // Device ids contain colons, so the name follows the last one
+        let (device, name) = match label.rsplit_once(':') {
+            Some((device, name)) => (Some(device.trim().to_string()), name.trim()),
+            None => (None, label.trim()),
+        };
+        anyhow::ensure!(!name.is_empty(), "missing preset name in '{s}'");
+        if let Some(device) = &device {
+            anyhow::ensure!(!device.is_empty(), "missing device name in '{s}'");
+        }
+
+        let (color, brightness) = match spec.rsplit_once('@') {
+            Some((color, brightness)) => {
+                let brightness = brightness.trim();
+                let brightness: u8 = brightness
+                    .strip_suffix('%')
+                    .unwrap_or(brightness)
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("parsing brightness in '{s}'"))?;
+                anyhow::ensure!(brightness <= 100, "brightness must be 0-100 in '{s}'");
+                (color, Some(brightness))
+            }
+            None => (spec, None),
+        };
+
+        Ok(Self {
+            device,
+            name: name.to_string(),
+            color: color.parse()?,
+            brightness,
+        })
+    }
+}
+
+impl ColorPreset {
+    pub fn applies_to(&self, device: &Device) -> bool {
+        let matches_device = match &self.device {
+            None => true,
+            Some(label) => {
+                label.eq_ignore_ascii_case(&device.id) || label.eq_ignore_ascii_case(&device.name())
+            }
+        };
+        let supported = match self.color {
+            PresetColor::Rgb(_) => device.supports_rgb(),
+            PresetColor::Kelvin(_) => device.get_color_temperature_range().is_some(),
+        };
+        matches_device && supported
+    }
+}
+
+/// Returns the presets that apply to a device, in the order that
+/// they were defined. A preset defined for the device replaces a
+/// global preset that has the same name.
+pub fn presets_for_device(presets: &[ColorPreset], device: &Device) -> Vec<ColorPreset> {
+    let applicable: Vec<&ColorPreset> = presets.iter().filter(|p| p.applies_to(device)).collect();
+    applicable
+        .iter()
+        .filter(|preset| {
+            preset.device.is_some()
+                || !applicable
+                    .iter()
+                    .any(|other| other.device.is_some() && other.name == preset.name)
+        })
+        .map(|preset| (*preset).clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_preset() {
+        let preset: ColorPreset = "Movie amber=#ffbf00@40%".parse().unwrap();
+        assert_eq!(preset.device, None);
+        assert_eq!(preset.name, "Movie amber");
+        assert_eq!(
+            preset.color,
+            PresetColor::Rgb(DeviceColor {
+                r: 255,
+                g: 191,
+                b: 0
+            })
+        );
+        assert_eq!(preset.brightness, Some(40));
+
+        let preset: ColorPreset = "Desk Lamp:Reading white = 4000K".parse().unwrap();
+        assert_eq!(preset.device.as_deref(), Some("Desk Lamp"));
+        assert_eq!(preset.name, "Reading white");
+        assert_eq!(preset.color, PresetColor::Kelvin(4000));
+        assert_eq!(preset.brightness, None);
+
+        let preset: ColorPreset = "AA:BB:CC:DD:EE:FF:00:11:Red=red".parse().unwrap();
+        assert_eq!(preset.device.as_deref(), Some("AA:BB:CC:DD:EE:FF:00:11"));
+        assert_eq!(preset.name, "Red");
+
+        assert!("Movie amber".parse::<ColorPreset>().is_err());
+        assert!("=red".parse::<ColorPreset>().is_err());
+        assert!(":Red=red".parse::<ColorPreset>().is_err());
+        assert!("Red=notacolor".parse::<ColorPreset>().is_err());
+        assert!("Red=red@150%".parse::<ColorPreset>().is_err());
+    }
+}
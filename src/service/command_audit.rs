@@ -0,0 +1,147 @@
+//! A record of the commands most recently sent to devices, for
+//! debugging why a device didn't respond. Each entry notes which
+//! transport carried the command, what was sent, whether it could
+//! be sent, and how long that took, including any time spent waiting
+//! for the command pacing or, for the IoT API, the publish queue.
+//!
+//! Sending a command successfully only means that it left govee2mqtt:
+//! LAN commands are fire-and-forget UDP datagrams, and IoT commands
+//! are published without acknowledgement, so a device that is
+//! unreachable will still show a successful LAN or IoT send.
+//!
+//! The entries are available from `/api/commands` and are published
+//! to `gv2mqtt/bridge/command_audit`.
+use crate::service::device::normalize_device_id;
+use crate::service::state_merge::Transport;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+pub const COMMAND_AUDIT_TOPIC: &str = "gv2mqtt/bridge/command_audit";
+
+/// How many commands are kept
+const MAX_ENTRIES: usize = 200;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub device_id: String,
+    pub sku: String,
+    pub transport: Transport,
+    pub payload: JsonValue,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+static ENTRIES: Lazy<Mutex<VecDeque<AuditEntry>>> = Lazy::new(Default::default);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A command that is on its way to a device. Call `finish` once the
+/// outcome of sending it is known to add it to the audit log.
+#[derive(Debug)]
+pub struct PendingCommand {
+    time: DateTime<Utc>,
+    started: Instant,
+    device_id: String,
+    sku: String,
+    transport: Transport,
+    payload: JsonValue,
+}
+
+impl PendingCommand {
+    pub fn start<P: Serialize>(
+        transport: Transport,
+        device_id: &str,
+        sku: &str,
+        payload: P,
+    ) -> Self {
+        Self {
+            time: Utc::now(),
+            started: Instant::now(),
+            device_id: normalize_device_id(device_id),
+            sku: sku.to_string(),
+            transport,
+            payload: serde_json::to_value(payload).unwrap_or(JsonValue::Null),
+        }
+    }
+
+    pub fn finish<T>(self, result: &anyhow::Result<T>) {
+        let entry = AuditEntry {
+            time: self.time,
+            device_id: self.device_id,
+            sku: self.sku,
+            transport: self.transport,
+            payload: self.payload,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+            latency_ms: self.started.elapsed().as_millis() as u64,
+        };
+
+        let mut entries = ENTRIES.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Changes whenever a command is recorded
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// The recorded commands, oldest first, optionally only
+/// those that were sent to a particular device
+pub fn recent_commands(device_id: Option<&str>) -> Vec<AuditEntry> {
+    let device_id = device_id.map(normalize_device_id);
+    ENTRIES
+        .lock()
+        .iter()
+        .filter(|entry| {
+            device_id
+                .as_ref()
+                .map(|id| *id == entry.device_id)
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn audit_commands() {
+        let device = "AA:BB:CC:DD:EE:FF:42:A1";
+        let before = generation();
+
+        PendingCommand::start(
+            Transport::Lan,
+            device,
+            "H6000",
+            serde_json::json!({"cmd": "turn"}),
+        )
+        .finish(&anyhow::Result::<()>::Ok(()));
+        PendingCommand::start(Transport::Platform, device, "H6000", "brightness").finish(
+            &anyhow::Result::<()>::Err(anyhow::anyhow!("device offline")),
+        );
+
+        assert!(generation() >= before + 2);
+        let entries = recent_commands(Some("aabbccddeeff42a1"));
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].ok);
+        assert_eq!(entries[0].transport, Transport::Lan);
+        assert_eq!(entries[0].payload, serde_json::json!({"cmd": "turn"}));
+        assert!(!entries[1].ok);
+        assert_eq!(entries[1].error.as_deref(), Some("device offline"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Dragging a slider in hass produces a rapid stream of commands for
+//! the same device. Sending each of them means that they queue up
+//! behind one another, and the device is still stepping through stale
+//! values long after the slider was released.
+//!
+//! Commands that replace the value of a capability take a ticket when
+//! they arrive, and wait for a short window before they are processed.
+//! A command whose ticket has been superseded by a newer command for
+//! the same capability of the same device, either during that window
+//! or while waiting behind another command for the device, is dropped,
+//! so that only the latest value is sent.
+//!
+//! Separately, commands sent to a device via a given transport are
+//! spaced out by a configurable minimum delay.
+use crate::service::playlist::parse_duration;
+use crate::service::state_merge::Transport;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandQueueConfig {
+    /// How long to wait for a newer command for the same capability
+    pub window: Duration,
+    pub lan_delay: Duration,
+    pub iot_delay: Duration,
+    pub platform_delay: Duration,
+}
+
+impl Default for CommandQueueConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::ZERO,
+            lan_delay: Duration::ZERO,
+            iot_delay: Duration::ZERO,
+            platform_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl CommandQueueConfig {
+    pub fn delay_for(&self, transport: Transport) -> Duration {
+        match transport {
+            Transport::Lan => self.lan_delay,
+            Transport::Iot => self.iot_delay,
+            Transport::Platform => self.platform_delay,
+        }
+    }
+}
+
+impl std::str::FromStr for CommandQueueConfig {
+    type Err = anyhow::Error;
+
+    /// Parses a list in the form `window=0.2s,lan=0.1s,iot=0.5s,platform=1s`.
+    /// Keys that are not given keep their default value.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for item in s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|s| !s.is_empty())
+        {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value, got '{item}'"))?;
+            let value = parse_duration(value)?;
+            match key.trim() {
+                "window" => config.window = value,
+                "lan" => config.lan_delay = value,
+                "iot" => config.iot_delay = value,
+                "platform" => config.platform_delay = value,
+                key => anyhow::bail!(
+                    "unknown command queue key '{key}'. \
+                    Expected one of window, lan, iot or platform"
+                ),
+            }
+        }
+        Ok(config)
+    }
+}
+
+static CONFIG: Lazy<Mutex<CommandQueueConfig>> = Lazy::new(Default::default);
+
+pub fn set_command_queue_config(config: CommandQueueConfig) {
+    *CONFIG.lock() = config;
+}
+
+pub fn command_queue_config() -> CommandQueueConfig {
+    *CONFIG.lock()
+}
+
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+/// The most recent ticket for each device and capability
+static LATEST: Lazy<Mutex<HashMap<(String, String), u64>>> = Lazy::new(Default::default);
+
+/// Holds the place of a command in the queue of a device
+pub struct CommandTicket {
+    key: (String, String),
+    ticket: u64,
+}
+
+impl CommandTicket {
+    pub fn new(device_id: &str, capability: &str) -> Self {
+        let key = (device_id.to_string(), capability.to_string());
+        let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+        LATEST.lock().insert(key.clone(), ticket);
+        Self { key, ticket }
+    }
+
+    /// Whether a newer command for the same capability has arrived
+    pub fn is_superseded(&self) -> bool {
+        LATEST.lock().get(&self.key) != Some(&self.ticket)
+    }
+}
+
+impl Drop for CommandTicket {
+    fn drop(&mut self) {
+        let mut latest = LATEST.lock();
+        if latest.get(&self.key) == Some(&self.ticket) {
+            latest.remove(&self.key);
+        }
+    }
+}
+
+/// When each device may next be sent a command via each transport
+static NEXT_SEND: Lazy<Mutex<HashMap<(Transport, String), Instant>>> = Lazy::new(Default::default);
+
+/// Reserve a slot for a command, returning when it may be sent
+fn reserve_send(transport: Transport, device_id: &str, delay: Duration, now: Instant) -> Instant {
+    let mut next_send = NEXT_SEND.lock();
+    let next = next_send
+        .entry((transport, device_id.to_string()))
+        .or_insert(now);
+    let when = (*next).max(now);
+    *next = when + delay;
+    when
+}
+
+/// Wait until a command may be sent to the device via `transport`,
+/// according to the configured minimum delay between commands
+pub async fn pace_command(transport: Transport, device_id: &str) {
+    let delay = command_queue_config().delay_for(transport);
+    if delay.is_zero() {
+        return;
+    }
+    let when = reserve_send(transport, device_id, delay, Instant::now());
+    if when > Instant::now() {
+        log::trace!("Delaying {transport:?} command for {device_id} until {when:?}");
+        tokio::time::sleep_until(when).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(
+            "".parse::<CommandQueueConfig>().unwrap(),
+            CommandQueueConfig::default()
+        );
+        let config: CommandQueueConfig = "window=0.25s, platform=1s".parse().unwrap();
+        assert_eq!(config.window, Duration::from_millis(250));
+        assert_eq!(
+            config.delay_for(Transport::Platform),
+            Duration::from_secs(1)
+        );
+        assert_eq!(config.delay_for(Transport::Lan), Duration::ZERO);
+        assert!("wobble=1s".parse::<CommandQueueConfig>().is_err());
+        assert!("lan".parse::<CommandQueueConfig>().is_err());
+    }
+
+    #[test]
+    fn tickets() {
+        let first = CommandTicket::new("test-device", "brightness");
+        let other = CommandTicket::new("test-device", "color");
+        assert!(!first.is_superseded());
+        let second = CommandTicket::new("test-device", "brightness");
+        assert!(first.is_superseded());
+        assert!(!second.is_superseded());
+        assert!(!other.is_superseded());
+
+        // Dropping a superseded ticket leaves the newer one alone
+        drop(first);
+        assert!(!second.is_superseded());
+        drop(second);
+        assert!(!LATEST
+            .lock()
+            .contains_key(&("test-device".to_string(), "brightness".to_string())));
+    }
+
+    #[test]
+    fn pacing() {
+        let now = Instant::now();
+        let delay = Duration::from_millis(500);
+        assert_eq!(reserve_send(Transport::Iot, "test-pace", delay, now), now);
+        assert_eq!(
+            reserve_send(Transport::Iot, "test-pace", delay, now),
+            now + delay
+        );
+        assert_eq!(
+            reserve_send(Transport::Iot, "test-pace", delay, now),
+            now + delay * 2
+        );
+        // Transports are paced independently
+        assert_eq!(reserve_send(Transport::Lan, "test-pace", delay, now), now);
+        // Once the delay has passed, commands are sent right away
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            reserve_send(Transport::Iot, "test-pace", delay, later),
+            later
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! A YAML or TOML configuration file, for those who would rather not
+//! pass dozens of options on the command line or via the environment.
+//! Its keys are the same as the options of the Home Assistant addon,
+//! eg: `mqtt_host` or `poll_intervals`. Files whose name ends in
+//! `.toml` are parsed as TOML, and any others as YAML.
+//!
+//! Every option that can be set in the file can also be set via an
+//! environment variable, and the argument structs already fall back
+//! to those when an option isn't passed on the command line. So the
+//! file is applied by setting the environment variables that aren't
+//! already set, which gives the command line precedence over the
+//! environment, and the environment precedence over the file.
+//! That happens before the tokio runtime, and its threads, are started.
+use anyhow::Context;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// How the value of an option is held in the environment
+#[derive(Clone, Copy)]
+enum Format {
+    /// A string, number or boolean
+    Scalar,
+    /// A scalar, or a list of them joined with the given separator
+    List(&'static str),
+    /// A whole section of the file, held as JSON
+    Document,
+}
+use Format::*;
+
+/// The options that may be set in the file, along with the
+/// environment variable that each of them maps to
+const OPTIONS: &[(&str, &str, Format)] = &[
+    ("govee_email", "GOVEE_EMAIL", Scalar),
+    ("govee_password", "GOVEE_PASSWORD", Scalar),
+    ("govee_api_key", "GOVEE_API_KEY", Scalar),
+    ("govee_accounts", "GOVEE_ACCOUNTS", List(",")),
+    ("token_file", "GOVEE_TOKEN_FILE", Scalar),
+    ("iot_publish_rate", "GOVEE_IOT_PUBLISH_RATE", Scalar),
+    (
+        "iot_publish_concurrency",
+        "GOVEE_IOT_PUBLISH_CONCURRENCY",
+        Scalar,
+    ),
+    ("log_sensitive_data", "GOVEE_LOG_SENSITIVE_DATA", Scalar),
+    ("mqtt_host", "GOVEE_MQTT_HOST", Scalar),
+    ("mqtt_port", "GOVEE_MQTT_PORT", Scalar),
+    ("mqtt_username", "GOVEE_MQTT_USER", Scalar),
+    ("mqtt_password", "GOVEE_MQTT_PASSWORD", Scalar),
+    ("mqtt_broker_listen", "GOVEE_MQTT_BROKER_LISTEN", Scalar),
+    ("mqtt_mirror_host", "GOVEE_MQTT_MIRROR_HOST", Scalar),
+    ("mqtt_mirror_port", "GOVEE_MQTT_MIRROR_PORT", Scalar),
+    ("mqtt_mirror_username", "GOVEE_MQTT_MIRROR_USER", Scalar),
+    ("mqtt_mirror_password", "GOVEE_MQTT_MIRROR_PASSWORD", Scalar),
+    ("hass_birth_topic", "GOVEE_HASS_BIRTH_TOPIC", Scalar),
+    ("hass_birth_payload", "GOVEE_HASS_BIRTH_PAYLOAD", Scalar),
+    ("temperature_scale", "GOVEE_TEMPERATURE_SCALE", Scalar),
+    ("no_multicast", "GOVEE_LAN_NO_MULTICAST", Scalar),
+    ("broadcast_all", "GOVEE_LAN_BROADCAST_ALL", Scalar),
+    ("global_broadcast", "GOVEE_LAN_BROADCAST_GLOBAL", Scalar),
+    ("scan", "GOVEE_LAN_SCAN", List(",")),
+    (
+        "multicast_interfaces",
+        "GOVEE_LAN_MULTICAST_INTERFACES",
+        List(","),
+    ),
+    ("scan_interval", "GOVEE_LAN_SCAN_INTERVAL", Scalar),
+    ("lan_disco_timeout", "GOVEE_LAN_DISCO_TIMEOUT", Scalar),
+    ("scene_icon_base_url", "GOVEE_SCENE_ICON_BASE_URL", Scalar),
+    (
+        "restore_after_power_loss",
+        "GOVEE_RESTORE_AFTER_POWER_LOSS",
+        List(","),
+    ),
+    ("segment_layouts", "GOVEE_SEGMENT_LAYOUTS", List(";")),
+    ("color_presets", "GOVEE_COLOR_PRESETS", List(";")),
+    ("scene_filters", "GOVEE_SCENE_FILTERS", List(";")),
+    (
+        "scene_select_per_category",
+        "GOVEE_SCENE_SELECT_PER_CATEGORY",
+        Scalar,
+    ),
+    ("derived_sensors", "GOVEE_DERIVED_SENSORS", List(";")),
+    (
+        "rate_of_change_window",
+        "GOVEE_RATE_OF_CHANGE_WINDOW",
+        Scalar,
+    ),
+    ("playlists_file", "GOVEE_PLAYLISTS_FILE", Scalar),
+    ("entity_id_overrides", "GOVEE_ENTITY_ID_OVERRIDES", Scalar),
+    ("bridge_instance", "GOVEE_BRIDGE_INSTANCE", Scalar),
+    ("shard_claims", "GOVEE_SHARD_CLAIMS", List(",")),
+    ("maintenance_window", "GOVEE_MAINTENANCE_WINDOW", Scalar),
+    ("state_merge_policy", "GOVEE_STATE_MERGE_POLICY", Scalar),
+    ("cloud_pacing", "GOVEE_CLOUD_PACING", Scalar),
+    ("command_queue", "GOVEE_COMMAND_QUEUE", Scalar),
+    ("poll_intervals", "GOVEE_POLL_INTERVALS", List(";")),
+    ("poll_intervals_file", "GOVEE_POLL_INTERVALS_FILE", Scalar),
+    (
+        "cloud_failure_policy",
+        "GOVEE_CLOUD_FAILURE_POLICY",
+        List(";"),
+    ),
+    ("watchdog_rules", "GOVEE_WATCHDOG_RULES", List(";")),
+    ("auto_brightness", "GOVEE_AUTO_BRIGHTNESS", List(";")),
+    ("preset_schedule", "GOVEE_PRESET_SCHEDULE", List(";")),
+    ("probe_capabilities", "GOVEE_PROBE_CAPABILITIES", Scalar),
+    ("power_save", "GOVEE_POWER_SAVE", Scalar),
+    ("ble_scan", "GOVEE_BLE_SCAN", Scalar),
+    ("cache_dir", "GOVEE_CACHE_DIR", Scalar),
+    ("quirks_dir", "GOVEE_QUIRKS_DIR", Scalar),
+    (
+        "trusted_quirk_signers",
+        "GOVEE_TRUSTED_QUIRK_SIGNERS",
+        List(","),
+    ),
+    (
+        "device_customizations",
+        "GOVEE_DEVICE_CUSTOMIZATIONS",
+        Scalar,
+    ),
+    ("devices", "GOVEE_DEVICES", Document),
+];
+
+/// Render a scalar value from the file as the environment would hold it
+fn scalar_to_string(key: &str, value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => anyhow::bail!("{key} must be a string, number or boolean"),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Yaml,
+    Toml,
+}
+
+impl FileFormat {
+    /// The format of a file, according to its extension
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+/// Parse the file content into environment variable names and values.
+/// Options that are set to null are omitted.
+pub fn parse_config_file(
+    content: &str,
+    format: FileFormat,
+) -> anyhow::Result<BTreeMap<&'static str, String>> {
+    let doc: Option<BTreeMap<String, Value>> = match format {
+        FileFormat::Yaml => serde_yaml::from_str(content)?,
+        FileFormat::Toml => Some(toml::from_str(content)?),
+    };
+    let mut env = BTreeMap::new();
+
+    for (key, value) in doc.unwrap_or_default() {
+        let (_, var, format) = OPTIONS
+            .iter()
+            .find(|(name, _, _)| *name == key)
+            .ok_or_else(|| anyhow::anyhow!("unknown option '{key}'"))?;
+
+        let value = match (&value, format) {
+            (Value::Null, _) => continue,
+            (value, Document) => serde_json::to_string(value)?,
+            (Value::Sequence(items), List(separator)) => items
+                .iter()
+                .map(|item| scalar_to_string(&key, item))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join(separator),
+            (Value::Sequence(_), Scalar) => anyhow::bail!("{key} does not accept a list"),
+            (value, _) => scalar_to_string(&key, value)?,
+        };
+        env.insert(*var, value);
+    }
+
+    Ok(env)
+}
+
+pub fn load_config_file(path: &Path) -> anyhow::Result<BTreeMap<&'static str, String>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse_config_file(&content, FileFormat::for_path(path))
+        .with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Load the file and set the environment variables that it
+/// provides, other than those that are already set.
+/// This must be called before any other threads are started.
+pub fn apply_config_file(path: &Path) -> anyhow::Result<()> {
+    for (var, value) in load_config_file(path)? {
+        if std::env::var_os(var).is_none() {
+            std::env::set_var(var, value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let env = parse_config_file(
+            "govee_email: me@example.com\n\
+             mqtt_port: 1884\n\
+             power_save: true\n\
+             scene_icon_base_url: ~\n\
+             poll_intervals:\n  - H5179=30m\n  - lan=30s\n\
+             devices:\n  H6008:\n    icon: mdi:lamp\n",
+            FileFormat::Yaml,
+        )
+        .unwrap();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                (
+                    "GOVEE_DEVICES",
+                    r#"{"H6008":{"icon":"mdi:lamp"}}"#.to_string()
+                ),
+                ("GOVEE_EMAIL", "me@example.com".to_string()),
+                ("GOVEE_MQTT_PORT", "1884".to_string()),
+                ("GOVEE_POLL_INTERVALS", "H5179=30m;lan=30s".to_string()),
+                ("GOVEE_POWER_SAVE", "true".to_string()),
+            ])
+        );
+
+        assert!(parse_config_file("", FileFormat::Yaml).unwrap().is_empty());
+        assert!(parse_config_file("mqtt_hots: localhost", FileFormat::Yaml).is_err());
+        assert!(parse_config_file("mqtt_host: [a, b]", FileFormat::Yaml).is_err());
+    }
+
+    #[test]
+    fn parse_toml() {
+        assert_eq!(
+            FileFormat::for_path(Path::new("/etc/govee.TOML")),
+            FileFormat::Toml
+        );
+        assert_eq!(
+            FileFormat::for_path(Path::new("/etc/govee.yml")),
+            FileFormat::Yaml
+        );
+
+        let env = parse_config_file(
+            "govee_email = \"me@example.com\"\n\
+             mqtt_port = 1884\n\
+             power_save = true\n\
+             poll_intervals = [\"H5179=30m\", \"lan=30s\"]\n\
+             [devices.H6008]\n\
+             icon = \"mdi:lamp\"\n",
+            FileFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                (
+                    "GOVEE_DEVICES",
+                    r#"{"H6008":{"icon":"mdi:lamp"}}"#.to_string()
+                ),
+                ("GOVEE_EMAIL", "me@example.com".to_string()),
+                ("GOVEE_MQTT_PORT", "1884".to_string()),
+                ("GOVEE_POLL_INTERVALS", "H5179=30m;lan=30s".to_string()),
+                ("GOVEE_POWER_SAVE", "true".to_string()),
+            ])
+        );
+        assert!(parse_config_file("", FileFormat::Toml).unwrap().is_empty());
+        assert!(parse_config_file("mqtt_hots = \"localhost\"", FileFormat::Toml).is_err());
+    }
+
+    /// Every variable in the table must be read somewhere, otherwise
+    /// setting its option in the file would silently do nothing
+    #[test]
+    fn options_are_read() {
+        fn sources(dir: &Path, content: &mut String) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    sources(&path, content);
+                } else if path.extension().is_some_and(|ext| ext == "rs")
+                    && !path.ends_with("config_file.rs")
+                {
+                    content.push_str(&std::fs::read_to_string(&path).unwrap());
+                }
+            }
+        }
+        let mut content = String::new();
+        sources(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut content,
+        );
+
+        let unread: Vec<&str> = OPTIONS
+            .iter()
+            .map(|(_, var, _)| *var)
+            .filter(|var| !content.contains(&format!("\"{var}\"")))
+            .collect();
+        assert!(unread.is_empty(), "not read anywhere: {unread:?}");
+    }
+}
@@ -0,0 +1,193 @@
+//! A versioned JSON contract for automation tools other than Home
+//! Assistant, such as Node-RED and openHAB. The topics live under
+//! `gv2mqtt/v1/` and their payloads are stable within a version,
+//! independent of the Home Assistant discovery entities, which are
+//! published alongside them and can change shape between releases.
+//! See docs/CONTRACT.md for the schemas.
+use crate::lan_api::DeviceColor;
+use crate::service::device::Device;
+use crate::service::hass::{topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use chrono::{DateTime, Utc};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+
+pub const CONTRACT_VERSION: u32 = 1;
+
+pub fn contract_info_topic(device: &Device) -> String {
+    format!("gv2mqtt/v1/devices/{}/info", topic_safe_id(device))
+}
+
+pub fn contract_state_topic(device: &Device) -> String {
+    format!("gv2mqtt/v1/devices/{}/state", topic_safe_id(device))
+}
+
+pub fn contract_command_topic(device: &Device) -> String {
+    format!("gv2mqtt/v1/devices/{}/set", topic_safe_id(device))
+}
+
+/// Describes a device and what it can do
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractDeviceInfo {
+    pub version: u32,
+    pub id: String,
+    pub sku: String,
+    pub name: String,
+    pub room: Option<String>,
+    pub supports_brightness: bool,
+    pub supports_rgb: bool,
+    /// The minimum and maximum color temperature in kelvin
+    pub color_temp_range_kelvin: Option<(u32, u32)>,
+    pub state_topic: String,
+    pub command_topic: String,
+}
+
+impl ContractDeviceInfo {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            version: CONTRACT_VERSION,
+            id: device.id.to_string(),
+            sku: device.sku.to_string(),
+            name: device.name(),
+            room: device.room_name().map(|room| room.to_string()),
+            supports_brightness: device.supports_brightness(),
+            supports_rgb: device.supports_rgb(),
+            color_temp_range_kelvin: device.get_color_temperature_range(),
+            state_topic: contract_state_topic(device),
+            command_topic: contract_command_topic(device),
+        }
+    }
+}
+
+/// The state of a device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractDeviceState {
+    pub version: u32,
+    pub id: String,
+    /// Whether the device is connected to the Govee cloud, if known
+    pub online: Option<bool>,
+    /// Whether the device is powered on
+    pub on: bool,
+    /// Whether the light function of the device is on, for devices
+    /// such as humidifiers that have a light in addition to their
+    /// main function
+    pub light_on: Option<bool>,
+    /// The brightness in percent (0-100)
+    pub brightness: u8,
+    /// The color, when the device is not in color temperature mode
+    pub color: Option<DeviceColor>,
+    /// The color temperature, when in color temperature mode
+    pub color_temp_kelvin: Option<u32>,
+    /// The active scene, if any
+    pub effect: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+impl ContractDeviceState {
+    pub fn new(device: &Device) -> Option<Self> {
+        let state = device.device_state()?;
+        let in_kelvin_mode = state.kelvin != 0;
+        Some(Self {
+            version: CONTRACT_VERSION,
+            id: device.id.to_string(),
+            online: state.online,
+            on: state.on,
+            light_on: state.light_on,
+            brightness: state.brightness,
+            color: (!in_kelvin_mode).then_some(state.color),
+            color_temp_kelvin: in_kelvin_mode.then_some(state.kelvin),
+            effect: state.scene,
+            updated: state.updated,
+        })
+    }
+}
+
+/// A command sent to `gv2mqtt/v1/devices/<id>/set`.
+/// Every field is optional; unknown fields are rejected so that
+/// typos are reported rather than silently ignored.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ContractCommand {
+    pub on: Option<bool>,
+    pub brightness: Option<u8>,
+    pub color: Option<DeviceColor>,
+    pub color_temp_kelvin: Option<u32>,
+    pub effect: Option<String>,
+}
+
+/// Publish the info and the state of a device to the contract topics.
+/// Both are retained, so that a flow that starts later sees them.
+pub async fn publish_contract_device(client: &HassClient, device: &Device) -> anyhow::Result<()> {
+    client
+        .publish_retained(contract_info_topic(device), ContractDeviceInfo::new(device))
+        .await?;
+    publish_contract_state(client, device).await
+}
+
+pub async fn publish_contract_state(client: &HassClient, device: &Device) -> anyhow::Result<()> {
+    if let Some(state) = ContractDeviceState::new(device) {
+        client
+            .publish_retained(contract_state_topic(device), state)
+            .await?;
+    }
+    Ok(())
+}
+
+/// A contract client is sending a command to a device
+pub async fn mqtt_contract_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let command: ContractCommand = serde_json::from_str(&payload)?;
+    log::info!("Contract command for {device}: {payload}");
+
+    if command.on == Some(false) {
+        return state.device_power_on(&device, false).await;
+    }
+
+    if let Some(brightness) = command.brightness {
+        state.device_set_brightness(&device, brightness).await?;
+    }
+    if let Some(effect) = &command.effect {
+        state.device_set_scene(&device, effect).await?;
+    } else if let Some(color) = command.color {
+        state
+            .device_set_color_rgb(&device, color.r, color.g, color.b)
+            .await?;
+    } else if let Some(kelvin) = command.color_temp_kelvin {
+        state.device_set_color_temperature(&device, kelvin).await?;
+    }
+
+    let changed_something = command.brightness.is_some()
+        || command.effect.is_some()
+        || command.color.is_some()
+        || command.color_temp_kelvin.is_some();
+    if command.on == Some(true) && !changed_something {
+        state.device_power_on(&device, true).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_command() {
+        let command: ContractCommand =
+            serde_json::from_str(r#"{"on": true, "color": {"r": 255, "g": 0, "b": 0}}"#).unwrap();
+        assert_eq!(
+            command,
+            ContractCommand {
+                on: Some(true),
+                color: Some(DeviceColor { r: 255, g: 0, b: 0 }),
+                ..Default::default()
+            }
+        );
+
+        assert!(serde_json::from_str::<ContractCommand>(r#"{"brightnes": 50}"#).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Context;
+use chrono::NaiveTime;
+use serde::Deserialize;
+
+/// A daily window of local time, in the form HH:MM-HH:MM,
+/// which may span midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DailyWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl std::str::FromStr for DailyWindow {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("expected HH:MM-HH:MM, got '{s}'"))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .with_context(|| format!("parsing '{t}' as HH:MM"))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for DailyWindow {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl DailyWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn daily_window() {
+        let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        let window: DailyWindow = "03:00-04:30".parse().unwrap();
+        assert!(window.contains(t("03:00")));
+        assert!(window.contains(t("04:29")));
+        assert!(!window.contains(t("04:30")));
+        assert!(!window.contains(t("12:00")));
+
+        let window: DailyWindow = "23:00-01:00".parse().unwrap();
+        assert!(window.contains(t("23:30")));
+        assert!(window.contains(t("00:30")));
+        assert!(!window.contains(t("01:00")));
+        assert!(!window.contains(t("22:59")));
+
+        assert!("3am".parse::<DailyWindow>().is_err());
+    }
+}
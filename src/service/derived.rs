@@ -0,0 +1,585 @@
+//! Derived sensors are computed by the bridge from the readings of
+//! other sensors on the same device, using a small expression
+//! language. This is useful for eg: greenhouse or terrarium setups
+//! that want dew point or VPD without writing templates in hass.
+//!
+//! Expressions may use the variables `temperature` (in celsius) and
+//! `humidity` (relative, in percent), numbers, `+ - * / ^`, parentheses,
+//! the comparisons `< <= > >=`, which produce 1 when true and 0 when
+//! false, and the functions `exp`, `ln`, `sqrt`, `abs`, as well as
+//! `heat_index` and `dew_point`, which take a temperature and humidity.
+//!
+//! Binary derived sensors, such as `frost_risk`, are on whenever their
+//! expression produces a value other than 0.
+use crate::service::device::Device;
+use crate::temperature::TemperatureUnits;
+
+/// Expressions for some commonly useful derived sensors,
+/// as (preset, name, unit, device_class, expression)
+const PRESETS: &[(&str, &str, &str, Option<&str>, &str)] = &[
+    (
+        "dew_point",
+        "Dew Point",
+        "°C",
+        Some("temperature"),
+        // Magnus formula
+        "243.12 * (ln(humidity / 100) + 17.62 * temperature / (243.12 + temperature)) / \
+         (17.62 - (ln(humidity / 100) + 17.62 * temperature / (243.12 + temperature)))",
+    ),
+    (
+        "absolute_humidity",
+        "Absolute Humidity",
+        "g/m³",
+        None,
+        "6.112 * exp(17.67 * temperature / (temperature + 243.5)) * humidity * 2.1674 \
+         / (273.15 + temperature)",
+    ),
+    (
+        "vpd",
+        "Vapor Pressure Deficit",
+        "kPa",
+        Some("pressure"),
+        "0.61078 * exp(17.27 * temperature / (temperature + 237.3)) * (1 - humidity / 100)",
+    ),
+    (
+        "heat_index",
+        "Heat Index",
+        "°C",
+        Some("temperature"),
+        "heat_index(temperature, humidity)",
+    ),
+];
+
+/// Presets for binary sensors, as (preset, name, device_class, expression)
+const BINARY_PRESETS: &[(&str, &str, Option<&str>, &str)] = &[(
+    "frost_risk",
+    "Frost Risk",
+    Some("cold"),
+    // Frost forms on surfaces, which can be a few degrees colder than
+    // the air, once the air is saturated below freezing
+    "(temperature <= 3) * (dew_point(temperature, humidity) <= 0)",
+)];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedSensorDefinition {
+    /// The id or name of the device that the sensor applies to,
+    /// or None if it applies to all devices
+    pub device: Option<String>,
+    pub name: String,
+    pub unit: Option<&'static str>,
+    pub device_class: Option<&'static str>,
+    /// If true, the sensor is a binary sensor
+    pub binary: bool,
+    pub expr: Expr,
+}
+
+impl DerivedSensorDefinition {
+    /// Parse a definition. This is either the name of one of the
+    /// presets (`dew_point`, `absolute_humidity`, `vpd`, `heat_index`
+    /// or `frost_risk`), or a custom definition in the form
+    /// `Name[unit]=expression`, where the `[unit]` portion is optional.
+    /// Either may be prefixed by `Device:` to add the sensor to only
+    /// that device, eg: `Outdoor Sensor:heat_index`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let spec = spec.trim();
+
+        // Device ids contain colons, so the definition follows the
+        // last one that precedes the expression
+        let label_end = spec.find('=').unwrap_or(spec.len());
+        let (device, spec) = match spec[..label_end].rfind(':') {
+            Some(colon) => {
+                let device = spec[..colon].trim();
+                anyhow::ensure!(!device.is_empty(), "missing device name in '{spec}'");
+                (Some(device.to_string()), spec[colon + 1..].trim())
+            }
+            None => (None, spec),
+        };
+
+        for (preset, name, unit, device_class, expr) in PRESETS {
+            if spec.eq_ignore_ascii_case(preset) {
+                return Ok(Self {
+                    device,
+                    name: name.to_string(),
+                    unit: Some(unit),
+                    device_class: *device_class,
+                    binary: false,
+                    expr: Expr::parse(expr)?,
+                });
+            }
+        }
+        for (preset, name, device_class, expr) in BINARY_PRESETS {
+            if spec.eq_ignore_ascii_case(preset) {
+                return Ok(Self {
+                    device,
+                    name: name.to_string(),
+                    unit: None,
+                    device_class: *device_class,
+                    binary: true,
+                    expr: Expr::parse(expr)?,
+                });
+            }
+        }
+
+        let (label, expr) = spec.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid derived sensor '{spec}': expected a preset name \
+                or Name[unit]=expression"
+            )
+        })?;
+        let label = label.trim();
+        let (name, unit) = match label.split_once('[') {
+            Some((name, unit)) => {
+                let unit = unit
+                    .strip_suffix(']')
+                    .ok_or_else(|| anyhow::anyhow!("missing ] in '{label}'"))?;
+                // Definitions are parsed once at startup and are needed
+                // for the lifetime of the process, so it is fine to leak
+                // the unit in order to use it in a SensorConfig
+                let unit: &'static str = Box::leak(unit.trim().to_string().into_boxed_str());
+                (name.trim(), Some(unit))
+            }
+            None => (label, None),
+        };
+        if name.is_empty() {
+            anyhow::bail!("invalid derived sensor '{spec}': name is empty");
+        }
+
+        Ok(Self {
+            device,
+            name: name.to_string(),
+            unit,
+            device_class: None,
+            binary: false,
+            expr: Expr::parse(expr)?,
+        })
+    }
+
+    /// Evaluate the definition using the current readings of the device
+    pub fn evaluate(&self, device: &Device) -> Option<f64> {
+        let temperature = device
+            .sensor_temperature()
+            .map(|t| t.as_unit(TemperatureUnits::Celsius).value());
+        let humidity = device.sensor_humidity_percent();
+
+        self.expr.eval(&|var| match var {
+            "temperature" => temperature,
+            "humidity" => humidity,
+            _ => None,
+        })
+    }
+
+    pub fn applies_to(&self, device: &Device) -> bool {
+        match &self.device {
+            None => true,
+            Some(label) => {
+                label.eq_ignore_ascii_case(&device.id) || label.eq_ignore_ascii_case(&device.name())
+            }
+        }
+    }
+
+    /// Returns true if the inputs used by the expression are
+    /// all available
+    pub fn is_computable_with(&self, available: &[&str]) -> bool {
+        self.expr
+            .variables()
+            .iter()
+            .all(|var| available.contains(&var.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+    Compare(Box<Expr>, Comparison, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// The functions, and the number of arguments that each takes
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("exp", 1),
+    ("ln", 1),
+    ("sqrt", 1),
+    ("abs", 1),
+    ("heat_index", 2),
+    ("dew_point", 2),
+];
+
+/// The apparent temperature, in celsius, for a temperature in
+/// celsius and relative humidity in percent, using the algorithm
+/// of the US National Weather Service: a simple formula, unless
+/// it is hot enough for the Rothfusz regression to apply
+/// <https://www.wpc.ncep.noaa.gov/html/heatindex_equation.shtml>
+fn heat_index(celsius: f64, rh: f64) -> f64 {
+    let t = celsius * 9. / 5. + 32.;
+    let simple = 0.5 * (t + 61. + (t - 68.) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2. < 80. {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+            - 0.22475541 * t * rh
+            - 0.00683783 * t * t
+            - 0.05481717 * rh * rh
+            + 0.00122874 * t * t * rh
+            + 0.00085282 * t * rh * rh
+            - 0.00000199 * t * t * rh * rh;
+        if rh < 13. && (80. ..=112.).contains(&t) {
+            hi -= ((13. - rh) / 4.) * ((17. - (t - 95.).abs()) / 17.).sqrt();
+        } else if rh > 85. && (80. ..=87.).contains(&t) {
+            hi += ((rh - 85.) / 10.) * ((87. - t) / 5.);
+        }
+        hi
+    };
+    (fahrenheit - 32.) * 5. / 9.
+}
+
+/// The dew point, in celsius, using the Magnus formula
+fn dew_point(celsius: f64, rh: f64) -> f64 {
+    let gamma = (rh / 100.).ln() + 17.62 * celsius / (243.12 + celsius);
+    243.12 * gamma / (17.62 - gamma)
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.comparison()?;
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("unexpected {token:?} in '{s}'");
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate the expression, resolving variables via `lookup`.
+    /// Returns None if a variable is unavailable or the result
+    /// is not a finite number.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        let result = match self {
+            Self::Number(n) => *n,
+            Self::Variable(name) => lookup(name)?,
+            Self::Negate(e) => -e.eval(lookup)?,
+            Self::Binary(a, op, b) => {
+                let a = a.eval(lookup)?;
+                let b = b.eval(lookup)?;
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    '^' => a.powf(b),
+                    _ => unreachable!(),
+                }
+            }
+            Self::Compare(a, cmp, b) => {
+                let a = a.eval(lookup)?;
+                let b = b.eval(lookup)?;
+                let result = match cmp {
+                    Comparison::Less => a < b,
+                    Comparison::LessOrEqual => a <= b,
+                    Comparison::Greater => a > b,
+                    Comparison::GreaterOrEqual => a >= b,
+                };
+                if result {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            Self::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(lookup))
+                    .collect::<Option<Vec<f64>>>()?;
+                match (func.as_str(), args.as_slice()) {
+                    ("exp", [arg]) => arg.exp(),
+                    ("ln", [arg]) => arg.ln(),
+                    ("sqrt", [arg]) => arg.sqrt(),
+                    ("abs", [arg]) => arg.abs(),
+                    ("heat_index", [t, rh]) => heat_index(*t, *rh),
+                    ("dew_point", [t, rh]) => dew_point(*t, *rh),
+                    _ => unreachable!(),
+                }
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    pub fn variables(&self) -> Vec<String> {
+        let mut vars = vec![];
+        self.collect_variables(&mut vars);
+        vars
+    }
+
+    fn collect_variables(&self, vars: &mut Vec<String>) {
+        match self {
+            Self::Number(_) => {}
+            Self::Variable(name) => {
+                if !vars.contains(name) {
+                    vars.push(name.to_string());
+                }
+            }
+            Self::Negate(e) => e.collect_variables(vars),
+            Self::Call(_, args) => {
+                for arg in args {
+                    arg.collect_variables(vars);
+                }
+            }
+            Self::Binary(a, _, b) | Self::Compare(a, _, b) => {
+                a.collect_variables(vars);
+                b.collect_variables(vars);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    Cmp(Comparison),
+    Comma,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() || c == '.' {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Number(number.parse().map_err(|err| {
+                anyhow::anyhow!("invalid number '{number}' in '{s}': {err}")
+            })?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    ident.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(Token::Ident(ident));
+        } else {
+            chars.next();
+            let or_equal = matches!(c, '<' | '>') && chars.next_if_eq(&'=').is_some();
+            tokens.push(match c {
+                '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+                '<' if or_equal => Token::Cmp(Comparison::LessOrEqual),
+                '<' => Token::Cmp(Comparison::Less),
+                '>' if or_equal => Token::Cmp(Comparison::GreaterOrEqual),
+                '>' => Token::Cmp(Comparison::Greater),
+                ',' => Token::Comma,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                _ => anyhow::bail!("unexpected character '{c}' in '{s}'"),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_op_in(&mut self, ops: &[char]) -> Option<char> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    /// comparison := expr (('<' | '<=' | '>' | '>=') expr)?
+    fn comparison(&mut self) -> anyhow::Result<Expr> {
+        let lhs = self.expr()?;
+        if let Some(Token::Cmp(cmp)) = self.peek() {
+            let cmp = *cmp;
+            self.pos += 1;
+            return Ok(Expr::Compare(Box::new(lhs), cmp, Box::new(self.expr()?)));
+        }
+        Ok(lhs)
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.term()?;
+        while let Some(op) = self.next_op_in(&['+', '-']) {
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    /// term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.next_op_in(&['*', '/']) {
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    /// unary := '-' unary | power
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.next_op_in(&['-']).is_some() {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    /// power := atom ('^' unary)?
+    fn power(&mut self) -> anyhow::Result<Expr> {
+        let base = self.atom()?;
+        if self.next_op_in(&['^']).is_some() {
+            return Ok(Expr::Binary(Box::new(base), '^', Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    /// atom := number | ident | ident '(' comparison (',' comparison)* ')'
+    ///       | '(' comparison ')'
+    fn atom(&mut self) -> anyhow::Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::Open) {
+                    let Some((_, arity)) = FUNCTIONS.iter().find(|(f, _)| *f == name) else {
+                        anyhow::bail!("unknown function '{name}'");
+                    };
+                    self.next();
+                    let mut args = vec![self.comparison()?];
+                    while self.peek() == Some(&Token::Comma) {
+                        self.next();
+                        args.push(self.comparison()?);
+                    }
+                    self.expect_close()?;
+                    anyhow::ensure!(
+                        args.len() == *arity,
+                        "{name} takes {arity} argument(s), but was given {}",
+                        args.len()
+                    );
+                    return Ok(Expr::Call(name, args));
+                }
+                Ok(Expr::Variable(name))
+            }
+            Some(Token::Open) => {
+                let expr = self.comparison()?;
+                self.expect_close()?;
+                Ok(expr)
+            }
+            Some(token) => anyhow::bail!("unexpected {token:?}"),
+            None => anyhow::bail!("unexpected end of expression"),
+        }
+    }
+
+    fn expect_close(&mut self) -> anyhow::Result<()> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => anyhow::bail!("expected ')'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(def: &DerivedSensorDefinition, temperature: f64, humidity: f64) -> f64 {
+        def.expr
+            .eval(&|var| match var {
+                "temperature" => Some(temperature),
+                "humidity" => Some(humidity),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn derived_sensors() {
+        let dew_point = DerivedSensorDefinition::parse("dew_point").unwrap();
+        assert_eq!(format!("{:.1}", eval(&dew_point, 25.0, 60.0)), "16.7");
+
+        let vpd = DerivedSensorDefinition::parse("vpd").unwrap();
+        assert_eq!(format!("{:.2}", eval(&vpd, 25.0, 60.0)), "1.27");
+
+        let custom = DerivedSensorDefinition::parse("Twice[°C] = -2 * -temperature ^ 2").unwrap();
+        assert_eq!(custom.name, "Twice");
+        assert_eq!(custom.unit, Some("°C"));
+        assert_eq!(eval(&custom, 3.0, 0.0), 18.0);
+        assert!(custom.is_computable_with(&["temperature"]));
+        assert!(!dew_point.is_computable_with(&["temperature"]));
+
+        assert!(DerivedSensorDefinition::parse("Bad=nope(1)").is_err());
+        assert!(DerivedSensorDefinition::parse("Bad=(1").is_err());
+        assert!(DerivedSensorDefinition::parse("Bad=dew_point(1)").is_err());
+    }
+
+    #[test]
+    fn outdoor_sensors() {
+        let heat_index =
+            DerivedSensorDefinition::parse("AA:BB:CC:DD:EE:FF:42:2A:heat_index").unwrap();
+        assert_eq!(
+            heat_index.device.as_deref(),
+            Some("AA:BB:CC:DD:EE:FF:42:2A")
+        );
+        assert!(!heat_index.binary);
+        // Matches the NWS heat index chart: 90°F at 70% feels like 106°F
+        assert_eq!(format!("{:.1}", eval(&heat_index, 32.22, 70.0)), "41.1");
+        // Below 80°F the simple formula applies
+        assert_eq!(format!("{:.1}", eval(&heat_index, 20.0, 50.0)), "19.4");
+
+        let frost = DerivedSensorDefinition::parse("Garden:frost_risk").unwrap();
+        assert_eq!(frost.device.as_deref(), Some("Garden"));
+        assert!(frost.binary);
+        assert_eq!(eval(&frost, 1.0, 90.0), 1.0);
+        assert_eq!(eval(&frost, 2.0, 99.0), 0.0);
+        assert_eq!(eval(&frost, 10.0, 90.0), 0.0);
+
+        let custom = DerivedSensorDefinition::parse("Garden:Hot=temperature > 30").unwrap();
+        assert_eq!(custom.device.as_deref(), Some("Garden"));
+        assert_eq!(custom.name, "Hot");
+        assert_eq!(eval(&custom, 31.0, 0.0), 1.0);
+        assert!(DerivedSensorDefinition::parse(":heat_index").is_err());
+    }
+}
@@ -1,15 +1,38 @@
+use crate::ble::advertisement::BleSensorReading;
 use crate::ble::NotifyHumidifierNightlightParams;
 use crate::commands::serve::POLL_INTERVAL;
 use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
 use crate::platform_api::{
     DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
 };
-use crate::service::quirks::{resolve_quirk, Quirk, BULB};
+use crate::service::brightness::{
+    iot_brightness_scale, platform_brightness_scale, BrightnessScale,
+};
+use crate::service::capability_probe::unsupported_capabilities;
+use crate::service::device_schedule::{parse_schedules, DeviceSchedule};
+use crate::service::quirks::{resolve_quirk, HumidityUnits, Quirk, BULB};
+use crate::service::state_merge::{state_merge_policy, AttributeSource, Transport};
+use crate::temperature::{TemperatureScale, TemperatureUnits, TemperatureValue};
+use crate::undoc_api::FirmwareUpdate;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::IpAddr;
+use std::time::Duration;
+
+/// LAN API round trips slower than this suggest a poor WiFi
+/// connection to the device
+const LAGGY_LAN_RTT: Duration = Duration::from_millis(1500);
+
+/// Discovery scans are sent at least every minute, so a device that
+/// hasn't replied to them for this long has most likely lost power
+const LAN_POWER_LOSS_SILENCE: chrono::Duration = chrono::Duration::minutes(5);
+
+/// A device that hasn't answered a status request sent via AWS IoT
+/// within this time is offline
+const IOT_STATUS_TIMEOUT: chrono::Duration = chrono::Duration::seconds(60);
 
 #[derive(Default, Clone, Debug)]
 pub struct Device {
@@ -33,8 +56,31 @@ pub struct Device {
     pub undoc_device_info: Option<UndocDeviceInfo>,
     pub last_undoc_device_info_update: Option<DateTime<Utc>>,
 
+    /// Rolling average of the time taken to receive a response
+    /// to a LAN API status request
+    pub lan_rtt: Option<Duration>,
+
     pub iot_device_status: Option<LanDeviceStatus>,
     pub last_iot_device_status_update: Option<DateTime<Utc>>,
+    /// When we requested a status update via AWS IoT that the device
+    /// has yet to answer
+    pub iot_status_requested: Option<DateTime<Utc>>,
+
+    /// The most recent reading broadcast by a BLE-only sensor
+    pub ble_sensor_reading: Option<BleSensorReading>,
+    pub last_ble_sensor_reading_update: Option<DateTime<Utc>>,
+
+    /// The most recent reading from an energy monitoring plug
+    pub power_reading: Option<PowerReading>,
+    pub last_power_reading_update: Option<DateTime<Utc>>,
+
+    /// The most recent state reported by a leak detector
+    pub leak_reading: Option<LeakReading>,
+    pub last_leak_reading_update: Option<DateTime<Utc>>,
+
+    /// The most recent state reported by a presence sensor
+    pub presence_reading: Option<PresenceReading>,
+    pub last_presence_reading_update: Option<DateTime<Utc>>,
 
     pub nightlight_state: Option<NotifyHumidifierNightlightParams>,
     pub target_humidity_percent: Option<u8>,
@@ -44,6 +90,181 @@ pub struct Device {
     pub last_polled: Option<DateTime<Utc>>,
 
     active_scene: Option<ActiveSceneInfo>,
+
+    /// The most recently activated scenes, most recent first
+    pub scene_history: VecDeque<SceneActivation>,
+
+    /// Out of range alarms currently raised by the device,
+    /// at most one per measurement
+    pub sensor_alarms: Vec<SensorAlarm>,
+
+    /// The most recent state observed while the device was online,
+    /// which can be re-applied after it has lost power
+    pub restorable_state: Option<RestorableState>,
+
+    /// The state saved before the most recent restart, reported
+    /// until fresh information arrives from any of the APIs
+    pub restored_state: Option<DeviceState>,
+
+    /// Wi-Fi connection information reported via AWS IoT
+    pub wifi_rssi: Option<i64>,
+    pub wifi_channel: Option<i64>,
+
+    /// The most recently applied values of the quirk-defined
+    /// extended controls, keyed by instance. Govee doesn't report
+    /// these, so we track what we last sent.
+    pub extended_control_values: HashMap<String, String>,
+
+    /// The firmware update that is available for the device, as
+    /// of the most recent check, or None if it is up to date
+    pub firmware_update: Option<FirmwareUpdate>,
+
+    /// The brightness percentage that we most recently requested,
+    /// used to reconcile the value reported on the device's own scale
+    pub requested_brightness: Option<u8>,
+
+    /// The email of the additional account that the device was
+    /// found in, or None if it belongs to the primary account
+    pub account: Option<String>,
+}
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareVersions {
+    pub version_soft: Option<String>,
+    pub version_hard: Option<String>,
+    pub wifi_soft_version: Option<String>,
+    pub wifi_hard_version: Option<String>,
+}
+
+/// The electrical measurements of an energy monitoring plug.
+/// Plugs report these piecemeal, so each is optional.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PowerReading {
+    pub power_watts: Option<f64>,
+    pub energy_kwh: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current_amps: Option<f64>,
+}
+
+/// A set of measurements that are reported piecemeal, with each
+/// field being optional
+pub trait PartialReading: Copy + Default + PartialEq {
+    /// Overlay the fields that are present in `other`
+    fn update(&mut self, other: Self);
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+macro_rules! partial_reading {
+    ($reading:ty, $($field:ident),*) => {
+        impl PartialReading for $reading {
+            fn update(&mut self, other: Self) {
+                $(
+                    if other.$field.is_some() {
+                        self.$field = other.$field;
+                    }
+                )*
+            }
+        }
+    };
+}
+
+/// Overlay `reading` onto `current`, unless it is empty or older
+/// than `last_update`. Returns true if it was applied.
+fn update_partial_reading<R: PartialReading>(
+    current: &mut Option<R>,
+    last_update: &mut Option<DateTime<Utc>>,
+    reading: R,
+    when: DateTime<Utc>,
+) -> bool {
+    if reading.is_empty() || last_update.is_some_and(|last| last > when) {
+        return false;
+    }
+    current.get_or_insert_with(R::default).update(reading);
+    last_update.replace(when);
+    true
+}
+
+partial_reading!(PowerReading, power_watts, energy_kwh, voltage, current_amps);
+
+/// The state of a leak detector, such as the H5054. These report
+/// via the cloud, piecemeal, so each field is optional.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LeakReading {
+    /// Whether water has been detected
+    pub leak: Option<bool>,
+    pub battery_percent: Option<u8>,
+    /// Whether the detector is connected to the Govee cloud
+    pub online: Option<bool>,
+    /// Whether the gateway that the detector is paired with, if any,
+    /// is connected to the Govee cloud
+    pub gateway_online: Option<bool>,
+}
+
+partial_reading!(LeakReading, leak, battery_percent, online, gateway_online);
+
+impl LeakReading {
+    /// Whether the detector is able to report a leak; false if
+    /// either it, or the gateway that relays its events, is offline
+    pub fn is_reachable(&self) -> Option<bool> {
+        match (self.online, self.gateway_online) {
+            (None, None) => None,
+            (online, gateway) => Some(online.unwrap_or(true) && gateway.unwrap_or(true)),
+        }
+    }
+}
+
+/// The state of a presence sensor, such as the H5127, along with
+/// the settings that determine what it detects. These are reported
+/// piecemeal, so each field is optional.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PresenceReading {
+    /// Whether someone is present
+    pub occupied: Option<bool>,
+    /// The range within which presence is detected, in centimeters
+    pub detection_distance: Option<i64>,
+    pub sensitivity: Option<i64>,
+}
+
+partial_reading!(PresenceReading, occupied, detection_distance, sensitivity);
+
+/// The APIs don't agree on the case of the SKU;
+/// the canonical form is upper case, eg: `H6072`.
+pub fn normalize_sku(sku: &str) -> String {
+    sku.trim().to_ascii_uppercase()
+}
+
+/// Device ids are reported in slightly different forms by the
+/// various Govee APIs; with or without colons, and in either case.
+/// This function produces the canonical form that we use to key
+/// the device registry: upper case hex bytes separated by colons,
+/// eg: `AA:BB:CC:DD:EE:FF:42:2A`. It is only used for lookups; the
+/// device keeps the id in the form that Govee reported it, as that
+/// is what the APIs expect, and what entity unique ids are based on.
+/// Ids that don't look like a sequence of hex bytes are simply
+/// trimmed and upper cased.
+pub fn normalize_device_id(id: &str) -> String {
+    let id = id.trim().to_ascii_uppercase();
+    let digits: String = id
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.' | ' '))
+        .collect();
+
+    if digits.len() < 12
+        || !digits.len().is_multiple_of(2)
+        || !digits.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return id;
+    }
+
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).expect("ascii"))
+        .collect::<Vec<_>>()
+        .join(":")
 }
 
 impl std::fmt::Display for Device {
@@ -62,6 +283,81 @@ struct ActiveSceneInfo {
     pub kelvin: u32,
 }
 
+/// How many entries to retain in Device::scene_history
+const SCENE_HISTORY_LEN: usize = 10;
+
+/// Where a scene activation originated
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SceneSource {
+    /// Activated via a command sent by us
+    Bridge,
+    /// Activated by some other means, such as the Govee app
+    External,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SceneActivation {
+    pub name: String,
+    pub source: SceneSource,
+    pub when: DateTime<Utc>,
+}
+
+/// A snapshot of the light state of a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestorableState {
+    pub on: bool,
+    pub brightness: u8,
+    pub color: DeviceColor,
+    pub kelvin: u32,
+    pub scene: Option<String>,
+}
+
+/// The measurement that a sensor alarm relates to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmMeasurement {
+    Temperature,
+    Humidity,
+}
+
+/// Which end of the configured range was crossed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmBound {
+    Low,
+    High,
+}
+
+/// An out of range alarm raised by a sensor device, according
+/// to the thresholds configured for it in the Govee app
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SensorAlarm {
+    pub measurement: AlarmMeasurement,
+    pub bound: Option<AlarmBound>,
+    /// The reading that triggered the alarm; celsius for temperature,
+    /// percent for humidity
+    pub value: Option<f64>,
+    /// The threshold that was crossed, in the same units as value
+    pub threshold: Option<f64>,
+    pub when: DateTime<Utc>,
+}
+
+impl SensorAlarm {
+    /// The event type used to represent this alarm in hass
+    pub fn event_type(&self) -> String {
+        let measurement = match self.measurement {
+            AlarmMeasurement::Temperature => "temperature",
+            AlarmMeasurement::Humidity => "humidity",
+        };
+        match self.bound {
+            Some(AlarmBound::Low) => format!("{measurement}_low"),
+            Some(AlarmBound::High) => format!("{measurement}_high"),
+            None => format!("{measurement}_out_of_range"),
+        }
+    }
+}
+
 /// Represents the device state; synthesized from the various
 /// sources of facts that we have in the Device
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -89,6 +385,10 @@ pub struct DeviceState {
     /// Where the information came from
     pub source: &'static str,
     pub updated: DateTime<Utc>,
+
+    /// Where each attribute of a merged state came from
+    #[serde(default)]
+    pub attributes: BTreeMap<String, AttributeSource>,
 }
 
 #[derive(Debug, Clone)]
@@ -97,14 +397,27 @@ pub struct UndocDeviceInfo {
     pub entry: crate::undoc_api::DeviceEntry,
 }
 
+/// Which bound of the alarm range the value is beyond, along with
+/// that bound. A value within the range, which the device may
+/// report as the alarm is clearing, is beyond neither.
+fn alarm_bound(value: f64, (min, max): (f64, f64)) -> Option<(AlarmBound, f64)> {
+    if value < min {
+        Some((AlarmBound::Low, min))
+    } else if value > max {
+        Some((AlarmBound::High, max))
+    } else {
+        None
+    }
+}
+
 impl Device {
     /// Create a new device given just its sku and id.
     /// No other facts are known or reflected by it at this time;
     /// they will need to be added by the caller.
-    pub fn new<S: Into<String>, I: Into<String>>(sku: S, id: I) -> Self {
+    pub fn new<S: AsRef<str>, I: AsRef<str>>(sku: S, id: I) -> Self {
         Self {
-            sku: sku.into(),
-            id: id.into(),
+            sku: normalize_sku(sku.as_ref()),
+            id: id.as_ref().to_string(),
             ..Self::default()
         }
     }
@@ -122,10 +435,19 @@ impl Device {
         self.computed_name()
     }
 
-    /// Returns the name defined for the device in the Govee App
+    /// Returns the name defined for the device in the Govee App.
+    /// The platform API is preferred, but the undocumented API
+    /// knows the names of devices that the platform API doesn't list.
     pub fn govee_name(&self) -> Option<&str> {
         if let Some(info) = &self.http_device_info {
-            return Some(&info.device_name);
+            if !info.device_name.trim().is_empty() {
+                return Some(&info.device_name);
+            }
+        }
+        if let Some(info) = &self.undoc_device_info {
+            if !info.entry.device_name.trim().is_empty() {
+                return Some(&info.entry.device_name);
+            }
         }
         None
     }
@@ -190,10 +512,41 @@ impl Device {
         self.humidifier_param_by_mode.insert(mode, param);
     }
 
-    /// Update the LAN device information
-    pub fn set_lan_device(&mut self, device: LanDevice) {
-        self.lan_device.replace(device);
+    /// Update the LAN device information.
+    /// Returns the prior IP address if the device has moved to a
+    /// different address, for example, due to a new DHCP lease.
+    pub fn set_lan_device(&mut self, device: LanDevice) -> Option<IpAddr> {
+        let new_ip = device.ip;
+        let prior = self.lan_device.replace(device);
         self.last_lan_device_update.replace(Utc::now());
+
+        match prior {
+            Some(prior) if prior.ip != new_ip => {
+                log::info!(
+                    "{self}: LAN IP address changed from {} to {new_ip}",
+                    prior.ip
+                );
+                // Round trip times measured against the old
+                // address are no longer relevant
+                self.lan_rtt.take();
+                Some(prior.ip)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if the device was silent on the LAN for long enough
+    /// that it has most likely just had its power restored. This must be
+    /// called prior to set_lan_device for the newly discovered device.
+    /// The state that it had beforehand is kept so that it can be restored.
+    pub fn lan_rediscovered_after_power_loss(&mut self) -> bool {
+        let silent = self
+            .last_lan_device_update
+            .is_some_and(|last| Utc::now() - last > LAN_POWER_LOSS_SILENCE);
+        if silent {
+            self.restorable_state = self.snapshot_light_state();
+        }
+        silent
     }
 
     /// Update the LAN device status information
@@ -209,10 +562,127 @@ impl Device {
         changed
     }
 
-    pub fn set_iot_device_status(&mut self, status: LanDeviceStatus) {
+    /// Fold a newly measured LAN API round trip time into
+    /// the rolling average
+    pub fn record_lan_rtt(&mut self, rtt: Duration) {
+        const WEIGHT: f64 = 0.2;
+        let avg = match self.lan_rtt {
+            Some(prior) => prior.mul_f64(1. - WEIGHT) + rtt.mul_f64(WEIGHT),
+            None => rtt,
+        };
+        self.lan_rtt.replace(avg);
+    }
+
+    /// Returns true if the LAN API is responding slowly enough
+    /// that we should prefer an alternative transport
+    pub fn lan_api_is_laggy(&self) -> bool {
+        self.lan_rtt.map(|rtt| rtt > LAGGY_LAN_RTT).unwrap_or(false)
+    }
+
+    /// Returns the LAN device if it should be used for control.
+    /// If the LAN API has been laggy and the IoT API is available,
+    /// returns None so that the IoT API will be used instead.
+    pub fn preferred_lan_device(&self) -> Option<&LanDevice> {
+        if self.lan_api_is_laggy() && self.iot_api_supported() {
+            return None;
+        }
+        self.lan_device.as_ref()
+    }
+
+    /// Note that we have asked the device for its status via AWS IoT.
+    /// Only the first of a series of unanswered requests is recorded.
+    pub fn set_iot_status_requested(&mut self) {
+        self.iot_status_requested.get_or_insert_with(Utc::now);
+    }
+
+    /// Update the AWS IoT status information. Returns true if the device
+    /// had stopped answering status requests and is now back online,
+    /// which typically means that it has just had its power restored.
+    pub fn set_iot_device_status(&mut self, status: LanDeviceStatus) -> bool {
+        let was_offline = self
+            .iot_status_requested
+            .take()
+            .is_some_and(|requested| Utc::now() - requested > IOT_STATUS_TIMEOUT);
+        if was_offline {
+            self.restorable_state = self.snapshot_light_state();
+        }
         self.iot_device_status.replace(status);
         self.last_iot_device_status_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        was_offline
+    }
+
+    /// Record a reading from the BLE advertisements of the device.
+    /// Returns true if this is the first reading seen for it.
+    #[cfg(feature = "ble")]
+    pub fn set_ble_sensor_reading(&mut self, reading: BleSensorReading) -> bool {
+        let first = self.ble_sensor_reading.is_none();
+        self.ble_sensor_reading.replace(reading);
+        self.last_ble_sensor_reading_update.replace(Utc::now());
+        first
+    }
+
+    /// Record the measurements reported by an energy monitoring plug.
+    /// `when` is the time at which they were taken; measurements that
+    /// are older than those we already have are ignored.
+    /// Returns true if these are the first measurements seen for it.
+    pub fn update_power_reading(&mut self, reading: PowerReading, when: DateTime<Utc>) -> bool {
+        let first = self.power_reading.is_none();
+        update_partial_reading(
+            &mut self.power_reading,
+            &mut self.last_power_reading_update,
+            reading,
+            when,
+        ) && first
+    }
+
+    /// Update the state of a leak detector
+    pub fn update_leak_reading(&mut self, reading: LeakReading, when: DateTime<Utc>) {
+        let was_leaking = self
+            .leak_reading
+            .is_some_and(|prior| prior.leak == Some(true));
+        if !update_partial_reading(
+            &mut self.leak_reading,
+            &mut self.last_leak_reading_update,
+            reading,
+            when,
+        ) {
+            return;
+        }
+
+        let leaking = reading.leak == Some(true);
+        if leaking && !was_leaking {
+            log::warn!("{self}: leak detected");
+        } else if was_leaking && reading.leak == Some(false) {
+            log::info!("{self}: leak cleared");
+        }
+    }
+
+    /// Whether the device is a leak detector
+    pub fn is_leak_sensor(&self) -> bool {
+        self.leak_reading.is_some() || self.resolve_quirk().is_some_and(|quirk| quirk.leak_sensor)
+    }
+
+    pub fn update_presence_reading(&mut self, reading: PresenceReading, when: DateTime<Utc>) {
+        update_partial_reading(
+            &mut self.presence_reading,
+            &mut self.last_presence_reading_update,
+            reading,
+            when,
+        );
+    }
+
+    /// Whether the device is a presence sensor
+    pub fn is_presence_sensor(&self) -> bool {
+        self.presence_reading.is_some()
+            || self
+                .resolve_quirk()
+                .is_some_and(|quirk| quirk.presence_sensor)
+    }
+
+    /// Whether the device has reported its power consumption
+    pub fn supports_power_metering(&self) -> bool {
+        self.power_reading.is_some()
     }
 
     pub fn set_http_device_info(&mut self, info: HttpDeviceInfo) {
@@ -220,10 +690,60 @@ impl Device {
         self.last_http_device_update.replace(Utc::now());
     }
 
-    pub fn set_http_device_state(&mut self, state: HttpDeviceState) {
+    /// Record a scene that the Platform API reports as active. If we
+    /// didn't activate it ourselves, it was most likely selected via
+    /// the Govee app.
+    pub fn record_reported_scene(&mut self, name: &str) {
+        let already_known = self
+            .scene_history
+            .front()
+            .is_some_and(|prior| prior.name == name);
+        if !already_known {
+            self.record_scene_activation(name, SceneSource::External);
+        }
+    }
+
+    /// Record a scene activation in the history
+    pub fn record_scene_activation(&mut self, name: &str, source: SceneSource) {
+        self.scene_history.push_front(SceneActivation {
+            name: name.to_string(),
+            source,
+            when: Utc::now(),
+        });
+        self.scene_history.truncate(SCENE_HISTORY_LEN);
+    }
+
+    /// Capture the current light state, so that it can be re-applied later
+    pub fn snapshot_light_state(&self) -> Option<RestorableState> {
+        self.device_state().map(|state| RestorableState {
+            on: state.light_on.unwrap_or(state.on),
+            brightness: state.brightness,
+            color: state.color,
+            kelvin: state.kelvin,
+            scene: state.scene,
+        })
+    }
+
+    /// Whether the Platform API reports the device as being
+    /// connected to the Govee cloud
+    pub fn is_online(&self) -> Option<bool> {
+        self.compute_http_device_state()?.online
+    }
+
+    /// Update the platform API state. Returns true if the device
+    /// was previously offline and has now come back online, which
+    /// typically means that it has just had its power restored.
+    pub fn set_http_device_state(&mut self, state: HttpDeviceState) -> bool {
+        let was_online = self.is_online();
+        if was_online == Some(true) {
+            self.restorable_state = self.snapshot_light_state();
+        }
+
         self.http_device_state.replace(state);
         self.last_http_device_state_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+
+        was_online == Some(false) && self.is_online() == Some(true)
     }
 
     pub fn set_undoc_device_info(
@@ -231,6 +751,38 @@ impl Device {
         entry: crate::undoc_api::DeviceEntry,
         room_name: Option<&str>,
     ) {
+        let data = &entry.device_ext.last_device_data;
+        let reading = PowerReading {
+            power_watts: data.power,
+            energy_kwh: data.energy,
+            voltage: data.voltage,
+            current_amps: data.current,
+        };
+        let when = data
+            .last_time
+            .and_then(|ms| DateTime::from_timestamp_millis(ms as i64))
+            .unwrap_or_else(Utc::now);
+        self.update_power_reading(reading, when);
+        if self.is_leak_sensor() {
+            let settings = &entry.device_ext.device_settings;
+            let reading = LeakReading {
+                leak: None,
+                battery_percent: settings.battery.and_then(|b| u8::try_from(b).ok()),
+                online: data.online,
+                gateway_online: data.gateway_online,
+            };
+            self.update_leak_reading(reading, when);
+        }
+        if self.is_presence_sensor() {
+            let settings = &entry.device_ext.device_settings;
+            let reading = PresenceReading {
+                occupied: data.occupied,
+                detection_distance: settings.detection_distance,
+                sensitivity: settings.sensitivity,
+            };
+            self.update_presence_reading(reading, when);
+        }
+
         self.undoc_device_info.replace(UndocDeviceInfo {
             entry,
             room_name: room_name.map(|s| s.to_string()),
@@ -239,6 +791,153 @@ impl Device {
         self.clear_scene_if_color_changed();
     }
 
+    pub fn set_firmware_update(&mut self, update: Option<FirmwareUpdate>) {
+        self.firmware_update = update;
+    }
+
+    /// The hardware and firmware versions of the device
+    pub fn firmware_versions(&self) -> FirmwareVersions {
+        let mut versions = FirmwareVersions::default();
+        if let Some(info) = &self.undoc_device_info {
+            let settings = &info.entry.device_ext.device_settings;
+            versions.version_soft = Some(info.entry.version_soft.clone());
+            versions.version_hard = Some(info.entry.version_hard.clone());
+            versions.wifi_soft_version = settings.wifi_soft_version.clone();
+            versions.wifi_hard_version = settings.wifi_hard_version.clone();
+        }
+        // The LAN API reports the versions that the device is
+        // running right now, so it takes precedence
+        if let Some(lan) = &self.lan_device {
+            versions.version_soft = Some(lan.ble_version_soft.clone());
+            versions.version_hard = Some(lan.ble_version_hard.clone());
+            versions.wifi_soft_version = Some(lan.wifi_version_soft.clone());
+            versions.wifi_hard_version = Some(lan.wifi_version_hard.clone());
+        }
+        versions
+    }
+
+    /// Returns the range of segment numbers to present to hass,
+    /// if the device supports segment control
+    pub fn segment_range(&self) -> Option<std::ops::Range<u32>> {
+        match self
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.supports_segmented_rgb())
+        {
+            Some(range) => Some(range),
+            None => Some(0..self.lan_segment_count()?),
+        }
+    }
+
+    /// Returns the temperature scale shown on the device's own display,
+    /// for devices that have a display and allow it to be configured
+    /// via the Govee app.
+    pub fn display_temperature_scale(&self) -> Option<TemperatureScale> {
+        let info = self.undoc_device_info.as_ref()?;
+        info.entry.device_ext.device_settings.fah_open.map(|fah| {
+            if fah {
+                TemperatureScale::Farenheit
+            } else {
+                TemperatureScale::Celsius
+            }
+        })
+    }
+
+    /// Returns true if the device can raise out of range alarms
+    /// that were configured via the Govee app
+    pub fn supports_sensor_alarms(&self) -> bool {
+        self.undoc_device_info
+            .as_ref()
+            .map(|info| {
+                let settings = &info.entry.device_ext.device_settings;
+                settings.tem_warning.is_some() || settings.hum_warning.is_some()
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns the (min, max) range configured for the measurement
+    /// in the Govee app, normalized to celsius or percent
+    pub fn alarm_range(&self, measurement: AlarmMeasurement) -> Option<(f64, f64)> {
+        let settings = &self
+            .undoc_device_info
+            .as_ref()?
+            .entry
+            .device_ext
+            .device_settings;
+        let (min, max) = match measurement {
+            AlarmMeasurement::Temperature => (settings.tem_min?, settings.tem_max?),
+            AlarmMeasurement::Humidity => (settings.hum_min?, settings.hum_max?),
+        };
+        Some((min as f64 / 100., max as f64 / 100.))
+    }
+
+    /// Update the alarm state for a measurement, as reported by the
+    /// device. Returns the alarm if it has been newly raised.
+    pub fn update_sensor_alarm(
+        &mut self,
+        measurement: AlarmMeasurement,
+        raised: bool,
+        value: Option<f64>,
+    ) -> Option<SensorAlarm> {
+        let was_raised = self
+            .sensor_alarms
+            .iter()
+            .any(|alarm| alarm.measurement == measurement);
+        self.sensor_alarms
+            .retain(|alarm| alarm.measurement != measurement);
+
+        if !raised {
+            if was_raised {
+                log::info!("{self}: {measurement:?} alarm cleared");
+            }
+            return None;
+        }
+
+        let (bound, threshold) = match (value, self.alarm_range(measurement)) {
+            (Some(value), Some(range)) => alarm_bound(value, range).unzip(),
+            _ => (None, None),
+        };
+
+        let alarm = SensorAlarm {
+            measurement,
+            bound,
+            value,
+            threshold,
+            when: Utc::now(),
+        };
+        self.sensor_alarms.push(alarm.clone());
+
+        if was_raised {
+            None
+        } else {
+            log::info!("{self}: alarm raised {alarm:?}");
+            Some(alarm)
+        }
+    }
+
+    /// Returns the schedules configured on the device via the Govee app
+    pub fn schedules(&self) -> Vec<DeviceSchedule> {
+        self.device_timer().map(parse_schedules).unwrap_or_default()
+    }
+
+    /// Returns the raw `timer` device setting
+    pub fn device_timer(&self) -> Option<&JsonValue> {
+        self.undoc_device_info
+            .as_ref()?
+            .entry
+            .device_ext
+            .device_settings
+            .timer
+            .as_ref()
+    }
+
+    pub fn set_display_temperature_scale(&mut self, scale: TemperatureScale) {
+        if let Some(info) = self.undoc_device_info.as_mut() {
+            info.entry.device_ext.device_settings.fah_open =
+                Some(scale == TemperatureScale::Farenheit);
+        }
+    }
+
     pub fn compute_iot_device_state(&self) -> Option<DeviceState> {
         let updated = self.last_iot_device_status_update?;
         let status = self.iot_device_status.as_ref()?;
@@ -251,12 +950,14 @@ impl Device {
                 self.nightlight_state.as_ref().map(|s| s.on)
             },
             online: None,
-            brightness: status.brightness,
+            brightness: iot_brightness_scale(&self.sku)
+                .reconcile(status.brightness.into(), self.requested_brightness),
             color: status.color,
             kelvin: status.color_temperature_kelvin,
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "AWS IoT API",
             updated,
+            attributes: BTreeMap::new(),
         })
     }
 
@@ -268,12 +969,14 @@ impl Device {
             on: status.on,
             light_on: Some(status.on), // assumption: LAN API == light
             online: None,
-            brightness: status.brightness,
+            brightness: BrightnessScale::PERCENT
+                .reconcile(status.brightness.into(), self.requested_brightness),
             color: status.color,
             kelvin: status.color_temperature_kelvin,
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "LAN API",
             updated,
+            attributes: BTreeMap::new(),
         })
     }
 
@@ -298,11 +1001,15 @@ impl Device {
         }
 
         let light_instance = self.get_light_power_toggle_instance_name();
+        let brightness_scale = self
+            .http_device_info
+            .as_ref()
+            .and_then(platform_brightness_scale)
+            .unwrap_or_default();
 
         for cap in &state.capabilities {
             if let Ok(value) = serde_json::from_value::<IntegerValueState>(cap.state.clone()) {
                 if light_instance
-                    .as_deref()
                     .map(|inst| inst == cap.instance.as_str())
                     .unwrap_or(false)
                 {
@@ -321,7 +1028,8 @@ impl Device {
                         };
                     }
                     "brightness" => {
-                        brightness = value.value as u8;
+                        brightness =
+                            brightness_scale.reconcile(value.value, self.requested_brightness);
                     }
                     "colorTemperatureK" => {
                         kelvin = value.value;
@@ -345,26 +1053,43 @@ impl Device {
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "PLATFORM API",
             updated,
+            attributes: BTreeMap::new(),
         })
     }
 
-    /// Returns the most recently received state information
+    /// Returns the state of the device, merged from the information
+    /// received via each transport according to the state merge policy
     pub fn device_state(&self) -> Option<DeviceState> {
         let mut candidates = vec![];
 
         if let Some(state) = self.compute_lan_device_state() {
-            candidates.push(state);
+            candidates.push((Transport::Lan, state));
         }
         if let Some(state) = self.compute_http_device_state() {
-            candidates.push(state);
+            candidates.push((Transport::Platform, state));
         }
         if let Some(state) = self.compute_iot_device_state() {
-            candidates.push(state);
+            candidates.push((Transport::Iot, state));
+        }
+
+        if candidates.is_empty() {
+            return self.restored_state.clone();
         }
 
-        candidates.sort_by(|a, b| a.updated.cmp(&b.updated));
+        state_merge_policy().merge(candidates)
+    }
+
+    pub fn set_account(&mut self, email: Option<&str>) {
+        self.account = email.map(|s| s.to_string());
+    }
+
+    pub fn set_requested_brightness(&mut self, percent: u8) {
+        self.requested_brightness.replace(percent);
+    }
 
-        candidates.pop()
+    pub fn set_extended_control_value(&mut self, instance: &str, value: &str) {
+        self.extended_control_values
+            .insert(instance.to_string(), value.to_string());
     }
 
     /// Records the active scene name
@@ -416,7 +1141,7 @@ impl Device {
     /// Indicate whether we require the platform API data in order
     /// to correctly report the device
     pub fn needs_platform_poll(&self) -> bool {
-        if !self.iot_api_supported() {
+        if !self.iot_api_supported() || self.has_light_sensor() {
             return true;
         }
 
@@ -439,11 +1164,28 @@ impl Device {
             return false;
         }
         let device_type = self.device_type();
-        match (device_type, self.sku.as_str()) {
-            (_, "H7160") => true,
-            (DeviceType::Light, _) => true,
-            _ => false,
+        matches!(
+            (device_type, self.sku.as_str()),
+            (_, "H7160") | (DeviceType::Light, _)
+        )
+    }
+
+    /// The names of the APIs via which the device can be reached
+    pub fn transports(&self) -> Vec<&'static str> {
+        let mut transports = vec![];
+        if self.lan_device.is_some() {
+            transports.push("LAN");
+        }
+        if self.iot_api_supported() && self.undoc_device_info.is_some() {
+            transports.push("IoT");
+        }
+        if self.http_device_info.is_some() {
+            transports.push("Platform");
+        }
+        if self.ble_sensor_reading.is_some() {
+            transports.push("BLE");
         }
+        transports
     }
 
     pub fn avoid_platform_api(&self) -> bool {
@@ -499,6 +1241,85 @@ impl Device {
             .and_then(|info| info.capability_by_instance(instance))
     }
 
+    /// Record a value that was just set via the Platform API, so that
+    /// it is reflected before the next poll. Returns false if there is
+    /// no platform state for the capability.
+    pub fn set_state_capability_value(&mut self, instance: &str, value: JsonValue) -> bool {
+        let Some(cap) = self.http_device_state.as_mut().and_then(|state| {
+            state
+                .capabilities
+                .iter_mut()
+                .find(|c| c.instance.eq_ignore_ascii_case(instance))
+        }) else {
+            return false;
+        };
+        cap.state = serde_json::json!({ "value": value });
+        true
+    }
+
+    /// The temperature reported by the sensorTemperature capability,
+    /// or else by the BLE advertisements of the device
+    pub fn sensor_temperature(&self) -> Option<TemperatureValue> {
+        let units = self
+            .resolve_quirk()
+            .and_then(|q| q.platform_temperature_sensor_units)
+            .unwrap_or(TemperatureUnits::Celsius);
+        let Some(cap) = self.get_state_capability_by_instance("sensorTemperature") else {
+            return self
+                .ble_sensor_reading
+                .map(|r| TemperatureValue::new(r.temperature_celsius, TemperatureUnits::Celsius));
+        };
+        cap.state
+            .pointer("/value")
+            .and_then(|v| v.as_f64())
+            .map(|v| TemperatureValue::new(v, units))
+    }
+
+    /// The relative humidity percentage reported by the
+    /// sensorHumidity capability, or else by BLE advertisements
+    pub fn sensor_humidity_percent(&self) -> Option<f64> {
+        let units = self
+            .resolve_quirk()
+            .and_then(|q| q.platform_humidity_sensor_units)
+            .unwrap_or(HumidityUnits::RelativePercent);
+        let Some(cap) = self.get_state_capability_by_instance("sensorHumidity") else {
+            return self.ble_sensor_reading.map(|r| r.humidity_percent);
+        };
+        cap.state
+            .pointer("/value/currentHumidity")
+            .and_then(|v| v.as_f64())
+            .map(|v| units.from_reading_to_relative_percent(v))
+    }
+
+    /// When the temperature and humidity readings were last updated
+    pub fn sensor_reading_updated(&self) -> Option<DateTime<Utc>> {
+        if self
+            .get_state_capability_by_instance("sensorTemperature")
+            .is_some()
+            || self
+                .get_state_capability_by_instance("sensorHumidity")
+                .is_some()
+        {
+            return self.last_http_device_state_update;
+        }
+        self.last_ble_sensor_reading_update
+    }
+
+    /// Whether the lamp has an ambient light sensor. Its reading is
+    /// only available via the Platform API.
+    pub fn has_light_sensor(&self) -> bool {
+        self.get_capability_by_instance("sensorLux").is_some()
+    }
+
+    /// The ambient light level, in lux, reported by the
+    /// sensorLux capability of lamps that have a light sensor
+    pub fn sensor_lux(&self) -> Option<f64> {
+        self.get_state_capability_by_instance("sensorLux")?
+            .state
+            .pointer("/value")
+            .and_then(|v| v.as_f64())
+    }
+
     pub fn get_light_power_toggle_instance_name(&self) -> Option<&'static str> {
         match self.device_type() {
             DeviceType::Light => Some("powerSwitch"),
@@ -526,6 +1347,10 @@ impl Device {
             return quirk.color_temp_range;
         }
 
+        if unsupported_capabilities(&self.id).contains_key("colorTemperatureK") {
+            return None;
+        }
+
         if self.lan_device.is_some() {
             // LAN API support suggests that it is a light
             return Some((2000, 9000));
@@ -560,6 +1385,30 @@ impl Device {
         false
     }
 
+    /// The number of segments that can be colored via ptReal,
+    /// for segmented lights that are known to support it.
+    /// Unless the quirk specifies the count, it is taken from
+    /// the Platform API capabilities.
+    pub fn lan_segment_count(&self) -> Option<u32> {
+        let quirk = self.resolve_quirk()?;
+        if let Some(count) = quirk.lan_segments {
+            return Some(count);
+        }
+        if !quirk.segment_packets {
+            return None;
+        }
+        self.http_device_info
+            .as_ref()
+            .and_then(|info| info.supports_segmented_rgb())
+            .map(|range| range.end)
+    }
+
+    pub fn supports_combined_light_commands(&self) -> bool {
+        self.resolve_quirk()
+            .map(|q| q.combined_light_commands)
+            .unwrap_or(false)
+    }
+
     pub fn supports_rgb(&self) -> bool {
         if let Some(quirk) = self.resolve_quirk() {
             return quirk.supports_rgb;
@@ -576,6 +1425,40 @@ impl Device {
             .unwrap_or(false)
     }
 
+    /// Returns true if the device connects to the Govee cloud via Wi-Fi
+    pub fn has_wifi(&self) -> bool {
+        self.undoc_device_info
+            .as_ref()
+            .map(|info| info.entry.device_ext.device_settings.wifi_name.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Record the Wi-Fi details reported by the IoT API.
+    /// Returns true if a value was seen for the first time, as
+    /// the sensor for it is only created once it has a value.
+    pub fn update_wifi_info(&mut self, rssi: Option<i64>, channel: Option<i64>) -> bool {
+        let first_seen = (rssi.is_some() && self.wifi_rssi.is_none())
+            || (channel.is_some() && self.wifi_channel.is_none());
+        if let Some(rssi) = rssi {
+            self.wifi_rssi.replace(rssi);
+        }
+        if let Some(channel) = channel {
+            self.wifi_channel.replace(channel);
+        }
+        first_seen
+    }
+
+    /// The Wi-Fi signal level reported by the Govee app API.
+    /// This appears to be a coarse bucketed value rather than dBm.
+    pub fn wifi_level(&self) -> Option<i64> {
+        self.undoc_device_info
+            .as_ref()?
+            .entry
+            .device_ext
+            .device_settings
+            .wifi_level
+    }
+
     pub fn is_ble_only_device(&self) -> Option<bool> {
         if let Some(quirk) = self.resolve_quirk() {
             return Some(quirk.ble_only);
@@ -588,18 +1471,19 @@ impl Device {
         }
 
         if let Some(info) = &self.undoc_device_info {
-            Some(info.entry.device_ext.device_settings.wifi_name.is_none())
-        } else {
-            // Don't know for sure
-            None
+            return Some(info.entry.device_ext.device_settings.wifi_name.is_none());
         }
+
+        // Only known from its BLE advertisements
+        if self.lan_device.is_none() && self.ble_sensor_reading.is_some() {
+            return Some(true);
+        }
+
+        None
     }
 
     pub fn is_controllable(&self) -> bool {
-        match self.is_ble_only_device() {
-            Some(true) => false,
-            _ => true,
-        }
+        !matches!(self.is_ble_only_device(), Some(true))
     }
 }
 
@@ -607,6 +1491,377 @@ impl Device {
 mod test {
     use super::*;
 
+    fn lan_device(ip: &str) -> LanDevice {
+        LanDevice {
+            ip: ip.parse().unwrap(),
+            device: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+            sku: "H6000".to_string(),
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        }
+    }
+
+    fn lan_status(on: bool, color: DeviceColor) -> LanDeviceStatus {
+        LanDeviceStatus {
+            on,
+            brightness: 50,
+            color,
+            color_temperature_kelvin: 0,
+        }
+    }
+
+    #[test]
+    fn lan_ip_change() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.set_lan_device(lan_device("10.0.0.2")), None);
+        assert_eq!(device.set_lan_device(lan_device("10.0.0.2")), None);
+        assert_eq!(
+            device.set_lan_device(lan_device("10.0.0.3")),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(device.ip_addr(), Some("10.0.0.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn power_reading() {
+        let mut device = Device::new("H5160", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!device.supports_power_metering());
+        // Empty readings don't count as support
+        assert!(!device.update_power_reading(PowerReading::default(), Utc::now()));
+        assert!(!device.supports_power_metering());
+
+        let now = Utc::now();
+        assert!(device.update_power_reading(
+            PowerReading {
+                power_watts: Some(12.5),
+                energy_kwh: Some(3.2),
+                ..Default::default()
+            },
+            now,
+        ));
+        assert!(device.supports_power_metering());
+        // A partial update keeps the other measurements
+        assert!(!device.update_power_reading(
+            PowerReading {
+                voltage: Some(120.1),
+                ..Default::default()
+            },
+            now + chrono::Duration::seconds(1),
+        ));
+        // Stale data is ignored
+        device.update_power_reading(
+            PowerReading {
+                power_watts: Some(0.),
+                ..Default::default()
+            },
+            now,
+        );
+        assert_eq!(
+            device.power_reading,
+            Some(PowerReading {
+                power_watts: Some(12.5),
+                energy_kwh: Some(3.2),
+                voltage: Some(120.1),
+                current_amps: None,
+            })
+        );
+    }
+
+    #[test]
+    fn leak_reading() {
+        let mut device = Device::new("H5054", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.is_leak_sensor());
+        assert!(!Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A").is_leak_sensor());
+
+        let now = Utc::now();
+        let leak = |leak| LeakReading {
+            leak: Some(leak),
+            ..Default::default()
+        };
+        device.update_leak_reading(
+            LeakReading {
+                battery_percent: Some(80),
+                online: Some(true),
+                ..Default::default()
+            },
+            now,
+        );
+        device.update_leak_reading(leak(true), now + chrono::Duration::seconds(1));
+        // Stale data is ignored
+        device.update_leak_reading(leak(false), now);
+        assert_eq!(
+            device.leak_reading,
+            Some(LeakReading {
+                leak: Some(true),
+                battery_percent: Some(80),
+                online: Some(true),
+                gateway_online: None,
+            })
+        );
+
+        let mut reading = device.leak_reading.unwrap();
+        assert_eq!(reading.is_reachable(), Some(true));
+        reading.gateway_online = Some(false);
+        assert_eq!(reading.is_reachable(), Some(false));
+    }
+
+    #[test]
+    fn presence_reading() {
+        let mut device = Device::new("H5127", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.is_presence_sensor());
+        assert!(!Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A").is_presence_sensor());
+
+        let now = Utc::now();
+        device.update_presence_reading(
+            PresenceReading {
+                detection_distance: Some(250),
+                sensitivity: Some(3),
+                ..Default::default()
+            },
+            now,
+        );
+        // Only the fields that are present are overlaid
+        device.update_presence_reading(
+            PresenceReading {
+                occupied: Some(true),
+                ..Default::default()
+            },
+            now + chrono::Duration::seconds(1),
+        );
+        // Stale and empty data is ignored
+        device.update_presence_reading(
+            PresenceReading {
+                occupied: Some(false),
+                ..Default::default()
+            },
+            now,
+        );
+        device.update_presence_reading(PresenceReading::default(), Utc::now());
+        assert_eq!(
+            device.presence_reading,
+            Some(PresenceReading {
+                occupied: Some(true),
+                detection_distance: Some(250),
+                sensitivity: Some(3),
+            })
+        );
+        assert_eq!(
+            device.last_presence_reading_update,
+            Some(now + chrono::Duration::seconds(1))
+        );
+    }
+
+    #[test]
+    fn light_sensor() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let resp: JsonValue = serde_json::from_str(include_str!(
+            "../../test-data/get_device_state_sensor_lux.json"
+        ))
+        .unwrap();
+        let state: HttpDeviceState =
+            serde_json::from_value(resp.pointer("/payload").unwrap().clone()).unwrap();
+        device.set_http_device_state(state);
+        assert_eq!(device.sensor_lux(), Some(125.));
+        assert!(!device.has_light_sensor());
+
+        let info: HttpDeviceInfo = serde_json::from_value(serde_json::json!({
+            "sku": "H6000",
+            "device": "AA:BB:CC:DD:EE:FF:42:2A",
+            "type": "devices.types.light",
+            "capabilities": [
+                {
+                    "type": "devices.capabilities.property",
+                    "instance": "sensorLux",
+                },
+            ],
+        }))
+        .unwrap();
+        device.set_http_device_info(info);
+        assert!(device.has_light_sensor());
+        // The reading is only available via the Platform API
+        assert!(device.needs_platform_poll());
+    }
+
+    #[test]
+    fn power_restore() {
+        let http_state = |online: bool, rgb: u32| -> HttpDeviceState {
+            serde_json::from_value(serde_json::json!({
+                "sku": "H6000",
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "capabilities": [
+                    {
+                        "type": "devices.capabilities.online",
+                        "instance": "online",
+                        "state": {"value": online},
+                    },
+                    {
+                        "type": "devices.capabilities.on_off",
+                        "instance": "powerSwitch",
+                        "state": {"value": 1},
+                    },
+                    {
+                        "type": "devices.capabilities.range",
+                        "instance": "brightness",
+                        "state": {"value": 50},
+                    },
+                    {
+                        "type": "devices.capabilities.color_setting",
+                        "instance": "colorRgb",
+                        "state": {"value": rgb},
+                    },
+                ],
+            }))
+            .unwrap()
+        };
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        // The first state that we see isn't a transition
+        assert!(!device.set_http_device_state(http_state(true, 0xff0000)));
+        assert!(!device.set_http_device_state(http_state(true, 0xff0000)));
+        // Going offline keeps the state from while it was online
+        assert!(!device.set_http_device_state(http_state(false, 0xffffff)));
+        assert!(!device.set_http_device_state(http_state(false, 0xffffff)));
+        // Coming back online in plain white is a power restore
+        assert!(device.set_http_device_state(http_state(true, 0xffffff)));
+        assert_eq!(
+            device.restorable_state,
+            Some(RestorableState {
+                on: true,
+                brightness: 50,
+                color: red,
+                kelvin: 0,
+                scene: None,
+            })
+        );
+        assert!(!device.set_http_device_state(http_state(true, 0xffffff)));
+    }
+
+    #[test]
+    fn lan_power_restore() {
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        // The first discovery isn't a transition
+        assert!(!device.lan_rediscovered_after_power_loss());
+        device.set_lan_device(lan_device("10.0.0.2"));
+        device.set_lan_device_status(lan_status(true, red));
+        // Nor is replying to each of the regular scans
+        assert!(!device.lan_rediscovered_after_power_loss());
+        device.set_lan_device(lan_device("10.0.0.2"));
+
+        device.last_lan_device_update = Some(Utc::now() - chrono::Duration::minutes(10));
+        assert!(device.lan_rediscovered_after_power_loss());
+        assert_eq!(device.restorable_state.as_ref().map(|s| s.color), Some(red));
+        device.set_lan_device(lan_device("10.0.0.2"));
+        assert!(!device.lan_rediscovered_after_power_loss());
+    }
+
+    #[test]
+    fn iot_power_restore() {
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let white = DeviceColor {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        // Unsolicited reports and timely answers aren't transitions
+        assert!(!device.set_iot_device_status(lan_status(true, red)));
+        device.set_iot_status_requested();
+        assert!(!device.set_iot_device_status(lan_status(true, red)));
+
+        // A request that went unanswered, followed by another
+        device.iot_status_requested = Some(Utc::now() - chrono::Duration::minutes(15));
+        device.set_iot_status_requested();
+        assert!(device.set_iot_device_status(lan_status(true, white)));
+        assert_eq!(device.restorable_state.as_ref().map(|s| s.color), Some(red));
+        assert!(!device.set_iot_device_status(lan_status(true, white)));
+    }
+
+    #[test]
+    fn wifi_info() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!device.update_wifi_info(None, None));
+        assert!(device.update_wifi_info(Some(-60), None));
+        assert!(!device.update_wifi_info(Some(-62), None));
+        // The channel is a separate sensor, so is new in its own right
+        assert!(device.update_wifi_info(Some(-61), Some(6)));
+        assert!(!device.update_wifi_info(None, Some(11)));
+        assert_eq!(device.wifi_rssi, Some(-61));
+        assert_eq!(device.wifi_channel, Some(11));
+    }
+
+    #[test]
+    fn scene_history() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        for n in 0..15 {
+            device.record_scene_activation(&format!("scene {n}"), SceneSource::Bridge);
+        }
+        assert_eq!(device.scene_history.len(), SCENE_HISTORY_LEN);
+        assert_eq!(device.scene_history[0].name, "scene 14");
+    }
+
+    #[test]
+    fn sensor_alarm_edges() {
+        let mut device = Device::new("H5179", "AA:BB:CC:DD:EE:FF:42:2A");
+        let alarm = device
+            .update_sensor_alarm(AlarmMeasurement::Humidity, true, Some(71.5))
+            .unwrap();
+        assert_eq!(alarm.event_type(), "humidity_out_of_range");
+        // Repeated reports of the same alarm don't raise it again
+        assert_eq!(
+            device.update_sensor_alarm(AlarmMeasurement::Humidity, true, Some(72.0)),
+            None
+        );
+        assert_eq!(device.sensor_alarms.len(), 1);
+        assert_eq!(
+            device.update_sensor_alarm(AlarmMeasurement::Humidity, false, Some(60.0)),
+            None
+        );
+        assert!(device.sensor_alarms.is_empty());
+    }
+
+    #[test]
+    fn sensor_alarm_bounds() {
+        let range = (20.0, 70.0);
+        assert_eq!(alarm_bound(10.0, range), Some((AlarmBound::Low, 20.0)));
+        assert_eq!(alarm_bound(71.5, range), Some((AlarmBound::High, 70.0)));
+        assert_eq!(alarm_bound(20.0, range), None);
+        assert_eq!(alarm_bound(45.0, range), None);
+        assert_eq!(alarm_bound(70.0, range), None);
+    }
+
+    #[test]
+    fn lan_rtt_average() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!device.lan_api_is_laggy());
+        device.record_lan_rtt(Duration::from_millis(100));
+        assert_eq!(device.lan_rtt, Some(Duration::from_millis(100)));
+        device.record_lan_rtt(Duration::from_millis(600));
+        assert_eq!(device.lan_rtt, Some(Duration::from_millis(200)));
+        assert!(!device.lan_api_is_laggy());
+        for _ in 0..20 {
+            device.record_lan_rtt(Duration::from_secs(3));
+        }
+        assert!(device.lan_api_is_laggy());
+    }
+
+    #[test]
+    fn device_id_normalization() {
+        for id in [
+            "AA:BB:CC:DD:EE:FF:42:2A",
+            "aa:bb:cc:dd:ee:ff:42:2a",
+            "AABBCCDDEEFF422A",
+            " aa-bb-cc-dd-ee-ff-42-2a ",
+        ] {
+            assert_eq!(normalize_device_id(id), "AA:BB:CC:DD:EE:FF:42:2A");
+        }
+        assert_eq!(normalize_device_id("ce"), "CE");
+        assert_eq!(normalize_device_id("group-1234"), "GROUP-1234");
+    }
+
     #[test]
     fn name_compute() {
         let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
@@ -0,0 +1,139 @@
+//! Device customizations change how a device is presented in Home
+//! Assistant: its name, the area that it is suggested to be placed in,
+//! the icon of its main entity, and which of its entities are disabled
+//! when they are first discovered. They are keyed by device id, device
+//! name or SKU, and are applied when the discovery configs are
+//! generated, so the names and areas that are set in Home Assistant
+//! itself continue to take precedence.
+use crate::service::device::Device;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceCustomization {
+    /// The name to use in place of the name from the Govee app
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The area to suggest in place of the room from the Govee app
+    #[serde(default)]
+    pub area: Option<String>,
+    /// The icon of the main entity of the device
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// The names or unique ids of the entities of the device
+    /// that should be disabled by default
+    #[serde(default)]
+    pub disabled_entities: Vec<String>,
+}
+
+impl DeviceCustomization {
+    /// Apply the customizations from `other` on top of these
+    fn merge(&mut self, other: &Self) {
+        if other.name.is_some() {
+            self.name = other.name.clone();
+        }
+        if other.area.is_some() {
+            self.area = other.area.clone();
+        }
+        if other.icon.is_some() {
+            self.icon = other.icon.clone();
+        }
+        self.disabled_entities
+            .extend(other.disabled_entities.iter().cloned());
+    }
+
+    /// Whether the entity should be disabled by default
+    pub fn disables(&self, unique_id: &str, name: Option<&str>) -> bool {
+        self.disabled_entities.iter().any(|entity| {
+            entity == unique_id
+                || name
+                    .map(|name| entity.eq_ignore_ascii_case(name))
+                    .unwrap_or(false)
+        })
+    }
+}
+
+/// Keyed by device id, device name or SKU
+pub type DeviceCustomizations = BTreeMap<String, DeviceCustomization>;
+
+/// Parse customizations from YAML, or JSON
+pub fn parse_device_customizations(s: &str) -> anyhow::Result<DeviceCustomizations> {
+    Ok(serde_yaml::from_str::<Option<DeviceCustomizations>>(s)?.unwrap_or_default())
+}
+
+pub fn load_device_customizations(path: &Path) -> anyhow::Result<DeviceCustomizations> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse_device_customizations(&data).with_context(|| format!("parsing {}", path.display()))
+}
+
+static CUSTOMIZATIONS: Lazy<Mutex<DeviceCustomizations>> = Lazy::new(Default::default);
+
+pub fn set_device_customizations(customizations: DeviceCustomizations) {
+    *CUSTOMIZATIONS.lock() = customizations;
+}
+
+fn customization_from(
+    customizations: &DeviceCustomizations,
+    device: &Device,
+) -> Option<DeviceCustomization> {
+    let by_sku = customizations
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(&device.sku));
+    let by_device = customizations.iter().find(|(key, _)| {
+        key.eq_ignore_ascii_case(&device.id) || key.eq_ignore_ascii_case(&device.name())
+    });
+    if by_sku.is_none() && by_device.is_none() {
+        return None;
+    }
+
+    // The customizations for the device take
+    // precedence over those for its SKU
+    let mut result = DeviceCustomization::default();
+    for (_, customization) in by_sku.into_iter().chain(by_device) {
+        result.merge(customization);
+    }
+    Some(result)
+}
+
+/// The customizations that apply to `device`, if any
+pub fn device_customization(device: &Device) -> Option<DeviceCustomization> {
+    customization_from(&CUSTOMIZATIONS.lock(), device)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn customizations() {
+        let customizations = parse_device_customizations(
+            "H6008:\n  icon: mdi:lamp\n  disabled_entities: [Mode/Scene]\n\
+             \"AA:BB:CC:DD:EE:FF:42:2A\":\n  name: Porch Light\n  area: Porch\n  icon: mdi:outdoor-lamp\n",
+        )
+        .unwrap();
+
+        let porch = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:2A");
+        let porch = customization_from(&customizations, &porch).unwrap();
+        assert_eq!(porch.name.as_deref(), Some("Porch Light"));
+        assert_eq!(porch.area.as_deref(), Some("Porch"));
+        assert_eq!(porch.icon.as_deref(), Some("mdi:outdoor-lamp"));
+        assert!(porch.disables("gv2mqtt-AABBCCDDEEFF422A-mode-scene", Some("mode/scene")));
+        assert!(!porch.disables("gv2mqtt-AABBCCDDEEFF422A", None));
+
+        let lamp = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:2B");
+        let lamp = customization_from(&customizations, &lamp).unwrap();
+        assert_eq!(lamp.name, None);
+        assert_eq!(lamp.icon.as_deref(), Some("mdi:lamp"));
+
+        let other = Device::new("H6159", "AA:BB:CC:DD:EE:FF:42:2C");
+        assert_eq!(customization_from(&customizations, &other), None);
+
+        assert!(parse_device_customizations("H6008:\n  colour: red\n").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Builds a graph of the relationships between devices: the outlets
+//! of multi-outlet plugs and the members of feasts (eg: DreamView
+//! groups) that follow a leader.
+//! Edges point from the dependent to the thing that it depends upon.
+use crate::service::device::Device;
+use crate::undoc_api::ParsedFeast;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Device,
+    SubDevice,
+    Feast,
+    /// Referenced by another device, but not otherwise known to us
+    Unknown,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct GraphNode {
+    pub id: String,
+    pub name: String,
+    pub sku: Option<String>,
+    pub kind: NodeKind,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Relation {
+    /// An outlet or channel of this device
+    SubDevice,
+    /// The feast is driven by this device
+    FeastLeader,
+    /// The device follows the leader of a feast
+    FeastMember,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub relation: Relation,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl DeviceGraph {
+    pub fn build(devices: &[Device], feasts: &[ParsedFeast]) -> Self {
+        let mut nodes = BTreeMap::new();
+        let mut edges = vec![];
+
+        for device in devices {
+            nodes.insert(
+                device.id.clone(),
+                GraphNode {
+                    id: device.id.clone(),
+                    name: device.name(),
+                    sku: Some(device.sku.clone()),
+                    kind: NodeKind::Device,
+                },
+            );
+        }
+
+        let reference = |nodes: &mut BTreeMap<String, GraphNode>, sku: &str, id: &str| {
+            nodes.entry(id.to_string()).or_insert_with(|| GraphNode {
+                id: id.to_string(),
+                name: format!("{sku} {id}"),
+                sku: Some(sku.to_string()),
+                kind: NodeKind::Unknown,
+            });
+        };
+
+        for device in devices {
+            let Some(info) = &device.undoc_device_info else {
+                continue;
+            };
+            let settings = &info.entry.device_ext.device_settings;
+
+            // eg: `{"sub_0": {"name": "Shelf Lights"}}`
+            if let Some(subs) = settings.sub_devices.as_ref().and_then(|s| s.as_object()) {
+                for (key, sub) in subs {
+                    let id = format!("{}/{key}", device.id);
+                    let name = sub
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| key.clone());
+                    nodes.insert(
+                        id.clone(),
+                        GraphNode {
+                            id: id.clone(),
+                            name,
+                            sku: None,
+                            kind: NodeKind::SubDevice,
+                        },
+                    );
+                    edges.push(GraphEdge {
+                        from: id,
+                        to: device.id.clone(),
+                        relation: Relation::SubDevice,
+                    });
+                }
+            }
+        }
+
+        for feast in feasts {
+            let id = format!("feast-{}", feast.id);
+            nodes.insert(
+                id.clone(),
+                GraphNode {
+                    id: id.clone(),
+                    name: feast.name.clone(),
+                    sku: None,
+                    kind: NodeKind::Feast,
+                },
+            );
+            reference(&mut nodes, &feast.leader.sku, &feast.leader.device);
+            edges.push(GraphEdge {
+                from: id,
+                to: feast.leader.device.clone(),
+                relation: Relation::FeastLeader,
+            });
+            for member in &feast.members {
+                reference(&mut nodes, &member.sku, &member.device);
+                edges.push(GraphEdge {
+                    from: member.device.clone(),
+                    to: feast.leader.device.clone(),
+                    relation: Relation::FeastMember,
+                });
+            }
+        }
+
+        edges.sort();
+        edges.dedup();
+
+        Self {
+            nodes: nodes.into_values().collect(),
+            edges,
+        }
+    }
+
+    /// Render the graph in the graphviz DOT language,
+    /// eg: `govee export-devices --format graph-dot | dot -Tsvg`
+    pub fn to_dot(&self) -> String {
+        fn quote(s: &str) -> String {
+            let s = s.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{}\"", s.replace('\n', "\\n"))
+        }
+
+        let mut dot = "digraph govee {\n  rankdir=LR;\n".to_string();
+        for node in &self.nodes {
+            let label = match &node.sku {
+                Some(sku) => format!("{}\n{sku}", node.name),
+                None => node.name.clone(),
+            };
+            let shape = match node.kind {
+                NodeKind::Device => "box",
+                NodeKind::SubDevice => "ellipse",
+                NodeKind::Feast => "hexagon",
+                NodeKind::Unknown => "box, style=dashed",
+            };
+            dot.push_str(&format!(
+                "  {} [label={}, shape={shape}];\n",
+                quote(&node.id),
+                quote(&label)
+            ));
+        }
+        for edge in &self.edges {
+            let label = match edge.relation {
+                Relation::SubDevice => "outlet of",
+                Relation::FeastLeader => "led by",
+                Relation::FeastMember => "follows",
+            };
+            dot.push_str(&format!(
+                "  {} -> {} [label={}];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                quote(label)
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+    use crate::undoc_api::{DevicesResponse, FeastMember};
+
+    #[test]
+    fn graph_from_device_list() {
+        let resp: DevicesResponse = from_json(include_str!(
+            "../../test-data/undoc-device-list-issue-21.json"
+        ))
+        .unwrap();
+        let devices: Vec<Device> = resp
+            .devices
+            .into_iter()
+            .map(|entry| {
+                let mut device = Device::new(&entry.sku, &entry.device);
+                device.set_undoc_device_info(entry, None);
+                device
+            })
+            .collect();
+        let leader = devices[0].clone();
+
+        let feast = ParsedFeast {
+            id: 42,
+            name: "Movie Time".to_string(),
+            leader: FeastMember {
+                sku: leader.sku.clone(),
+                device: leader.id.clone(),
+            },
+            members: vec![FeastMember {
+                sku: "H6199".to_string(),
+                device: "AA:BB".to_string(),
+            }],
+        };
+
+        let graph = DeviceGraph::build(&devices, &[feast]);
+
+        let outlets: Vec<&GraphEdge> = graph
+            .edges
+            .iter()
+            .filter(|e| e.relation == Relation::SubDevice && e.to == "XX:XX:XX:2D:0A")
+            .collect();
+        assert_eq!(outlets.len(), 2);
+        assert!(graph
+            .nodes
+            .iter()
+            .any(|n| n.kind == NodeKind::SubDevice && n.name == "G SCSO Shelf Lights"));
+
+        assert!(graph.edges.contains(&GraphEdge {
+            from: "feast-42".to_string(),
+            to: leader.id.clone(),
+            relation: Relation::FeastLeader,
+        }));
+        assert!(graph.edges.contains(&GraphEdge {
+            from: "AA:BB".to_string(),
+            to: leader.id.clone(),
+            relation: Relation::FeastMember,
+        }));
+        assert!(graph
+            .nodes
+            .iter()
+            .any(|n| n.id == "AA:BB" && n.kind == NodeKind::Unknown));
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph govee {"));
+        assert!(dot.contains("\"feast-42\" -> "));
+    }
+}
@@ -0,0 +1,147 @@
+//! The Govee Home app arranges devices into groups, which it
+//! presents as rooms, eg: "Living Room". The lights of each group
+//! are offered as a single group light, whose commands fan out to
+//! each of the member lights, and whose state is aggregated from
+//! the state of its members.
+use crate::lan_api::DeviceColor;
+use crate::platform_api::DeviceType;
+use crate::service::device::{normalize_device_id, Device, DeviceState};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug)]
+pub struct DeviceGroup {
+    pub id: u64,
+    pub name: String,
+    pub members: Vec<Device>,
+}
+
+impl DeviceGroup {
+    pub fn contains(&self, device_id: &str) -> bool {
+        let device_id = normalize_device_id(device_id);
+        self.members
+            .iter()
+            .any(|m| normalize_device_id(&m.id) == device_id)
+    }
+}
+
+pub fn group_command_topic(id: u64) -> String {
+    format!("gv2mqtt/group/{id}/command")
+}
+
+pub fn group_state_topic(id: u64) -> String {
+    format!("gv2mqtt/group/{id}/state")
+}
+
+/// Returns the groups that have at least two member lights.
+/// A group with a single light would just duplicate that light.
+pub fn device_groups(devices: &[Device]) -> Vec<DeviceGroup> {
+    let mut groups: BTreeMap<u64, DeviceGroup> = BTreeMap::new();
+    for device in devices {
+        let Some(info) = &device.undoc_device_info else {
+            continue;
+        };
+        let Some(name) = &info.room_name else {
+            continue;
+        };
+        if device.device_type() != DeviceType::Light {
+            continue;
+        }
+        groups
+            .entry(info.entry.group_id)
+            .or_insert_with(|| DeviceGroup {
+                id: info.entry.group_id,
+                name: name.to_string(),
+                members: vec![],
+            })
+            .members
+            .push(device.clone());
+    }
+
+    let mut groups: Vec<DeviceGroup> = groups
+        .into_values()
+        .filter(|group| group.members.len() > 1)
+        .collect();
+    for group in &mut groups {
+        group.members.sort_by_key(|m| m.name());
+    }
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    groups
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupState {
+    pub on: bool,
+    pub brightness: u8,
+    pub color: DeviceColor,
+    /// The color temperature, or 0 if in color mode
+    pub kelvin: u32,
+}
+
+/// The group is on if any of its members are on. The brightness is
+/// the average of the members that are on, and the color is taken
+/// from the first member that is on.
+pub fn aggregate_group_state(states: &[DeviceState]) -> Option<GroupState> {
+    let lit: Vec<&DeviceState> = states
+        .iter()
+        .filter(|s| s.light_on.unwrap_or(s.on))
+        .collect();
+    let Some(first) = lit.first() else {
+        return if states.is_empty() {
+            None
+        } else {
+            Some(GroupState {
+                on: false,
+                brightness: 0,
+                color: DeviceColor::default(),
+                kelvin: 0,
+            })
+        };
+    };
+
+    let total: u32 = lit.iter().map(|s| s.brightness as u32).sum();
+    Some(GroupState {
+        on: true,
+        brightness: (total / lit.len() as u32) as u8,
+        color: first.color,
+        kelvin: first.kelvin,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn state(on: bool, brightness: u8, kelvin: u32) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: Some(on),
+            online: Some(true),
+            kelvin,
+            color: DeviceColor { r: 1, g: 2, b: 3 },
+            brightness,
+            scene: None,
+            source: "TEST",
+            updated: Utc::now(),
+            attributes: Default::default(),
+        }
+    }
+
+    #[test]
+    fn aggregate() {
+        assert_eq!(aggregate_group_state(&[]), None);
+
+        let off = aggregate_group_state(&[state(false, 50, 0), state(false, 20, 0)]).unwrap();
+        assert!(!off.on);
+
+        let mixed = aggregate_group_state(&[
+            state(false, 10, 0),
+            state(true, 40, 3000),
+            state(true, 80, 0),
+        ])
+        .unwrap();
+        assert!(mixed.on);
+        assert_eq!(mixed.brightness, 60);
+        assert_eq!(mixed.kelvin, 3000);
+    }
+}
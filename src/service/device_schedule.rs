@@ -0,0 +1,128 @@
+//! Schedules that were configured in the Govee app are stored in the
+//! device settings and run on the device itself, so a light that they
+//! turn on appears to do so by itself. They are parsed here so that
+//! they can be shown in Home Assistant.
+//!
+//! The settings are not documented and differ between models, so this
+//! accepts either a list of schedules or an object holding such a list,
+//! and ignores entries that don't look like a schedule. Since the layout
+//! is not known for certain, the schedules are never written back; doing
+//! so could clobber those that the user configured on the device.
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// The keys under which the list of schedules has been observed
+const LIST_KEYS: [&str; 3] = ["timing", "timers", "list"];
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceSchedule {
+    /// The position of the schedule in the list of schedules
+    pub index: usize,
+    pub enabled: bool,
+    /// The time at which the schedule turns the device on, as HH:MM
+    pub on_at: Option<String>,
+    /// The time at which the schedule turns the device off, as HH:MM
+    pub off_at: Option<String>,
+    /// The days on which the schedule repeats; empty if it runs once
+    pub days: Vec<&'static str>,
+}
+
+fn schedule_list(timer: &JsonValue) -> Option<&Vec<JsonValue>> {
+    match timer {
+        JsonValue::Array(list) => Some(list),
+        JsonValue::Object(map) => LIST_KEYS
+            .iter()
+            .find_map(|key| map.get(*key).and_then(|v| v.as_array())),
+        _ => None,
+    }
+}
+
+/// Interprets the `enable` field, which may be a bool or 0/1
+fn is_enabled(entry: &JsonValue) -> Option<bool> {
+    let value = entry.get("enable").or_else(|| entry.get("onOff"))?;
+    value.as_bool().or_else(|| value.as_i64().map(|n| n != 0))
+}
+
+fn time_of_day(entry: &JsonValue, prefix: &str) -> Option<String> {
+    let hour = entry.get(format!("{prefix}Hour"))?.as_u64()?;
+    let minute = entry.get(format!("{prefix}Min"))?.as_u64()?;
+    (hour < 24 && minute < 60).then(|| format!("{hour:02}:{minute:02}"))
+}
+
+/// Parse the `timer` device setting into the list of schedules
+pub fn parse_schedules(timer: &JsonValue) -> Vec<DeviceSchedule> {
+    let Some(list) = schedule_list(timer) else {
+        return vec![];
+    };
+
+    list.iter()
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let on_at = time_of_day(entry, "open");
+            let off_at = time_of_day(entry, "close");
+            if on_at.is_none() && off_at.is_none() {
+                return None;
+            }
+            // The high bit flags a repeating schedule, and the
+            // others are the days of the week, starting with Monday
+            let repeat = entry.get("repeat").and_then(|v| v.as_u64()).unwrap_or(0);
+            let days = DAYS
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| repeat & (1 << bit) != 0)
+                .map(|(_, day)| *day)
+                .collect();
+
+            Some(DeviceSchedule {
+                index,
+                enabled: is_enabled(entry).unwrap_or(true),
+                on_at,
+                off_at,
+                days,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn schedules() {
+        let timer = json!({
+            "timing": [
+                {"enable": 1, "openHour": 7, "openMin": 5, "closeHour": 23, "closeMin": 30, "repeat": 0x9f},
+                {"enable": 0, "closeHour": 1, "closeMin": 0, "repeat": 0},
+                {"enable": 1},
+            ]
+        });
+        assert_eq!(
+            parse_schedules(&timer),
+            vec![
+                DeviceSchedule {
+                    index: 0,
+                    enabled: true,
+                    on_at: Some("07:05".to_string()),
+                    off_at: Some("23:30".to_string()),
+                    days: vec!["mon", "tue", "wed", "thu", "fri"],
+                },
+                DeviceSchedule {
+                    index: 1,
+                    enabled: false,
+                    on_at: None,
+                    off_at: Some("01:00".to_string()),
+                    days: vec![],
+                },
+            ]
+        );
+
+        let timer = json!([{"onOff": true, "openHour": 18, "openMin": 0}]);
+        assert_eq!(parse_schedules(&timer)[0].on_at.as_deref(), Some("18:00"));
+
+        assert!(parse_schedules(&json!("garbage")).is_empty());
+    }
+}
@@ -0,0 +1,42 @@
+//! Entity id overrides ask Home Assistant to give an entity a
+//! particular entity id when it is first discovered, rather than
+//! one derived from its name. They are used to take over the entity
+//! ids of another integration, so that the history recorded for
+//! those entities carries on. The overrides are held in a JSON file
+//! mapping our unique ids to entity ids, which is usually produced
+//! by `govee migrate-from-cloud-integration`.
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type EntityIdOverrides = BTreeMap<String, String>;
+
+static OVERRIDES: Lazy<Mutex<EntityIdOverrides>> = Lazy::new(Default::default);
+
+pub fn load_entity_id_overrides(path: &Path) -> anyhow::Result<EntityIdOverrides> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_str(&data).with_context(|| format!("parsing {}", path.display()))
+}
+
+pub fn set_entity_id_overrides(overrides: EntityIdOverrides) {
+    *OVERRIDES.lock() = overrides;
+}
+
+/// Returns the entity id to use for the entity with `unique_id`,
+/// provided that it belongs to the `integration` domain
+pub fn entity_id_override(integration: &str, unique_id: &str) -> Option<String> {
+    let overrides = OVERRIDES.lock();
+    let entity_id = overrides.get(unique_id)?;
+    let (domain, _) = entity_id.split_once('.')?;
+    if domain != integration {
+        log::warn!(
+            "Ignoring entity id override {entity_id} for {unique_id}, \
+            which is a {integration} entity"
+        );
+        return None;
+    }
+    Some(entity_id.to_string())
+}
@@ -0,0 +1,111 @@
+//! A short history of notable events, such as devices changing state
+//! or commands failing, for tools like `govee tui` that show what the
+//! service has been doing without having to follow its log.
+use crate::service::device::{Device, DeviceState};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// How many events are kept
+const MAX_EVENTS: usize = 200;
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LoggedEvent {
+    pub time: DateTime<Utc>,
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+    pub message: String,
+}
+
+static EVENTS: Lazy<Mutex<VecDeque<LoggedEvent>>> = Lazy::new(Default::default);
+
+/// The most recently logged state summary of each device
+static LAST_SUMMARY: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+
+pub fn record_event<S: Into<String>>(device: Option<&Device>, message: S) {
+    let mut events = EVENTS.lock();
+    if events.len() >= MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(LoggedEvent {
+        time: Utc::now(),
+        device_id: device.map(|d| d.id.to_string()),
+        device_name: device.map(|d| d.name()),
+        message: message.into(),
+    });
+}
+
+/// A one line description of the parts of the state that
+/// a person would notice changing
+fn state_summary(state: &DeviceState) -> String {
+    if !state.light_on.unwrap_or(state.on) {
+        return "off".to_string();
+    }
+    let mut summary = format!("on, {}%", state.brightness);
+    match &state.scene {
+        Some(scene) => summary.push_str(&format!(", scene {scene}")),
+        None if state.kelvin != 0 => summary.push_str(&format!(", {}K", state.kelvin)),
+        None => {
+            let c = &state.color;
+            summary.push_str(&format!(", #{:02x}{:02x}{:02x}", c.r, c.g, c.b));
+        }
+    }
+    summary
+}
+
+/// Record the state of a device, if it differs from the
+/// state that was most recently recorded for it
+pub fn record_state_change(device: &Device, state: &DeviceState) {
+    let summary = state_summary(state);
+    let previous = LAST_SUMMARY
+        .lock()
+        .insert(device.id.to_string(), summary.clone());
+    if previous.as_ref() != Some(&summary) {
+        record_event(Some(device), format!("{summary} (via {})", state.source));
+    }
+}
+
+/// The logged events, oldest first
+pub fn recent_events() -> Vec<LoggedEvent> {
+    EVENTS.lock().iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_changes() {
+        let device = Device::new("H6008", "AA:BB:CC:DD:EE:FF:42:EE");
+        let mut state = DeviceState {
+            on: true,
+            light_on: None,
+            online: None,
+            kelvin: 2700,
+            color: Default::default(),
+            brightness: 80,
+            scene: None,
+            source: "LAN API",
+            updated: Utc::now(),
+            attributes: Default::default(),
+        };
+        let logged = || {
+            recent_events()
+                .into_iter()
+                .filter(|e| e.device_id.as_deref() == Some(device.id.as_str()))
+                .map(|e| e.message)
+                .collect::<Vec<_>>()
+        };
+
+        record_state_change(&device, &state);
+        record_state_change(&device, &state);
+        state.on = false;
+        record_state_change(&device, &state);
+        assert_eq!(
+            logged(),
+            vec!["on, 80%, 2700K (via LAN API)", "off (via LAN API)"]
+        );
+    }
+}
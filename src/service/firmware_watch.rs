@@ -0,0 +1,168 @@
+//! Firmware updates often change what a device can do, such as
+//! widening its color temperature range or adding segments, but
+//! nothing tells us that a device has been updated. The firmware
+//! version of each device is compared from one check to the next,
+//! and when it changes, the capabilities and scene library of the
+//! device are fetched again and its entities are re-registered.
+use crate::service::device::{normalize_device_id, Device};
+use crate::service::shard::owns_device;
+use crate::service::state::StateHandle;
+use crate::undoc_api::GoveeUndocumentedApi;
+use std::collections::HashMap;
+use tokio::time::{sleep, Duration};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The firmware versions most recently reported for a device.
+/// The LAN API and the undocumented API don't report the version
+/// in quite the same way, so each is compared only with itself.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+struct SeenVersions {
+    lan: Option<String>,
+    undoc: Option<String>,
+}
+
+impl SeenVersions {
+    fn of(device: &Device) -> Self {
+        Self {
+            lan: device
+                .lan_device
+                .as_ref()
+                .map(|lan| lan.ble_version_soft.clone())
+                .filter(|v| !v.is_empty()),
+            undoc: device
+                .undoc_device_info
+                .as_ref()
+                .map(|info| info.entry.version_soft.clone())
+                .filter(|v| !v.is_empty()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FirmwareWatch {
+    seen: HashMap<String, SeenVersions>,
+}
+
+impl FirmwareWatch {
+    /// Record the firmware versions of the device. Returns the prior
+    /// and current version if either API reports a different version
+    /// than it last did. An API that is temporarily not reporting the
+    /// device, such as while it is off the LAN, is not a change.
+    pub fn observe(&mut self, device: &Device) -> Option<(String, String)> {
+        let current = SeenVersions::of(device);
+        let seen = self.seen.entry(device.id.to_string()).or_default();
+
+        let mut change = None;
+        for (seen, now) in [
+            (&mut seen.lan, current.lan),
+            (&mut seen.undoc, current.undoc),
+        ] {
+            let Some(now) = now else {
+                continue;
+            };
+            if let Some(before) = seen.replace(now.clone()) {
+                if before != now && change.is_none() {
+                    change = Some((before, now));
+                }
+            }
+        }
+        change
+    }
+}
+
+/// Fetch the capabilities and scene library of a device again,
+/// bypassing the caches
+async fn requery_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
+    if let Some(client) = state.get_platform_client_for(device).await {
+        client.invalidate_device_list()?;
+        if let Some(info) = client
+            .get_devices()
+            .await?
+            .into_iter()
+            .find(|info| normalize_device_id(&info.device) == normalize_device_id(&device.id))
+        {
+            state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_http_device_info(info);
+        }
+    }
+
+    crate::cache::invalidate_key("undoc-api", &format!("scenes-{}", device.sku))?;
+    GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await?;
+    Ok(())
+}
+
+pub async fn periodic_firmware_watch(state: StateHandle) -> anyhow::Result<()> {
+    let mut watch = FirmwareWatch::default();
+    loop {
+        let mut changed = false;
+        for device in state.devices().await.into_iter().filter(owns_device) {
+            let Some((before, after)) = watch.observe(&device) else {
+                continue;
+            };
+            log::info!(
+                "{device} firmware changed from {before} to {after}; \
+                fetching its capabilities and scenes again"
+            );
+            if let Err(err) = requery_device(&state, &device).await {
+                log::warn!("Failed to fetch the capabilities of {device}: {err:#}");
+            }
+            changed = true;
+        }
+
+        if changed {
+            if let Some(client) = state.get_hass_client().await {
+                client.register_with_hass(&state).await?;
+            }
+        }
+
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::LanDevice;
+
+    fn lan_device(version: &str) -> LanDevice {
+        LanDevice {
+            ip: "10.0.0.2".parse().unwrap(),
+            device: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+            sku: "H6000".to_string(),
+            ble_version_hard: String::new(),
+            ble_version_soft: version.to_string(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        }
+    }
+
+    #[test]
+    fn firmware_changes() {
+        let mut watch = FirmwareWatch::default();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+
+        // Nothing is known about the device yet
+        assert_eq!(watch.observe(&device), None);
+        // The first version that we see is not a change
+        device.set_lan_device(lan_device("1.00.10"));
+        assert_eq!(watch.observe(&device), None);
+        assert_eq!(watch.observe(&device), None);
+
+        device.set_lan_device(lan_device("1.00.12"));
+        assert_eq!(
+            watch.observe(&device),
+            Some(("1.00.10".to_string(), "1.00.12".to_string()))
+        );
+        assert_eq!(watch.observe(&device), None);
+
+        // Dropping off the LAN and returning with the
+        // same version is not a change
+        device.lan_device = None;
+        assert_eq!(watch.observe(&device), None);
+        device.set_lan_device(lan_device("1.00.12"));
+        assert_eq!(watch.observe(&device), None);
+    }
+}
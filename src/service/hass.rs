@@ -1,24 +1,65 @@
 use crate::hass_mqtt::climate::mqtt_set_temperature;
 use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
+use crate::hass_mqtt::event::publish_command_failed;
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
-use crate::hass_mqtt::select::mqtt_set_mode_scene;
-use crate::lan_api::DeviceColor;
+use crate::hass_mqtt::light::GroupLight;
+use crate::hass_mqtt::number::{
+    mqtt_active_work_mode_number_command, mqtt_capability_number_command, mqtt_number_command,
+};
+use crate::hass_mqtt::select::{
+    mqtt_set_color_preset, mqtt_set_display_unit, mqtt_set_mode_scene, mqtt_set_work_mode_preset,
+};
+use crate::lan_api::{truthy, DeviceColor};
 use crate::opt_env_var;
 use crate::platform_api::{from_json, DeviceType};
+use crate::service::cloud_failure::{
+    cloud_failure_policy, command_sequence, is_cloud_failure, track_command_device,
+    CloudFailurePolicy, MAX_COMMAND_AGE, QUEUE_RETRY_INTERVAL,
+};
+use crate::service::color_preset::ColorPreset;
+use crate::service::contract::{mqtt_contract_command, publish_contract_device};
+use crate::service::derived::DerivedSensorDefinition;
 use crate::service::device::Device as ServiceDevice;
+use crate::service::device_customization::{
+    load_device_customizations, parse_device_customizations, set_device_customizations,
+    DeviceCustomizations,
+};
+use crate::service::device_group::device_groups;
+use crate::service::entity_overrides::{
+    load_entity_id_overrides, set_entity_id_overrides, EntityIdOverrides,
+};
+use crate::service::mqtt_mirror::{spawn_mqtt_mirror, MirrorConfig, MqttMirror};
+use crate::service::playlist::{
+    parse_duration, playlist_command, PlaylistDefinition, PlaylistDocument,
+};
+use crate::service::power_save::set_power_save;
+use crate::service::scene_filter::SceneFilterRule;
+use crate::service::segment_control::SegmentChange;
+use crate::service::segment_layout::SegmentLayout;
+use crate::service::shard::{
+    bridge_availability_topic, bridge_claims_topic, is_leader, is_sharded, owns_device,
+    shard_config, update_bridge_availability, update_bridge_claims, NotOwnedByThisBridge,
+    ShardConfig,
+};
+use crate::service::stall_detector::with_timeout;
 use crate::service::state::StateHandle;
+use crate::service::transaction::Transaction;
+use crate::service::watchdog::{mqtt_watchdog_topic, watchdog_topics};
 use crate::temperature::TemperatureScale;
 use anyhow::Context;
 use async_channel::Receiver;
-use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
-use mosquitto_rs::{Client, Event, QoS};
+use mosquitto_rs::router::{MqttRouter, Params, Payload, RouterError, State};
+use mosquitto_rs::{Client, Event, Message, QoS};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
+/// Where the aggregate result of a `gv2mqtt/transaction` is published
+const TRANSACTION_RESULT_TOPIC: &str = "gv2mqtt/transaction/result";
 
 #[derive(clap::Parser, Debug)]
 pub struct HassArguments {
@@ -46,9 +87,62 @@ pub struct HassArguments {
     #[arg(long, global = true)]
     mqtt_bind_address: Option<String>,
 
+    /// Run an embedded mqtt broker, listening on the specified
+    /// address, eg: `0.0.0.0:1883`. When no --mqtt-host is specified,
+    /// govee2mqtt will connect to the embedded broker itself.
+    /// If a username and password are configured, clients of the
+    /// embedded broker must supply them.
+    /// You may also set this via the GOVEE_MQTT_BROKER_LISTEN
+    /// environment variable.
+    #[cfg(feature = "embedded-broker")]
+    #[arg(long, global = true)]
+    mqtt_broker_listen: Option<String>,
+
     #[arg(long, global = true, default_value = "homeassistant")]
     hass_discovery_prefix: String,
 
+    /// The topic on which Home Assistant publishes its birth message
+    /// when it starts. Defaults to the `status` topic under the
+    /// discovery prefix, eg: `homeassistant/status`.
+    /// You may also set this via the GOVEE_HASS_BIRTH_TOPIC
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_birth_topic: Option<String>,
+
+    /// The payload of the Home Assistant birth message. When it is
+    /// received, the entities are registered with Home Assistant again
+    /// and their current state is published. Defaults to `online`.
+    /// You may also set this via the GOVEE_HASS_BIRTH_PAYLOAD
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_birth_payload: Option<String>,
+
+    /// Mirror the state that is published to the mqtt broker to a
+    /// second, read-only, broker; eg: one that feeds a remote dashboard.
+    /// Commands are accepted only from the primary broker.
+    /// You may also set this via the GOVEE_MQTT_MIRROR_HOST
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_mirror_host: Option<String>,
+
+    /// The port of the mirror mqtt broker. If unspecified, uses 1883.
+    /// You may also set this via the GOVEE_MQTT_MIRROR_PORT
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_mirror_port: Option<u16>,
+
+    /// The username to authenticate against the mirror broker.
+    /// You may also set this via the GOVEE_MQTT_MIRROR_USER
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_mirror_username: Option<String>,
+
+    /// The password to authenticate against the mirror broker.
+    /// You may also set this via the GOVEE_MQTT_MIRROR_PASSWORD
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_mirror_password: Option<String>,
+
     /// The temperature scale to use when showing temperature values as
     /// entities in home assistant. Can be either "C" or "F" for Celsius
     /// or Farenheit respectively.
@@ -56,6 +150,123 @@ pub struct HassArguments {
     /// variable.
     #[arg(long, global = true)]
     temperature_scale: Option<String>,
+
+    /// The URL at which the govee2mqtt HTTP service can be reached
+    /// by your dashboards, eg: `http://192.168.1.10:8056`.
+    /// When set, scene icons are served and cached by govee2mqtt
+    /// and their URLs are included in the light entity attributes.
+    /// You may also set this via the GOVEE_SCENE_ICON_BASE_URL
+    /// environment variable.
+    #[arg(long, global = true)]
+    scene_icon_base_url: Option<String>,
+
+    /// The ids or names of devices whose light state should be
+    /// re-applied when they come back online after losing power.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_RESTORE_AFTER_POWER_LOSS=Lamp,Strip
+    /// via the environment.
+    #[arg(long, global = true)]
+    restore_after_power_loss: Vec<String>,
+
+    /// The physical order of the segments of a device, for lights
+    /// such as neon rope that are installed in shapes that don't
+    /// follow the segment numbering of the device. In the form
+    /// `Device=7-0,8-14`; the segment indices used by the device,
+    /// listed from one end of the installation to the other, where
+    /// `a-b` is an inclusive range that may count down.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_SEGMENT_LAYOUTS=Rope=7-0,8-14;Strip=3-0
+    /// via the environment, separating each layout with a semicolon.
+    #[arg(long, global = true)]
+    segment_layout: Vec<SegmentLayout>,
+
+    /// A named color to offer in the Color Preset select of each
+    /// light, in the form `Name=Color[@Brightness%]`, where the color
+    /// is either a CSS color such as `#ffbf00` or a color temperature
+    /// such as `4000K`. Prefix the name with `Device:` to offer the
+    /// preset for only that device.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_COLOR_PRESETS=Movie amber=#ffbf00@40%;Reading white=4000K
+    /// via the environment, separating each preset with a semicolon.
+    #[arg(long, global = true)]
+    color_preset: Vec<ColorPreset>,
+
+    /// Choose which scenes are offered for a model of device, in the
+    /// form `SKU=SCENE,...`, where the SKU may be `*` for all devices,
+    /// and each scene is either a scene name or `category:NAME` for a
+    /// whole category of scenes. When any scenes are listed, only those
+    /// are offered; prefix a scene with `-` to exclude it instead.
+    /// eg: `H6199=category:Nature,-Sunset`.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_SCENE_FILTERS=H6199=category:Nature;*=-category:Other
+    /// via the environment, separating each rule with a semicolon.
+    #[arg(long, global = true)]
+    scene_filter: Vec<SceneFilterRule>,
+
+    /// Offer the scenes of each device as one select per category
+    /// of scenes, eg: Nature or Festival, as well as via the effect
+    /// list of the light.
+    /// You may also set GOVEE_SCENE_SELECT_PER_CATEGORY=true via the environment.
+    #[arg(long, global = true)]
+    scene_select_per_category: bool,
+
+    /// Additional sensors to compute from the temperature and humidity
+    /// readings of each sensor device. Either the name of a preset
+    /// (dew_point, absolute_humidity, vpd, heat_index or frost_risk),
+    /// or a custom definition in the form `Name[unit]=expression`.
+    /// Prefix a definition with `Device:` to add it to only that
+    /// device, eg: `Outdoor Sensor:heat_index`.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_DERIVED_SENSORS=dew_point;vpd via the
+    /// environment, separating each definition with a semicolon.
+    #[arg(long, global = true)]
+    derived_sensor: Vec<String>,
+
+    /// When set, sensor devices gain sensors that report how quickly
+    /// their temperature and humidity are changing, per hour, over
+    /// a sliding window of this duration, eg: `30m` or `1h`.
+    /// You may also set this via the GOVEE_RATE_OF_CHANGE_WINDOW
+    /// environment variable.
+    #[arg(long, global = true)]
+    rate_of_change_window: Option<String>,
+
+    /// The name of this bridge instance, when running several bridges
+    /// that share the devices between them. Each bridge must have a
+    /// different name.
+    /// You may also set this via the GOVEE_BRIDGE_INSTANCE
+    /// environment variable.
+    #[arg(long, global = true)]
+    bridge_instance: Option<String>,
+
+    /// The id or name of a device that this bridge instance should
+    /// handle, rather than leaving it to be assigned automatically.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_SHARD_CLAIMS=Lamp,Strip via the environment.
+    #[arg(long, global = true)]
+    shard_claim: Vec<String>,
+
+    /// The path to a YAML file defining scene playlists.
+    /// You may also set this via the GOVEE_PLAYLISTS_FILE
+    /// environment variable.
+    #[arg(long, global = true)]
+    playlists_file: Option<PathBuf>,
+
+    /// The path to a JSON file mapping the unique ids of entities to
+    /// the entity ids that Home Assistant should give them, as
+    /// produced by `govee migrate-from-cloud-integration`.
+    /// You may also set this via the GOVEE_ENTITY_ID_OVERRIDES
+    /// environment variable.
+    #[arg(long, global = true)]
+    entity_id_overrides: Option<PathBuf>,
+
+    /// The path to a YAML file mapping device ids, device names or
+    /// SKUs to the name, area, icon and disabled entities to use for
+    /// them in Home Assistant.
+    /// You may also set this via the GOVEE_DEVICE_CUSTOMIZATIONS
+    /// environment variable, or list them in the `devices` section
+    /// of the configuration file.
+    #[arg(long, global = true)]
+    device_customizations: Option<PathBuf>,
 }
 
 impl HassArguments {
@@ -75,6 +286,14 @@ impl HassArguments {
         })
     }
 
+    #[cfg(feature = "embedded-broker")]
+    pub fn mqtt_broker_listen(&self) -> anyhow::Result<Option<String>> {
+        match &self.mqtt_broker_listen {
+            Some(addr) => Ok(Some(addr.to_string())),
+            None => opt_env_var("GOVEE_MQTT_BROKER_LISTEN"),
+        }
+    }
+
     pub fn mqtt_port(&self) -> anyhow::Result<u16> {
         match self.mqtt_port {
             Some(p) => Ok(p),
@@ -96,6 +315,210 @@ impl HassArguments {
         }
     }
 
+    pub fn birth_message(&self) -> anyhow::Result<BirthMessage> {
+        let topic = match &self.hass_birth_topic {
+            Some(topic) => topic.to_string(),
+            None => opt_env_var("GOVEE_HASS_BIRTH_TOPIC")?
+                .unwrap_or_else(|| format!("{}/status", self.hass_discovery_prefix)),
+        };
+        let payload = match &self.hass_birth_payload {
+            Some(payload) => payload.to_string(),
+            None => {
+                opt_env_var("GOVEE_HASS_BIRTH_PAYLOAD")?.unwrap_or_else(|| "online".to_string())
+            }
+        };
+        anyhow::ensure!(
+            !topic.contains(['+', '#']),
+            "the birth topic {topic} must not contain wildcards"
+        );
+        Ok(BirthMessage { topic, payload })
+    }
+
+    pub fn mirror_config(&self) -> anyhow::Result<Option<MirrorConfig>> {
+        let host = match &self.mqtt_mirror_host {
+            Some(host) => host.to_string(),
+            None => match opt_env_var("GOVEE_MQTT_MIRROR_HOST")? {
+                Some(host) => host,
+                None => return Ok(None),
+            },
+        };
+        let port = match self.mqtt_mirror_port {
+            Some(port) => port,
+            None => opt_env_var("GOVEE_MQTT_MIRROR_PORT")?.unwrap_or(1883),
+        };
+        let username = match &self.mqtt_mirror_username {
+            Some(u) => Some(u.to_string()),
+            None => opt_env_var("GOVEE_MQTT_MIRROR_USER")?,
+        };
+        let password = match &self.mqtt_mirror_password {
+            Some(p) => Some(p.to_string()),
+            None => opt_env_var("GOVEE_MQTT_MIRROR_PASSWORD")?,
+        };
+        anyhow::ensure!(
+            username.is_some() == password.is_some(),
+            "The mirror MQTT username and password either both need to be set, \
+            or both need to be unset"
+        );
+        Ok(Some(MirrorConfig {
+            host,
+            port,
+            username,
+            password,
+        }))
+    }
+
+    pub fn scene_icon_base_url(&self) -> anyhow::Result<Option<String>> {
+        match &self.scene_icon_base_url {
+            Some(url) => Ok(Some(url.to_string())),
+            None => opt_env_var("GOVEE_SCENE_ICON_BASE_URL"),
+        }
+    }
+
+    pub fn restore_after_power_loss(&self) -> anyhow::Result<Vec<String>> {
+        let mut devices = self.restore_after_power_loss.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_RESTORE_AFTER_POWER_LOSS")? {
+            devices.extend(
+                v.split(',')
+                    .map(|d| d.trim().to_string())
+                    .filter(|d| !d.is_empty()),
+            );
+        }
+        Ok(devices)
+    }
+
+    pub fn segment_layouts(&self) -> anyhow::Result<Vec<SegmentLayout>> {
+        let mut layouts = self.segment_layout.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_SEGMENT_LAYOUTS")? {
+            for spec in v.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                layouts.push(spec.parse()?);
+            }
+        }
+        Ok(layouts)
+    }
+
+    pub fn color_presets(&self) -> anyhow::Result<Vec<ColorPreset>> {
+        let mut presets = self.color_preset.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_COLOR_PRESETS")? {
+            for spec in v.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                presets.push(spec.parse()?);
+            }
+        }
+        Ok(presets)
+    }
+
+    pub fn scene_filters(&self) -> anyhow::Result<Vec<SceneFilterRule>> {
+        let mut rules = self.scene_filter.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_SCENE_FILTERS")? {
+            for spec in v.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                rules.push(
+                    spec.parse()
+                        .with_context(|| format!("scene filter '{spec}'"))?,
+                );
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn scene_select_per_category(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_SCENE_SELECT_PER_CATEGORY")? {
+            return truthy(&v);
+        }
+        Ok(self.scene_select_per_category)
+    }
+
+    pub fn derived_sensors(&self) -> anyhow::Result<Vec<DerivedSensorDefinition>> {
+        let mut specs = self.derived_sensor.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_DERIVED_SENSORS")? {
+            specs.extend(
+                v.split(';')
+                    .map(|d| d.trim().to_string())
+                    .filter(|d| !d.is_empty()),
+            );
+        }
+        specs
+            .iter()
+            .map(|spec| DerivedSensorDefinition::parse(spec))
+            .collect()
+    }
+
+    pub fn rate_of_change_window(&self) -> anyhow::Result<Option<Duration>> {
+        let window = match &self.rate_of_change_window {
+            Some(w) => Some(w.to_string()),
+            None => opt_env_var::<String>("GOVEE_RATE_OF_CHANGE_WINDOW")?,
+        };
+        let Some(window) = window else {
+            return Ok(None);
+        };
+        let window = parse_duration(&window).context("rate of change window")?;
+        anyhow::ensure!(
+            window >= Duration::from_secs(60),
+            "the rate of change window must be at least 1m"
+        );
+        Ok(Some(window))
+    }
+
+    pub fn shard_config(&self) -> anyhow::Result<Option<ShardConfig>> {
+        let instance = match &self.bridge_instance {
+            Some(instance) => Some(instance.to_string()),
+            None => opt_env_var::<String>("GOVEE_BRIDGE_INSTANCE")?,
+        };
+        let Some(instance) = instance else {
+            return Ok(None);
+        };
+        let instance = topic_safe_string(&instance);
+        anyhow::ensure!(!instance.is_empty(), "the bridge instance name is empty");
+
+        let mut claims = self.shard_claim.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_SHARD_CLAIMS")? {
+            claims.extend(
+                v.split(',')
+                    .map(|d| d.trim().to_string())
+                    .filter(|d| !d.is_empty()),
+            );
+        }
+        Ok(Some(ShardConfig { instance, claims }))
+    }
+
+    pub fn playlists(&self) -> anyhow::Result<Vec<PlaylistDefinition>> {
+        let path = match &self.playlists_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_PLAYLISTS_FILE")?,
+        };
+        match path {
+            Some(path) => Ok(PlaylistDocument::load_yaml(&path)?.playlists),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn entity_id_overrides(&self) -> anyhow::Result<EntityIdOverrides> {
+        let path = match &self.entity_id_overrides {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_ENTITY_ID_OVERRIDES")?,
+        };
+        match path {
+            Some(path) => load_entity_id_overrides(&path),
+            None => Ok(EntityIdOverrides::new()),
+        }
+    }
+
+    /// The customizations from the file come first, so that those
+    /// from the `devices` section of the configuration file
+    /// take precedence for the same key
+    pub fn device_customizations(&self) -> anyhow::Result<DeviceCustomizations> {
+        let path = match &self.device_customizations {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_DEVICE_CUSTOMIZATIONS")?,
+        };
+        let mut customizations = match path {
+            Some(path) => load_device_customizations(&path)?,
+            None => DeviceCustomizations::new(),
+        };
+        if let Some(v) = opt_env_var::<String>("GOVEE_DEVICES")? {
+            customizations.extend(parse_device_customizations(&v).context("GOVEE_DEVICES")?);
+        }
+        Ok(customizations)
+    }
+
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
@@ -106,18 +529,79 @@ impl HassArguments {
     }
 }
 
+/// The message that Home Assistant publishes when it starts
+#[derive(Clone, Debug, Default)]
+pub struct BirthMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// The topics and payloads of the messages published via a capture client
+#[cfg(test)]
+pub type CapturedMessages = Arc<parking_lot::Mutex<Vec<(String, String)>>>;
+
+/// Where the messages published via a HassClient go
+#[derive(Clone)]
+enum MessageSink {
+    Broker(Client),
+    /// The messages are kept rather than sent, so that tests
+    /// can examine exactly what would have been published
+    #[cfg(test)]
+    Capture(CapturedMessages),
+}
+
 #[derive(Clone)]
 pub struct HassClient {
-    client: Client,
+    sink: MessageSink,
+    mirror: Option<MqttMirror>,
 }
 
 impl HassClient {
-    async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
+    /// A client that keeps the topics and payloads of the messages
+    /// that are published, rather than sending them to a broker
+    #[cfg(test)]
+    pub fn capture() -> (Self, CapturedMessages) {
+        let messages = Arc::new(parking_lot::Mutex::new(vec![]));
+        (
+            Self {
+                sink: MessageSink::Capture(messages.clone()),
+                mirror: None,
+            },
+            messages,
+        )
+    }
+
+    pub async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
         let entities = enumerate_all_entites(state).await?;
 
         // Register the configs
         log::trace!("register_with_hass: register entities");
+        crate::hass_mqtt::discovery::begin_registration();
         entities.publish_config(state, self).await?;
+        let stats = crate::hass_mqtt::discovery::stats();
+        log::info!(
+            "Published {} discovery payloads totalling {} bytes ({} bytes before minimization)",
+            stats.payloads,
+            stats.published_bytes,
+            stats.full_bytes
+        );
+
+        // Only the devices owned by this bridge; the configs of the
+        // others are published by their own bridge
+        let live_devices: Vec<String> = state
+            .devices()
+            .await
+            .into_iter()
+            .filter(owns_device)
+            .map(|d| d.id)
+            .collect();
+        for topic in crate::hass_mqtt::discovery::finish_registration(&live_devices) {
+            log::info!("Removing stale discovery config {topic}");
+            // An empty config removes the entity from hass
+            self.publish(&topic, "")
+                .await
+                .with_context(|| format!("removing stale config {topic}"))?;
+        }
 
         // Allow hass extra time to register the entities before
         // we mark them as available
@@ -130,14 +614,33 @@ impl HassClient {
 
         // Mark as available
         log::trace!("register_with_hass: mark as online");
-        self.publish(availability_topic(), "online")
-            .await
-            .context("online -> availability_topic")?;
+        if let Some(config) = shard_config() {
+            // Retained, so that bridges that start later learn of us
+            self.publish_retained(bridge_claims_topic(&config.instance), &config.claims)
+                .await
+                .context("claims -> bridge_claims_topic")?;
+            self.publish_raw(availability_topic(), "online", true)
+                .await
+                .context("online -> availability_topic")?;
+        } else {
+            self.publish(availability_topic(), "online")
+                .await
+                .context("online -> availability_topic")?;
+        }
 
         // report initial state
         log::trace!("register_with_hass: reporting state");
         entities.notify_state(self).await.context("notify_state")?;
 
+        log::trace!("register_with_hass: publishing contract topics");
+        for device in state.devices().await {
+            if owns_device(&device) {
+                publish_contract_device(self, &device)
+                    .await
+                    .context("publish_contract_device")?;
+            }
+        }
+
         log::trace!("register_with_hass: done");
 
         Ok(())
@@ -149,9 +652,33 @@ impl HassClient {
         payload: P,
     ) -> anyhow::Result<()> {
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
+        self.publish_raw(topic, payload, false).await
+    }
+
+    /// Publish to the broker, and queue the message for the mirror
+    async fn publish_raw<T: AsRef<str>, P: AsRef<[u8]>>(
+        &self,
+        topic: T,
+        payload: P,
+        retain: bool,
+    ) -> anyhow::Result<()> {
+        if let Some(mirror) = &self.mirror {
+            mirror.mirror(topic.as_ref(), payload.as_ref(), retain);
+        }
+        match &self.sink {
+            MessageSink::Broker(client) => {
+                client
+                    .publish(topic, payload, QoS::AtMostOnce, retain)
+                    .await?;
+            }
+            #[cfg(test)]
+            MessageSink::Capture(messages) => {
+                messages.lock().push((
+                    topic.as_ref().to_string(),
+                    String::from_utf8_lossy(payload.as_ref()).to_string(),
+                ));
+            }
+        }
         Ok(())
     }
 
@@ -162,10 +689,17 @@ impl HassClient {
     ) -> anyhow::Result<()> {
         let payload = serde_json::to_string(&payload)?;
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
-        Ok(())
+        self.publish_raw(topic, payload, false).await
+    }
+
+    pub async fn publish_retained<T: AsRef<str> + std::fmt::Display, P: Serialize>(
+        &self,
+        topic: T,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&payload)?;
+        log::trace!("{topic} -> {payload} (retained)");
+        self.publish_raw(topic, payload, true).await
     }
 
     pub async fn advise_hass_of_light_state(
@@ -175,10 +709,30 @@ impl HassClient {
     ) -> anyhow::Result<()> {
         let mut entities = EntityList::new();
         enumerate_entities_for_device(device, state, &mut entities).await?;
+        if is_leader() {
+            for group in device_groups(&state.devices().await) {
+                if group.contains(&device.id) {
+                    entities.add(GroupLight::new(&group, state));
+                }
+            }
+        }
         entities.notify_state(self).await?;
 
         Ok(())
     }
+
+    /// Publish the entity configs for a device that was discovered
+    /// after we registered with hass, or whose entities have changed
+    pub async fn register_device(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(device, state, &mut entities).await?;
+        entities.publish_config(state, self).await?;
+        entities.notify_state(self).await
+    }
 }
 
 pub fn topic_safe_string(s: &str) -> String {
@@ -207,6 +761,20 @@ pub fn switch_instance_state_topic(device: &ServiceDevice, instance: &str) -> St
     )
 }
 
+pub fn extended_control_command_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "gv2mqtt/{id}/extended/{instance}/command",
+        id = topic_safe_id(device)
+    )
+}
+
+pub fn extended_control_state_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "gv2mqtt/{id}/extended/{instance}/state",
+        id = topic_safe_id(device)
+    )
+}
+
 pub fn light_state_topic(device: &ServiceDevice) -> String {
     format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
 }
@@ -219,9 +787,13 @@ pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String
 }
 
 /// All entities use the same topic so that we can mark unavailable
-/// via last-will
+/// via last-will. When several bridges share the devices, each
+/// has its own topic.
 pub fn availability_topic() -> String {
-    "gv2mqtt/availability".to_string()
+    match shard_config() {
+        Some(config) => bridge_availability_topic(&config.instance),
+        None => "gv2mqtt/availability".to_string(),
+    }
 }
 
 pub fn oneclick_topic() -> String {
@@ -232,11 +804,28 @@ pub fn purge_cache_topic() -> String {
     "gv2mqtt/purge-caches".to_string()
 }
 
+pub fn power_save_command_topic() -> String {
+    "gv2mqtt/power-save/set".to_string()
+}
+
+pub fn power_save_state_topic() -> String {
+    "gv2mqtt/power-save/state".to_string()
+}
+
 #[derive(Deserialize)]
 pub struct IdParameter {
     pub id: String,
 }
 
+/// Someone clicked the "Load All Scenes" button
+async fn mqtt_load_all_scenes(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(&id).await?;
+    state.load_all_scenes(&device).await
+}
+
 /// Someone clicked the "Request Platform API State" button
 async fn mqtt_request_platform_data(
     Params(IdParameter { id }): Params<IdParameter>,
@@ -259,15 +848,133 @@ struct HassLightCommand {
     brightness: Option<u8>,
 }
 
+impl HassLightCommand {
+    /// Commands that change the same properties of a light
+    /// supersede one another
+    fn coalesce_key(&self) -> String {
+        let mut key = format!("light:{}", self.state);
+        for (present, name) in [
+            (self.brightness.is_some(), "brightness"),
+            (self.color.is_some(), "color"),
+            (self.color_temp.is_some(), "color_temp"),
+            (self.effect.is_some(), "effect"),
+        ] {
+            if present {
+                key.push('+');
+                key.push_str(name);
+            }
+        }
+        key
+    }
+}
+
 /// HASS is sending a command to a light
 async fn mqtt_light_command(
     Payload(payload): Payload<String>,
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
+    light_command(&state, &id, &payload).await
+}
 
-    let command: HassLightCommand = serde_json::from_str(&payload)?;
+/// HASS is sending a command to a feast (eg: DreamView) group.
+/// The members follow the lead of the leader device, so we send
+/// the command to the leader and then refresh the state of the
+/// members so that it remains coherent.
+async fn mqtt_feast_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let feast = state.resolve_feast(&id).await?;
+    log::info!("Command for feast {}: {payload}", feast.name);
+
+    light_command(&state, &feast.leader.device, &payload).await?;
+
+    tokio::spawn(async move {
+        // Give the members a moment to follow the leader
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        for member in &feast.members {
+            if let Some(device) = state.device_by_id(&member.device).await {
+                if let Err(err) = state.refresh_device_state(&device).await {
+                    log::warn!(
+                        "Failed to refresh {device} in feast {}: {err:#}",
+                        feast.name
+                    );
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// HASS is sending a command to a device group. The command is
+/// sent to each of the member lights at the same time. Members
+/// that are handled by another bridge are sent the command via
+/// their own command topic.
+async fn mqtt_group_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let group = device_groups(&state.devices().await)
+        .into_iter()
+        .find(|group| group.id.to_string() == id)
+        .ok_or_else(|| anyhow::anyhow!("group '{id}' not found"))?;
+    log::info!("Command for group {}: {payload}", group.name);
+
+    // Send to all of the members concurrently, so that
+    // they change together
+    let hass = state.get_hass_client().await;
+    let mut tasks = JoinSet::new();
+    for member in group.members {
+        let state = state.clone();
+        let payload = payload.clone();
+        let hass = hass.clone();
+        tasks.spawn(async move {
+            let result = if owns_device(&member) {
+                light_command(&state, &member.id, &payload).await
+            } else if let Some(hass) = hass {
+                hass.publish(
+                    format!("gv2mqtt/light/{}/command", topic_safe_id(&member)),
+                    payload,
+                )
+                .await
+            } else {
+                Ok(())
+            };
+            result.with_context(|| format!("sending group command to {member}"))
+        });
+    }
+
+    let mut failures = 0;
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                log::error!("{err:#}");
+                failures += 1;
+            }
+            Err(err) => log::error!("group command task failed: {err:#}"),
+        }
+    }
+    anyhow::ensure!(
+        failures == 0,
+        "{failures} member(s) of group {} failed",
+        group.name
+    );
+    Ok(())
+}
+
+pub async fn light_command(state: &StateHandle, id: &str, payload: &str) -> anyhow::Result<()> {
+    let command: HassLightCommand = serde_json::from_str(payload)?;
+    let Some(device) = state
+        .resolve_device_for_coalesced_control(id, &command.coalesce_key())
+        .await?
+    else {
+        return Ok(());
+    };
     log::info!("Command for {device}: {payload}");
 
     let is_light = device.device_type() == DeviceType::Light;
@@ -287,6 +994,25 @@ async fn mqtt_light_command(
     } else {
         let mut power_on = true;
 
+        // If we're changing both the brightness and the color at the
+        // same time, try to send them together so that the light
+        // doesn't visibly step through the intermediate state
+        if let (Some(brightness), None) = (command.brightness, &command.effect) {
+            if (command.color.is_some() || command.color_temp.is_some())
+                && state
+                    .try_combined_light_command(
+                        &device,
+                        brightness,
+                        command.color,
+                        command.color_temp.map(mired_to_kelvin),
+                    )
+                    .await
+                    .context("mqtt_light_command: state.try_combined_light_command")?
+            {
+                return Ok(());
+            }
+        }
+
         if let Some(brightness) = command.brightness {
             state
                 .device_set_brightness(&device, brightness)
@@ -355,44 +1081,60 @@ async fn mqtt_light_segment_command(
     Params(IdAndSeg { id, segment }): Params<IdAndSeg>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
     let segment: u32 = segment.parse()?;
-
     let command: HassLightCommand = from_json(&payload)?;
+    let Some(device) = state
+        .resolve_device_for_coalesced_control(
+            &id,
+            &format!("segment-{segment}:{}", command.coalesce_key()),
+        )
+        .await?
+    else {
+        return Ok(());
+    };
     log::info!("Command for {device} segment {segment}: {payload}");
+    let segment = state.physical_segment(&device, segment).await;
+
+    // When turning off, do nothing here. We used to set brightness
+    // to zero, but it is problematic:
+    // * Some devices don't have a 0
+    // * Setting it to 0 will power up the rest of the device,
+    //   so if HASS is turning off all lights in an area, the
+    //   effect is that they will turn off and then immediate
+    //   on again when there are segments involved
+    if command.brightness.is_none() && command.color.is_none() && command.color_temp.is_none() {
+        return Ok(());
+    }
 
-    if let Some(client) = state.get_platform_client().await {
-        let info = device
-            .http_device_info
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
-
-        log::info!("Using Platform API to control {device} segment");
+    state
+        .device_set_segments(
+            &device,
+            &[SegmentChange {
+                segment,
+                color: command.color,
+                color_temp_kelvin: command.color_temp.map(mired_to_kelvin),
+                brightness: command.brightness,
+            }],
+        )
+        .await
+}
 
-        if let Some(brightness) = command.brightness {
-            client
-                .set_segment_brightness(&info, segment, brightness)
-                .await?;
-        } else if command.state == "OFF" {
-            // Do nothing here. We used to set brightness to zero,
-            // but it is problematic:
-            // * Some devices don't have a 0
-            // * Setting it to 0 will power up the rest of the device,
-            //   so if HASS is turning off all lights in an area, the
-            //   effect is that they will turn off and then immediate
-            //   on again when there are segments involved
-            // client.set_segment_brightness(&info, segment, 0).await?;
-        }
-        if let Some(color) = &command.color {
-            client
-                .set_segment_rgb(&info, segment, color.r, color.g, color.b)
-                .await?;
-        }
-    } else {
-        anyhow::bail!("set segments for {device}: Platform API is not available");
+/// Set the color and/or brightness of several segments at once.
+/// The payload is a JSON list of segment changes, eg:
+/// `[{"segment": 0, "color": {"r": 255, "g": 0, "b": 0}, "brightness": 50}]`.
+/// A segment may have a `color_temp_kelvin` in place of a `color`.
+async fn mqtt_light_segments_set(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let mut changes: Vec<SegmentChange> = from_json(&payload)?;
+    log::info!("Segments command for {device}: {payload}");
+    for change in &mut changes {
+        change.segment = state.physical_segment(&device, change.segment).await;
     }
-
-    Ok(())
+    state.device_set_segments(&device, &changes).await
 }
 
 async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<()> {
@@ -407,17 +1149,92 @@ async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<(
         .context("register_with_hass")
 }
 
+async fn mqtt_set_power_save(
+    Payload(command): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_power_save: {command}");
+    let enabled = truthy(&command)?;
+    set_power_save(enabled);
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish(power_save_state_topic(), if enabled { "ON" } else { "OFF" })
+            .await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PlaylistParameter {
+    id: String,
+    name: String,
+}
+
+async fn mqtt_playlist_command(
+    Payload(command): Payload<String>,
+    Params(PlaylistParameter { id, name }): Params<PlaylistParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(&id).await?;
+    log::info!("playlist {name} for {device}: {command}");
+    playlist_command(&state, &device, &name, &command).await
+}
+
+/// Replace the playlists with those from a JSON document
+async fn mqtt_set_playlists(
+    Payload(document): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let doc = PlaylistDocument::parse_json(&document)?;
+    log::info!("Setting {} playlists", doc.playlists.len());
+    state.set_playlists(doc.playlists).await;
+    state
+        .get_hass_client()
+        .await
+        .expect("have hass client")
+        .register_with_hass(&state)
+        .await
+        .context("register_with_hass")
+}
+
+/// Apply a light command to several devices as a unit,
+/// and publish the aggregate result
+async fn mqtt_transaction(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if !is_leader() {
+        return Ok(());
+    }
+    let transaction: Transaction = serde_json::from_str(&payload)?;
+    log::info!(
+        "Transaction for {:?}: {}",
+        transaction.devices,
+        transaction.command
+    );
+
+    let result = transaction.run(&state).await;
+    if !result.ok {
+        log::warn!("Transaction failed: {result:?}");
+    }
+    if let Some(client) = state.get_hass_client().await {
+        client
+            .publish_obj(TRANSACTION_RESULT_TOPIC, &result)
+            .await?;
+    }
+    Ok(())
+}
+
 async fn mqtt_oneclick(
     Payload(name): Payload<String>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("mqtt_oneclick: {name}");
+    if !is_leader() {
+        return Ok(());
+    }
 
-    let undoc = state
-        .get_undoc_client()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
-    let items = undoc.parse_one_clicks().await?;
+    let items = state.resolve_one_clicks().await?;
     let item = items
         .iter()
         .find(|item| item.name == name)
@@ -428,13 +1245,13 @@ async fn mqtt_oneclick(
         .await
         .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))?;
 
-    iot.activate_one_click(&item).await
+    iot.activate_one_click(item).await
 }
 
 #[derive(Deserialize)]
-struct IdAndInst {
-    id: String,
-    instance: String,
+pub struct IdAndInst {
+    pub id: String,
+    pub instance: String,
 }
 
 async fn mqtt_switch_command(
@@ -453,7 +1270,7 @@ async fn mqtt_switch_command(
 
     if instance == "powerSwitch" {
         state.device_power_on(&device, on).await?;
-    } else if let Some(client) = state.get_platform_client().await {
+    } else if let Some(client) = state.get_platform_client_for(&device).await {
         if let Some(http_dev) = &device.http_device_info {
             client.set_toggle_state(http_dev, &instance, on).await?;
         } else {
@@ -466,36 +1283,118 @@ async fn mqtt_switch_command(
     Ok(())
 }
 
+async fn mqtt_extended_control_command(
+    Payload(value): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {value}");
+    let device = state.resolve_device_for_control(&id).await?;
+    state
+        .device_set_extended_control(&device, &instance, &value)
+        .await
+}
+
 pub fn mired_to_kelvin(mired: u32) -> u32 {
-    if mired == 0 {
-        0
-    } else {
-        1000000 / mired
-    }
+    1000000u32.checked_div(mired).unwrap_or(0)
 }
 
 pub fn kelvin_to_mired(kelvin: u32) -> u32 {
-    if kelvin == 0 {
-        0
+    1000000u32.checked_div(kelvin).unwrap_or(0)
+}
+
+/// HASS is advising us that its status has changed
+#[derive(Deserialize)]
+struct InstanceParameter {
+    instance: String,
+}
+
+/// The generation of the most recently scheduled re-registration
+static REREGISTER_GENERATION: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+/// Re-register with hass once things have settled, so that we
+/// publish entities that depend on what has changed, such as
+/// the devices that we now own
+pub fn schedule_reregistration(state: StateHandle, reason: &'static str) {
+    use std::sync::atomic::Ordering;
+    let generation = REREGISTER_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    tokio::spawn(async move {
+        tokio::time::sleep(HASS_REGISTER_DELAY).await;
+        if REREGISTER_GENERATION.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        log::info!("{reason}; re-registering entities");
+        if let Some(client) = state.get_hass_client().await {
+            if let Err(err) = client.register_with_hass(&state).await {
+                log::error!("register_with_hass: {err:#}");
+            }
+        }
+    });
+}
+
+async fn mqtt_bridge_availability(
+    Payload(status): Payload<String>,
+    Params(InstanceParameter { instance }): Params<InstanceParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if update_bridge_availability(&instance, status == "online") {
+        log::info!("Bridge {instance} is {status}");
+        schedule_reregistration(state, "The set of bridges changed");
+    }
+    Ok(())
+}
+
+async fn mqtt_bridge_claims(
+    Payload(claims): Payload<String>,
+    Params(InstanceParameter { instance }): Params<InstanceParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let claims: Vec<String> = if claims.is_empty() {
+        vec![]
     } else {
-        1000000 / kelvin
+        serde_json::from_str(&claims)?
+    };
+    if update_bridge_claims(&instance, claims) {
+        log::info!("Bridge {instance} changed its claims");
+        schedule_reregistration(state, "The set of bridges changed");
     }
+    Ok(())
 }
 
-/// HASS is advising us that its status has changed
+/// The generation of the most recently scheduled birth re-registration
+static BIRTH_GENERATION: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
 async fn mqtt_homeassitant_status(
     Payload(status): Payload<String>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let client = state
-        .get_hass_client()
-        .await
-        .expect("hass client to be present");
+    use std::sync::atomic::Ordering;
 
-    log::info!("Home Assistant status changed: {status}, waiting {HASS_REGISTER_DELAY:?} before re-registering entities");
-    tokio::time::sleep(HASS_REGISTER_DELAY).await;
+    let birth = state.get_hass_birth_message().await;
+    if status.trim() != birth.payload {
+        log::info!("Home Assistant status changed: {status}");
+        return Ok(());
+    }
 
-    client.register_with_hass(&state).await?;
+    log::info!(
+        "Home Assistant status changed: {status}, waiting \
+        {HASS_REGISTER_DELAY:?} before re-registering entities"
+    );
+    // Home Assistant may publish its birth message several times
+    // in quick succession; only re-register once it has settled
+    let generation = BIRTH_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    tokio::spawn(async move {
+        tokio::time::sleep(HASS_REGISTER_DELAY).await;
+        if BIRTH_GENERATION.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if let Some(client) = state.get_hass_client().await {
+            if let Err(err) = client.register_with_hass(&state).await {
+                log::error!("register_with_hass: {err:#}");
+            }
+        }
+    });
 
     Ok(())
 }
@@ -513,12 +1412,10 @@ async fn run_mqtt_loop(
         client: &Client,
         state: &StateHandle,
     ) -> anyhow::Result<Arc<MqttRouter<StateHandle>>> {
-        let disco_prefix = state.get_hass_disco_prefix().await;
+        let birth = state.get_hass_birth_message().await;
         let mut router: MqttRouter<StateHandle> = MqttRouter::new(client.clone());
 
-        router
-            .route(format!("{disco_prefix}/status"), mqtt_homeassitant_status)
-            .await?;
+        router.route(birth.topic, mqtt_homeassitant_status).await?;
 
         router
             .route("gv2mqtt/light/:id/command", mqtt_light_command)
@@ -529,12 +1426,62 @@ async fn run_mqtt_loop(
                 mqtt_light_segment_command,
             )
             .await?;
+        router
+            .route("gv2mqtt/light/:id/segments/set", mqtt_light_segments_set)
+            .await?;
+        router
+            .route("gv2mqtt/feast/:id/command", mqtt_feast_command)
+            .await?;
+        router
+            .route("gv2mqtt/group/:id/command", mqtt_group_command)
+            .await?;
+        router
+            .route("gv2mqtt/v1/devices/:id/set", mqtt_contract_command)
+            .await?;
         router
             .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
             .await?;
+        router
+            .route(
+                "gv2mqtt/number/:id/capability/:instance",
+                mqtt_capability_number_command,
+            )
+            .await?;
+        router
+            .route(
+                "gv2mqtt/:id/extended/:instance/command",
+                mqtt_extended_control_command,
+            )
+            .await?;
 
+        if is_sharded() {
+            router
+                .route(
+                    "gv2mqtt/bridges/:instance/availability",
+                    mqtt_bridge_availability,
+                )
+                .await?;
+            router
+                .route("gv2mqtt/bridges/:instance/claims", mqtt_bridge_claims)
+                .await?;
+        }
         router.route(oneclick_topic(), mqtt_oneclick).await?;
+        router
+            .route("gv2mqtt/transaction", mqtt_transaction)
+            .await?;
+        router
+            .route("gv2mqtt/:id/playlist/:name/command", mqtt_playlist_command)
+            .await?;
+        router
+            .route("gv2mqtt/playlists/set", mqtt_set_playlists)
+            .await?;
         router.route(purge_cache_topic(), mqtt_purge_caches).await?;
+        for topic in watchdog_topics() {
+            router.route(topic, mqtt_watchdog_topic).await?;
+        }
+        router
+            .route(power_save_command_topic(), mqtt_set_power_save)
+            .await?;
         router
             .route(
                 "gv2mqtt/:id/request-platform-data",
@@ -547,6 +1494,12 @@ async fn run_mqtt_loop(
                 mqtt_number_command,
             )
             .await?;
+        router
+            .route(
+                "gv2mqtt/number/:id/command/active-work-mode",
+                mqtt_active_work_mode_number_command,
+            )
+            .await?;
         router
             .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
             .await?;
@@ -568,13 +1521,28 @@ async fn run_mqtt_loop(
         router
             .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
             .await?;
+        router
+            .route("gv2mqtt/:id/load-all-scenes", mqtt_load_all_scenes)
+            .await?;
+        router
+            .route("gv2mqtt/:id/set-display-unit", mqtt_set_display_unit)
+            .await?;
+        router
+            .route("gv2mqtt/:id/set-color-preset", mqtt_set_color_preset)
+            .await?;
+        router
+            .route(
+                "gv2mqtt/:id/set-work-mode-preset",
+                mqtt_set_work_mode_preset,
+            )
+            .await?;
 
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
         state
             .get_hass_client()
             .await
             .expect("have hass client")
-            .register_with_hass(&state)
+            .register_with_hass(state)
             .await
             .context("register_with_hass")?;
 
@@ -589,11 +1557,7 @@ async fn run_mqtt_loop(
             Event::Message(msg) => {
                 let router = router.clone();
                 let state = state.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = router.dispatch(msg.clone(), state.clone()).await {
-                        log::error!("While dispatching {msg:?}: {err:#}");
-                    }
-                });
+                tokio::spawn(async move { dispatch_message(router, msg, state).await });
             }
             Event::Disconnected(reason) => {
                 log::warn!("MQTT disconnected with reason={reason}");
@@ -613,6 +1577,97 @@ async fn run_mqtt_loop(
     Ok(())
 }
 
+/// Dispatch a message to its handler. A command that fails for a
+/// device that can only be reached via the Govee cloud is then
+/// dropped, retried or held for the LAN API according to the
+/// cloud failure policy of the device.
+async fn dispatch_message(router: Arc<MqttRouter<StateHandle>>, msg: Message, state: StateHandle) {
+    let started = Instant::now();
+    loop {
+        let (result, device_id) =
+            track_command_device(router.dispatch(msg.clone(), state.clone())).await;
+        let err = match result {
+            Ok(()) => return,
+            Err(RouterError::Any(err)) if NotOwnedByThisBridge::is_not_owned(&err) => {
+                log::trace!("Ignoring {msg:?}: {err:#}");
+                return;
+            }
+            Err(RouterError::Any(err)) => err,
+            Err(err) => {
+                log::error!("While dispatching {msg:?}: {err:#}");
+                return;
+            }
+        };
+        log::error!("While dispatching {msg:?}: {err:#}");
+
+        let Some(device_id) = device_id else {
+            return;
+        };
+        let Some(device) = state.device_by_id(&device_id).await else {
+            return;
+        };
+        if device.preferred_lan_device().is_some() {
+            // It wasn't the cloud that failed
+            return;
+        }
+
+        let policy = if is_cloud_failure(&err) {
+            cloud_failure_policy(&device)
+        } else {
+            // The command itself was at fault, so trying it
+            // again would only fail in the same way
+            CloudFailurePolicy::Drop
+        };
+        let sequence = command_sequence(&device_id);
+        let remaining = MAX_COMMAND_AGE.saturating_sub(started.elapsed());
+        let retry = match policy {
+            CloudFailurePolicy::Drop => false,
+            CloudFailurePolicy::Queue if remaining.is_zero() => false,
+            CloudFailurePolicy::Queue => {
+                log::warn!("Will retry the command for {device} in {QUEUE_RETRY_INTERVAL:?}");
+                tokio::time::sleep(QUEUE_RETRY_INTERVAL.min(remaining)).await;
+                true
+            }
+            CloudFailurePolicy::LanOnly => {
+                log::warn!("Holding the command for {device} until it is reachable via LAN");
+                wait_for_lan_device(&state, &device_id, remaining).await
+            }
+        };
+
+        if command_sequence(&device_id) != sequence {
+            log::info!("Dropping the failed command for {device}; a newer one was sent");
+            return;
+        }
+        if !retry {
+            let event_type = match policy {
+                CloudFailurePolicy::Drop => "dropped",
+                _ => "expired",
+            };
+            if let Err(err) =
+                publish_command_failed(&state, &device, &msg.topic, event_type, &err).await
+            {
+                log::error!("Failed to publish the command failed event: {err:#}");
+            }
+            return;
+        }
+    }
+}
+
+/// Wait up to `limit` for the device to be reachable via the LAN API.
+/// Returns false if it didn't become reachable in time.
+async fn wait_for_lan_device(state: &StateHandle, device_id: &str, limit: Duration) -> bool {
+    let deadline = Instant::now() + limit;
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        if let Some(device) = state.device_by_id(device_id).await {
+            if device.preferred_lan_device().is_some() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
 pub async fn spawn_hass_integration(
     state: StateHandle,
     args: &HassArguments,
@@ -623,13 +1678,58 @@ pub async fn spawn_hass_integration(
     )?;
 
     state.set_temperature_scale(args.temperature_scale()?).await;
+    state
+        .set_scene_icon_base_url(args.scene_icon_base_url()?)
+        .await;
+    state
+        .set_restore_after_power_loss(args.restore_after_power_loss()?)
+        .await;
+    state.set_segment_layouts(args.segment_layouts()?).await;
+    state.set_color_presets(args.color_presets()?).await;
+    state.set_scene_filters(args.scene_filters()?).await;
+    state
+        .set_scene_select_per_category(args.scene_select_per_category()?)
+        .await;
+    state.set_derived_sensors(args.derived_sensors()?).await;
+    state
+        .set_rate_of_change_window(args.rate_of_change_window()?)
+        .await;
+    state.set_playlists(args.playlists()?).await;
+    set_entity_id_overrides(args.entity_id_overrides()?);
+    set_device_customizations(args.device_customizations()?);
 
-    let mqtt_host = args.mqtt_host()?;
     let mqtt_username = args.mqtt_username()?;
     let mqtt_password = args.mqtt_password()?;
-    let mqtt_port = args.mqtt_port()?;
 
-    client.set_last_will(availability_topic(), "offline", QoS::AtMostOnce, false)?;
+    #[cfg(feature = "embedded-broker")]
+    let (mqtt_host, mqtt_port) = match args.mqtt_broker_listen()? {
+        Some(listen) => {
+            let credentials = match (&mqtt_username, &mqtt_password) {
+                (Some(username), Some(password)) => {
+                    Some(crate::service::broker::BrokerCredentials {
+                        username: username.to_string(),
+                        password: password.to_string(),
+                    })
+                }
+                _ => None,
+            };
+            let addr = crate::service::broker::start_broker(&listen, credentials).await?;
+            match args.opt_mqtt_host()? {
+                Some(host) => (host, args.mqtt_port()?),
+                None => ("127.0.0.1".to_string(), addr.port()),
+            }
+        }
+        None => (args.mqtt_host()?, args.mqtt_port()?),
+    };
+    #[cfg(not(feature = "embedded-broker"))]
+    let (mqtt_host, mqtt_port) = (args.mqtt_host()?, args.mqtt_port()?);
+
+    client.set_last_will(
+        availability_topic(),
+        "offline",
+        QoS::AtMostOnce,
+        is_sharded(),
+    )?;
 
     if mqtt_username.is_some() != mqtt_password.is_some() {
         log::error!(
@@ -637,25 +1737,43 @@ pub async fn spawn_hass_integration(
         );
     }
     client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
-    client
-        .connect(
-            &mqtt_host,
-            mqtt_port.into(),
-            Duration::from_secs(120),
-            args.mqtt_bind_address.as_deref(),
-        )
-        .await
-        .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
+    with_timeout(
+        "connecting to the mqtt broker",
+        Duration::from_secs(60),
+        async {
+            client
+                .connect(
+                    &mqtt_host,
+                    mqtt_port.into(),
+                    Duration::from_secs(120),
+                    args.mqtt_bind_address.as_deref(),
+                )
+                .await
+                .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))
+        },
+    )
+    .await?;
     let subscriber = client.subscriber().expect("to own the subscriber");
 
+    let disco_prefix = args.hass_discovery_prefix.clone();
+    let mirror = args.mirror_config()?.map(|config| {
+        log::info!(
+            "Mirroring state to mqtt broker {}:{}",
+            config.host,
+            config.port
+        );
+        spawn_mqtt_mirror(config, &disco_prefix)
+    });
+
     state
         .set_hass_client(HassClient {
-            client: client.clone(),
+            sink: MessageSink::Broker(client.clone()),
+            mirror,
         })
         .await;
 
-    let disco_prefix = args.hass_discovery_prefix.clone();
     state.set_hass_disco_prefix(disco_prefix).await;
+    state.set_hass_birth_message(args.birth_message()?).await;
 
     tokio::spawn(async move {
         let res = run_mqtt_loop(state, subscriber, client).await;
@@ -1,13 +1,21 @@
+use crate::service::command_audit::recent_commands;
 use crate::service::coordinator::Coordinator;
 use crate::service::device::{Device, DeviceState};
+use crate::service::event_log::recent_events;
+use crate::service::inventory::{inventory, inventory_csv};
+use crate::service::scene_search::SceneQuery;
 use crate::service::state::StateHandle;
+use crate::service::systemd;
+use crate::service::transaction::Transaction;
+use crate::service::transport_health::transport_report;
+use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use tower_http::services::ServeDir;
 
@@ -42,13 +50,13 @@ async fn resolve_device_for_control(
     id: &str,
 ) -> Result<Coordinator, Response> {
     state
-        .resolve_device_for_control(&id)
+        .resolve_device_for_control(id)
         .await
         .map_err(not_found)
 }
 
 async fn resolve_device_read_only(state: &StateHandle, id: &str) -> Result<Device, Response> {
-    state.resolve_device_read_only(&id).await.map_err(not_found)
+    state.resolve_device_read_only(id).await.map_err(not_found)
 }
 
 /// Returns a json array of device information
@@ -63,6 +71,7 @@ async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Resp
         pub name: String,
         pub room: Option<String>,
         pub ip: Option<IpAddr>,
+        pub transports: Vec<&'static str>,
         pub state: Option<DeviceState>,
     }
 
@@ -72,6 +81,7 @@ async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Resp
             name: d.name(),
             room: d.room_name().map(|r| r.to_string()),
             ip: d.ip_addr(),
+            transports: d.transports(),
             state: d.device_state(),
             sku: d.sku,
             id: d.id,
@@ -81,6 +91,117 @@ async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Resp
     Ok(Json(devices).into_response())
 }
 
+/// Returns the recent events, oldest first
+async fn list_events() -> Result<Response, Response> {
+    Ok(Json(recent_events()).into_response())
+}
+
+#[derive(Deserialize)]
+struct CommandsQuery {
+    device: Option<String>,
+}
+
+/// Returns the commands most recently sent to devices, oldest first,
+/// optionally only those sent to the device given by `?device=`
+async fn list_commands(
+    State(state): State<StateHandle>,
+    Query(query): Query<CommandsQuery>,
+) -> Result<Response, Response> {
+    let commands = match &query.device {
+        Some(label) => {
+            let device = resolve_device_read_only(&state, label).await?;
+            recent_commands(Some(&device.id))
+        }
+        None => recent_commands(None),
+    };
+    Ok(Json(commands).into_response())
+}
+
+/// Returns everything that is known about the state of a device,
+/// including the raw state reported by each of the APIs
+async fn device_state(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+) -> Result<Response, Response> {
+    let device = resolve_device_read_only(&state, &id).await?;
+
+    Ok(Json(serde_json::json!({
+        "sku": device.sku,
+        "id": device.id,
+        "name": device.name(),
+        "state": device.device_state(),
+        "restored_state": device.restored_state,
+        "lan_status": device.lan_device_status,
+        "last_lan_status_update": device.last_lan_device_status_update,
+        "http_state": device.http_device_state,
+        "last_http_state_update": device.last_http_device_state_update,
+        "iot_status": device.iot_device_status,
+        "last_iot_status_update": device.last_iot_device_status_update,
+        "last_polled": device.last_polled,
+        "transport": transport_report(&device.id),
+    }))
+    .into_response())
+}
+
+/// Requests the current state of a device, bypassing the
+/// poll interval, and returns it once it has been received
+async fn device_refresh(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+) -> Result<Response, Response> {
+    let device = resolve_device_read_only(&state, &id).await?;
+
+    state.refresh_device_state(&device).await.map_err(generic)?;
+
+    device_state(State(state), Path(id)).await
+}
+
+/// Fetches the device lists and scenes from the Govee APIs
+/// again, and re-registers the entities with Home Assistant
+async fn run_discovery(State(state): State<StateHandle>) -> Result<Response, Response> {
+    crate::commands::serve::run_maintenance(&state)
+        .await
+        .map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
+/// Returns where and when each device was found by LAN discovery
+async fn lan_discoveries(State(state): State<StateHandle>) -> Response {
+    let discoveries = match state.get_lan_client().await {
+        Some(client) => client.discoveries(),
+        None => vec![],
+    };
+    Json(discoveries).into_response()
+}
+
+/// Sends a discovery packet directly to an address, which
+/// registers the device there if it responds
+async fn lan_probe(
+    State(state): State<StateHandle>,
+    Path(ip): Path<String>,
+) -> Result<Response, Response> {
+    let ip: IpAddr = ip.parse().map_err(bad_request)?;
+    let client = state
+        .get_lan_client()
+        .await
+        .ok_or_else(|| not_found("the LAN API is not enabled"))?;
+    let device = client
+        .scan_ip(ip)
+        .await
+        .map_err(|err| response_with_code(StatusCode::GATEWAY_TIMEOUT, err))?;
+
+    Ok(Json(serde_json::json!({
+        "device": device,
+        "discovery": client.discovery_for_device(&device.device),
+    }))
+    .into_response())
+}
+
+async fn cache_report() -> Response {
+    Json(crate::cache::cache_report()).into_response()
+}
+
 /// Turns on a given device
 async fn device_power_on(
     State(state): State<StateHandle>,
@@ -187,13 +308,23 @@ async fn device_list_scenes(
     Ok(Json(scenes).into_response())
 }
 
-async fn list_one_clicks(State(state): State<StateHandle>) -> Result<Response, Response> {
-    let undoc = state
-        .get_undoc_client()
+/// Returns a JSON array of the scenes matching the `keyword` and/or
+/// `category` query parameters, and the devices that support them
+async fn search_scenes(
+    State(state): State<StateHandle>,
+    Query(query): Query<SceneQuery>,
+) -> Result<Response, Response> {
+    let devices = state.devices().await;
+
+    let matches = crate::service::scene_search::search_scenes(&devices, &query)
         .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))
         .map_err(generic)?;
-    let items = undoc.parse_one_clicks().await.map_err(generic)?;
+
+    Ok(Json(matches).into_response())
+}
+
+async fn list_one_clicks(State(state): State<StateHandle>) -> Result<Response, Response> {
+    let items = state.resolve_one_clicks().await.map_err(generic)?;
 
     Ok(Json(items).into_response())
 }
@@ -202,12 +333,7 @@ async fn activate_one_click(
     State(state): State<StateHandle>,
     Path(name): Path<String>,
 ) -> Result<Response, Response> {
-    let undoc = state
-        .get_undoc_client()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))
-        .map_err(generic)?;
-    let items = undoc.parse_one_clicks().await.map_err(generic)?;
+    let items = state.resolve_one_clicks().await.map_err(generic)?;
     let item = items
         .iter()
         .find(|item| item.name == name)
@@ -220,18 +346,123 @@ async fn activate_one_click(
         .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))
         .map_err(generic)?;
 
-    iot.activate_one_click(&item).await.map_err(generic)?;
+    iot.activate_one_click(item).await.map_err(generic)?;
 
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+async fn scene_icon(Path((sku, scene_id)): Path<(String, u32)>) -> Result<Response, Response> {
+    let icon = GoveeUndocumentedApi::get_scene_icon(&sku, scene_id)
+        .await
+        .map_err(not_found)?;
+    let data = data_encoding::BASE64
+        .decode(icon.data.as_bytes())
+        .map_err(generic)?;
+
+    Ok((
+        [
+            (axum::http::header::CONTENT_TYPE, icon.content_type),
+            (
+                axum::http::header::CACHE_CONTROL,
+                "public, max-age=86400".to_string(),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+async fn schema_drift() -> Response {
+    Json(crate::schema_drift::report()).into_response()
+}
+
+async fn discovery_stats() -> Response {
+    Json(crate::hass_mqtt::discovery::stats()).into_response()
+}
+
+async fn device_graph(State(state): State<StateHandle>) -> Response {
+    Json(state.device_graph().await).into_response()
+}
+
+async fn device_graph_dot(State(state): State<StateHandle>) -> Response {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/vnd.graphviz")],
+        state.device_graph().await.to_dot(),
+    )
+        .into_response()
+}
+
+async fn device_inventory(State(state): State<StateHandle>) -> Response {
+    Json(inventory(&state.devices().await)).into_response()
+}
+
+async fn device_inventory_csv(State(state): State<StateHandle>) -> Response {
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "text/csv"),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                "attachment; filename=\"govee-inventory.csv\"",
+            ),
+        ],
+        inventory_csv(&inventory(&state.devices().await)),
+    )
+        .into_response()
+}
+
+async fn run_transaction(
+    State(state): State<StateHandle>,
+    Json(transaction): Json<Transaction>,
+) -> Response {
+    let result = transaction.run(&state).await;
+    let code = if result.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let mut response = Json(result).into_response();
+    *response.status_mut() = code;
+    response
+}
+
 async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
 
+/// Returns the path of a URL, without a trailing slash,
+/// eg: `/api/hassio_ingress/abc` for `http://hass:8123/api/hassio_ingress/abc/`
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    match without_scheme.find('/') {
+        Some(idx) => without_scheme[idx..].trim_end_matches('/'),
+        None => "",
+    }
+}
+
 pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
-    let app = Router::new()
+    // The icon URLs are published relative to the configured base,
+    // which may include a path, such as when the service is reached
+    // via a reverse proxy that doesn't strip that path
+    let icon_route = format!(
+        "{}/api/scene-icon/:sku/:scene_id",
+        state
+            .get_scene_icon_base_url()
+            .await
+            .as_deref()
+            .map(url_path)
+            .unwrap_or_default()
+    );
+
+    let mut app = Router::new()
         .route("/api/devices", get(list_devices))
+        .route("/api/device/:id/state", get(device_state))
+        .route("/api/device/:id/refresh", post(device_refresh))
+        .route("/api/discovery", post(run_discovery))
+        .route("/api/lan/discovery", get(lan_discoveries))
+        .route("/api/lan/probe/:ip", post(lan_probe))
+        .route("/api/cache", get(cache_report))
+        .route("/api/events", get(list_events))
+        .route("/api/commands", get(list_commands))
         .route("/api/device/:id/power/on", get(device_power_on))
         .route("/api/device/:id/power/off", get(device_power_off))
         .route(
@@ -245,20 +476,55 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
         .route("/api/device/:id/color/:color", get(device_set_color))
         .route("/api/device/:id/scene/:scene", get(device_set_scene))
         .route("/api/device/:id/scenes", get(device_list_scenes))
+        .route("/api/scenes/search", get(search_scenes))
         .route("/api/oneclicks", get(list_one_clicks))
+        .route("/api/schema-drift", get(schema_drift))
+        .route("/api/discovery-stats", get(discovery_stats))
+        .route("/api/device-graph", get(device_graph))
+        .route("/api/device-graph/dot", get(device_graph_dot))
+        .route("/api/inventory", get(device_inventory))
+        .route("/api/inventory/csv", get(device_inventory_csv))
+        .route("/api/transaction", post(run_transaction))
         .route("/api/oneclick/activate/:scene", get(activate_one_click))
+        .route("/api/scene-icon/:sku/:scene_id", get(scene_icon))
         .route("/", get(redirect_to_index))
-        .nest_service("/assets", ServeDir::new("assets"))
-        .with_state(state);
-
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
-        .await
-        .with_context(|| format!("run_http_server: binding to port {port}"))?;
+        .nest_service("/assets", ServeDir::new("assets"));
+    if icon_route != "/api/scene-icon/:sku/:scene_id" {
+        app = app.route(&icon_route, get(scene_icon));
+    }
+    let app = app.with_state(state);
+
+    let listener = match systemd::take_activated_listener()? {
+        Some(listener) => {
+            log::info!("Using the listening socket passed in by systemd");
+            tokio::net::TcpListener::from_std(listener)?
+        }
+        None => tokio::net::TcpListener::bind(("0.0.0.0", port))
+            .await
+            .with_context(|| format!("run_http_server: binding to port {port}"))?,
+    };
     let addr = listener.local_addr()?;
     log::info!("http server addr is {addr:?}");
+    systemd::notify_ready(&format!("Serving HTTP on {addr}"));
     if let Err(err) = axum::serve(listener, app).await {
         log::error!("http server stopped: {err:#}");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base_url_path() {
+        assert_eq!(url_path("http://192.168.1.10:8056"), "");
+        assert_eq!(url_path("http://192.168.1.10:8056/"), "");
+        assert_eq!(
+            url_path("https://hass.example.com/api/hassio_ingress/abc/"),
+            "/api/hassio_ingress/abc"
+        );
+        assert_eq!(url_path("/govee"), "/govee");
+    }
+}
@@ -0,0 +1,131 @@
+//! An asset inventory of the known devices and their hardware and
+//! firmware versions, drawn from the merged view of all of the APIs.
+use crate::service::device::Device;
+use crate::service::device_group::device_groups;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InventoryEntry {
+    pub name: String,
+    pub sku: String,
+    pub id: String,
+    pub mac: Option<String>,
+    pub ip: Option<String>,
+    pub room: Option<String>,
+    pub groups: Vec<String>,
+    pub version_soft: Option<String>,
+    pub version_hard: Option<String>,
+    pub wifi_soft_version: Option<String>,
+    pub wifi_hard_version: Option<String>,
+}
+
+impl InventoryEntry {
+    fn new(device: &Device, groups: Vec<String>) -> Self {
+        let versions = device.firmware_versions();
+        Self {
+            name: device.name(),
+            sku: device.sku.clone(),
+            id: device.id.clone(),
+            mac: device
+                .undoc_device_info
+                .as_ref()
+                .and_then(|info| info.entry.device_ext.device_settings.wifi_mac.clone()),
+            ip: device.ip_addr().map(|ip| ip.to_string()),
+            room: device.room_name().map(|r| r.to_string()),
+            groups,
+            version_soft: versions.version_soft,
+            version_hard: versions.version_hard,
+            wifi_soft_version: versions.wifi_soft_version,
+            wifi_hard_version: versions.wifi_hard_version,
+        }
+    }
+}
+
+/// Build the inventory, sorted by name
+pub fn inventory(devices: &[Device]) -> Vec<InventoryEntry> {
+    let groups = device_groups(devices);
+    let mut entries: Vec<InventoryEntry> = devices
+        .iter()
+        .map(|device| {
+            let groups = groups
+                .iter()
+                .filter(|group| group.contains(&device.id))
+                .map(|group| group.name.clone())
+                .collect();
+            InventoryEntry::new(device, groups)
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.name, &a.sku, &a.id).cmp(&(&b.name, &b.sku, &b.id)));
+    entries
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Render the inventory as CSV, with a header row.
+/// Multiple groups are separated by semicolons.
+pub fn inventory_csv(entries: &[InventoryEntry]) -> String {
+    let mut csv = "name,sku,id,mac,ip,room,groups,version_soft,version_hard,\
+        wifi_soft_version,wifi_hard_version\r\n"
+        .to_string();
+    for entry in entries {
+        let opt = |s: &Option<String>| s.clone().unwrap_or_default();
+        let fields = [
+            entry.name.clone(),
+            entry.sku.clone(),
+            entry.id.clone(),
+            opt(&entry.mac),
+            opt(&entry.ip),
+            opt(&entry.room),
+            entry.groups.join(";"),
+            opt(&entry.version_soft),
+            opt(&entry.version_hard),
+            opt(&entry.wifi_soft_version),
+            opt(&entry.wifi_hard_version),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::LanDevice;
+
+    #[test]
+    fn csv() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_lan_device(LanDevice {
+            ip: "10.0.0.2".parse().unwrap(),
+            device: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+            sku: "H6000".to_string(),
+            ble_version_hard: "3.01.01".to_string(),
+            ble_version_soft: "1.00.10".to_string(),
+            wifi_version_hard: "1.00.10".to_string(),
+            wifi_version_soft: "1.02.03".to_string(),
+        });
+        let entries = inventory(&[device]);
+        assert_eq!(entries[0].ip.as_deref(), Some("10.0.0.2"));
+        assert_eq!(entries[0].wifi_soft_version.as_deref(), Some("1.02.03"));
+
+        let csv = inventory_csv(&entries);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[1],
+            "H6000_422A,H6000,AA:BB:CC:DD:EE:FF:42:2A,,10.0.0.2,,,1.00.10,3.01.01,1.02.03,1.00.10"
+        );
+
+        assert_eq!(csv_field("Desk, left"), "\"Desk, left\"");
+        assert_eq!(csv_field("The \"big\" one"), "\"The \"\"big\"\" one\"");
+    }
+}
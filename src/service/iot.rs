@@ -1,51 +1,228 @@
 use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumidifierMode};
-use crate::lan_api::{DeviceColor, DeviceStatus};
+use crate::hass_mqtt::event::publish_sensor_alarm;
+use crate::lan_api::{opt_boolean_int, DeviceColor, DeviceStatus};
 use crate::platform_api::from_json;
+use crate::service::brightness::iot_brightness_scale;
+use crate::service::cloud_health;
+use crate::service::command_audit::PendingCommand;
+use crate::service::command_queue::pace_command;
+use crate::service::device::{
+    AlarmMeasurement, LeakReading, PartialReading, PowerReading, PresenceReading,
+};
+use crate::service::hass::schedule_reregistration;
+use crate::service::iot_health;
+use crate::service::stall_detector::with_timeout;
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::service::state_merge::Transport;
+use crate::service::supervisor::RestartPolicy;
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, GoveeUndocumentedApi, LoginAccountResponse, ParsedOneClick,
+};
 use crate::Args;
 use anyhow::Context;
-use async_channel::Receiver;
+use async_channel::{Receiver, Sender};
+use chrono::Utc;
 use mosquitto_rs::{Event, QoS};
+use parking_lot::Mutex;
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
+use tokio::sync::{oneshot, Semaphore};
+
+/// Returns the topic to which a message for the device is published.
+/// A device without a topic of its own, that is on WiFi, is addressed
+/// via the account topic instead, with its sku and device id added to
+/// `msg` so that it can be routed to the device. That routing hasn't
+/// been confirmed for every model, so it is only used for commands,
+/// and such devices are not polled via IoT.
+fn route_message<'a>(
+    device: &'a DeviceEntry,
+    account_topic: &'a str,
+    msg: &mut serde_json::Value,
+) -> anyhow::Result<&'a str> {
+    match device.device_topic() {
+        Ok(topic) => Ok(topic),
+        Err(_) if device.device_ext.device_settings.wifi_name.is_some() => {
+            log::trace!(
+                "{} has no topic, addressing it via the account topic",
+                device.device
+            );
+            msg["sku"] = device.sku.clone().into();
+            msg["device"] = device.device.clone().into();
+            Ok(account_topic)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Outgoing messages are queued according to their priority;
+/// interactive commands are sent ahead of background status requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Priority {
+    Interactive,
+    Background,
+}
+
+struct OutgoingMessage {
+    topic: String,
+    payload: String,
+    /// Interactive commands are added to the audit log once published
+    audit: Option<PendingCommand>,
+    /// Receives the outcome of publishing the message, for
+    /// senders that wait to learn whether it was published
+    done: Option<oneshot::Sender<anyhow::Result<()>>>,
+}
 
 #[derive(Clone)]
 pub struct IotClient {
+    interactive_tx: Sender<OutgoingMessage>,
+    background_tx: Sender<OutgoingMessage>,
+    /// The account-level topic. Used to address devices that
+    /// don't report a topic of their own in their settings.
+    account_topic: String,
+}
+
+/// The current connection to AWS IoT. It is replaced when
+/// the session is rebuilt.
+struct IotSession {
     client: mosquitto_rs::Client,
+    events: Receiver<Event>,
+}
+
+type SharedSession = Arc<Mutex<IotSession>>;
+
+/// What we need in order to (re)establish a session
+#[derive(Clone)]
+struct IotLink {
+    /// Names the link in the health report
+    name: String,
+    undoc: GoveeUndocumentedApi,
+    acct: LoginAccountResponse,
+    root_ca: PathBuf,
+    key_path: PathBuf,
+    cert_path: PathBuf,
 }
 
 impl IotClient {
+    fn new(
+        session: SharedSession,
+        account_topic: String,
+        publish_rate: u32,
+        concurrency: usize,
+    ) -> Self {
+        let (interactive_tx, interactive_rx) = async_channel::unbounded();
+        let (background_tx, background_rx) = async_channel::unbounded();
+        let interval = Duration::from_secs(1) / publish_rate.max(1);
+        // Limits the number of publishes that are in flight at once
+        let in_flight = Arc::new(Semaphore::new(concurrency.max(1)));
+
+        tokio::spawn(run_iot_publisher(
+            session,
+            interactive_rx,
+            background_rx,
+            interval,
+            in_flight,
+        ));
+
+        Self {
+            interactive_tx,
+            background_tx,
+            account_topic,
+        }
+    }
+
     pub fn is_device_compatible(&self, device: &DeviceEntry) -> bool {
         device.device_ext.device_settings.topic.is_some()
     }
 
-    pub async fn request_status_update(&self, device: &DeviceEntry) -> anyhow::Result<()> {
-        let device_topic = device.device_topic()?;
-
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "status",
-                        "cmdVersion": 2,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 0,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await?;
+    /// Publish `msg` to the device.
+    /// If the device has no topic of its own, the message is sent to
+    /// the account topic instead, with the sku and device id included
+    /// in the message so that it can be routed to the device.
+    /// Interactive messages are only Ok once they have been published;
+    /// background messages are Ok once they have been queued.
+    async fn publish_msg(
+        &self,
+        device: &DeviceEntry,
+        priority: Priority,
+        mut msg: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let audit = (priority == Priority::Interactive)
+            .then(|| PendingCommand::start(Transport::Iot, &device.device, &device.sku, &msg));
+        let topic = match route_message(device, &self.account_topic, &mut msg) {
+            Ok(topic) => topic,
+            Err(err) => {
+                let result = Err(err);
+                if let Some(audit) = audit {
+                    audit.finish(&result);
+                }
+                return result;
+            }
+        };
 
-        Ok(())
+        if priority == Priority::Interactive {
+            pace_command(Transport::Iot, &device.device).await;
+        }
+        let payload = serde_json::to_string(&serde_json::json!({ "msg": msg }))?;
+        self.enqueue(priority, topic, payload, audit).await
+    }
+
+    async fn enqueue(
+        &self,
+        priority: Priority,
+        topic: &str,
+        payload: String,
+        audit: Option<PendingCommand>,
+    ) -> anyhow::Result<()> {
+        let (tx, done) = match priority {
+            Priority::Interactive => {
+                let (done_tx, done_rx) = oneshot::channel();
+                (&self.interactive_tx, Some((done_tx, done_rx)))
+            }
+            Priority::Background => (&self.background_tx, None),
+        };
+        let (done_tx, done_rx) = done.unzip();
+        if let Err(err) = tx
+            .send(OutgoingMessage {
+                topic: topic.to_string(),
+                payload,
+                audit,
+                done: done_tx,
+            })
+            .await
+        {
+            let result = Err(anyhow::anyhow!("IoT publish queue is closed"));
+            if let Some(audit) = err.into_inner().audit {
+                audit.finish(&result);
+            }
+            return result;
+        }
+
+        match done_rx {
+            Some(done) => done
+                .await
+                .map_err(|_| anyhow::anyhow!("IoT publisher terminated"))?,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn request_status_update(&self, device: &DeviceEntry) -> anyhow::Result<()> {
+        self.publish_msg(
+            device,
+            Priority::Background,
+            serde_json::json!({
+                "cmd": "status",
+                "cmdVersion": 2,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 0,
+            }),
+        )
+        .await
     }
 
     pub async fn set_power_state(&self, device: &DeviceEntry, on: bool) -> anyhow::Result<()> {
         log::trace!("set_power_state for {} to {on}", device.device);
-        let device_topic = device.device_topic()?;
 
         fn pwr(is_on: bool, on: u8, off: u8) -> u8 {
             if is_on {
@@ -60,51 +237,41 @@ impl IotClient {
             _ => pwr(on, 1, 0),
         };
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "turn",
-                        "data": {
-                            "val": power_state,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_power_state")?;
-        Ok(())
+        self.publish_msg(
+            device,
+            Priority::Interactive,
+            serde_json::json!({
+                "cmd": "turn",
+                "data": {
+                    "val": power_state,
+                },
+                "cmdVersion": 0,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 1,
+            }),
+        )
+        .await
+        .context("IotClient::set_power_state")
     }
 
     pub async fn set_brightness(&self, device: &DeviceEntry, percent: u8) -> anyhow::Result<()> {
         log::trace!("set_brightness for {} to {percent}", device.device);
-        let device_topic = device.device_topic()?;
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "brightness",
-                        "data": {
-                            "val": percent,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_brightness")?;
-        Ok(())
+        let value = iot_brightness_scale(&device.sku).to_device(percent);
+        self.publish_msg(
+            device,
+            Priority::Interactive,
+            serde_json::json!({
+                "cmd": "brightness",
+                "data": {
+                    "val": value,
+                },
+                "cmdVersion": 0,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 1,
+            }),
+        )
+        .await
+        .context("IotClient::set_brightness")
     }
 
     pub async fn set_color_temperature(
@@ -113,33 +280,26 @@ impl IotClient {
         kelvin: u32,
     ) -> anyhow::Result<()> {
         log::trace!("set_color_temperature for {} to {kelvin}", device.device);
-        let device_topic = device.device_topic()?;
-
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color": {
-                                "r": 0,
-                                "g": 0,
-                                "b": 0,
-                            },
-                            "colorTemInKelvin": kelvin,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_temperature")?;
-        Ok(())
+        self.publish_msg(
+            device,
+            Priority::Interactive,
+            serde_json::json!({
+                "cmd": "colorwc",
+                "data": {
+                    "color": {
+                        "r": 0,
+                        "g": 0,
+                        "b": 0,
+                    },
+                    "colorTemInKelvin": kelvin,
+                },
+                "cmdVersion": 0,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 1,
+            }),
+        )
+        .await
+        .context("IotClient::set_color_temperature")
     }
 
     pub async fn set_color_rgb(
@@ -150,33 +310,26 @@ impl IotClient {
         b: u8,
     ) -> anyhow::Result<()> {
         log::trace!("set_color_rgb for {} to {r},{g},{b}", device.device);
-        let device_topic = device.device_topic()?;
-
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color":{
-                                "r": r,
-                                "g": g,
-                                "b": b,
-                            },
-                            "colorTemInKelvin": 0,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_rgb")?;
-        Ok(())
+        self.publish_msg(
+            device,
+            Priority::Interactive,
+            serde_json::json!({
+                "cmd": "colorwc",
+                "data": {
+                    "color":{
+                        "r": r,
+                        "g": g,
+                        "b": b,
+                    },
+                    "colorTemInKelvin": 0,
+                },
+                "cmdVersion": 0,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 1,
+            }),
+        )
+        .await
+        .context("IotClient::set_color_rgb")
     }
 
     pub async fn send_real(
@@ -185,48 +338,96 @@ impl IotClient {
         commands: Vec<String>,
     ) -> anyhow::Result<()> {
         log::trace!("send_real for {} to {commands:?}", device.device);
-        let device_topic = device.device_topic()?;
-
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "ptReal",
-                        "data": {
-                            "command": commands,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::send_real")?;
-        Ok(())
+        self.publish_msg(
+            device,
+            Priority::Interactive,
+            serde_json::json!({
+                "cmd": "ptReal",
+                "data": {
+                    "command": commands,
+                },
+                "cmdVersion": 0,
+                "transaction": format!("v_{}000", ms_timestamp()),
+                "type": 1,
+            }),
+        )
+        .await
+        .context("IotClient::send_real")
     }
 
     pub async fn activate_one_click(&self, item: &ParsedOneClick) -> anyhow::Result<()> {
         for entry in &item.entries {
             for command in &entry.msgs {
-                self.client
-                    .publish(
-                        entry.topic.as_str(),
-                        serde_json::to_string(command)?,
-                        QoS::AtMostOnce,
-                        false,
-                    )
-                    .await
-                    .context("sending OneClick")?;
+                self.enqueue(
+                    Priority::Interactive,
+                    entry.topic.as_str(),
+                    serde_json::to_string(command)?,
+                    None,
+                )
+                .await
+                .context("sending OneClick")?;
             }
         }
         Ok(())
     }
 }
 
+/// Publishes queued messages no faster than one per `interval`, with
+/// no more than the permits of `in_flight` being published at once,
+/// so that we stay clear of the AWS IoT rate limits.
+/// Interactive messages are always sent ahead of background messages.
+async fn run_iot_publisher(
+    session: SharedSession,
+    interactive: Receiver<OutgoingMessage>,
+    background: Receiver<OutgoingMessage>,
+    interval: Duration,
+    in_flight: Arc<Semaphore>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let msg = match interactive.try_recv() {
+            Ok(msg) => msg,
+            Err(_) => {
+                let next = tokio::select! {
+                    biased;
+                    msg = interactive.recv() => msg,
+                    msg = background.recv() => msg,
+                };
+                match next {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                }
+            }
+        };
+
+        ticker.tick().await;
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            break;
+        };
+        let client = session.lock().client.clone();
+        tokio::spawn(async move {
+            let result = client
+                .publish(&msg.topic, &msg.payload, QoS::AtMostOnce, false)
+                .await
+                .map_err(anyhow::Error::from);
+            drop(permit);
+            if let Err(err) = &result {
+                log::error!("IoT publish to {} failed: {err:#}", msg.topic);
+                cloud_health::record_failure(cloud_health::IOT_ENDPOINT, &format!("{err:#}"));
+            }
+            if let Some(audit) = msg.audit {
+                audit.finish(&result);
+            }
+            if let Some(done) = msg.done {
+                done.send(result.map(|_| ())).ok();
+            }
+        });
+    }
+    log::info!("IoT publisher terminated");
+}
+
 pub async fn start_iot_client(
     args: &Args,
     state: StateHandle,
@@ -237,72 +438,169 @@ pub async fn start_iot_client(
         Some(a) => a,
         None => client.login_account_cached().await?,
     };
+    let iot = connect_iot_client(
+        args,
+        state.clone(),
+        &client,
+        acct,
+        &args.undoc_args.govee_iot_key,
+        &args.undoc_args.govee_iot_cert,
+        None,
+    )
+    .await?;
+    state.set_iot_client(iot).await;
+    Ok(())
+}
+
+/// Connect to AWS IoT using the credentials of the given account,
+/// writing its key and certificate to the specified paths.
+/// `account` is the email of an additional account, or None for
+/// the primary account.
+pub async fn connect_iot_client(
+    args: &Args,
+    state: StateHandle,
+    client: &GoveeUndocumentedApi,
+    acct: LoginAccountResponse,
+    key_path: &Path,
+    cert_path: &Path,
+    account: Option<&str>,
+) -> anyhow::Result<IotClient> {
     log::trace!("{acct:#?}");
-    let res = client.get_iot_key(&acct.token).await?;
-    log::trace!("{res:#?}");
-
-    let key_bytes = data_encoding::BASE64.decode(res.p12.as_bytes())?;
-
-    log::trace!("parsing IoT PFX key");
-    let container = p12::PFX::parse(&key_bytes).context("PFX::parse")?;
-    for key in container.key_bags(&res.p12_pass).context("key_bags")? {
-        let priv_key = openssl::pkey::PKey::private_key_from_der(&key).context("from_der")?;
-        let pem = priv_key
-            .private_key_to_pem_pkcs8()
-            .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
-    }
-    for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
-        let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
-        let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
-    }
+    let link = IotLink {
+        name: account.unwrap_or("primary").to_string(),
+        undoc: client.clone(),
+        acct,
+        root_ca: args.undoc_args.amazon_root_ca.clone(),
+        key_path: key_path.to_path_buf(),
+        cert_path: cert_path.to_path_buf(),
+    };
+    let session = Arc::new(Mutex::new(link.connect().await?));
 
-    let client = mosquitto_rs::Client::with_id(
-        &format!(
-            "AP/{account_id}/{id}",
-            account_id = *acct.account_id,
-            id = uuid::Uuid::new_v4().simple()
-        ),
-        true,
-    )
-    .context("new client")?;
-    client
-        .configure_tls(
-            Some(&args.undoc_args.amazon_root_ca),
-            None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
-            None,
-        )
-        .context("configure_tls")?;
-    log::trace!("Connecting to IoT {} port 8883", res.endpoint);
-    let status = timeout(
-        Duration::from_secs(60),
-        client.connect(&res.endpoint, 8883, Duration::from_secs(120), None),
-    )
-    .await
-    .context("timeout connecting to IoT in AWS")?
-    .context("failed to connect to IoT in AWS")?;
-    log::info!("Connected to IoT: {status}");
+    let iot = IotClient::new(
+        session.clone(),
+        link.acct.topic.to_string(),
+        args.undoc_args.iot_publish_rate()?,
+        args.undoc_args.iot_publish_concurrency()?,
+    );
+
+    let task_name = match account {
+        Some(email) => format!("iot_subscriber {email}"),
+        None => "iot_subscriber".to_string(),
+    };
+    let supervisor = state.supervisor().clone();
+    supervisor.supervise(&task_name, RestartPolicy::OnFailure, move || {
+        run_iot_subscriber(link.clone(), session.clone(), state.clone())
+    });
+
+    Ok(iot)
+}
 
-    let subscriptions = client.subscriber().expect("first and only");
+impl IotLink {
+    async fn connect(&self) -> anyhow::Result<IotSession> {
+        // The token captured when the link was created may have since
+        // expired, which is the likely reason for needing a new key
+        let token = self
+            .undoc
+            .login_account_cached()
+            .await
+            .context("logging in to fetch the IoT key")?
+            .token;
+        let res = self.undoc.get_iot_key(&token).await?;
+        log::trace!("{res:#?}");
+
+        // Parsing the key and writing the files is blocking work
+        // that shouldn't hold up the runtime
+        let (p12, p12_pass) = (res.p12.clone(), res.p12_pass.clone());
+        let (key_file, cert_file) = (self.key_path.clone(), self.cert_path.clone());
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let key_bytes = data_encoding::BASE64.decode(p12.as_bytes())?;
 
-    state
-        .set_iot_client(IotClient {
-            client: client.clone(),
+            log::trace!("parsing IoT PFX key");
+            let container = p12::PFX::parse(&key_bytes).context("PFX::parse")?;
+            for key in container.key_bags(&p12_pass).context("key_bags")? {
+                let priv_key =
+                    openssl::pkey::PKey::private_key_from_der(&key).context("from_der")?;
+                let pem = priv_key
+                    .private_key_to_pem_pkcs8()
+                    .context("to_pem_pkcs8")?;
+                std::fs::write(&key_file, &pem)?;
+            }
+            for cert in container.cert_bags(&p12_pass).context("cert_bags")? {
+                let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
+                let pem = cert.to_pem().context("cert.to_pem")?;
+                std::fs::write(&cert_file, &pem)?;
+            }
+            Ok(())
         })
-        .await;
+        .await
+        .context("parsing the IoT credentials")??;
 
-    tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
-            log::error!("IoT loop failed: {err:#}");
+        let client = mosquitto_rs::Client::with_id(
+            &format!(
+                "AP/{account_id}/{id}",
+                account_id = *self.acct.account_id,
+                id = uuid::Uuid::new_v4().simple()
+            ),
+            true,
+        )
+        .context("new client")?;
+        client
+            .configure_tls(
+                Some(&self.root_ca),
+                None::<&std::path::Path>,
+                Some(&self.cert_path),
+                Some(&self.key_path),
+                None,
+            )
+            .context("configure_tls")?;
+        let events = client.subscriber().expect("first and only");
+        log::trace!("Connecting to IoT {} port 8883", res.endpoint);
+        let status = with_timeout("connecting to IoT in AWS", Duration::from_secs(60), async {
+            client
+                .connect(&res.endpoint, 8883, Duration::from_secs(120), None)
+                .await
+                .context("failed to connect to IoT in AWS")
+        })
+        .await
+        .map_err(|err| {
+            cloud_health::record_failure(cloud_health::IOT_ENDPOINT, &format!("{err:#}"));
+            err
+        })?;
+        log::info!("Connected to IoT: {status}");
+        cloud_health::record_success(cloud_health::IOT_ENDPOINT);
+
+        Ok(IotSession { client, events })
+    }
+
+    /// Replace the session with a new one
+    async fn rebuild(&self, session: &SharedSession, refetch_key: bool) -> anyhow::Result<()> {
+        if refetch_key {
+            log::warn!(
+                "IoT session for {} was refused repeatedly; fetching a new certificate",
+                self.name
+            );
+            self.undoc.invalidate_iot_key()?;
+        } else {
+            log::warn!("Rebuilding the IoT session for {}", self.name);
         }
-        log::info!("IoT loop terminated");
-        Ok::<(), anyhow::Error>(())
-    });
+        iot_health::record_rebuild(&self.name);
+        let replacement = self.connect().await?;
+        // Dropping the old client disconnects it
+        let _old = std::mem::replace(&mut *session.lock(), replacement);
+        Ok(())
+    }
 
-    Ok(())
+    /// Check that the session is working by subscribing to the
+    /// account topic again; the broker must acknowledge it
+    async fn probe(&self, client: &mosquitto_rs::Client) -> anyhow::Result<()> {
+        with_timeout("probing the IoT session", Duration::from_secs(30), async {
+            client
+                .subscribe(&self.acct.topic, mosquitto_rs::QoS::AtMostOnce)
+                .await
+                .context("resubscribing to the account topic")
+        })
+        .await
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -328,6 +626,82 @@ struct StateUpdate {
     pub color_temperature_kelvin: Option<u32>,
     pub sku: Option<String>,
     pub device: Option<String>,
+
+    // The next 4 fields are sourced from thermo-hygrometers,
+    // which report their readings along with the state of the
+    // out of range alarms that were configured in the Govee app.
+    /// hundredths of a degree celsius
+    pub tem: Option<i64>,
+    /// hundredths of a percent
+    pub hum: Option<i64>,
+    #[serde(rename = "temWarning", default, deserialize_with = "opt_boolean_int")]
+    pub tem_warning: Option<bool>,
+    #[serde(rename = "humWarning", default, deserialize_with = "opt_boolean_int")]
+    pub hum_warning: Option<bool>,
+
+    /// Wi-Fi signal strength in dBm, reported by some devices
+    pub rssi: Option<i64>,
+    #[serde(rename = "wifiChannel")]
+    pub wifi_channel: Option<i64>,
+
+    // Measurements of energy monitoring plugs, in the same
+    // units as the LastDeviceData of the undocumented API
+    pub power: Option<f64>,
+    pub energy: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+
+    #[serde(flatten)]
+    pub leak: LeakEvent,
+
+    #[serde(flatten)]
+    pub presence: PresenceEvent,
+}
+
+/// The fields reported by presence sensors
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PresenceEvent {
+    #[serde(default, alias = "presence", deserialize_with = "opt_boolean_int")]
+    pub occupied: Option<bool>,
+    pub detection_distance: Option<i64>,
+    pub sensitivity: Option<i64>,
+}
+
+impl PresenceEvent {
+    fn reading(&self) -> PresenceReading {
+        PresenceReading {
+            occupied: self.occupied,
+            detection_distance: self.detection_distance,
+            sensitivity: self.sensitivity,
+        }
+    }
+}
+
+/// The fields reported by leak detectors. These are relayed by their
+/// gateway, either in the state of the packet, or embedded as json
+/// in its msg field.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct LeakEvent {
+    #[serde(default, alias = "leak", deserialize_with = "opt_boolean_int")]
+    pub leak_state: Option<bool>,
+    pub battery: Option<u8>,
+    #[serde(default, deserialize_with = "opt_boolean_int")]
+    pub online: Option<bool>,
+    #[serde(rename = "gwonline", default, deserialize_with = "opt_boolean_int")]
+    pub gateway_online: Option<bool>,
+}
+
+impl LeakEvent {
+    fn reading(&self) -> LeakReading {
+        LeakReading {
+            leak: self.leak_state,
+            battery_percent: self.battery,
+            online: self.online,
+            gateway_online: self.gateway_online,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -368,139 +742,283 @@ impl Packet {
         let device = self.device()?;
         Some((sku, device))
     }
+
+    /// The state of a leak detector, combining the fields found in
+    /// the state with those embedded in the msg
+    fn leak_reading(&self) -> LeakReading {
+        let mut reading = self.state.leak.reading();
+        if let Some(event) = self
+            .msg
+            .as_deref()
+            .and_then(|msg| serde_json::from_str::<LeakEvent>(msg).ok())
+        {
+            reading.update(event.reading());
+        }
+        reading
+    }
 }
 
+/// Process the events of the session, periodically assessing its
+/// health, and rebuilding it when it has stopped working
 async fn run_iot_subscriber(
-    subscriptions: Receiver<Event>,
+    link: IotLink,
+    session: SharedSession,
     state: StateHandle,
-    client: mosquitto_rs::Client,
-    acct: LoginAccountResponse,
 ) -> anyhow::Result<()> {
-    while let Ok(event) = subscriptions.recv().await {
-        match event {
-            Event::Message(msg) => {
-                let payload = String::from_utf8_lossy(&msg.payload);
-                log::trace!("{} -> {payload}", msg.topic);
-
-                match from_json::<Packet, _>(&msg.payload) {
-                    Ok(packet) => {
-                        log::debug!("{packet:?}");
-                        if let Some((sku, device_id)) = packet.sku_and_device() {
-                            {
-                                let mut device = state.device_mut(sku, device_id).await;
-                                let mut state = match device.iot_device_status.clone() {
-                                    Some(state) => state,
-                                    None => match device.device_state() {
-                                        Some(state) => DeviceStatus {
-                                            on: state.on,
-                                            brightness: state.brightness,
-                                            color: state.color,
-                                            color_temperature_kelvin: state.kelvin,
-                                        },
-                                        None => DeviceStatus::default(),
+    let mut assess = tokio::time::interval(iot_health::ASSESS_INTERVAL);
+    assess.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let (client, events) = {
+            let session = session.lock();
+            (session.client.clone(), session.events.clone())
+        };
+
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => handle_iot_event(event, &state, &client, &link).await?,
+                Err(_) => {
+                    // The broker ended the session deliberately,
+                    // so mosquitto won't reconnect it for us
+                    iot_health::record_disconnected(&link.name, false);
+                    link.rebuild(&session, false).await?;
+                }
+            },
+            _ = assess.tick() => match iot_health::assess(&link.name) {
+                iot_health::Verdict::Healthy => {}
+                iot_health::Verdict::Probe => match link.probe(&client).await {
+                    Ok(()) => {
+                        log::debug!("IoT session for {} is alive", link.name);
+                        iot_health::record_probe_success(&link.name);
+                    }
+                    Err(err) => {
+                        log::warn!("IoT session for {} is unresponsive: {err:#}", link.name);
+                        link.rebuild(&session, false).await?;
+                    }
+                },
+                iot_health::Verdict::Rebuild { refetch_key } => {
+                    link.rebuild(&session, refetch_key).await?;
+                }
+            },
+        }
+    }
+}
+
+async fn handle_iot_event(
+    event: Event,
+    state: &StateHandle,
+    client: &mosquitto_rs::Client,
+    link: &IotLink,
+) -> anyhow::Result<()> {
+    match event {
+        Event::Message(msg) => {
+            iot_health::record_message(&link.name);
+            let payload = String::from_utf8_lossy(&msg.payload);
+            log::trace!("{} -> {payload}", msg.topic);
+
+            match from_json::<Packet, _>(&msg.payload) {
+                Ok(packet) => {
+                    log::debug!("{packet:?}");
+                    if let Some((sku, device_id)) = packet.sku_and_device() {
+                        let mut new_alarms = vec![];
+                        let wifi_first_seen;
+                        let power_first_seen;
+                        let power_restored;
+                        {
+                            let mut device = state.device_mut(sku, device_id).await;
+                            let mut state = match device.iot_device_status.clone() {
+                                Some(state) => state,
+                                None => match device.device_state() {
+                                    Some(state) => DeviceStatus {
+                                        on: state.on,
+                                        brightness: iot_brightness_scale(sku)
+                                            .to_device(state.brightness)
+                                            as u8,
+                                        color: state.color,
+                                        color_temperature_kelvin: state.kelvin,
                                     },
-                                };
+                                    None => DeviceStatus::default(),
+                                },
+                            };
 
-                                if let Some(v) = packet.state.brightness {
-                                    state.brightness = v;
-                                    state.on = v != 0;
-                                }
-                                if let Some(v) = packet.state.color {
-                                    state.color = v;
-                                    state.on = true;
-                                }
-                                if let Some(v) = packet.state.color_temperature_kelvin {
-                                    state.color_temperature_kelvin = v;
-                                    state.on = true;
-                                }
+                            if let Some(v) = packet.state.brightness {
+                                state.brightness = v;
+                                state.on = v != 0;
+                            }
+                            if let Some(v) = packet.state.color {
+                                state.color = v;
+                                state.on = true;
+                            }
+                            if let Some(v) = packet.state.color_temperature_kelvin {
+                                state.color_temperature_kelvin = v;
+                                state.on = true;
+                            }
 
-                                if let Some(op) = &packet.op {
-                                    for cmd in &op.command {
-                                        let decoded = cmd.decode_for_sku(sku);
-                                        log::debug!("Decoded: {decoded:?} for {sku}");
-                                        match decoded {
-                                            GoveeBlePacket::NotifyHumidifierNightlight(nl) => {
-                                                state.brightness = nl.brightness;
-                                                state.color = DeviceColor {
-                                                    r: nl.r,
-                                                    g: nl.g,
-                                                    b: nl.b,
-                                                };
-                                                device.set_nightlight_state(nl.clone());
-                                            }
-                                            GoveeBlePacket::NotifyHumidifierAutoMode(
-                                                HumidifierAutoMode { target_humidity },
-                                            ) => {
-                                                device.set_target_humidity(
-                                                    target_humidity.as_percent(),
-                                                );
-                                            }
-                                            GoveeBlePacket::NotifyHumidifierMode(
-                                                NotifyHumidifierMode { mode, param },
-                                            ) => {
-                                                device.set_humidifier_work_mode_and_param(
-                                                    mode, param,
-                                                );
-                                            }
-                                            GoveeBlePacket::Generic(_) => {
-                                                // Ignore packets that we can't decode
-                                            }
-                                            GoveeBlePacket::SetHumidifierMode(_)
-                                            | GoveeBlePacket::SetHumidifierNightlight(_) => {
-                                                // Ignore packets that are essentially echoing
-                                                // commands sent to the device
-                                            }
-                                            _ => {
-                                                // But warn about the ones we could decode and
-                                                // aren't handling here
-                                                log::warn!(
-                                                    "Taking no action for {decoded:?} for {sku}"
-                                                );
-                                            }
+                            if let Some(op) = &packet.op {
+                                for cmd in &op.command {
+                                    let decoded = cmd.decode_for_sku(sku);
+                                    log::debug!("Decoded: {decoded:?} for {sku}");
+                                    match decoded {
+                                        GoveeBlePacket::NotifyHumidifierNightlight(nl) => {
+                                            state.brightness = nl.brightness;
+                                            state.color = DeviceColor {
+                                                r: nl.r,
+                                                g: nl.g,
+                                                b: nl.b,
+                                            };
+                                            device.set_nightlight_state(nl);
+                                        }
+                                        GoveeBlePacket::NotifyHumidifierAutoMode(
+                                            HumidifierAutoMode { target_humidity },
+                                        ) => {
+                                            device
+                                                .set_target_humidity(target_humidity.as_percent());
+                                        }
+                                        GoveeBlePacket::NotifyHumidifierMode(
+                                            NotifyHumidifierMode { mode, param },
+                                        ) => {
+                                            device.set_humidifier_work_mode_and_param(mode, param);
+                                        }
+                                        GoveeBlePacket::Generic(_) => {
+                                            // Ignore packets that we can't decode
+                                        }
+                                        GoveeBlePacket::SetHumidifierMode(_)
+                                        | GoveeBlePacket::SetHumidifierNightlight(_) => {
+                                            // Ignore packets that are essentially echoing
+                                            // commands sent to the device
+                                        }
+                                        _ => {
+                                            // But warn about the ones we could decode and
+                                            // aren't handling here
+                                            log::warn!(
+                                                "Taking no action for {decoded:?} for {sku}"
+                                            );
                                         }
                                     }
                                 }
+                            }
 
-                                // Check on/off last, as we can synthesize "on"
-                                // if the other fields are present
-                                if let Some(on_off) = packet.state.on_off {
-                                    state.on = on_off != 0;
+                            for (measurement, raised, value) in [
+                                (
+                                    AlarmMeasurement::Temperature,
+                                    packet.state.tem_warning,
+                                    packet.state.tem,
+                                ),
+                                (
+                                    AlarmMeasurement::Humidity,
+                                    packet.state.hum_warning,
+                                    packet.state.hum,
+                                ),
+                            ] {
+                                if let Some(raised) = raised {
+                                    new_alarms.extend(device.update_sensor_alarm(
+                                        measurement,
+                                        raised,
+                                        value.map(|v| v as f64 / 100.),
+                                    ));
+                                }
+                            }
+
+                            if device.is_leak_sensor() {
+                                device.update_leak_reading(packet.leak_reading(), Utc::now());
+                            }
+                            if device.is_presence_sensor() {
+                                device.update_presence_reading(
+                                    packet.state.presence.reading(),
+                                    Utc::now(),
+                                );
+                            }
+
+                            wifi_first_seen = device
+                                .update_wifi_info(packet.state.rssi, packet.state.wifi_channel);
+                            power_first_seen = device.update_power_reading(
+                                PowerReading {
+                                    power_watts: packet.state.power,
+                                    energy_kwh: packet.state.energy,
+                                    voltage: packet.state.voltage,
+                                    current_amps: packet.state.current,
+                                },
+                                Utc::now(),
+                            );
+
+                            // Check on/off last, as we can synthesize "on"
+                            // if the other fields are present
+                            if let Some(on_off) = packet.state.on_off {
+                                state.on = on_off != 0;
+                            }
+                            power_restored = device.set_iot_device_status(state);
+                        }
+                        state.notify_of_state_change(device_id).await?;
+                        if wifi_first_seen {
+                            schedule_reregistration(state.clone(), "Wi-Fi details were reported");
+                        }
+                        if power_first_seen {
+                            schedule_reregistration(
+                                state.clone(),
+                                "Power measurements were reported",
+                            );
+                        }
+                        if power_restored {
+                            if let Some(device) = state.device_by_id(device_id).await {
+                                let state = state.clone();
+                                // Restoring sends commands whose results may
+                                // arrive via this same loop, so don't block it
+                                tokio::spawn(async move {
+                                    if let Err(err) = state.handle_power_restored(&device).await {
+                                        log::error!("Restoring {device} after power loss: {err:#}");
+                                    }
+                                });
+                            }
+                        }
+
+                        if !new_alarms.is_empty() {
+                            if let Some(device) = state.device_by_id(device_id).await {
+                                for alarm in &new_alarms {
+                                    publish_sensor_alarm(state, &device, alarm).await?;
                                 }
-                                device.set_iot_device_status(state);
                             }
-                            state.notify_of_state_change(device_id).await?;
                         }
-                    }
-                    Err(err) => {
-                        log::error!("Decoding IoT Packet: {err:#} {payload}");
                     }
                 }
+                Err(err) => {
+                    log::error!("Decoding IoT Packet: {err:#} {payload}");
+                }
             }
-            Event::Disconnected(reason) => {
-                log::warn!("IoT disconnected with reason {reason}");
-            }
-            Event::Connected(status) => {
-                log::info!("IoT (re)connected with status {status}");
-
-                client
-                    .subscribe(&acct.topic, mosquitto_rs::QoS::AtMostOnce)
-                    .await
-                    .context("subscribe to account topic")?;
-                // This logic tries to subscribe to the same data that is
-                // being sent to the individual devices, but the server
-                // will close the connection on us when we try this.
-                if false {
-                    let devices = state.devices().await;
-                    for d in devices {
-                        if let Some(undoc) = &d.undoc_device_info {
-                            if let Ok(topic) = undoc.entry.device_topic() {
-                                client
-                                    .subscribe(topic, mosquitto_rs::QoS::AtMostOnce)
-                                    .await
-                                    .with_context(|| {
-                                        format!("subscribe to device topic {topic}")
-                                    })?;
-                            }
+        }
+        Event::Disconnected(reason) => {
+            log::warn!("IoT disconnected with reason {reason}");
+            cloud_health::record_failure(
+                cloud_health::IOT_ENDPOINT,
+                &format!("disconnected with reason {reason}"),
+            );
+            iot_health::record_disconnected(&link.name, iot_health::is_auth_disconnect(reason.0));
+        }
+        Event::Connected(status) if !status.is_successful() => {
+            log::warn!("IoT connection was refused: {status}");
+            cloud_health::record_failure(cloud_health::IOT_ENDPOINT, &status.to_string());
+            iot_health::record_disconnected(&link.name, iot_health::is_auth_refusal(status.0));
+        }
+        Event::Connected(status) => {
+            log::info!("IoT (re)connected with status {status}");
+            cloud_health::record_success(cloud_health::IOT_ENDPOINT);
+            iot_health::record_connected(&link.name);
+
+            client
+                .subscribe(&link.acct.topic, mosquitto_rs::QoS::AtMostOnce)
+                .await
+                .context("subscribe to account topic")?;
+            // This logic tries to subscribe to the same data that is
+            // being sent to the individual devices, but the server
+            // will close the connection on us when we try this.
+            if false {
+                let devices = state.devices().await;
+                for d in devices {
+                    if let Some(undoc) = &d.undoc_device_info {
+                        if let Ok(topic) = undoc.entry.device_topic() {
+                            client
+                                .subscribe(topic, mosquitto_rs::QoS::AtMostOnce)
+                                .await
+                                .with_context(|| format!("subscribe to device topic {topic}"))?;
                         }
                     }
                 }
@@ -509,3 +1027,92 @@ async fn run_iot_subscriber(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::undoc_api::DevicesResponse;
+
+    #[test]
+    fn account_topic_routing() {
+        let resp: DevicesResponse =
+            from_json(include_str!("../../test-data/undoc-device-list.json")).unwrap();
+        let mut device = resp.devices[0].clone();
+        let command = serde_json::json!({"cmd": "turn", "data": {"val": 1}});
+
+        let mut msg = command.clone();
+        assert_eq!(
+            route_message(&device, "GA/account", &mut msg).unwrap(),
+            "GD/"
+        );
+        assert_eq!(msg, command);
+
+        device.device_ext.device_settings.topic = None;
+        let mut msg = command.clone();
+        assert_eq!(
+            route_message(&device, "GA/account", &mut msg).unwrap(),
+            "GA/account"
+        );
+        assert_eq!(
+            msg,
+            serde_json::json!({
+                "cmd": "turn",
+                "data": {"val": 1},
+                "sku": "H6072",
+                "device": "47:13:CF:00:00:00:00:25",
+            })
+        );
+
+        device.device_ext.device_settings.wifi_name = None;
+        assert!(route_message(&device, "GA/account", &mut command.clone()).is_err());
+    }
+
+    #[test]
+    fn power_packet() {
+        let packet: Packet = serde_json::from_value(serde_json::json!({
+            "sku": "H5160",
+            "device": "AA:BB:CC:DD:EE:FF:42:2A",
+            "state": {
+                "onOff": 1,
+                "power": 12.5,
+                "energy": 3.2,
+            },
+        }))
+        .unwrap();
+        assert_eq!(packet.state.power, Some(12.5));
+        assert_eq!(packet.state.energy, Some(3.2));
+        assert_eq!(packet.state.voltage, None);
+        assert_eq!(packet.state.current, None);
+    }
+
+    #[test]
+    fn presence_packet() {
+        let packet: Packet = serde_json::from_value(serde_json::json!({
+            "sku": "H5127",
+            "device": "AA:BB:CC:DD:EE:FF:42:2A",
+            "state": {
+                "presence": 1,
+                "detectionDistance": 250,
+                "sensitivity": 3,
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            packet.state.presence.reading(),
+            PresenceReading {
+                occupied: Some(true),
+                detection_distance: Some(250),
+                sensitivity: Some(3),
+            }
+        );
+
+        // A packet without presence fields yields nothing to apply
+        let packet: Packet = serde_json::from_value(serde_json::json!({
+            "sku": "H6072",
+            "device": "AA:BB:CC:DD:EE:FF:42:2A",
+            "state": {"onOff": 1},
+        }))
+        .unwrap();
+        assert!(packet.state.presence.reading().is_empty());
+    }
+}
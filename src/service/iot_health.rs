@@ -0,0 +1,219 @@
+//! Tracks the health of the AWS IoT sessions, one per Govee account.
+//! The sessions have been observed to die silently, so that state
+//! updates stop arriving, without mosquitto noticing. The subscriber
+//! of each session periodically assesses its health: a session that
+//! has been quiet for a while is probed by subscribing to the account
+//! topic again, and a session that stays disconnected, or is refused
+//! because of its credentials, is rebuilt from scratch.
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often each session is assessed
+pub const ASSESS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A connected session that has seen no traffic for this
+/// long is probed
+const PROBE_AFTER: Duration = Duration::from_secs(600);
+
+/// A session that has been disconnected for this long is rebuilt,
+/// rather than waiting for mosquitto to reconnect it
+const REBUILD_AFTER: Duration = Duration::from_secs(300);
+
+/// After this many consecutive authentication failures, the
+/// certificate is fetched again before the session is rebuilt
+const AUTH_FAILURES_BEFORE_REFETCH: u32 = 3;
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct IotLinkStatus {
+    pub connected: bool,
+    pub last_message: Option<DateTime<Utc>>,
+    pub last_connected: Option<DateTime<Utc>>,
+    pub reconnects: u32,
+    pub consecutive_auth_failures: u32,
+}
+
+#[derive(Debug)]
+struct LinkHealth {
+    status: IotLinkStatus,
+    /// When we last knew that the session was working
+    last_activity: Instant,
+    disconnected_since: Option<Instant>,
+}
+
+impl LinkHealth {
+    fn new(now: Instant) -> Self {
+        Self {
+            status: IotLinkStatus {
+                connected: false,
+                last_message: None,
+                last_connected: None,
+                reconnects: 0,
+                consecutive_auth_failures: 0,
+            },
+            last_activity: now,
+            disconnected_since: Some(now),
+        }
+    }
+
+    fn assess(&self, now: Instant) -> Verdict {
+        if self.status.consecutive_auth_failures >= AUTH_FAILURES_BEFORE_REFETCH {
+            return Verdict::Rebuild { refetch_key: true };
+        }
+        match self.disconnected_since {
+            Some(since) if now.saturating_duration_since(since) >= REBUILD_AFTER => {
+                Verdict::Rebuild { refetch_key: false }
+            }
+            Some(_) => Verdict::Healthy,
+            None if now.saturating_duration_since(self.last_activity) >= PROBE_AFTER => {
+                Verdict::Probe
+            }
+            None => Verdict::Healthy,
+        }
+    }
+}
+
+/// What the subscriber should do about its session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Healthy,
+    /// Check that the session is still working
+    Probe,
+    /// Replace the session with a new one
+    Rebuild {
+        refetch_key: bool,
+    },
+}
+
+static LINKS: Lazy<Mutex<BTreeMap<String, LinkHealth>>> = Lazy::new(Default::default);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn update<R>(link: &str, apply: impl FnOnce(&mut LinkHealth, Instant) -> R) -> R {
+    let now = Instant::now();
+    let mut links = LINKS.lock();
+    let health = links
+        .entry(link.to_string())
+        .or_insert_with(|| LinkHealth::new(now));
+    apply(health, now)
+}
+
+fn changed() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Incremented whenever a session connects or disconnects
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+pub fn record_connected(link: &str) {
+    update(link, |health, now| {
+        health.status.connected = true;
+        health.status.last_connected.replace(Utc::now());
+        health.status.consecutive_auth_failures = 0;
+        health.last_activity = now;
+        health.disconnected_since = None;
+    });
+    changed();
+}
+
+pub fn record_disconnected(link: &str, auth_failure: bool) {
+    update(link, |health, now| {
+        health.status.connected = false;
+        if auth_failure {
+            health.status.consecutive_auth_failures += 1;
+        }
+        health.disconnected_since.get_or_insert(now);
+    });
+    changed();
+}
+
+pub fn record_message(link: &str) {
+    update(link, |health, now| {
+        health.status.last_message.replace(Utc::now());
+        health.last_activity = now;
+    });
+}
+
+/// A probe showed that the session is working
+pub fn record_probe_success(link: &str) {
+    update(link, |health, now| {
+        health.last_activity = now;
+    });
+}
+
+pub fn record_rebuild(link: &str) {
+    update(link, |health, now| {
+        health.status.reconnects += 1;
+        health.status.consecutive_auth_failures = 0;
+        health.disconnected_since.get_or_insert(now);
+    });
+    changed();
+}
+
+pub fn assess(link: &str) -> Verdict {
+    update(link, |health, now| health.assess(now))
+}
+
+/// Whether a CONNACK code means that our credentials were refused
+pub fn is_auth_refusal(connack_code: i32) -> bool {
+    // 4: bad username or password, 5: not authorized
+    matches!(connack_code, 4 | 5)
+}
+
+/// Whether a disconnect reason suggests a credentials problem
+pub fn is_auth_disconnect(reason_code: i32) -> bool {
+    // MOSQ_ERR_TLS and MOSQ_ERR_AUTH
+    matches!(reason_code, 8 | 11)
+}
+
+pub fn link_statuses() -> BTreeMap<String, IotLinkStatus> {
+    LINKS
+        .lock()
+        .iter()
+        .map(|(link, health)| (link.to_string(), health.status.clone()))
+        .collect()
+}
+
+/// Whether all of the sessions are connected, or None
+/// if there are no sessions
+pub fn iot_link_up() -> Option<bool> {
+    let links = LINKS.lock();
+    if links.is_empty() {
+        None
+    } else {
+        Some(links.values().all(|health| health.status.connected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verdicts() {
+        let start = Instant::now();
+        let mut health = LinkHealth::new(start);
+        assert_eq!(health.assess(start), Verdict::Healthy);
+        assert_eq!(
+            health.assess(start + REBUILD_AFTER),
+            Verdict::Rebuild { refetch_key: false }
+        );
+
+        health.disconnected_since = None;
+        assert_eq!(health.assess(start + PROBE_AFTER / 2), Verdict::Healthy);
+        assert_eq!(health.assess(start + PROBE_AFTER), Verdict::Probe);
+
+        health.status.consecutive_auth_failures = AUTH_FAILURES_BEFORE_REFETCH;
+        assert_eq!(health.assess(start), Verdict::Rebuild { refetch_key: true });
+
+        assert!(is_auth_refusal(5));
+        assert!(!is_auth_refusal(0));
+        assert!(is_auth_disconnect(11));
+        assert!(!is_auth_disconnect(7));
+    }
+}
@@ -1,7 +1,52 @@
+pub mod auto_brightness;
+pub mod brightness;
+#[cfg(feature = "embedded-broker")]
+pub mod broker;
+pub mod capability_probe;
+pub mod cloud_failure;
+pub mod cloud_health;
+pub mod cloud_pacing;
+pub mod color_preset;
+pub mod command_audit;
+pub mod command_queue;
+pub mod config_file;
+pub mod contract;
 pub mod coordinator;
+pub mod daily_window;
+pub mod derived;
 pub mod device;
+pub mod device_customization;
+pub mod device_graph;
+pub mod device_group;
+pub mod device_schedule;
+pub mod entity_overrides;
+pub mod event_log;
+pub mod firmware_watch;
 pub mod hass;
 pub mod http;
+pub mod inventory;
+#[cfg(feature = "cloud")]
 pub mod iot;
+pub mod iot_health;
+pub mod mqtt_mirror;
+pub mod playlist;
+pub mod poll_intervals;
+pub mod power_save;
+pub mod preset_schedule;
+pub mod quirk_bundle;
 pub mod quirks;
+pub mod rate_of_change;
+pub mod scene_filter;
+pub mod scene_search;
+pub mod segment_control;
+pub mod segment_layout;
+pub mod shard;
+pub mod stall_detector;
 pub mod state;
+pub mod state_merge;
+pub mod state_snapshot;
+pub mod supervisor;
+pub mod systemd;
+pub mod transaction;
+pub mod transport_health;
+pub mod watchdog;
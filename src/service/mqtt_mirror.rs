@@ -0,0 +1,240 @@
+//! Mirrors the state that we publish to a second, read-only, mqtt
+//! broker; for example a cloud hosted broker that feeds a remote
+//! dashboard. Commands are only ever accepted from the primary broker;
+//! we don't subscribe to anything on the mirror.
+//!
+//! The mirror has its own connection, which is (re)established
+//! independently of the primary, so that an unreachable mirror never
+//! holds up the primary. We remember the most recent payload of each
+//! mirrored topic, and publish them all again whenever the mirror
+//! connects, so that it catches up on anything it missed.
+use crate::service::hass::availability_topic;
+use crate::service::stall_detector::with_timeout;
+use anyhow::Context;
+use async_channel::Receiver;
+use mosquitto_rs::{Client, Event, QoS};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// The most recent payload and retain flag of each topic
+    values: BTreeMap<String, (Vec<u8>, bool)>,
+    /// The topics that have changed since they were last published
+    dirty: BTreeSet<String>,
+}
+
+#[derive(Clone)]
+pub struct MqttMirror {
+    pending: Arc<Mutex<Pending>>,
+    notify: Arc<Notify>,
+    disco_prefix: String,
+}
+
+impl MqttMirror {
+    /// Whether a topic that we publish to the primary should be
+    /// mirrored. Discovery configs describe command topics that
+    /// the mirror cannot act upon, so they are left out, as are
+    /// the commands that we send to ourselves.
+    fn should_mirror(&self, topic: &str) -> bool {
+        !topic.starts_with(&format!("{}/", self.disco_prefix))
+            && !topic.split('/').any(|segment| segment == "command")
+    }
+
+    /// Queue a message to be published to the mirror.
+    /// This never blocks on the mirror connection.
+    pub fn mirror(&self, topic: &str, payload: &[u8], retain: bool) {
+        if !self.should_mirror(topic) || topic == availability_topic() {
+            return;
+        }
+        let mut pending = self.pending.lock();
+        pending
+            .values
+            .insert(topic.to_string(), (payload.to_vec(), retain));
+        pending.dirty.insert(topic.to_string());
+        drop(pending);
+        self.notify.notify_one();
+    }
+
+    /// Take the changed topics along with their current payloads
+    fn take_dirty(&self) -> Vec<(String, Vec<u8>, bool)> {
+        let mut pending = self.pending.lock();
+        let dirty = std::mem::take(&mut pending.dirty);
+        dirty
+            .into_iter()
+            .filter_map(|topic| {
+                let (payload, retain) = pending.values.get(&topic)?.clone();
+                Some((topic, payload, retain))
+            })
+            .collect()
+    }
+
+    /// Arrange for everything to be published again
+    fn mark_all_dirty(&self) {
+        let mut pending = self.pending.lock();
+        // Retained, as the mirror is typically used by dashboards that
+        // come and go, and the last will clears it when we disconnect
+        pending
+            .values
+            .insert(availability_topic(), (b"online".to_vec(), true));
+        let topics: BTreeSet<String> = pending.values.keys().cloned().collect();
+        pending.dirty = topics;
+    }
+}
+
+/// Start mirroring to the broker described by `config`.
+/// The connection is made in the background.
+pub fn spawn_mqtt_mirror(config: MirrorConfig, disco_prefix: &str) -> MqttMirror {
+    let mirror = MqttMirror {
+        pending: Arc::new(Mutex::new(Pending::default())),
+        notify: Arc::new(Notify::new()),
+        disco_prefix: disco_prefix.to_string(),
+    };
+    let runner = mirror.clone();
+    tokio::spawn(async move { run_mirror(config, runner).await });
+    mirror
+}
+
+async fn connect_mirror(config: &MirrorConfig) -> anyhow::Result<(Client, Receiver<Event>)> {
+    let client = Client::with_id(
+        &format!("govee2mqtt-mirror/{}", uuid::Uuid::new_v4().simple()),
+        true,
+    )?;
+    client.set_last_will(availability_topic(), "offline", QoS::AtMostOnce, true)?;
+    client.set_username_and_password(config.username.as_deref(), config.password.as_deref())?;
+    client.set_reconnect_delay(Duration::from_secs(1), Duration::from_secs(60), true)?;
+    let subscriber = client.subscriber().expect("to own the subscriber");
+    with_timeout(
+        "connecting to the mirror mqtt broker",
+        Duration::from_secs(60),
+        async {
+            client
+                .connect(
+                    &config.host,
+                    config.port.into(),
+                    Duration::from_secs(120),
+                    None,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "connecting to mirror mqtt broker {}:{}",
+                        config.host, config.port
+                    )
+                })
+        },
+    )
+    .await?;
+    Ok((client, subscriber))
+}
+
+async fn run_mirror(config: MirrorConfig, mirror: MqttMirror) {
+    let mut backoff = INITIAL_CONNECT_BACKOFF;
+    loop {
+        match connect_mirror(&config).await {
+            Ok((client, events)) => {
+                log::info!("Connected to mirror mqtt broker {}", config.host);
+                backoff = INITIAL_CONNECT_BACKOFF;
+                run_mirror_session(&client, events, &mirror).await;
+                log::warn!("Lost the connection to mirror mqtt broker {}", config.host);
+            }
+            Err(err) => {
+                log::warn!("{err:#}; trying again in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Publish to the mirror until the session ends; mosquitto
+/// reconnects by itself after an unexpected disconnect, but
+/// the session ends if the broker closed it deliberately.
+async fn run_mirror_session(client: &Client, events: Receiver<Event>, mirror: &MqttMirror) {
+    let mut connected = true;
+    mirror.mark_all_dirty();
+    mirror.notify.notify_one();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Ok(Event::Connected(_)) => {
+                        log::info!("Reconnected to the mirror mqtt broker");
+                        connected = true;
+                        mirror.mark_all_dirty();
+                        mirror.notify.notify_one();
+                    }
+                    Ok(Event::Disconnected(reason)) => {
+                        log::debug!("Mirror mqtt broker disconnected: {reason}");
+                        connected = false;
+                    }
+                    Ok(Event::Message(_)) => {}
+                    Err(_) => return,
+                }
+            }
+            _ = mirror.notify.notified(), if connected => {
+                for (topic, payload, retain) in mirror.take_dirty() {
+                    if let Err(err) = client
+                        .publish(&topic, &payload, QoS::AtMostOnce, retain)
+                        .await
+                    {
+                        log::debug!("Publishing {topic} to the mirror failed: {err:#}");
+                        // Try again once the mirror reconnects
+                        mirror.mark_all_dirty();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pending_messages() {
+        let mirror = MqttMirror {
+            pending: Default::default(),
+            notify: Default::default(),
+            disco_prefix: "homeassistant".to_string(),
+        };
+        mirror.mirror("homeassistant/light/x/config", b"{}", false);
+        mirror.mirror("gv2mqtt/light/x/command", b"{}", false);
+        mirror.mirror("gv2mqtt/light/x/state", b"ON", false);
+        mirror.mirror("gv2mqtt/light/x/state", b"OFF", false);
+        mirror.mirror("gv2mqtt/bridge/cloud_status", b"{}", true);
+
+        assert_eq!(
+            mirror.take_dirty(),
+            vec![
+                (
+                    "gv2mqtt/bridge/cloud_status".to_string(),
+                    b"{}".to_vec(),
+                    true
+                ),
+                ("gv2mqtt/light/x/state".to_string(), b"OFF".to_vec(), false),
+            ]
+        );
+        assert!(mirror.take_dirty().is_empty());
+
+        mirror.mark_all_dirty();
+        assert_eq!(mirror.take_dirty().len(), 3);
+    }
+}
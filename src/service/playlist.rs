@@ -0,0 +1,420 @@
+//! Playlists rotate a device through an ordered list of scenes,
+//! showing each for a configured duration; for example, cycling
+//! through Halloween scenes each evening.
+//! They are defined in a YAML file, or by publishing a JSON document
+//! of the same shape to `gv2mqtt/playlists/set`:
+//!
+//! ```yaml
+//! playlists:
+//!   - name: Halloween
+//!     device: Porch Lights
+//!     window: "18:00-23:00"
+//!     items:
+//!       - scene: Pumpkin
+//!         duration: 15m
+//!       - scene: Ghost
+//!         duration: 90s
+//! ```
+use crate::service::daily_window::DailyWindow;
+use crate::service::device::Device;
+use crate::service::hass::topic_safe_string;
+use crate::service::shard::owns_device;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct PlaylistItem {
+    pub scene: String,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct PlaylistDefinition {
+    pub name: String,
+    /// The name or id of the device
+    pub device: String,
+    pub items: Vec<PlaylistItem>,
+    /// If set, the playlist automatically starts playing when the
+    /// window begins, and stops when it ends
+    #[serde(default)]
+    pub window: Option<DailyWindow>,
+    /// Whether to start over after the last item
+    #[serde(default = "default_repeat")]
+    pub repeat: bool,
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct PlaylistDocument {
+    pub playlists: Vec<PlaylistDefinition>,
+}
+
+impl PlaylistDocument {
+    pub fn load_yaml(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading playlists from {path:?}"))?;
+        let doc: Self = serde_yaml::from_str(&data)
+            .with_context(|| format!("parsing playlists from {path:?}"))?;
+        doc.validate()?;
+        Ok(doc)
+    }
+
+    pub fn parse_json(data: &str) -> anyhow::Result<Self> {
+        let doc: Self = serde_json::from_str(data).context("parsing playlists document")?;
+        doc.validate()?;
+        Ok(doc)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for playlist in &self.playlists {
+            if playlist.items.is_empty() {
+                anyhow::bail!("playlist {} has no items", playlist.name);
+            }
+            if playlist.items.iter().any(|item| item.duration.is_zero()) {
+                anyhow::bail!("playlist {} has an item with no duration", playlist.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accepts either a number of seconds, or a number with
+/// an `s`, `m` or `h` suffix
+fn deserialize_duration<'de, D: serde::de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    use serde::de::Error as _;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Seconds(u64),
+        String(String),
+    }
+
+    match Value::deserialize(deserializer)? {
+        Value::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        Value::String(s) => parse_duration(&s).map_err(|err| D::Error::custom(format!("{err:#}"))),
+    }
+}
+
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((idx, 's')) => (&s[..idx], 1),
+        Some((idx, 'm')) => (&s[..idx], 60),
+        Some((idx, 'h')) => (&s[..idx], 3600),
+        _ => (s, 1),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid duration '{s}'"))?;
+    if !number.is_finite() || number < 0. {
+        anyhow::bail!("invalid duration '{s}'");
+    }
+    Ok(Duration::from_secs_f64(number * multiplier as f64))
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Clone, Debug)]
+pub struct Playlist {
+    pub definition: PlaylistDefinition,
+    pub playback: PlaybackState,
+    pub index: usize,
+    /// When the current item ends, while playing
+    deadline: Option<Instant>,
+    /// The remaining time of the current item, while paused
+    remaining: Option<Duration>,
+    was_in_window: bool,
+}
+
+impl Playlist {
+    pub fn new(definition: PlaylistDefinition) -> Self {
+        Self {
+            definition,
+            playback: PlaybackState::Stopped,
+            index: 0,
+            deadline: None,
+            remaining: None,
+            was_in_window: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.definition.name
+    }
+
+    pub fn is_for_device(&self, device: &Device) -> bool {
+        self.definition.device.eq_ignore_ascii_case(&device.id)
+            || self.definition.device.eq_ignore_ascii_case(&device.name())
+    }
+
+    pub fn current_scene(&self) -> Option<&str> {
+        self.definition
+            .items
+            .get(self.index)
+            .map(|item| item.scene.as_str())
+    }
+
+    /// Returns the remaining time of the current item
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        match self.playback {
+            PlaybackState::Playing => self.deadline.map(|d| d.saturating_duration_since(now)),
+            PlaybackState::Paused => self.remaining,
+            PlaybackState::Stopped => None,
+        }
+    }
+
+    /// Start playing the item at `index`, returning the scene to apply
+    fn start_item(&mut self, index: usize, now: Instant) -> Option<String> {
+        let item = self.definition.items.get(index)?;
+        self.index = index;
+        self.playback = PlaybackState::Playing;
+        self.deadline.replace(now + item.duration);
+        self.remaining.take();
+        Some(item.scene.clone())
+    }
+
+    /// Start or resume playback, returning the scene to apply
+    pub fn play(&mut self, now: Instant) -> Option<String> {
+        match self.playback {
+            PlaybackState::Playing => None,
+            PlaybackState::Paused => {
+                self.playback = PlaybackState::Playing;
+                let remaining = self.remaining.take().unwrap_or_default();
+                self.deadline.replace(now + remaining);
+                self.current_scene().map(|s| s.to_string())
+            }
+            PlaybackState::Stopped => self.start_item(0, now),
+        }
+    }
+
+    pub fn pause(&mut self, now: Instant) {
+        if self.playback == PlaybackState::Playing {
+            self.remaining = self.remaining(now);
+            self.deadline.take();
+            self.playback = PlaybackState::Paused;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.playback = PlaybackState::Stopped;
+        self.index = 0;
+        self.deadline.take();
+        self.remaining.take();
+    }
+
+    /// Advance to the next item, returning the scene to apply.
+    /// Playback stops after the last item unless the playlist repeats.
+    pub fn next(&mut self, now: Instant) -> Option<String> {
+        let mut index = self.index + 1;
+        if index >= self.definition.items.len() {
+            if !self.definition.repeat {
+                self.stop();
+                return None;
+            }
+            index = 0;
+        }
+        self.start_item(index, now)
+    }
+
+    /// Called periodically to advance the playlist, and to start
+    /// or stop it according to its window.
+    /// Returns true if the playback state changed, along with the
+    /// scene to apply, if any.
+    fn tick(&mut self, now: Instant, in_window: Option<bool>) -> (bool, Option<String>) {
+        if let Some(in_window) = in_window {
+            let was_in_window = std::mem::replace(&mut self.was_in_window, in_window);
+            if in_window && !was_in_window && self.playback == PlaybackState::Stopped {
+                return (true, self.play(now));
+            }
+            if !in_window && was_in_window && self.playback != PlaybackState::Stopped {
+                self.stop();
+                return (true, None);
+            }
+        }
+
+        if self.playback == PlaybackState::Playing
+            && self.deadline.map(|d| now >= d).unwrap_or(false)
+        {
+            return (true, self.next(now));
+        }
+
+        (false, None)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlaylistStatus {
+    pub state: PlaybackState,
+    pub scene: Option<String>,
+    pub index: usize,
+    pub remaining_seconds: Option<u64>,
+    pub items: Vec<String>,
+}
+
+impl From<&Playlist> for PlaylistStatus {
+    fn from(playlist: &Playlist) -> Self {
+        Self {
+            state: playlist.playback,
+            scene: playlist.current_scene().map(|s| s.to_string()),
+            index: playlist.index,
+            remaining_seconds: playlist.remaining(Instant::now()).map(|d| d.as_secs()),
+            items: playlist
+                .definition
+                .items
+                .iter()
+                .map(|item| item.scene.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Apply a scene on behalf of a playlist, and let hass know
+/// about the new playlist state
+async fn apply_playlist_change(
+    state: &StateHandle,
+    device_name: &str,
+    scene: Option<String>,
+) -> anyhow::Result<()> {
+    if let Some(device) = state.resolve_device(device_name).await {
+        if !owns_device(&device) {
+            // Another bridge is running the same playlist
+            return Ok(());
+        }
+    }
+    let device = state.resolve_device_for_control(device_name).await?;
+    if let Some(scene) = scene {
+        log::info!("Playlist: setting {device} to scene {scene}");
+        state.device_set_scene(&device, &scene).await?;
+    }
+    state.notify_of_state_change(&device.id).await
+}
+
+/// Handle a play, pause, next or stop command for the playlist
+/// of `device` whose topic safe name is `name`
+pub async fn playlist_command(
+    state: &StateHandle,
+    device: &Device,
+    name: &str,
+    command: &str,
+) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let (device_name, scene) = {
+        let mut playlists = state.playlists_mut().await;
+        let playlist = playlists
+            .iter_mut()
+            .find(|p| {
+                p.is_for_device(device) && topic_safe_string(p.name()) == topic_safe_string(name)
+            })
+            .ok_or_else(|| anyhow::anyhow!("{device} has no playlist named {name}"))?;
+
+        let scene = match command.to_ascii_lowercase().as_str() {
+            "play" => playlist.play(now),
+            "pause" => {
+                playlist.pause(now);
+                None
+            }
+            "next" => playlist.next(now),
+            "stop" => {
+                playlist.stop();
+                None
+            }
+            _ => anyhow::bail!("invalid playlist command {command}"),
+        };
+        (playlist.definition.device.clone(), scene)
+    };
+
+    apply_playlist_change(state, &device_name, scene).await
+}
+
+/// Advance the playlists as their items expire
+pub async fn run_playlists(state: StateHandle) -> anyhow::Result<()> {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let now = Instant::now();
+        let local_time = Local::now().time();
+        let changes: Vec<(String, Option<String>)> = state
+            .playlists_mut()
+            .await
+            .iter_mut()
+            .filter_map(|playlist| {
+                let in_window = playlist.definition.window.map(|w| w.contains(local_time));
+                match playlist.tick(now, in_window) {
+                    (true, scene) => Some((playlist.definition.device.clone(), scene)),
+                    (false, _) => None,
+                }
+            })
+            .collect();
+
+        for (device_name, scene) in changes {
+            if let Err(err) = apply_playlist_change(&state, &device_name, scene).await {
+                log::error!("Playlist for {device_name}: {err:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn playlist_playback() {
+        let doc = PlaylistDocument::parse_json(
+            r#"{"playlists": [{"name": "Halloween", "device": "Porch",
+                "items": [{"scene": "Pumpkin", "duration": "1.5m"},
+                          {"scene": "Ghost", "duration": 30}]}]}"#,
+        )
+        .unwrap();
+        let definition = &doc.playlists[0];
+        assert_eq!(definition.items[0].duration, Duration::from_secs(90));
+        assert!(definition.repeat);
+
+        let mut playlist = Playlist::new(definition.clone());
+        let start = Instant::now();
+        assert_eq!(playlist.play(start).as_deref(), Some("Pumpkin"));
+        assert_eq!(
+            playlist.tick(start + Duration::from_secs(89), None),
+            (false, None)
+        );
+
+        playlist.pause(start + Duration::from_secs(80));
+        assert_eq!(playlist.remaining(start), Some(Duration::from_secs(10)));
+        let resumed = start + Duration::from_secs(200);
+        assert_eq!(playlist.play(resumed).as_deref(), Some("Pumpkin"));
+        assert_eq!(
+            playlist.tick(resumed + Duration::from_secs(10), None),
+            (true, Some("Ghost".to_string()))
+        );
+        assert_eq!(playlist.next(resumed).as_deref(), Some("Pumpkin"));
+
+        // Leaving the window stops playback
+        playlist.was_in_window = true;
+        assert_eq!(playlist.tick(resumed, Some(false)), (true, None));
+        assert_eq!(playlist.playback, PlaybackState::Stopped);
+
+        assert!(PlaylistDocument::parse_json(
+            r#"{"playlists": [{"name": "Empty", "device": "Porch", "items": []}]}"#
+        )
+        .is_err());
+    }
+}
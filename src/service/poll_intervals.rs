@@ -0,0 +1,219 @@
+//! Polling every device at the same interval wastes Platform API
+//! quota on devices whose state rarely matters, and is too slow for
+//! the few whose state matters a lot. Poll interval rules set the
+//! interval for all devices, for the devices of a SKU or for an
+//! individual device, optionally limited to one of the APIs that
+//! are used for polling.
+use crate::service::device::Device;
+use crate::service::playlist::parse_duration;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The shortest interval that a rule may set
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The interval between LAN status requests when no rule applies
+pub const DEFAULT_LAN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollApi {
+    Platform,
+    /// AWS IoT, via the undocumented API
+    Iot,
+    Lan,
+}
+
+impl std::str::FromStr for PollApi {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "platform" => Ok(Self::Platform),
+            "iot" | "undoc" => Ok(Self::Iot),
+            "lan" => Ok(Self::Lan),
+            s => anyhow::bail!("unknown API '{s}'. Expected one of platform, iot or lan"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PollIntervalRule {
+    /// A SKU, device id or device name, or None for all devices
+    pub target: Option<String>,
+    /// The API that the rule applies to, or None for all of them
+    pub api: Option<PollApi>,
+    pub interval: Duration,
+}
+
+impl std::str::FromStr for PollIntervalRule {
+    type Err = anyhow::Error;
+
+    /// Parses `[TARGET][/API]=INTERVAL`, where TARGET is a SKU, device
+    /// id or device name, or `*` for all devices. A bare API name may
+    /// be used in place of `*/API`. eg: `H5179=30m`,
+    /// `Porch Light/platform=1h` or `lan=30s`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (key, interval) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected TARGET=INTERVAL, got '{s}'"))?;
+        let interval = parse_duration(interval)?;
+        anyhow::ensure!(
+            interval >= MIN_INTERVAL,
+            "poll interval in '{s}' must be at least {MIN_INTERVAL:?}"
+        );
+
+        let key = key.trim();
+        let (target, api) = match key.rsplit_once('/') {
+            Some((target, api)) => (target.trim(), Some(api.parse()?)),
+            None => match key.parse::<PollApi>() {
+                Ok(api) => ("*", Some(api)),
+                Err(_) => (key, None),
+            },
+        };
+        anyhow::ensure!(!target.is_empty(), "missing target in '{s}'");
+
+        Ok(Self {
+            target: (target != "*").then(|| target.to_string()),
+            api,
+            interval,
+        })
+    }
+}
+
+impl PollIntervalRule {
+    /// How specific the rule is for the device and api, or None
+    /// if it doesn't apply. Higher values are more specific.
+    fn specificity(&self, device: &Device, api: PollApi) -> Option<u8> {
+        let target = match &self.target {
+            None => 0,
+            Some(t) if t.eq_ignore_ascii_case(&device.sku) => 2,
+            Some(t)
+                if t.eq_ignore_ascii_case(&device.id) || t.eq_ignore_ascii_case(&device.name()) =>
+            {
+                4
+            }
+            Some(_) => return None,
+        };
+        let api = match self.api {
+            None => 0,
+            Some(a) if a == api => 1,
+            Some(_) => return None,
+        };
+        Some(target + api)
+    }
+}
+
+/// Parse a list of rules separated by semicolons or newlines.
+/// Lines starting with `#` are ignored, so that the list can
+/// be kept in a commented file. `kind` names the kind of rule
+/// in the error reported for a rule that doesn't parse.
+pub fn parse_rule_list<T: FromStr<Err = anyhow::Error>>(
+    s: &str,
+    kind: &str,
+) -> anyhow::Result<Vec<T>> {
+    s.split([';', '\n'])
+        .map(|rule| rule.trim())
+        .filter(|rule| !rule.is_empty() && !rule.starts_with('#'))
+        .map(|rule| rule.parse().with_context(|| format!("{kind} '{rule}'")))
+        .collect()
+}
+
+pub fn parse_poll_interval_rules(s: &str) -> anyhow::Result<Vec<PollIntervalRule>> {
+    parse_rule_list(s, "poll interval rule")
+}
+
+pub fn load_poll_interval_rules(path: &std::path::Path) -> anyhow::Result<Vec<PollIntervalRule>> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse_poll_interval_rules(&data).with_context(|| format!("parsing {}", path.display()))
+}
+
+static RULES: Lazy<Mutex<Vec<PollIntervalRule>>> = Lazy::new(Default::default);
+
+pub fn set_poll_interval_rules(rules: Vec<PollIntervalRule>) {
+    *RULES.lock() = rules;
+}
+
+fn interval_from_rules(
+    rules: &[PollIntervalRule],
+    device: &Device,
+    api: PollApi,
+) -> Option<Duration> {
+    rules
+        .iter()
+        .filter_map(|rule| Some((rule.specificity(device, api)?, rule.interval)))
+        // The last of equally specific rules wins
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, interval)| interval)
+}
+
+/// Returns the interval at which to poll the device via the api,
+/// according to the configured rules, falling back to `default`
+pub fn poll_interval_for(device: &Device, api: PollApi, default: Duration) -> Duration {
+    interval_from_rules(&RULES.lock(), device, api).unwrap_or(default)
+}
+
+/// How often the pollers need to check whether a device is due,
+/// so that the shortest configured interval is honored
+pub fn poll_tick(default: Duration) -> Duration {
+    RULES
+        .lock()
+        .iter()
+        .map(|rule| rule.interval)
+        .min()
+        .map(|shortest| shortest.min(default))
+        .unwrap_or(default)
+        .max(MIN_INTERVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rule_precedence() {
+        let rules = parse_poll_interval_rules(
+            "*=20m; platform=30m\n\
+             # comment\n\
+             H5179=1h; H5179/iot=10m\n\
+             AA:BB:CC:DD:EE:FF:00:11/platform=2h",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 5);
+        assert_eq!(rules[1].target, None);
+        assert_eq!(rules[1].api, Some(PollApi::Platform));
+
+        let mins = |m: u64| Some(Duration::from_secs(m * 60));
+
+        let light = Device::new("H6072", "11:22:33:44:55:66:77:88");
+        assert_eq!(interval_from_rules(&rules, &light, PollApi::Iot), mins(20));
+        assert_eq!(
+            interval_from_rules(&rules, &light, PollApi::Platform),
+            mins(30)
+        );
+
+        let sensor = Device::new("H5179", "11:22:33:44:55:66:77:99");
+        assert_eq!(interval_from_rules(&rules, &sensor, PollApi::Iot), mins(10));
+        assert_eq!(
+            interval_from_rules(&rules, &sensor, PollApi::Platform),
+            mins(60)
+        );
+
+        let specific = Device::new("H5179", "AA:BB:CC:DD:EE:FF:00:11");
+        assert_eq!(
+            interval_from_rules(&rules, &specific, PollApi::Platform),
+            mins(120)
+        );
+        assert_eq!(
+            interval_from_rules(&rules, &specific, PollApi::Iot),
+            mins(10)
+        );
+
+        assert!("H6072=1s".parse::<PollIntervalRule>().is_err());
+        assert!("H6072/bluetooth=1m".parse::<PollIntervalRule>().is_err());
+        assert!("=1m".parse::<PollIntervalRule>().is_err());
+    }
+}
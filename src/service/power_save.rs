@@ -0,0 +1,65 @@
+//! When govee2mqtt runs on a battery-powered host, such as a laptop
+//! or a small board on a UPS, the recurring polling of devices and
+//! the cloud keeps the host and its network busy. Power save mode
+//! suspends that recurring work, while continuing to listen for LAN
+//! and IoT status updates and to handle commands from MQTT, and can
+//! be switched off again at any time to resume full operation.
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+static POWER_SAVE: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+pub fn set_power_save(enabled: bool) {
+    let changed = POWER_SAVE.send_if_modified(|current| {
+        let changed = *current != enabled;
+        *current = enabled;
+        changed
+    });
+    if changed {
+        if enabled {
+            log::info!("Entering power save mode; polling is suspended");
+        } else {
+            log::info!("Leaving power save mode; resuming polling");
+        }
+    }
+}
+
+pub fn power_save() -> bool {
+    *POWER_SAVE.borrow()
+}
+
+/// Wait until power save mode is not active. Recurring tasks call
+/// this before each round of work, so that they pick up again as
+/// soon as power save mode is switched off.
+pub async fn wait_while_power_save() {
+    let mut rx = POWER_SAVE.subscribe();
+    while *rx.borrow_and_update() {
+        // The sender is static and is never dropped
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn resume_wakes_waiters() {
+        set_power_save(true);
+        assert!(power_save());
+
+        let waiter = tokio::spawn(wait_while_power_save());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        set_power_save(false);
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!power_save());
+    }
+}
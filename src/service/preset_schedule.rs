@@ -0,0 +1,161 @@
+//! Desk lamps such as the H6047 have native presets, eg: Focus and
+//! Relax, that pick a color temperature and brightness to suit the
+//! task at hand. A preset schedule switches a lamp between its presets
+//! at set times of day, using the preset commands of the lamp rather
+//! than setting the color temperature directly, so that the lamp and
+//! the Govee app agree about which preset is active.
+//!
+//! The schedule only acts when one of its entries becomes due, so a
+//! preset that is chosen by other means is left alone until the next
+//! entry. Activating a preset may turn a lamp on, so a lamp that is off
+//! when an entry becomes due is switched once it has been turned on.
+use crate::service::poll_intervals::parse_rule_list;
+use crate::service::shard::owns_device;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use chrono::{Local, NaiveTime};
+use std::time::Duration;
+
+/// How often the schedules are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresetScheduleRule {
+    /// The lamp, by name or id
+    pub lamp: String,
+    /// (time of day, preset), sorted by time
+    pub entries: Vec<(NaiveTime, String)>,
+}
+
+impl std::str::FromStr for PresetScheduleRule {
+    type Err = anyhow::Error;
+
+    /// Parses `LAMP=HH:MM PRESET,HH:MM PRESET,...`,
+    /// eg: `Desk Lamp=08:00 Focus,18:30 Relax`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (lamp, entries) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected LAMP=HH:MM PRESET,..., got '{s}'"))?;
+        let lamp = lamp.trim();
+        anyhow::ensure!(!lamp.is_empty(), "missing lamp in '{s}'");
+
+        let mut schedule = vec![];
+        for entry in entries
+            .split(',')
+            .map(|e| e.trim())
+            .filter(|e| !e.is_empty())
+        {
+            let (time, preset) = entry
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow::anyhow!("expected HH:MM PRESET, got '{entry}'"))?;
+            let time = NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("parsing '{time}' as HH:MM"))?;
+            let preset = preset.trim();
+            schedule.push((time, preset.to_string()));
+        }
+        anyhow::ensure!(!schedule.is_empty(), "missing schedule in '{s}'");
+        schedule.sort_by_key(|(time, _)| *time);
+        anyhow::ensure!(
+            schedule.windows(2).all(|w| w[0].0 != w[1].0),
+            "duplicate time in '{s}'"
+        );
+
+        Ok(Self {
+            lamp: lamp.to_string(),
+            entries: schedule,
+        })
+    }
+}
+
+impl PresetScheduleRule {
+    /// The index of the entry that is in effect at `time`: the latest
+    /// one at or before it, or the last one of the previous day
+    pub fn due_entry(&self, time: NaiveTime) -> usize {
+        self.entries
+            .iter()
+            .rposition(|(start, _)| *start <= time)
+            .unwrap_or(self.entries.len() - 1)
+    }
+}
+
+/// Parse a list of rules separated by semicolons or newlines
+pub fn parse_preset_schedule_rules(s: &str) -> anyhow::Result<Vec<PresetScheduleRule>> {
+    parse_rule_list(s, "preset schedule rule")
+}
+
+pub async fn periodic_preset_schedule(
+    state: StateHandle,
+    rules: Vec<PresetScheduleRule>,
+) -> anyhow::Result<()> {
+    // The entry of each rule that was most recently applied
+    let mut applied: Vec<Option<usize>> = vec![None; rules.len()];
+
+    loop {
+        let now = Local::now().time();
+        for (rule, applied) in rules.iter().zip(applied.iter_mut()) {
+            let due = rule.due_entry(now);
+            if *applied == Some(due) {
+                continue;
+            }
+            let Some(lamp) = state.resolve_device(&rule.lamp).await else {
+                continue;
+            };
+            if !owns_device(&lamp) {
+                continue;
+            }
+            let Some(device_state) = lamp.device_state() else {
+                continue;
+            };
+            if !device_state.light_on.unwrap_or(device_state.on) {
+                continue;
+            }
+
+            let preset = &rule.entries[due].1;
+            log::info!("preset schedule: switching {lamp} to {preset}");
+            if let Err(err) = state.device_set_work_mode_preset(&lamp, preset).await {
+                log::error!("preset schedule: switching {lamp} to {preset}: {err:#}");
+            }
+            // Don't retry a failed entry every minute; it will
+            // be tried again when the next entry becomes due
+            *applied = Some(due);
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schedule() {
+        let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        let rule: PresetScheduleRule = "Desk Lamp=18:30 Wind Down, 08:00 Focus,13:00 Relax"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.lamp, "Desk Lamp");
+        assert_eq!(
+            rule.entries,
+            vec![
+                (t("08:00"), "Focus".to_string()),
+                (t("13:00"), "Relax".to_string()),
+                (t("18:30"), "Wind Down".to_string()),
+            ]
+        );
+        assert_eq!(rule.due_entry(t("08:00")), 0);
+        assert_eq!(rule.due_entry(t("12:59")), 0);
+        assert_eq!(rule.due_entry(t("15:00")), 1);
+        assert_eq!(rule.due_entry(t("23:00")), 2);
+        assert_eq!(rule.due_entry(t("03:00")), 2);
+
+        assert!("Desk Lamp=".parse::<PresetScheduleRule>().is_err());
+        assert!("Desk Lamp=8am Focus".parse::<PresetScheduleRule>().is_err());
+        assert!("Desk Lamp=08:00".parse::<PresetScheduleRule>().is_err());
+        assert!("Desk Lamp=08:00 Focus,08:00 Relax"
+            .parse::<PresetScheduleRule>()
+            .is_err());
+        assert!("=08:00 Focus".parse::<PresetScheduleRule>().is_err());
+    }
+}
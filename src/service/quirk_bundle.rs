@@ -0,0 +1,585 @@
+//! Quirk bundles allow support for a device to be shared between
+//! users without waiting for a new release. A bundle is a JSON file
+//! holding one or more quirks; the capability overrides, the packets
+//! used by the extended controls and the entity customizations for
+//! a SKU. Bundles are signed with an ed25519 key so that the person
+//! importing one can tell who produced it and that it hasn't been
+//! modified since.
+//!
+//! Imported bundles are placed in the quirks directory and are
+//! loaded at startup, taking precedence over the built-in quirks.
+use crate::ble::SegmentMask;
+use crate::platform_api::DeviceType;
+use crate::service::brightness::BrightnessScale;
+use crate::service::quirks::{ExtendedControl, HumidityUnits, Quirk};
+use crate::temperature::TemperatureUnits;
+use anyhow::Context;
+use data_encoding::HEXLOWER;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// The serializable form of a `Quirk`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QuirkSpec {
+    pub sku: String,
+    pub device_type: DeviceType,
+    pub icon: String,
+    #[serde(default)]
+    pub supports_rgb: bool,
+    #[serde(default)]
+    pub supports_brightness: bool,
+    #[serde(default)]
+    pub color_temp_range: Option<(u32, u32)>,
+    #[serde(default)]
+    pub avoid_platform_api: bool,
+    #[serde(default)]
+    pub ble_only: bool,
+    #[serde(default)]
+    pub lan_api_capable: bool,
+    #[serde(default)]
+    pub iot_api_supported: bool,
+    #[serde(default)]
+    pub combined_light_commands: bool,
+    #[serde(default)]
+    pub iot_brightness_scale: Option<BrightnessScale>,
+    #[serde(default)]
+    pub segment_packets: bool,
+    #[serde(default)]
+    pub lan_segments: Option<u32>,
+    #[serde(default)]
+    pub leak_sensor: bool,
+    #[serde(default)]
+    pub presence_sensor: bool,
+    #[serde(default)]
+    pub platform_temperature_sensor_units: Option<TemperatureUnits>,
+    #[serde(default)]
+    pub platform_humidity_sensor_units: Option<HumidityUnits>,
+    /// Modes to show as buttons rather than in a select entity
+    #[serde(default)]
+    pub show_as_preset_modes: Vec<String>,
+    #[serde(default)]
+    pub extended_controls: Vec<ExtendedControlSpec>,
+}
+
+/// The serializable form of an `ExtendedControl`.
+/// Packets are hex encoded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExtendedControlSpec {
+    Switch {
+        instance: String,
+        name: String,
+        icon: String,
+        on: String,
+        off: String,
+    },
+    Select {
+        instance: String,
+        name: String,
+        icon: String,
+        options: Vec<(String, String)>,
+    },
+    Lock {
+        instance: String,
+        name: String,
+        icon: String,
+        lock: String,
+        unlock: String,
+    },
+}
+
+/// Quirks are static for the life of the process, so the values
+/// loaded from a bundle are leaked to obtain the same lifetime
+/// as the built-in quirks
+fn leak_str(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn leak_packet(hex: &str) -> anyhow::Result<&'static [u8]> {
+    let bytes = HEXLOWER
+        .decode(hex.to_ascii_lowercase().as_bytes())
+        .with_context(|| format!("decoding packet {hex}"))?;
+    Ok(Box::leak(bytes.into_boxed_slice()))
+}
+
+impl ExtendedControlSpec {
+    fn from_control(control: &ExtendedControl) -> Self {
+        let hex = |packet: &[u8]| HEXLOWER.encode(packet);
+        match *control {
+            ExtendedControl::Switch {
+                instance,
+                name,
+                icon,
+                on,
+                off,
+            } => Self::Switch {
+                instance: instance.to_string(),
+                name: name.to_string(),
+                icon: icon.to_string(),
+                on: hex(on),
+                off: hex(off),
+            },
+            ExtendedControl::Select {
+                instance,
+                name,
+                icon,
+                options,
+            } => Self::Select {
+                instance: instance.to_string(),
+                name: name.to_string(),
+                icon: icon.to_string(),
+                options: options
+                    .iter()
+                    .map(|(name, packet)| (name.to_string(), hex(packet)))
+                    .collect(),
+            },
+            ExtendedControl::Lock {
+                instance,
+                name,
+                icon,
+                lock,
+                unlock,
+            } => Self::Lock {
+                instance: instance.to_string(),
+                name: name.to_string(),
+                icon: icon.to_string(),
+                lock: hex(lock),
+                unlock: hex(unlock),
+            },
+        }
+    }
+
+    fn into_control(self) -> anyhow::Result<ExtendedControl> {
+        Ok(match self {
+            Self::Switch {
+                instance,
+                name,
+                icon,
+                on,
+                off,
+            } => ExtendedControl::Switch {
+                on: leak_packet(&on)?,
+                off: leak_packet(&off)?,
+                instance: leak_str(instance),
+                name: leak_str(name),
+                icon: leak_str(icon),
+            },
+            Self::Select {
+                instance,
+                name,
+                icon,
+                options,
+            } => {
+                let options = options
+                    .into_iter()
+                    .map(|(name, packet)| Ok((leak_str(name), leak_packet(&packet)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                ExtendedControl::Select {
+                    options: Box::leak(options.into_boxed_slice()),
+                    instance: leak_str(instance),
+                    name: leak_str(name),
+                    icon: leak_str(icon),
+                }
+            }
+            Self::Lock {
+                instance,
+                name,
+                icon,
+                lock,
+                unlock,
+            } => ExtendedControl::Lock {
+                lock: leak_packet(&lock)?,
+                unlock: leak_packet(&unlock)?,
+                instance: leak_str(instance),
+                name: leak_str(name),
+                icon: leak_str(icon),
+            },
+        })
+    }
+}
+
+impl QuirkSpec {
+    pub fn from_quirk(quirk: &Quirk) -> Self {
+        Self {
+            sku: quirk.sku.to_string(),
+            device_type: quirk.device_type.clone(),
+            icon: quirk.icon.to_string(),
+            supports_rgb: quirk.supports_rgb,
+            supports_brightness: quirk.supports_brightness,
+            color_temp_range: quirk.color_temp_range,
+            avoid_platform_api: quirk.avoid_platform_api,
+            ble_only: quirk.ble_only,
+            lan_api_capable: quirk.lan_api_capable,
+            iot_api_supported: quirk.iot_api_supported,
+            combined_light_commands: quirk.combined_light_commands,
+            iot_brightness_scale: Some(quirk.iot_brightness_scale)
+                .filter(|scale| *scale != BrightnessScale::PERCENT),
+            segment_packets: quirk.segment_packets,
+            lan_segments: quirk.lan_segments,
+            leak_sensor: quirk.leak_sensor,
+            presence_sensor: quirk.presence_sensor,
+            platform_temperature_sensor_units: quirk.platform_temperature_sensor_units,
+            platform_humidity_sensor_units: quirk.platform_humidity_sensor_units,
+            show_as_preset_modes: quirk
+                .show_as_preset_buttons
+                .unwrap_or_default()
+                .iter()
+                .map(|mode| mode.to_string())
+                .collect(),
+            extended_controls: quirk
+                .extended_controls
+                .iter()
+                .map(ExtendedControlSpec::from_control)
+                .collect(),
+        }
+    }
+
+    pub fn into_quirk(self) -> anyhow::Result<Quirk> {
+        let mut quirk = Quirk::device(self.sku, self.device_type, "");
+        quirk.icon = self.icon.into();
+        quirk.supports_rgb = self.supports_rgb;
+        quirk.supports_brightness = self.supports_brightness;
+        quirk.color_temp_range = self.color_temp_range;
+        quirk.avoid_platform_api = self.avoid_platform_api;
+        quirk.ble_only = self.ble_only;
+        quirk.lan_api_capable = self.lan_api_capable;
+        quirk.iot_api_supported = self.iot_api_supported;
+        quirk.combined_light_commands = self.combined_light_commands;
+        quirk.iot_brightness_scale = self.iot_brightness_scale.unwrap_or_default();
+        if let Some(segments) = self.lan_segments {
+            anyhow::ensure!(
+                segments <= SegmentMask::MAX_SEGMENTS,
+                "{}: lan_segments must be no more than {}",
+                quirk.sku,
+                SegmentMask::MAX_SEGMENTS
+            );
+        }
+        quirk.segment_packets = self.segment_packets;
+        quirk.lan_segments = self.lan_segments;
+        quirk.leak_sensor = self.leak_sensor;
+        quirk.presence_sensor = self.presence_sensor;
+        quirk.platform_temperature_sensor_units = self.platform_temperature_sensor_units;
+        quirk.platform_humidity_sensor_units = self.platform_humidity_sensor_units;
+        if !self.show_as_preset_modes.is_empty() {
+            let modes: Vec<&'static str> = self
+                .show_as_preset_modes
+                .into_iter()
+                .map(leak_str)
+                .collect();
+            quirk.show_as_preset_buttons = Some(Box::leak(modes.into_boxed_slice()));
+        }
+        let controls = self
+            .extended_controls
+            .into_iter()
+            .map(ExtendedControlSpec::into_control)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        quirk.extended_controls = Box::leak(controls.into_boxed_slice());
+        Ok(quirk)
+    }
+}
+
+/// The signed portion of a bundle
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BundlePayload {
+    pub format: u32,
+    pub quirks: Vec<QuirkSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QuirkBundle {
+    #[serde(flatten)]
+    pub payload: BundlePayload,
+    /// The hex encoded public key of the signer
+    pub signer: String,
+    /// The hex encoded signature of the payload
+    pub signature: String,
+}
+
+impl QuirkBundle {
+    pub fn sign(quirks: Vec<QuirkSpec>, key: &SigningKey) -> anyhow::Result<Self> {
+        let payload = BundlePayload {
+            format: BUNDLE_FORMAT,
+            quirks,
+        };
+        let signature = key.sign(&serde_json::to_vec(&payload)?);
+        Ok(Self {
+            payload,
+            signer: HEXLOWER.encode(key.verifying_key().as_bytes()),
+            signature: HEXLOWER.encode(&signature.to_bytes()),
+        })
+    }
+
+    /// Check that the bundle was signed by its signer and
+    /// that it hasn't been modified since
+    pub fn verify(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.payload.format == BUNDLE_FORMAT,
+            "unsupported bundle format {}",
+            self.payload.format
+        );
+        let signer: [u8; 32] = HEXLOWER
+            .decode(self.signer.as_bytes())
+            .context("decoding signer")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("signer is not an ed25519 public key"))?;
+        let signer = VerifyingKey::from_bytes(&signer).context("invalid signer")?;
+        let signature: [u8; 64] = HEXLOWER
+            .decode(self.signature.as_bytes())
+            .context("decoding signature")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("signature is not an ed25519 signature"))?;
+        signer
+            .verify(
+                &serde_json::to_vec(&self.payload)?,
+                &Signature::from_bytes(&signature),
+            )
+            .context("the bundle signature is not valid")
+    }
+
+    /// A short form of the signer key, for display
+    pub fn fingerprint(&self) -> &str {
+        &self.signer[..self.signer.len().min(16)]
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("reading bundle {}", path.display()))?;
+        let bundle: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing bundle {}", path.display()))?;
+        bundle
+            .verify()
+            .with_context(|| format!("verifying bundle {}", path.display()))?;
+        Ok(bundle)
+    }
+}
+
+/// Load the signing key from `path`, creating a new one
+/// if it doesn't already exist
+pub fn load_or_create_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    if path.exists() {
+        let hex = std::fs::read_to_string(path)
+            .with_context(|| format!("reading signing key {}", path.display()))?;
+        let bytes: [u8; 32] = HEXLOWER
+            .decode(hex.trim().as_bytes())
+            .context("decoding signing key")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("{} is not an ed25519 key", path.display()))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let key = SigningKey::from_bytes(&rand::random());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    file.write_all(HEXLOWER.encode(key.as_bytes()).as_bytes())?;
+    log::info!("Created a new signing key in {}", path.display());
+    Ok(key)
+}
+
+/// The directory from which bundles are loaded
+pub fn bundle_dir() -> PathBuf {
+    std::env::var("GOVEE_QUIRKS_DIR")
+        .ok()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| crate::cache::cache_dir().join("govee2mqtt-quirks"))
+}
+
+/// The keys whose bundles may be loaded. A signature only shows who
+/// made a bundle, so bundles from any other signer are rejected.
+fn trusted_signers() -> Vec<String> {
+    let Ok(signers) = std::env::var("GOVEE_TRUSTED_QUIRK_SIGNERS") else {
+        return vec![];
+    };
+    signers
+        .split(',')
+        .map(|s| s.trim().to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .filter(|s| {
+            let valid = s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                log::warn!(
+                    "Ignoring {s} in GOVEE_TRUSTED_QUIRK_SIGNERS; \
+                    signers must be given as their full 64 character key"
+                );
+            }
+            valid
+        })
+        .collect()
+}
+
+/// Returns true if the bundle is signed by one of `signers`
+fn is_signed_by(bundle: &QuirkBundle, signers: &[String]) -> bool {
+    signers
+        .iter()
+        .any(|s| bundle.signer.eq_ignore_ascii_case(s))
+}
+
+/// Returns true if the bundle may be loaded
+pub fn is_trusted(bundle: &QuirkBundle) -> bool {
+    is_signed_by(bundle, &trusted_signers())
+}
+
+/// Load the quirks from all of the bundles in the bundle directory.
+/// Bundles that fail to verify are skipped.
+pub fn load_bundled_quirks() -> Vec<Quirk> {
+    let dir = bundle_dir();
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return vec![];
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name != crate::service::capability_probe::LEARNED_QUIRKS_FILE_NAME)
+                .unwrap_or(true)
+        })
+        .collect();
+    paths.sort();
+
+    let mut quirks = vec![];
+    for path in paths {
+        let bundle = match QuirkBundle::load(&path) {
+            Ok(bundle) => bundle,
+            Err(err) => {
+                log::error!("Skipping quirk bundle: {err:#}");
+                continue;
+            }
+        };
+        if !is_trusted(&bundle) {
+            log::error!(
+                "Skipping quirk bundle {}: signer {} is not trusted",
+                path.display(),
+                bundle.fingerprint()
+            );
+            continue;
+        }
+        let fingerprint = bundle.fingerprint().to_string();
+        for spec in bundle.payload.quirks {
+            let sku = spec.sku.clone();
+            match spec.into_quirk() {
+                Ok(quirk) => {
+                    log::info!(
+                        "Loaded quirk for {sku} from {} (signer {fingerprint})",
+                        path.display(),
+                    );
+                    quirks.push(quirk);
+                }
+                Err(err) => log::error!("Skipping quirk for {sku} in {}: {err:#}", path.display()),
+            }
+        }
+    }
+    quirks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::quirks::resolve_quirk;
+
+    #[test]
+    fn round_trip() {
+        let quirk = resolve_quirk("H7131").unwrap();
+        let mut spec = QuirkSpec::from_quirk(quirk);
+        assert_eq!(spec.show_as_preset_modes, vec!["gearMode"]);
+        assert!(spec.extended_controls.is_empty());
+        spec.extended_controls.push(ExtendedControlSpec::Lock {
+            instance: "controlLock".to_string(),
+            name: "Control Lock".to_string(),
+            icon: "mdi:lock".to_string(),
+            lock: "331001".to_string(),
+            unlock: "331000".to_string(),
+        });
+
+        let restored = spec.clone().into_quirk().unwrap();
+        assert_eq!(QuirkSpec::from_quirk(&restored), spec);
+    }
+
+    #[test]
+    fn extended_control_encoding() {
+        let spec: ExtendedControlSpec = serde_json::from_value(serde_json::json!({
+            "kind": "select",
+            "instance": "sceneDirection",
+            "name": "Scene Direction",
+            "icon": "mdi:rotate-3d-variant",
+            "options": [["Clockwise", "330B00"], ["Counterclockwise", "330b01"]],
+        }))
+        .unwrap();
+        let control = spec.into_control().unwrap();
+
+        let frame = |packet: &[u8]| {
+            let checksum = packet.iter().fold(0, |acc, b| acc ^ b);
+            let mut frame = packet.to_vec();
+            frame.resize(19, 0);
+            frame.push(checksum);
+            data_encoding::BASE64.encode(&frame)
+        };
+        assert_eq!(
+            control.command_for_value("Clockwise").unwrap(),
+            frame(&[0x33, 0x0b, 0x00])
+        );
+        assert_eq!(
+            control.command_for_value("Counterclockwise").unwrap(),
+            frame(&[0x33, 0x0b, 0x01])
+        );
+        assert!(control.command_for_value("Sideways").is_err());
+
+        let lock = ExtendedControlSpec::Lock {
+            instance: "controlLock".to_string(),
+            name: "Control Lock".to_string(),
+            icon: "mdi:lock".to_string(),
+            lock: "aa1001".to_string(),
+            unlock: "aa1000".to_string(),
+        }
+        .into_control()
+        .unwrap();
+        assert_eq!(
+            lock.command_for_value("LOCK").unwrap(),
+            frame(&[0xaa, 0x10, 0x01])
+        );
+        assert_eq!(
+            lock.command_for_value("unlock").unwrap(),
+            frame(&[0xaa, 0x10, 0x00])
+        );
+        assert!(lock.command_for_value("ON").is_err());
+    }
+
+    #[test]
+    fn signature() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let spec = QuirkSpec::from_quirk(resolve_quirk("H6072").unwrap());
+        let bundle = QuirkBundle::sign(vec![spec], &key).unwrap();
+        bundle.verify().unwrap();
+
+        let json = serde_json::to_string(&bundle).unwrap();
+        let parsed: QuirkBundle = serde_json::from_str(&json).unwrap();
+        parsed.verify().unwrap();
+
+        let mut tampered = parsed.clone();
+        tampered.payload.quirks[0].supports_rgb = false;
+        assert!(tampered.verify().is_err());
+
+        let signer = parsed.signer.clone();
+        assert!(is_signed_by(&parsed, &[signer.to_ascii_uppercase()]));
+        assert!(!is_signed_by(&parsed, &[]));
+        // A prefix of the key doesn't identify the signer
+        assert!(!is_signed_by(&parsed, &[signer[..16].to_string()]));
+
+        let mut forged = parsed;
+        forged.signer =
+            HEXLOWER.encode(SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes());
+        assert!(forged.verify().is_err());
+    }
+}
@@ -1,17 +1,22 @@
+use crate::ble::Base64HexBytes;
 use crate::platform_api::DeviceType;
+use crate::service::brightness::BrightnessScale;
+use crate::service::quirk_bundle::load_bundled_quirks;
 use crate::temperature::TemperatureUnits;
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::HashMap;
 
 #[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HumidityUnits {
     RelativePercent,
     RelativePercentTimes100,
 }
 
 impl HumidityUnits {
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_reading_to_relative_percent(&self, value: f64) -> f64 {
         match self {
             Self::RelativePercent => value,
@@ -20,6 +25,88 @@ impl HumidityUnits {
     }
 }
 
+/// A device-specific control that isn't covered by the generic
+/// light entity. These are implemented by sending raw BLE packets
+/// to the device via ptReal, and are exposed to hass as switch,
+/// select and lock entities.
+#[derive(Clone, Copy, Debug)]
+pub enum ExtendedControl {
+    Switch {
+        instance: &'static str,
+        name: &'static str,
+        icon: &'static str,
+        on: &'static [u8],
+        off: &'static [u8],
+    },
+    Select {
+        instance: &'static str,
+        name: &'static str,
+        icon: &'static str,
+        options: &'static [(&'static str, &'static [u8])],
+    },
+    Lock {
+        instance: &'static str,
+        name: &'static str,
+        icon: &'static str,
+        lock: &'static [u8],
+        unlock: &'static [u8],
+    },
+}
+
+impl ExtendedControl {
+    pub fn instance(&self) -> &'static str {
+        match self {
+            Self::Switch { instance, .. }
+            | Self::Select { instance, .. }
+            | Self::Lock { instance, .. } => instance,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Switch { name, .. } | Self::Select { name, .. } | Self::Lock { name, .. } => name,
+        }
+    }
+
+    pub fn icon(&self) -> &'static str {
+        match self {
+            Self::Switch { icon, .. } | Self::Select { icon, .. } | Self::Lock { icon, .. } => icon,
+        }
+    }
+
+    /// Returns the packet that will set the control to `value`,
+    /// which is either `ON`/`OFF` for a switch, `LOCK`/`UNLOCK`
+    /// for a lock, or the name of one of the options of a select
+    pub fn packet_for_value(&self, value: &str) -> anyhow::Result<&'static [u8]> {
+        match self {
+            Self::Switch { on, off, .. } => match value {
+                "ON" | "on" => Ok(on),
+                "OFF" | "off" => Ok(off),
+                _ => anyhow::bail!("invalid value {value} for switch {}", self.instance()),
+            },
+            Self::Select { options, .. } => options
+                .iter()
+                .find(|(name, _)| *name == value)
+                .map(|(_, packet)| *packet)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("invalid option {value} for select {}", self.instance())
+                }),
+            Self::Lock { lock, unlock, .. } => match value {
+                "LOCK" | "lock" => Ok(lock),
+                "UNLOCK" | "unlock" => Ok(unlock),
+                _ => anyhow::bail!("invalid value {value} for lock {}", self.instance()),
+            },
+        }
+    }
+
+    /// Returns the base64 encoded command that will set the control
+    /// to `value`; the packet is padded and checksummed as usual
+    pub fn command_for_value(&self, value: &str) -> anyhow::Result<String> {
+        let packet = self.packet_for_value(value)?;
+        Ok(Base64HexBytes::with_bytes(packet.to_vec()).base64())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Quirk {
     pub sku: Cow<'static, str>,
@@ -38,6 +125,27 @@ pub struct Quirk {
     /// their state.
     pub iot_api_supported: bool,
     pub show_as_preset_buttons: Option<&'static [&'static str]>,
+    /// If true, the device accepts a batch of BLE-encoded light
+    /// commands in a single ptReal message, which allows us to
+    /// apply brightness and color changes in one step.
+    pub combined_light_commands: bool,
+    pub extended_controls: &'static [ExtendedControl],
+    /// If true, the segments can be colored individually via
+    /// ptReal. The number of segments is taken from the Platform
+    /// API capabilities, as it varies with the length of the strip.
+    pub segment_packets: bool,
+    /// The number of segments that can be colored individually
+    /// via ptReal, when it is known up front
+    pub lan_segments: Option<u32>,
+    /// The range of brightness values that the device uses
+    /// in AWS IoT messages
+    pub iot_brightness_scale: BrightnessScale,
+    /// If true, the device is a leak detector that reports
+    /// its events via the cloud
+    pub leak_sensor: bool,
+    /// If true, the device is a presence sensor, such as
+    /// an mmWave radar, that reports occupancy
+    pub presence_sensor: bool,
 }
 
 impl Quirk {
@@ -60,6 +168,13 @@ impl Quirk {
             platform_humidity_sensor_units: None,
             iot_api_supported: false,
             show_as_preset_buttons: None,
+            combined_light_commands: false,
+            extended_controls: &[],
+            segment_packets: false,
+            lan_segments: None,
+            iot_brightness_scale: BrightnessScale::PERCENT,
+            leak_sensor: false,
+            presence_sensor: false,
         }
     }
 
@@ -83,6 +198,14 @@ impl Quirk {
         Self::device(sku, DeviceType::Thermometer, "mdi:thermometer")
     }
 
+    pub fn leak_sensor<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
+        Self::device(sku, DeviceType::Sensor, "mdi:water-alert").with_leak_sensor()
+    }
+
+    pub fn presence_sensor<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
+        Self::device(sku, DeviceType::Sensor, "mdi:motion-sensor").with_presence_sensor()
+    }
+
     pub fn with_rgb(mut self) -> Self {
         self.supports_rgb = true;
         self
@@ -123,6 +246,32 @@ impl Quirk {
         self
     }
 
+    pub fn with_combined_light_commands(mut self) -> Self {
+        self.combined_light_commands = true;
+        self
+    }
+
+    pub fn with_segment_packets(mut self) -> Self {
+        self.segment_packets = true;
+        self
+    }
+
+    pub fn with_leak_sensor(mut self) -> Self {
+        self.leak_sensor = true;
+        self
+    }
+
+    pub fn with_presence_sensor(mut self) -> Self {
+        self.presence_sensor = true;
+        self
+    }
+
+    pub fn extended_control(&self, instance: &str) -> Option<&'static ExtendedControl> {
+        self.extended_controls
+            .iter()
+            .find(|control| control.instance() == instance)
+    }
+
     pub fn with_broken_platform(mut self) -> Self {
         self.avoid_platform_api = true;
         self
@@ -145,7 +294,14 @@ impl Quirk {
     }
 }
 
-static QUIRKS: Lazy<HashMap<String, Quirk>> = Lazy::new(load_quirks);
+static QUIRKS: Lazy<HashMap<String, Quirk>> = Lazy::new(|| {
+    let mut map = load_quirks();
+    // Imported bundles take precedence over the built-in quirks
+    for quirk in load_bundled_quirks() {
+        map.insert(quirk.sku.to_string(), quirk);
+    }
+    map
+});
 
 const STRIP: &str = "mdi:led-strip-variant";
 const STRIP_ALT: &str = "mdi:led-strip";
@@ -212,6 +368,11 @@ fn load_quirks() -> HashMap<String, Quirk> {
             .with_iot_api_support(true)
             .with_rgb()
             .with_brightness(),
+        // Humidifiers that may have no platform API metadata,
+        // which would otherwise be treated as lights
+        Quirk::humidifier("H7141"),
+        Quirk::humidifier("H7142"),
+        Quirk::humidifier("H7143"),
         Quirk::space_heater("H7130")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
         Quirk::space_heater("H7131")
@@ -239,6 +400,12 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::thermometer("H5179")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
             .with_platform_humidity_sensor_units(HumidityUnits::RelativePercent),
+        // Leak detectors, which only report via the cloud;
+        // the H5058 is paired with an H5043 gateway
+        Quirk::leak_sensor("H5054"),
+        Quirk::leak_sensor("H5058"),
+        Quirk::presence_sensor("H5127"),
+        Quirk::device("H7172", DeviceType::IceMaker, "mdi:cube-outline"),
         Quirk::device("H7170", DeviceType::Kettle, "mdi:kettle")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
         Quirk::device("H7171", DeviceType::Kettle, "mdi:kettle")
@@ -249,7 +416,7 @@ fn load_quirks() -> HashMap<String, Quirk> {
             .with_show_as_preset_modes(&["Tea", "Coffee", "DIY"]),
         // Lights from the list of LAN API enabled devices
         // at <https://app-h5.govee.com/user-manual/wlan-guide>
-        Quirk::lan_api_capable_light("H6072", FLOOR_LAMP),
+        Quirk::lan_api_capable_light("H6072", FLOOR_LAMP).with_combined_light_commands(),
         Quirk::lan_api_capable_light("H619B", STRIP),
         Quirk::lan_api_capable_light("H619C", STRIP),
         Quirk::lan_api_capable_light("H619Z", STRIP),
@@ -265,7 +432,7 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::lan_api_capable_light("H6066", HEX),
         Quirk::lan_api_capable_light("H6067", TRIANGLE),
         Quirk::lan_api_capable_light("H6073", FLOOR_LAMP),
-        Quirk::lan_api_capable_light("H6076", FLOOR_LAMP),
+        Quirk::lan_api_capable_light("H6076", FLOOR_LAMP).with_combined_light_commands(),
         Quirk::lan_api_capable_light("H6078", FLOOR_LAMP),
         Quirk::lan_api_capable_light("H6087", WALL_SCONCE),
         Quirk::lan_api_capable_light("H610A", STRIP),
@@ -275,7 +442,7 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::lan_api_capable_light("H615E", STRIP),
         Quirk::lan_api_capable_light("H6163", STRIP),
         Quirk::lan_api_capable_light("H6168", TV_BACK),
-        Quirk::lan_api_capable_light("H6172", STRIP),
+        Quirk::lan_api_capable_light("H6172", STRIP).with_segment_packets(),
         Quirk::lan_api_capable_light("H6173", STRIP),
         Quirk::lan_api_capable_light("H618A", STRIP),
         Quirk::lan_api_capable_light("H618C", STRIP),
@@ -286,7 +453,7 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::lan_api_capable_light("H619E", STRIP),
         Quirk::lan_api_capable_light("H61A0", STRIP),
         Quirk::lan_api_capable_light("H61A1", STRIP),
-        Quirk::lan_api_capable_light("H61A2", STRIP),
+        Quirk::lan_api_capable_light("H61A2", STRIP).with_segment_packets(),
         Quirk::lan_api_capable_light("H61A3", STRIP),
         Quirk::lan_api_capable_light("H61A5", STRIP),
         Quirk::lan_api_capable_light("H61A8", STRIP),
@@ -0,0 +1,150 @@
+//! Rate of change sensors report how quickly the temperature and
+//! humidity measured by a sensor device are changing, in units per
+//! hour, so that automations can react to eg: a window being left
+//! open without having to set up statistics helpers in hass.
+//!
+//! The rate is the slope of a least squares fit over the readings
+//! that fall within a sliding window, which smooths out the jitter
+//! of individual readings.
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// The readings must span at least this fraction of the window
+/// before a rate is reported, so that the first couple of readings
+/// don't produce a wildly inaccurate rate
+const MIN_SPAN_FRACTION: f64 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateMeasurement {
+    Temperature,
+    Humidity,
+}
+
+impl RateMeasurement {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Temperature => "Temperature Rate of Change",
+            Self::Humidity => "Humidity Rate of Change",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Self::Temperature => "°C/h",
+            Self::Humidity => "%/h",
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature-rate",
+            Self::Humidity => "humidity-rate",
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct Readings {
+    samples: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+impl Readings {
+    /// Add a reading, discarding those that have left the window.
+    /// A reading with the same timestamp as the most recent one
+    /// is the same reading being reported again, and is ignored.
+    fn record(&mut self, when: DateTime<Utc>, value: f64, window: Duration) {
+        if self.samples.back().is_some_and(|(last, _)| *last >= when) {
+            return;
+        }
+        self.samples.push_back((when, value));
+        while let Some((first, _)) = self.samples.front() {
+            if (when - *first).to_std().unwrap_or_default() > window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// The rate of change per hour
+    fn rate_per_hour(&self, window: Duration) -> Option<f64> {
+        let (first, _) = self.samples.front()?;
+        let (last, _) = self.samples.back()?;
+        let span = (*last - *first).to_std().ok()?;
+        if self.samples.len() < 2 || span.as_secs_f64() < window.as_secs_f64() * MIN_SPAN_FRACTION {
+            return None;
+        }
+
+        let hours: Vec<f64> = self
+            .samples
+            .iter()
+            .map(|(when, _)| (*when - *first).num_milliseconds() as f64 / 3_600_000.)
+            .collect();
+        let n = self.samples.len() as f64;
+        let mean_t = hours.iter().sum::<f64>() / n;
+        let mean_v = self.samples.iter().map(|(_, v)| v).sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0., 0.);
+        for (t, (_, v)) in hours.iter().zip(self.samples.iter()) {
+            covariance += (t - mean_t) * (v - mean_v);
+            variance += (t - mean_t) * (t - mean_t);
+        }
+        (variance > 0.).then(|| covariance / variance)
+    }
+}
+
+static READINGS: Lazy<Mutex<HashMap<(String, RateMeasurement), Readings>>> =
+    Lazy::new(Default::default);
+
+/// Record a reading for a device and return the current rate of
+/// change per hour, if enough readings have been seen
+pub fn record_reading(
+    device_id: &str,
+    measurement: RateMeasurement,
+    when: DateTime<Utc>,
+    value: f64,
+    window: Duration,
+) -> Option<f64> {
+    let mut readings = READINGS.lock();
+    let readings = readings
+        .entry((device_id.to_string(), measurement))
+        .or_default();
+    readings.record(when, value, window);
+    readings.rate_per_hour(window)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate() {
+        let window = Duration::from_secs(3600);
+        let start = Utc::now();
+        let at = |mins: i64| start + chrono::Duration::minutes(mins);
+
+        let mut readings = Readings::default();
+        readings.record(at(0), 20.0, window);
+        assert_eq!(readings.rate_per_hour(window), None);
+        // Not yet spanning enough of the window
+        readings.record(at(10), 19.5, window);
+        assert_eq!(readings.rate_per_hour(window), None);
+        // The same reading reported again
+        readings.record(at(10), 19.5, window);
+        assert_eq!(readings.samples.len(), 2);
+
+        readings.record(at(20), 19.0, window);
+        readings.record(at(30), 18.5, window);
+        let rate = readings.rate_per_hour(window).unwrap();
+        assert!((rate - -3.0).abs() < 0.001, "{rate}");
+
+        // Old readings leave the window
+        readings.record(at(90), 18.5, window);
+        assert_eq!(readings.samples.len(), 2);
+        assert_eq!(readings.samples.front().unwrap().0, at(30));
+        let rate = readings.rate_per_hour(window).unwrap();
+        assert!(rate.abs() < 0.001, "{rate}");
+    }
+}
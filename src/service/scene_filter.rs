@@ -0,0 +1,234 @@
+//! Some devices, such as the H6199, have hundreds of scenes, which makes
+//! for an effect list that is too long to be usable. Scene filters pick
+//! out the scenes, or whole categories of scenes, that are offered in
+//! Home Assistant for each model of device.
+//!
+//! The categories are those of the scene library of the Govee Home app,
+//! eg: `Nature` or `Festival`. Scenes that aren't in the library, such
+//! as DIY scenes and music modes, are in the `Other` category.
+
+/// The category of the scenes that aren't in the scene library
+pub const OTHER_CATEGORY: &str = "Other";
+
+/// The scenes of a device, grouped by category, in the order
+/// that the categories are shown in the Govee Home app
+pub type SceneCategories = Vec<(String, Vec<String>)>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SceneSelector {
+    Category(String),
+    Name(String),
+}
+
+impl SceneSelector {
+    fn matches(&self, scene: &str, category: &str) -> bool {
+        match self {
+            Self::Category(c) => c.eq_ignore_ascii_case(category),
+            Self::Name(n) => n.eq_ignore_ascii_case(scene),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SceneFilterRule {
+    /// The SKU that the rule applies to, or None for all devices
+    pub sku: Option<String>,
+    /// When not empty, only the matching scenes are offered
+    pub include: Vec<SceneSelector>,
+    /// The matching scenes are not offered
+    pub exclude: Vec<SceneSelector>,
+}
+
+impl std::str::FromStr for SceneFilterRule {
+    type Err = anyhow::Error;
+
+    /// Parses `SKU=SELECTOR,SELECTOR,...` where the SKU may be `*` to
+    /// apply to all devices, and each selector is either the name of a
+    /// scene or `category:NAME`, optionally prefixed with `-` to exclude
+    /// the matching scenes, eg: `H6199=category:Nature,-Sunset`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (sku, selectors) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected SKU=SCENE,..., got '{s}'"))?;
+        let sku = sku.trim();
+        anyhow::ensure!(!sku.is_empty(), "missing SKU in '{s}'");
+
+        let mut include = vec![];
+        let mut exclude = vec![];
+        for selector in selectors
+            .split(',')
+            .map(|sel| sel.trim())
+            .filter(|sel| !sel.is_empty())
+        {
+            let (list, selector) = match selector.strip_prefix('-') {
+                Some(selector) => (&mut exclude, selector.trim()),
+                None => (&mut include, selector),
+            };
+            let selector = match selector.strip_prefix("category:") {
+                Some(category) => SceneSelector::Category(category.trim().to_string()),
+                None => SceneSelector::Name(selector.to_string()),
+            };
+            list.push(selector);
+        }
+        anyhow::ensure!(
+            !include.is_empty() || !exclude.is_empty(),
+            "missing scenes in '{s}'"
+        );
+
+        Ok(Self {
+            sku: if sku == "*" {
+                None
+            } else {
+                Some(sku.to_string())
+            },
+            include,
+            exclude,
+        })
+    }
+}
+
+impl SceneFilterRule {
+    pub fn applies_to(&self, sku: &str) -> bool {
+        self.sku
+            .as_deref()
+            .map(|s| s.eq_ignore_ascii_case(sku))
+            .unwrap_or(true)
+    }
+
+    /// Whether any of the selectors of the rule are categories
+    pub fn uses_categories(&self) -> bool {
+        self.include
+            .iter()
+            .chain(self.exclude.iter())
+            .any(|sel| matches!(sel, SceneSelector::Category(_)))
+    }
+
+    fn allows(&self, scene: &str, category: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|sel| sel.matches(scene, category)))
+            && !self.exclude.iter().any(|sel| sel.matches(scene, category))
+    }
+}
+
+/// The category of `scene`
+pub fn category_of<'a>(categories: &'a SceneCategories, scene: &str) -> &'a str {
+    categories
+        .iter()
+        .find(|(_, scenes)| scenes.iter().any(|s| s == scene))
+        .map(|(category, _)| category.as_str())
+        .unwrap_or(OTHER_CATEGORY)
+}
+
+/// Keep the scenes that are allowed by each of the rules that apply to `sku`
+pub fn filter_scenes(
+    rules: &[SceneFilterRule],
+    sku: &str,
+    scenes: Vec<String>,
+    categories: &SceneCategories,
+) -> Vec<String> {
+    let rules: Vec<&SceneFilterRule> = rules.iter().filter(|r| r.applies_to(sku)).collect();
+    scenes
+        .into_iter()
+        .filter(|scene| {
+            let category = category_of(categories, scene);
+            rules.iter().all(|rule| rule.allows(scene, category))
+        })
+        .collect()
+}
+
+/// Group `scenes` by category, keeping the order of the categories,
+/// and of the scenes within each of them
+pub fn group_by_category(scenes: &[String], categories: &SceneCategories) -> SceneCategories {
+    let mut groups: SceneCategories = vec![];
+    let mut other = vec![];
+    for scene in scenes {
+        let category = category_of(categories, scene);
+        if category == OTHER_CATEGORY {
+            other.push(scene.to_string());
+            continue;
+        }
+        match groups.iter_mut().find(|(c, _)| c == category) {
+            Some((_, group)) => group.push(scene.to_string()),
+            None => groups.push((category.to_string(), vec![scene.to_string()])),
+        }
+    }
+    groups.sort_by_key(|(category, _)| {
+        categories
+            .iter()
+            .position(|(c, _)| c == category)
+            .unwrap_or(usize::MAX)
+    });
+    if !other.is_empty() {
+        groups.push((OTHER_CATEGORY.to_string(), other));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn filters() {
+        let categories: SceneCategories = vec![
+            (
+                "Nature".to_string(),
+                strings(&["Forest", "Ocean", "Sunset"]),
+            ),
+            ("Festival".to_string(), strings(&["Christmas", "Halloween"])),
+        ];
+        let scenes = strings(&[
+            "Christmas",
+            "Forest",
+            "Halloween",
+            "Music: Energic",
+            "Ocean",
+            "Sunset",
+        ]);
+
+        let rule: SceneFilterRule = "H6199=category:nature, -Sunset".parse().unwrap();
+        assert_eq!(rule.sku.as_deref(), Some("H6199"));
+        assert!(rule.uses_categories());
+        assert_eq!(
+            filter_scenes(
+                std::slice::from_ref(&rule),
+                "H6199",
+                scenes.clone(),
+                &categories
+            ),
+            strings(&["Forest", "Ocean"])
+        );
+        assert_eq!(
+            filter_scenes(&[rule], "H6008", scenes.clone(), &categories),
+            scenes
+        );
+
+        let rules: Vec<SceneFilterRule> = ["*=-category:Other", "H6199=-Halloween"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(
+            filter_scenes(&rules, "H6199", scenes.clone(), &categories),
+            strings(&["Christmas", "Forest", "Ocean", "Sunset"])
+        );
+
+        assert_eq!(
+            group_by_category(&scenes, &categories),
+            vec![
+                (
+                    "Nature".to_string(),
+                    strings(&["Forest", "Ocean", "Sunset"])
+                ),
+                ("Festival".to_string(), strings(&["Christmas", "Halloween"])),
+                ("Other".to_string(), strings(&["Music: Energic"])),
+            ]
+        );
+
+        assert!("H6199".parse::<SceneFilterRule>().is_err());
+        assert!("H6199=".parse::<SceneFilterRule>().is_err());
+        assert!("=Sunset".parse::<SceneFilterRule>().is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Searching the scene catalogs of all of the devices at once.
+//! Govee publishes a separate scene catalog for each SKU, and
+//! similarly named scenes are offered across many of them, so
+//! this merges the catalogs by scene name to make it easier to
+//! find which devices can show eg: "Sunset".
+use crate::platform_api::DeviceType;
+use crate::service::device::Device;
+use crate::undoc_api::{GoveeUndocumentedApi, LightEffectCategory};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SceneQuery {
+    /// Matches scenes whose name contains this text,
+    /// ignoring case
+    pub keyword: Option<String>,
+    /// Matches scenes in categories whose name contains
+    /// this text, ignoring case
+    pub category: Option<String>,
+}
+
+impl SceneQuery {
+    fn matches(&self, category: &str, scene: &str) -> bool {
+        fn contains(haystack: &str, needle: &Option<String>) -> bool {
+            needle
+                .as_ref()
+                .map(|needle| {
+                    haystack
+                        .to_ascii_lowercase()
+                        .contains(&needle.to_ascii_lowercase())
+                })
+                .unwrap_or(true)
+        }
+
+        contains(scene, &self.keyword) && contains(category, &self.category)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SceneMatchDevice {
+    pub id: String,
+    pub sku: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SceneMatch {
+    pub name: String,
+    /// The categories under which the scene is listed;
+    /// these can vary by SKU
+    pub categories: Vec<String>,
+    pub devices: Vec<SceneMatchDevice>,
+}
+
+fn merge_catalog(
+    matches: &mut BTreeMap<String, SceneMatch>,
+    query: &SceneQuery,
+    catalog: &[LightEffectCategory],
+    device: &Device,
+) {
+    for category in catalog {
+        for scene in &category.scenes {
+            if !query.matches(&category.category_name, &scene.scene_name) {
+                continue;
+            }
+
+            let entry = matches
+                .entry(scene.scene_name.to_ascii_lowercase())
+                .or_insert_with(|| SceneMatch {
+                    name: scene.scene_name.to_string(),
+                    categories: vec![],
+                    devices: vec![],
+                });
+            if !entry.categories.contains(&category.category_name) {
+                entry.categories.push(category.category_name.to_string());
+            }
+            if !entry.devices.iter().any(|d| d.id == device.id) {
+                entry.devices.push(SceneMatchDevice {
+                    id: device.id.to_string(),
+                    sku: device.sku.to_string(),
+                    name: device.name(),
+                });
+            }
+        }
+    }
+}
+
+/// Search the scene catalogs of the supplied devices, returning
+/// the matching scenes ordered by name, along with the devices
+/// that support each of them. Only lights have scene catalogs,
+/// so the catalogs of other devices are not fetched.
+pub async fn search_scenes(
+    devices: &[Device],
+    query: &SceneQuery,
+) -> anyhow::Result<Vec<SceneMatch>> {
+    let mut catalog_by_sku = HashMap::new();
+    let mut matches = BTreeMap::new();
+
+    for device in devices
+        .iter()
+        .filter(|device| device.device_type() == DeviceType::Light)
+    {
+        if !catalog_by_sku.contains_key(&device.sku) {
+            let catalog = match GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await {
+                Ok(catalog) => catalog,
+                Err(err) => {
+                    log::warn!("Failed to fetch scenes for {}: {err:#}", device.sku);
+                    vec![]
+                }
+            };
+            catalog_by_sku.insert(device.sku.to_string(), catalog);
+        }
+
+        merge_catalog(&mut matches, query, &catalog_by_sku[&device.sku], device);
+    }
+
+    Ok(matches.into_values().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+    use crate::undoc_api::LightEffectLibraryResponse;
+
+    #[test]
+    fn search_catalog() {
+        let resp: LightEffectLibraryResponse = from_json(include_str!(
+            "../../test-data/light-effect-library-h6072.json"
+        ))
+        .unwrap();
+        let catalog = resp.data.categories;
+        let first = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        let second = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2B");
+
+        let scene = &catalog[0].scenes[0];
+        let query = SceneQuery {
+            keyword: Some(scene.scene_name.to_ascii_uppercase()),
+            category: None,
+        };
+
+        let mut matches = BTreeMap::new();
+        merge_catalog(&mut matches, &query, &catalog, &first);
+        merge_catalog(&mut matches, &query, &catalog, &second);
+
+        let found = matches
+            .get(&scene.scene_name.to_ascii_lowercase())
+            .expect("scene to be found");
+        assert!(found.categories.contains(&catalog[0].category_name));
+        assert_eq!(found.devices.len(), 2);
+
+        let query = SceneQuery {
+            keyword: None,
+            category: Some("no such category".to_string()),
+        };
+        let mut matches = BTreeMap::new();
+        merge_catalog(&mut matches, &query, &catalog, &first);
+        assert!(matches.is_empty());
+    }
+}
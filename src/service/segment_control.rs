@@ -0,0 +1,199 @@
+//! Segmented lights, such as the H6172 and H61A2 strips, can color
+//! each of their segments individually via ptReal packets, which can
+//! be sent over the LAN as well as via AWS IoT. Several segments are
+//! addressed by a single packet, so changes that share a color or a
+//! brightness are combined into one packet.
+//! A segment can also be given a white color temperature, which
+//! allows for gradients of white. It is sent as the approximately
+//! equivalent RGB color, as there is no dedicated packet for it.
+use crate::ble::{Base64HexBytes, SegmentMask, SetSegmentBrightness, SetSegmentColorRgb};
+use crate::lan_api::DeviceColor;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// A change to one segment, as accepted by the
+/// `gv2mqtt/light/<id>/segments/set` topic
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentChange {
+    pub segment: u32,
+    #[serde(default)]
+    pub color: Option<DeviceColor>,
+    /// A white color temperature, in place of `color`
+    #[serde(default)]
+    pub color_temp_kelvin: Option<u32>,
+    /// The brightness in percent (0-100)
+    #[serde(default)]
+    pub brightness: Option<u8>,
+}
+
+impl SegmentChange {
+    /// The color to set, with a color temperature
+    /// converted to its RGB equivalent
+    pub fn rgb(&self) -> Option<DeviceColor> {
+        self.color
+            .or_else(|| self.color_temp_kelvin.map(kelvin_to_rgb))
+    }
+}
+
+/// Approximates the color of a black body at the given temperature,
+/// using Tanner Helland's fit of the CIE 1964 color matching data.
+pub fn kelvin_to_rgb(kelvin: u32) -> DeviceColor {
+    let temp = kelvin.clamp(1000, 40000) as f64 / 100.;
+    let channel = |value: f64| value.round().clamp(0., 255.) as u8;
+
+    let r = if temp <= 66. {
+        255.
+    } else {
+        329.698727446 * (temp - 60.).powf(-0.1332047592)
+    };
+    let g = if temp <= 66. {
+        99.4708025861 * temp.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (temp - 60.).powf(-0.0755148492)
+    };
+    let b = if temp >= 66. {
+        255.
+    } else if temp <= 19. {
+        0.
+    } else {
+        138.5177312231 * (temp - 10.).ln() - 305.0447927307
+    };
+
+    DeviceColor {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+/// Returns the base64 encoded ptReal packets that apply the changes
+pub fn segment_commands(changes: &[SegmentChange]) -> anyhow::Result<Vec<String>> {
+    let mut by_color: BTreeMap<(u8, u8, u8), Vec<u32>> = BTreeMap::new();
+    let mut by_brightness: BTreeMap<u8, Vec<u32>> = BTreeMap::new();
+    for change in changes {
+        anyhow::ensure!(
+            change.color.is_some()
+                || change.color_temp_kelvin.is_some()
+                || change.brightness.is_some(),
+            "segment {} has neither a color nor a brightness",
+            change.segment
+        );
+        anyhow::ensure!(
+            change.color.is_none() || change.color_temp_kelvin.is_none(),
+            "segment {} has both a color and a color temperature",
+            change.segment
+        );
+        if let Some(DeviceColor { r, g, b }) = change.rgb() {
+            by_color.entry((r, g, b)).or_default().push(change.segment);
+        }
+        if let Some(brightness) = change.brightness {
+            anyhow::ensure!(
+                brightness <= 100,
+                "brightness {brightness} of segment {} is out of range",
+                change.segment
+            );
+            by_brightness
+                .entry(brightness)
+                .or_default()
+                .push(change.segment);
+        }
+    }
+
+    let mut commands = vec![];
+    for ((r, g, b), segments) in by_color {
+        commands.push(
+            Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetSegmentColorRgb {
+                    r,
+                    g,
+                    b,
+                    segments: SegmentMask::from_segments(segments)?,
+                },
+            )?
+            .base64(),
+        );
+    }
+    for (percent, segments) in by_brightness {
+        commands.push(
+            Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetSegmentBrightness {
+                    percent,
+                    segments: SegmentMask::from_segments(segments)?,
+                },
+            )?
+            .base64(),
+        );
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combine_changes() {
+        let changes: Vec<SegmentChange> = serde_json::from_str(
+            r#"[
+              {"segment": 0, "color": {"r": 255, "g": 0, "b": 0}},
+              {"segment": 3, "color": {"r": 255, "g": 0, "b": 0}, "brightness": 50},
+              {"segment": 4, "color": {"r": 0, "g": 0, "b": 255}},
+              {"segment": 5, "color_temp_kelvin": 2700},
+              {"segment": 6, "color_temp_kelvin": 2700}
+            ]"#,
+        )
+        .unwrap();
+        let commands = segment_commands(&changes).unwrap();
+        assert_eq!(commands.len(), 4);
+
+        let red = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentColorRgb {
+                r: 255,
+                g: 0,
+                b: 0,
+                segments: SegmentMask(0b1001),
+            },
+        )
+        .unwrap();
+        assert!(commands.contains(&red.base64()));
+
+        let warm_white = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetSegmentColorRgb {
+                r: 255,
+                g: 167,
+                b: 87,
+                segments: SegmentMask(0b110_0000),
+            },
+        )
+        .unwrap();
+        assert!(commands.contains(&warm_white.base64()));
+
+        let nothing = SegmentChange {
+            segment: 1,
+            color: None,
+            color_temp_kelvin: None,
+            brightness: None,
+        };
+        assert!(segment_commands(&[nothing]).is_err());
+        assert!(segment_commands(&[SegmentChange {
+            color: Some(DeviceColor::default()),
+            color_temp_kelvin: Some(4000),
+            ..nothing
+        }])
+        .is_err());
+        assert!(serde_json::from_str::<SegmentChange>(r#"{"segment": 1, "colour": {}}"#).is_err());
+    }
+
+    #[test]
+    fn color_temperature() {
+        let white = |r, g, b| DeviceColor { r, g, b };
+        assert_eq!(kelvin_to_rgb(2700), white(255, 167, 87));
+        assert_eq!(kelvin_to_rgb(6600), white(255, 255, 255));
+        assert_eq!(kelvin_to_rgb(9000), white(210, 223, 255));
+    }
+}
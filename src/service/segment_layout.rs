@@ -0,0 +1,119 @@
+//! Lights such as neon rope (eg: H61E1, H61D0) are often installed
+//! in shapes where the segment numbering used by the device doesn't
+//! follow the physical layout; for example, a rope that starts in
+//! the middle of a shelf and doubles back on itself.
+//! A segment layout maps the segments presented to hass, which are
+//! numbered left to right in the physical installation, to the
+//! segment indices used by the device, so that commands addressing
+//! "the left half" affect the physical left half.
+use anyhow::Context;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentLayout {
+    /// The id or name of the device
+    pub device: String,
+    /// The device segment index for each presented segment
+    pub order: Vec<u32>,
+}
+
+impl std::str::FromStr for SegmentLayout {
+    type Err = anyhow::Error;
+
+    /// Parses `Device=7-0,8-14`; the list of device segment indices
+    /// in their physical order, where `a-b` is an inclusive range
+    /// that may count down as well as up
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (device, spec) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected Device=0-7,..., got '{s}'"))?;
+        let device = device.trim();
+        anyhow::ensure!(!device.is_empty(), "missing device name in '{s}'");
+
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<u32>()
+                .with_context(|| format!("parsing segment '{n}' in '{s}'"))
+        };
+
+        let mut order = vec![];
+        for item in spec.split(',').filter(|item| !item.trim().is_empty()) {
+            match item.split_once('-') {
+                Some((a, b)) => {
+                    let (a, b) = (parse(a)?, parse(b)?);
+                    if a <= b {
+                        order.extend(a..=b);
+                    } else {
+                        order.extend((b..=a).rev());
+                    }
+                }
+                None => order.push(parse(item)?),
+            }
+        }
+        anyhow::ensure!(!order.is_empty(), "no segments listed in '{s}'");
+
+        let mut seen = order.clone();
+        seen.sort_unstable();
+        if let Some(dup) = seen.windows(2).find(|w| w[0] == w[1]) {
+            anyhow::bail!("segment {} is listed more than once in '{s}'", dup[0]);
+        }
+
+        Ok(Self {
+            device: device.to_string(),
+            order,
+        })
+    }
+}
+
+impl SegmentLayout {
+    /// Map a segment number, as presented to hass, to the index
+    /// of the segment used by the device
+    pub fn physical_segment(&self, segment: u32) -> u32 {
+        self.order.get(segment as usize).copied().unwrap_or(segment)
+    }
+
+    pub fn segment_range(&self) -> std::ops::Range<u32> {
+        0..self.order.len() as u32
+    }
+
+    /// Check that the layout only lists segments that the device has
+    pub fn validate(&self, device_range: &std::ops::Range<u32>) -> anyhow::Result<()> {
+        if let Some(segment) = self.order.iter().find(|s| !device_range.contains(s)) {
+            anyhow::bail!(
+                "the segment layout for {} lists segment {segment}, but the device \
+                 only has segments {}-{}",
+                self.device,
+                device_range.start,
+                device_range.end.saturating_sub(1)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_layout() {
+        let layout: SegmentLayout = "Shelf Rope=3-0, 4-6,9".parse().unwrap();
+        assert_eq!(layout.device, "Shelf Rope");
+        assert_eq!(layout.order, vec![3, 2, 1, 0, 4, 5, 6, 9]);
+        assert_eq!(layout.segment_range(), 0..8);
+        assert_eq!(layout.physical_segment(0), 3);
+        assert_eq!(layout.physical_segment(7), 9);
+        assert_eq!(layout.physical_segment(8), 8);
+
+        assert!("Shelf Rope".parse::<SegmentLayout>().is_err());
+        assert!("Shelf Rope=".parse::<SegmentLayout>().is_err());
+        assert!("Shelf Rope=0-3,2".parse::<SegmentLayout>().is_err());
+        assert!("=0-3".parse::<SegmentLayout>().is_err());
+    }
+
+    #[test]
+    fn validate_layout() {
+        let layout: SegmentLayout = "Shelf Rope=3-0,4-6,9".parse().unwrap();
+        assert!(layout.validate(&(0..10)).is_ok());
+        assert!(layout.validate(&(0..8)).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Very large installations can run several bridge instances that
+//! share the devices between them. Each instance is given a name,
+//! and announces itself via a retained availability message under
+//! `gv2mqtt/bridges/<name>/`, along with the devices that it has been
+//! configured to claim. The devices that no live bridge claims are
+//! assigned using rendezvous hashing over the names of the live
+//! bridges, so that only the devices of a bridge that comes or goes
+//! move to another bridge. Each bridge registers, polls and controls
+//! only its own devices; the global entities are handled by the
+//! leader, which is the live bridge whose name sorts first.
+use crate::service::device::Device;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+#[derive(Clone, Debug, Default)]
+pub struct ShardConfig {
+    /// The name of this bridge instance
+    pub instance: String,
+    /// The ids or names of the devices that this bridge claims
+    pub claims: Vec<String>,
+}
+
+#[derive(Default)]
+struct Membership {
+    config: Option<ShardConfig>,
+    /// The other bridges that are currently online
+    online: BTreeSet<String>,
+    /// The claims announced by the other bridges
+    claims: BTreeMap<String, Vec<String>>,
+}
+
+impl Membership {
+    /// The claims of each live bridge, including this one
+    fn members(&self) -> BTreeMap<String, Vec<String>> {
+        let mut members: BTreeMap<String, Vec<String>> = self
+            .online
+            .iter()
+            .map(|instance| {
+                (
+                    instance.to_string(),
+                    self.claims.get(instance).cloned().unwrap_or_default(),
+                )
+            })
+            .collect();
+        if let Some(config) = &self.config {
+            members.insert(config.instance.to_string(), config.claims.clone());
+        }
+        members
+    }
+}
+
+static MEMBERSHIP: Lazy<Mutex<Membership>> = Lazy::new(Default::default);
+
+/// Returned when asked to control a device that belongs to another bridge
+#[derive(Error, Debug)]
+#[error("{device} is handled by bridge {owner}")]
+pub struct NotOwnedByThisBridge {
+    device: String,
+    owner: String,
+}
+
+impl NotOwnedByThisBridge {
+    pub fn is_not_owned(err: &anyhow::Error) -> bool {
+        err.root_cause().downcast_ref::<Self>().is_some()
+    }
+}
+
+pub fn set_shard_config(config: Option<ShardConfig>) {
+    MEMBERSHIP.lock().config = config;
+}
+
+pub fn shard_config() -> Option<ShardConfig> {
+    MEMBERSHIP.lock().config.clone()
+}
+
+pub fn is_sharded() -> bool {
+    MEMBERSHIP.lock().config.is_some()
+}
+
+pub fn bridge_availability_topic(instance: &str) -> String {
+    format!("gv2mqtt/bridges/{instance}/availability")
+}
+
+pub fn bridge_claims_topic(instance: &str) -> String {
+    format!("gv2mqtt/bridges/{instance}/claims")
+}
+
+/// Record the availability of another bridge.
+/// Returns true if the set of live bridges changed.
+pub fn update_bridge_availability(instance: &str, online: bool) -> bool {
+    let mut membership = MEMBERSHIP.lock();
+    if membership
+        .config
+        .as_ref()
+        .map(|config| config.instance == instance)
+        .unwrap_or(false)
+    {
+        return false;
+    }
+    if online {
+        membership.online.insert(instance.to_string())
+    } else {
+        membership.online.remove(instance)
+    }
+}
+
+/// Record the claims announced by another bridge.
+/// Returns true if they changed.
+pub fn update_bridge_claims(instance: &str, claims: Vec<String>) -> bool {
+    let mut membership = MEMBERSHIP.lock();
+    membership
+        .claims
+        .insert(instance.to_string(), claims.clone())
+        != Some(claims)
+}
+
+/// A stable hash, so that every bridge computes the same
+/// assignment, even when they are running different versions
+fn rendezvous_score(instance: &str, device_id: &str) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in instance
+        .bytes()
+        .chain(std::iter::once(0))
+        .chain(device_id.bytes())
+    {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Determine which of the `members` owns a device
+fn owner_of<'a>(
+    members: &'a BTreeMap<String, Vec<String>>,
+    device_id: &str,
+    device_name: &str,
+) -> Option<&'a str> {
+    let claimant = members.iter().find(|(_, claims)| {
+        claims.iter().any(|claim| {
+            claim.eq_ignore_ascii_case(device_id) || claim.eq_ignore_ascii_case(device_name)
+        })
+    });
+    if let Some((instance, _)) = claimant {
+        return Some(instance);
+    }
+    members
+        .keys()
+        .max_by_key(|instance| rendezvous_score(instance, device_id))
+        .map(|instance| instance.as_str())
+}
+
+/// Returns the name of the bridge that owns the device, if
+/// it isn't this one
+pub fn other_owner(device: &Device) -> Option<String> {
+    let membership = MEMBERSHIP.lock();
+    let config = membership.config.as_ref()?;
+    let members = membership.members();
+    let owner = owner_of(&members, &device.id, &device.name())?;
+    (owner != config.instance).then(|| owner.to_string())
+}
+
+/// Whether this bridge is responsible for the device.
+/// Always true when sharding is not enabled.
+pub fn owns_device(device: &Device) -> bool {
+    other_owner(device).is_none()
+}
+
+/// Fail with NotOwnedByThisBridge if another bridge owns the device
+pub fn ensure_owned(device: &Device) -> anyhow::Result<()> {
+    match other_owner(device) {
+        Some(owner) => Err(NotOwnedByThisBridge {
+            device: device.to_string(),
+            owner,
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Whether this bridge is responsible for the global entities
+/// and commands. Always true when sharding is not enabled.
+pub fn is_leader() -> bool {
+    let membership = MEMBERSHIP.lock();
+    match &membership.config {
+        Some(config) => membership
+            .members()
+            .keys()
+            .next()
+            .map(|leader| *leader == config.instance)
+            .unwrap_or(true),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn members(names: &[&str]) -> BTreeMap<String, Vec<String>> {
+        names.iter().map(|n| (n.to_string(), vec![])).collect()
+    }
+
+    #[test]
+    fn assignment() {
+        let ids: Vec<String> = (0..300)
+            .map(|n| format!("AA:BB:CC:DD:EE:FF:{:02X}:{:02X}", n / 256, n % 256))
+            .collect();
+
+        let three = members(&["a", "b", "c"]);
+        let mut counts = BTreeMap::new();
+        for id in &ids {
+            *counts.entry(owner_of(&three, id, "").unwrap()).or_insert(0) += 1;
+        }
+        // Each bridge gets a reasonable share
+        for count in counts.values() {
+            assert!(*count > 60, "{counts:?}");
+        }
+
+        // When a bridge leaves, only its devices move
+        let two = members(&["a", "c"]);
+        for id in &ids {
+            let before = owner_of(&three, id, "").unwrap();
+            let after = owner_of(&two, id, "").unwrap();
+            if before != "b" {
+                assert_eq!(before, after);
+            }
+        }
+
+        // Claims take precedence over the hash
+        let mut claimed = three.clone();
+        claimed.insert("b".to_string(), vec!["Porch Light".to_string()]);
+        for id in &ids {
+            assert_eq!(owner_of(&claimed, id, "porch light"), Some("b"));
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Detects when the async runtime has stalled, which happens when
+//! a task performs blocking work rather than awaiting it. A task on
+//! the runtime records a heartbeat a few times per second, and a
+//! plain thread, which keeps running even if every worker of the
+//! runtime is blocked, checks that the heartbeat keeps advancing.
+//!
+//! Network operations register themselves while they are in flight,
+//! so that a stall can be attributed to the operations that were
+//! running at the time.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often the heartbeat is recorded
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A heartbeat that is late by more than this is reported as a stall
+const STALL_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// How many of the longest running operations to report
+const MAX_REPORTED_OPERATIONS: usize = 5;
+
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+static HEARTBEAT_MS: AtomicU64 = AtomicU64::new(0);
+static NEXT_OPERATION: AtomicU64 = AtomicU64::new(0);
+static OPERATIONS: Lazy<Mutex<BTreeMap<u64, (String, Instant)>>> = Lazy::new(Default::default);
+
+fn now_ms() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// Registers an operation for the lifetime of the guard
+pub struct OperationGuard(u64);
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        OPERATIONS.lock().remove(&self.0);
+    }
+}
+
+pub fn track_operation(name: impl Into<String>) -> OperationGuard {
+    let id = NEXT_OPERATION.fetch_add(1, Ordering::Relaxed);
+    OPERATIONS.lock().insert(id, (name.into(), Instant::now()));
+    OperationGuard(id)
+}
+
+/// Run a network operation with a timeout, tracking it
+/// while it is in flight
+pub async fn with_timeout<T, F>(name: &str, duration: Duration, future: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let _guard = track_operation(name);
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| anyhow::anyhow!("{name} timed out after {duration:?}"))?
+}
+
+/// Describes the in-flight operations, longest running first
+fn describe_operations(now: Instant) -> Vec<String> {
+    let mut operations: Vec<(Duration, String)> = OPERATIONS
+        .lock()
+        .values()
+        .map(|(name, started)| (now.saturating_duration_since(*started), name.to_string()))
+        .collect();
+    operations.sort_by_key(|(elapsed, _)| std::cmp::Reverse(*elapsed));
+    operations
+        .into_iter()
+        .take(MAX_REPORTED_OPERATIONS)
+        .map(|(elapsed, name)| format!("{name} (running for {elapsed:.1?})"))
+        .collect()
+}
+
+/// How late the heartbeat is, if it is late enough to be a stall
+fn stalled_for(heartbeat_ms: u64, now_ms: u64) -> Option<Duration> {
+    let late = Duration::from_millis(now_ms.saturating_sub(heartbeat_ms));
+    (late > STALL_THRESHOLD + HEARTBEAT_INTERVAL).then_some(late)
+}
+
+/// Start the heartbeat task and the thread that watches it
+pub fn spawn_stall_detector() {
+    HEARTBEAT_MS.store(now_ms(), Ordering::SeqCst);
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            HEARTBEAT_MS.store(now_ms(), Ordering::SeqCst);
+        }
+    });
+
+    let spawned = std::thread::Builder::new()
+        .name("stall-detector".to_string())
+        .spawn(|| {
+            let mut reported = false;
+            loop {
+                std::thread::sleep(Duration::from_secs(1));
+                match stalled_for(HEARTBEAT_MS.load(Ordering::SeqCst), now_ms()) {
+                    Some(late) if !reported => {
+                        let operations = describe_operations(Instant::now());
+                        log::warn!(
+                            "The runtime has been unresponsive for {late:.1?}; \
+                            this is a bug. In-flight operations: {}",
+                            if operations.is_empty() {
+                                "none".to_string()
+                            } else {
+                                operations.join(", ")
+                            }
+                        );
+                        reported = true;
+                    }
+                    Some(_) => {}
+                    None if reported => {
+                        log::warn!("The runtime is responsive again");
+                        reported = false;
+                    }
+                    None => {}
+                }
+            }
+        });
+    if let Err(err) = spawned {
+        log::warn!("Failed to start the stall detector: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stalls_and_operations() {
+        assert_eq!(stalled_for(1000, 1500), None);
+        assert_eq!(stalled_for(1000, 3000), None);
+        assert_eq!(stalled_for(1000, 4000), Some(Duration::from_secs(3)));
+        assert_eq!(stalled_for(4000, 1000), None);
+
+        let now = Instant::now();
+        let _fast = track_operation("test: fast");
+        let slow = track_operation("test: slow");
+        OPERATIONS.lock().get_mut(&slow.0).unwrap().1 -= Duration::from_secs(30);
+        let described: Vec<String> = describe_operations(now + Duration::from_secs(1))
+            .into_iter()
+            .filter(|op| op.starts_with("test:"))
+            .collect();
+        assert_eq!(described.len(), 2);
+        assert!(described[0].starts_with("test: slow (running for 31"));
+
+        drop(slow);
+        assert!(!describe_operations(now)
+            .iter()
+            .any(|op| op.starts_with("test: slow")));
+    }
+}
@@ -1,15 +1,46 @@
-use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams};
-use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
-use crate::platform_api::{DeviceCapability, GoveeApiClient};
+#[cfg(feature = "ble")]
+use crate::ble::advertisement::BleAdvertisement;
+use crate::ble::{
+    Base64HexBytes, BigEndianU16, SetBrightness, SetColorRgb, SetColorTemperature,
+    SetHumidifierMode, SetHumidifierNightlightParams,
+};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::lan_api::{
+    scene_packet_by_name, Client as LanClient, DeviceColor, DeviceStatus as LanDeviceStatus,
+    LanDevice,
+};
+#[cfg(not(feature = "cloud"))]
+use crate::local_only::IotClient;
+use crate::platform_api::{DeviceCapability, GoveeApiClient, HttpDeviceInfo};
+use crate::service::brightness::BrightnessScale;
+use crate::service::cloud_failure::note_command_device;
+use crate::service::color_preset::{presets_for_device, ColorPreset, PresetColor};
+use crate::service::command_queue::{command_queue_config, CommandTicket};
+use crate::service::contract::publish_contract_state;
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
-use crate::service::hass::{topic_safe_id, HassClient};
+use crate::service::derived::DerivedSensorDefinition;
+use crate::service::device::{normalize_device_id, Device, RestorableState, SceneSource};
+use crate::service::device_graph::DeviceGraph;
+use crate::service::event_log::record_state_change;
+use crate::service::hass::{topic_safe_id, BirthMessage, HassClient};
+#[cfg(feature = "cloud")]
 use crate::service::iot::IotClient;
+use crate::service::playlist::{Playlist, PlaylistDefinition};
+use crate::service::scene_filter::{
+    filter_scenes, group_by_category, SceneCategories, SceneFilterRule,
+};
+use crate::service::segment_control::{segment_commands, SegmentChange};
+use crate::service::segment_layout::SegmentLayout;
+use crate::service::shard::{ensure_owned, owns_device};
+use crate::service::state_merge::Transport;
+use crate::service::state_snapshot::mark_state_changed;
+use crate::service::supervisor::Supervisor;
+use crate::service::transport_health::{record_outcome, transport_order};
 use crate::temperature::{TemperatureScale, TemperatureValue};
-use crate::undoc_api::GoveeUndocumentedApi;
+use crate::undoc_api::{GoveeUndocumentedApi, ParsedFeast, ParsedOneClick};
 use anyhow::Context;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
@@ -23,18 +54,57 @@ pub struct State {
     platform_client: Mutex<Option<GoveeApiClient>>,
     undoc_client: Mutex<Option<GoveeUndocumentedApi>>,
     iot_client: Mutex<Option<IotClient>>,
+    /// The clients for additional accounts, keyed by account email
+    accounts: Mutex<HashMap<String, AccountClients>>,
     hass_client: Mutex<Option<HassClient>>,
     hass_discovery_prefix: Mutex<String>,
+    hass_birth_message: Mutex<BirthMessage>,
     temperature_scale: Mutex<TemperatureScale>,
+    scene_icon_base_url: Mutex<Option<String>>,
+    restore_after_power_loss: Mutex<Vec<String>>,
+    segment_layouts: Mutex<Vec<SegmentLayout>>,
+    color_presets: Mutex<Vec<ColorPreset>>,
+    derived_sensors: Mutex<Vec<DerivedSensorDefinition>>,
+    rate_of_change_window: Mutex<Option<Duration>>,
+    playlists: Mutex<Vec<Playlist>>,
+    /// The devices whose full scene list has been requested
+    full_scene_lists: Mutex<HashSet<String>>,
+    scene_filters: Mutex<Vec<SceneFilterRule>>,
+    scene_select_per_category: Mutex<bool>,
+    /// The addresses of the BLE sensors outside of the Govee account
+    /// whose advertisements are accepted
+    #[cfg(feature = "ble")]
+    ble_sensors: Mutex<HashSet<String>>,
+    supervisor: Supervisor,
 }
 
 pub type StateHandle = Arc<State>;
 
+/// The means to control a device via one of the transports
+enum TransportHandle<'a> {
+    Lan(&'a LanDevice),
+    Iot(IotClient, &'a crate::undoc_api::DeviceEntry),
+    Platform(GoveeApiClient, &'a HttpDeviceInfo),
+}
+
+/// The API clients for an additional Govee account
+#[derive(Default, Clone)]
+pub struct AccountClients {
+    pub platform: Option<GoveeApiClient>,
+    pub undoc: Option<GoveeUndocumentedApi>,
+    pub iot: Option<IotClient>,
+}
+
 impl State {
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// The supervisor of the background tasks of this bridge
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
     pub async fn set_temperature_scale(&self, scale: TemperatureScale) {
         *self.temperature_scale.lock().await = scale;
     }
@@ -43,6 +113,134 @@ impl State {
         *self.temperature_scale.lock().await
     }
 
+    pub async fn set_scene_icon_base_url(&self, url: Option<String>) {
+        *self.scene_icon_base_url.lock().await = url;
+    }
+
+    pub async fn get_scene_icon_base_url(&self) -> Option<String> {
+        self.scene_icon_base_url.lock().await.clone()
+    }
+
+    pub async fn set_derived_sensors(&self, sensors: Vec<DerivedSensorDefinition>) {
+        *self.derived_sensors.lock().await = sensors;
+    }
+
+    pub async fn get_derived_sensors(&self) -> Vec<DerivedSensorDefinition> {
+        self.derived_sensors.lock().await.clone()
+    }
+
+    pub async fn set_rate_of_change_window(&self, window: Option<Duration>) {
+        *self.rate_of_change_window.lock().await = window;
+    }
+
+    pub async fn get_rate_of_change_window(&self) -> Option<Duration> {
+        *self.rate_of_change_window.lock().await
+    }
+
+    /// Replace the playlists, stopping any that are playing
+    pub async fn set_playlists(&self, definitions: Vec<PlaylistDefinition>) {
+        *self.playlists.lock().await = definitions.into_iter().map(Playlist::new).collect();
+    }
+
+    pub async fn playlists_mut(&self) -> MutexGuard<'_, Vec<Playlist>> {
+        self.playlists.lock().await
+    }
+
+    pub async fn get_playlists(&self) -> Vec<Playlist> {
+        self.playlists.lock().await.clone()
+    }
+
+    pub async fn set_restore_after_power_loss(&self, devices: Vec<String>) {
+        *self.restore_after_power_loss.lock().await = devices;
+    }
+
+    /// Returns true if the user opted in to restoring the state
+    /// of this device after it has lost power
+    pub async fn wants_restore_after_power_loss(&self, device: &Device) -> bool {
+        self.restore_after_power_loss
+            .lock()
+            .await
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(&device.id) || d.eq_ignore_ascii_case(&device.name()))
+    }
+
+    pub async fn set_segment_layouts(&self, layouts: Vec<SegmentLayout>) {
+        *self.segment_layouts.lock().await = layouts;
+    }
+
+    /// Returns the user-defined segment layout for the device, if any.
+    /// A layout that lists segments that the device doesn't have is
+    /// ignored, so that those indices are never sent to the device.
+    pub async fn segment_layout(&self, device: &Device) -> Option<SegmentLayout> {
+        let layout = self
+            .segment_layouts
+            .lock()
+            .await
+            .iter()
+            .find(|layout| {
+                layout.device.eq_ignore_ascii_case(&device.id)
+                    || layout.device.eq_ignore_ascii_case(&device.name())
+            })
+            .cloned()?;
+        let range = device.segment_range()?;
+        match layout.validate(&range) {
+            Ok(()) => Some(layout),
+            Err(err) => {
+                log::warn!("Ignoring segment layout for {device}: {err:#}");
+                None
+            }
+        }
+    }
+
+    pub async fn set_color_presets(&self, presets: Vec<ColorPreset>) {
+        *self.color_presets.lock().await = presets;
+    }
+
+    /// Returns the color presets that apply to the device
+    pub async fn color_presets(&self, device: &Device) -> Vec<ColorPreset> {
+        presets_for_device(&self.color_presets.lock().await, device)
+    }
+
+    pub async fn set_scene_filters(&self, filters: Vec<SceneFilterRule>) {
+        *self.scene_filters.lock().await = filters;
+    }
+
+    pub async fn set_scene_select_per_category(&self, enabled: bool) {
+        *self.scene_select_per_category.lock().await = enabled;
+    }
+
+    pub async fn scene_select_per_category(&self) -> bool {
+        *self.scene_select_per_category.lock().await
+    }
+
+    #[cfg(feature = "ble")]
+    pub async fn set_ble_sensors(&self, addresses: Vec<String>) {
+        *self.ble_sensors.lock().await = addresses
+            .iter()
+            .map(|address| normalize_device_id(address))
+            .collect();
+    }
+
+    /// Map a segment number, as presented to hass, to the index of
+    /// the segment used by the device, taking into account any
+    /// user-defined segment layout
+    pub async fn physical_segment(&self, device: &Device, segment: u32) -> u32 {
+        match self.segment_layout(device).await {
+            Some(layout) => layout.physical_segment(segment),
+            None => segment,
+        }
+    }
+
+    /// Returns the range of segment numbers to present to hass,
+    /// if the device supports segment control
+    pub async fn segment_range(&self, device: &Device) -> Option<std::ops::Range<u32>> {
+        let range = device.segment_range()?;
+        match self.segment_layout(device).await {
+            Some(layout) => Some(layout.segment_range()),
+            None => Some(range),
+        }
+    }
+
     pub async fn set_hass_disco_prefix(&self, prefix: String) {
         *self.hass_discovery_prefix.lock().await = prefix;
     }
@@ -51,13 +249,23 @@ impl State {
         self.hass_discovery_prefix.lock().await.to_string()
     }
 
+    pub async fn set_hass_birth_message(&self, birth: BirthMessage) {
+        *self.hass_birth_message.lock().await = birth;
+    }
+
+    pub async fn get_hass_birth_message(&self) -> BirthMessage {
+        self.hass_birth_message.lock().await.clone()
+    }
+
     /// Returns a mutable version of the specified device, creating
-    /// an entry for it if necessary.
-    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<Device> {
+    /// an entry for it if necessary. The registry is keyed by the
+    /// normalized id, so that the different forms of the id that
+    /// the various APIs report all refer to the same entry.
+    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<'_, Device> {
         let devices = self.devices_by_id.lock().await;
         MutexGuard::map(devices, |devices| {
             devices
-                .entry(id.to_string())
+                .entry(normalize_device_id(id))
                 .or_insert_with(|| Device::new(sku, id))
         })
     }
@@ -69,7 +277,7 @@ impl State {
     /// Returns an immutable copy of the specified Device
     pub async fn device_by_id(&self, id: &str) -> Option<Device> {
         let devices = self.devices_by_id.lock().await;
-        devices.get(id).cloned()
+        devices.get(&normalize_device_id(id)).cloned()
     }
 
     async fn semaphore_for_device(&self, device: &Device) -> Arc<Semaphore> {
@@ -101,6 +309,8 @@ impl State {
             .resolve_device(label)
             .await
             .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
+        ensure_owned(&device)?;
+        note_command_device(&device.id);
         let semaphore = self.semaphore_for_device(&device).await;
         let permit = semaphore.acquire_owned().await?;
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -118,13 +328,45 @@ impl State {
         Ok(Coordinator::new(device, permit, tx))
     }
 
+    /// Like resolve_device_for_control, but for a command that replaces
+    /// the value of `capability`. Returns None if the command was
+    /// superseded by a newer command for the same capability while
+    /// it was waiting to be processed; the caller should then drop it.
+    pub async fn resolve_device_for_coalesced_control(
+        self: &Arc<Self>,
+        label: &str,
+        capability: &str,
+    ) -> anyhow::Result<Option<Coordinator>> {
+        let device = self
+            .resolve_device(label)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
+        let ticket = CommandTicket::new(&device.id, capability);
+
+        let window = command_queue_config().window;
+        if !window.is_zero() {
+            tokio::time::sleep(window).await;
+        }
+        if ticket.is_superseded() {
+            log::debug!("Dropping {capability} command for {device}; a newer one arrived");
+            return Ok(None);
+        }
+
+        let coordinator = self.resolve_device_for_control(label).await?;
+        if ticket.is_superseded() {
+            log::debug!("Dropping {capability} command for {device}; a newer one arrived");
+            return Ok(None);
+        }
+        Ok(Some(coordinator))
+    }
+
     /// Resolve a device using its name, computed name, id or label,
     /// ignoring case.
     pub async fn resolve_device(&self, label: &str) -> Option<Device> {
         let devices = self.devices_by_id.lock().await;
 
         // Try by id first
-        if let Some(device) = devices.get(label) {
+        if let Some(device) = devices.get(&normalize_device_id(label)) {
             return Some(device.clone());
         }
 
@@ -185,8 +427,180 @@ impl State {
         self.undoc_client.lock().await.clone()
     }
 
+    pub async fn set_account_clients(&self, email: &str, clients: AccountClients) {
+        self.accounts
+            .lock()
+            .await
+            .insert(email.to_string(), clients);
+    }
+
+    /// Returns the clients for each of the additional accounts
+    pub async fn get_account_clients(&self) -> Vec<(String, AccountClients)> {
+        self.accounts
+            .lock()
+            .await
+            .iter()
+            .map(|(email, clients)| (email.clone(), clients.clone()))
+            .collect()
+    }
+
+    /// Returns the IoT client for the account that the device belongs to
+    pub async fn get_iot_client_for(&self, device: &Device) -> Option<IotClient> {
+        match &device.account {
+            Some(email) => self.accounts.lock().await.get(email)?.iot.clone(),
+            None => self.get_iot_client().await,
+        }
+    }
+
+    /// Returns the undocumented API client for the account that the
+    /// device belongs to
+    pub async fn get_undoc_client_for(&self, device: &Device) -> Option<GoveeUndocumentedApi> {
+        match &device.account {
+            Some(email) => self.accounts.lock().await.get(email)?.undoc.clone(),
+            None => self.get_undoc_client().await,
+        }
+    }
+
+    /// Returns the Platform API client for the account that the
+    /// device belongs to
+    pub async fn get_platform_client_for(&self, device: &Device) -> Option<GoveeApiClient> {
+        match &device.account {
+            Some(email) => self.accounts.lock().await.get(email)?.platform.clone(),
+            None => self.get_platform_client().await,
+        }
+    }
+
+    /// Returns the list of One-Click shortcuts, with their entries
+    /// resolved against the known devices.
+    /// Entries for devices that we don't know about are omitted,
+    /// as are shortcuts that don't have any remaining entries.
+    pub async fn resolve_one_clicks(&self) -> anyhow::Result<Vec<ParsedOneClick>> {
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let mut items = undoc.parse_one_clicks().await?;
+
+        for item in &mut items {
+            let mut entries = vec![];
+            for mut entry in item.entries.drain(..) {
+                match self.device_by_id(&entry.device).await {
+                    Some(device) => {
+                        entry.name = device.name();
+                        entry.sku = device.sku.clone();
+                        entries.push(entry);
+                    }
+                    None => {
+                        log::debug!(
+                            "{}: skipping entry for unknown device {} {}",
+                            item.name,
+                            entry.sku,
+                            entry.device
+                        );
+                    }
+                }
+            }
+            item.entries = entries;
+        }
+
+        items.retain(|item| !item.entries.is_empty());
+        Ok(items)
+    }
+
+    /// Resolve a feast (linked device group) by its id
+    pub async fn resolve_feast(&self, id: &str) -> anyhow::Result<ParsedFeast> {
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        undoc
+            .parse_feasts()
+            .await?
+            .into_iter()
+            .find(|feast| feast.id.to_string() == id)
+            .ok_or_else(|| anyhow::anyhow!("feast '{id}' not found"))
+    }
+
+    /// Record a reading decoded from the advertisement of a BLE-only
+    /// sensor. The sensor is matched to a device from the Govee app by
+    /// its address, which forms the tail of the device id. Sensors that
+    /// aren't in the account are ignored unless their address has been
+    /// listed via `--ble-sensor`, so that sensors belonging to the
+    /// neighbours don't show up in Home Assistant.
+    #[cfg(feature = "ble")]
+    pub async fn record_ble_advertisement(
+        self: &Arc<Self>,
+        address: &str,
+        advert: BleAdvertisement,
+    ) -> anyhow::Result<()> {
+        let address = normalize_device_id(address);
+        let known = self
+            .devices_by_id
+            .lock()
+            .await
+            .keys()
+            .find(|id| id.ends_with(&address))
+            .cloned();
+        let id = match known {
+            Some(id) => id,
+            None if self.ble_sensors.lock().await.contains(&address) => address,
+            None => {
+                log::trace!(
+                    "BLE: ignoring {} {address}, which isn't a known sensor",
+                    advert.sku
+                );
+                return Ok(());
+            }
+        };
+
+        let (first, changed) = {
+            let mut device = self.device_mut(&advert.sku, &id).await;
+            let changed = device.ble_sensor_reading != Some(advert.reading);
+            (device.set_ble_sensor_reading(advert.reading), changed)
+        };
+
+        if first {
+            log::info!("BLE: found {} {id}", advert.sku);
+            if let Some(hass) = self.get_hass_client().await {
+                if let Some(device) = self.device_by_id(&id).await {
+                    hass.register_device(&device, self).await?;
+                }
+            }
+        }
+        if changed {
+            self.notify_of_state_change(&id).await?;
+        }
+        Ok(())
+    }
+
+    /// Build the graph of outlet and feast relationships
+    /// between the known devices
+    pub async fn device_graph(&self) -> DeviceGraph {
+        let mut feasts = vec![];
+        if let Some(undoc) = self.get_undoc_client().await {
+            match undoc.parse_feasts().await {
+                Ok(f) => feasts = f,
+                Err(err) => log::warn!("Failed to list feasts for the device graph: {err:#}"),
+            }
+        }
+        DeviceGraph::build(&self.devices().await, &feasts)
+    }
+
+    /// Request the current state of a device using the
+    /// most appropriate available transport
+    pub async fn refresh_device_state(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        if let Some(lan_dev) = &device.lan_device {
+            return self.measure_lan_rtt(lan_dev).await;
+        }
+        if device.pollable_via_iot() && self.poll_iot_api(device).await? {
+            return Ok(());
+        }
+        self.poll_platform_api(device).await?;
+        Ok(())
+    }
+
     pub async fn poll_iot_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(iot) = self.get_iot_client().await {
+        if let Some(iot) = self.get_iot_client_for(device).await {
             if let Some(info) = device.undoc_device_info.clone() {
                 if iot.is_device_compatible(&info.entry) {
                     let device_state = device.device_state();
@@ -204,9 +618,9 @@ impl State {
                             // However, if the device is offline, nothing will change our state.
                             // Let's explicitly mark the device as having been polled so that
                             // we don't keep sending a request every minute.
-                            self.device_mut(&device.sku, &device.id)
-                                .await
-                                .set_last_polled();
+                            let mut device = self.device_mut(&device.sku, &device.id).await;
+                            device.set_last_polled();
+                            device.set_iot_status_requested();
 
                             return Ok(true);
                         }
@@ -217,8 +631,27 @@ impl State {
         Ok(false)
     }
 
+    /// Query the LAN API status of a device, recording the round
+    /// trip time as a measure of the quality of its connection
+    pub async fn measure_lan_rtt(self: &Arc<Self>, device: &LanDevice) -> anyhow::Result<()> {
+        let client = self
+            .get_lan_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("no lan client"))?;
+        let started = Instant::now();
+        let status = client.query_status(device).await?;
+        let rtt = started.elapsed();
+        log::trace!("LAN RTT for {} is {rtt:?}", device.device);
+        {
+            let mut dev = self.device_mut(&device.sku, &device.device).await;
+            dev.set_lan_device_status(status);
+            dev.record_lan_rtt(rtt);
+        }
+        self.notify_of_state_change(&device.device).await
+    }
+
     pub async fn poll_platform_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             let device_state = device.device_state();
             log::info!("requesting update via Platform API {device} {device_state:?}");
             if let Some(info) = &device.http_device_info {
@@ -228,14 +661,35 @@ impl State {
                     .context("get_device_state")?;
                 log::trace!("updated state for {device}");
 
+                let reported_scene = match http_state
+                    .capability_by_instance("lightScene")
+                    .and_then(|cap| cap.state.pointer("/value"))
                 {
+                    Some(value) => client
+                        .scene_name_for_value(info, value)
+                        .await
+                        .unwrap_or_else(|err| {
+                            log::debug!("Unable to resolve the scene of {device}: {err:#}");
+                            None
+                        }),
+                    None => None,
+                };
+
+                let power_restored = {
                     let mut device = self.device_mut(&device.sku, &device.id).await;
-                    device.set_http_device_state(http_state);
+                    if let Some(scene) = &reported_scene {
+                        device.record_reported_scene(scene);
+                    }
+                    let power_restored = device.set_http_device_state(http_state);
                     device.set_last_polled();
-                }
+                    power_restored
+                };
                 self.notify_of_state_change(&device.id)
                     .await
                     .context("state.notify_of_state_change")?;
+                if power_restored {
+                    self.handle_power_restored(device).await?;
+                }
                 return Ok(true);
             }
         } else {
@@ -246,32 +700,168 @@ impl State {
         Ok(false)
     }
 
+    /// Called when any of the APIs observe that the device is back
+    /// online after having been offline, which typically means that
+    /// it has just had its power restored
+    pub async fn handle_power_restored(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        if self.wants_restore_after_power_loss(device).await {
+            self.restore_after_power_loss(device).await?;
+        }
+        Ok(())
+    }
+
+    /// Re-apply the state that the device had prior to losing power
+    async fn restore_after_power_loss(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        let Some(device) = self.device_by_id(&device.id).await else {
+            return Ok(());
+        };
+        let Some(prior) = device.restorable_state.clone() else {
+            log::info!("{device} is back online, but there is no prior state to restore");
+            return Ok(());
+        };
+        log::info!("{device} is back online; restoring {prior:?}");
+        self.restore_light_state(&device, &prior).await
+    }
+
+    /// Re-apply a previously captured light state
+    pub async fn restore_light_state(
+        self: &Arc<Self>,
+        device: &Device,
+        prior: &RestorableState,
+    ) -> anyhow::Result<()> {
+        if !prior.on {
+            return self.device_power_on(device, false).await;
+        }
+
+        if let Some(scene) = &prior.scene {
+            self.device_set_scene(device, scene).await?;
+        } else if prior.kelvin != 0 {
+            self.device_set_color_temperature(device, prior.kelvin)
+                .await?;
+        } else {
+            let DeviceColor { r, g, b } = prior.color;
+            self.device_set_color_rgb(device, r, g, b).await?;
+        }
+        self.device_set_brightness(device, prior.brightness).await
+    }
+
+    /// Poll the device until its status is accepted by `acceptor`,
+    /// for up to 5 seconds. Returns true if the status was accepted.
     async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
         self: &Arc<Self>,
         device: &LanDevice,
         acceptor: F,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         match self.get_lan_client().await {
             Some(client) => {
                 let deadline = Instant::now() + Duration::from_secs(5);
+                let mut accepted = false;
                 while Instant::now() <= deadline {
+                    let started = Instant::now();
                     let status = client.query_status(device).await?;
-                    let accepted = (acceptor)(&status);
-                    self.device_mut(&device.sku, &device.device)
-                        .await
-                        .set_lan_device_status(status);
+                    let rtt = started.elapsed();
+                    accepted = (acceptor)(&status);
+                    let mut dev = self.device_mut(&device.sku, &device.device).await;
+                    dev.set_lan_device_status(status);
+                    dev.record_lan_rtt(rtt);
+                    drop(dev);
                     if accepted {
                         break;
                     }
                     sleep(Duration::from_millis(100)).await;
                 }
                 self.notify_of_state_change(&device.device).await?;
-                Ok(())
+                Ok(accepted)
             }
             None => anyhow::bail!("no lan client"),
         }
     }
 
+    /// As poll_lan_api, for use once a command has been sent via the
+    /// LAN API. Since the command was sent, a failure to see the change
+    /// is logged rather than returned, as failing would cause the same
+    /// command to be sent again via another transport.
+    async fn confirm_via_lan<F: Fn(&LanDeviceStatus) -> bool>(
+        self: &Arc<Self>,
+        device: &LanDevice,
+        acceptor: F,
+    ) {
+        match self.poll_lan_api(device, acceptor).await {
+            Ok(true) => {}
+            Ok(false) => log::warn!(
+                "{} didn't confirm the change via the LAN API",
+                device.device
+            ),
+            Err(err) => log::warn!(
+                "Failed to confirm the change to {} via the LAN API: {err:#}",
+                device.device
+            ),
+        }
+    }
+
+    /// Returns the means to control the device via `transport`,
+    /// or None if that transport isn't available for it
+    async fn transport_handle<'a>(
+        &self,
+        device: &'a Device,
+        transport: Transport,
+    ) -> Option<TransportHandle<'a>> {
+        match transport {
+            Transport::Lan => device.preferred_lan_device().map(TransportHandle::Lan),
+            Transport::Iot => {
+                if !device.iot_api_supported() {
+                    return None;
+                }
+                let iot = self.get_iot_client_for(device).await?;
+                let info = device.undoc_device_info.as_ref()?;
+                Some(TransportHandle::Iot(iot, &info.entry))
+            }
+            Transport::Platform => {
+                let client = self.get_platform_client_for(device).await?;
+                let info = device.http_device_info.as_ref()?;
+                Some(TransportHandle::Platform(client, info))
+            }
+        }
+    }
+
+    /// Control the device via each of the transports that are available
+    /// for it in turn, in the order chosen by transport_order, until one
+    /// of them succeeds
+    async fn control_with_fallback<'a, F, Fut>(
+        &self,
+        device: &'a Device,
+        what: &str,
+        attempt: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(TransportHandle<'a>) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<()>>,
+    {
+        let mut last_error = None;
+        for transport in transport_order(&device.id) {
+            let Some(handle) = self.transport_handle(device, transport).await else {
+                continue;
+            };
+            let started = Instant::now();
+            match attempt(handle).await {
+                Ok(()) => {
+                    record_outcome(&device.id, transport, true, started.elapsed());
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!("Failed to set {what} of {device} via {transport:?}: {err:#}");
+                    record_outcome(&device.id, transport, false, started.elapsed());
+                    last_error.replace(err);
+                }
+            }
+        }
+
+        match last_error {
+            Some(err) => Err(err),
+            None => anyhow::bail!("Unable to control {what} for {device}"),
+        }
+    }
+
     pub async fn device_control<V: Into<JsonValue>>(
         self: &Arc<Self>,
         device: &Device,
@@ -279,7 +869,7 @@ impl State {
         value: V,
     ) -> anyhow::Result<()> {
         let value: JsonValue = value.into();
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to send {value:?} control to {device}");
                 client.control_device(info, capability, value).await?;
@@ -311,32 +901,26 @@ impl State {
                 )
             })?;
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_with_fallback(device, "light power state", |handle| async move {
+            match handle {
+                TransportHandle::Lan(lan_dev) => {
+                    log::info!("Using LAN API to set {device} light power state");
+                    lan_dev.send_turn(on).await?;
+                    self.confirm_via_lan(lan_dev, |status| status.on == on)
+                        .await;
+                }
+                TransportHandle::Iot(iot, entry) => {
                     log::info!("Using IoT API to set {device} light power state");
-                    iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
+                    iot.set_power_state(entry, on).await?;
+                }
+                TransportHandle::Platform(client, info) => {
+                    log::info!("Using Platform API to set {device} light {instance_name} state");
+                    client.set_toggle_state(info, instance_name, on).await?;
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} light {instance_name} state");
-                client.set_toggle_state(info, instance_name, on).await?;
-                return Ok(());
-            }
-        }
-
-        anyhow::bail!("Unable to control light power state for {device}");
+            Ok(())
+        })
+        .await
     }
 
     pub async fn device_power_on(
@@ -344,32 +928,26 @@ impl State {
         device: &Device,
         on: bool,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_with_fallback(device, "power state", |handle| async move {
+            match handle {
+                TransportHandle::Lan(lan_dev) => {
+                    log::info!("Using LAN API to set {device} power state");
+                    lan_dev.send_turn(on).await?;
+                    self.confirm_via_lan(lan_dev, |status| status.on == on)
+                        .await;
+                }
+                TransportHandle::Iot(iot, entry) => {
                     log::info!("Using IoT API to set {device} power state");
-                    iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
+                    iot.set_power_state(entry, on).await?;
+                }
+                TransportHandle::Platform(client, info) => {
+                    log::info!("Using Platform API to set {device} power state");
+                    client.set_power_state(info, on).await?;
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} power state");
-                client.set_power_state(info, on).await?;
-                return Ok(());
-            }
-        }
-
-        anyhow::bail!("Unable to control power state for {device}");
+            Ok(())
+        })
+        .await
     }
 
     pub async fn device_set_brightness(
@@ -387,32 +965,33 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
-            self.poll_lan_api(lan_dev, |status| status.brightness == percent)
-                .await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_requested_brightness(percent);
+
+        self.control_with_fallback(device, "brightness", |handle| async move {
+            match handle {
+                TransportHandle::Lan(lan_dev) => {
+                    log::info!("Using LAN API to set {device} brightness");
+                    lan_dev.send_brightness(percent).await?;
+                    self.confirm_via_lan(lan_dev, |status| {
+                        BrightnessScale::PERCENT.reconcile(status.brightness.into(), Some(percent))
+                            == percent
+                    })
+                    .await;
+                }
+                TransportHandle::Iot(iot, entry) => {
                     log::info!("Using IoT API to set {device} brightness");
-                    iot.set_brightness(&info.entry, percent).await?;
-                    return Ok(());
+                    iot.set_brightness(entry, percent).await?;
+                }
+                TransportHandle::Platform(client, info) => {
+                    log::info!("Using Platform API to set {device} brightness");
+                    client.set_brightness(info, percent).await?;
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} brightness");
-                client.set_brightness(info, percent).await?;
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Unable to control brightness for {device}");
+            Ok(())
+        })
+        .await
     }
 
     pub async fn device_set_color_temperature(
@@ -420,38 +999,188 @@ impl State {
         device: &Device,
         kelvin: u32,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
-            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
+        self.control_with_fallback(device, "color temperature", |handle| async move {
+            match handle {
+                TransportHandle::Lan(lan_dev) => {
+                    log::info!("Using LAN API to set {device} color temperature");
+                    lan_dev.send_color_temperature_kelvin(kelvin).await?;
+                    self.confirm_via_lan(lan_dev, |status| {
+                        status.color_temperature_kelvin == kelvin
+                    })
+                    .await;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
+                }
+                TransportHandle::Iot(iot, entry) => {
+                    log::info!("Using IoT API to set {device} color temperature");
+                    iot.set_color_temperature(entry, kelvin).await?;
+                }
+                TransportHandle::Platform(client, info) => {
+                    log::info!("Using Platform API to set {device} color temperature");
+                    client.set_color_temperature(info, kelvin).await?;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Attempt to apply brightness together with either a color or
+    /// a color temperature as a single ptReal message, so that the
+    /// device doesn't visibly step through the intermediate state.
+    /// Returns Ok(false) if the device doesn't support this, in which
+    /// case the caller should apply the attributes individually.
+    pub async fn try_combined_light_command(
+        self: &Arc<Self>,
+        device: &Device,
+        brightness: u8,
+        color: Option<DeviceColor>,
+        kelvin: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        if !device.supports_combined_light_commands() {
+            return Ok(false);
+        }
+        let Some(change) = CombinedLightChange::new(brightness, color, kelvin) else {
+            return Ok(false);
+        };
+        let commands = change
+            .commands()?
+            .iter()
+            .map(|command| command.base64())
+            .collect();
+
+        if let Some(lan_dev) = device.preferred_lan_device() {
+            log::info!("Using LAN API to set {device} brightness and color in one step");
+            lan_dev.send_real(commands).await?;
+            self.poll_lan_api(lan_dev, |status| change.is_applied(status))
                 .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
+        } else if device.iot_api_supported() {
+            let Some(iot) = self.get_iot_client_for(device).await else {
+                return Ok(false);
+            };
+            let Some(info) = &device.undoc_device_info else {
+                return Ok(false);
+            };
+            log::info!("Using IoT API to set {device} brightness and color in one step");
+            iot.send_real(&info.entry, commands).await?;
+        } else {
+            return Ok(false);
         }
 
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} color temperature");
-                    iot.set_color_temperature(&info.entry, kelvin).await?;
-                    return Ok(());
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_active_scene(None);
+        Ok(true)
+    }
+
+    /// Set the color and/or brightness of individual segments.
+    /// The segments are the indices used by the device.
+    pub async fn device_set_segments(
+        self: &Arc<Self>,
+        device: &Device,
+        changes: &[SegmentChange],
+    ) -> anyhow::Result<()> {
+        if let Some(range) = device.segment_range() {
+            if let Some(change) = changes.iter().find(|c| !range.contains(&c.segment)) {
+                anyhow::bail!(
+                    "{device} has {} segments; segment {} is out of range",
+                    range.end,
+                    change.segment
+                );
+            }
+        }
+        let changes: Vec<SegmentChange> = if changes.iter().any(|c| c.color_temp_kelvin.is_some()) {
+            let Some((min, max)) = device.get_color_temperature_range() else {
+                anyhow::bail!(
+                    "{device} doesn't report a color temperature range, \
+                     so its segments can only be set to a color"
+                );
+            };
+            changes
+                .iter()
+                .map(|c| SegmentChange {
+                    color_temp_kelvin: c.color_temp_kelvin.map(|k| k.clamp(min, max)),
+                    ..*c
+                })
+                .collect()
+        } else {
+            changes.to_vec()
+        };
+
+        if device.lan_segment_count().is_some() {
+            let commands = segment_commands(&changes)?;
+
+            if let Some(lan_dev) = device.preferred_lan_device() {
+                log::info!("Using LAN API to set {device} segments");
+                lan_dev.send_real(commands).await?;
+                return Ok(());
+            }
+            if device.iot_api_supported() {
+                if let Some(iot) = self.get_iot_client_for(device).await {
+                    if let Some(info) = &device.undoc_device_info {
+                        log::info!("Using IoT API to set {device} segments");
+                        iot.send_real(&info.entry, commands).await?;
+                        return Ok(());
+                    }
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color temperature");
-                client.set_color_temperature(info, kelvin).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
+                log::info!("Using Platform API to set {device} segments");
+                for change in &changes {
+                    if let Some(brightness) = change.brightness {
+                        client
+                            .set_segment_brightness(info, change.segment, brightness)
+                            .await?;
+                    }
+                    if let Some(DeviceColor { r, g, b }) = change.rgb() {
+                        client
+                            .set_segment_rgb(info, change.segment, r, g, b)
+                            .await?;
+                    }
+                }
                 return Ok(());
             }
         }
-        anyhow::bail!("Unable to control color temperature for {device}");
+
+        anyhow::bail!(
+            "Unable to set segments for {device}: it isn't reachable via \
+             the LAN or IoT APIs, and the Platform API isn't available for it"
+        );
+    }
+
+    pub async fn device_set_display_temperature_scale(
+        self: &Arc<Self>,
+        device: &Device,
+        scale: TemperatureScale,
+    ) -> anyhow::Result<()> {
+        let undoc = self
+            .get_undoc_client_for(device)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        undoc
+            .set_device_settings(
+                &acct.token,
+                &device.sku,
+                &device.id,
+                serde_json::json!({
+                    "fahOpen": scale == TemperatureScale::Farenheit,
+                }),
+            )
+            .await?;
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_display_temperature_scale(scale);
+        self.notify_of_state_change(&device.id).await?;
+        Ok(())
     }
 
     // FIXME: this function probably shouldn't exist here
@@ -461,11 +1190,11 @@ impl State {
         apply: F,
     ) -> anyhow::Result<bool> {
         let mut params: SetHumidifierNightlightParams =
-            device.nightlight_state.clone().unwrap_or_default().into();
+            device.nightlight_state.unwrap_or_default().into();
         (apply)(&mut params);
 
         if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color");
                     iot.send_real(&info.entry, vec![command.base64()]).await?;
@@ -490,7 +1219,7 @@ impl State {
                 param: value as u8,
             },
         ) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.get_iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     iot.send_real(&info.entry, vec![command.base64()]).await?;
                     return Ok(());
@@ -498,7 +1227,7 @@ impl State {
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 client.set_work_mode(info, work_mode, value).await?;
                 return Ok(());
@@ -507,6 +1236,45 @@ impl State {
         anyhow::bail!("Unable to control humidifier parameter work_mode={work_mode} for {device}");
     }
 
+    /// Activate one of the native presets of the device, by its label
+    pub async fn device_set_work_mode_preset(
+        self: &Arc<Self>,
+        device: &Device,
+        label: &str,
+    ) -> anyhow::Result<()> {
+        let preset = ParsedWorkMode::with_device(device)?
+            .preset_by_label(label)
+            .ok_or_else(|| anyhow::anyhow!("{device} has no preset named '{label}'"))?;
+        self.humidifier_set_parameter(device, preset.mode_num, preset.value)
+            .await
+    }
+
+    pub async fn device_apply_color_preset(
+        self: &Arc<Self>,
+        device: &Device,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let preset = self
+            .color_presets(device)
+            .await
+            .into_iter()
+            .find(|preset| preset.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow::anyhow!("{device} has no color preset named '{name}'"))?;
+
+        match preset.color {
+            PresetColor::Rgb(DeviceColor { r, g, b }) => {
+                self.device_set_color_rgb(device, r, g, b).await?;
+            }
+            PresetColor::Kelvin(kelvin) => {
+                self.device_set_color_temperature(device, kelvin).await?;
+            }
+        }
+        if let Some(brightness) = preset.brightness {
+            self.device_set_brightness(device, brightness).await?;
+        }
+        Ok(())
+    }
+
     pub async fn device_set_color_rgb(
         self: &Arc<Self>,
         device: &Device,
@@ -526,39 +1294,33 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            let color = crate::lan_api::DeviceColor { r, g, b };
-            log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
-            self.poll_lan_api(lan_dev, |status| status.color == color)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
+        self.control_with_fallback(device, "color", |handle| async move {
+            match handle {
+                TransportHandle::Lan(lan_dev) => {
+                    let color = DeviceColor { r, g, b };
+                    log::info!("Using LAN API to set {device} color");
+                    lan_dev.send_color_rgb(color).await?;
+                    self.confirm_via_lan(lan_dev, |status| status.color == color)
+                        .await;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
+                }
+                TransportHandle::Iot(iot, entry) => {
                     log::info!("Using IoT API to set {device} color");
-                    iot.set_color_rgb(&info.entry, r, g, b).await?;
-                    return Ok(());
+                    iot.set_color_rgb(entry, r, g, b).await?;
+                }
+                TransportHandle::Platform(client, info) => {
+                    log::info!("Using Platform API to set {device} color");
+                    client.set_color_rgb(info, r, g, b).await?;
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_active_scene(None);
                 }
             }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color");
-                client.set_color_rgb(info, r, g, b).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Unable to control color for {device}");
+            Ok(())
+        })
+        .await
     }
 
     pub async fn poll_after_control(self: &Arc<Self>, id: String) {
@@ -566,7 +1328,7 @@ impl State {
             return;
         };
 
-        let iot_available = self.get_iot_client().await.is_some();
+        let iot_available = self.get_iot_client_for(&device).await.is_some();
 
         if device.pollable_via_iot() && iot_available {
             return;
@@ -589,7 +1351,7 @@ impl State {
 
     pub async fn device_list_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 return Ok(sort_and_dedup_scenes(client.list_scene_names(info).await?));
             }
@@ -600,13 +1362,125 @@ impl State {
         Ok(vec![])
     }
 
+    /// The scenes of the device, grouped by the categories of
+    /// the scene library of the Govee Home app
+    async fn device_scene_categories(&self, device: &Device) -> SceneCategories {
+        match GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await {
+            Ok(catalog) => catalog
+                .into_iter()
+                .map(|c| {
+                    (
+                        c.category_name,
+                        c.scenes.into_iter().map(|s| s.scene_name).collect(),
+                    )
+                })
+                .collect(),
+            Err(err) => {
+                log::debug!("Unable to categorize the scenes of {device}: {err:#}");
+                vec![]
+            }
+        }
+    }
+
+    /// The scenes of the device that are allowed by the scene filters
+    async fn device_filtered_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        let scenes = self.device_list_scenes(device).await?;
+        let rules: Vec<SceneFilterRule> = self
+            .scene_filters
+            .lock()
+            .await
+            .iter()
+            .filter(|rule| rule.applies_to(&device.sku))
+            .cloned()
+            .collect();
+        if rules.is_empty() {
+            return Ok(scenes);
+        }
+        let categories = if rules.iter().any(|rule| rule.uses_categories()) {
+            self.device_scene_categories(device).await
+        } else {
+            vec![]
+        };
+        Ok(filter_scenes(&rules, &device.sku, scenes, &categories))
+    }
+
+    /// The scenes offered in Home Assistant, grouped by category,
+    /// for offering one select per category
+    pub async fn device_scenes_by_category(
+        &self,
+        device: &Device,
+    ) -> anyhow::Result<SceneCategories> {
+        let scenes = self.device_filtered_scenes(device).await?;
+        if scenes.is_empty() {
+            return Ok(vec![]);
+        }
+        let categories = self.device_scene_categories(device).await;
+        Ok(group_by_category(&scenes, &categories))
+    }
+
+    /// The scenes to offer in Home Assistant. Some devices have
+    /// thousands of scenes, which makes for huge discovery payloads
+    /// and unwieldy lists, so for those only a curated subset is
+    /// offered until the full list is requested via load_all_scenes.
+    pub async fn device_published_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        let scenes = self.device_filtered_scenes(device).await?;
+        if scenes.len() <= SCENE_LIST_LIMIT
+            || self.full_scene_lists.lock().await.contains(&device.id)
+        {
+            return Ok(scenes);
+        }
+
+        let mut preferred: Vec<String> = device
+            .scene_history
+            .iter()
+            .map(|activation| activation.name.to_string())
+            .collect();
+        if let Some(scene) = device.device_state().and_then(|s| s.scene) {
+            preferred.push(scene);
+        }
+        Ok(curate_scenes(scenes, &preferred, SCENE_LIST_LIMIT))
+    }
+
+    /// Whether the device has too many scenes to offer them all
+    /// in Home Assistant until they are requested
+    pub async fn device_has_many_scenes(&self, device: &Device) -> bool {
+        self.device_filtered_scenes(device)
+            .await
+            .map(|scenes| scenes.len() > SCENE_LIST_LIMIT)
+            .unwrap_or(false)
+    }
+
+    /// Whether Home Assistant is only offered some of the scenes
+    /// of the device
+    pub async fn device_has_partial_scene_list(&self, device: &Device) -> bool {
+        !self.full_scene_lists.lock().await.contains(&device.id)
+            && self.device_has_many_scenes(device).await
+    }
+
+    /// Offer all of the scenes of the device in Home Assistant,
+    /// updating its entities if they were only offered some of them
+    pub async fn load_all_scenes(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        if !self.device_has_partial_scene_list(device).await {
+            return Ok(());
+        }
+        self.full_scene_lists
+            .lock()
+            .await
+            .insert(device.id.to_string());
+        log::info!("Offering all of the scenes of {device}");
+        if let Some(hass) = self.get_hass_client().await {
+            hass.register_device(device, self).await?;
+        }
+        Ok(())
+    }
+
     pub async fn device_set_target_temperature(
         self: &Arc<Self>,
         device: &Device,
         instance_name: &str,
         target: TemperatureValue,
     ) -> anyhow::Result<()> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.get_platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} target temperature to {target}");
                 client
@@ -624,53 +1498,358 @@ impl State {
         device: &Device,
         scene: &str,
     ) -> anyhow::Result<()> {
+        if self.device_has_partial_scene_list(device).await
+            && !self
+                .device_published_scenes(device)
+                .await?
+                .iter()
+                .any(|s| s == scene)
+        {
+            // Someone knows about scenes beyond those that we offered,
+            // so they may as well all be offered
+            let state = self.clone();
+            let device = device.clone();
+            tokio::spawn(async move {
+                if let Err(err) = state.load_all_scenes(&device).await {
+                    log::error!("Failed to offer all of the scenes of {device}: {err:#}");
+                }
+            });
+        }
+
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
         let avoid_platform_api = device.avoid_platform_api();
 
         if !avoid_platform_api {
-            if let Some(client) = self.get_platform_client().await {
+            if let Some(client) = self.get_platform_client_for(device).await {
                 if let Some(info) = &device.http_device_info {
                     log::info!("Using Platform API to set {device} to scene {scene}");
                     client.set_scene_by_name(info, scene).await?;
-                    self.device_mut(&device.sku, &device.id)
-                        .await
-                        .set_active_scene(Some(scene));
+                    let mut device = self.device_mut(&device.sku, &device.id).await;
+                    device.set_active_scene(Some(scene));
+                    device.record_scene_activation(scene, SceneSource::Bridge);
                     return Ok(());
                 }
             }
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = device.preferred_lan_device() {
             log::info!("Using LAN API to set {device} to scene {scene}");
-            lan_dev.set_scene_by_name(scene).await?;
+            match lan_dev.set_scene_by_name(scene).await {
+                Ok(()) => {
+                    let mut device = self.device_mut(&device.sku, &device.id).await;
+                    device.set_active_scene(Some(scene));
+                    device.record_scene_activation(scene, SceneSource::Bridge);
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!("Failed to set {device} to scene {scene} via the LAN API: {err:#}");
+                }
+            }
+        }
 
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(Some(scene));
-            return Ok(());
+        if device.iot_api_supported() {
+            if let Some(iot) = self.get_iot_client_for(device).await {
+                if let Some(info) = &device.undoc_device_info {
+                    let result = match scene_packet_by_name(&device.sku, scene).await {
+                        Ok(Some(encoded)) => {
+                            log::info!("Using IoT API to set {device} to scene {scene}");
+                            iot.send_real(&info.entry, vec![encoded])
+                                .await
+                                .map(|_| true)
+                        }
+                        Ok(None) => Ok(false),
+                        Err(err) => Err(err),
+                    };
+                    match result {
+                        Ok(true) => {
+                            let mut device = self.device_mut(&device.sku, &device.id).await;
+                            device.set_active_scene(Some(scene));
+                            device.record_scene_activation(scene, SceneSource::Bridge);
+                            return Ok(());
+                        }
+                        Ok(false) => {}
+                        Err(err) => {
+                            log::warn!(
+                                "Failed to set {device} to scene {scene} via the IoT API: {err:#}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        if avoid_platform_api {
+            // As a last resort, try the Platform API even though we'd
+            // normally avoid it for this device, as LAN and IoT were
+            // either unavailable or failed. The scene list that it
+            // uses includes the entries synthesized from the scene catalog,
+            // so scenes that the device doesn't advertise can still be set.
+            if let Some(client) = self.get_platform_client_for(device).await {
+                if let Some(info) = &device.http_device_info {
+                    log::info!(
+                        "LAN and IoT didn't set the scene, falling back to \
+                         the Platform API to set {device} to scene {scene}"
+                    );
+                    client.set_scene_by_name(info, scene).await?;
+                    let mut device = self.device_mut(&device.sku, &device.id).await;
+                    device.set_active_scene(Some(scene));
+                    device.record_scene_activation(scene, SceneSource::Bridge);
+                    return Ok(());
+                }
+            }
         }
 
         anyhow::bail!("Unable to set scene for {device}");
     }
 
+    /// Apply one of the quirk-defined extended controls for the device.
+    /// `value` is either `ON`/`OFF` for a switch, `LOCK`/`UNLOCK`
+    /// for a lock, or the name of an option for a select.
+    pub async fn device_set_extended_control(
+        self: &Arc<Self>,
+        device: &Device,
+        instance: &str,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        let control = device
+            .resolve_quirk()
+            .and_then(|quirk| quirk.extended_control(instance))
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {instance} control"))?;
+        let command = control.command_for_value(value)?;
+
+        if let Some(lan_dev) = device.preferred_lan_device() {
+            log::info!("Using LAN API to set {device} {instance} to {value}");
+            lan_dev.send_real(vec![command]).await?;
+        } else if let Some((iot, info)) = self
+            .get_iot_client_for(device)
+            .await
+            .zip(device.undoc_device_info.as_ref())
+        {
+            log::info!("Using IoT API to set {device} {instance} to {value}");
+            iot.send_real(&info.entry, vec![command]).await?;
+        } else {
+            anyhow::bail!("Unable to set {instance} for {device}");
+        }
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_extended_control_value(instance, value);
+        self.notify_of_state_change(&device.id).await
+    }
+
     // Take care not to call this while you hold a mutable device
     // reference, as that will deadlock!
     pub async fn notify_of_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
-        let Some(canonical_device) = self.device_by_id(&device_id).await else {
+        let Some(canonical_device) = self.device_by_id(device_id).await else {
             anyhow::bail!("cannot find device {device_id}!?");
         };
+        if !owns_device(&canonical_device) {
+            return Ok(());
+        }
+        mark_state_changed();
+        if let Some(device_state) = canonical_device.device_state() {
+            record_state_change(&canonical_device, &device_state);
+        }
 
         if let Some(hass) = self.get_hass_client().await {
             hass.advise_hass_of_light_state(&canonical_device, self)
                 .await?;
+            publish_contract_state(&hass, &canonical_device).await?;
         }
 
         Ok(())
     }
 }
 
+/// Devices with more scenes than this initially offer only some of them
+pub const SCENE_LIST_LIMIT: usize = 100;
+
+/// Choose up to `limit` of `scenes`, starting with those that are in
+/// `preferred`, such as the recently used ones, then taking the rest
+/// in order. The chosen scenes are returned in their original order.
+fn curate_scenes(scenes: Vec<String>, preferred: &[String], limit: usize) -> Vec<String> {
+    let mut chosen: HashSet<&str> = preferred
+        .iter()
+        .filter(|name| scenes.contains(name))
+        .map(|name| name.as_str())
+        .take(limit)
+        .collect();
+    for scene in &scenes {
+        if chosen.len() >= limit {
+            break;
+        }
+        chosen.insert(scene);
+    }
+    let chosen: HashSet<String> = chosen.into_iter().map(|s| s.to_string()).collect();
+    scenes
+        .into_iter()
+        .filter(|scene| chosen.contains(scene))
+        .collect()
+}
+
 pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.sort_by_key(|s| s.to_ascii_lowercase());
     scenes.dedup();
     scenes
 }
+
+/// Brightness together with either a color or a color temperature,
+/// to be applied in a single ptReal message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct CombinedLightChange {
+    brightness: u8,
+    color: Option<DeviceColor>,
+    /// Only set when there is no color; the color takes precedence
+    kelvin: Option<u32>,
+}
+
+impl CombinedLightChange {
+    fn new(brightness: u8, color: Option<DeviceColor>, kelvin: Option<u32>) -> Option<Self> {
+        if color.is_none() && kelvin.is_none() {
+            return None;
+        }
+        Some(Self {
+            brightness,
+            color,
+            kelvin: if color.is_some() { None } else { kelvin },
+        })
+    }
+
+    fn commands(&self) -> anyhow::Result<Vec<Base64HexBytes>> {
+        let mut commands = vec![Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetBrightness {
+                percent: self.brightness,
+            },
+        )?];
+        if let Some(DeviceColor { r, g, b }) = self.color {
+            commands.push(Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetColorRgb { r, g, b },
+            )?);
+        } else if let Some(kelvin) = self.kelvin {
+            commands.push(Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetColorTemperature {
+                    kelvin: BigEndianU16(kelvin.min(u16::MAX as u32) as u16),
+                },
+            )?);
+        }
+        Ok(commands)
+    }
+
+    /// Whether the status reported by the device reflects the change
+    fn is_applied(&self, status: &LanDeviceStatus) -> bool {
+        status.brightness == self.brightness
+            && self.color.map(|c| status.color == c).unwrap_or(true)
+            && self
+                .kelvin
+                .map(|k| status.color_temperature_kelvin == k)
+                .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ble::GoveeBlePacket;
+
+    #[tokio::test]
+    async fn restore_after_power_loss_opt_in() {
+        let state = State::new();
+        state
+            .set_restore_after_power_loss(vec!["AA:BB:CC:DD:EE:FF:42:2A".to_string()])
+            .await;
+        let device = Device::new("H6000", "aa:bb:cc:dd:ee:ff:42:2a");
+        assert!(state.wants_restore_after_power_loss(&device).await);
+        let other = Device::new("H6000", "aa:bb:cc:dd:ee:ff:42:2b");
+        assert!(!state.wants_restore_after_power_loss(&other).await);
+    }
+
+    #[tokio::test]
+    async fn device_id_forms() {
+        let state = State::new();
+        state
+            .device_mut("H6000", "aa:bb:cc:dd:ee:ff:42:2a")
+            .await
+            .wifi_rssi
+            .replace(-60);
+        state
+            .device_mut("H6000", "AABBCCDDEEFF422A")
+            .await
+            .wifi_channel
+            .replace(6);
+
+        let devices = state.devices().await;
+        assert_eq!(devices.len(), 1);
+        // The id is kept as it was first reported
+        assert_eq!(devices[0].id, "aa:bb:cc:dd:ee:ff:42:2a");
+        assert_eq!(devices[0].wifi_rssi, Some(-60));
+        assert_eq!(devices[0].wifi_channel, Some(6));
+
+        let device = state.device_by_id("AA:BB:CC:DD:EE:FF:42:2A").await.unwrap();
+        assert_eq!(device.id, "aa:bb:cc:dd:ee:ff:42:2a");
+    }
+
+    #[test]
+    fn combined_light_change() {
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        assert_eq!(CombinedLightChange::new(50, None, None), None);
+
+        // A color takes precedence over a color temperature, which
+        // therefore isn't waited for either
+        let change = CombinedLightChange::new(50, Some(red), Some(2700)).unwrap();
+        let packets: Vec<GoveeBlePacket> = change
+            .commands()
+            .unwrap()
+            .iter()
+            .map(|command| command.decode_for_sku("Generic:Light"))
+            .collect();
+        assert_eq!(
+            packets,
+            vec![
+                GoveeBlePacket::SetBrightness(SetBrightness { percent: 50 }),
+                GoveeBlePacket::SetColorRgb(SetColorRgb { r: 255, g: 0, b: 0 }),
+            ]
+        );
+        let mut status = LanDeviceStatus {
+            on: true,
+            brightness: 50,
+            color: red,
+            color_temperature_kelvin: 0,
+        };
+        assert!(change.is_applied(&status));
+        status.brightness = 40;
+        assert!(!change.is_applied(&status));
+
+        let change = CombinedLightChange::new(50, None, Some(2700)).unwrap();
+        assert_eq!(
+            change.commands().unwrap()[1].decode_for_sku("Generic:Light"),
+            GoveeBlePacket::SetColorTemperature(SetColorTemperature {
+                kelvin: BigEndianU16(2700)
+            })
+        );
+        status.brightness = 50;
+        assert!(!change.is_applied(&status));
+        status.color_temperature_kelvin = 2700;
+        assert!(change.is_applied(&status));
+    }
+
+    #[test]
+    fn curated_scenes() {
+        let scenes: Vec<String> = ["Aurora", "Forest", "Ocean", "Rainbow", "Sunset"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let preferred = vec!["Sunset".to_string(), "Missing".to_string()];
+        assert_eq!(
+            curate_scenes(scenes.clone(), &preferred, 3),
+            vec!["Aurora", "Forest", "Sunset"]
+        );
+        assert_eq!(
+            curate_scenes(scenes.clone(), &[], 2),
+            vec!["Aurora", "Forest"]
+        );
+        assert_eq!(curate_scenes(scenes, &preferred, 10).len(), 5);
+    }
+}
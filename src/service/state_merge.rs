@@ -0,0 +1,285 @@
+//! The LAN API, AWS IoT and the Platform API can each report the
+//! state of a device, and they don't always agree with each other;
+//! the Platform API in particular can lag behind by several seconds.
+//! The state is merged attribute by attribute. Of the reports received
+//! within a short window of the most recent one, the value from the
+//! most trusted transport is used, so that a lagging report from a
+//! less trusted transport doesn't override a fresher one. Outside of
+//! the window, the most recent value wins.
+use crate::service::device::DeviceState;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The ways in which we communicate with a device
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Lan,
+    Iot,
+    Platform,
+}
+
+impl Transport {
+    /// A human readable name for the transport
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Lan => "LAN API",
+            Self::Iot => "AWS IoT API",
+            Self::Platform => "PLATFORM API",
+        }
+    }
+}
+
+/// Records where the value of a particular state attribute came from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttributeSource {
+    pub source: Transport,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateMergePolicy {
+    /// Reports received within this many seconds of the most
+    /// recent report are considered to be equally fresh,
+    /// and are chosen between by trust
+    pub window: u32,
+    pub lan_trust: u8,
+    pub iot_trust: u8,
+    pub platform_trust: u8,
+}
+
+impl Default for StateMergePolicy {
+    fn default() -> Self {
+        Self {
+            window: 0,
+            lan_trust: 3,
+            iot_trust: 2,
+            platform_trust: 1,
+        }
+    }
+}
+
+impl std::str::FromStr for StateMergePolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut policy = Self::default();
+        for item in s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|s| !s.is_empty())
+        {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value, got '{item}'"))?;
+            let key = key.trim();
+            let value = value.trim();
+            match key {
+                "window" => policy.window = value.parse()?,
+                "lan" => policy.lan_trust = value.parse()?,
+                "iot" => policy.iot_trust = value.parse()?,
+                "platform" => policy.platform_trust = value.parse()?,
+                _ => anyhow::bail!(
+                    "unknown state merge policy key '{key}'. \
+                    Expected one of window, lan, iot or platform"
+                ),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+static POLICY: Lazy<Mutex<StateMergePolicy>> = Lazy::new(Default::default);
+
+pub fn set_state_merge_policy(policy: StateMergePolicy) {
+    *POLICY.lock() = policy;
+}
+
+pub fn state_merge_policy() -> StateMergePolicy {
+    *POLICY.lock()
+}
+
+impl StateMergePolicy {
+    pub fn trust(&self, transport: Transport) -> u8 {
+        match transport {
+            Transport::Lan => self.lan_trust,
+            Transport::Iot => self.iot_trust,
+            Transport::Platform => self.platform_trust,
+        }
+    }
+
+    /// Choose between a set of candidate values for an attribute
+    fn choose<T: PartialEq>(
+        &self,
+        candidates: Vec<(AttributeSource, T)>,
+    ) -> Option<(AttributeSource, T)> {
+        let newest = candidates.iter().map(|(src, _)| src.updated).max()?;
+        let cutoff = newest - chrono::Duration::seconds(self.window.into());
+        candidates
+            .into_iter()
+            .filter(|(src, _)| src.updated >= cutoff)
+            .max_by_key(|(src, _)| (self.trust(src.source), src.updated))
+    }
+
+    /// Merge the states reported by each transport into a single state
+    pub fn merge(&self, candidates: Vec<(Transport, DeviceState)>) -> Option<DeviceState> {
+        let mut attributes = BTreeMap::new();
+
+        macro_rules! choose {
+            ($name:literal, $value:expr) => {{
+                let chosen = self.choose(
+                    candidates
+                        .iter()
+                        .filter_map(|(transport, state)| {
+                            let value = $value(state)?;
+                            Some((
+                                AttributeSource {
+                                    source: *transport,
+                                    updated: state.updated,
+                                },
+                                value,
+                            ))
+                        })
+                        .collect(),
+                );
+                if let Some((src, _)) = &chosen {
+                    attributes.insert($name.to_string(), *src);
+                }
+                chosen
+            }};
+        }
+
+        let (on_source, on) = choose!("on", |s: &DeviceState| Some(s.on))?;
+        let light_on = choose!("light_on", |s: &DeviceState| s.light_on).map(|(_, v)| v);
+        let online = choose!("online", |s: &DeviceState| s.online).map(|(_, v)| v);
+        let brightness =
+            choose!("brightness", |s: &DeviceState| Some(s.brightness)).map(|(_, v)| v)?;
+        let (color_source, (color, kelvin)) =
+            choose!("color", |s: &DeviceState| Some((s.color, s.kelvin)))?;
+        attributes.insert("kelvin".to_string(), color_source);
+
+        let updated = candidates.iter().map(|(_, state)| state.updated).max()?;
+        let scene = candidates.iter().find_map(|(_, state)| state.scene.clone());
+
+        Some(DeviceState {
+            on,
+            light_on,
+            online,
+            kelvin,
+            color,
+            brightness,
+            scene,
+            source: on_source.source.label(),
+            updated,
+            attributes,
+        })
+    }
+}
+
+/// Describes the source and age, in seconds, of each attribute
+/// of the state, for inclusion in entity attributes
+pub fn describe_sources(state: &DeviceState) -> serde_json::Value {
+    let now = Utc::now();
+    let sources: BTreeMap<&str, serde_json::Value> = state
+        .attributes
+        .iter()
+        .map(|(name, src)| {
+            (
+                name.as_str(),
+                serde_json::json!({
+                    "source": src.source,
+                    "age": (now - src.updated).num_seconds(),
+                }),
+            )
+        })
+        .collect();
+    serde_json::json!(sources)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::DeviceColor;
+
+    fn state(on: bool, brightness: u8, light_on: Option<bool>, age: i64) -> DeviceState {
+        DeviceState {
+            on,
+            light_on,
+            online: None,
+            kelvin: 0,
+            color: DeviceColor::default(),
+            brightness,
+            scene: None,
+            source: "test",
+            updated: DateTime::<Utc>::from_timestamp(1_700_000_000 - age, 0).unwrap(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn merge_policy() {
+        let policy: StateMergePolicy = "lan=3, iot=2, platform=1, window=0".parse().unwrap();
+        assert_eq!(policy, StateMergePolicy::default());
+        assert!("lan".parse::<StateMergePolicy>().is_err());
+        assert!("bluetooth=4".parse::<StateMergePolicy>().is_err());
+        let policy: StateMergePolicy = "window=10".parse().unwrap();
+
+        // The platform report is newer, but the LAN report is within
+        // the window and more trusted, so its values win
+        let merged = policy
+            .merge(vec![
+                (Transport::Lan, state(true, 50, Some(true), 5)),
+                (Transport::Platform, state(false, 20, None, 0)),
+            ])
+            .unwrap();
+        assert!(merged.on);
+        assert_eq!(merged.brightness, 50);
+        assert_eq!(merged.source, "LAN API");
+        assert_eq!(merged.attributes["on"].source, Transport::Lan);
+        assert_eq!(merged.attributes["brightness"].source, Transport::Lan);
+        assert_eq!(
+            merged.updated,
+            DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap()
+        );
+
+        // Trusting the platform API more reverses the choice
+        let platform_first: StateMergePolicy = "window=10, platform=4".parse().unwrap();
+        let merged = platform_first
+            .merge(vec![
+                (Transport::Lan, state(true, 50, Some(true), 5)),
+                (Transport::Platform, state(false, 20, None, 0)),
+            ])
+            .unwrap();
+        assert!(!merged.on);
+        assert_eq!(merged.brightness, 20);
+        assert_eq!(merged.source, "PLATFORM API");
+
+        // Without a window, the most recent report wins
+        let merged = StateMergePolicy::default()
+            .merge(vec![
+                (Transport::Lan, state(true, 50, Some(true), 5)),
+                (Transport::Platform, state(false, 20, None, 0)),
+            ])
+            .unwrap();
+        assert!(!merged.on);
+        assert_eq!(merged.attributes["on"].source, Transport::Platform);
+
+        // The LAN report is too old to be considered
+        let merged = policy
+            .merge(vec![
+                (Transport::Lan, state(true, 50, Some(true), 60)),
+                (Transport::Platform, state(false, 20, None, 0)),
+            ])
+            .unwrap();
+        assert!(!merged.on);
+        assert_eq!(merged.brightness, 20);
+        // Only the LAN API knows the light state
+        assert_eq!(merged.light_on, Some(true));
+        assert_eq!(merged.attributes["light_on"].source, Transport::Lan);
+        assert_eq!(merged.attributes["on"].source, Transport::Platform);
+
+        assert!(policy.merge(vec![]).is_none());
+    }
+}
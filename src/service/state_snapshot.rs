@@ -0,0 +1,231 @@
+//! Without a record of the device state, every device would show
+//! as unknown after a restart until it is next polled or reports
+//! a change. The state of each device is saved to a snapshot in
+//! the cache directory whenever it changes, and is restored when
+//! govee2mqtt starts up. Restored values are reported with their
+//! original `last_updated` time, and are superseded as soon as
+//! fresh information arrives from any of the APIs.
+use crate::cache::cache_dir;
+use crate::lan_api::DeviceColor;
+use crate::service::device::{normalize_device_id, DeviceState};
+use crate::service::state::StateHandle;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::Duration;
+
+/// How often the snapshot is written, if anything changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PersistedState {
+    pub sku: String,
+    pub on: bool,
+    pub light_on: Option<bool>,
+    pub kelvin: u32,
+    pub color: DeviceColor,
+    pub brightness: u8,
+    pub scene: Option<String>,
+    pub updated: DateTime<Utc>,
+}
+
+impl PersistedState {
+    fn from_state(sku: &str, state: &DeviceState) -> Self {
+        Self {
+            sku: sku.to_string(),
+            on: state.on,
+            light_on: state.light_on,
+            kelvin: state.kelvin,
+            color: state.color,
+            brightness: state.brightness,
+            scene: state.scene.clone(),
+            updated: state.updated,
+        }
+    }
+
+    pub fn to_device_state(&self) -> DeviceState {
+        DeviceState {
+            on: self.on,
+            light_on: self.light_on,
+            online: None,
+            kelvin: self.kelvin,
+            color: self.color,
+            brightness: self.brightness,
+            scene: self.scene.clone(),
+            source: "RESTORED",
+            updated: self.updated,
+            attributes: BTreeMap::new(),
+        }
+    }
+}
+
+/// The persisted states, keyed by normalized device id
+type Snapshot = BTreeMap<String, PersistedState>;
+
+/// Re-key a snapshot by normalized device id. Snapshots written by
+/// earlier versions were keyed by the id in whichever form the APIs
+/// reported it, so the same device may appear more than once; the
+/// most recently updated of those entries is kept.
+fn merge_duplicates(snapshot: Snapshot) -> Snapshot {
+    let mut merged = Snapshot::new();
+    for (id, persisted) in snapshot {
+        let key = normalize_device_id(&id);
+        match merged.get(&key) {
+            Some(existing) => {
+                log::info!("Merging duplicate snapshot entry {id} into {key}");
+                if existing.updated < persisted.updated {
+                    merged.insert(key, persisted);
+                }
+            }
+            None => {
+                merged.insert(key, persisted);
+            }
+        }
+    }
+    merged
+}
+
+fn snapshot_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-device-state.json")
+}
+
+fn load_snapshot() -> Option<Snapshot> {
+    let data = std::fs::read_to_string(snapshot_file_name()).ok()?;
+    serde_json::from_str(&data)
+        .map_err(|err| log::warn!("Ignoring unreadable device state snapshot: {err:#}"))
+        .ok()
+        .map(merge_duplicates)
+}
+
+fn save_snapshot(snapshot: &Snapshot) -> anyhow::Result<()> {
+    // Write to a temporary file first, so that a crash part way
+    // through doesn't leave a truncated snapshot behind
+    let path = snapshot_file_name();
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_string(snapshot)?)?;
+    std::fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+/// Record that the state of a device changed, so that the
+/// snapshot is written the next time around
+pub fn mark_state_changed() {
+    DIRTY.store(true, Ordering::Relaxed);
+}
+
+/// Apply the most recent snapshot to the devices that are
+/// currently known
+pub async fn restore_device_states(state: &StateHandle) {
+    let Some(snapshot) = load_snapshot() else {
+        return;
+    };
+
+    let mut restored = 0;
+    for device in state.devices().await {
+        if let Some(persisted) = snapshot.get(&normalize_device_id(&device.id)) {
+            if persisted.sku.eq_ignore_ascii_case(&device.sku) {
+                state
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .restored_state
+                    .replace(persisted.to_device_state());
+                restored += 1;
+            }
+        }
+    }
+    log::info!("Restored the last known state of {restored} device(s)");
+}
+
+async fn take_snapshot(state: &StateHandle) -> Snapshot {
+    let mut snapshot = load_snapshot().unwrap_or_default();
+    for device in state.devices().await {
+        if let Some(device_state) = device.device_state() {
+            snapshot.insert(
+                normalize_device_id(&device.id),
+                PersistedState::from_state(&device.sku, &device_state),
+            );
+        }
+    }
+    snapshot
+}
+
+/// Periodically writes the snapshot when the state of any
+/// device has changed
+pub async fn periodic_state_snapshot(state: StateHandle) -> anyhow::Result<()> {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        if !DIRTY.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let snapshot = take_snapshot(&state).await;
+        let saved = tokio::task::spawn_blocking(move || save_snapshot(&snapshot))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result);
+        if let Err(err) = saved {
+            log::warn!("Failed to save device state snapshot: {err:#}");
+            DIRTY.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let persisted = PersistedState {
+            sku: "H6072".to_string(),
+            on: true,
+            light_on: Some(true),
+            kelvin: 0,
+            color: DeviceColor {
+                r: 255,
+                g: 128,
+                b: 0,
+            },
+            brightness: 42,
+            scene: Some("Sunrise".to_string()),
+            updated: Utc::now(),
+        };
+        let state = persisted.to_device_state();
+        assert_eq!(state.source, "RESTORED");
+        assert_eq!(PersistedState::from_state("H6072", &state), persisted);
+
+        let snapshot: Snapshot = [("AA:BB".to_string(), persisted)].into_iter().collect();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, snapshot);
+    }
+
+    #[test]
+    fn merge_duplicate_entries() {
+        let persisted = |brightness, minutes_ago| PersistedState {
+            sku: "H6072".to_string(),
+            on: true,
+            light_on: None,
+            kelvin: 0,
+            color: DeviceColor::default(),
+            brightness,
+            scene: None,
+            updated: Utc::now() - chrono::Duration::minutes(minutes_ago),
+        };
+        let snapshot: Snapshot = [
+            ("aa:bb:cc:dd:ee:ff:42:2a".to_string(), persisted(10, 5)),
+            ("AABBCCDDEEFF422A".to_string(), persisted(20, 1)),
+            ("AA:BB:CC:DD:EE:FF:42:2B".to_string(), persisted(30, 1)),
+        ]
+        .into_iter()
+        .collect();
+
+        let merged = merge_duplicates(snapshot);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["AA:BB:CC:DD:EE:FF:42:2A"].brightness, 20);
+        assert_eq!(merged["AA:BB:CC:DD:EE:FF:42:2B"].brightness, 30);
+    }
+}
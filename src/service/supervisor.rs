@@ -0,0 +1,225 @@
+//! Supervision of the long running background tasks, such as the
+//! pollers and the AWS IoT and LAN listeners. Rather than having
+//! a task fail silently and leave the bridge half working, each
+//! supervised task is restarted according to its policy, with an
+//! exponential backoff between attempts. A task that keeps failing
+//! is reported as failed via the stats topic and a diagnostic entity.
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+/// The delay before the first restart; it doubles after each
+/// consecutive failure, up to MAX_BACKOFF
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// After this many consecutive failures, the task is reported
+/// as failed, although we continue to try to restart it
+const ESCALATE_AFTER_FAILURES: u32 = 5;
+
+/// A run that lasts at least this long resets the backoff
+/// and the failure count
+const HEALTHY_RUN: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Restart the task whenever it exits, whether or not it failed
+    Always,
+    /// Restart the task only if it returned an error or panicked
+    OnFailure,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Waiting for the backoff to elapse before restarting
+    Restarting,
+    /// Exited successfully and won't be restarted
+    Finished,
+    /// Failed repeatedly; we're still trying to restart it
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    pub policy: RestartPolicy,
+    pub restarts: u32,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub started: DateTime<Utc>,
+    pub last_exit: Option<DateTime<Utc>>,
+}
+
+/// Tracks the status of the supervised tasks. It is cheap to clone;
+/// the clones share the same set of tasks.
+#[derive(Default, Clone)]
+pub struct Supervisor {
+    tasks: Arc<Mutex<BTreeMap<String, TaskStatus>>>,
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+impl Supervisor {
+    /// Returns the status of each of the supervised tasks
+    pub fn task_statuses(&self) -> BTreeMap<String, TaskStatus> {
+        self.tasks.lock().clone()
+    }
+
+    /// Returns the names of the tasks which are failing repeatedly
+    pub fn failed_tasks(&self) -> Vec<String> {
+        self.tasks
+            .lock()
+            .iter()
+            .filter(|(_, status)| status.state == TaskState::Failed)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    fn update<F: FnOnce(&mut TaskStatus)>(&self, name: &str, func: F) {
+        if let Some(status) = self.tasks.lock().get_mut(name) {
+            func(status);
+        }
+    }
+
+    /// Spawn a supervised task. `factory` is called to produce the
+    /// future each time that the task is (re)started.
+    pub fn supervise<F, Fut>(&self, name: &str, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let name = name.to_string();
+        self.tasks.lock().insert(
+            name.clone(),
+            TaskStatus {
+                state: TaskState::Running,
+                policy,
+                restarts: 0,
+                consecutive_failures: 0,
+                last_error: None,
+                started: Utc::now(),
+                last_exit: None,
+            },
+        );
+
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                // Run the task in its own tokio task so that a panic
+                // is reported to us as an error rather than taking
+                // down the supervisor
+                let mut handle = tokio::spawn(factory());
+                let healthy = tokio::time::sleep(HEALTHY_RUN);
+                tokio::pin!(healthy);
+                let mut is_healthy = false;
+                let joined = loop {
+                    tokio::select! {
+                        joined = &mut handle => break joined,
+                        _ = &mut healthy, if !is_healthy => {
+                            is_healthy = true;
+                            backoff = INITIAL_BACKOFF;
+                            supervisor.update(&name, |status| {
+                                status.state = TaskState::Running;
+                                status.consecutive_failures = 0;
+                            });
+                        }
+                    }
+                };
+                let result = match joined {
+                    Ok(result) => result,
+                    Err(err) => Err(anyhow::anyhow!("task panicked: {err:#}")),
+                };
+
+                match result {
+                    Ok(()) if policy == RestartPolicy::OnFailure => {
+                        log::info!("{name} finished");
+                        supervisor.update(&name, |status| {
+                            status.state = TaskState::Finished;
+                            status.last_exit.replace(Utc::now());
+                        });
+                        return;
+                    }
+                    Ok(()) => {
+                        log::warn!("{name} exited; restarting in {backoff:?}");
+                        supervisor.update(&name, |status| {
+                            status.state = TaskState::Restarting;
+                            status.last_exit.replace(Utc::now());
+                        });
+                    }
+                    Err(err) => {
+                        log::error!("{name} failed: {err:#}; restarting in {backoff:?}");
+                        supervisor.update(&name, |status| {
+                            status.consecutive_failures += 1;
+                            status.state = if status.consecutive_failures >= ESCALATE_AFTER_FAILURES
+                            {
+                                TaskState::Failed
+                            } else {
+                                TaskState::Restarting
+                            };
+                            status.last_error.replace(format!("{err:#}"));
+                            status.last_exit.replace(Utc::now());
+                        });
+                    }
+                }
+
+                tokio::time::sleep(backoff).await;
+                backoff = next_backoff(backoff);
+
+                supervisor.update(&name, |status| {
+                    if status.state != TaskState::Failed {
+                        status.state = TaskState::Running;
+                    }
+                    status.restarts += 1;
+                    status.started = Utc::now();
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = INITIAL_BACKOFF;
+        for _ in 0..20 {
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(backoff, MAX_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_and_escalate() {
+        let supervisor = Supervisor::default();
+        supervisor.supervise("done", RestartPolicy::OnFailure, || async { Ok(()) });
+        supervisor.supervise("broken", RestartPolicy::OnFailure, || async {
+            anyhow::bail!("boom")
+        });
+        // The tasks of another supervisor are tracked separately
+        assert!(Supervisor::default().task_statuses().is_empty());
+
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+
+        let statuses = supervisor.task_statuses();
+        assert_eq!(statuses["done"].state, TaskState::Finished);
+        assert_eq!(statuses["done"].restarts, 0);
+
+        let broken = &statuses["broken"];
+        assert_eq!(broken.state, TaskState::Failed);
+        assert!(broken.consecutive_failures >= ESCALATE_AFTER_FAILURES);
+        assert_eq!(broken.last_error.as_deref(), Some("boom"));
+        assert_eq!(supervisor.failed_tasks(), vec!["broken".to_string()]);
+    }
+}
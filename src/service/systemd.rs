@@ -0,0 +1,76 @@
+//! Integration with systemd service supervision.
+//! When run as a `Type=notify` service, we tell systemd when we
+//! are ready and, if `WatchdogSec=` is configured, keep the watchdog
+//! fed for as long as we appear to be healthy, so that systemd can
+//! restart a hung bridge.
+//! When started via a `.socket` unit, the HTTP service uses the
+//! listening socket passed in by systemd rather than binding its own.
+use crate::service::state::StateHandle;
+use sd_notify::NotifyState;
+use tokio::time::{timeout, Duration};
+
+/// How long we'll wait for the device registry before
+/// deciding that the service is wedged
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the first TCP listener passed in via socket activation,
+/// if any
+pub fn take_activated_listener() -> anyhow::Result<Option<std::net::TcpListener>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let Some(listener) = fds.take_tcp_listener(0)? else {
+        return Ok(None);
+    };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+fn notify(state: &[NotifyState]) {
+    // This is a no-op if we are not running under systemd
+    if let Err(err) = sd_notify::notify(false, state) {
+        log::warn!("Failed to notify systemd of {state:?}: {err:#}");
+    }
+}
+
+/// Let systemd know that we have finished starting up
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+async fn is_healthy(state: &StateHandle) -> bool {
+    // A deadlock around the device registry would prevent
+    // us from doing anything useful, so check that we can
+    // still acquire it in a timely fashion
+    if timeout(HEALTH_CHECK_TIMEOUT, state.devices())
+        .await
+        .is_err()
+    {
+        log::error!("Timed out waiting for the device registry");
+        return false;
+    }
+    true
+}
+
+/// If the systemd watchdog is enabled, feed it periodically
+/// for as long as the service is healthy
+pub fn spawn_watchdog(state: StateHandle) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+
+    // systemd recommends pinging at half the configured interval
+    let interval = Duration::from_micros(usec / 2);
+    log::info!("systemd watchdog is enabled; pinging every {interval:?}");
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if is_healthy(&state).await {
+                notify(&[NotifyState::Watchdog]);
+            } else {
+                log::error!("Unhealthy; not pinging the systemd watchdog");
+                notify(&[NotifyState::Status("Unhealthy")]);
+            }
+        }
+    });
+}
@@ -0,0 +1,258 @@
+//! A transaction applies the same light command to several devices
+//! at once and reports a single result for the group. When rollback
+//! is requested and any device fails, every device is returned to
+//! the state that it had before the transaction, so that a scene
+//! is never left half applied.
+use crate::service::device::RestorableState;
+use crate::service::hass::light_command;
+use crate::service::state::StateHandle;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tokio::task::JoinSet;
+
+#[derive(Deserialize, Debug)]
+pub struct Transaction {
+    /// An optional identifier, echoed back in the result
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The names or ids of the devices
+    pub devices: Vec<String>,
+    /// A light command, in the same JSON schema
+    /// that hass uses, eg: `{"state": "OFF"}`
+    pub command: JsonValue,
+    /// Restore all of the devices if any of them fail
+    #[serde(default)]
+    pub rollback: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct MemberResult {
+    pub device: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the device was restored to its prior state;
+    /// absent when no rollback was attempted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restored: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restore_error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TransactionResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// true if the command was applied to every device
+    pub ok: bool,
+    /// true if a rollback was attempted and every device was restored
+    pub rolled_back: bool,
+    pub devices: Vec<MemberResult>,
+}
+
+impl TransactionResult {
+    fn new(id: Option<String>, devices: Vec<MemberResult>) -> Self {
+        Self {
+            id,
+            ok: !devices.is_empty() && devices.iter().all(|d| d.ok),
+            rolled_back: !devices.is_empty() && devices.iter().all(|d| d.restored == Some(true)),
+            devices,
+        }
+    }
+
+    /// A transaction that failed before anything was applied
+    fn failed(id: Option<String>, device: &str, error: String) -> Self {
+        Self::new(
+            id,
+            vec![MemberResult {
+                device: device.to_string(),
+                ok: false,
+                error: Some(error),
+                restored: None,
+                restore_error: None,
+            }],
+        )
+    }
+}
+
+/// The error of each member, given the outcome of each task that
+/// was joined. A member whose task didn't complete, such as because
+/// it panicked, is treated as having failed.
+fn member_errors(outcomes: Vec<Option<Result<(), String>>>) -> Vec<Option<String>> {
+    outcomes
+        .into_iter()
+        .map(|outcome| match outcome {
+            Some(Ok(())) => None,
+            Some(Err(err)) => Some(err),
+            None => Some("the command did not complete".to_string()),
+        })
+        .collect()
+}
+
+impl Transaction {
+    pub async fn run(self, state: &StateHandle) -> TransactionResult {
+        let command = self.command.to_string();
+
+        // Resolve everything up front, so that a typo in a device
+        // name fails the transaction before anything is changed
+        let mut members = vec![];
+        for label in &self.devices {
+            match state.resolve_device_read_only(label).await {
+                Ok(device) => members.push(device),
+                Err(err) => return TransactionResult::failed(self.id, label, format!("{err:#}")),
+            }
+        }
+        if members.is_empty() {
+            return TransactionResult::failed(self.id, "", "no devices were listed".to_string());
+        }
+
+        let snapshots: Vec<Option<RestorableState>> =
+            members.iter().map(|d| d.snapshot_light_state()).collect();
+
+        // Send to all of the devices concurrently, so that
+        // they change together
+        let mut tasks = JoinSet::new();
+        for (idx, device) in members.iter().enumerate() {
+            let state = state.clone();
+            let id = device.id.clone();
+            let command = command.clone();
+            tasks.spawn(async move { (idx, light_command(&state, &id, &command).await) });
+        }
+
+        let mut outcomes: Vec<Option<Result<(), String>>> = vec![None; members.len()];
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok((idx, result)) => {
+                    outcomes[idx] = Some(result.map_err(|err| format!("{err:#}")));
+                }
+                Err(err) => log::error!("transaction task failed: {err:#}"),
+            }
+        }
+        let errors = member_errors(outcomes);
+
+        let failed = errors.iter().any(|e| e.is_some());
+        let mut restores: Vec<Option<Result<(), String>>> = vec![None; members.len()];
+        if failed && self.rollback {
+            log::warn!("Transaction failed; restoring {} devices", members.len());
+            for ((device, snapshot), restore) in members.iter().zip(&snapshots).zip(&mut restores) {
+                let result = match snapshot {
+                    Some(snapshot) => state
+                        .restore_light_state(device, snapshot)
+                        .await
+                        .map_err(|err| format!("{err:#}")),
+                    None => Err("no prior state to restore".to_string()),
+                };
+                if let Err(err) = &result {
+                    log::error!("Failed to restore {device}: {err}");
+                }
+                restore.replace(result);
+            }
+        }
+
+        let results = members
+            .iter()
+            .zip(errors)
+            .zip(restores)
+            .map(|((device, error), restore)| MemberResult {
+                device: device.name(),
+                ok: error.is_none(),
+                error,
+                restored: restore.as_ref().map(|r| r.is_ok()),
+                restore_error: restore.and_then(|r| r.err()),
+            })
+            .collect();
+        TransactionResult::new(self.id, results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_transaction() {
+        let t: Transaction = serde_json::from_str(
+            r#"{"devices": ["Lamp", "Strip"], "command": {"state": "OFF"}, "rollback": true}"#,
+        )
+        .unwrap();
+        assert_eq!(t.devices, vec!["Lamp", "Strip"]);
+        assert_eq!(t.command.to_string(), r#"{"state":"OFF"}"#);
+        assert!(t.rollback);
+        assert_eq!(t.id, None);
+    }
+
+    #[test]
+    fn aggregate_result() {
+        let ok = |device: &str| MemberResult {
+            device: device.to_string(),
+            ok: true,
+            error: None,
+            restored: None,
+            restore_error: None,
+        };
+
+        let result = TransactionResult::new(None, vec![ok("a"), ok("b")]);
+        assert!(result.ok);
+        assert!(!result.rolled_back);
+
+        let restored = |member: MemberResult| MemberResult {
+            restored: Some(true),
+            ..member
+        };
+        let result = TransactionResult::new(
+            Some("lights-down".to_string()),
+            vec![
+                restored(ok("a")),
+                restored(MemberResult {
+                    device: "b".to_string(),
+                    ok: false,
+                    error: Some("timed out".to_string()),
+                    restored: None,
+                    restore_error: None,
+                }),
+            ],
+        );
+        assert!(!result.ok);
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({
+                "id": "lights-down",
+                "ok": false,
+                "rolled_back": true,
+                "devices": [
+                    {"device": "a", "ok": true, "restored": true},
+                    {"device": "b", "ok": false, "error": "timed out", "restored": true},
+                ]
+            })
+        );
+
+        // A device that couldn't be restored means that
+        // the transaction was not fully rolled back
+        let result = TransactionResult::new(
+            None,
+            vec![
+                restored(ok("a")),
+                MemberResult {
+                    restored: Some(false),
+                    restore_error: Some("offline".to_string()),
+                    ..ok("b")
+                },
+            ],
+        );
+        assert!(!result.rolled_back);
+
+        assert!(!TransactionResult::new(None, vec![]).ok);
+    }
+
+    #[test]
+    fn incomplete_members_fail() {
+        assert_eq!(
+            member_errors(vec![Some(Ok(())), None, Some(Err("timed out".to_string()))]),
+            vec![
+                None,
+                Some("the command did not complete".to_string()),
+                Some("timed out".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Chooses which transport to use to control a device.
+//! Each of the LAN, IoT and Platform APIs is scored per device by its
+//! recent success rate and latency, and commands are sent via the
+//! best transport, falling back to the next best when that fails.
+//! Failures are forgotten over time, so that a transport that was
+//! struggling is given another chance once it may have recovered.
+//!
+//! The LAN API is always tried first, unless sending to the device
+//! has been failing. Its outcomes can't be compared with those of the
+//! cloud transports: a LAN command is sent straight to the device,
+//! whereas the IoT and Platform APIs succeed once the Govee cloud has
+//! accepted the command, which says nothing about whether the device
+//! received it. The two cloud transports are ranked by their scores.
+//!
+//! Until a transport has been used, it is assumed to be reliable and
+//! to have a typical latency, which is lower for the IoT API than for
+//! the Platform API, so that the usual order of preference applies
+//! until there is evidence to the contrary.
+use crate::service::state_merge::Transport;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The order in which transports are preferred when they score the same
+pub const TRANSPORT_CHAIN: [Transport; 3] = [Transport::Lan, Transport::Iot, Transport::Platform];
+
+/// How much weight the most recent outcome has in the success rate
+/// and latency, versus the outcomes that came before it
+const SMOOTHING: f64 = 0.3;
+
+/// How long it takes for half of the effect of past failures to be
+/// forgotten
+const FAILURE_HALF_LIFE: Duration = Duration::from_secs(600);
+
+/// The LAN API is tried after the cloud transports while its
+/// success rate is below this
+const LAN_DEMOTION_RATE: f64 = 0.5;
+
+fn typical_latency(transport: Transport) -> Duration {
+    match transport {
+        Transport::Lan => Duration::from_millis(100),
+        Transport::Iot => Duration::from_millis(500),
+        Transport::Platform => Duration::from_secs(1),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Health {
+    success_rate: f64,
+    latency_secs: f64,
+    updated: Instant,
+}
+
+impl Health {
+    fn new(transport: Transport, now: Instant) -> Self {
+        Self {
+            success_rate: 1.0,
+            latency_secs: typical_latency(transport).as_secs_f64(),
+            updated: now,
+        }
+    }
+
+    /// The success rate, with past failures decayed according to
+    /// how long ago the transport was last used
+    fn success_rate_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let decay = 0.5f64.powf(elapsed.as_secs_f64() / FAILURE_HALF_LIFE.as_secs_f64());
+        1.0 - (1.0 - self.success_rate) * decay
+    }
+
+    fn score_at(&self, now: Instant) -> f64 {
+        self.success_rate_at(now) / (1.0 + self.latency_secs)
+    }
+}
+
+#[derive(Default)]
+struct DeviceHealth {
+    transports: HashMap<Transport, Health>,
+    active: Option<Transport>,
+}
+
+static HEALTH: Lazy<Mutex<HashMap<String, DeviceHealth>>> = Lazy::new(Default::default);
+
+/// Record the outcome of attempting to control the device via `transport`
+pub fn record_outcome(device_id: &str, transport: Transport, ok: bool, latency: Duration) {
+    record_outcome_at(device_id, transport, ok, latency, Instant::now());
+}
+
+fn record_outcome_at(
+    device_id: &str,
+    transport: Transport,
+    ok: bool,
+    latency: Duration,
+    now: Instant,
+) {
+    let mut health = HEALTH.lock();
+    let device = health.entry(device_id.to_string()).or_default();
+    let entry = device
+        .transports
+        .entry(transport)
+        .or_insert_with(|| Health::new(transport, now));
+
+    let outcome = if ok { 1.0 } else { 0.0 };
+    entry.success_rate = entry.success_rate_at(now) * (1.0 - SMOOTHING) + outcome * SMOOTHING;
+    // A failure may have taken a long time only because it timed out,
+    // so only successes say anything about the latency
+    if ok {
+        entry.latency_secs =
+            entry.latency_secs * (1.0 - SMOOTHING) + latency.as_secs_f64() * SMOOTHING;
+        device.active.replace(transport);
+    }
+    entry.updated = now;
+}
+
+/// The transports, best scoring first, in which to attempt
+/// to control the device
+pub fn transport_order(device_id: &str) -> Vec<Transport> {
+    transport_order_at(device_id, Instant::now())
+}
+
+fn transport_order_at(device_id: &str, now: Instant) -> Vec<Transport> {
+    let health = HEALTH.lock();
+    let device = health.get(device_id);
+    let health_of = |transport: Transport| {
+        device
+            .and_then(|d| d.transports.get(&transport))
+            .copied()
+            .unwrap_or_else(|| Health::new(transport, now))
+    };
+    let score = |transport: Transport| health_of(transport).score_at(now);
+
+    let mut order = vec![Transport::Iot, Transport::Platform];
+    // The sort is stable, so ties keep the order of the chain
+    order.sort_by(|a, b| score(*b).total_cmp(&score(*a)));
+    if health_of(Transport::Lan).success_rate_at(now) < LAN_DEMOTION_RATE {
+        order.push(Transport::Lan);
+    } else {
+        order.insert(0, Transport::Lan);
+    }
+    order
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TransportScore {
+    pub transport: Transport,
+    pub success_rate: f64,
+    pub latency_ms: u64,
+    pub score: f64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TransportReport {
+    /// The transport that most recently controlled the device
+    pub active: Option<Transport>,
+    /// The transports that have been used to control the device
+    pub scores: Vec<TransportScore>,
+}
+
+pub fn transport_report(device_id: &str) -> TransportReport {
+    let now = Instant::now();
+    let health = HEALTH.lock();
+    let Some(device) = health.get(device_id) else {
+        return TransportReport {
+            active: None,
+            scores: vec![],
+        };
+    };
+
+    TransportReport {
+        active: device.active,
+        scores: TRANSPORT_CHAIN
+            .iter()
+            .filter_map(|transport| {
+                let health = device.transports.get(transport)?;
+                Some(TransportScore {
+                    transport: *transport,
+                    success_rate: (health.success_rate_at(now) * 100.).round() / 100.,
+                    latency_ms: (health.latency_secs * 1000.) as u64,
+                    score: (health.score_at(now) * 100.).round() / 100.,
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fallback_order() {
+        let device = "AA:BB:CC:DD:EE:FF:42:5C";
+        let now = Instant::now();
+        assert_eq!(transport_order_at(device, now), TRANSPORT_CHAIN.to_vec());
+
+        // Repeated failures to send via LAN demote it below the other transports
+        for _ in 0..3 {
+            record_outcome_at(device, Transport::Lan, false, Duration::from_secs(5), now);
+        }
+        record_outcome_at(
+            device,
+            Transport::Iot,
+            true,
+            Duration::from_millis(400),
+            now,
+        );
+        assert_eq!(
+            transport_order_at(device, now),
+            vec![Transport::Iot, Transport::Platform, Transport::Lan]
+        );
+        let report = transport_report(device);
+        assert_eq!(report.active, Some(Transport::Iot));
+        assert_eq!(report.scores[0].transport, Transport::Lan);
+        assert!(report.scores[0].success_rate < 0.5);
+
+        // Once the failures have been forgotten, LAN is preferred again
+        let later = now + FAILURE_HALF_LIFE * 6;
+        assert_eq!(transport_order_at(device, later), TRANSPORT_CHAIN.to_vec());
+
+        // A slow cloud transport is demoted even if it succeeds
+        record_outcome_at(device, Transport::Iot, true, Duration::from_secs(10), later);
+        record_outcome_at(device, Transport::Iot, true, Duration::from_secs(10), later);
+        assert_eq!(
+            transport_order_at(device, later),
+            vec![Transport::Lan, Transport::Platform, Transport::Iot]
+        );
+    }
+
+    #[test]
+    fn lan_stays_first() {
+        let device = "AA:BB:CC:DD:EE:FF:42:5D";
+        let now = Instant::now();
+        // Acks from the cloud being quicker than the LAN API...
+        for _ in 0..5 {
+            record_outcome_at(device, Transport::Lan, true, Duration::from_secs(2), now);
+            record_outcome_at(
+                device,
+                Transport::Iot,
+                true,
+                Duration::from_millis(200),
+                now,
+            );
+        }
+        // ...doesn't mean that IoT is the better way to reach the device
+        assert_eq!(transport_order_at(device, now), TRANSPORT_CHAIN.to_vec());
+
+        // Failed IoT publishes demote it below the Platform API
+        for _ in 0..3 {
+            record_outcome_at(device, Transport::Iot, false, Duration::ZERO, now);
+        }
+        assert_eq!(
+            transport_order_at(device, now),
+            vec![Transport::Lan, Transport::Platform, Transport::Iot]
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! Some devices, such as 3D printers, cameras and network gear, can
+//! wedge themselves in a way that only cutting their power recovers.
+//! Watchdog rules power-cycle the smart plug that feeds such a device
+//! when a condition has held for a while: either a MQTT topic, such as
+//! the availability topic of another integration, carrying a payload
+//! like `offline`, or a Govee device being reported offline.
+//!
+//! Since cutting power is disruptive, each rule has a cooldown between
+//! power cycles and a limit on how many it may perform in a day, and
+//! a plug that is switched off is left alone, as someone most likely
+//! switched it off deliberately.
+use crate::service::playlist::parse_duration;
+use crate::service::poll_intervals::parse_rule_list;
+use crate::service::shard::owns_device;
+use crate::service::state::StateHandle;
+use anyhow::Context;
+use mosquitto_rs::router::{Payload, Topic};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// How often the rules are evaluated
+const EVALUATE_INTERVAL: Duration = Duration::from_secs(15);
+const DAY: Duration = Duration::from_secs(86400);
+const MIN_AFTER: Duration = Duration::from_secs(60);
+const MIN_COOLDOWN: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WatchCondition {
+    /// A MQTT topic carries the payload, ignoring case
+    Topic { topic: String, payload: String },
+    /// A Govee device, by name or id, is reported offline
+    DeviceOffline(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WatchdogRule {
+    /// The plug to power-cycle, by name or id
+    pub plug: String,
+    pub condition: WatchCondition,
+    /// How long the condition must hold before power-cycling
+    pub after: Duration,
+    /// How long to leave the plug switched off
+    pub off_for: Duration,
+    /// The shortest time between two power cycles
+    pub cooldown: Duration,
+    /// The most power cycles in any 24 hour period
+    pub max_per_day: usize,
+}
+
+impl std::str::FromStr for WatchdogRule {
+    type Err = anyhow::Error;
+
+    /// Parses a list of `key=value` pairs, eg:
+    /// `plug=Printer Plug,topic=octoprint/status,payload=offline,after=10m`
+    /// or `plug=Camera Plug,device=Camera,after=15m,cooldown=2h,max_per_day=2`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut plug = None;
+        let mut topic = None;
+        let mut payload = None;
+        let mut device = None;
+        let mut after = None;
+        let mut off_for = Duration::from_secs(10);
+        let mut cooldown = Duration::from_secs(3600);
+        let mut max_per_day = 3;
+
+        for item in s
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|s| !s.is_empty())
+        {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value, got '{item}'"))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "plug" => plug = Some(value),
+                "topic" => topic = Some(value),
+                "payload" => payload = Some(value),
+                "device" => device = Some(value),
+                "after" => after = Some(parse_duration(&value)?),
+                "off" => off_for = parse_duration(&value)?,
+                "cooldown" => cooldown = parse_duration(&value)?,
+                "max_per_day" => max_per_day = value.parse()?,
+                key => anyhow::bail!(
+                    "unknown watchdog key '{key}'. Expected one of plug, topic, \
+                    payload, device, after, off, cooldown or max_per_day"
+                ),
+            }
+        }
+
+        let plug = plug.ok_or_else(|| anyhow::anyhow!("missing plug in '{s}'"))?;
+        let condition = match (topic, device) {
+            (Some(topic), None) => {
+                anyhow::ensure!(
+                    !topic.is_empty() && !topic.contains(['+', '#', ':', '*']),
+                    "watchdog topic '{topic}' must not be empty or contain wildcards"
+                );
+                anyhow::ensure!(
+                    !topic.starts_with("gv2mqtt/"),
+                    "use device= rather than topic= to watch the govee2mqtt device in '{s}'"
+                );
+                WatchCondition::Topic {
+                    topic,
+                    payload: payload.unwrap_or_else(|| "offline".to_string()),
+                }
+            }
+            (None, Some(device)) => {
+                anyhow::ensure!(payload.is_none(), "payload requires topic in '{s}'");
+                WatchCondition::DeviceOffline(device)
+            }
+            _ => anyhow::bail!("expected exactly one of topic or device in '{s}'"),
+        };
+
+        let after = after.ok_or_else(|| anyhow::anyhow!("missing after in '{s}'"))?;
+        anyhow::ensure!(
+            after >= MIN_AFTER,
+            "after in '{s}' must be at least {MIN_AFTER:?}"
+        );
+        anyhow::ensure!(
+            cooldown >= MIN_COOLDOWN,
+            "cooldown in '{s}' must be at least {MIN_COOLDOWN:?}"
+        );
+        anyhow::ensure!(
+            off_for >= Duration::from_secs(1) && off_for <= Duration::from_secs(300),
+            "off in '{s}' must be between 1s and 5m"
+        );
+        anyhow::ensure!(max_per_day > 0, "max_per_day in '{s}' must be at least 1");
+
+        Ok(Self {
+            plug,
+            condition,
+            after,
+            off_for,
+            cooldown,
+            max_per_day,
+        })
+    }
+}
+
+/// Parse a list of rules separated by semicolons or newlines
+pub fn parse_watchdog_rules(s: &str) -> anyhow::Result<Vec<WatchdogRule>> {
+    parse_rule_list(s, "watchdog rule")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    /// The condition doesn't hold, or hasn't held for long enough
+    Wait,
+    /// Power-cycle the plug now
+    PowerCycle,
+    /// The condition has held for long enough, but a safeguard
+    /// prevents power-cycling the plug
+    Suppressed(&'static str),
+}
+
+#[derive(Default, Debug)]
+struct RuleState {
+    /// When the condition was first seen to hold, while it holds
+    since: Option<Instant>,
+    /// When the plug was power-cycled, within the last day
+    cycles: VecDeque<Instant>,
+    /// Whether a suppressed power cycle has been logged
+    /// since the condition started to hold
+    reported_suppression: bool,
+    /// The power cycle that is in progress, if any
+    cycling: Option<JoinHandle<()>>,
+}
+
+impl RuleState {
+    fn evaluate(
+        &mut self,
+        rule: &WatchdogRule,
+        holds: bool,
+        switched_off: bool,
+        now: Instant,
+    ) -> Verdict {
+        while self
+            .cycles
+            .front()
+            .is_some_and(|cycle| now.duration_since(*cycle) >= DAY)
+        {
+            self.cycles.pop_front();
+        }
+
+        if self
+            .cycling
+            .as_ref()
+            .is_some_and(|cycle| !cycle.is_finished())
+        {
+            return Verdict::Wait;
+        }
+        // Someone most likely switched the plug off deliberately, and
+        // the device it feeds needs time to start once it's back on
+        if !holds || switched_off {
+            self.since = None;
+            self.reported_suppression = false;
+            return Verdict::Wait;
+        }
+        let since = *self.since.get_or_insert(now);
+        if now.duration_since(since) < rule.after {
+            return Verdict::Wait;
+        }
+
+        if self.cycles.len() >= rule.max_per_day {
+            return Verdict::Suppressed("the daily limit has been reached");
+        }
+        if self
+            .cycles
+            .back()
+            .is_some_and(|last| now.duration_since(*last) < rule.cooldown)
+        {
+            return Verdict::Suppressed("the cooldown has not elapsed");
+        }
+        Verdict::PowerCycle
+    }
+
+    /// Record that a power cycle was started. The condition has to
+    /// hold for `after` again before the next power cycle.
+    fn record_cycle(&mut self, now: Instant) {
+        self.since = None;
+        self.reported_suppression = false;
+        self.cycles.push_back(now);
+    }
+}
+
+static RULES: Lazy<Mutex<Vec<WatchdogRule>>> = Lazy::new(Default::default);
+static TOPIC_PAYLOADS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+/// The state of each rule, which outlives `periodic_watchdog` so that
+/// the cooldowns and daily limits still apply after it is restarted
+static RULE_STATES: Lazy<Mutex<HashMap<WatchdogRule, RuleState>>> = Lazy::new(Default::default);
+
+pub fn set_watchdog_rules(rules: Vec<WatchdogRule>) {
+    *RULES.lock() = rules;
+}
+
+pub fn watchdog_rules() -> Vec<WatchdogRule> {
+    RULES.lock().clone()
+}
+
+/// The MQTT topics that the rules watch
+pub fn watchdog_topics() -> BTreeSet<String> {
+    RULES
+        .lock()
+        .iter()
+        .filter_map(|rule| match &rule.condition {
+            WatchCondition::Topic { topic, .. } => Some(topic.to_string()),
+            WatchCondition::DeviceOffline(_) => None,
+        })
+        .collect()
+}
+
+/// A watched topic has changed
+pub async fn mqtt_watchdog_topic(
+    Topic(topic): Topic,
+    Payload(payload): Payload<String>,
+) -> anyhow::Result<()> {
+    log::trace!("watchdog: {topic} is {payload}");
+    TOPIC_PAYLOADS.lock().insert(topic, payload);
+    Ok(())
+}
+
+async fn condition_holds(state: &StateHandle, condition: &WatchCondition) -> bool {
+    match condition {
+        WatchCondition::Topic { topic, payload } => TOPIC_PAYLOADS
+            .lock()
+            .get(topic)
+            .is_some_and(|current| current.trim().eq_ignore_ascii_case(payload)),
+        WatchCondition::DeviceOffline(label) => match state.resolve_device(label).await {
+            Some(device) => device
+                .device_state()
+                .is_some_and(|s| s.online == Some(false)),
+            None => false,
+        },
+    }
+}
+
+async fn power_cycle(state: &StateHandle, rule: &WatchdogRule) -> anyhow::Result<()> {
+    let plug = state
+        .resolve_device(&rule.plug)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("watchdog plug '{}' was not found", rule.plug))?;
+
+    state.device_power_on(&plug, false).await?;
+    tokio::time::sleep(rule.off_for).await;
+
+    // Leaving the plug off would be worse than not having
+    // power-cycled it at all, so try hard to turn it back on
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match state.device_power_on(&plug, true).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < 5 => {
+                log::error!("watchdog: turning {plug} back on: {err:#}. Retrying");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            Err(err) => return Err(err).context(format!("turning {plug} back on")),
+        }
+    }
+}
+
+pub async fn periodic_watchdog(state: StateHandle) -> anyhow::Result<()> {
+    let rules = watchdog_rules();
+
+    loop {
+        tokio::time::sleep(EVALUATE_INTERVAL).await;
+
+        for rule in &rules {
+            // The bridge that owns the plug is the one to power-cycle it
+            let Some(plug) = state.resolve_device(&rule.plug).await else {
+                continue;
+            };
+            if !owns_device(&plug) {
+                continue;
+            }
+
+            let holds = condition_holds(&state, &rule.condition).await;
+            let switched_off = plug.device_state().is_some_and(|s| !s.on);
+            let mut rule_states = RULE_STATES.lock();
+            let rule_state = rule_states.entry(rule.clone()).or_default();
+            let now = Instant::now();
+            match rule_state.evaluate(rule, holds, switched_off, now) {
+                Verdict::Wait => {}
+                Verdict::Suppressed(reason) => {
+                    if !rule_state.reported_suppression {
+                        rule_state.reported_suppression = true;
+                        log::warn!(
+                            "watchdog: not power-cycling {plug} for {:?} because {reason}",
+                            rule.condition
+                        );
+                    }
+                }
+                Verdict::PowerCycle => {
+                    log::warn!(
+                        "watchdog: power-cycling {plug} because {:?} has held for {:?}",
+                        rule.condition,
+                        rule.after
+                    );
+                    rule_state.record_cycle(now);
+                    // Cycling takes a while, so that it doesn't hold
+                    // up the evaluation of the other rules
+                    let state = state.clone();
+                    let rule = rule.clone();
+                    rule_state.cycling = Some(tokio::spawn(async move {
+                        if let Err(err) = power_cycle(&state, &rule).await {
+                            log::error!("watchdog: power-cycling {plug}: {err:#}");
+                        }
+                    }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let rules = parse_watchdog_rules(
+            "plug=Printer Plug,topic=octoprint/status,after=10m;\
+             plug=Camera Plug,device=Camera,after=15m,off=30s,cooldown=2h,max_per_day=2",
+        )
+        .unwrap();
+        assert_eq!(
+            rules[0].condition,
+            WatchCondition::Topic {
+                topic: "octoprint/status".to_string(),
+                payload: "offline".to_string()
+            }
+        );
+        assert_eq!(rules[0].cooldown, Duration::from_secs(3600));
+        assert_eq!(
+            rules[1].condition,
+            WatchCondition::DeviceOffline("Camera".to_string())
+        );
+        assert_eq!(rules[1].off_for, Duration::from_secs(30));
+        assert_eq!(rules[1].max_per_day, 2);
+
+        assert!("plug=A,after=10m".parse::<WatchdogRule>().is_err());
+        assert!("plug=A,topic=a/+/b,after=10m"
+            .parse::<WatchdogRule>()
+            .is_err());
+        assert!("plug=A,device=B,topic=c,after=10m"
+            .parse::<WatchdogRule>()
+            .is_err());
+        assert!("plug=A,device=B,after=10s".parse::<WatchdogRule>().is_err());
+        assert!("plug=A,device=B,after=10m,cooldown=1m"
+            .parse::<WatchdogRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn safeguards() {
+        let rule: WatchdogRule = "plug=A,device=B,after=10m,cooldown=1h,max_per_day=2"
+            .parse()
+            .unwrap();
+        let mins = |m: u64| Duration::from_secs(m * 60);
+        let start = Instant::now();
+        let mut state = RuleState::default();
+        let mut evaluate = |holds: bool, switched_off: bool, m: u64| {
+            let now = start + mins(m);
+            let verdict = state.evaluate(&rule, holds, switched_off, now);
+            if verdict == Verdict::PowerCycle {
+                state.record_cycle(now);
+            }
+            verdict
+        };
+
+        assert_eq!(evaluate(true, false, 0), Verdict::Wait);
+        assert_eq!(evaluate(true, false, 9), Verdict::Wait);
+        // A recovery restarts the wait
+        assert_eq!(evaluate(false, false, 9), Verdict::Wait);
+        assert_eq!(evaluate(true, false, 10), Verdict::Wait);
+        // A plug that is switched off doesn't use up the budget,
+        // and the wait restarts once it's switched back on
+        assert_eq!(evaluate(true, true, 20), Verdict::Wait);
+        assert_eq!(evaluate(true, false, 25), Verdict::Wait);
+        assert_eq!(evaluate(true, false, 35), Verdict::PowerCycle);
+
+        assert_eq!(evaluate(true, false, 40), Verdict::Wait);
+        assert_eq!(
+            evaluate(true, false, 50),
+            Verdict::Suppressed("the cooldown has not elapsed")
+        );
+        assert_eq!(evaluate(true, false, 95), Verdict::PowerCycle);
+
+        assert_eq!(evaluate(true, false, 100), Verdict::Wait);
+        assert_eq!(
+            evaluate(true, false, 200),
+            Verdict::Suppressed("the daily limit has been reached")
+        );
+        // The first power cycle falls out of the window a day later
+        assert_eq!(evaluate(true, false, 35 + 24 * 60), Verdict::PowerCycle);
+    }
+}
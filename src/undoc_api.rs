@@ -1,31 +1,133 @@
 #![allow(unused)]
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
-use crate::lan_api::{boolean_int, truthy};
+#[cfg(feature = "cloud")]
+use crate::cache::{
+    account_key, cache_get, cache_peek, cache_put, CacheComputeResult, CacheGetOptions,
+};
+use crate::lan_api::truthy;
+#[cfg(not(feature = "cloud"))]
+pub use crate::local_only::GoveeUndocumentedApi;
 use crate::opt_env_var;
 use crate::platform_api::{
-    from_json, http_response_body, DeviceCapability, DeviceCapabilityKind, DeviceParameters,
-    EnumOption,
+    from_json, DeviceCapability, DeviceCapabilityKind, DeviceParameters, EnumOption,
+};
+#[cfg(feature = "cloud")]
+use crate::platform_api::{http_client, http_response_body, stream_json_body, SendWithRetry};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+pub use govee_models::json::{as_json, embedded_json};
+pub use govee_models::redacted::Redacted;
+pub use govee_models::undoc::{
+    DeviceEntry, DeviceEntryExt, DeviceSettings, DevicesResponse, DiyEffect, DiyEffectGroup,
+    DiyEffectsData, DiyEffectsResponse, ExtResources, FirmwareUpdate, GroupEntry, LastDeviceData,
+    LightEffectCategory, LightEffectEntry, LightEffectLibraryCategoryList,
+    LightEffectLibraryResponse, LightEffectScene, OneClick, OneClickComponent,
+    OneClickComponentList, OneClickIotRule, OneClickIotRuleDevice, OneClickIotRuleEntry,
+    OneClickIotRuleEntryCmd, OneClickResponse,
 };
+#[cfg(feature = "cloud")]
 use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use uuid::Uuid;
 
 // <https://github.com/constructorfleet/homebridge-ultimate-govee/blob/main/src/data/clients/RestClient.ts>
 
+#[cfg(feature = "cloud")]
 const APP_VERSION: &str = "5.6.01";
+#[cfg(feature = "cloud")]
 const HALF_DAY: Duration = Duration::from_secs(3600 * 12);
+#[cfg(feature = "cloud")]
 const ONE_DAY: Duration = Duration::from_secs(86400);
+#[cfg(feature = "cloud")]
 const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
+#[cfg(feature = "cloud")]
 const FIFTEEN_MINS: Duration = Duration::from_secs(60 * 15);
 
-/// Some data is not meant for human eyes except in very unusual circumstances.
-#[derive(Deserialize, Serialize, Clone)]
-#[serde(transparent)]
-pub struct Redacted<T: std::fmt::Debug>(T);
+/// The home payload can run to several megabytes for accounts
+/// with many devices, so it is given longer to arrive
+#[cfg(feature = "cloud")]
+const HOME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The types of the components of the home payload that hold
+/// one-clicks (1) and DreamView feasts (2). The others, such as
+/// groups and environment information, are of no use to us.
+const HOME_COMPONENT_TYPES: &[u64] = &[1, 2];
+
+/// The envelope of the bff-app home payload. The components are
+/// kept as raw json, so that only those of the types that we use
+/// are parsed in full.
+#[derive(Deserialize)]
+struct HomeResponse {
+    #[serde(default)]
+    data: HomeComponents,
+    message: String,
+    status: u32,
+}
+
+#[derive(Deserialize, Default)]
+struct HomeComponents {
+    components: Vec<Box<serde_json::value::RawValue>>,
+}
+
+#[derive(Deserialize)]
+struct HomeComponentType {
+    #[serde(rename = "type")]
+    component_type: u64,
+}
+
+fn parse_home_components(resp: HomeResponse) -> anyhow::Result<Vec<OneClickComponent>> {
+    if resp.status != 200 {
+        anyhow::bail!(
+            "failed to fetch one-clicks: {status} {message}",
+            status = resp.status,
+            message = resp.message
+        );
+    }
+
+    let mut components = vec![];
+    for raw in resp.data.components {
+        // Only the type is read here; the other fields are skipped,
+        // and aren't reported as schema drift
+        let HomeComponentType { component_type } = serde_json::from_str(raw.get())?;
+        if HOME_COMPONENT_TYPES.contains(&component_type) {
+            components.push(from_json(raw.get())?);
+        }
+    }
+    Ok(components)
+}
+
+/// The DIY effects of each account, keyed by client id
+#[cfg(feature = "cloud")]
+static DIY_EFFECTS: once_cell::sync::Lazy<parking_lot::Mutex<HashMap<String, Vec<DiyEffect>>>> =
+    once_cell::sync::Lazy::new(Default::default);
+
+#[cfg(feature = "cloud")]
+fn flatten_diy_effects(data: DiyEffectsData) -> Vec<DiyEffect> {
+    data.groups.into_iter().flat_map(|g| g.diys).collect()
+}
+
+/// Returns the DIY effects for `sku` from the accounts that
+/// have been queried via get_diy_effects_for_account
+#[cfg(feature = "cloud")]
+fn diy_effects_for_sku(sku: &str) -> Vec<DiyEffect> {
+    let mut effects: Vec<DiyEffect> = DIY_EFFECTS
+        .lock()
+        .values()
+        .flatten()
+        .filter(|diy| diy.sku == sku)
+        .cloned()
+        .collect();
+    effects.sort_by_key(|diy| diy.diy_code);
+    effects.dedup();
+    effects
+}
 
 pub fn should_log_sensitive_data() -> bool {
     if let Ok(Some(v)) = opt_env_var::<String>("GOVEE_LOG_SENSITIVE_DATA") {
@@ -35,23 +137,22 @@ pub fn should_log_sensitive_data() -> bool {
     }
 }
 
-impl<T: std::fmt::Debug> std::fmt::Debug for Redacted<T> {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if should_log_sensitive_data() {
-            self.0.fmt(fmt)
-        } else {
-            fmt.write_str("REDACTED")
-        }
-    }
-}
-
-impl<T: std::fmt::Debug> std::ops::Deref for Redacted<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        &self.0
-    }
+/// The body of a request to update the settings of a device;
+/// the settings themselves are sent as a JSON encoded string
+#[cfg(feature = "cloud")]
+fn device_settings_body(
+    sku: &str,
+    device: &str,
+    settings: &JsonValue,
+) -> anyhow::Result<JsonValue> {
+    Ok(serde_json::json!({
+        "sku": sku,
+        "device": device,
+        "deviceSettings": serde_json::to_string(settings)?,
+    }))
 }
 
+#[cfg(feature = "cloud")]
 fn user_agent() -> String {
     format!(
         "GoveeHome/{APP_VERSION} (com.ihoment.GoVeeSensor; build:2; iOS 16.5.0) Alamofire/5.6.4"
@@ -91,6 +192,86 @@ pub struct UndocApiArguments {
     /// Where to find the AWS root CA certificate
     #[arg(long, global = true, default_value = "AmazonRootCA1.pem")]
     pub amazon_root_ca: PathBuf,
+
+    /// The maximum number of messages per second to publish to
+    /// AWS IoT. Messages beyond this rate are queued, with device
+    /// control taking priority over status requests.
+    /// If not passed here, it will be read from the
+    /// GOVEE_IOT_PUBLISH_RATE environment variable.
+    /// If unspecified, uses 5.
+    #[arg(long, global = true)]
+    pub iot_publish_rate: Option<u32>,
+
+    /// The maximum number of messages that may be in the process
+    /// of being published to AWS IoT at once.
+    /// If not passed here, it will be read from the
+    /// GOVEE_IOT_PUBLISH_CONCURRENCY environment variable.
+    /// If unspecified, uses 1, so that messages are published
+    /// one after the other.
+    #[arg(long, global = true)]
+    pub iot_publish_concurrency: Option<usize>,
+
+    /// A file produced by `govee login`, to use in place of
+    /// the account email and password.
+    /// If not passed here, it will be read from the
+    /// GOVEE_TOKEN_FILE environment variable.
+    #[arg(long, global = true)]
+    pub govee_token_file: Option<PathBuf>,
+
+    /// An additional Govee account, as `EMAIL:PASSWORD`, whose
+    /// devices will be merged with those of the primary account.
+    /// A Platform API key for the account may be appended,
+    /// as `EMAIL:PASSWORD:API_KEY`.
+    /// May be repeated. If not passed here, a comma separated
+    /// list will be read from the GOVEE_ACCOUNTS environment
+    /// variable.
+    #[arg(long = "govee-account", global = true)]
+    pub govee_accounts: Vec<GoveeAccount>,
+}
+
+/// Credentials for an additional Govee account
+#[derive(Clone, PartialEq, Eq)]
+pub struct GoveeAccount {
+    pub email: String,
+    pub password: String,
+    pub api_key: Option<String>,
+}
+
+impl std::fmt::Debug for GoveeAccount {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("GoveeAccount")
+            .field("email", &self.email)
+            .field("api_key", &self.api_key.as_ref().map(|_| "REDACTED"))
+            .finish()
+    }
+}
+
+impl std::str::FromStr for GoveeAccount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (email, password) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected EMAIL:PASSWORD"))?;
+        anyhow::ensure!(!email.is_empty(), "the account email is empty");
+
+        // Govee API keys are UUIDs, so a trailing UUID is taken to
+        // be the API key rather than part of the password
+        let (password, api_key) = match password.rsplit_once(':') {
+            Some((password, key)) if Uuid::parse_str(key).is_ok() => {
+                (password, Some(key.to_string()))
+            }
+            _ => (password, None),
+        };
+        anyhow::ensure!(!password.is_empty(), "the password for {email} is empty");
+
+        Ok(Self {
+            email: email.to_string(),
+            password: password.to_string(),
+            api_key,
+        })
+    }
 }
 
 impl UndocApiArguments {
@@ -126,20 +307,121 @@ impl UndocApiArguments {
         })
     }
 
+    pub fn iot_publish_rate(&self) -> anyhow::Result<u32> {
+        match self.iot_publish_rate {
+            Some(rate) => Ok(rate),
+            None => Ok(opt_env_var("GOVEE_IOT_PUBLISH_RATE")?.unwrap_or(5)),
+        }
+    }
+
+    pub fn iot_publish_concurrency(&self) -> anyhow::Result<usize> {
+        match self.iot_publish_concurrency {
+            Some(concurrency) => Ok(concurrency),
+            None => Ok(opt_env_var("GOVEE_IOT_PUBLISH_CONCURRENCY")?.unwrap_or(1)),
+        }
+    }
+
+    pub fn opt_token_file(&self) -> anyhow::Result<Option<PathBuf>> {
+        match &self.govee_token_file {
+            Some(path) => Ok(Some(path.clone())),
+            None => opt_env_var("GOVEE_TOKEN_FILE"),
+        }
+    }
+
+    /// The accounts to use in addition to the primary account
+    pub fn additional_accounts(&self) -> anyhow::Result<Vec<GoveeAccount>> {
+        if !self.govee_accounts.is_empty() {
+            return Ok(self.govee_accounts.clone());
+        }
+        let Some(accounts) = opt_env_var::<String>("GOVEE_ACCOUNTS")? else {
+            return Ok(vec![]);
+        };
+        accounts
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.parse().context("parsing $GOVEE_ACCOUNTS"))
+            .collect()
+    }
+
+    #[cfg(feature = "cloud")]
     pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
+        if let Some(path) = self.opt_token_file()? {
+            let login = SavedLogin::load(&path)?;
+            return Ok(GoveeUndocumentedApi::with_saved_login(login));
+        }
         let email = self.email()?;
         let password = self.password()?;
         Ok(GoveeUndocumentedApi::new(email, password))
     }
+
+    #[cfg(not(feature = "cloud"))]
+    pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
+        anyhow::bail!(crate::local_only::LOCAL_ONLY)
+    }
 }
 
+/// Credentials obtained via `govee login`, which allow running
+/// without having the account password available
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SavedLogin {
+    pub email: String,
+    pub account: LoginAccountResponse,
+    pub iot_key: IotKey,
+    pub community_token: Option<Redacted<String>>,
+    pub obtained: DateTime<Utc>,
+}
+
+impl SavedLogin {
+    pub fn new(
+        email: String,
+        account: LoginAccountResponse,
+        iot_key: IotKey,
+        community_token: Option<String>,
+    ) -> Self {
+        Self {
+            email,
+            account,
+            iot_key,
+            community_token: community_token.map(Redacted::new),
+            obtained: Utc::now(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("reading saved login from {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("parsing saved login from {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        file.write_all(data.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "cloud")]
 #[derive(Clone)]
 pub struct GoveeUndocumentedApi {
     email: String,
     password: String,
     client_id: String,
+    saved_login: Option<Arc<SavedLogin>>,
+    token_expired: Arc<AtomicBool>,
 }
 
+#[cfg(feature = "cloud")]
 impl GoveeUndocumentedApi {
     pub fn new<E: Into<String>, P: Into<String>>(email: E, password: P) -> Self {
         let email = email.into();
@@ -150,24 +432,53 @@ impl GoveeUndocumentedApi {
             email,
             password,
             client_id,
+            saved_login: None,
+            token_expired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Create a client that uses a previously saved login rather
+    /// than the account password. When the saved token expires,
+    /// requests that need it will fail until `govee login` is
+    /// used to obtain a new one.
+    pub fn with_saved_login(login: SavedLogin) -> Self {
+        let mut client = Self::new(login.email.clone(), String::new());
+        client.saved_login.replace(Arc::new(login));
+        client
+    }
+
+    pub fn has_saved_login(&self) -> bool {
+        self.saved_login.is_some()
+    }
+
+    fn saved_login(&self) -> anyhow::Result<Option<&SavedLogin>> {
+        match &self.saved_login {
+            Some(_) if self.token_expired.load(Ordering::SeqCst) => anyhow::bail!(
+                "The saved Govee account token has expired. \
+                Run `govee login` to obtain a new one"
+            ),
+            Some(login) => Ok(Some(login)),
+            None => Ok(None),
         }
     }
 
-    #[allow(unused)]
     pub async fn get_iot_key(&self, token: &str) -> anyhow::Result<IotKey> {
+        if let Some(login) = self.saved_login()? {
+            return Ok(login.iot_key.clone());
+        }
+        let key = self.cache_key("iot-key");
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "iot-key",
+                key: &key,
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
                 allow_stale: false,
+                defer_to_maintenance: false,
             },
             async {
-                let response = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(30))
-                    .build()?
+                let response = http_client(Duration::from_secs(30))?
                     .request(Method::GET, "https://app2.govee.com/app/v1/account/iot/key")
                     .header("Authorization", format!("Bearer {token}"))
                     .header("appVersion", APP_VERSION)
@@ -176,9 +487,13 @@ impl GoveeUndocumentedApi {
                     .header("iotVersion", "0")
                     .header("timestamp", ms_timestamp())
                     .header("User-Agent", user_agent())
-                    .send()
+                    .send_with_retry()
                     .await?;
 
+                if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    self.invalidate_account_login();
+                }
+
                 #[derive(Deserialize, Debug)]
                 #[allow(non_snake_case, dead_code)]
                 struct Response {
@@ -196,13 +511,33 @@ impl GoveeUndocumentedApi {
     }
 
     pub fn invalidate_account_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "account-info").ok();
+        if self.saved_login.is_some() {
+            if !self.token_expired.swap(true, Ordering::SeqCst) {
+                log::error!(
+                    "The saved Govee account token has expired or been revoked. \
+                    Cloud based features are paused until you run `govee login` \
+                    to obtain a new token and restart govee2mqtt"
+                );
+            }
+            return;
+        }
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("account-info")).ok();
     }
 
-    async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?
+    /// Forget the cached AWS IoT certificate, so that the next
+    /// call to get_iot_key will fetch a fresh one
+    pub fn invalidate_iot_key(&self) -> anyhow::Result<()> {
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("iot-key"))
+    }
+
+    /// Returns a cache key that is specific to this account
+    fn cache_key(&self, key: &str) -> String {
+        account_key(&self.client_id, key)
+    }
+
+    /// Perform a full login using the account email and password
+    async fn full_login(&self) -> anyhow::Result<LoginAccountResponse> {
+        let response = http_client(Duration::from_secs(30))?
             .request(
                 Method::POST,
                 "https://app2.govee.com/account/rest/account/v1/login",
@@ -212,7 +547,7 @@ impl GoveeUndocumentedApi {
                 "password": self.password,
                 "client": &self.client_id,
             }))
-            .send()
+            .send_with_retry()
             .await?;
 
         let resp: Response = http_response_body(response).await?;
@@ -225,35 +560,123 @@ impl GoveeUndocumentedApi {
             status: u64,
         }
 
-        let ttl = Duration::from_secs(resp.client.token_expire_cycle as u64);
-        Ok(CacheComputeResult::WithTtl(resp.client, ttl))
+        self.remember_login(&resp.client);
+        Ok(resp.client)
+    }
+
+    /// Exchange the refresh token from a prior login for a new
+    /// bearer token, without sending the account password
+    async fn refresh_login(
+        &self,
+        prior: &LoginAccountResponse,
+    ) -> anyhow::Result<LoginAccountResponse> {
+        let refresh_token = prior
+            .refresh_token
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("the prior login has no refresh token"))?;
+
+        let response = http_client(Duration::from_secs(30))?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/account/rest/v1/user/refresh-tokens",
+            )
+            .header("Authorization", format!("Bearer {}", prior.token.as_str()))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&serde_json::json!({
+                "refreshToken": refresh_token.as_str(),
+            }))
+            .send_with_retry()
+            .await?;
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Response {
+            data: RefreshTokenResponse,
+            message: String,
+            status: u64,
+        }
+
+        let resp: Response = http_response_body(response).await?;
+        let account = prior.clone().with_refreshed_token(resp.data);
+        self.remember_login(&account);
+        Ok(account)
+    }
+
+    /// Keep the most recent login, and its refresh token, for
+    /// longer than the bearer token lasts, so that it can be
+    /// refreshed when the bearer token expires. The cache file
+    /// is only readable by the current user.
+    fn remember_login(&self, account: &LoginAccountResponse) {
+        if let Err(err) = cache_put(
+            "undoc-api",
+            &self.cache_key("account-refresh"),
+            account,
+            ONE_WEEK * 4,
+        ) {
+            log::warn!("Failed to save the Govee account refresh token: {err:#}");
+        }
+    }
+
+    async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+        let prior: Option<LoginAccountResponse> =
+            cache_peek("undoc-api", &self.cache_key("account-refresh")).unwrap_or_else(|err| {
+                log::warn!("Failed to load the Govee account refresh token: {err:#}");
+                None
+            });
+
+        let account = match prior {
+            Some(prior) if prior.refresh_token.is_some() => {
+                match self.refresh_login(&prior).await {
+                    Ok(account) => {
+                        log::info!("Refreshed the Govee account token");
+                        account
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "Failed to refresh the Govee account token: {err:#}. \
+                            Logging in again"
+                        );
+                        self.full_login().await?
+                    }
+                }
+            }
+            _ => self.full_login().await?,
+        };
+
+        let ttl = Duration::from_secs(account.token_expire_cycle as u64);
+        Ok(CacheComputeResult::WithTtl(account, ttl))
     }
 
     pub async fn login_account_cached(&self) -> anyhow::Result<LoginAccountResponse> {
+        if let Some(login) = self.saved_login()? {
+            return Ok(login.account.clone());
+        }
+        let key = self.cache_key("account-info");
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "account-info",
+                key: &key,
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: FIFTEEN_MINS,
                 allow_stale: false,
+                defer_to_maintenance: false,
             },
             async { self.login_account_impl().await },
         )
         .await
     }
 
-    #[allow(dead_code)]
     pub async fn login_account(&self) -> anyhow::Result<LoginAccountResponse> {
-        let value = self.login_account_impl().await?;
-        Ok(value.into_inner())
+        self.full_login().await
     }
 
     pub async fn get_device_list(&self, token: &str) -> anyhow::Result<DevicesResponse> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()?
+        let response = http_client(Duration::from_secs(30))?
             .request(
                 Method::POST,
                 "https://app2.govee.com/device/rest/devices/v1/list",
@@ -265,7 +688,7 @@ impl GoveeUndocumentedApi {
             .header("iotVersion", "0")
             .header("timestamp", ms_timestamp())
             .header("User-Agent", user_agent())
-            .send()
+            .send_with_retry()
             .await?;
 
         if response.status() == reqwest::StatusCode::UNAUTHORIZED {
@@ -277,31 +700,209 @@ impl GoveeUndocumentedApi {
         Ok(resp)
     }
 
+    /// Fetch the DIY effects that the user created in the Govee Home
+    /// app. These are held separately from the scene catalog, so they
+    /// are remembered here to be merged into the scene list of each
+    /// device by synthesize_platform_api_scene_list.
+    pub async fn get_diy_effects_for_account(&self) -> anyhow::Result<Vec<DiyEffect>> {
+        let key = self.cache_key("diy-effects");
+        let effects = cache_get(
+            CacheGetOptions {
+                topic: "undoc-api",
+                key: &key,
+                soft_ttl: FIFTEEN_MINS,
+                hard_ttl: ONE_WEEK,
+                negative_ttl: Duration::from_secs(1),
+                allow_stale: true,
+                defer_to_maintenance: false,
+            },
+            async {
+                let token = self.login_account_cached().await?.token;
+                let response = http_client(Duration::from_secs(30))?
+                    .request(
+                        Method::GET,
+                        "https://app2.govee.com/appsku/v1/diys/groups-diys",
+                    )
+                    .header("Authorization", format!("Bearer {}", token.as_str()))
+                    .header("appVersion", APP_VERSION)
+                    .header("clientId", &self.client_id)
+                    .header("clientType", "1")
+                    .header("iotVersion", "0")
+                    .header("timestamp", ms_timestamp())
+                    .header("User-Agent", user_agent())
+                    .send_with_retry()
+                    .await?;
+
+                if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    self.invalidate_account_login();
+                }
+
+                let resp: DiyEffectsResponse = http_response_body(response).await?;
+
+                Ok(CacheComputeResult::Value(flatten_diy_effects(resp.data)))
+            },
+        )
+        .await?;
+
+        DIY_EFFECTS
+            .lock()
+            .insert(self.client_id.to_string(), effects.clone());
+
+        Ok(effects)
+    }
+
+    /// Update the device settings that are stored in the Govee account.
+    /// `settings` is merged into the existing settings by the server,
+    /// so it only needs to contain the fields that are changing.
+    pub async fn set_device_settings(
+        &self,
+        token: &str,
+        sku: &str,
+        device: &str,
+        settings: JsonValue,
+    ) -> anyhow::Result<()> {
+        let response = http_client(Duration::from_secs(30))?
+            .request(
+                Method::PUT,
+                "https://app2.govee.com/device/rest/devices/v1/settings",
+            )
+            .header("Authorization", format!("Bearer {token}"))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&device_settings_body(sku, device, &settings)?)
+            .send_with_retry()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.invalidate_account_login();
+        }
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Response {
+            message: String,
+            status: u64,
+        }
+
+        let resp: Response = http_response_body(response).await?;
+        if resp.status != 200 {
+            anyhow::bail!(
+                "failed to update settings for {device}: {status} {message}",
+                status = resp.status,
+                message = resp.message
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Ask whether a firmware update is available for a device.
+    /// Returns None if the device is running the latest firmware.
+    pub async fn check_firmware_update(
+        &self,
+        token: &str,
+        entry: &DeviceEntry,
+    ) -> anyhow::Result<Option<FirmwareUpdate>> {
+        let key = self.cache_key(&format!("firmware-{}-{}", entry.sku, entry.device));
+        let settings = &entry.device_ext.device_settings;
+
+        cache_get(
+            CacheGetOptions {
+                topic: "undoc-api",
+                key: &key,
+                // Firmware is released rarely, so there is little
+                // point checking each device more than weekly
+                soft_ttl: ONE_WEEK,
+                hard_ttl: ONE_WEEK * 4,
+                negative_ttl: ONE_DAY,
+                allow_stale: true,
+                defer_to_maintenance: false,
+            },
+            async {
+                let response = http_client(Duration::from_secs(30))?
+                    .request(
+                        Method::POST,
+                        "https://app2.govee.com/device/rest/devices/v1/check-version",
+                    )
+                    .header("Authorization", format!("Bearer {token}"))
+                    .header("appVersion", APP_VERSION)
+                    .header("clientId", &self.client_id)
+                    .header("clientType", "1")
+                    .header("iotVersion", "0")
+                    .header("timestamp", ms_timestamp())
+                    .header("User-Agent", user_agent())
+                    .json(&serde_json::json!({
+                        "sku": entry.sku,
+                        "device": entry.device,
+                        "versionSoft": entry.version_soft,
+                        "versionHard": entry.version_hard,
+                        "wifiSoftVersion": settings.wifi_soft_version,
+                        "wifiHardVersion": settings.wifi_hard_version,
+                    }))
+                    .send_with_retry()
+                    .await?;
+
+                if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    self.invalidate_account_login();
+                }
+
+                let resp: CheckVersionResponse = http_response_body(response).await?;
+                if resp.status != 200 {
+                    anyhow::bail!(
+                        "failed to check firmware of {device}: {status} {message}",
+                        device = entry.device,
+                        status = resp.status,
+                        message = resp.message
+                    );
+                }
+
+                // Some devices report their current version as the
+                // available one, which isn't an update at all
+                let update = resp
+                    .data
+                    .filter(|update| update.version_soft != entry.version_soft);
+                Ok(CacheComputeResult::Value(update))
+            },
+        )
+        .await
+    }
+
     pub fn invalidate_community_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "community-login").ok();
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("community-login")).ok();
     }
 
     /// Login to community-api.govee.com and return the bearer token
     pub async fn login_community(&self) -> anyhow::Result<String> {
+        if let Some(login) = self.saved_login()? {
+            return login
+                .community_token
+                .as_ref()
+                .map(|token| token.to_string())
+                .ok_or_else(|| anyhow::anyhow!("The saved login has no community token"));
+        }
+        let key = self.cache_key("community-login");
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "community-login",
+                key: &key,
                 soft_ttl: ONE_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
                 allow_stale: false,
+                defer_to_maintenance: false,
             },
             async {
-                let response = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(60))
-                    .build()?
+                let response = http_client(Duration::from_secs(60))?
                     .request(Method::POST, "https://community-api.govee.com/os/v1/login")
                     .json(&serde_json::json!({
                         "email": self.email,
                         "password": self.password,
                     }))
-                    .send()
+                    .send_with_retry()
                     .await?;
 
                 #[derive(Deserialize, Debug)]
@@ -350,11 +951,10 @@ impl GoveeUndocumentedApi {
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(1),
                 allow_stale: true,
+                defer_to_maintenance: true,
             },
             async {
-                let response = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(10))
-                    .build()?
+                let response = http_client(Duration::from_secs(10))?
                     .request(
                         Method::GET,
                         format!(
@@ -363,7 +963,7 @@ impl GoveeUndocumentedApi {
                     )
                     .header("AppVersion", APP_VERSION)
                     .header("User-Agent", user_agent())
-                    .send()
+                    .send_with_retry()
                     .await?;
 
                 let resp: LightEffectLibraryResponse = http_response_body(response).await?;
@@ -374,6 +974,62 @@ impl GoveeUndocumentedApi {
         .await
     }
 
+    /// Returns the scene id for each named scene that has an icon
+    pub async fn get_scene_ids_with_icons(sku: &str) -> anyhow::Result<Vec<(String, u32)>> {
+        let catalog = Self::get_scenes_for_device(sku).await?;
+        Ok(catalog
+            .into_iter()
+            .flat_map(|c| c.scenes)
+            .filter(|s| !s.icon_urls.is_empty())
+            .map(|s| (s.scene_name, s.scene_id))
+            .collect())
+    }
+
+    /// Fetch the icon for a scene, caching it locally
+    pub async fn get_scene_icon(sku: &str, scene_id: u32) -> anyhow::Result<SceneIcon> {
+        let catalog = Self::get_scenes_for_device(sku).await?;
+        let url = catalog
+            .into_iter()
+            .flat_map(|c| c.scenes)
+            .find(|s| s.scene_id == scene_id)
+            .and_then(|s| s.icon_urls.into_iter().next())
+            .ok_or_else(|| anyhow::anyhow!("no icon for scene {scene_id} of {sku}"))?;
+
+        cache_get(
+            CacheGetOptions {
+                topic: "scene-icons",
+                key: &url,
+                soft_ttl: ONE_WEEK,
+                hard_ttl: ONE_WEEK * 4,
+                negative_ttl: FIFTEEN_MINS,
+                allow_stale: true,
+                defer_to_maintenance: false,
+            },
+            async {
+                let response = http_client(Duration::from_secs(30))?
+                    .request(Method::GET, &url)
+                    .header("User-Agent", user_agent())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("application/octet-stream")
+                    .to_string();
+                let bytes = response.bytes().await?;
+
+                Ok(CacheComputeResult::Value(SceneIcon {
+                    content_type,
+                    data: data_encoding::BASE64.encode(&bytes),
+                }))
+            },
+        )
+        .await
+    }
+
     /// This is present primarily to workaround a bug where Govee aren't returning
     /// the full list of scenes via their supported platform API
     pub async fn synthesize_platform_api_scene_list(
@@ -384,7 +1040,7 @@ impl GoveeUndocumentedApi {
 
         for c in catalog {
             for s in c.scenes {
-                if let Some(param_id) = s.light_effects.get(0).map(|e| e.scence_param_id) {
+                if let Some(param_id) = s.light_effects.first().map(|e| e.scence_param_id) {
                     options.push(EnumOption {
                         name: s.scene_name,
                         value: json!({
@@ -397,32 +1053,56 @@ impl GoveeUndocumentedApi {
             }
         }
 
-        Ok(vec![DeviceCapability {
+        let mut caps = vec![DeviceCapability {
             kind: DeviceCapabilityKind::DynamicScene,
             parameters: Some(DeviceParameters::Enum { options }),
             alarm_type: None,
             event_state: None,
             instance: "lightScene".to_string(),
-        }])
+        }];
+
+        // The platform API activates DIY effects by their code via
+        // the diyScene instance, rather than via lightScene
+        let diy_options: Vec<EnumOption> = diy_effects_for_sku(sku)
+            .into_iter()
+            .map(|diy| EnumOption {
+                name: diy.diy_name,
+                value: json!(diy.diy_code),
+                extras: Default::default(),
+            })
+            .collect();
+        if !diy_options.is_empty() {
+            caps.push(DeviceCapability {
+                kind: DeviceCapabilityKind::DynamicScene,
+                parameters: Some(DeviceParameters::Enum {
+                    options: diy_options,
+                }),
+                alarm_type: None,
+                event_state: None,
+                instance: "diyScene".to_string(),
+            });
+        }
+
+        Ok(caps)
     }
 
     pub async fn get_saved_one_click_shortcuts(
         &self,
         community_token: &str,
     ) -> anyhow::Result<Vec<OneClickComponent>> {
+        let key = self.cache_key("one-click-shortcuts");
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "one-click-shortcuts",
+                key: &key,
                 soft_ttl: ONE_DAY,
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(1),
                 allow_stale: true,
+                defer_to_maintenance: false,
             },
             async {
-                let response = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(10))
-                    .build()?
+                let response = http_client(HOME_TIMEOUT)?
                     .request(
                         Method::GET,
                         "https://app2.govee.com/bff-app/v1/exec-plat/home",
@@ -434,21 +1114,30 @@ impl GoveeUndocumentedApi {
                     .header("iotVersion", "0")
                     .header("timestamp", ms_timestamp())
                     .header("User-Agent", user_agent())
-                    .send()
+                    .send_with_retry()
                     .await?;
 
                 if response.status() == reqwest::StatusCode::UNAUTHORIZED {
                     self.invalidate_community_login();
                 }
 
-                let resp: OneClickResponse = http_response_body(response).await?;
-
-                Ok(CacheComputeResult::Value(resp.data.components))
+                let resp: HomeResponse = stream_json_body(response).await?;
+                Ok(CacheComputeResult::Value(parse_home_components(resp)?))
             },
         )
         .await
     }
 
+    pub async fn parse_feasts(&self) -> anyhow::Result<Vec<ParsedFeast>> {
+        let token = self.login_community().await?;
+        let res = self.get_saved_one_click_shortcuts(&token).await?;
+        Ok(res
+            .iter()
+            .flat_map(|group| group.feasts.iter())
+            .filter_map(ParsedFeast::parse)
+            .collect())
+    }
+
     pub async fn parse_one_clicks(&self) -> anyhow::Result<Vec<ParsedOneClick>> {
         let token = self.login_community().await?;
         let res = self.get_saved_one_click_shortcuts(&token).await?;
@@ -468,6 +1157,8 @@ impl GoveeUndocumentedApi {
                     entries.push(ParsedOneClickEntry {
                         topic: rule.device_obj.topic,
                         device: rule.device_obj.device,
+                        sku: rule.device_obj.sku,
+                        name: rule.device_obj.name,
                         msgs,
                     });
                 }
@@ -479,6 +1170,69 @@ impl GoveeUndocumentedApi {
     }
 }
 
+/// A feast is a linked group of devices, such as a DreamView,
+/// which act together under the direction of a leader device
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ParsedFeast {
+    pub id: i64,
+    pub name: String,
+    pub leader: FeastMember,
+    pub members: Vec<FeastMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeastMember {
+    pub sku: String,
+    pub device: String,
+}
+
+impl ParsedFeast {
+    /// Parse an entry from OneClickComponent::feasts.
+    /// Returns None if the feast has no identifiable leader.
+    fn parse(feast: &JsonValue) -> Option<Self> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Feast {
+            feast_id: i64,
+            name: String,
+            #[serde(default)]
+            devices: Vec<FeastDevice>,
+            feast_main_device: Option<FeastDevice>,
+        }
+
+        #[derive(Deserialize)]
+        struct FeastDevice {
+            sku: Option<String>,
+            device: Option<String>,
+        }
+
+        impl FeastDevice {
+            fn member(self) -> Option<FeastMember> {
+                Some(FeastMember {
+                    sku: self.sku?,
+                    device: self.device?,
+                })
+            }
+        }
+
+        let feast: Feast = serde_json::from_value(feast.clone()).ok()?;
+        let leader = feast.feast_main_device.and_then(FeastDevice::member)?;
+        let members = feast
+            .devices
+            .into_iter()
+            .filter_map(FeastDevice::member)
+            .filter(|m| *m != leader)
+            .collect();
+
+        Some(Self {
+            id: feast.feast_id,
+            name: feast.name,
+            leader,
+            members,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParsedOneClick {
     pub name: String,
@@ -489,11 +1243,14 @@ pub struct ParsedOneClick {
 pub struct ParsedOneClickEntry {
     pub topic: Redacted<String>,
     pub device: String,
+    pub sku: String,
+    /// The device name; replaced with our own idea of the name
+    /// when resolved against the known devices
+    pub name: String,
     pub msgs: Vec<JsonValue>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 #[serde(rename_all = "camelCase")]
 pub struct IotKey {
     pub endpoint: String,
@@ -502,208 +1259,22 @@ pub struct IotKey {
     pub p12_pass: Redacted<String>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LightEffectLibraryResponse {
-    pub data: LightEffectLibraryCategoryList,
-    pub message: String,
-    pub status: u32,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LightEffectLibraryCategoryList {
-    pub categories: Vec<LightEffectCategory>,
-    pub support_speed: u8,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LightEffectCategory {
-    pub category_id: u32,
-    pub category_name: String,
-    pub scenes: Vec<LightEffectScene>,
-}
-
+/// A cached scene icon image
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LightEffectScene {
-    pub scene_id: u32,
-    pub icon_urls: Vec<String>,
-    pub scene_name: String,
-    pub analytic_name: String,
-    pub scene_type: u32,
-    pub scene_code: u32,
-    pub scence_category_id: u32,
-    pub pop_up_prompt: u32,
-    pub scenes_hint: String,
-    /// Eg: min/max applicable device version constraints
-    pub rule: JsonValue,
-    pub light_effects: Vec<LightEffectEntry>,
-    pub voice_url: String,
-    pub create_time: u64,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LightEffectEntry {
-    pub scence_param_id: u32,
-    pub scence_name: String,
-    /// base64 encoded
-    pub scence_param: String,
-    pub scene_code: u16,
-    pub special_effect: Vec<JsonValue>,
-    pub cmd_version: u32,
-    pub scene_type: u32,
-    pub diy_effect_code: Vec<JsonValue>,
-    pub diy_effect_str: String,
-    pub rules: Vec<JsonValue>,
-    pub speed_info: JsonValue,
+pub struct SceneIcon {
+    pub content_type: String,
+    /// base64 encoded image data
+    pub data: String,
 }
 
+/// The response to a firmware check. `data` is null when
+/// there is no update available.
 #[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickResponse {
-    pub data: OneClickComponentList,
-    pub message: String,
-    pub status: u32,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickComponentList {
-    pub components: Vec<OneClickComponent>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickComponent {
-    pub can_disable: Option<u8>,
-    #[serde(deserialize_with = "boolean_int")]
-    pub can_manage: bool,
-
-    pub feast_type: Option<u64>,
-    #[serde(default)]
-    pub feasts: Vec<JsonValue>,
-
-    #[serde(default)]
-    pub groups: Vec<JsonValue>,
-
-    pub main_device: Option<JsonValue>,
-
-    pub component_id: u64,
-    #[serde(default)]
-    pub environments: Vec<JsonValue>,
-    pub name: String,
-    #[serde(rename = "type")]
-    pub component_type: u64,
-
-    pub guide_url: Option<String>,
-    pub h5_url: Option<String>,
-    pub video_url: Option<String>,
-
-    #[serde(default)]
-    pub one_clicks: Vec<OneClick>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClick {
-    pub name: String,
-    pub plan_type: i64,
-    pub preset_id: i64,
-    pub preset_state: i64,
-    pub siri_engine_id: i64,
-    #[serde(rename = "type")]
-    pub rule_type: i64,
-    pub desc: String,
-    #[serde(default)]
-    pub exec_rules: Vec<JsonValue>,
-    pub group_id: i64,
-    pub group_name: String,
-    #[serde(default)]
-    pub iot_rules: Vec<OneClickIotRule>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickIotRule {
-    pub device_obj: OneClickIotRuleDevice,
-    pub rule: Vec<OneClickIotRuleEntry>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickIotRuleEntry {
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub blue_msg: JsonValue,
-    pub cmd_type: u64,
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub cmd_val: OneClickIotRuleEntryCmd,
-    pub device_type: u32,
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub iot_msg: JsonValue,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickIotRuleEntryCmd {
-    pub open: Option<u32>,
-    pub scenes_code: Option<u16>,
-    pub scence_id: Option<u16>,
-    pub scenes_str: Option<String>,
-    pub scence_param_id: Option<u16>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct OneClickIotRuleDevice {
-    pub name: String,
-    pub device: String,
-    pub sku: String,
-
-    pub topic: Redacted<String>,
-
-    pub ble_address: String,
-    pub ble_name: String,
-    pub device_splicing_status: u32,
-    pub feast_id: u64,
-    pub feast_name: String,
-    pub feast_type: u64,
-    pub goods_type: u64,
-    pub ic: Option<u32>,
-    #[serde(rename = "ic_sub_1")]
-    pub ic_sub_1: Option<u32>,
-    #[serde(rename = "ic_sub_2")]
-    pub ic_sub_2: Option<u32>,
-    #[serde(deserialize_with = "boolean_int")]
-    pub is_feast: bool,
-    pub pact_type: u32,
-    pub pact_code: u32,
-
-    pub settings: Option<JsonValue>,
-    pub spec: String,
-    pub sub_device: String,
-    pub sub_device_num: u64,
-    pub sub_devices: Option<JsonValue>,
-
-    pub version_hard: String,
-    pub version_soft: String,
-    pub wifi_soft_version: String,
-    pub wifi_hard_version: String,
+#[allow(dead_code)]
+struct CheckVersionResponse {
+    data: Option<FirmwareUpdate>,
+    message: String,
+    status: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -728,198 +1299,30 @@ pub struct LoginAccountResponse {
     pub topic: Redacted<String>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct DevicesResponse {
-    pub devices: Vec<DeviceEntry>,
-    pub groups: Vec<GroupEntry>,
-    pub message: String,
-    pub status: u16,
-}
-
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct GroupEntry {
-    pub group_id: u64,
-    pub group_name: String,
-}
-
-#[derive(Deserialize, Debug, Clone)]
+/// The new credentials returned when refreshing a login
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceEntry {
-    pub attributes_id: u32,
-    pub device_id: Option<u32>,
-    pub device: String,
-    pub device_ext: DeviceEntryExt,
-    pub device_name: String,
-    pub goods_type: u32,
-    pub group_id: u64,
-    pub pact_code: Option<u32>,
-    pub pact_type: Option<u32>,
-    pub share: Option<u32>,
-    pub sku: String,
-    pub spec: String,
-    #[serde(deserialize_with = "boolean_int")]
-    pub support_scene: bool,
-    pub version_hard: String,
-    pub version_soft: String,
-    pub gid_confirmed: Option<bool>,
+pub struct RefreshTokenResponse {
+    pub token: Redacted<String>,
+    /// Present if the refresh token was rotated
+    pub refresh_token: Option<Redacted<String>>,
+    pub token_expire_cycle: Option<u32>,
 }
 
-impl DeviceEntry {
-    pub fn device_topic(&self) -> anyhow::Result<&str> {
-        self.device_ext
-            .device_settings
-            .topic
-            .as_ref()
-            .map(|t| t.as_str())
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "device {id} has no topic, is it a BLE-only device?",
-                    id = self.device
-                )
-            })
+impl LoginAccountResponse {
+    /// Apply the result of refreshing this login
+    pub fn with_refreshed_token(mut self, refreshed: RefreshTokenResponse) -> Self {
+        self.token = refreshed.token;
+        if refreshed.refresh_token.is_some() {
+            self.refresh_token = refreshed.refresh_token;
+        }
+        if let Some(cycle) = refreshed.token_expire_cycle {
+            self.token_expire_cycle = cycle;
+        }
+        self
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceEntryExt {
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub device_settings: DeviceSettings,
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub ext_resources: ExtResources,
-    #[serde(deserialize_with = "embedded_json", serialize_with = "as_json")]
-    pub last_device_data: LastDeviceData,
-}
-
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceSettings {
-    /// Maybe be absent for BLE devices
-    pub wifi_name: Option<String>,
-    pub address: Option<String>,
-    pub ble_name: Option<String>,
-    pub topic: Option<Redacted<String>>,
-    pub wifi_mac: Option<String>,
-    pub pact_type: Option<u32>,
-    pub pact_code: Option<u32>,
-    pub dsp_version_soft: Option<JsonValue>,
-    pub wifi_soft_version: Option<String>,
-    pub wifi_hard_version: Option<String>,
-    pub ic: Option<u32>,
-    #[serde(rename = "ic_sub_1")]
-    pub ic_sub_1: Option<u32>,
-    #[serde(rename = "ic_sub_2")]
-    pub ic_sub_2: Option<u32>,
-    pub secret_code: Option<Redacted<String>>,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub boil_water_completed_noti_on_off: bool,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub boil_water_exception_noti_on_off: bool,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub completion_noti_on_off: bool,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub auto_shut_down_on_off: bool,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub water_shortage_on_off: bool,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub air_quality_on_off: bool,
-    pub mcu_soft_version: Option<String>,
-    pub mcu_hard_version: Option<String>,
-    pub sku: Option<String>,
-    pub device: Option<String>,
-    pub device_name: Option<String>,
-    pub version_hard: Option<String>,
-    pub version_soft: Option<String>,
-    pub play_state: Option<bool>,
-    pub tem_min: Option<i64>,
-    pub tem_max: Option<i64>,
-    pub tem_warning: Option<bool>,
-    pub fah_open: Option<bool>,
-    pub tem_cali: Option<i64>,
-    pub hum_min: Option<i64>,
-    pub hum_max: Option<i64>,
-    pub hum_warning: Option<bool>,
-    pub hum_cali: Option<i64>,
-    pub net_waring: Option<bool>,
-    pub upload_rate: Option<i64>,
-    pub battery: Option<i64>,
-    /// millisecond timestamp
-    pub time: Option<u64>,
-    pub wifi_level: Option<i64>,
-
-    pub pm25_min: Option<i64>,
-    pub pm25_max: Option<i64>,
-    pub pm25_warning: Option<bool>,
-
-    /// `{"sub_0": {"name": "Device Name"}}`
-    pub sub_devices: Option<JsonValue>,
-    pub bd_type: Option<i64>,
-    #[serde(deserialize_with = "boolean_int", default)]
-    pub filter_expire_on_off: bool,
-
-    /// eg: Glide Hexa. Value is base64 encoded data
-    pub shapes: Option<String>,
-    pub support_ble_broad_v3: Option<bool>,
-}
-
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct ExtResources {
-    pub sku_url: Option<String>,
-    pub head_on_img_new: Option<String>,
-    pub head_on_img: Option<String>,
-    pub head_off_img: Option<String>,
-    pub head_off_img_new: Option<String>,
-    pub ext: Option<String>,
-    pub ic: Option<u32>,
-}
-
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct LastDeviceData {
-    pub online: Option<bool>,
-    pub bind: Option<bool>,
-
-    pub tem: Option<i64>,
-    pub hum: Option<i64>,
-    /// timestamp in milliseconds
-    pub last_time: Option<u64>,
-    pub avg_day_tem: Option<i64>,
-    pub avg_day_hum: Option<i64>,
-}
-
-pub fn as_json<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
-where
-    T: Serialize,
-    S: serde::Serializer,
-{
-    use serde::ser::Error as _;
-
-    let s = serde_json::to_string(value).map_err(|e| S::Error::custom(format!("{e:#}")))?;
-
-    s.serialize(serializer)
-}
-
-pub fn embedded_json<'de, T: DeserializeOwned, D: serde::de::Deserializer<'de>>(
-    deserializer: D,
-) -> Result<T, D::Error> {
-    use serde::de::Error as _;
-    let s = String::deserialize(deserializer)?;
-    from_json(if s.is_empty() { "null" } else { &s }).map_err(|e| {
-        D::Error::custom(format!(
-            "{} {e:#} while processing embedded json text {s}",
-            std::any::type_name::<T>()
-        ))
-    })
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -932,6 +1335,25 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[cfg(feature = "cloud")]
+    #[test]
+    fn get_diy_effects() {
+        let resp: DiyEffectsResponse =
+            from_json(include_str!("../test-data/undoc-diy-effects.json")).unwrap();
+        let effects = flatten_diy_effects(resp.data);
+        let names: Vec<&str> = effects.iter().map(|d| d.diy_name.as_str()).collect();
+        assert_eq!(names, vec!["Campfire", "Team Colors", "Candy Cane"]);
+
+        DIY_EFFECTS
+            .lock()
+            .insert("test-diy".to_string(), effects.clone());
+        let codes: Vec<u32> = diy_effects_for_sku("H6072")
+            .iter()
+            .map(|d| d.diy_code)
+            .collect();
+        assert_eq!(codes, vec![8412, 9120]);
+    }
+
     #[test]
     fn get_one_click() {
         let resp: OneClickResponse =
@@ -939,6 +1361,66 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn home_components() {
+        let text = include_str!("../test-data/undoc-one-click.json");
+        // As the response is parsed while it is being received
+        let resp: HomeResponse = crate::platform_api::from_json_reader(text.as_bytes()).unwrap();
+        let components = parse_home_components(resp).unwrap();
+        let names: Vec<_> = components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Default", "Daily", "DreamView"]);
+
+        // The components that we keep are parsed in full
+        let full: OneClickResponse =
+            from_json(include_str!("../test-data/undoc-one-click.json")).unwrap();
+        assert_eq!(
+            format!("{:#?}", components[2]),
+            format!("{:#?}", full.data.components[3])
+        );
+    }
+
+    #[test]
+    fn parse_feast() {
+        let resp: OneClickResponse =
+            from_json(include_str!("../test-data/undoc-one-click.json")).unwrap();
+        let feasts: Vec<_> = resp
+            .data
+            .components
+            .iter()
+            .flat_map(|c| c.feasts.iter())
+            .filter_map(ParsedFeast::parse)
+            .collect();
+        // The only feast in the test data has no leader
+        assert!(feasts.is_empty());
+
+        let feast = ParsedFeast::parse(&json!({
+            "devices": [
+                {"sku": "H6072", "device": "AA:BB"},
+                {"sku": "H6199", "device": "CC:DD"},
+            ],
+            "enable": 1,
+            "feastId": 42,
+            "feastMainDevice": {"sku": "H6072", "device": "AA:BB"},
+            "name": "DreamView",
+        }))
+        .unwrap();
+        assert_eq!(
+            feast,
+            ParsedFeast {
+                id: 42,
+                name: "DreamView".to_string(),
+                leader: FeastMember {
+                    sku: "H6072".to_string(),
+                    device: "AA:BB".to_string()
+                },
+                members: vec![FeastMember {
+                    sku: "H6199".to_string(),
+                    device: "CC:DD".to_string()
+                }],
+            }
+        );
+    }
+
     #[test]
     fn issue36() {
         let resp: OneClickResponse =
@@ -965,4 +1447,130 @@ mod test {
             from_json(include_str!("../test-data/undoc-device-list-issue-21.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
+
+    #[tokio::test]
+    #[cfg(feature = "cloud")]
+    async fn saved_login() {
+        let account: LoginAccountResponse = from_json(
+            r#"{"A": "a", "B": "b", "accountId": 1, "client": "c", "isSavvyUser": false,
+            "refreshToken": "r1", "token": "t1", "tokenExpireCycle": 3600, "topic": "t"}"#,
+        )
+        .unwrap();
+        let iot_key: IotKey = from_json(
+            r#"{"endpoint": "iot.example.com", "log": "", "p12": "cert", "p12Pass": "pass"}"#,
+        )
+        .unwrap();
+        let login = SavedLogin::new(
+            "me@example.com".to_string(),
+            account,
+            iot_key,
+            Some("community".to_string()),
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("govee2mqtt-test-token-{}.json", std::process::id()));
+        login.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = SavedLogin::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let client = GoveeUndocumentedApi::with_saved_login(loaded);
+        assert!(client.has_saved_login());
+        let account = client.login_account_cached().await.unwrap();
+        assert_eq!(account.token.as_str(), "t1");
+        let iot_key = client.get_iot_key(account.token.as_str()).await.unwrap();
+        assert_eq!(iot_key.endpoint, "iot.example.com");
+        assert_eq!(client.login_community().await.unwrap(), "community");
+
+        // Once the token is rejected, cloud requests stop until
+        // a new login is saved, rather than trying the password
+        client.invalidate_account_login();
+        let err = client.login_account_cached().await.unwrap_err();
+        assert!(format!("{err:#}").contains("govee login"), "{err:#}");
+        assert!(client.get_iot_key("t1").await.is_err());
+        assert!(client.login_community().await.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "cloud")]
+    fn settings_body() {
+        let body = device_settings_body(
+            "H5100",
+            "XX:XX:XX:78:EA",
+            &serde_json::json!({"fahOpen": true}),
+        )
+        .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "sku": "H5100",
+                "device": "XX:XX:XX:78:EA",
+                "deviceSettings": "{\"fahOpen\":true}",
+            })
+        );
+    }
+
+    #[test]
+    fn parse_account() {
+        let acct: GoveeAccount = "me@example.com:hunter2".parse().unwrap();
+        assert_eq!(acct.email, "me@example.com");
+        assert_eq!(acct.password, "hunter2");
+        assert_eq!(acct.api_key, None);
+
+        let acct: GoveeAccount = "me@example.com:pass:word:2bd4a1f5-55d6-4b45-8b3e-6e4c1f7e6a0d"
+            .parse()
+            .unwrap();
+        assert_eq!(acct.password, "pass:word");
+        assert_eq!(
+            acct.api_key.as_deref(),
+            Some("2bd4a1f5-55d6-4b45-8b3e-6e4c1f7e6a0d")
+        );
+
+        assert!("me@example.com".parse::<GoveeAccount>().is_err());
+        assert!("me@example.com:".parse::<GoveeAccount>().is_err());
+    }
+
+    #[test]
+    fn refresh_login() {
+        let prior: LoginAccountResponse = from_json(
+            r#"{"A": "a", "B": "b", "accountId": 1, "client": "c", "isSavvyUser": false,
+            "refreshToken": "r1", "token": "t1", "tokenExpireCycle": 3600, "topic": "t"}"#,
+        )
+        .unwrap();
+
+        let refreshed: RefreshTokenResponse =
+            from_json(r#"{"token": "t2", "tokenExpireCycle": 7200}"#).unwrap();
+        let account = prior.clone().with_refreshed_token(refreshed);
+        assert_eq!(account.token.as_str(), "t2");
+        assert_eq!(account.refresh_token.as_ref().unwrap().as_str(), "r1");
+        assert_eq!(account.token_expire_cycle, 7200);
+
+        let refreshed: RefreshTokenResponse =
+            from_json(r#"{"token": "t3", "refreshToken": "r2"}"#).unwrap();
+        let account = prior.with_refreshed_token(refreshed);
+        assert_eq!(account.refresh_token.as_ref().unwrap().as_str(), "r2");
+        assert_eq!(account.token_expire_cycle, 3600);
+    }
+
+    #[test]
+    fn firmware_update() {
+        let resp: CheckVersionResponse =
+            from_json(include_str!("../test-data/undoc-check-version.json")).unwrap();
+        assert_eq!(resp.status, 200);
+        let update = resp.data.unwrap();
+        assert_eq!(update.version_soft, "1.02.04");
+        assert_eq!(
+            update.description.as_deref(),
+            Some("1. Improved the stability of the Wi-Fi connection.")
+        );
+
+        let resp: CheckVersionResponse =
+            from_json(r#"{"data": null, "message": "Success", "status": 200}"#).unwrap();
+        assert!(resp.data.is_none());
+    }
 }